@@ -1,5 +1,8 @@
-use criterion::{Criterion, criterion_group, criterion_main};
+use criterion::{Criterion, Throughput, criterion_group, criterion_main};
+use dashmap::DashMap;
+use mergedb_node::shards;
 use mergedb_types::{Merge, pn_counter::PNCounter};
+use std::thread;
 
 fn benchmark_counter_merge(c: &mut Criterion) {
     let mut c1 = PNCounter::new("node_1".to_string(), 0, 0);
@@ -13,13 +16,54 @@ fn benchmark_counter_merge(c: &mut Criterion) {
     c.bench_function("merge_1000_counter_updates", |b| {
         b.iter_batched(
             || (c1.clone(), c2.clone()), //setup part, is not counted in benchmark time
-            |(mut target, mut source)| {
-                target.merge(&mut source);
+            |(mut target, source)| {
+                target.merge(&source);
             },
             criterion::BatchSize::SmallInput,
         );
     });
 }
 
-criterion_group!(benches, benchmark_counter_merge);
+//writers hammering one counter, kept whole under its key and spread over shards the way
+//CSHARD lays them out. each write holds its entry as long as CINC does, for the add and the
+//copy that gets pushed to peers
+fn benchmark_hot_counter(c: &mut Criterion) {
+    const WRITERS: usize = 8;
+    const WRITES: usize = 10_000;
+
+    let mut group = c.benchmark_group("hot_counter");
+    group.throughput(Throughput::Elements((WRITERS * WRITES) as u64));
+    for shard_count in [1, 8] {
+        group.bench_function(format!("{}_shards", shard_count), |b| {
+            b.iter_batched(
+                || {
+                    let store = DashMap::new();
+                    for shard in 0..shard_count {
+                        let counter = PNCounter::new("node_1".to_string(), 0, 0);
+                        store.insert(shards::shard_key("views", shard), counter);
+                    }
+                    store
+                },
+                |store| {
+                    thread::scope(|scope| {
+                        for _ in 0..WRITERS {
+                            scope.spawn(|| {
+                                for _ in 0..WRITES {
+                                    let key = shards::shard_key("views", shards::pick(shard_count));
+                                    let mut counter = store.get_mut(&key).unwrap();
+                                    counter.checked_add("node_1".to_string(), 1).unwrap();
+                                    std::hint::black_box(counter.clone());
+                                }
+                            });
+                        }
+                    });
+                },
+                criterion::BatchSize::LargeInput,
+            );
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, benchmark_counter_merge, benchmark_hot_counter);
 criterion_main!(benches);
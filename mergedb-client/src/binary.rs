@@ -0,0 +1,127 @@
+//--encoding msgpack|cbor on RSET, SADD, SREM and SMETA: the value is taken as json and sent
+//encoded, what doesn't parse as json is sent as a json string.
+//--type int|float|bool|bytes on SADD, SREM and SMETA sends the tag as that kind of element,
+//bytes are given in hex
+use anyhow::{anyhow, bail, Context, Result};
+use mergedb_proto::wire::{self, Encoding};
+use mergedb_types::element::Element;
+use serde_json::Value;
+
+use crate::ToBytes;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Encoded {
+    pub bytes: Vec<u8>,
+    pub encoding: Encoding,
+    pub element_type: &'static str,
+}
+
+impl ToBytes for Encoded {
+    fn to_bytes(&self) -> Vec<u8> {
+        self.bytes.clone()
+    }
+
+    fn encoding(&self) -> Encoding {
+        self.encoding
+    }
+
+    fn element_type(&self) -> &'static str {
+        self.element_type
+    }
+}
+
+pub fn encode(value: &str, encoding: Encoding) -> Result<Encoded> {
+    let json = || serde_json::from_str(value).unwrap_or_else(|_| Value::String(value.to_string()));
+    let bytes = match encoding {
+        Encoding::Utf8 => value.as_bytes().to_vec(),
+        Encoding::Msgpack => rmp_serde::to_vec(&json()).context("failed to encode msgpack")?,
+        Encoding::Cbor => {
+            let mut bytes = Vec::new();
+            ciborium::ser::into_writer(&json(), &mut bytes).context("failed to encode cbor")?;
+            bytes
+        }
+    };
+    Ok(Encoded {
+        bytes,
+        encoding,
+        element_type: "",
+    })
+}
+
+//a set tag given as text, of the kind --type names
+pub fn tag(value: &str, encoding: Encoding, element_type: &str) -> Result<Encoded> {
+    let element = match element_type {
+        "string" => return encode(value, encoding),
+        _ if encoding != Encoding::Utf8 => bail!("only string tags take an --encoding"),
+        "int" => Element::Int(value.parse().context("not an integer")?),
+        "float" => Element::Float(value.parse().context("not a float")?),
+        "bool" => Element::Bool(value.parse().context("not true or false")?),
+        "bytes" => Element::Bytes(from_hex(value)?),
+        other => bail!(
+            "unknown --type {}, expected string, int, float, bool or bytes",
+            other
+        ),
+    };
+    let (bytes, element_type) = wire::encode_element(&element);
+    Ok(Encoded {
+        bytes,
+        encoding,
+        element_type,
+    })
+}
+
+fn from_hex(hex: &str) -> Result<Vec<u8>> {
+    let hex = hex.strip_prefix("0x").unwrap_or(hex);
+    if !hex.is_ascii() || !hex.len().is_multiple_of(2) {
+        bail!("{} is not hex, it needs an even number of hex digits", hex);
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| anyhow!("{} is not hex", hex)))
+        .collect()
+}
+
+//the json a binary value holds, None when it isn't valid in its encoding or doesn't map to json
+pub fn decode(bytes: &[u8], encoding: Encoding) -> Option<Value> {
+    match encoding {
+        Encoding::Utf8 => None,
+        Encoding::Msgpack => rmp_serde::from_slice(bytes).ok(),
+        Encoding::Cbor => ciborium::de::from_reader(bytes).ok(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_json_round_trips_through_both_encodings() {
+        let json = r#"{"name":"Ada","tags":[1,2.5,null,true]}"#;
+        for encoding in [Encoding::Msgpack, Encoding::Cbor] {
+            let encoded = encode(json, encoding).unwrap();
+            assert_eq!(
+                decode(&encoded.bytes, encoding),
+                Some(serde_json::from_str(json).unwrap())
+            );
+        }
+        //not json, so a string
+        let encoded = encode("Ada", Encoding::Cbor).unwrap();
+        assert_eq!(encoded.bytes, b"\x63Ada");
+        assert_eq!(decode(&[0xc1], Encoding::Msgpack), None);
+    }
+
+    #[test]
+    fn test_typed_tags() {
+        let int = tag("-4", Encoding::Utf8, "int").unwrap();
+        assert_eq!((int.bytes, int.element_type), (wire::encode_i64(-4), "int"));
+        assert_eq!(
+            tag("0x00ff", Encoding::Utf8, "bytes").unwrap().bytes,
+            [0, 0xff]
+        );
+        assert_eq!(tag("7", Encoding::Utf8, "string").unwrap().bytes, b"7");
+        assert!(tag("yes", Encoding::Utf8, "bool").is_err());
+        assert!(tag("0f0", Encoding::Utf8, "bytes").is_err());
+        assert!(tag("é0", Encoding::Utf8, "bytes").is_err());
+        assert!(tag("1", Encoding::Cbor, "int").is_err());
+    }
+}
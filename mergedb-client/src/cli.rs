@@ -1,4 +1,6 @@
 use clap::{Parser, Subcommand};
+use clap_complete::Shell;
+use mergedb_proto::{metadata::Priority, wire::Encoding};
 
 #[derive(Parser)]
 #[command(
@@ -8,9 +10,41 @@ use clap::{Parser, Subcommand};
     long_about = None
 )]
 pub struct Cli {
+    /// Node address, eg 127.0.0.1:8000, [::1]:8000 or node1.internal:8000
     #[arg(short, long)]
     pub addr: Option<String>,
 
+    /// Connect over the node's unix socket (uds_path) instead of tcp
+    #[arg(long, conflicts_with = "addr")]
+    pub uds: Option<std::path::PathBuf>,
+
+    /// Read from a file written by `export` instead of a node, without connecting to one. Only
+    /// reads work: CGET, SGET, RGET, RLEN, SCAN --prefix, DBSIZE, and STAT in the REPL
+    #[arg(long, conflicts_with_all = ["addr", "uds"])]
+    pub offline: Option<std::path::PathBuf>,
+
+    /// Disable colored output
+    #[arg(long)]
+    pub no_color: bool,
+
+    /// Send every command once, without retrying when the node can't be reached
+    #[arg(long)]
+    pub no_retry: bool,
+
+    /// Give up on a command after this many milliseconds, retries included. The node drops
+    /// it too when it is still waiting its turn by then
+    #[arg(long, default_value_t = 10_000)]
+    pub deadline_ms: u64,
+
+    /// Where data commands go in the node's queue when it is busy: low, normal or high
+    #[arg(long, default_value_t = Priority::Normal)]
+    pub priority: Priority,
+
+    /// Print where each command's time went (connecting, the round trip, the node's share of
+    /// it) and the node's raw answer
+    #[arg(short, long)]
+    pub verbose: bool,
+
     #[command(subcommand)]
     pub command: Option<Commands>,
 }
@@ -23,6 +57,7 @@ pub enum Commands {
     /// Set a counter
     Cset {
         key: String,
+        #[arg(allow_negative_numbers = true)]
         value: i64,
     },
 
@@ -34,36 +69,85 @@ pub enum Commands {
     /// Increment a counter
     Cinc {
         key: String,
+        #[arg(allow_negative_numbers = true)]
         amount: i64,
     },
 
     /// Decrement a counter
     Cdec {
         key: String,
+        #[arg(allow_negative_numbers = true)]
         amount: i64,
     },
+
+    /// Spread a hot counter's writes over n entries, CGET still reads one value.
+    /// The count can be raised but not lowered
+    Cshard {
+        key: String,
+        shards: usize,
+    },
     
     /// Add to a set
     Sadd {
         key: String,
         tag: String,
+        /// Send the tag as json encoded to msgpack or cbor instead of as utf8 text
+        #[arg(long, default_value_t = Encoding::Utf8)]
+        encoding: Encoding,
+        /// Send the tag as an int, float, bool or bytes (in hex) element instead of a string
+        #[arg(long = "type", default_value = "string")]
+        element_type: String,
     },
     
     /// Remove from a set
     Srem {
         key: String,
         tag: String,
+        /// Show what would be removed and where it would replicate to, without removing it
+        #[arg(long)]
+        dry_run: bool,
+        /// Send the tag as json encoded to msgpack or cbor instead of as utf8 text
+        #[arg(long, default_value_t = Encoding::Utf8)]
+        encoding: Encoding,
+        /// Send the tag as an int, float, bool or bytes (in hex) element instead of a string
+        #[arg(long = "type", default_value = "string")]
+        element_type: String,
     },
     
     /// Get the set
     Sget {
         key: String,
+        /// Have the node sort the members, asc or desc, numbers by value
+        #[arg(long)]
+        sort: Option<String>,
+    },
+
+    /// Which node added a tag of the set, and when
+    Smeta {
+        key: String,
+        tag: String,
+        /// Send the tag as json encoded to msgpack or cbor instead of as utf8 text
+        #[arg(long, default_value_t = Encoding::Utf8)]
+        encoding: Encoding,
+        /// Send the tag as an int, float, bool or bytes (in hex) element instead of a string
+        #[arg(long = "type", default_value = "string")]
+        element_type: String,
     },
     
+    /// Cap how many elements a set keeps, the ones added longest ago are evicted first.
+    /// Without a limit the cap is lifted
+    Slimit {
+        key: String,
+        limit: Option<usize>,
+    },
+
     /// Set the register
     Rset {
         key: String,
         register: String,
+        /// Send the value as json encoded to msgpack or cbor instead of as utf8 text
+        #[arg(long, default_value_t = Encoding::Utf8)]
+        encoding: Encoding,
     },
     
     /// Get the register
@@ -81,4 +165,216 @@ pub enum Commands {
     Rlen {
         key: String,
     },
+
+    /// Show node information, optionally a single section
+    /// (server, memory, replication, gossip, keyspace, persistence)
+    Info {
+        section: Option<String>,
+    },
+
+    /// Number of keys on the node
+    Dbsize,
+
+    /// Make a key read-only for clients on every node
+    Freeze {
+        key: String,
+    },
+
+    /// Make a frozen key writable again
+    Thaw {
+        key: String,
+    },
+
+    /// Merge a key's whole state into another key, which keeps converging like the original
+    Copy {
+        src: String,
+        dst: String,
+    },
+
+    /// Copy a key and retire the old name, later reads and writes of it are refused
+    Rename {
+        src: String,
+        dst: String,
+    },
+
+    /// Change a setting for the whole cluster (gossip_interval, gossip_mode, max_value_size,
+    /// script_max_operations, script_timeout), without a value it goes back to each node's config
+    Setting {
+        name: String,
+        value: Option<String>,
+    },
+
+    /// Settings in effect on the node, and whether they come from the cluster or its config
+    Settings,
+
+    /// Keys read and written most often lately, counts are estimates that can run high
+    Hotkeys {
+        /// How many keys, at most 100
+        n: Option<usize>,
+    },
+
+    /// Keys whose writes waited on each other the most since the node started, candidates for
+    /// CSHARD or batching
+    Contention {
+        /// How many keys, at most 100
+        n: Option<usize>,
+    },
+
+    /// Keys that changed on the node and that no peer has taken for a while, stuck replication
+    Audit,
+
+    /// Round trip latency to the node (min/avg/max)
+    Ping {
+        /// How many pings to send
+        #[arg(default_value_t = 4)]
+        count: u32,
+
+        /// Also have the node ping each of its peers and report how long they took
+        #[arg(long)]
+        all: bool,
+    },
+
+    /// Keep a file on the node in step with the keys under a prefix (.csv or json), or list
+    /// what is exported when given nothing
+    Materialize {
+        #[arg(requires = "path")]
+        prefix: Option<String>,
+        /// Path on the node's host, eg /var/lib/legacy/users.csv
+        path: Option<String>,
+    },
+
+    /// Stop keeping a materialized file up to date, it stays as it was last written
+    Unmaterialize {
+        path: String,
+    },
+
+    /// Attach labels to a key, eg env=prod,team=search (team= removes the team label)
+    Label {
+        key: String,
+        labels: String,
+    },
+
+    /// A key's labels
+    Labels {
+        key: String,
+    },
+
+    /// Keys whose labels match a selector
+    Scan {
+        /// Terms that all have to hold, eg env=prod,team!=search,owner,!legacy
+        #[arg(short, long, default_value = "")]
+        selector: String,
+
+        /// Only keys starting with this prefix
+        #[arg(short, long, default_value = "")]
+        prefix: String,
+    },
+
+    /// Stream a range of keys to a file as json lines, a backup can be split over several
+    /// workers by key or by hash slot
+    Export {
+        /// First key, inclusive
+        #[arg(long, default_value = "")]
+        start: String,
+
+        /// Key to stop before, none for the last key there is
+        #[arg(long, default_value = "")]
+        end: String,
+
+        /// Hash slots instead of keys, eg 0-4096 of 16384, the end is exclusive
+        #[arg(long, conflicts_with_all = ["start", "end"])]
+        slots: Option<String>,
+
+        /// Keys per chunk the node sends, 0 for its default
+        #[arg(long, default_value_t = 0)]
+        batch: u32,
+
+        /// File to write, stdout when left out
+        #[arg(short, long)]
+        output: Option<std::path::PathBuf>,
+    },
+
+    /// Run a rhai script on the node, with nothing else in between (get, inc, dec, add, rem, set)
+    Eval {
+        /// The script itself, eg 'inc("views", 1); get("views")'
+        #[arg(required_unless_present = "file", conflicts_with = "file")]
+        script: Option<String>,
+
+        /// Read the script from a file instead
+        #[arg(short, long)]
+        file: Option<std::path::PathBuf>,
+    },
+
+    /// Live dashboard of values and update rates for some keys, press q to quit
+    Top {
+        /// Keys to watch, with neither keys nor --prefix every key is watched
+        keys: Vec<String>,
+
+        /// Watch every key starting with this prefix, new keys show up as they are created
+        #[arg(short, long)]
+        prefix: Option<String>,
+
+        /// Refresh interval in milliseconds
+        #[arg(short, long, default_value_t = 1000)]
+        interval_ms: u64,
+    },
+
+    /// Print a shell completion script, eg `mergedb-client completions bash > /etc/bash_completion.d/mergedb-client`
+    Completions {
+        shell: Shell,
+    },
+
+    /// Cluster membership and health
+    Cluster {
+        #[command(subcommand)]
+        action: ClusterCommands,
+    },
+
+    /// The connected node's own state
+    Node {
+        #[command(subcommand)]
+        action: NodeCommands,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum NodeCommands {
+    /// How far the node has got recovering its data since it started, with an ETA
+    Status,
+}
+
+#[derive(Subcommand)]
+pub enum ClusterCommands {
+    /// Show members, their reachability, any split-brain and the store fingerprint
+    Status,
+
+    /// Gossip round-trip latency from the node to each of its peers (p50/p95/p99)
+    Peers,
+
+    /// Take a node out of the cluster for good: it hands its data to its peers and exits
+    Decommission {
+        node_id: String,
+
+        /// How long its peers get to match its data before it gives up, 0 for the node's default
+        #[arg(long, default_value_t = 0)]
+        timeout_secs: u64,
+    },
+
+    /// Refuse client writes on every node until resumed, reads and gossip carry on
+    Pause {
+        /// Told to every client whose write is refused
+        #[arg(long, default_value = "")]
+        reason: String,
+
+        /// Only the connected node, and only until it restarts
+        #[arg(long)]
+        node: bool,
+    },
+
+    /// Take client writes again after a pause
+    Resume {
+        /// Lift the connected node's own pause instead of the cluster wide one
+        #[arg(long)]
+        node: bool,
+    },
 }
@@ -1,8 +1,19 @@
 use anyhow::Result;
+use base64::{engine::general_purpose::STANDARD, Engine};
 use colored::*;
+use comfy_table::{
+    presets::UTF8_FULL_CONDENSED, Attribute, Cell, CellAlignment, Color, ContentArrangement, Table,
+};
 use figlet_rs::FIGfont;
+use mergedb_proto::{
+    communication::Replicas,
+    wire::{self, Encoding},
+};
+use mergedb_types::element::Element;
 use std::io::{stdin, stdout, Write};
 
+use crate::binary;
+
 pub fn show_welcome_screen_start() -> Result<()> {
     let font = FIGfont::standard().map_err(|e| anyhow::anyhow!(e))?;
     let figure = match font.convert("mergeDB") {
@@ -27,3 +38,219 @@ pub fn show_prompt() {
     print!("{}", ":: ".bright_green().bold());
     let _ = stdout().flush();
 }
+
+//inside a `pipe` block, commands are only queued
+pub fn show_pipe_prompt() {
+    print!("{}", ".. ".yellow().bold());
+    let _ = stdout().flush();
+}
+
+//cells longer than this get cut, so one huge value can't wreck the whole table
+pub const MAX_CELL_WIDTH: usize = 48;
+
+fn truncate(cell: &str) -> String {
+    if cell.chars().count() <= MAX_CELL_WIDTH {
+        return cell.to_string();
+    }
+    let mut cut: String = cell.chars().take(MAX_CELL_WIDTH - 1).collect();
+    cut.push('…');
+    cut
+}
+
+//renders rows under the given headers, numeric columns are right aligned. colors follow
+//colored's global switch, so --no-color turns them off here too
+pub fn render_table(headers: &[&str], rows: &[Vec<String>]) -> String {
+    let colorize = colored::control::SHOULD_COLORIZE.should_colorize();
+
+    let mut table = Table::new();
+    table
+        .load_preset(UTF8_FULL_CONDENSED)
+        .set_content_arrangement(ContentArrangement::Dynamic);
+
+    table.set_header(headers.iter().map(|header| {
+        let cell = Cell::new(header);
+        if colorize {
+            cell.add_attribute(Attribute::Bold).fg(Color::Cyan)
+        } else {
+            cell
+        }
+    }));
+
+    for row in rows {
+        table.add_row(row.iter().map(|cell| truncate(cell)));
+    }
+
+    for (index, column) in table.column_iter_mut().enumerate() {
+        let numeric = !rows.is_empty()
+            && rows
+                .iter()
+                .all(|row| row.get(index).is_some_and(|cell| cell.parse::<f64>().is_ok()));
+        if numeric {
+            column.set_cell_alignment(CellAlignment::Right);
+        }
+    }
+
+    table.to_string()
+}
+
+pub fn print_table(headers: &[&str], rows: &[Vec<String>]) {
+    println!("{}", render_table(headers, rows));
+}
+
+//binary values longer than this show as base64 rather than hex when they don't decode
+const MAX_HEX_BYTES: usize = 32;
+
+//how a register value or set element that isn't utf8 text reads: its encoding and the json it
+//holds, or its raw bytes when it doesn't decode. None for text, which shows as it is
+pub fn binary_value(held: &str) -> Option<String> {
+    let (bytes, encoding) = wire::decode_value(held);
+    if encoding == Encoding::Utf8 {
+        return None;
+    }
+    let shown = match binary::decode(&bytes, encoding) {
+        Some(json) => json.to_string(),
+        None if bytes.len() <= MAX_HEX_BYTES => {
+            let hex: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+            format!("{} bytes, hex {}", bytes.len(), hex)
+        }
+        None => format!("{} bytes, base64 {}", bytes.len(), STANDARD.encode(&bytes)),
+    };
+    Some(format!("<{}> {}", encoding, shown))
+}
+
+//the members of an SGET reply as they read, in element order unless the node already sorted them
+pub fn members(reply: Vec<serde_json::Value>, node_sorted: bool) -> Vec<String> {
+    let mut members: Vec<Element> = reply.iter().filter_map(wire::element_from_json).collect();
+    if !node_sorted {
+        members.sort();
+    }
+    members
+        .into_iter()
+        .map(|member| match member {
+            Element::Str(tag) => binary_value(&tag).unwrap_or(tag),
+            other => other.to_string(),
+        })
+        .collect()
+}
+
+//what a write left on the node it was sent to, None for writes that don't report anything or
+//nodes predating the report
+pub fn written(cmd: &str, number: Option<i64>) -> Option<String> {
+    let number = number?;
+    match cmd {
+        "CSET" | "CINC" | "CDEC" => Some(format!("value {}", number)),
+        "SADD" | "SREM" => Some(format!("{} members", number)),
+        "RSET" | "RAPP" => Some(format!("length {}", number)),
+        _ => None,
+    }
+}
+
+//how far a write got: "pushed to 2 peers", or "pushed to 1 of 2 peers, not to <address>" when
+//replication is degraded
+pub fn replicated(replicas: &Replicas) -> String {
+    let peers = |count: usize| match count {
+        1 => "1 peer".to_string(),
+        count => format!("{} peers", count),
+    };
+    let reached = replicas.reached as usize;
+    match replicas.failed.len() {
+        0 if reached == 0 => "no peers to push to".to_string(),
+        0 => format!("pushed to {}", peers(reached)),
+        failed => format!(
+            "pushed to {} of {}, not to {}",
+            reached,
+            peers(reached + failed),
+            replicas.failed.join(", ")
+        ),
+    }
+}
+
+//round trip times in microseconds as "0.210/0.340/1.200 ms", "-" when there are none
+pub fn min_avg_max(rtts_us: &[u64]) -> String {
+    let (Some(min), Some(max)) = (rtts_us.iter().min(), rtts_us.iter().max()) else {
+        return "-".to_string();
+    };
+    let avg = rtts_us.iter().sum::<u64>() as f64 / rtts_us.len() as f64;
+    let ms = |us: f64| us / 1_000.0;
+    format!(
+        "{:.3}/{:.3}/{:.3} ms",
+        ms(*min as f64),
+        ms(avg),
+        ms(*max as f64)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_long_cells_are_truncated() {
+        let long = "x".repeat(MAX_CELL_WIDTH + 10);
+        let cut = truncate(&long);
+        assert_eq!(cut.chars().count(), MAX_CELL_WIDTH);
+        assert!(cut.ends_with('…'));
+        assert_eq!(truncate("short"), "short");
+    }
+
+    #[test]
+    fn test_table_contains_every_cell() {
+        colored::control::set_override(false);
+        let rendered = render_table(
+            &["field", "value"],
+            &[
+                vec!["keys".to_string(), "10".to_string()],
+                vec!["node_id".to_string(), "node_1".to_string()],
+            ],
+        );
+        for cell in ["field", "value", "keys", "10", "node_id", "node_1"] {
+            assert!(rendered.contains(cell));
+        }
+    }
+
+    #[test]
+    fn test_binary_values_show_decoded_or_raw() {
+        let held = |bytes: Vec<u8>, encoding| wire::encode_value(bytes, encoding).unwrap();
+        assert_eq!(binary_value("Ada"), None);
+        assert_eq!(
+            binary_value(&held(vec![0xa1, 0x61, 0x61, 0x01], Encoding::Cbor)),
+            Some(r#"<cbor> {"a":1}"#.to_string())
+        );
+        assert_eq!(
+            binary_value(&held(vec![0xc1, 0xff], Encoding::Msgpack)),
+            Some("<msgpack> 2 bytes, hex c1ff".to_string())
+        );
+        assert!(binary_value(&held(vec![0xc1; 40], Encoding::Msgpack))
+            .unwrap()
+            .starts_with("<msgpack> 40 bytes, base64 wcHB"));
+    }
+
+    #[test]
+    fn test_writes_show_what_they_left() {
+        assert_eq!(written("CINC", Some(-3)).as_deref(), Some("value -3"));
+        assert_eq!(written("SREM", Some(0)).as_deref(), Some("0 members"));
+        assert_eq!(written("RAPP", Some(12)).as_deref(), Some("length 12"));
+        assert_eq!(written("CINC", None), None);
+        assert_eq!(written("FREEZE", Some(1)), None);
+    }
+
+    #[test]
+    fn test_degraded_writes_name_the_failed_peers() {
+        let replicas = |reached, failed: &[&str]| Replicas {
+            reached,
+            failed: failed.iter().map(|peer| peer.to_string()).collect(),
+        };
+        assert_eq!(replicated(&replicas(0, &[])), "no peers to push to");
+        assert_eq!(replicated(&replicas(1, &[])), "pushed to 1 peer");
+        assert_eq!(
+            replicated(&replicas(1, &["10.0.0.3:8001", "10.0.0.4:8001"])),
+            "pushed to 1 of 3 peers, not to 10.0.0.3:8001, 10.0.0.4:8001"
+        );
+    }
+
+    #[test]
+    fn test_round_trips_summarized() {
+        assert_eq!(min_avg_max(&[210, 300, 1_200]), "0.210/0.570/1.200 ms");
+        assert_eq!(min_avg_max(&[]), "-");
+    }
+}
@@ -0,0 +1,69 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use mergedb_proto::communication::{
+    crdt_data::Data, replication_service_client::ReplicationServiceClient, ExportRequest,
+};
+use prost::Message;
+use std::io::Write;
+use tonic::{transport::Channel, Request};
+
+//"0-4096" is slots 0 to 4095, the end is exclusive like the node's
+pub fn parse_slots(slots: &str) -> Result<(u32, u32), String> {
+    let parse = |slot: &str| {
+        slot.trim()
+            .parse::<u32>()
+            .map_err(|_| format!("{:?} is not a slot range, eg 0-4096", slots))
+    };
+    let Some((start, end)) = slots.split_once('-') else {
+        return Err(format!("{:?} is not a slot range, eg 0-4096", slots));
+    };
+    Ok((parse(start)?, parse(end)?))
+}
+
+//writes one json line per key: {"key", "type", "state"}, the state being the key's CRDTData
+//message in base64, the way the node keeps it in its snapshot. answers with how many keys
+//were written
+pub async fn run(
+    client: &mut ReplicationServiceClient<Channel>,
+    request: ExportRequest,
+    out: &mut dyn Write,
+) -> Result<u64, Box<dyn std::error::Error>> {
+    let mut chunks = client.export(Request::new(request)).await?.into_inner();
+    let mut written = 0;
+    while let Some(chunk) = chunks.message().await? {
+        for exported in chunk.keys {
+            let Some(value) = exported.value else {
+                continue;
+            };
+            let kind = match value.data {
+                Some(Data::PnCounter(_)) => "counter",
+                Some(Data::AwSet(_)) => "set",
+                Some(Data::LwwRegister(_)) => "register",
+                Some(Data::LwwMap(_)) => "map",
+                Some(Data::Custom(_)) => "custom",
+                None => continue,
+            };
+            let line = serde_json::json!({
+                "key": exported.key,
+                "type": kind,
+                "state": STANDARD.encode(value.encode_to_vec()),
+            });
+            writeln!(out, "{}", line)?;
+            written += 1;
+        }
+    }
+    out.flush()?;
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_slots() {
+        assert_eq!(parse_slots("0-4096"), Ok((0, 4096)));
+        assert_eq!(parse_slots("12288 - 16384"), Ok((12288, 16384)));
+        assert!(parse_slots("4096").is_err());
+        assert!(parse_slots("a-b").is_err());
+    }
+}
@@ -0,0 +1,110 @@
+use mergedb_proto::communication::{
+    replication_service_client::ReplicationServiceClient, PropagateDataRequest,
+};
+use std::collections::HashMap;
+use tonic::{transport::Channel, Request};
+
+//the type a command works on, None for commands that aren't about one key's value
+pub fn expected_type(cmd: &str) -> Option<&'static str> {
+    match cmd {
+        "CSET" | "CGET" | "CINC" | "CDEC" | "CSHARD" => Some("counter"),
+        "SADD" | "SREM" | "SGET" | "SMETA" => Some("set"),
+        "RSET" | "RGET" | "RAPP" | "RLEN" => Some("register"),
+        _ => None,
+    }
+}
+
+fn commands_for(type_name: &str) -> &'static str {
+    match type_name {
+        "counter" => "CSET/CGET/CINC/CDEC",
+        "set" => "SADD/SREM/SGET/SMETA",
+        _ => "RSET/RGET/RAPP/RLEN",
+    }
+}
+
+//key -> type as the node reported it, so that eg CINC on a set is caught with a useful message
+//before it goes out. a key keeps its type once created, but a hint can still be wrong when
+//two nodes created the key as different types, so a refused command drops the hint
+#[derive(Debug, Default)]
+pub struct TypeHints {
+    types: HashMap<String, String>,
+}
+
+impl TypeHints {
+    //Err with what to do instead when `cmd` can't work on `key`. the first time a key comes
+    //up its type is asked for with TYPE, keys that don't exist yet aren't remembered
+    pub async fn check(
+        &mut self,
+        client: &mut ReplicationServiceClient<Channel>,
+        cmd: &str,
+        key: &str,
+    ) -> Result<(), String> {
+        let Some(expected) = expected_type(cmd) else {
+            return Ok(());
+        };
+        if !self.types.contains_key(key) {
+            if let Some(type_name) = fetch_type(client, key).await {
+                self.types.insert(key.to_string(), type_name);
+            }
+        }
+        self.mismatch(cmd, key, expected)
+    }
+
+    fn mismatch(&self, cmd: &str, key: &str, expected: &str) -> Result<(), String> {
+        match self.types.get(key) {
+            Some(actual) if actual != expected => Err(format!(
+                "{} is a {}, {} only works on {}s (try {})",
+                key,
+                actual,
+                cmd,
+                expected,
+                commands_for(actual)
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn forget(&mut self, key: &str) {
+        self.types.remove(key);
+    }
+}
+
+//None when the key doesn't exist or the node couldn't be asked, the node then has the last word
+async fn fetch_type(client: &mut ReplicationServiceClient<Channel>, key: &str) -> Option<String> {
+    let response = client
+        .propagate_data(Request::new(PropagateDataRequest {
+            valuetype: "TYPE".to_string(),
+            key: key.to_string(),
+            value: Vec::new(),
+            dry_run: false,
+            encoding: String::new(),
+            element_type: String::new(),
+            amount: None,
+        }))
+        .await
+        .ok()?
+        .into_inner();
+    let type_name = String::from_utf8(response.response).ok()?;
+    (response.success && type_name != "none").then_some(type_name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mismatch_suggests_the_right_commands() {
+        let mut hints = TypeHints::default();
+        hints.types.insert("tags".to_string(), "set".to_string());
+
+        assert_eq!(
+            hints.mismatch("CINC", "tags", "counter").unwrap_err(),
+            "tags is a set, CINC only works on counters (try SADD/SREM/SGET/SMETA)"
+        );
+        assert!(hints.mismatch("SADD", "tags", "set").is_ok());
+        assert!(hints.mismatch("CSET", "views", "counter").is_ok());
+
+        hints.forget("tags");
+        assert!(hints.mismatch("CINC", "tags", "counter").is_ok());
+    }
+}
@@ -1,27 +1,67 @@
+mod binary;
 mod cli;
 mod display;
+mod export;
+mod hints;
+mod offline;
+mod pipe;
+mod rc;
+mod retry;
+mod timing;
+mod top;
+mod usage;
 
 use anyhow::Result;
-use clap::Parser;
-use cli::{Cli, Commands};
+use clap::{CommandFactory, Parser};
+use cli::{Cli, ClusterCommands, Commands, NodeCommands};
+use hints::TypeHints;
+use pipe::Pipeline;
+use rc::Rc;
+use retry::{Backoff, RetryPolicy};
+use timing::Timing;
 use colored::*;
+use mergedb_proto::{communication, wire};
 use communication::replication_service_client::ReplicationServiceClient;
-use communication::PropagateDataRequest;
+use communication::{
+    ClusterStatusRequest, DecommissionRequest, FingerprintRequest, NodeStatusRequest,
+    PauseRequest, PingRequest, PropagateDataRequest, ResumeRequest,
+};
+use std::collections::BTreeMap;
 use std::fmt::Debug;
-use std::io::stdin;
+use std::io::{stdin, stdout};
+use std::net::{Ipv6Addr, SocketAddr};
+use std::path::PathBuf;
+use tokio::net::UnixStream;
+use tonic::transport::{Channel, Endpoint, Uri};
 use tonic::Request;
-
-pub mod communication {
-    tonic::include_proto!("communication");
-}
+use tower::service_fn;
 
 pub trait ToBytes {
     fn to_bytes(&self) -> Vec<u8>;
+
+    //how the node should read the bytes when they are a tag or register value
+    fn encoding(&self) -> wire::Encoding {
+        wire::Encoding::Utf8
+    }
+
+    //what kind of set element the bytes are, empty for a string
+    fn element_type(&self) -> &'static str {
+        ""
+    }
+
+    //counter amounts go in their own signed field too
+    fn amount(&self) -> Option<i64> {
+        None
+    }
 }
 
 impl ToBytes for i64 {
     fn to_bytes(&self) -> Vec<u8> {
-        self.to_be_bytes().to_vec()
+        wire::encode_i64(*self)
+    }
+
+    fn amount(&self) -> Option<i64> {
+        Some(*self)
     }
 }
 
@@ -33,18 +73,68 @@ impl ToBytes for String {
 
 impl ToBytes for usize {
     fn to_bytes(&self) -> Vec<u8> {
-        self.to_be_bytes().to_vec()
+        wire::encode_u64(*self as u64)
     }
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let cli = Cli::parse();
+    if cli.no_color {
+        colored::control::set_override(false);
+    }
+    //the node doesn't drop duplicate requests yet, so increments and appends are never resent
+    let mut policy = RetryPolicy::builder().dedup(false);
+    if cli.no_retry {
+        policy = policy.reads(Backoff::NEVER).writes(Backoff::NEVER);
+    }
+    let policy = policy
+        .deadline(std::time::Duration::from_millis(cli.deadline_ms))
+        .priority(cli.priority);
+    retry::install(policy.build());
+
+    //completions don't need a node, so they are handled before connecting
+    if let Some(Commands::Completions { shell }) = cli.command {
+        //completions have to be registered under the binary's name, not the "mergeDB" display name
+        clap_complete::generate(shell, &mut Cli::command(), env!("CARGO_BIN_NAME"), &mut stdout());
+        return Ok(());
+    }
+
+    if let Some(path) = cli.offline {
+        let dump = offline::Dump::load(&path)?;
+        let input = match cli.command {
+            Some(Commands::Interactive) | None => {
+                offline::run_interactive(&dump, &path)?;
+                return Ok(());
+            }
+            Some(Commands::Cget { key }) => format!("CGET {}", key),
+            Some(Commands::Sget { key, .. }) => format!("SGET {}", key),
+            Some(Commands::Rget { key }) => format!("RGET {}", key),
+            Some(Commands::Rlen { key }) => format!("RLEN {}", key),
+            Some(Commands::Scan { selector, prefix }) if selector.is_empty() => {
+                format!("SCAN {}", prefix)
+            }
+            Some(Commands::Dbsize) => "DBSIZE".to_string(),
+            Some(_) => {
+                return Err(
+                    "only CGET, SGET, RGET, RLEN, SCAN --prefix and DBSIZE work offline".into(),
+                )
+            }
+        };
+        offline::show(dump.answer(&input));
+        return Ok(());
+    }
 
     let addr = cli.addr.unwrap_or_else(|| "127.0.0.1:8000".to_string());
 
-    let endpoint = format!("http://{}", addr);
-    let mut client = ReplicationServiceClient::connect(endpoint.clone()).await?;
+    let connecting = std::time::Instant::now();
+    let mut client = match cli.uds {
+        Some(uds_path) => connect_uds(uds_path).await?,
+        None => ReplicationServiceClient::connect(endpoint_for(&addr)).await?,
+    };
+    if cli.verbose {
+        timing::enable(connecting.elapsed());
+    }
 
     match cli.command {
         Some(Commands::Interactive) | None => {
@@ -67,20 +157,41 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         Some(Commands::Cdec { key, amount }) => {
             send_request(&mut client, "CDEC", &key, Some(amount)).await?;
         }
+
+        Some(Commands::Cshard { key, shards }) => {
+            send_request(&mut client, "CSHARD", &key, Some(shards.to_string())).await?;
+        }
         
-        Some(Commands::Sadd { key, tag }) => {
+        Some(Commands::Sadd { key, tag, encoding, element_type }) => {
+            let tag = binary::tag(&tag, encoding, &element_type)?;
             send_request(&mut client, "SADD", &key, Some(tag)).await?;
         }
         
-        Some(Commands::Srem { key, tag }) => {
+        Some(Commands::Srem { key, tag, dry_run: true, encoding, element_type }) => {
+            dry_run(&mut client, "SREM", &key, binary::tag(&tag, encoding, &element_type)?).await?;
+        }
+
+        Some(Commands::Srem { key, tag, dry_run: false, encoding, element_type }) => {
+            let tag = binary::tag(&tag, encoding, &element_type)?;
             send_request(&mut client, "SREM", &key, Some(tag)).await?;
         }
         
-        Some(Commands::Sget { key }) => {
-            send_request::<String>(&mut client, "SGET", &key, None).await?;
+        Some(Commands::Sget { key, sort }) => {
+            send_request(&mut client, "SGET", &key, sort).await?;
+        }
+        
+        Some(Commands::Smeta { key, tag, encoding, element_type }) => {
+            let tag = binary::tag(&tag, encoding, &element_type)?;
+            send_request(&mut client, "SMETA", &key, Some(tag)).await?;
         }
         
-        Some(Commands::Rset { key, register }) => {
+        Some(Commands::Slimit { key, limit }) => {
+            let limit = limit.map(|limit| limit.to_string()).unwrap_or_default();
+            send_request(&mut client, "SLIMIT", &key, Some(limit)).await?;
+        }
+
+        Some(Commands::Rset { key, register, encoding }) => {
+            let register = binary::encode(&register, encoding)?;
             send_request(&mut client, "RSET", &key, Some(register)).await?;
         }
         
@@ -95,132 +206,1226 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         Some(Commands::Rlen { key }) => {
             send_request::<usize>(&mut client, "RLEN", &key, None).await?;
         }
+
+        Some(Commands::Info { section }) => {
+            send_request(&mut client, "INFO", "", section).await?;
+        }
+
+        Some(Commands::Dbsize) => {
+            send_request::<String>(&mut client, "DBSIZE", "", None).await?;
+        }
+
+        Some(Commands::Freeze { key }) => {
+            send_request::<String>(&mut client, "FREEZE", &key, None).await?;
+        }
+
+        Some(Commands::Thaw { key }) => {
+            send_request::<String>(&mut client, "THAW", &key, None).await?;
+        }
+
+        Some(Commands::Copy { src, dst }) => {
+            send_request(&mut client, "COPY", &src, Some(dst)).await?;
+        }
+
+        Some(Commands::Rename { src, dst }) => {
+            send_request(&mut client, "RENAME", &src, Some(dst)).await?;
+        }
+
+        Some(Commands::Setting { name, value }) => {
+            send_request(&mut client, "SETTING", &name, Some(value.unwrap_or_default())).await?;
+        }
+
+        Some(Commands::Settings) => {
+            send_request::<String>(&mut client, "SETTINGS", "", None).await?;
+        }
+
+        Some(Commands::Hotkeys { n }) => {
+            send_request(&mut client, "HOTKEYS", "", n.map(|n| n.to_string())).await?;
+        }
+
+        Some(Commands::Contention { n }) => {
+            send_request(&mut client, "CONTENTION", "", n.map(|n| n.to_string())).await?;
+        }
+
+        Some(Commands::Audit) => {
+            send_request::<String>(&mut client, "AUDIT", "", None).await?;
+        }
+
+        Some(Commands::Ping { count, all }) => {
+            ping(&mut client, count, all).await?;
+        }
+
+        Some(Commands::Materialize { prefix, path }) => {
+            send_request(&mut client, "MATERIALIZE", &prefix.unwrap_or_default(), path).await?;
+        }
+
+        Some(Commands::Unmaterialize { path }) => {
+            send_request::<String>(&mut client, "UNMATERIALIZE", &path, None).await?;
+        }
+
+        Some(Commands::Label { key, labels }) => {
+            send_request(&mut client, "LABEL", &key, Some(labels)).await?;
+        }
+
+        Some(Commands::Labels { key }) => {
+            send_request::<String>(&mut client, "LABELS", &key, None).await?;
+        }
+
+        Some(Commands::Scan { selector, prefix }) => {
+            send_request(&mut client, "SCAN", &prefix, Some(selector)).await?;
+        }
+
+        Some(Commands::Export { start, end, slots, batch, output }) => {
+            let (slots, slot_start, slot_end) = match slots {
+                Some(slots) => {
+                    let (slot_start, slot_end) = export::parse_slots(&slots)?;
+                    (true, slot_start, slot_end)
+                }
+                None => (false, 0, 0),
+            };
+            let request = communication::ExportRequest {
+                start,
+                end,
+                slots,
+                slot_start,
+                slot_end,
+                batch,
+            };
+            let mut out: Box<dyn std::io::Write> = match &output {
+                Some(path) => Box::new(std::io::BufWriter::new(std::fs::File::create(path)?)),
+                None => Box::new(std::io::BufWriter::new(stdout().lock())),
+            };
+            let written = export::run(&mut client, request, &mut *out).await?;
+            eprintln!("{}", format!("✓ exported {} keys", written).green());
+        }
+
+        Some(Commands::Eval { script, file }) => {
+            let script = match file {
+                Some(path) => std::fs::read_to_string(path)?,
+                None => script.unwrap_or_default(),
+            };
+            send_request(&mut client, "EVAL", "", Some(script)).await?;
+        }
+
+        Some(Commands::Top { keys, prefix, interval_ms }) => {
+            //no keys and no prefix means everything on the node
+            let watch = match prefix {
+                Some(prefix) => top::Watch::Prefix(prefix),
+                None if keys.is_empty() => top::Watch::Prefix(String::new()),
+                None => top::Watch::Keys(keys),
+            };
+            top::run(client, addr, watch, std::time::Duration::from_millis(interval_ms)).await?;
+        }
+
+        Some(Commands::Cluster { action }) => match action {
+            ClusterCommands::Status => cluster_status(&mut client).await?,
+            ClusterCommands::Peers => cluster_peers(&mut client).await?,
+            ClusterCommands::Decommission { node_id, timeout_secs } => {
+                cluster_decommission(&mut client, &node_id, timeout_secs).await?
+            }
+            ClusterCommands::Pause { reason, node } => {
+                cluster_pause(&mut client, Some(reason), !node).await?
+            }
+            ClusterCommands::Resume { node } => cluster_pause(&mut client, None, !node).await?,
+        },
+
+        Some(Commands::Node { action }) => match action {
+            NodeCommands::Status => node_status(&mut client).await?,
+        },
+
+        Some(Commands::Completions { .. }) => unreachable!("handled before connecting"),
     }
 
     Ok(())
 }
 
+//turns whatever was given to --addr into an endpoint uri: hostnames, ipv4, bracketed or bare ipv6
+//are all accepted, and the default port is assumed when none is given
+fn endpoint_for(addr: &str) -> String {
+    if addr.contains("://") {
+        return addr.to_string();
+    }
+    if let Ok(ip) = addr.parse::<Ipv6Addr>() {
+        return format!("http://[{}]:8000", ip);
+    }
+    if let Ok(socket_addr) = addr.parse::<SocketAddr>() {
+        return format!("http://{}", socket_addr);
+    }
+    if !addr.contains(':') {
+        return format!("http://{}:8000", addr);
+    }
+    format!("http://{}", addr)
+}
+
+async fn connect_uds(
+    uds_path: PathBuf,
+) -> Result<ReplicationServiceClient<Channel>, Box<dyn std::error::Error>> {
+    //the uri is never dialed, the connector below ignores it, but tonic needs a valid one
+    let channel = Endpoint::try_from("http://[::]:8000")?
+        .connect_with_connector(service_fn(move |_: Uri| UnixStream::connect(uds_path.clone())))
+        .await?;
+    Ok(ReplicationServiceClient::new(channel))
+}
+
+//with --verbose, where the time went and what the node answered is printed after the result
 async fn send_request<T>(
     client: &mut ReplicationServiceClient<tonic::transport::Channel>,
     cmd: &str,
     key: &str,
     value: Option<T>,
+) -> Result<(), Box<dyn std::error::Error>>
+where
+    T: ToBytes + Debug,
+{
+    let mut timing = Timing::start();
+    let result = request_and_show(client, cmd, key, value, &mut timing).await;
+    if timing::enabled() {
+        println!("{}", format!("   {}", timing.summary()).dimmed());
+        println!("{}", format!("   status {}", timing.status()).dimmed());
+    }
+    result
+}
+
+async fn request_and_show<T>(
+    client: &mut ReplicationServiceClient<tonic::transport::Channel>,
+    cmd: &str,
+    key: &str,
+    value: Option<T>,
+    timing: &mut Timing,
 ) -> Result<(), Box<dyn std::error::Error>> 
 where 
     T: ToBytes + Debug,
 {
-    let bytes = value.map(|v| v.to_bytes()).unwrap_or_default();
+    let bytes = value.as_ref().map(|v| v.to_bytes()).unwrap_or_default();
+    let encoding = value.as_ref().map(|v| v.encoding()).unwrap_or_default();
+    let element_type = value.as_ref().map(|v| v.element_type()).unwrap_or_default();
+    let amount = value.as_ref().and_then(|v| v.amount());
 
-    let request = Request::new(PropagateDataRequest {
+    let request = PropagateDataRequest {
         valuetype: cmd.to_string(),
         key: key.to_string(),
         value: bytes,
-    }); 
+        dry_run: false,
+        encoding: encoding.to_string(),
+        element_type: element_type.to_string(),
+        amount,
+    };
+
+    timing.sending();
+    let response = retry::policy()
+        .run(retry::class_of(cmd), || {
+            let mut client = client.clone();
+            let request = retry::policy().request(request.clone());
+            async move { client.propagate_data(request).await }
+        })
+        .await;
+    timing.answered(&response);
+    let inner = response?.into_inner();
+    timing.note(&format!("success={}, seq={}", inner.success, inner.seq));
 
-    let response = client.propagate_data(request).await?;
-    let inner = response.into_inner();
+    //a refused command carries no payload, decoding it would only produce a made up zero
+    if !inner.success {
+        return Err(format!("{} was refused by the node (wrong type for this key?)", cmd).into());
+    }
     
     if cmd == "CGET" {
-        let raw = inner.response;
-        let val = i64::from_be_bytes(raw.try_into().unwrap_or([0; 8]));
+        let val = wire::decode_signed(inner.number, &inner.response)?;
         println!("{}", format!(":: {}", val).cyan());
     } else if cmd == "SGET" {
-        //has been serialised by json then converted to string then to be_bytes,
+        //a json list of elements, in the node's order when it was asked to sort them
         let raw = inner.response;
-        let val: Vec<String> = serde_json::from_slice(&raw).expect("failed to desrialise");
-        println!("{}", format!(":: {:?}", val).cyan());
+        let val: Vec<serde_json::Value> = wire::decode_json(&raw)?;
+        let rows: Vec<Vec<String>> = display::members(val, !request.value.is_empty())
+            .into_iter()
+            .map(|member| vec![member])
+            .collect();
+        display::print_table(&["member"], &rows);
     }else if cmd == "RGET" {
         let raw = inner.response;
-        let val = match str::from_utf8(&raw) {
-            Ok(v) => v,
-            Err(_) => "failed to convert to utf8: {}",
-        };
-        println!("{}", format!(":: {:?}", val).cyan());
+        let val = wire::decode_string(raw)?;
+        let shown = display::binary_value(&val).unwrap_or_else(|| format!("{:?}", val));
+        println!("{}", format!(":: {}", shown).cyan());
     }else if cmd == "RLEN" {
         let raw = inner.response;
-        let val = usize::from_be_bytes(raw.try_into().unwrap_or([0; 8]));
+        let val = wire::decode_u64(&raw)?;
+        println!("{}", format!(":: {}", val).cyan());
+    } else if cmd == "SMETA" {
+        //one entry per add that keeps the tag visible
+        let raw = inner.response;
+        let meta: Vec<serde_json::Value> = wire::decode_json(&raw)?;
+        let rows: Vec<Vec<String>> = meta
+            .iter()
+            .map(|entry| {
+                vec![
+                    entry["added_by"].as_str().unwrap_or_default().to_string(),
+                    entry["counter"].to_string(),
+                    entry["added_at_ms"].to_string(),
+                    entry["added_at"].to_string(),
+                ]
+            })
+            .collect();
+        display::print_table(&["added_by", "counter", "added_at_ms", "hlc"], &rows);
+    } else if cmd == "EVAL" {
+        //whatever the script returned, as json
+        let raw = inner.response;
+        let val: serde_json::Value = wire::decode_json(&raw)?;
+        let line = format!(":: {}", val).cyan();
+        match inner.seq {
+            0 => println!("{}", line),
+            seq => println!("{} {}", line, format!("(seq {})", seq).dimmed()),
+        }
+    } else if cmd == "SETTINGS" {
+        //{name: {value, source}}
+        let raw = inner.response;
+        let settings: BTreeMap<String, serde_json::Value> = wire::decode_json(&raw)?;
+        let rows: Vec<Vec<String>> = settings
+            .iter()
+            .map(|(name, setting)| {
+                vec![
+                    name.clone(),
+                    setting["value"].as_str().unwrap_or_default().to_string(),
+                    setting["source"].as_str().unwrap_or_default().to_string(),
+                ]
+            })
+            .collect();
+        display::print_table(&["setting", "value", "source"], &rows);
+    } else if cmd == "HOTKEYS" {
+        //[{key, reads, writes}], hottest first
+        let raw = inner.response;
+        let hot: Vec<serde_json::Value> = wire::decode_json(&raw)?;
+        let rows: Vec<Vec<String>> = hot
+            .iter()
+            .map(|hot| {
+                vec![
+                    hot["key"].as_str().unwrap_or_default().to_string(),
+                    hot["reads"].to_string(),
+                    hot["writes"].to_string(),
+                ]
+            })
+            .collect();
+        display::print_table(&["key", "~reads", "~writes"], &rows);
+    } else if cmd == "CONTENTION" {
+        //[{key, waits, wait_total_us, wait_max_us, peak_writers}], longest waited first
+        let raw = inner.response;
+        let worst: Vec<serde_json::Value> = wire::decode_json(&raw)?;
+        let ms = |us: &serde_json::Value| {
+            format!("{:.3} ms", us.as_u64().unwrap_or(0) as f64 / 1_000.0)
+        };
+        let rows: Vec<Vec<String>> = worst
+            .iter()
+            .map(|stats| {
+                vec![
+                    stats["key"].as_str().unwrap_or_default().to_string(),
+                    stats["waits"].to_string(),
+                    ms(&stats["wait_total_us"]),
+                    ms(&stats["wait_max_us"]),
+                    stats["peak_writers"].to_string(),
+                ]
+            })
+            .collect();
+        display::print_table(&["key", "waits", "waited", "longest wait", "peak writers"], &rows);
+    } else if cmd == "AUDIT" {
+        //{stalled_after_secs, stalled, keys: [{key, behind_secs}]}, the longest stalled first
+        let raw = inner.response;
+        let audit: serde_json::Value = wire::decode_json(&raw)?;
+        let stalled = audit["stalled"].as_u64().unwrap_or_default();
+        let after = audit["stalled_after_secs"].as_u64().unwrap_or_default();
+        if stalled == 0 {
+            println!("{}", format!(":: no key stalled for {}s or longer", after).green());
+        } else {
+            let keys = audit["keys"].as_array().cloned().unwrap_or_default();
+            let rows: Vec<Vec<String>> = keys
+                .iter()
+                .map(|stall| {
+                    vec![
+                        stall["key"].as_str().unwrap_or_default().to_string(),
+                        format!("{}s", stall["behind_secs"]),
+                    ]
+                })
+                .collect();
+            display::print_table(&["key", "untaken for"], &rows);
+            let summary = format!("{} keys no peer has taken for {}s or longer", stalled, after);
+            println!("{}", summary.yellow());
+        }
+    } else if cmd == "MATERIALIZE" || cmd == "UNMATERIALIZE" {
+        //[{prefix, path, format}], the exports still running
+        let raw = inner.response;
+        let exports: Vec<serde_json::Value> = wire::decode_json(&raw)?;
+        let rows: Vec<Vec<String>> = exports
+            .iter()
+            .map(|export| {
+                vec![
+                    export["prefix"].as_str().unwrap_or_default().to_string(),
+                    export["path"].as_str().unwrap_or_default().to_string(),
+                    export["format"].as_str().unwrap_or_default().to_string(),
+                ]
+            })
+            .collect();
+        display::print_table(&["prefix", "path", "format"], &rows);
+    } else if cmd == "SLIMIT" {
+        //{limit, evicted}, what went over the new limit
+        let raw = inner.response;
+        let report: serde_json::Value = wire::decode_json(&raw)?;
+        let limit = match report["limit"].as_u64() {
+            Some(limit) => format!("limited to {}", limit),
+            None => "no limit".to_string(),
+        };
+        let evicted = match report["evicted"].as_array() {
+            Some(evicted) if !evicted.is_empty() => {
+                format!(", evicted {}", display::members(evicted.clone(), true).join(", "))
+            }
+            _ => String::new(),
+        };
+        println!("{}", format!(":: {}{}", limit, evicted).cyan());
+    } else if cmd == "LABEL" || cmd == "LABELS" {
+        //{name: value}, all the key's labels
+        let raw = inner.response;
+        let labels: BTreeMap<String, String> = wire::decode_json(&raw)?;
+        let rows: Vec<Vec<String>> = labels
+            .into_iter()
+            .map(|(name, value)| vec![name, value])
+            .collect();
+        display::print_table(&["label", "value"], &rows);
+    } else if cmd == "SCAN" {
+        let raw = inner.response;
+        let keys: Vec<String> = wire::decode_json(&raw)?;
+        let rows: Vec<Vec<String>> = keys.into_iter().map(|key| vec![key]).collect();
+        display::print_table(&["key"], &rows);
+    } else if cmd == "DBSIZE" {
+        let raw = inner.response;
+        let val = wire::decode_u64(&raw)?;
         println!("{}", format!(":: {}", val).cyan());
+    } else if cmd == "INFO" {
+        //{section: {field: value}}, printed redis style
+        let raw = inner.response;
+        let sections: BTreeMap<String, BTreeMap<String, serde_json::Value>> =
+            wire::decode_json(&raw)?;
+        let mut rows = Vec::new();
+        for (section, fields) in sections {
+            for (field, value) in fields {
+                let value = match value {
+                    serde_json::Value::String(s) => s,
+                    other => other.to_string(),
+                };
+                rows.push(vec![section.clone(), field, value]);
+            }
+        }
+        display::print_table(&["section", "field", "value"], &rows);
     }
     else {
-        println!("{}", "✓ OK".green());
+        let ok = match display::written(cmd, inner.number) {
+            Some(left) => format!("{} {}", "✓ OK".green(), format!(":: {}", left).cyan()),
+            None => "✓ OK".green().to_string(),
+        };
+        let ok = match inner.seq {
+            0 => ok,
+            //the node's commit sequence number, orders writes made against the same node
+            seq => format!("{} {}", ok, format!("(seq {})", seq).dimmed()),
+        };
+        match &inner.replicas {
+            Some(replicas) if !replicas.failed.is_empty() => {
+                println!("{} {}", ok, display::replicated(replicas).yellow())
+            }
+            Some(replicas) => println!("{} {}", ok, display::replicated(replicas).dimmed()),
+            None => println!("{}", ok),
+        }
     }
 
     Ok(())
 }
 
+//asks the node what a destructive command would change, nothing gets applied
+async fn dry_run<T: ToBytes>(
+    client: &mut ReplicationServiceClient<tonic::transport::Channel>,
+    cmd: &str,
+    key: &str,
+    value: T,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let request = PropagateDataRequest {
+        valuetype: cmd.to_string(),
+        key: key.to_string(),
+        value: value.to_bytes(),
+        dry_run: true,
+        encoding: value.encoding().to_string(),
+        element_type: value.element_type().to_string(),
+        amount: value.amount(),
+    };
+    //nothing is applied, so it retries like a read
+    let inner = retry::policy()
+        .run(retry::CommandClass::Read, || {
+            let mut client = client.clone();
+            let request = retry::policy().request(request.clone());
+            async move { client.propagate_data(request).await }
+        })
+        .await?
+        .into_inner();
+    let report: BTreeMap<String, serde_json::Value> = wire::decode_json(&inner.response)?;
+
+    let rows: Vec<Vec<String>> = report
+        .into_iter()
+        .map(|(field, value)| {
+            let value = match value {
+                serde_json::Value::Array(items) => items
+                    .iter()
+                    .map(|item| match item.as_str() {
+                        Some(member) => display::binary_value(member).unwrap_or(member.to_string()),
+                        None => wire::element_from_json(item)
+                            .map_or_else(|| item.to_string(), |member| member.to_string()),
+                    })
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                serde_json::Value::String(s) => s,
+                other => other.to_string(),
+            };
+            vec![field, value]
+        })
+        .collect();
+    display::print_table(&["field", "value"], &rows);
+    println!("{}", "dry run, nothing was changed".yellow());
+    Ok(())
+}
+
+//sent to the node being decommissioned itself, found through the connected node's membership.
+//progress is printed as the node reports it, until it exits
+async fn cluster_decommission(
+    client: &mut ReplicationServiceClient<tonic::transport::Channel>,
+    node_id: &str,
+    timeout_secs: u64,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let status = client
+        .cluster_status(Request::new(ClusterStatusRequest {}))
+        .await?
+        .into_inner();
+    let mut target = if status.node_id == node_id {
+        client.clone()
+    } else {
+        let Some(member) = status.members.iter().find(|member| member.node_id == node_id) else {
+            return Err(format!("{} is not a member {} knows of", node_id, status.node_id).into());
+        };
+        if member.address.is_empty() {
+            return Err(format!("{} doesn't know where {} is, use --addr", status.node_id, node_id)
+                .into());
+        }
+        println!("{}", format!(":: connecting to {} at {}", node_id, member.address).dimmed());
+        ReplicationServiceClient::connect(endpoint_for(&member.address)).await?
+    };
+
+    let request = Request::new(DecommissionRequest {
+        node_id: node_id.to_string(),
+        timeout_secs,
+    });
+    let mut progress = target.decommission(request).await?.into_inner();
+    let mut done = false;
+    while let Some(step) = progress.message().await? {
+        println!("{} {}", format!("[{}]", step.stage).cyan(), step.message);
+        done = step.stage == "done";
+    }
+    if !done {
+        return Err(format!("lost track of {} before it was done", node_id).into());
+    }
+    println!("{}", format!("✓ {} was decommissioned", node_id).green());
+    Ok(())
+}
+
+//Some(reason) pauses client writes, None resumes them. either is safe to send twice
+async fn cluster_pause(
+    client: &mut ReplicationServiceClient<tonic::transport::Channel>,
+    reason: Option<String>,
+    cluster: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let response = retry::policy()
+        .run(retry::CommandClass::IdempotentWrite, || {
+            let mut client = client.clone();
+            let reason = reason.clone();
+            async move {
+                match reason {
+                    Some(reason) => {
+                        client.pause(Request::new(PauseRequest { reason, cluster })).await
+                    }
+                    None => client.resume(Request::new(ResumeRequest { cluster })).await,
+                }
+            }
+        })
+        .await?
+        .into_inner();
+
+    let scope = if cluster { "the cluster".to_string() } else { response.node_id.clone() };
+    let resumed = reason.is_none();
+    let done = match reason {
+        Some(_) => format!("✓ {} is paused", scope),
+        None => format!("✓ {} was resumed", scope),
+    };
+    match response.seq {
+        0 => println!("{}", done.green()),
+        seq => println!("{} {}", done.green(), format!("(seq {})", seq).dimmed()),
+    }
+    //eg a node resumed while the cluster wide pause is still on
+    if resumed && !response.paused.is_empty() {
+        println!(
+            "{}",
+            format!("{} still refuses writes: {}", response.node_id, response.paused).yellow()
+        );
+    }
+    Ok(())
+}
+
+//the wal is counted in bytes, the warm-up in keys
+async fn node_status(
+    client: &mut ReplicationServiceClient<tonic::transport::Channel>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let status = retry::policy()
+        .run(retry::CommandClass::Read, || {
+            let mut client = client.clone();
+            async move { client.node_status(Request::new(NodeStatusRequest {})).await }
+        })
+        .await?
+        .into_inner();
+
+    let amount = |n: u64| match status.stage.as_str() {
+        "wal" => format!("{} bytes", n),
+        _ => n.to_string(),
+    };
+    let mut rows = vec![
+        vec!["node_id".to_string(), status.node_id.clone()],
+        vec!["stage".to_string(), status.stage.clone()],
+    ];
+    if status.stage != "ready" {
+        rows.push(vec!["entries".to_string(), status.entries.to_string()]);
+        rows.push(vec![
+            "progress".to_string(),
+            format!("{} / {}", amount(status.done), amount(status.total)),
+        ]);
+        rows.push(vec![
+            "elapsed".to_string(),
+            format!("{}s", status.elapsed_ms / 1000),
+        ]);
+        rows.push(vec![
+            "eta".to_string(),
+            status.eta_secs.map_or("-".to_string(), |secs| format!("{}s", secs)),
+        ]);
+    }
+    if !status.paused.is_empty() {
+        rows.push(vec!["paused".to_string(), status.paused.clone()]);
+    }
+    display::print_table(&["field", "value"], &rows);
+    Ok(())
+}
+
+async fn cluster_status(
+    client: &mut ReplicationServiceClient<tonic::transport::Channel>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let status = retry::policy()
+        .run(retry::CommandClass::Read, || {
+            let mut client = client.clone();
+            async move { client.cluster_status(Request::new(ClusterStatusRequest {})).await }
+        })
+        .await?
+        .into_inner();
+
+    //equal fingerprints on two nodes mean they hold the same data
+    let fingerprint = retry::policy()
+        .run(retry::CommandClass::Read, || {
+            let mut client = client.clone();
+            async move { client.fingerprint(Request::new(FingerprintRequest {})).await }
+        })
+        .await?
+        .into_inner();
+
+    println!("{}", format!(":: node {}", status.node_id).cyan());
+    println!(
+        "   fingerprint {:016x} over {} keys (commit seq {})",
+        fingerprint.fingerprint, fingerprint.keys, fingerprint.commit_seq
+    );
+    let rows: Vec<Vec<String>> = status
+        .members
+        .iter()
+        .map(|member| {
+            let state = if member.reachable {
+                "reachable"
+            } else {
+                "unreachable"
+            };
+            vec![
+                member.node_id.clone(),
+                member.address.clone(),
+                state.to_string(),
+                member.last_seen_ms.to_string(),
+                member.format_version.to_string(),
+                if member.witness { "witness" } else { "data" }.to_string(),
+            ]
+        })
+        .collect();
+    display::print_table(
+        &["node_id", "address", "state", "last_seen_ms", "format", "role"],
+        &rows,
+    );
+
+    if status.split_brain {
+        println!(
+            "{}",
+            format!(
+                "!! split-brain: {:?} cannot reach {:?} for {}s",
+                status.local_group, status.remote_group, status.partitioned_for_secs
+            )
+            .red()
+            .bold()
+        );
+        //what a witness is there for, telling the side that should keep going
+        match status.majority {
+            true => println!("   this side holds the majority"),
+            false => println!("{}", "   this side is in the minority".yellow()),
+        }
+    } else {
+        println!("{}", "✓ no split-brain".green());
+    }
+    if let Some(diverged) = status.last_divergence_estimate {
+        println!("   {} keys changed on this side during the last partition", diverged);
+    }
+
+    Ok(())
+}
+
+//the node's latency histograms are bucketed, so these are bucket upper bounds
+fn format_latency(us: u64, samples: u64) -> String {
+    match samples {
+        0 => "-".to_string(),
+        _ if us >= 1_000_000 => format!("{:.1}s", us as f64 / 1_000_000.0),
+        _ if us >= 1_000 => format!("{:.1}ms", us as f64 / 1_000.0),
+        _ => format!("{}µs", us),
+    }
+}
+
+async fn cluster_peers(
+    client: &mut ReplicationServiceClient<tonic::transport::Channel>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let status = retry::policy()
+        .run(retry::CommandClass::Read, || {
+            let mut client = client.clone();
+            async move { client.cluster_status(Request::new(ClusterStatusRequest {})).await }
+        })
+        .await?
+        .into_inner();
+
+    println!("{}", format!(":: gossip latency from {}", status.node_id).cyan());
+    let rows: Vec<Vec<String>> = status
+        .members
+        .iter()
+        .map(|member| {
+            let samples = member.latency_samples;
+            vec![
+                member.node_id.clone(),
+                member.address.clone(),
+                if member.reachable { "reachable" } else { "unreachable" }.to_string(),
+                samples.to_string(),
+                format_latency(member.latency_p50_us, samples),
+                format_latency(member.latency_p95_us, samples),
+                format_latency(member.latency_p99_us, samples),
+            ]
+        })
+        .collect();
+    display::print_table(
+        &["node_id", "address", "state", "rpcs", "p50", "p95", "p99"],
+        &rows,
+    );
+    Ok(())
+}
+
+//round trips to the node, sent one after the other and not retried, a lost one is an error.
+//with `all` each ping also has the node ping its peers, whose times are listed per peer
+async fn ping(
+    client: &mut ReplicationServiceClient<tonic::transport::Channel>,
+    count: u32,
+    all: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut node_id = String::new();
+    let mut rtts_us = Vec::new();
+    //address -> (node_id, round trips, pings it missed)
+    let mut peers: BTreeMap<String, (String, Vec<u64>, u32)> = BTreeMap::new();
+    for _ in 0..count {
+        let started = std::time::Instant::now();
+        let pong = client
+            .ping(Request::new(PingRequest { peers: all }))
+            .await?
+            .into_inner();
+        rtts_us.push(started.elapsed().as_micros() as u64);
+        node_id = pong.node_id;
+        for peer in pong.peers {
+            let (peer_id, rtts, missed) = peers.entry(peer.address).or_default();
+            match peer.error.is_empty() {
+                true => {
+                    *peer_id = peer.node_id;
+                    rtts.push(peer.rtt_us);
+                }
+                false => *missed += 1,
+            }
+        }
+    }
+
+    println!(
+        "{}",
+        format!(
+            ":: {} replies from {}, min/avg/max {}",
+            rtts_us.len(),
+            node_id,
+            display::min_avg_max(&rtts_us)
+        )
+        .cyan()
+    );
+    if !all {
+        return Ok(());
+    }
+    let rows: Vec<Vec<String>> = peers
+        .into_iter()
+        .map(|(address, (peer_id, rtts, missed))| {
+            vec![
+                address,
+                peer_id,
+                rtts.len().to_string(),
+                missed.to_string(),
+                display::min_avg_max(&rtts),
+            ]
+        })
+        .collect();
+    display::print_table(
+        &["address", "node_id", "replies", "missed", "min/avg/max"],
+        &rows,
+    );
+    Ok(())
+}
+
 async fn run_interactive(mut client: ReplicationServiceClient<tonic::transport::Channel>) -> Result<()>{
-    loop {
-        crate::display::show_prompt();
+    let rc_path = rc::default_path();
+    let mut rc = match rc_path.as_deref().map(Rc::load) {
+        Some(Ok(rc)) => rc,
+        Some(Err(e)) => {
+            println!("{}", format!("ignoring ~/.mergedbrc: {:#}", e).red());
+            Rc::default()
+        }
+        None => Rc::default(),
+    };
+    let mut hints = TypeHints::default();
+    //Some between `pipe` and `end`, commands are queued instead of sent
+    let mut pipeline: Option<Pipeline> = None;
+
+    'repl: loop {
+        match pipeline {
+            Some(_) => crate::display::show_pipe_prompt(),
+            None => crate::display::show_prompt(),
+        }
 
         let mut input = String::new();
-        stdin().read_line(&mut input)?;
-        let parts: Vec<&str> = input.split_whitespace().collect();
+        if stdin().read_line(&mut input)? == 0 {
+            break;
+        }
+        let input = input.trim();
+        let word = input.split_whitespace().next().unwrap_or("").to_lowercase();
+
+        if matches!(word.as_str(), "alias" | "macro" | "unalias" | "unmacro") {
+            manage_rc(&mut rc, rc_path.as_deref(), &word, input);
+            continue;
+        }
 
-        if parts.is_empty() {
+        if pipeline.is_none() && word == "pipe" && input.eq_ignore_ascii_case("pipe") {
+            println!("{}", "queueing commands, `end` sends them".dimmed());
+            pipeline = Some(Pipeline::default());
             continue;
         }
+        if word == "end" {
+            if let Some(queued) = pipeline.take() {
+                run_pipeline(&mut client, queued).await;
+                continue;
+            }
+        }
+
+        let commands = match rc.expand(input) {
+            Ok(commands) => commands,
+            Err(e) => {
+                println!("{}", e.to_string().red());
+                continue;
+            }
+        };
+        if let Some(queued) = pipeline.as_mut() {
+            for command in commands {
+                if let Err(e) = queued.queue(&command) {
+                    println!("{}", e.red());
+                }
+            }
+            continue;
+        }
+        for command in commands {
+            if !run_command(&mut client, &mut hints, &command).await {
+                break 'repl;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+//sends a `pipe` block in one round trip and prints every result next to its command
+async fn run_pipeline(
+    client: &mut ReplicationServiceClient<tonic::transport::Channel>,
+    pipeline: Pipeline,
+) {
+    if pipeline.queued.is_empty() {
+        println!("{}", "nothing was queued".dimmed());
+        return;
+    }
+    match pipeline.send(client).await {
+        Ok(results) => {
+            let rows: Vec<Vec<String>> = results
+                .into_iter()
+                .enumerate()
+                .map(|(i, (input, result))| vec![(i + 1).to_string(), input, result])
+                .collect();
+            display::print_table(&["#", "command", "result"], &rows);
+        }
+        Err(e) => println!("{}", format!("error: {}", e.message()).red()),
+    }
+}
+
+//alias/macro with no arguments lists them, with a definition they add one, unalias/unmacro
+//remove one. changes are written back to ~/.mergedbrc straight away
+fn manage_rc(rc: &mut Rc, rc_path: Option<&std::path::Path>, word: &str, input: &str) {
+    let args = input[word.len()..].trim();
+    let changed = match word {
+        "alias" if args.is_empty() => {
+            let rows: Vec<Vec<String>> = rc
+                .aliases
+                .iter()
+                .map(|(name, body)| vec![name.clone(), body.clone()])
+                .collect();
+            display::print_table(&["alias", "expands to"], &rows);
+            false
+        }
+        "macro" if args.is_empty() => {
+            let rows: Vec<Vec<String>> = rc
+                .macros
+                .iter()
+                .map(|(name, mac)| vec![name.clone(), mac.params.join(" "), mac.body.join("; ")])
+                .collect();
+            display::print_table(&["macro", "params", "commands"], &rows);
+            false
+        }
+        "alias" | "macro" => match rc.define(input) {
+            Ok(()) => true,
+            Err(e) => {
+                println!("{}", e.to_string().red());
+                false
+            }
+        },
+        "unalias" => rc.aliases.remove(args).is_some(),
+        _ => rc.macros.remove(args).is_some(),
+    };
+
+    if !changed {
+        if let Some(kind) = word.strip_prefix("un") {
+            println!("{}", format!("no such {}: {}", kind, args).red());
+        }
+        return;
+    }
+    match rc_path.map(|path| rc.save(path)) {
+        Some(Err(e)) => println!("{}", format!("{:#}", e).red()),
+        _ => println!("{}", "✓ OK".green()),
+    }
+}
+
+//the REPL keeps going after a failed command, but says why it failed. false if it did
+fn report(result: Result<(), Box<dyn std::error::Error>>) -> bool {
+    if let Err(e) = &result {
+        println!("{}", format!("error: {}", e).red());
+    }
+    result.is_ok()
+}
+
+//runs one REPL command, returns false once the user asked to leave
+async fn run_command(
+    client: &mut ReplicationServiceClient<tonic::transport::Channel>,
+    hints: &mut TypeHints,
+    input: &str,
+) -> bool {
+    let parts: Vec<&str> = input.split_whitespace().collect();
+
+    if parts.is_empty() {
+        return true;
+    }
+
+    let cmd = parts[0].to_uppercase();
+    if parts.len() >= 2 {
+        //wrong type for the key, no point sending it
+        if let Err(hint) = hints.check(client, &cmd, parts[1]).await {
+            println!("{}", hint.red());
+            return true;
+        }
+    }
+
+    match cmd.as_str() {
+        "HELP" if parts.len() == 1 => {
+            println!("{}", "Commands:".bold());
+            for usage in usage::COMMANDS {
+                println!("  {}", usage.synopsis);
+            }
+            println!("{}", "HELP <command> for details and examples".dimmed());
+        }
+
+        "HELP" => match usage::lookup(&parts[1..].join(" ")) {
+            Some(usage) => print!("{}", usage::render(usage)),
+            None => println!("{}", format!("no such command: {}", parts[1..].join(" ")).red()),
+        },
+
+        "EXIT" | "QUIT" => {
+            println!("{}", "Goodbye!".blue().bold());
+            return false;
+        }
+
+        "CGET" if parts.len() == 2 => {
+            if !report(send_request::<i64>(client, "CGET", parts[1], None).await) {
+                hints.forget(parts[1]);
+            }
+        }
+        
+        "SGET" if parts.len() == 2 => {
+            if !report(send_request::<String>(client, "SGET", parts[1], None).await) {
+                hints.forget(parts[1]);
+            }
+        }
+
+        "SGET" if parts.len() == 3 => {
+            let order = parts[2].to_string();
+            if !report(send_request(client, "SGET", parts[1], Some(order)).await) {
+                hints.forget(parts[1]);
+            }
+        }
+        
+        "RGET" if parts.len() == 2 => {
+            if !report(send_request::<String>(client, "RGET", parts[1], None).await) {
+                hints.forget(parts[1]);
+            }
+        }
+        
+        "RLEN" if parts.len() == 2 => {
+            if !report(send_request::<usize>(client, "RLEN", parts[1], None).await) {
+                hints.forget(parts[1]);
+            }
+        }
+
+        "INFO" if parts.len() <= 2 => {
+            let section = parts.get(1).map(|s| s.to_string());
+            report(send_request(client, "INFO", "", section).await);
+        }
+
+        "DBSIZE" if parts.len() == 1 => {
+            report(send_request::<String>(client, "DBSIZE", "", None).await);
+        }
+
+        cmd @ ("FREEZE" | "THAW") if parts.len() == 2 => {
+            report(send_request::<String>(client, cmd, parts[1], None).await);
+        }
+
+        cmd @ ("COPY" | "RENAME") if parts.len() == 3 => {
+            report(send_request(client, cmd, parts[1], Some(parts[2].to_string())).await);
+        }
+
+        "SETTING" if parts.len() == 2 || parts.len() == 3 => {
+            let value = parts.get(2).map(|v| v.to_string()).unwrap_or_default();
+            report(send_request(client, "SETTING", parts[1], Some(value)).await);
+        }
+
+        "SETTINGS" if parts.len() == 1 => {
+            report(send_request::<String>(client, "SETTINGS", "", None).await);
+        }
+
+        "HOTKEYS" if parts.len() <= 2 => {
+            let n = parts.get(1).map(|n| n.to_string());
+            report(send_request(client, "HOTKEYS", "", n).await);
+        }
+
+        "CONTENTION" if parts.len() <= 2 => {
+            let n = parts.get(1).map(|n| n.to_string());
+            report(send_request(client, "CONTENTION", "", n).await);
+        }
+
+        "AUDIT" if parts.len() == 1 => {
+            report(send_request::<String>(client, "AUDIT", "", None).await);
+        }
+
+        "PING" if parts.len() <= 3 => {
+            let all = parts[1..].contains(&"--all");
+            let count = match parts[1..].iter().find(|part| **part != "--all") {
+                Some(count) => count.parse::<u32>(),
+                None => Ok(4),
+            };
+            match count {
+                Ok(count) => {
+                    if let Err(e) = ping(client, count, all).await {
+                        println!("{}", format!("ping failed: {}", e).red());
+                    }
+                }
+                Err(_) => println!("{}", "Count must be a whole number".red()),
+            }
+        }
+
+        "MATERIALIZE" if parts.len() == 1 || parts.len() == 3 => {
+            let path = parts.get(2).map(|path| path.to_string());
+            let prefix = parts.get(1).copied().unwrap_or_default();
+            report(send_request(client, "MATERIALIZE", prefix, path).await);
+        }
+
+        "UNMATERIALIZE" if parts.len() == 2 => {
+            report(send_request::<String>(client, "UNMATERIALIZE", parts[1], None).await);
+        }
+
+        "CSHARD" if parts.len() == 3 => {
+            let shards = parts[2].to_string();
+            if !report(send_request(client, "CSHARD", parts[1], Some(shards)).await) {
+                hints.forget(parts[1]);
+            }
+        }
 
-        match parts[0].to_uppercase().as_str() {
-            "HELP" => {
-                println!("{}", "Commands:".bold());
-                println!("  CSET <key> <value>");
-                println!("  CGET <key>");
-                println!("  CINC <key> <amount>");
-                println!("  CDEC <key> <amount>");
-                println!("  SADD <key> <tag>");
-                println!("  SREM <key> <tag>");
-                println!("  SGET <key>");
-                println!("  RSET <key> <register>");
-                println!("  RGET <key>");
-                println!("  RAPP <key> <to_append>");
-                println!("  RLEN <key>");
-                println!("  EXIT");
+        "SLIMIT" if (2..=3).contains(&parts.len()) => {
+            let limit = parts.get(2).copied().unwrap_or_default().to_string();
+            if !report(send_request(client, "SLIMIT", parts[1], Some(limit)).await) {
+                hints.forget(parts[1]);
             }
+        }
+
+        "LABEL" if parts.len() == 3 => {
+            report(send_request(client, "LABEL", parts[1], Some(parts[2].to_string())).await);
+        }
+
+        "LABELS" if parts.len() == 2 => {
+            report(send_request::<String>(client, "LABELS", parts[1], None).await);
+        }
+
+        "SCAN" if parts.len() % 2 == 1 => {
+            let (mut selector, mut prefix) = ("", "");
+            for flag in parts[1..].chunks(2) {
+                match flag[0] {
+                    "--selector" => selector = flag[1],
+                    "--prefix" => prefix = flag[1],
+                    other => {
+                        println!("{}", format!("Unknown SCAN option {}", other).red());
+                        return true;
+                    }
+                }
+            }
+            report(send_request(client, "SCAN", prefix, Some(selector.to_string())).await);
+        }
+
+        "EVAL" if parts.len() >= 2 => {
+            let script = input[parts[0].len()..].trim().to_string();
+            report(send_request(client, "EVAL", "", Some(script)).await);
+        }
+
+        "CLUSTER" if parts.len() == 2 && parts[1].eq_ignore_ascii_case("STATUS") => {
+            if let Err(e) = cluster_status(client).await {
+                println!("{}", format!("failed to fetch cluster status: {}", e).red());
+            }
+        }
+
+        "CLUSTER" if parts.len() == 2 && parts[1].eq_ignore_ascii_case("PEERS") => {
+            if let Err(e) = cluster_peers(client).await {
+                println!("{}", format!("failed to fetch peer latencies: {}", e).red());
+            }
+        }
+
+        "CLUSTER" if parts.len() == 3 && parts[1].eq_ignore_ascii_case("DECOMMISSION") => {
+            if let Err(e) = cluster_decommission(client, parts[2], 0).await {
+                println!("{}", format!("decommission failed: {}", e).red());
+            }
+        }
 
-            "EXIT" | "QUIT" => {
-                println!("{}", "Goodbye!".blue().bold());
-                break;
+        "NODE" if parts.len() == 2 && parts[1].eq_ignore_ascii_case("STATUS") => {
+            if let Err(e) = node_status(client).await {
+                println!("{}", format!("failed to fetch node status: {}", e).red());
             }
+        }
 
-            "CGET" if parts.len() == 2 => {
-                let _ = send_request::<i64>(&mut client, "CGET", parts[1], None).await;
+        "CLUSTER" if parts.len() >= 2 && parts[1].eq_ignore_ascii_case("PAUSE") => {
+            let node = parts.get(2).is_some_and(|flag| *flag == "--node");
+            let reason = parts[if node { 3 } else { 2 }..].join(" ");
+            if let Err(e) = cluster_pause(client, Some(reason), !node).await {
+                println!("{}", format!("pause failed: {}", e).red());
             }
-            
-            "SGET" if parts.len() == 2 => {
-                let _ = send_request::<String>(&mut client, "SGET", parts[1], None).await;
+        }
+
+        "CLUSTER" if (2..=3).contains(&parts.len()) && parts[1].eq_ignore_ascii_case("RESUME") => {
+            let node = match parts.get(2) {
+                None => false,
+                Some(&"--node") => true,
+                Some(_) => {
+                    println!("{}", "usage: CLUSTER RESUME [--node]".red());
+                    return true;
+                }
+            };
+            if let Err(e) = cluster_pause(client, None, !node).await {
+                println!("{}", format!("resume failed: {}", e).red());
             }
-            
-            "RGET" if parts.len() == 2 => {
-                let _ = send_request::<String>(&mut client, "RGET", parts[1], None).await;
+        }
+
+        cmd @ ("CSET" | "CINC" | "CDEC") if parts.len() == 3 => {
+            if let Ok(val) = parts[2].parse::<i64>() {
+                if !report(send_request(client, cmd, parts[1], Some(val)).await) {
+                    hints.forget(parts[1]);
+                }
+            } else {
+                println!("{}", "Value must be an integer".red());
             }
-            
-            "RLEN" if parts.len() == 2 => {
-                let _ = send_request::<usize>(&mut client, "RLEN", parts[1], None).await;
+        }
+        
+        "SREM" if parts.len() == 4 && parts[3] == "--dry-run" => {
+            if !report(dry_run(client, "SREM", parts[1], parts[2].to_string()).await) {
+                hints.forget(parts[1]);
             }
+        }
 
-            cmd @ ("CSET" | "CINC" | "CDEC") if parts.len() == 3 => {
-                if let Ok(val) = parts[2].parse::<i64>() {
-                    let _ = send_request(&mut client, cmd, parts[1], Some(val)).await;
-                } else {
-                    println!("{}", "Value must be an integer".red());
+        cmd @ ("SADD" | "SREM" | "SMETA") if parts.len() == 5 && parts[3] == "--type" => {
+            match binary::tag(parts[2], wire::Encoding::Utf8, parts[4]) {
+                Ok(val) => {
+                    if !report(send_request(client, cmd, parts[1], Some(val)).await) {
+                        hints.forget(parts[1]);
+                    }
                 }
+                Err(e) => println!("{}", e.to_string().red()),
             }
-            
-            cmd @ ("SADD" | "SREM") if parts.len() == 3 => {
-                let val = parts[2].to_string();
-                let _ = send_request(&mut client, cmd, parts[1], Some(val)).await;
+        }
+
+        //the value is json, sent encoded. no spaces in it, the repl splits on them
+        cmd @ ("SADD" | "SREM" | "SMETA" | "RSET")
+            if parts.len() == 5 && parts[3] == "--encoding" =>
+        {
+            let encoded = parts[4]
+                .parse()
+                .map_err(anyhow::Error::from)
+                .and_then(|encoding| binary::encode(parts[2], encoding));
+            match encoded {
+                Ok(val) => {
+                    if !report(send_request(client, cmd, parts[1], Some(val)).await) {
+                        hints.forget(parts[1]);
+                    }
+                }
+                Err(e) => println!("{}", e.to_string().red()),
             }
-            
-            cmd @ ("RSET" | "RAPP") if parts.len() == 3 => {
-                let val = parts[2].to_string();
-                let _ = send_request(&mut client, cmd, parts[1], Some(val)).await;
+        }
+
+        cmd @ ("SADD" | "SREM" | "SMETA") if parts.len() == 3 => {
+            let val = parts[2].to_string();
+            if !report(send_request(client, cmd, parts[1], Some(val)).await) {
+                hints.forget(parts[1]);
             }
-            
-            _ => {
-                println!("{}", "Invalid command. Type HELP.".red());
+        }
+        
+        cmd @ ("RSET" | "RAPP") if parts.len() == 3 => {
+            let val = parts[2].to_string();
+            if !report(send_request(client, cmd, parts[1], Some(val)).await) {
+                hints.forget(parts[1]);
             }
         }
+        
+        _ => {
+            println!("{}", "Invalid command. Type HELP.".red());
+        }
     }
 
-    Ok(())
+    true
 }
@@ -0,0 +1,301 @@
+//--offline <dump>: read commands answered from a file `export` wrote instead of from a node, to
+//look into backups or compare the snapshots of two nodes that don't converge. the states are
+//decoded with mergedb-types the way the node would, nothing is ever written back. STAT shows
+//what a key's state is made of, eg how much of a counter each node holds
+use anyhow::{bail, Context, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use colored::*;
+use mergedb_proto::{communication::CrdtData, CrdtProto};
+use mergedb_types::{aw_set::Dot, element::Element, CrdtValue};
+use prost::Message;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    io::stdin,
+    path::Path,
+};
+
+use crate::display;
+
+//the node's own keys (freeze markers, settings, labels, counter shards...) all start with this,
+//they are left out of SCAN and DBSIZE but can still be read
+const INTERNAL_PREFIX: &str = "__";
+//where the node keeps a sharded counter's other shards, see CSHARD
+const SHARD_PREFIX: &str = "__shard:";
+
+pub enum Answer {
+    Line(String),
+    Table(Vec<&'static str>, Vec<Vec<String>>),
+}
+
+#[derive(Debug, Default)]
+pub struct Dump {
+    pub keys: BTreeMap<String, CrdtValue>,
+}
+
+impl Dump {
+    pub fn load(path: &Path) -> Result<Dump> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("can't read {}", path.display()))?;
+        Dump::parse(&text).with_context(|| format!("{} is not an export", path.display()))
+    }
+
+    //the json lines `export` writes: {"key", "type", "state"}, the state a base64 CrdtData
+    pub fn parse(text: &str) -> Result<Dump> {
+        let mut dump = Dump::default();
+        for (i, line) in text.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let parsed = || -> Result<(String, CrdtValue)> {
+                let line: serde_json::Value = serde_json::from_str(line)?;
+                let (Some(key), Some(state)) = (line["key"].as_str(), line["state"].as_str())
+                else {
+                    bail!("no key or state");
+                };
+                let wire = CrdtData::decode(STANDARD.decode(state)?.as_slice())?;
+                Ok((key.to_string(), CrdtValue::from_proto(wire)?))
+            };
+            let (key, value) = parsed().with_context(|| format!("line {}", i + 1))?;
+            dump.keys.insert(key, value);
+        }
+        Ok(dump)
+    }
+
+    fn get(&self, key: &str) -> Result<&CrdtValue, String> {
+        self.keys
+            .get(key)
+            .ok_or_else(|| format!("{} is not in the dump", key))
+    }
+
+    fn mismatch(key: &str, value: &CrdtValue, expected: &str) -> String {
+        format!("{} is a {}, not a {}", key, value.type_name(), expected)
+    }
+
+    //one command as typed in the REPL
+    pub fn answer(&self, input: &str) -> Result<Answer, String> {
+        let parts: Vec<&str> = input.split_whitespace().collect();
+        let Some(cmd) = parts.first().map(|cmd| cmd.to_uppercase()) else {
+            return Err("no command".to_string());
+        };
+        match (cmd.as_str(), &parts[1..]) {
+            ("CGET", [key]) => {
+                let total = self.counter(key)?;
+                Ok(Answer::Line(total.to_string()))
+            }
+            ("SGET", [key, ..]) => match self.get(key)? {
+                CrdtValue::Set(set) => {
+                    let rows = set
+                        .read_sorted()
+                        .into_iter()
+                        .map(|member| vec![shown(member)])
+                        .collect();
+                    Ok(Answer::Table(vec!["member"], rows))
+                }
+                other => Err(Dump::mismatch(key, other, "set")),
+            },
+            ("RGET", [key]) => match self.get(key)? {
+                CrdtValue::Register(register) => {
+                    let value = register.get();
+                    let shown =
+                        display::binary_value(&value).unwrap_or_else(|| format!("{:?}", value));
+                    Ok(Answer::Line(shown))
+                }
+                other => Err(Dump::mismatch(key, other, "register")),
+            },
+            ("RLEN", [key]) => match self.get(key)? {
+                CrdtValue::Register(register) => Ok(Answer::Line(register.strlen().to_string())),
+                other => Err(Dump::mismatch(key, other, "register")),
+            },
+            ("TYPE", [key]) => Ok(Answer::Line(self.get(key)?.type_name().to_string())),
+            ("STAT", [key]) => Ok(Answer::Table(vec!["field", "value"], stat(self.get(key)?))),
+            ("SCAN", prefix) if prefix.len() <= 1 => {
+                let prefix = prefix.first().copied().unwrap_or_default();
+                let rows = self
+                    .keys
+                    .range(prefix.to_string()..)
+                    .take_while(|(key, _)| key.starts_with(prefix))
+                    .filter(|(key, _)| !key.starts_with(INTERNAL_PREFIX))
+                    .map(|(key, value)| vec![key.clone(), value.type_name().to_string()])
+                    .collect();
+                Ok(Answer::Table(vec!["key", "type"], rows))
+            }
+            ("DBSIZE", []) => {
+                let keys = self
+                    .keys
+                    .keys()
+                    .filter(|key| !key.starts_with(INTERNAL_PREFIX));
+                Ok(Answer::Line(keys.count().to_string()))
+            }
+            (cmd, _) => Err(format!(
+                "{} doesn't work offline, try CGET/SGET/RGET/RLEN/TYPE/STAT <key>, SCAN [prefix] \
+                 or DBSIZE",
+                cmd
+            )),
+        }
+    }
+
+    //the key plus its shards, the way CGET reads a sharded counter
+    fn counter(&self, key: &str) -> Result<i64, String> {
+        let own = match self.get(key)? {
+            CrdtValue::Counter(counter) => counter.value(),
+            other => return Err(Dump::mismatch(key, other, "counter")),
+        };
+        let shards = format!("{}{}#", SHARD_PREFIX, key);
+        let sharded: i64 = self
+            .keys
+            .range(shards.clone()..)
+            .take_while(|(shard, _)| shard.starts_with(&shards))
+            .filter_map(|(_, value)| match value {
+                CrdtValue::Counter(counter) => Some(counter.value()),
+                _ => None,
+            })
+            .sum();
+        Ok(own + sharded)
+    }
+}
+
+fn shown(member: Element) -> String {
+    match member {
+        Element::Str(tag) => display::binary_value(&tag).unwrap_or(tag),
+        other => other.to_string(),
+    }
+}
+
+fn dot_count(tags: &HashMap<Element, HashSet<Dot>>) -> usize {
+    tags.values().map(|dots| dots.len()).sum()
+}
+
+//what the state is made of, the metadata the read commands hide
+fn stat(value: &CrdtValue) -> Vec<Vec<String>> {
+    let row = |field: &str, value: String| vec![field.to_string(), value];
+    let mut rows = vec![
+        row("type", value.type_name().to_string()),
+        row("size", format!("~{} bytes", value.estimated_size())),
+    ];
+    match value {
+        CrdtValue::Counter(counter) => {
+            rows.push(row("value", counter.value().to_string()));
+            for node in counter.contributors() {
+                let p = counter.p.get(&node).copied().unwrap_or(0);
+                let n = counter.n.get(&node).copied().unwrap_or(0);
+                rows.push(row(&format!("node {}", node), format!("+{} -{}", p, n)));
+            }
+        }
+        CrdtValue::Set(set) => {
+            rows.push(row("members", set.read().len().to_string()));
+            rows.push(row("add dots", dot_count(&set.add_tags).to_string()));
+            rows.push(row("tombstones", dot_count(&set.remove_tags).to_string()));
+            rows.push(row("clock", set.clock.to_string()));
+        }
+        CrdtValue::Register(register) => {
+            let state = &register.register_state;
+            rows.push(row("length", register.strlen().to_string()));
+            rows.push(row("written by", state.node_id.clone()));
+            rows.push(row("stamp", state.counter.to_string()));
+        }
+        CrdtValue::Map(map) => {
+            let removed = map.entries.values().filter(|e| e.value.is_none()).count();
+            rows.push(row("fields", map.len().to_string()));
+            rows.push(row("removed fields", removed.to_string()));
+            rows.push(row("clock", map.clock.to_string()));
+        }
+        CrdtValue::Custom(custom) => {
+            rows.push(row("type tag", custom.type_tag.clone()));
+            rows.push(row("states", custom.states.len().to_string()));
+        }
+    }
+    rows
+}
+
+pub fn show(answer: Result<Answer, String>) {
+    match answer {
+        Ok(Answer::Line(line)) => println!("{}", format!(":: {}", line).cyan()),
+        Ok(Answer::Table(headers, rows)) => display::print_table(&headers, &rows),
+        Err(e) => println!("{}", e.red()),
+    }
+}
+
+//a REPL over the dump, only for reads
+pub fn run_interactive(dump: &Dump, path: &Path) -> Result<()> {
+    println!(
+        "{}",
+        format!("offline: {} keys from {}", dump.keys.len(), path.display()).dimmed()
+    );
+    loop {
+        display::show_prompt();
+        let mut input = String::new();
+        if stdin().read_line(&mut input)? == 0 {
+            return Ok(());
+        }
+        match input.trim().to_uppercase().as_str() {
+            "" => continue,
+            "EXIT" | "QUIT" => return Ok(()),
+            "HELP" => {
+                println!("{}", "Offline commands:".bold());
+                println!("  CGET/SGET/RGET/RLEN <key>");
+                println!("  TYPE <key>");
+                println!("  STAT <key> (what the state is made of)");
+                println!("  SCAN [prefix]");
+                println!("  DBSIZE");
+                println!("  EXIT");
+            }
+            _ => show(dump.answer(&input)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mergedb_types::{aw_set::AWSet, pn_counter::PNCounter};
+
+    fn line(key: &str, value: CrdtValue) -> String {
+        let state = STANDARD.encode(value.to_proto().encode_to_vec());
+        serde_json::json!({"key": key, "type": "whatever", "state": state}).to_string()
+    }
+
+    fn lines(answer: Result<Answer, String>) -> Vec<Vec<String>> {
+        match answer {
+            Ok(Answer::Line(line)) => vec![vec![line]],
+            Ok(Answer::Table(_, rows)) => rows,
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    #[test]
+    fn test_reads_from_a_dump() {
+        let mut tags = AWSet::new();
+        tags.add("rust", "node_1".to_string());
+        tags.add(Element::Int(7), "node_2".to_string());
+        let text = [
+            line(
+                "views",
+                CrdtValue::Counter(PNCounter::new("n1".to_string(), 5, 1)),
+            ),
+            line(
+                "__shard:views#1",
+                CrdtValue::Counter(PNCounter::new("n2".to_string(), 3, 0)),
+            ),
+            line("tags", CrdtValue::Set(tags)),
+        ]
+        .join("\n");
+        let dump = Dump::parse(&text).unwrap();
+
+        assert_eq!(lines(dump.answer("cget views")), [["7"]]);
+        assert_eq!(lines(dump.answer("SGET tags")), [["7"], ["rust"]]);
+        assert_eq!(lines(dump.answer("DBSIZE")), [["2"]]);
+        assert_eq!(
+            lines(dump.answer("SCAN t")),
+            [["tags".to_string(), "set".to_string()]]
+        );
+        let stat = lines(dump.answer("STAT views"));
+        assert!(stat.contains(&vec!["node n1".to_string(), "+5 -1".to_string()]));
+
+        assert_eq!(
+            dump.answer("CGET tags").err().unwrap(),
+            "tags is a set, not a counter"
+        );
+        assert!(dump.answer("CINC views 1").is_err());
+        assert!(Dump::parse("{\"key\": \"x\", \"state\": \"not base64!\"}").is_err());
+    }
+}
@@ -0,0 +1,174 @@
+use mergedb_proto::{
+    communication::{
+        replication_service_client::ReplicationServiceClient, PropagateBatchRequest,
+        PropagateBatchResult, PropagateDataRequest,
+    },
+    wire,
+};
+use tonic::transport::Channel;
+
+use crate::{display, retry};
+
+//commands queued between `pipe` and `end` in the REPL, sent to the node as one PropagateBatch.
+//only commands on a key's value can be queued, everything else is refused when it's typed
+#[derive(Debug, Default)]
+pub struct Pipeline {
+    pub queued: Vec<(String, PropagateDataRequest)>,
+}
+
+impl Pipeline {
+    pub fn queue(&mut self, input: &str) -> Result<(), String> {
+        let request = parse(input)?;
+        self.queued.push((input.to_string(), request));
+        Ok(())
+    }
+
+    //sends everything queued, returns each input with what came of it, in the order typed
+    pub async fn send(
+        self,
+        client: &mut ReplicationServiceClient<Channel>,
+    ) -> Result<Vec<(String, String)>, tonic::Status> {
+        let (inputs, requests): (Vec<String>, Vec<PropagateDataRequest>) =
+            self.queued.into_iter().unzip();
+        let results = client
+            .propagate_batch(retry::policy().request(PropagateBatchRequest {
+                requests: requests.clone(),
+            }))
+            .await?
+            .into_inner()
+            .results;
+        Ok(inputs
+            .into_iter()
+            .zip(requests)
+            .zip(results)
+            .map(|((input, request), result)| (input, summarize(&request.valuetype, &result)))
+            .collect())
+    }
+}
+
+fn parse(input: &str) -> Result<PropagateDataRequest, String> {
+    let parts: Vec<&str> = input.split_whitespace().collect();
+    let cmd = parts.first().map(|cmd| cmd.to_uppercase()).unwrap_or_default();
+    let (value, amount) = match (cmd.as_str(), parts.len()) {
+        ("CGET" | "SGET" | "RGET" | "RLEN", 2) => (Vec::new(), None),
+        ("CSET" | "CINC" | "CDEC", 3) => match parts[2].parse::<i64>() {
+            Ok(value) => (wire::encode_i64(value), Some(value)),
+            Err(_) => return Err("Value must be an integer".to_string()),
+        },
+        ("SADD" | "SREM" | "SMETA" | "RSET" | "RAPP", 3) => (parts[2].as_bytes().to_vec(), None),
+        _ => {
+            return Err(format!(
+                "can't pipeline `{}`, only commands on one key's value can be queued",
+                input
+            ))
+        }
+    };
+    Ok(PropagateDataRequest {
+        valuetype: cmd,
+        key: parts[1].to_string(),
+        value,
+        dry_run: false,
+        encoding: String::new(),
+        element_type: String::new(),
+        amount,
+    })
+}
+
+//one line per result, so they line up with the inputs in a table
+fn summarize(cmd: &str, result: &PropagateBatchResult) -> String {
+    let Some(response) = result.response.as_ref() else {
+        return format!("error: {}", result.error);
+    };
+    if !response.success {
+        return "refused (wrong type for this key?)".to_string();
+    }
+    let raw = &response.response;
+    let decoded = match cmd {
+        "CGET" => wire::decode_signed(response.number, raw).map(|value| value.to_string()),
+        "SGET" => wire::decode_json(raw).map(|members| display::members(members, false).join(", ")),
+        "RGET" => wire::decode_string(raw.clone()).map(|value| {
+            display::binary_value(&value).unwrap_or_else(|| format!("{:?}", value))
+        }),
+        "RLEN" => wire::decode_u64(raw).map(|len| len.to_string()),
+        "SMETA" => wire::decode_json::<serde_json::Value>(raw).map(|meta| meta.to_string()),
+        _ => {
+            let ok = match display::written(cmd, response.number) {
+                Some(left) => format!("OK, {}", left),
+                None => "OK".to_string(),
+            };
+            let ok = match response.seq {
+                0 => ok,
+                seq => format!("{} (seq {})", ok, seq),
+            };
+            match &response.replicas {
+                Some(replicas) => Ok(format!("{}, {}", ok, display::replicated(replicas))),
+                None => Ok(ok),
+            }
+        }
+    };
+    decoded.unwrap_or_else(|e| format!("error: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mergedb_proto::communication::{PropagateDataResponse, Replicas};
+
+    fn answered(response: Vec<u8>, seq: u64) -> PropagateBatchResult {
+        PropagateBatchResult {
+            response: Some(PropagateDataResponse {
+                success: true,
+                response,
+                seq,
+                number: None,
+                replicas: None,
+            }),
+            error: String::new(),
+        }
+    }
+
+    #[test]
+    fn test_only_value_commands_are_queued() {
+        let mut pipe = Pipeline::default();
+        assert!(pipe.queue("cset views 3").is_ok());
+        assert!(pipe.queue("SADD tags red").is_ok());
+        assert!(pipe.queue("CINC views many").is_err());
+        assert!(pipe.queue("INFO").is_err());
+        assert!(pipe.queue("SGET").is_err());
+
+        assert_eq!(pipe.queued.len(), 2);
+        assert_eq!(pipe.queued[0].1.valuetype, "CSET");
+        assert_eq!(pipe.queued[0].1.value, wire::encode_i64(3));
+        assert_eq!(pipe.queued[0].1.amount, Some(3));
+        assert_eq!(pipe.queued[1].1.amount, None);
+        assert_eq!(pipe.queued[1].1.value, b"red");
+    }
+
+    #[test]
+    fn test_results_fit_on_one_line() {
+        assert_eq!(summarize("CSET", &answered(Vec::new(), 4)), "OK (seq 4)");
+        let mut added = answered(Vec::new(), 5);
+        added.response.as_mut().unwrap().number = Some(3);
+        assert_eq!(summarize("SADD", &added), "OK, 3 members (seq 5)");
+        added.response.as_mut().unwrap().replicas = Some(Replicas {
+            reached: 2,
+            failed: vec!["10.0.0.3:8001".to_string()],
+        });
+        assert_eq!(
+            summarize("SADD", &added),
+            "OK, 3 members (seq 5), pushed to 2 of 3 peers, not to 10.0.0.3:8001"
+        );
+        assert_eq!(summarize("CGET", &answered(wire::encode_i64(-2), 0)), "-2");
+        let members = serde_json::to_vec(&serde_json::json!(["b", 10, "a", 2.5])).unwrap();
+        assert_eq!(summarize("SGET", &answered(members, 0)), "2.5, 10, a, b");
+
+        let failed = PropagateBatchResult {
+            response: None,
+            error: "views is frozen, THAW it first".to_string(),
+        };
+        assert_eq!(
+            summarize("CINC", &failed),
+            "error: views is frozen, THAW it first"
+        );
+    }
+}
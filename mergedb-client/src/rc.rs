@@ -0,0 +1,223 @@
+use anyhow::{bail, Context, Result};
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+//macros may call aliases and other macros, but a loop between them has to stop somewhere
+const MAX_EXPANSION_DEPTH: usize = 8;
+
+//a macro body is one or more REPL commands separated by ';', $name is replaced by the argument
+//given for that parameter
+#[derive(Debug, Clone, PartialEq)]
+pub struct Macro {
+    pub params: Vec<String>,
+    pub body: Vec<String>,
+}
+
+//aliases and macros from ~/.mergedbrc, the file looks like:
+//
+//  # comments start with '#'
+//  alias votes = CGET poll:votes
+//  macro vote poll = CINC $poll:votes 1; CGET $poll:votes
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Rc {
+    pub aliases: BTreeMap<String, String>,
+    pub macros: BTreeMap<String, Macro>,
+}
+
+pub fn default_path() -> Option<PathBuf> {
+    std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".mergedbrc"))
+}
+
+impl Rc {
+    //a missing file is just an empty rc
+    pub fn load(path: &Path) -> Result<Rc> {
+        if !path.exists() {
+            return Ok(Rc::default());
+        }
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        Rc::parse(&contents).with_context(|| format!("invalid {}", path.display()))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        fs::write(path, self.to_string())
+            .with_context(|| format!("failed to write {}", path.display()))
+    }
+
+    pub fn parse(contents: &str) -> Result<Rc> {
+        let mut rc = Rc::default();
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            rc.define(line)
+                .with_context(|| format!("line {}", number + 1))?;
+        }
+        Ok(rc)
+    }
+
+    //takes a whole `alias ...` or `macro ...` definition, as written in the file or the REPL
+    pub fn define(&mut self, definition: &str) -> Result<()> {
+        let (kind, rest) = definition
+            .split_once(char::is_whitespace)
+            .unwrap_or((definition, ""));
+        let Some((head, body)) = rest.split_once('=') else {
+            bail!("expected `{} <name> = <commands>`", kind.to_lowercase());
+        };
+        let mut head = head.split_whitespace();
+        let Some(name) = head.next() else {
+            bail!("missing {} name", kind.to_lowercase());
+        };
+        let body = body.trim();
+        if body.is_empty() {
+            bail!("{} {} has an empty body", kind.to_lowercase(), name);
+        }
+
+        match kind.to_lowercase().as_str() {
+            "alias" => {
+                if head.next().is_some() {
+                    bail!("aliases take no parameters, use a macro instead");
+                }
+                self.aliases.insert(name.to_string(), body.to_string());
+            }
+            "macro" => {
+                let body = body
+                    .split(';')
+                    .map(|command| command.trim().to_string())
+                    .filter(|command| !command.is_empty())
+                    .collect();
+                self.macros.insert(
+                    name.to_string(),
+                    Macro {
+                        params: head.map(|param| param.to_string()).collect(),
+                        body,
+                    },
+                );
+            }
+            other => bail!("unknown definition `{}`, expected alias or macro", other),
+        }
+        Ok(())
+    }
+
+    //the commands a line turns into, a line that is neither an alias nor a macro is returned
+    //as it is
+    pub fn expand(&self, line: &str) -> Result<Vec<String>> {
+        let mut commands = Vec::new();
+        self.expand_into(line, 0, &mut commands)?;
+        Ok(commands)
+    }
+
+    fn expand_into(&self, line: &str, depth: usize, commands: &mut Vec<String>) -> Result<()> {
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        let Some(first) = parts.first() else {
+            return Ok(());
+        };
+
+        let expanded = if let Some(alias) = self.aliases.get(*first) {
+            //extra words are tacked onto the end, so `alias cg = CGET` then `cg likes` works
+            vec![std::iter::once(alias.as_str())
+                .chain(parts[1..].iter().copied())
+                .collect::<Vec<_>>()
+                .join(" ")]
+        } else if let Some(mac) = self.macros.get(*first) {
+            let args = &parts[1..];
+            if args.len() != mac.params.len() {
+                bail!(
+                    "macro {} takes {} argument(s) ({}), got {}",
+                    first,
+                    mac.params.len(),
+                    mac.params.join(" "),
+                    args.len()
+                );
+            }
+            mac.body
+                .iter()
+                .map(|command| substitute(command, &mac.params, args))
+                .collect()
+        } else {
+            commands.push(line.trim().to_string());
+            return Ok(());
+        };
+
+        if depth >= MAX_EXPANSION_DEPTH {
+            bail!("{} expands too deeply, is it calling itself?", first);
+        }
+        for command in expanded {
+            self.expand_into(&command, depth + 1, commands)?;
+        }
+        Ok(())
+    }
+}
+
+//longest parameter names first, so $key doesn't eat the start of $keys
+fn substitute(command: &str, params: &[String], args: &[&str]) -> String {
+    let mut bindings: Vec<(&String, &&str)> = params.iter().zip(args).collect();
+    bindings.sort_by_key(|(param, _)| std::cmp::Reverse(param.len()));
+    bindings
+        .into_iter()
+        .fold(command.to_string(), |command, (param, arg)| {
+            command.replace(&format!("${}", param), arg)
+        })
+}
+
+impl std::fmt::Display for Rc {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "# mergeDB REPL aliases and macros")?;
+        for (name, body) in &self.aliases {
+            writeln!(f, "alias {} = {}", name, body)?;
+        }
+        for (name, mac) in &self.macros {
+            let mut head = vec![name.as_str()];
+            head.extend(mac.params.iter().map(String::as_str));
+            writeln!(f, "macro {} = {}", head.join(" "), mac.body.join("; "))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_expand() {
+        let rc = Rc::parse(
+            "# ops shortcuts\n\
+             alias votes = CGET poll:votes\n\
+             alias cg = CGET\n\
+             macro vote poll amount = CINC $poll:votes $amount; votes\n",
+        )
+        .unwrap();
+
+        assert_eq!(rc.expand("votes").unwrap(), vec!["CGET poll:votes"]);
+        assert_eq!(rc.expand("cg likes").unwrap(), vec!["CGET likes"]);
+        assert_eq!(
+            rc.expand("vote poll 3").unwrap(),
+            vec!["CINC poll:votes 3", "CGET poll:votes"]
+        );
+        assert_eq!(rc.expand("SGET tags").unwrap(), vec!["SGET tags"]);
+        assert!(rc.expand("vote poll").is_err());
+    }
+
+    #[test]
+    fn test_recursive_definitions_are_cut_off() {
+        let rc = Rc::parse("alias a = b\nalias b = a\n").unwrap();
+        assert!(rc.expand("a").is_err());
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut rc = Rc::default();
+        rc.define("alias votes = CGET poll:votes").unwrap();
+        rc.define("macro swap key value = RSET $key $value; RGET $key")
+            .unwrap();
+        assert!(rc.define("alias broken").is_err());
+        assert!(rc.define("alias votes x = CGET x").is_err());
+
+        assert_eq!(Rc::parse(&rc.to_string()).unwrap(), rc);
+    }
+}
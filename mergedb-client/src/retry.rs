@@ -0,0 +1,328 @@
+use mergedb_proto::metadata::{Priority, PRIORITY_HEADER};
+use std::{
+    future::Future,
+    sync::OnceLock,
+    time::{Duration, Instant},
+};
+use tonic::{metadata::MetadataValue, Code, Request, Status};
+
+//how far a command may be retried depends on what a second copy of it would do on the node
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommandClass {
+    //reads and anything else that doesn't change the store
+    Read,
+    //writes that land on the same state when applied twice, eg CSET, SADD, FREEZE or SETTING
+    IdempotentWrite,
+    //writes that count twice when applied twice (CINC, CDEC, RAPP, EVAL), or that a second
+    //copy would be refused for (RENAME)
+    NonIdempotentWrite,
+}
+
+pub fn class_of(cmd: &str) -> CommandClass {
+    match cmd {
+        "CINC" | "CDEC" | "RAPP" | "EVAL" | "RENAME" => CommandClass::NonIdempotentWrite,
+        "CSET" | "CSHARD" | "RSET" | "SADD" | "SREM" | "FREEZE" | "THAW" | "SETTING"
+        | "MATERIALIZE" | "UNMATERIALIZE" | "COPY" => CommandClass::IdempotentWrite,
+        _ => CommandClass::Read,
+    }
+}
+
+//exponential backoff with full jitter: the n-th retry waits a random time between 0 and
+//min(max_delay, base_delay * 2^n)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Backoff {
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Backoff {
+    pub const NEVER: Backoff = Backoff {
+        max_attempts: 1,
+        base_delay: Duration::ZERO,
+        max_delay: Duration::ZERO,
+    };
+
+    //the upper bound of the wait before retry number `retry` (0 for the first retry)
+    pub fn ceiling(&self, retry: u32) -> Duration {
+        self.base_delay
+            .saturating_mul(2u32.saturating_pow(retry.min(31)))
+            .min(self.max_delay)
+    }
+
+    fn delay(&self, retry: u32) -> Duration {
+        let ceiling = self.ceiling(retry).as_millis() as u64;
+        Duration::from_millis(rand::random_range(0..=ceiling))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetryPolicy {
+    reads: Backoff,
+    writes: Backoff,
+    //non-idempotent writes are only retried, like any other write, when the node drops
+    //duplicates by request id
+    dedup: bool,
+    //the whole command, every attempt and wait included, gives up after this long
+    deadline: Duration,
+    //which of the node's waiting commands goes first, sent with every data command
+    priority: Priority,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy::builder().build()
+    }
+}
+
+impl RetryPolicy {
+    pub fn builder() -> RetryPolicyBuilder {
+        RetryPolicyBuilder::default()
+    }
+
+    pub fn backoff(&self, class: CommandClass) -> Backoff {
+        match class {
+            CommandClass::Read => self.reads,
+            CommandClass::IdempotentWrite => self.writes,
+            CommandClass::NonIdempotentWrite if self.dedup => self.writes,
+            CommandClass::NonIdempotentWrite => Backoff::NEVER,
+        }
+    }
+
+    //the message with the command's deadline (as grpc-timeout) and priority attached, so a busy
+    //node runs it in turn and drops it once nobody waits for the answer anymore. every attempt
+    //carries the whole deadline, the node may hold on to a retry a little longer than needed
+    pub fn request<T>(&self, message: T) -> Request<T> {
+        let mut request = Request::new(message);
+        request.set_timeout(self.deadline);
+        request
+            .metadata_mut()
+            .insert(PRIORITY_HEADER, MetadataValue::from_static(self.priority.name()));
+        request
+    }
+
+    //calls `attempt` until it succeeds, fails with an error that retrying can't fix, runs out of
+    //attempts or would run past the deadline. the last error is what comes back
+    pub async fn run<T, F, Fut>(&self, class: CommandClass, mut attempt: F) -> Result<T, Status>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, Status>>,
+    {
+        let backoff = self.backoff(class);
+        let started = Instant::now();
+        let mut retry = 0;
+        loop {
+            let remaining = self.deadline.saturating_sub(started.elapsed());
+            let status = match tokio::time::timeout(remaining, attempt()).await {
+                Ok(Ok(value)) => return Ok(value),
+                Ok(Err(status)) => status,
+                Err(_) => {
+                    return Err(Status::deadline_exceeded(format!(
+                        "gave up after {:?} and {} attempt(s)",
+                        self.deadline,
+                        retry + 1
+                    )))
+                }
+            };
+            if !retryable(&status) || retry + 1 >= backoff.max_attempts {
+                return Err(status);
+            }
+            let delay = backoff.delay(retry);
+            if started.elapsed() + delay >= self.deadline {
+                return Err(status);
+            }
+            tokio::time::sleep(delay).await;
+            retry += 1;
+        }
+    }
+}
+
+//only failures where the node may simply not have been there, anything the node answered on
+//purpose (a wrong type, a frozen key, a bad script) fails the same way every time
+fn retryable(status: &Status) -> bool {
+    matches!(
+        status.code(),
+        Code::Unavailable | Code::ResourceExhausted | Code::Aborted
+    )
+}
+
+#[derive(Debug, Clone)]
+pub struct RetryPolicyBuilder {
+    policy: RetryPolicy,
+}
+
+impl Default for RetryPolicyBuilder {
+    fn default() -> Self {
+        RetryPolicyBuilder {
+            policy: RetryPolicy {
+                reads: Backoff {
+                    max_attempts: 6,
+                    base_delay: Duration::from_millis(50),
+                    max_delay: Duration::from_secs(1),
+                },
+                writes: Backoff {
+                    max_attempts: 3,
+                    base_delay: Duration::from_millis(100),
+                    max_delay: Duration::from_secs(1),
+                },
+                dedup: false,
+                deadline: Duration::from_secs(10),
+                priority: Priority::Normal,
+            },
+        }
+    }
+}
+
+impl RetryPolicyBuilder {
+    pub fn reads(mut self, backoff: Backoff) -> Self {
+        self.policy.reads = backoff;
+        self
+    }
+
+    pub fn writes(mut self, backoff: Backoff) -> Self {
+        self.policy.writes = backoff;
+        self
+    }
+
+    pub fn dedup(mut self, dedup: bool) -> Self {
+        self.policy.dedup = dedup;
+        self
+    }
+
+    pub fn deadline(mut self, deadline: Duration) -> Self {
+        self.policy.deadline = deadline;
+        self
+    }
+
+    pub fn priority(mut self, priority: Priority) -> Self {
+        self.policy.priority = priority;
+        self
+    }
+
+    pub fn build(self) -> RetryPolicy {
+        self.policy
+    }
+}
+
+//the policy every command in this process goes through, set once from the command line
+static POLICY: OnceLock<RetryPolicy> = OnceLock::new();
+
+pub fn install(policy: RetryPolicy) {
+    let _ = POLICY.set(policy);
+}
+
+pub fn policy() -> &'static RetryPolicy {
+    POLICY.get_or_init(RetryPolicy::default)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    fn quick() -> Backoff {
+        Backoff {
+            max_attempts: 4,
+            base_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(4),
+        }
+    }
+
+    #[test]
+    fn test_backoff_doubles_up_to_the_cap() {
+        let backoff = Backoff {
+            max_attempts: 10,
+            base_delay: Duration::from_millis(50),
+            max_delay: Duration::from_millis(300),
+        };
+        let ceilings: Vec<u128> = (0..5).map(|n| backoff.ceiling(n).as_millis()).collect();
+        assert_eq!(ceilings, vec![50, 100, 200, 300, 300]);
+        assert_eq!(backoff.ceiling(u32::MAX), Duration::from_millis(300));
+        for retry in 0..5 {
+            assert!(backoff.delay(retry) <= backoff.ceiling(retry));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retries_only_what_is_safe_to_retry() {
+        let policy = RetryPolicy::builder()
+            .reads(quick())
+            .writes(quick())
+            .build();
+        let attempts_for = |class, code| {
+            let policy = policy.clone();
+            async move {
+                let attempts = AtomicU32::new(0);
+                let result: Result<(), Status> = policy
+                    .run(class, || {
+                        attempts.fetch_add(1, Ordering::SeqCst);
+                        async move { Err(Status::new(code, "nope")) }
+                    })
+                    .await;
+                assert_eq!(result.unwrap_err().code(), code);
+                attempts.load(Ordering::SeqCst)
+            }
+        };
+
+        assert_eq!(attempts_for(CommandClass::Read, Code::Unavailable).await, 4);
+        assert_eq!(
+            attempts_for(CommandClass::IdempotentWrite, Code::Unavailable).await,
+            4
+        );
+        assert_eq!(
+            attempts_for(CommandClass::Read, Code::InvalidArgument).await,
+            1
+        );
+        //an increment that may have landed is never sent again without dedup
+        assert_eq!(
+            attempts_for(CommandClass::NonIdempotentWrite, Code::Unavailable).await,
+            1
+        );
+
+        let with_dedup = RetryPolicy::builder().writes(quick()).dedup(true).build();
+        let attempts = AtomicU32::new(0);
+        let result = with_dedup
+            .run(class_of("CINC"), || {
+                let n = attempts.fetch_add(1, Ordering::SeqCst);
+                async move {
+                    match n {
+                        0 => Err(Status::unavailable("not yet")),
+                        _ => Ok(n),
+                    }
+                }
+            })
+            .await;
+        assert_eq!(result.unwrap(), 1);
+    }
+
+    #[test]
+    fn test_requests_carry_deadline_and_priority() {
+        let policy = RetryPolicy::builder()
+            .deadline(Duration::from_millis(1500))
+            .priority(Priority::High)
+            .build();
+        let request = policy.request(());
+        assert_eq!(request.metadata().get("grpc-timeout").unwrap(), "1500000u");
+        assert_eq!(request.metadata().get(PRIORITY_HEADER).unwrap(), "high");
+    }
+
+    #[tokio::test]
+    async fn test_deadline_covers_every_attempt() {
+        let policy = RetryPolicy::builder()
+            .reads(Backoff {
+                max_attempts: 100,
+                ..quick()
+            })
+            .deadline(Duration::from_millis(50))
+            .build();
+        let started = Instant::now();
+        let result: Result<(), Status> = policy
+            .run(CommandClass::Read, || async {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                Err(Status::unavailable("down"))
+            })
+            .await;
+        assert!(result.is_err());
+        assert!(started.elapsed() < Duration::from_millis(200));
+    }
+}
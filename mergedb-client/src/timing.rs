@@ -0,0 +1,143 @@
+//--verbose: where a command's time went, to tell a slow client, network or node apart. the node
+//stamps how long it spent on a request into the response metadata (SERVER_TIME_HEADER), the
+//rest of the round trip is the network's. connecting is only paid for by the first command
+use mergedb_proto::metadata::SERVER_TIME_HEADER;
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tonic::{metadata::MetadataMap, Response, Status};
+
+static VERBOSE: AtomicBool = AtomicBool::new(false);
+//how long connecting took, until the first command reports it
+static CONNECT: Mutex<Option<Duration>> = Mutex::new(None);
+
+pub fn enable(connect: Duration) {
+    VERBOSE.store(true, Ordering::Relaxed);
+    *CONNECT.lock().unwrap() = Some(connect);
+}
+
+pub fn enabled() -> bool {
+    VERBOSE.load(Ordering::Relaxed)
+}
+
+//None for nodes that don't stamp it
+fn server_time(metadata: &MetadataMap) -> Option<Duration> {
+    let us = metadata
+        .get(SERVER_TIME_HEADER)?
+        .to_str()
+        .ok()?
+        .parse()
+        .ok()?;
+    Some(Duration::from_micros(us))
+}
+
+fn ms(duration: Duration) -> String {
+    format!("{:.2}ms", duration.as_secs_f64() * 1_000.0)
+}
+
+#[derive(Debug)]
+pub struct Timing {
+    started: Instant,
+    connect: Option<Duration>,
+    sent: Option<Instant>,
+    //retries and their backoff included
+    round_trip: Option<Duration>,
+    server: Option<Duration>,
+    status: String,
+}
+
+impl Timing {
+    pub fn start() -> Self {
+        Timing {
+            started: Instant::now(),
+            connect: CONNECT.lock().unwrap().take(),
+            sent: None,
+            round_trip: None,
+            server: None,
+            status: String::new(),
+        }
+    }
+
+    pub fn sending(&mut self) {
+        self.sent = Some(Instant::now());
+    }
+
+    pub fn answered<T>(&mut self, answer: &Result<Response<T>, Status>) {
+        self.round_trip = self.sent.map(|sent| sent.elapsed());
+        let (metadata, status) = match answer {
+            Ok(response) => (response.metadata(), "OK".to_string()),
+            Err(status) => (
+                status.metadata(),
+                format!("{:?}: {}", status.code(), status.message()),
+            ),
+        };
+        self.server = server_time(metadata);
+        self.status = status;
+    }
+
+    //the node's own verdict next to the grpc status, eg success=false for a wrong type
+    pub fn note(&mut self, note: &str) {
+        self.status = format!("{}, {}", self.status, note);
+    }
+
+    //eg "connect 1.20ms, round trip 0.90ms (node 0.30ms, network 0.60ms), total 2.40ms"
+    pub fn summary(&self) -> String {
+        let mut parts = Vec::new();
+        if let Some(connect) = self.connect {
+            parts.push(format!("connect {}", ms(connect)));
+        }
+        match (self.round_trip, self.server) {
+            (Some(round_trip), Some(server)) => parts.push(format!(
+                "round trip {} (node {}, network {})",
+                ms(round_trip),
+                ms(server),
+                ms(round_trip.saturating_sub(server))
+            )),
+            (Some(round_trip), None) => parts.push(format!("round trip {}", ms(round_trip))),
+            (None, _) => {}
+        }
+        let total = self.started.elapsed() + self.connect.unwrap_or_default();
+        parts.push(format!("total {}", ms(total)));
+        parts.join(", ")
+    }
+
+    pub fn status(&self) -> &str {
+        &self.status
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_the_node_share_comes_from_the_metadata() {
+        let mut response = Response::new(());
+        response
+            .metadata_mut()
+            .insert(SERVER_TIME_HEADER, "1500".parse().unwrap());
+        let mut timing = Timing {
+            connect: Some(Duration::from_millis(3)),
+            ..Timing::start()
+        };
+        timing.sent = Some(Instant::now() - Duration::from_millis(4));
+        timing.answered(&Ok(response));
+        assert_eq!(timing.server, Some(Duration::from_micros(1500)));
+        assert_eq!(timing.status(), "OK");
+        let summary = timing.summary();
+        assert!(summary.starts_with("connect 3.00ms, round trip 4."));
+        assert!(summary.contains("(node 1.50ms, network 2."));
+
+        let mut timing = Timing::start();
+        timing.sending();
+        timing.answered::<()>(&Err(Status::unavailable("node is down")));
+        timing.note("refused");
+        assert_eq!(timing.status(), "Unavailable: node is down, refused");
+        assert_eq!(timing.server, None);
+        assert!(!timing.summary().contains("node"));
+    }
+}
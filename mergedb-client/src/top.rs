@@ -0,0 +1,269 @@
+use crate::communication::{replication_service_client::ReplicationServiceClient, PropagateDataRequest};
+use mergedb_proto::wire;
+use ratatui::{
+    crossterm::event::{self, Event, KeyCode, KeyModifiers},
+    layout::{Constraint, Layout},
+    style::{Color, Modifier, Style},
+    text::Line,
+    widgets::{Block, Borders, Cell, Row, Sparkline, Table},
+    DefaultTerminal, Frame,
+};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    time::{Duration, Instant},
+};
+use tonic::{transport::Channel, Request};
+
+//how many past rates each key keeps for its sparkline
+const HISTORY_LEN: usize = 60;
+
+//what is watched, either a fixed list of keys or whatever currently matches a prefix
+pub enum Watch {
+    Keys(Vec<String>),
+    Prefix(String),
+}
+
+//the latest reading of one key. value is the counter value, the set size or the register
+//length, and rate is how fast that moved between the last two readings
+#[derive(Debug, Default)]
+struct KeyStat {
+    kind: String,
+    value: i64,
+    rate: f64,
+    updates: u64,
+    last_read: Option<Instant>,
+    history: VecDeque<u64>,
+}
+
+impl KeyStat {
+    fn record(&mut self, value: i64, now: Instant) {
+        if let Some(last_read) = self.last_read {
+            let elapsed = now.duration_since(last_read).as_secs_f64();
+            if elapsed > 0.0 {
+                self.rate = (value - self.value) as f64 / elapsed;
+            }
+            if value != self.value {
+                self.updates += 1;
+            }
+        }
+        self.value = value;
+        self.last_read = Some(now);
+
+        if self.history.len() == HISTORY_LEN {
+            self.history.pop_front();
+        }
+        self.history.push_back(self.rate.abs().round() as u64);
+    }
+}
+
+struct Dashboard {
+    addr: String,
+    watch: Watch,
+    stats: BTreeMap<String, KeyStat>,
+    error: Option<String>,
+    refreshed_at: Option<Instant>,
+}
+
+pub async fn run(
+    mut client: ReplicationServiceClient<Channel>,
+    addr: String,
+    watch: Watch,
+    interval: Duration,
+) -> anyhow::Result<()> {
+    let mut dashboard = Dashboard {
+        addr,
+        watch,
+        stats: BTreeMap::new(),
+        error: None,
+        refreshed_at: None,
+    };
+
+    let mut terminal = ratatui::init();
+    let result = dashboard.run(&mut client, &mut terminal, interval).await;
+    ratatui::restore();
+    result
+}
+
+impl Dashboard {
+    async fn run(
+        &mut self,
+        client: &mut ReplicationServiceClient<Channel>,
+        terminal: &mut DefaultTerminal,
+        interval: Duration,
+    ) -> anyhow::Result<()> {
+        loop {
+            //a node going away shows up on screen instead of tearing the dashboard down
+            self.error = self.refresh(client).await.err().map(|e| e.to_string());
+            terminal.draw(|frame| self.draw(frame))?;
+
+            //wait out the interval, but stay responsive to q
+            let deadline = Instant::now() + interval;
+            while let Some(left) = deadline.checked_duration_since(Instant::now()) {
+                if !event::poll(left)? {
+                    break;
+                }
+                if let Event::Key(key) = event::read()? {
+                    let ctrl_c = key.modifiers.contains(KeyModifiers::CONTROL)
+                        && key.code == KeyCode::Char('c');
+                    if ctrl_c || matches!(key.code, KeyCode::Char('q') | KeyCode::Esc) {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+    }
+
+    async fn refresh(&mut self, client: &mut ReplicationServiceClient<Channel>) -> anyhow::Result<()> {
+        let keys = match &self.watch {
+            Watch::Keys(keys) => keys.clone(),
+            Watch::Prefix(prefix) => {
+                let raw = query(client, "KEYS", prefix).await?;
+                wire::decode_json(&raw)?
+            }
+        };
+        self.stats.retain(|key, _| keys.contains(key));
+
+        let now = Instant::now();
+        for key in keys {
+            let stat = self.stats.entry(key.clone()).or_default();
+            //a key only gets its type looked up until it exists, after that it can't change
+            if stat.kind.is_empty() || stat.kind == "none" {
+                stat.kind = wire::decode_string(query(client, "TYPE", &key).await?)?;
+            }
+            let value = match stat.kind.as_str() {
+                "counter" => wire::decode_i64(&query(client, "CGET", &key).await?)?,
+                "set" => {
+                    let members: Vec<serde_json::Value> =
+                        wire::decode_json(&query(client, "SGET", &key).await?)?;
+                    members.len() as i64
+                }
+                "register" => wire::decode_u64(&query(client, "RLEN", &key).await?)? as i64,
+                _ => continue,
+            };
+            stat.record(value, now);
+        }
+        self.refreshed_at = Some(now);
+        Ok(())
+    }
+
+    fn draw(&self, frame: &mut Frame) {
+        let [header, table, sparkline] = Layout::vertical([
+            Constraint::Length(1),
+            Constraint::Min(3),
+            Constraint::Length(6),
+        ])
+        .areas(frame.area());
+
+        let watching = match &self.watch {
+            Watch::Keys(keys) => format!("{} keys", keys.len()),
+            Watch::Prefix(prefix) if prefix.is_empty() => "all keys".to_string(),
+            Watch::Prefix(prefix) => format!("prefix {:?}", prefix),
+        };
+        let status = match &self.error {
+            Some(e) => format!("error: {}", e),
+            None => "q to quit".to_string(),
+        };
+        frame.render_widget(
+            Line::from(format!(" mergeDB top :: {} :: {} :: {}", self.addr, watching, status))
+                .style(Style::default().add_modifier(Modifier::BOLD)),
+            header,
+        );
+
+        let rows = self.stats.iter().map(|(key, stat)| {
+            let rate_style = match stat.rate {
+                r if r > 0.0 => Style::default().fg(Color::Green),
+                r if r < 0.0 => Style::default().fg(Color::Red),
+                _ => Style::default(),
+            };
+            Row::new(vec![
+                Cell::from(key.clone()),
+                Cell::from(stat.kind.clone()),
+                Cell::from(stat.value.to_string()),
+                Cell::from(format!("{:+.1}/s", stat.rate)).style(rate_style),
+                Cell::from(stat.updates.to_string()),
+            ])
+        });
+        let widths = [
+            Constraint::Fill(3),
+            Constraint::Length(9),
+            Constraint::Fill(1),
+            Constraint::Fill(1),
+            Constraint::Length(8),
+        ];
+        frame.render_widget(
+            Table::new(rows, widths)
+                .header(
+                    Row::new(["key", "type", "value", "rate", "updates"])
+                        .style(Style::default().fg(Color::Cyan).add_modifier(Modifier::BOLD)),
+                )
+                .block(Block::default().borders(Borders::ALL)),
+            table,
+        );
+
+        //the busiest key gets the sparkline
+        if let Some((key, stat)) = self
+            .stats
+            .iter()
+            .max_by(|a, b| a.1.rate.abs().total_cmp(&b.1.rate.abs()))
+        {
+            let history: Vec<u64> = stat.history.iter().copied().collect();
+            frame.render_widget(
+                Sparkline::default()
+                    .block(
+                        Block::default()
+                            .borders(Borders::ALL)
+                            .title(format!(" {} rate ", key)),
+                    )
+                    .data(&history)
+                    .style(Style::default().fg(Color::Yellow)),
+                sparkline,
+            );
+        }
+    }
+}
+
+async fn query(
+    client: &mut ReplicationServiceClient<Channel>,
+    cmd: &str,
+    key: &str,
+) -> anyhow::Result<Vec<u8>> {
+    let response = client
+        .propagate_data(Request::new(PropagateDataRequest {
+            valuetype: cmd.to_string(),
+            key: key.to_string(),
+            value: Vec::new(),
+            dry_run: false,
+            encoding: String::new(),
+            element_type: String::new(),
+            amount: None,
+        }))
+        .await?
+        .into_inner();
+    if !response.success {
+        anyhow::bail!("{} {} was refused by the node", cmd, key);
+    }
+    Ok(response.response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_follows_value() {
+        let start = Instant::now();
+        let mut stat = KeyStat::default();
+
+        stat.record(10, start);
+        assert_eq!(stat.rate, 0.0);
+        assert_eq!(stat.updates, 0);
+
+        stat.record(30, start + Duration::from_secs(2));
+        assert_eq!(stat.rate, 10.0);
+        assert_eq!(stat.updates, 1);
+
+        stat.record(20, start + Duration::from_secs(3));
+        assert_eq!(stat.rate, -10.0);
+        assert_eq!(stat.history, [0, 10, 10]);
+    }
+}
@@ -0,0 +1,556 @@
+//what HELP prints. the table holds what only the REPL needs: how a command is typed there,
+//argument types, examples and related commands. descriptions and argument help come from the
+//clap definitions in cli.rs, so the REPL and --help can't tell different stories, and a test
+//fails when a command or argument is added to one and not the other
+use clap::{Arg, Command, CommandFactory};
+
+use crate::cli::Cli;
+
+pub struct Usage {
+    //as typed in the REPL, eg CLUSTER STATUS for a subcommand
+    pub name: &'static str,
+    pub synopsis: &'static str,
+    //(clap argument id, type), every positional clap argument and the flags the REPL takes
+    pub args: &'static [(&'static str, &'static str)],
+    pub examples: &'static [&'static str],
+    pub related: &'static [&'static str],
+    //only for REPL commands, the others are described by their clap doc comment
+    pub about: Option<&'static str>,
+}
+
+const KEY: (&str, &str) = ("key", "string");
+const ENCODING: (&str, &str) = ("encoding", "msgpack|cbor");
+const ELEMENT_TYPE: (&str, &str) = ("element_type", "int|float|bool|bytes");
+
+pub const COMMANDS: &[Usage] = &[
+    Usage {
+        name: "CSET",
+        synopsis: "CSET <key> <value>",
+        args: &[KEY, ("value", "integer")],
+        examples: &["CSET stock 40"],
+        related: &["CGET", "CINC", "CDEC"],
+        about: None,
+    },
+    Usage {
+        name: "CGET",
+        synopsis: "CGET <key>",
+        args: &[KEY],
+        examples: &["CGET stock"],
+        related: &["CSET", "CINC", "CDEC"],
+        about: None,
+    },
+    Usage {
+        name: "CINC",
+        synopsis: "CINC <key> <amount>",
+        args: &[KEY, ("amount", "integer")],
+        examples: &["CINC views 1"],
+        related: &["CDEC", "CGET", "CSHARD"],
+        about: None,
+    },
+    Usage {
+        name: "CDEC",
+        synopsis: "CDEC <key> <amount>",
+        args: &[KEY, ("amount", "integer")],
+        examples: &["CDEC stock 2"],
+        related: &["CINC", "CGET"],
+        about: None,
+    },
+    Usage {
+        name: "CSHARD",
+        synopsis: "CSHARD <key> <n>",
+        args: &[KEY, ("shards", "integer")],
+        examples: &["CSHARD views 8"],
+        related: &["CINC", "HOTKEYS"],
+        about: None,
+    },
+    Usage {
+        name: "SADD",
+        synopsis: "SADD <key> <tag> [--type int|float|bool|bytes] [--encoding msgpack|cbor]",
+        args: &[KEY, ("tag", "string"), ELEMENT_TYPE, ENCODING],
+        examples: &["SADD tags rust", "SADD ports 8080 --type int"],
+        related: &["SREM", "SGET", "SMETA", "SLIMIT"],
+        about: None,
+    },
+    Usage {
+        name: "SREM",
+        synopsis: "SREM <key> <tag> [--dry-run | --type int|float|bool|bytes]",
+        args: &[KEY, ("tag", "string"), ("dry_run", "flag"), ELEMENT_TYPE],
+        examples: &["SREM tags rust", "SREM tags rust --dry-run"],
+        related: &["SADD", "SGET"],
+        about: None,
+    },
+    Usage {
+        name: "SGET",
+        synopsis: "SGET <key> [asc|desc]",
+        args: &[KEY, ("sort", "asc|desc")],
+        examples: &["SGET tags", "SGET ports desc"],
+        related: &["SADD", "SREM", "SMETA"],
+        about: None,
+    },
+    Usage {
+        name: "SMETA",
+        synopsis: "SMETA <key> <tag>",
+        args: &[KEY, ("tag", "string"), ELEMENT_TYPE, ENCODING],
+        examples: &["SMETA tags rust"],
+        related: &["SADD", "SGET"],
+        about: None,
+    },
+    Usage {
+        name: "SLIMIT",
+        synopsis: "SLIMIT <key> [n]",
+        args: &[KEY, ("limit", "integer")],
+        examples: &["SLIMIT recent 100", "SLIMIT recent"],
+        related: &["SADD", "SGET"],
+        about: None,
+    },
+    Usage {
+        name: "RSET",
+        synopsis: "RSET <key> <register> [--encoding msgpack|cbor]",
+        args: &[KEY, ("register", "string"), ENCODING],
+        examples: &[
+            "RSET name alice",
+            r#"RSET profile {"age":30} --encoding msgpack"#,
+        ],
+        related: &["RGET", "RAPP", "RLEN"],
+        about: None,
+    },
+    Usage {
+        name: "RGET",
+        synopsis: "RGET <key>",
+        args: &[KEY],
+        examples: &["RGET name"],
+        related: &["RSET", "RLEN"],
+        about: None,
+    },
+    Usage {
+        name: "RAPP",
+        synopsis: "RAPP <key> <to_append>",
+        args: &[KEY, ("reg_append", "string")],
+        examples: &["RAPP log ,done"],
+        related: &["RSET", "RGET"],
+        about: None,
+    },
+    Usage {
+        name: "RLEN",
+        synopsis: "RLEN <key>",
+        args: &[KEY],
+        examples: &["RLEN name"],
+        related: &["RGET"],
+        about: None,
+    },
+    Usage {
+        name: "INFO",
+        synopsis: "INFO [section]",
+        args: &[("section", "string")],
+        examples: &["INFO", "INFO replication"],
+        related: &["DBSIZE", "SETTINGS", "NODE STATUS"],
+        about: None,
+    },
+    Usage {
+        name: "DBSIZE",
+        synopsis: "DBSIZE",
+        args: &[],
+        examples: &["DBSIZE"],
+        related: &["INFO"],
+        about: None,
+    },
+    Usage {
+        name: "FREEZE",
+        synopsis: "FREEZE <key>",
+        args: &[KEY],
+        examples: &["FREEZE prices"],
+        related: &["THAW"],
+        about: None,
+    },
+    Usage {
+        name: "THAW",
+        synopsis: "THAW <key>",
+        args: &[KEY],
+        examples: &["THAW prices"],
+        related: &["FREEZE"],
+        about: None,
+    },
+    Usage {
+        name: "COPY",
+        synopsis: "COPY <src> <dst>",
+        args: &[("src", "string"), ("dst", "string")],
+        examples: &["COPY cart:42 cart:42:backup"],
+        related: &["RENAME"],
+        about: None,
+    },
+    Usage {
+        name: "RENAME",
+        synopsis: "RENAME <src> <dst>",
+        args: &[("src", "string"), ("dst", "string")],
+        examples: &["RENAME cart:guest cart:42"],
+        related: &["COPY", "FREEZE"],
+        about: None,
+    },
+    Usage {
+        name: "SETTING",
+        synopsis: "SETTING <name> [value]",
+        args: &[("name", "string"), ("value", "string")],
+        examples: &["SETTING gossip_interval 5s", "SETTING gossip_interval"],
+        related: &["SETTINGS"],
+        about: None,
+    },
+    Usage {
+        name: "SETTINGS",
+        synopsis: "SETTINGS",
+        args: &[],
+        examples: &["SETTINGS"],
+        related: &["SETTING", "INFO"],
+        about: None,
+    },
+    Usage {
+        name: "HOTKEYS",
+        synopsis: "HOTKEYS [n]",
+        args: &[("n", "integer")],
+        examples: &["HOTKEYS 10"],
+        related: &["CSHARD", "CONTENTION", "INFO"],
+        about: None,
+    },
+    Usage {
+        name: "CONTENTION",
+        synopsis: "CONTENTION [n]",
+        args: &[("n", "integer")],
+        examples: &["CONTENTION 10"],
+        related: &["HOTKEYS", "CSHARD"],
+        about: None,
+    },
+    Usage {
+        name: "AUDIT",
+        synopsis: "AUDIT",
+        args: &[],
+        examples: &["AUDIT"],
+        related: &["CLUSTER STATUS", "INFO"],
+        about: None,
+    },
+    Usage {
+        name: "PING",
+        synopsis: "PING [count] [--all]",
+        args: &[("count", "integer"), ("all", "flag")],
+        examples: &["PING", "PING 10 --all"],
+        related: &["CLUSTER PEERS", "CLUSTER STATUS"],
+        about: None,
+    },
+    Usage {
+        name: "MATERIALIZE",
+        synopsis: "MATERIALIZE [<prefix> <path>]",
+        args: &[("prefix", "string"), ("path", "path")],
+        examples: &["MATERIALIZE user: /var/lib/legacy/users.csv", "MATERIALIZE"],
+        related: &["UNMATERIALIZE"],
+        about: None,
+    },
+    Usage {
+        name: "UNMATERIALIZE",
+        synopsis: "UNMATERIALIZE <path>",
+        args: &[("path", "path")],
+        examples: &["UNMATERIALIZE /var/lib/legacy/users.csv"],
+        related: &["MATERIALIZE"],
+        about: None,
+    },
+    Usage {
+        name: "LABEL",
+        synopsis: "LABEL <key> <name=value,...>",
+        args: &[KEY, ("labels", "name=value,...")],
+        examples: &["LABEL orders env=prod,team=billing", "LABEL orders team="],
+        related: &["LABELS", "SCAN"],
+        about: None,
+    },
+    Usage {
+        name: "LABELS",
+        synopsis: "LABELS <key>",
+        args: &[KEY],
+        examples: &["LABELS orders"],
+        related: &["LABEL", "SCAN"],
+        about: None,
+    },
+    Usage {
+        name: "SCAN",
+        synopsis: "SCAN [--selector <env=prod,team!=search,...>] [--prefix <prefix>]",
+        args: &[("selector", "selector"), ("prefix", "string")],
+        examples: &["SCAN --selector env=prod,!legacy", "SCAN --prefix user:"],
+        related: &["LABEL", "LABELS"],
+        about: None,
+    },
+    Usage {
+        name: "EVAL",
+        synopsis: "EVAL <script> (get, inc, dec, add, rem, set)",
+        args: &[("script", "rhai")],
+        examples: &[r#"EVAL inc("views", 1); get("views")"#],
+        related: &["PIPE"],
+        about: None,
+    },
+    Usage {
+        name: "CLUSTER STATUS",
+        synopsis: "CLUSTER STATUS",
+        args: &[],
+        examples: &["CLUSTER STATUS"],
+        related: &["CLUSTER PEERS", "NODE STATUS", "AUDIT"],
+        about: None,
+    },
+    Usage {
+        name: "CLUSTER PEERS",
+        synopsis: "CLUSTER PEERS",
+        args: &[],
+        examples: &["CLUSTER PEERS"],
+        related: &["CLUSTER STATUS"],
+        about: None,
+    },
+    Usage {
+        name: "CLUSTER DECOMMISSION",
+        synopsis: "CLUSTER DECOMMISSION <node_id>",
+        args: &[("node_id", "string")],
+        examples: &["CLUSTER DECOMMISSION node_3"],
+        related: &["CLUSTER STATUS"],
+        about: None,
+    },
+    Usage {
+        name: "CLUSTER PAUSE",
+        synopsis: "CLUSTER PAUSE [--node] [reason]",
+        args: &[("node", "flag"), ("reason", "text")],
+        examples: &["CLUSTER PAUSE migrating to v2", "CLUSTER PAUSE --node"],
+        related: &["CLUSTER RESUME"],
+        about: None,
+    },
+    Usage {
+        name: "CLUSTER RESUME",
+        synopsis: "CLUSTER RESUME [--node]",
+        args: &[("node", "flag")],
+        examples: &["CLUSTER RESUME"],
+        related: &["CLUSTER PAUSE"],
+        about: None,
+    },
+    Usage {
+        name: "NODE STATUS",
+        synopsis: "NODE STATUS",
+        args: &[],
+        examples: &["NODE STATUS"],
+        related: &["CLUSTER STATUS", "INFO"],
+        about: None,
+    },
+    Usage {
+        name: "PIPE",
+        synopsis: "PIPE ... END",
+        args: &[],
+        examples: &["PIPE", "CINC views 1", "SADD seen alice", "END"],
+        related: &["EVAL"],
+        about: Some("Queue the commands that follow and send them in one batch at END"),
+    },
+    Usage {
+        name: "ALIAS",
+        synopsis: "ALIAS [<name> = <command>]",
+        args: &[],
+        examples: &["ALIAS v = CGET views", "ALIAS"],
+        related: &["UNALIAS", "MACRO"],
+        about: Some("Define a shorthand for a command, or list them. Kept in ~/.mergedbrc"),
+    },
+    Usage {
+        name: "MACRO",
+        synopsis: "MACRO [<name> <params...> = <command>; <command>...]",
+        args: &[],
+        examples: &["MACRO bump key = CINC $key 1; CGET $key", "MACRO"],
+        related: &["UNMACRO", "ALIAS"],
+        about: Some("Define a named series of commands with parameters, or list them"),
+    },
+    Usage {
+        name: "UNALIAS",
+        synopsis: "UNALIAS <name>",
+        args: &[],
+        examples: &["UNALIAS v"],
+        related: &["ALIAS"],
+        about: Some("Remove an alias"),
+    },
+    Usage {
+        name: "UNMACRO",
+        synopsis: "UNMACRO <name>",
+        args: &[],
+        examples: &["UNMACRO bump"],
+        related: &["MACRO"],
+        about: Some("Remove a macro"),
+    },
+    Usage {
+        name: "HELP",
+        synopsis: "HELP [command]",
+        args: &[],
+        examples: &["HELP", "HELP SADD", "HELP CLUSTER PAUSE"],
+        related: &[],
+        about: Some("List the commands, or show one in detail"),
+    },
+    Usage {
+        name: "EXIT",
+        synopsis: "EXIT",
+        args: &[],
+        examples: &["EXIT"],
+        related: &[],
+        about: Some("Leave the REPL"),
+    },
+];
+
+//case doesn't matter, nor does the spacing between the words of a subcommand
+pub fn lookup(name: &str) -> Option<&'static Usage> {
+    let name = name.split_whitespace().collect::<Vec<_>>().join(" ");
+    COMMANDS
+        .iter()
+        .find(|usage| usage.name.eq_ignore_ascii_case(&name))
+}
+
+//the clap command for a REPL command, None for the REPL's own
+fn clap_command(usage: &Usage) -> Option<Command> {
+    if usage.about.is_some() {
+        return None;
+    }
+    let mut command = Cli::command();
+    for word in usage.name.split_whitespace() {
+        command = command.find_subcommand(word.to_lowercase())?.clone();
+    }
+    Some(command)
+}
+
+//flags go by how they are typed
+fn arg_label(arg: &Arg) -> String {
+    match arg.get_long() {
+        Some(long) => format!("--{}", long),
+        None => arg.get_id().to_string(),
+    }
+}
+
+//the detailed usage HELP <command> prints
+pub fn render(usage: &Usage) -> String {
+    let command = clap_command(usage);
+    let about = match (&command, usage.about) {
+        (_, Some(about)) => about.to_string(),
+        (Some(command), None) => command
+            .get_long_about()
+            .or(command.get_about())
+            .map(|about| about.to_string())
+            .unwrap_or_default(),
+        (None, None) => String::new(),
+    };
+    let mut out = format!("{}\n  {}\n", usage.synopsis, about);
+
+    let args: Vec<(String, &str, String)> = usage
+        .args
+        .iter()
+        .map(|(id, kind)| {
+            let arg = command
+                .as_ref()
+                .and_then(|command| command.get_arguments().find(|arg| arg.get_id() == *id));
+            let label = arg.map(arg_label).unwrap_or_else(|| id.to_string());
+            let help = arg
+                .and_then(Arg::get_help)
+                .map(|help| help.to_string())
+                .unwrap_or_default();
+            (label, *kind, help)
+        })
+        .collect();
+    if !args.is_empty() {
+        let width = args
+            .iter()
+            .map(|(label, ..)| label.len())
+            .max()
+            .unwrap_or(0);
+        let kind_width = args
+            .iter()
+            .map(|(_, kind, _)| kind.len())
+            .max()
+            .unwrap_or(0);
+        out.push_str("\narguments:\n");
+        for (label, kind, help) in &args {
+            let line = format!("  {:width$}  {:kind_width$}  {}", label, kind, help);
+            out.push_str(line.trim_end());
+            out.push('\n');
+        }
+    }
+    if !usage.examples.is_empty() {
+        out.push_str("\nexamples:\n");
+        for example in usage.examples {
+            out.push_str(&format!("  {}\n", example));
+        }
+    }
+    if !usage.related.is_empty() {
+        out.push_str(&format!("\nsee also: {}\n", usage.related.join(", ")));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //clap commands the REPL has no use for
+    const CLI_ONLY: [&str; 4] = ["interactive", "export", "top", "completions"];
+
+    //every command the REPL documents with clap's words has a clap definition with the same
+    //arguments, and every clap command is documented
+    #[test]
+    fn test_table_matches_the_clap_definitions() {
+        for usage in COMMANDS {
+            let Some(command) = clap_command(usage) else {
+                assert!(usage.about.is_some(), "{} has no clap command", usage.name);
+                continue;
+            };
+            for (id, _) in usage.args {
+                assert!(
+                    command.get_arguments().any(|arg| arg.get_id() == *id),
+                    "{} has no argument {}",
+                    usage.name,
+                    id
+                );
+            }
+            for arg in command.get_positionals() {
+                assert!(
+                    usage.args.iter().any(|(id, _)| arg.get_id() == *id),
+                    "{} doesn't document {}",
+                    usage.name,
+                    arg.get_id()
+                );
+            }
+            for related in usage.related {
+                assert!(
+                    lookup(related).is_some(),
+                    "{} refers to {}",
+                    usage.name,
+                    related
+                );
+            }
+        }
+
+        for command in Cli::command().get_subcommands() {
+            let name = command.get_name();
+            if CLI_ONLY.contains(&name) || name == "help" {
+                continue;
+            }
+            let names: Vec<String> = match command.has_subcommands() {
+                true => command
+                    .get_subcommands()
+                    .filter(|sub| sub.get_name() != "help")
+                    .map(|sub| format!("{} {}", name, sub.get_name()))
+                    .collect(),
+                false => vec![name.to_string()],
+            };
+            for name in names {
+                assert!(lookup(&name).is_some(), "HELP doesn't know {}", name);
+            }
+        }
+    }
+
+    #[test]
+    fn test_help_for_one_command() {
+        let usage = lookup("cluster   pause").unwrap();
+        assert_eq!(usage.name, "CLUSTER PAUSE");
+
+        let help = render(lookup("sadd").unwrap());
+        assert!(help.starts_with("SADD <key> <tag> [--type"));
+        assert!(help.contains("\n  Add to a set\n"));
+        assert!(help.contains("  --type      int|float|bool|bytes  Send the tag as an int"));
+        assert!(help.contains("  key         string\n"));
+        assert!(help.contains("\nsee also: SREM, SGET, SMETA, SLIMIT\n"));
+
+        let help = render(lookup("pipe").unwrap());
+        assert!(help.contains("Queue the commands"));
+        assert!(!help.contains("arguments:"));
+        assert!(lookup("nope").is_none());
+    }
+}
@@ -0,0 +1,163 @@
+use serde_json::json;
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+    time::Instant,
+};
+
+use crate::config::CounterAnomaly;
+
+//how many past windows a rate is compared against
+const HISTORY: usize = 30;
+
+//a counter that moved by the same amount every window has no deviation at all, without a
+//floor the first +1 after that would be flagged
+const MIN_STDDEV: f64 = 1.0;
+
+//per contributor rather than per key, a replica that starts double counting shows up as its
+//own contribution jumping while everyone else's stays put
+#[derive(Debug, Default)]
+struct Series {
+    last_value: i64,
+    last_at: Option<Instant>,
+    rates: VecDeque<f64>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Anomaly {
+    pub key: String,
+    pub contributor: String,
+    pub rate: f64,
+    pub mean: f64,
+    pub stddev: f64,
+}
+
+impl Anomaly {
+    pub fn to_event(&self, node_id: &str, sigma: f64) -> serde_json::Value {
+        json!({
+            "event": "counter_rate_anomaly",
+            "node_id": node_id,
+            "key": self.key,
+            "contributor": self.contributor,
+            "rate_per_sec": self.rate,
+            "mean_per_sec": self.mean,
+            "stddev_per_sec": self.stddev,
+            "sigma": sigma,
+        })
+    }
+}
+
+//flags a counter contribution whose rate of change strays more than `sigma` standard
+//deviations from its recent history. it only looks at values as they are sampled, so it
+//can't tell a real burst of traffic from a misbehaving replica, it just says when to look
+#[derive(Debug)]
+pub struct AnomalyDetector {
+    pub rules: CounterAnomaly,
+    series: Mutex<HashMap<(String, String), Series>>,
+}
+
+impl AnomalyDetector {
+    pub fn new(rules: CounterAnomaly) -> Self {
+        AnomalyDetector {
+            rules,
+            series: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn watches(&self, key: &str) -> bool {
+        key.starts_with(&self.rules.prefix)
+    }
+
+    //records one sample of a contributor's share of a counter
+    pub fn observe(
+        &self,
+        key: &str,
+        contributor: &str,
+        value: i64,
+        at: Instant,
+    ) -> Option<Anomaly> {
+        let mut series = self.series.lock().unwrap();
+        let series = series
+            .entry((key.to_string(), contributor.to_string()))
+            .or_default();
+        let Some(last_at) = series.last_at.replace(at) else {
+            series.last_value = value;
+            return None;
+        };
+        let elapsed = at.duration_since(last_at).as_secs_f64();
+        let delta = value - std::mem::replace(&mut series.last_value, value);
+        if elapsed <= 0.0 {
+            return None;
+        }
+        let rate = delta as f64 / elapsed;
+
+        let mut anomaly = None;
+        if series.rates.len() >= self.rules.min_samples.max(2) {
+            let count = series.rates.len() as f64;
+            let mean = series.rates.iter().sum::<f64>() / count;
+            let variance =
+                series.rates.iter().map(|r| (r - mean).powi(2)).sum::<f64>() / (count - 1.0);
+            let stddev = variance.sqrt();
+            if (rate - mean).abs() > self.rules.sigma * stddev.max(MIN_STDDEV) {
+                anomaly = Some(Anomaly {
+                    key: key.to_string(),
+                    contributor: contributor.to_string(),
+                    rate,
+                    mean,
+                    stddev,
+                });
+            }
+        }
+
+        if series.rates.len() == HISTORY {
+            series.rates.pop_front();
+        }
+        series.rates.push_back(rate);
+        anomaly
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn rules() -> CounterAnomaly {
+        CounterAnomaly {
+            prefix: "views:".to_string(),
+            window: Duration::from_secs(10),
+            sigma: 4.0,
+            min_samples: 5,
+            webhook: None,
+        }
+    }
+
+    #[test]
+    fn test_spike_is_flagged_after_enough_history() {
+        let detector = AnomalyDetector::new(rules());
+        let start = Instant::now();
+        let at = |window: u64| start + Duration::from_secs(window * 10);
+
+        //steady at ~100/window, with a bit of jitter
+        let mut value = 0;
+        for window in 0..8 {
+            value += 100 + (window as i64 % 3) * 5;
+            assert_eq!(
+                detector.observe("views:home", "node_2", value, at(window)),
+                None
+            );
+        }
+
+        //node_2 starts counting everything twice
+        value += 210;
+        let anomaly = detector
+            .observe("views:home", "node_2", value, at(8))
+            .unwrap();
+        assert_eq!(anomaly.contributor, "node_2");
+        assert!(anomaly.rate > 20.0 && anomaly.mean < 11.0);
+
+        //other contributors have their own history
+        assert_eq!(detector.observe("views:home", "node_3", 5, at(8)), None);
+        assert!(!detector.watches("likes:home"));
+    }
+}
@@ -0,0 +1,266 @@
+//[audit]: a canary for replication bugs. every interval the node picks a few random keys and a
+//few random peers, asks the peers for their state of those keys and merges everything it got
+//with its own. a peer whose state is behind the merge is missing updates. that only counts
+//when this node holds the whole merge itself, then it knows the missing updates were here at
+//least since it last changed the key, and it reports the peer once that is longer than grace
+//ago. a key this node is behind on itself is left to the auditors of the nodes that aren't
+use mergedb_proto::CrdtProto;
+use mergedb_types::CrdtValue;
+use rand::seq::IteratorRandom;
+use serde_json::json;
+use std::{collections::HashMap, time::Duration};
+use tonic::Request;
+
+use crate::{
+    communication::SampleRequest,
+    fingerprint,
+    network::{ReplicationServer, StoredValue},
+    webhook,
+};
+
+//the most keys a Sample is answered for
+pub const MAX_SAMPLE: usize = 1000;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Finding {
+    pub peer: String,
+    pub key: String,
+    //at least this long, see above
+    pub missing_for: Duration,
+}
+
+impl Finding {
+    pub fn to_event(&self, node_id: &str) -> serde_json::Value {
+        json!({
+            "event": "stale_replica",
+            "node_id": node_id,
+            "peer": self.peer,
+            "key": self.key,
+            "missing_for_secs": self.missing_for.as_secs(),
+        })
+    }
+}
+
+//the peers behind on a key, given this node's value and what each peer answered, None for a
+//peer that doesn't hold the key at all
+pub fn judge(
+    key: &str,
+    local: &StoredValue,
+    answers: &[(String, Option<CrdtValue>)],
+    grace: Duration,
+) -> Vec<Finding> {
+    let mut merged = local.data.clone();
+    for value in answers.iter().filter_map(|(_, value)| value.as_ref()) {
+        if let Err(e) = merged.merge_with(value) {
+            println!("audit: {} for {}", e, key);
+            return Vec::new();
+        }
+    }
+    let complete = fingerprint::key_hash(key, &merged);
+    if fingerprint::key_hash(key, &local.data) != complete {
+        return Vec::new();
+    }
+    let missing_for = local.last_updated.elapsed().unwrap_or(Duration::ZERO);
+    if missing_for < grace {
+        return Vec::new();
+    }
+    answers
+        .iter()
+        .filter(|(_, value)| match value {
+            Some(value) => fingerprint::key_hash(key, value) != complete,
+            None => true,
+        })
+        .map(|(peer, _)| Finding {
+            peer: peer.clone(),
+            key: key.to_string(),
+            missing_for,
+        })
+        .collect()
+}
+
+//the peer's state of the keys it holds
+async fn sample(
+    server: &ReplicationServer,
+    peer_addr: &str,
+    keys: &[String],
+) -> anyhow::Result<HashMap<String, CrdtValue>> {
+    let request = Request::new(SampleRequest {
+        node_id: server.config.node_id.clone(),
+        cluster_id: server.config.cluster_id().to_string(),
+        keys: keys.to_vec(),
+    });
+    let states = server
+        .peer_client(peer_addr)
+        .await?
+        .sample(request)
+        .await?
+        .into_inner()
+        .states;
+    let mut values = HashMap::new();
+    for (key, state) in states {
+        values.insert(key, CrdtValue::from_proto(state)?);
+    }
+    Ok(values)
+}
+
+//one round, the findings are reported as they are returned
+pub async fn round(server: &ReplicationServer) -> Vec<Finding> {
+    let Some(config) = server.config.audit.clone() else {
+        return Vec::new();
+    };
+    let keys: Vec<String> = server
+        .store
+        .iter()
+        .map(|entry| entry.key().clone())
+        .choose_multiple(&mut rand::rng(), config.keys);
+    let peer_addrs: Vec<String> = server
+        .peers
+        .iter()
+        .map(|entry| entry.key().clone())
+        .filter(|peer_addr| !server.membership.is_witness_at(peer_addr))
+        .filter(|peer_addr| server.breakers.allow(peer_addr))
+        .choose_multiple(&mut rand::rng(), config.replicas);
+    if keys.is_empty() || peer_addrs.is_empty() {
+        return Vec::new();
+    }
+
+    //asked before this node's values are read, an update that reaches a peer while the
+    //others are asked can only make it look ahead, never behind
+    let mut answered = Vec::new();
+    for peer_addr in peer_addrs {
+        match sample(server, &peer_addr, &keys).await {
+            Ok(values) => answered.push((peer_addr, values)),
+            Err(e) => println!("audit: failed to sample {}: {}", peer_addr, e),
+        }
+    }
+
+    let mut findings = Vec::new();
+    for key in &keys {
+        let Some(local) = server.store.get(key).map(|stored| StoredValue {
+            data: stored.data.clone(),
+            last_updated: stored.last_updated,
+        }) else {
+            continue;
+        };
+        let answers: Vec<(String, Option<CrdtValue>)> = answered
+            .iter()
+            .map(|(peer_addr, values)| (peer_addr.clone(), values.get(key).cloned()))
+            .collect();
+        server.metrics.incr("audit_keys_checked_total", 1);
+        findings.extend(judge(key, &local, &answers, config.grace));
+    }
+    for finding in &findings {
+        eprintln!(
+            "warning: audit found {} missing updates to {} that are at least {}s old",
+            finding.peer,
+            finding.key,
+            finding.missing_for.as_secs()
+        );
+        server.metrics.incr("audit_stale_keys_total", 1);
+        server.metrics.incr(
+            &format!("audit_stale_keys_total{{peer=\"{}\"}}", finding.peer),
+            1,
+        );
+        webhook::notify(
+            config.webhook.clone(),
+            finding.to_event(&server.config.node_id),
+        );
+    }
+    findings
+}
+
+//a round every interval for as long as the node runs
+pub async fn run(server: &ReplicationServer) {
+    let Some(config) = server.config.audit.clone() else {
+        return;
+    };
+    let mut interval = tokio::time::interval(config.interval);
+    //the first tick is right away, give gossip a round to settle first
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        round(server).await;
+    }
+}
+
+//the Sample rpc
+pub fn answer(
+    server: &ReplicationServer,
+    keys: &[String],
+) -> HashMap<String, mergedb_proto::communication::CrdtData> {
+    let format = server.membership.write_format();
+    keys.iter()
+        .take(MAX_SAMPLE)
+        .filter_map(|key| {
+            server.ensure_loaded(key);
+            let value = server.store.get(key)?.data.clone();
+            Some((key.clone(), value.to_proto_as(format)))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mergedb_types::{lww_register::LwwRegister, pn_counter::PNCounter};
+    use std::time::SystemTime;
+
+    fn register(value: &str, node_id: &str) -> LwwRegister {
+        let mut register = LwwRegister::new(node_id.to_string());
+        register.set(value.to_string(), node_id.to_string());
+        register
+    }
+
+    fn stored(data: CrdtValue, age: Duration) -> StoredValue {
+        StoredValue {
+            data,
+            last_updated: SystemTime::now() - age,
+        }
+    }
+
+    #[test]
+    fn test_peers_behind_this_node_are_reported() {
+        let old = register("v1", "n1");
+        let mut new = old.clone();
+        new.set("v2".to_string(), "n1".to_string());
+        let local = stored(CrdtValue::Register(new.clone()), Duration::from_secs(120));
+        let answers = vec![
+            ("p1".to_string(), Some(CrdtValue::Register(new.clone()))),
+            ("p2".to_string(), Some(CrdtValue::Register(old))),
+            ("p3".to_string(), None),
+        ];
+        let findings = judge("k", &local, &answers, Duration::from_secs(30));
+        let peers: Vec<&str> = findings.iter().map(|f| f.peer.as_str()).collect();
+        assert_eq!(peers, vec!["p2", "p3"]);
+        assert!(findings[0].missing_for >= Duration::from_secs(120));
+        assert_eq!(findings[0].to_event("n1")["event"], "stale_replica");
+
+        //still within grace, the update may be on its way
+        let fresh = stored(CrdtValue::Register(new), Duration::ZERO);
+        assert!(judge("k", &fresh, &answers, Duration::from_secs(30)).is_empty());
+    }
+
+    #[test]
+    fn test_nothing_is_reported_when_this_node_is_behind() {
+        let mut counter = PNCounter::new("n1".to_string(), 0, 0);
+        counter.checked_add("n1".to_string(), 1);
+        let mut ahead = counter.clone();
+        ahead.checked_add("n2".to_string(), 5);
+        let local = stored(
+            CrdtValue::Counter(counter.clone()),
+            Duration::from_secs(120),
+        );
+        let answers = vec![
+            ("p1".to_string(), Some(CrdtValue::Counter(ahead))),
+            ("p2".to_string(), Some(CrdtValue::Counter(counter))),
+        ];
+        assert!(judge("k", &local, &answers, Duration::ZERO).is_empty());
+
+        //nor for a peer holding another type, that isn't a missing update
+        let conflicting = vec![(
+            "p1".to_string(),
+            Some(CrdtValue::Register(register("v", "n2"))),
+        )];
+        assert!(judge("k", &local, &conflicting, Duration::ZERO).is_empty());
+    }
+}
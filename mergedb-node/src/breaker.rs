@@ -0,0 +1,220 @@
+//a circuit breaker per peer, so a peer that is down doesn't cost a connection attempt on every
+//write. while closed every call goes through. after `failures` failures in a row it opens and
+//the peer is skipped for a cooldown, jittered so that breakers opened together don't all
+//retry together. then a single call is let through (half open): success closes the breaker,
+//failure opens it again for twice as long, up to max_cooldown
+use dashmap::DashMap;
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crate::{config::PeerBreaker, metrics::Metrics};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+impl State {
+    pub fn name(&self) -> &'static str {
+        match self {
+            State::Closed => "closed",
+            State::Open => "open",
+            State::HalfOpen => "half-open",
+        }
+    }
+
+    //what peer_breaker_state is set to
+    fn gauge(&self) -> i64 {
+        match self {
+            State::Closed => 0,
+            State::Open => 1,
+            State::HalfOpen => 2,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Breaker {
+    state: State,
+    //in a row, reset by any success
+    failures: u32,
+    //how often it opened again straight after a half-open call, the cooldown doubles each time
+    reopened: u32,
+    //when open, the end of the cooldown. when half open, when another call may be let through
+    //in case the last one never reported back
+    retry_at: Instant,
+}
+
+#[derive(Debug)]
+pub struct Breakers {
+    config: PeerBreaker,
+    metrics: Arc<Metrics>,
+    peers: DashMap<String, Breaker>,
+}
+
+impl Breakers {
+    pub fn new(config: PeerBreaker, metrics: Arc<Metrics>) -> Self {
+        Breakers {
+            config,
+            metrics,
+            peers: DashMap::new(),
+        }
+    }
+
+    pub fn state(&self, peer_addr: &str) -> State {
+        self.peers
+            .get(peer_addr)
+            .map(|breaker| breaker.state)
+            .unwrap_or(State::Closed)
+    }
+
+    //whether the peer should be tried now, a true has to be followed by a success or a failure
+    pub fn allow(&self, peer_addr: &str) -> bool {
+        let Some(mut breaker) = self.peers.get_mut(peer_addr) else {
+            return true;
+        };
+        match breaker.state {
+            State::Closed => true,
+            State::Open | State::HalfOpen if Instant::now() >= breaker.retry_at => {
+                breaker.retry_at = Instant::now() + self.cooldown(breaker.reopened);
+                self.transition(peer_addr, &mut breaker, State::HalfOpen);
+                true
+            }
+            State::Open | State::HalfOpen => false,
+        }
+    }
+
+    pub fn record_success(&self, peer_addr: &str) {
+        if let Some((_, mut breaker)) = self.peers.remove(peer_addr) {
+            if breaker.state != State::Closed {
+                self.transition(peer_addr, &mut breaker, State::Closed);
+            }
+        }
+    }
+
+    pub fn record_failure(&self, peer_addr: &str) {
+        let mut breaker = self
+            .peers
+            .entry(peer_addr.to_string())
+            .or_insert_with(|| Breaker {
+                state: State::Closed,
+                failures: 0,
+                reopened: 0,
+                retry_at: Instant::now(),
+            });
+        breaker.failures += 1;
+        let reopen = match breaker.state {
+            State::HalfOpen => true,
+            State::Closed => breaker.failures >= self.config.failures,
+            State::Open => false,
+        };
+        if reopen {
+            if breaker.state == State::HalfOpen {
+                breaker.reopened += 1;
+            }
+            breaker.retry_at = Instant::now() + self.jittered(self.cooldown(breaker.reopened));
+            self.transition(peer_addr, &mut breaker, State::Open);
+        }
+    }
+
+    //cooldown * 2^reopened, capped at max_cooldown
+    fn cooldown(&self, reopened: u32) -> Duration {
+        self.config
+            .cooldown
+            .saturating_mul(2u32.saturating_pow(reopened.min(31)))
+            .min(self.config.max_cooldown)
+    }
+
+    //somewhere between half the cooldown and all of it
+    fn jittered(&self, cooldown: Duration) -> Duration {
+        let ms = cooldown.as_millis() as u64;
+        Duration::from_millis(rand::random_range(ms / 2..=ms))
+    }
+
+    fn transition(&self, peer_addr: &str, breaker: &mut Breaker, to: State) {
+        let why = match to {
+            State::Open => format!(", {} failure(s) in a row", breaker.failures),
+            _ => String::new(),
+        };
+        println!(
+            "peer {} circuit {} -> {}{}",
+            peer_addr,
+            breaker.state.name(),
+            to.name(),
+            why
+        );
+        breaker.state = to;
+        if to == State::Open {
+            self.metrics.incr("peer_breaker_opened_total", 1);
+        }
+        self.metrics.set_gauge(
+            &format!("peer_breaker_state{{peer=\"{}\"}}", peer_addr),
+            to.gauge(),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn breakers() -> Breakers {
+        let config = PeerBreaker {
+            failures: 2,
+            cooldown: Duration::from_millis(20),
+            max_cooldown: Duration::from_millis(60),
+        };
+        Breakers::new(config, Arc::new(Metrics::new()))
+    }
+
+    fn wait_out(breakers: &Breakers, peer_addr: &str) {
+        let retry_at = breakers.peers.get(peer_addr).unwrap().retry_at;
+        std::thread::sleep(retry_at.saturating_duration_since(Instant::now()));
+    }
+
+    #[test]
+    fn test_opens_after_failures_in_a_row() {
+        let breakers = breakers();
+        let peer = "10.0.0.2:8000";
+        breakers.record_failure(peer);
+        assert!(breakers.allow(peer));
+        breakers.record_failure(peer);
+        assert_eq!(breakers.state(peer), State::Open);
+        assert!(!breakers.allow(peer));
+        assert_eq!(breakers.metrics.counter("peer_breaker_opened_total"), 1);
+
+        //one call once the cooldown is over, and no other until it reports back
+        wait_out(&breakers, peer);
+        assert!(breakers.allow(peer));
+        assert_eq!(breakers.state(peer), State::HalfOpen);
+        assert!(!breakers.allow(peer));
+
+        breakers.record_success(peer);
+        assert_eq!(breakers.state(peer), State::Closed);
+        assert!(breakers.allow(peer));
+        assert!(breakers.allow("10.0.0.3:8000"));
+    }
+
+    #[test]
+    fn test_cooldown_doubles_while_the_peer_stays_down() {
+        let breakers = breakers();
+        let peer = "10.0.0.2:8000";
+        breakers.record_failure(peer);
+        breakers.record_failure(peer);
+        for reopened in 1..=3 {
+            wait_out(&breakers, peer);
+            assert!(breakers.allow(peer));
+            let probed_at = Instant::now();
+            breakers.record_failure(peer);
+            assert_eq!(breakers.state(peer), State::Open);
+
+            let cooldown = Duration::from_millis(20 << reopened).min(Duration::from_millis(60));
+            let retry_in = breakers.peers.get(peer).unwrap().retry_at - probed_at;
+            assert!(retry_in >= cooldown / 2 && retry_in <= cooldown + Duration::from_millis(5));
+        }
+    }
+}
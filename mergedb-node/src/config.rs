@@ -1,16 +1,583 @@
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
 use serde::{Deserialize, Serialize};
 use std::{
     fs::File,
     io::{Read, Write},
+    net::{IpAddr, Ipv6Addr, SocketAddr},
     path::PathBuf,
+    time::Duration,
 };
 
+use crate::{digest::GossipMode, labels::LabelRule, units, wal::WalSync};
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Config {
+    //"auto" generates one on first start, with a data_dir it has to match the identity kept
+    //there, see the identity module
     pub node_id: String,
+    //names the cluster. peer traffic carrying another cluster_id is refused, so that a node
+    //pointed at the wrong seed can't merge two clusters' data. left out, nothing is checked
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cluster_id: Option<String>,
     pub listen_address: String,
-    pub peers: Vec<String>,
+    //client api and peer gossip can be split onto their own addresses, eg gossip on a private
+    //interface and clients on a public one. either one left out falls back to listen_address
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_listen_address: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub peer_listen_address: Option<String>,
+    pub peers: Vec<PeerConfig>,
+    //optional unix socket for clients on the same host
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uds_path: Option<PathBuf>,
+    //permission bits applied to the socket file, eg 0o660
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uds_mode: Option<u32>,
+    //durations and sizes take "500ms" / "5m" / "1MiB" style values, see the units module
+
+    //a peer that hasn't answered a heartbeat for this long counts as unreachable
+    #[serde(
+        default = "default_peer_timeout",
+        alias = "peer_timeout_secs",
+        with = "units::secs"
+    )]
+    pub peer_timeout: Duration,
+    //how long part of the cluster has to stay unreachable before split-brain is reported
+    #[serde(
+        default = "default_split_brain_after",
+        alias = "split_brain_after_secs",
+        with = "units::secs"
+    )]
+    pub split_brain_after: Duration,
+    //http endpoint that gets a json POST when a split-brain is detected or heals
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub split_brain_webhook: Option<String>,
+    //where the store is snapshotted to, nothing is persisted without it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data_dir: Option<PathBuf>,
+    #[serde(
+        default = "default_snapshot_every",
+        alias = "snapshot_interval_secs",
+        with = "units::secs"
+    )]
+    pub snapshot_every: Duration,
+    //key prefixes loaded from the snapshot before the node reports itself as serving, the rest
+    //is loaded in the background or when first touched
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub preload: Vec<String>,
+    //how often keys outside every gossip_priority class are gossiped, and heartbeats sent.
+    //follows the size of the cluster when left out, see the tuning module
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "units::optional_millis"
+    )]
+    pub gossip_interval: Option<Duration>,
+    //how many peers a client write is pushed to straight away, follows the size of the
+    //cluster when left out
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fanout: Option<usize>,
+    //keys under these prefixes are gossiped ahead of, and more often than, everything else
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub gossip_priority: Vec<GossipPriority>,
+    //"push" sends peers every dirty key, "digest" only the ones they turn out to need, see the
+    //digest module
+    #[serde(default)]
+    pub gossip_mode: GossipMode,
+    //client writes carrying a bigger value are refused
+    #[serde(default = "default_max_value_size", with = "units::size")]
+    pub max_value_size: u64,
+    //fence nodes off from this one, by node_id, by ip (a port is ignored, senders connect from
+    //ephemeral ports) or by both as "node_id@ip". gossip, batches and heartbeats from a node on
+    //gossip_deny, or missing from a non-empty gossip_allow, are refused. deny wins over allow.
+    //a node says its node_id itself, so only the ip holds against one that lies about it:
+    //gossip_allow entries need one, a node_id on gossip_deny only stops nodes that keep theirs
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub gossip_allow: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub gossip_deny: Vec<String>,
+    //off unless configured
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub counter_anomaly: Option<CounterAnomaly>,
+    //what one EVAL script may spend before it is stopped, in rhai operations and wall time
+    #[serde(default = "default_script_max_operations")]
+    pub script_max_operations: u64,
+    #[serde(default = "default_script_timeout", with = "units::millis")]
+    pub script_timeout: Duration,
+    //a witness takes part in membership, failure detection and split-brain arbitration but
+    //holds no data, eg the node at a third, tiny site that breaks ties between the other two
+    #[serde(default)]
+    pub witness: bool,
+    //where prometheus can scrape /metrics, nothing is served without it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metrics_address: Option<String>,
+    //where browsers can connect to ws://<websocket_address>/ws, nothing is served without it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub websocket_address: Option<String>,
+    //where frontends can send graphql queries to http://<graphql_address>/graphql, nothing is
+    //served without it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub graphql_address: Option<String>,
+    //lets browsers call the client rpcs with grpc-web, on the client listener itself
+    #[serde(default)]
+    pub grpc_web: bool,
+    #[serde(default)]
+    pub peer_breaker: PeerBreaker,
+    //nodes learnt from other nodes' heartbeats are gossiped with too, see the discovery module
+    #[serde(default = "default_discover_peers")]
+    pub discover_peers: bool,
+    //when a write to a node with a data_dir is answered: "always" once its wal record is
+    //synced, or right away with the wal synced every so often eg "10ms", or never ("os")
+    #[serde(default)]
+    pub wal_sync: WalSync,
+    //retention and webhooks for keys picked out by their labels, see the labels module
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub label_rules: Vec<LabelRule>,
+    #[serde(default)]
+    pub runtime: RuntimeConfig,
+    #[serde(default)]
+    pub request_queue: RequestQueue,
+    //client writes go down a broadcast tree instead of to K random peers, off unless configured
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub plumtree: Option<Plumtree>,
+    //compares random keys with random peers now and then, off unless configured
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub audit: Option<Audit>,
+    //warns about keys no peer has taken for a while, off unless configured. AUDIT lists them
+    //either way
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub watchdog: Option<Watchdog>,
+}
+
+//eg, so feature flags converge ahead of bulk counters:
+//[[gossip_priority]]
+//prefix = "flags:"
+//priority = 10
+//interval = "200ms"
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct GossipPriority {
+    pub prefix: String,
+    //higher goes first, keys outside every prefix are 0
+    pub priority: u8,
+    #[serde(
+        default = "default_gossip_interval",
+        alias = "interval_ms",
+        with = "units::millis"
+    )]
+    pub interval: Duration,
+}
+
+//flags counters whose rate of change suddenly strays from their history, eg:
+//[counter_anomaly]
+//prefix = "views:"
+//sigma = 4.0
+//webhook = "http://alerts.internal/mergedb"
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CounterAnomaly {
+    //only counters under this prefix are watched, all of them when empty
+    #[serde(default)]
+    pub prefix: String,
+    //how often counters are sampled, a rate is the change over one window
+    #[serde(
+        default = "default_anomaly_window",
+        alias = "window_secs",
+        with = "units::secs"
+    )]
+    pub window: Duration,
+    //how many standard deviations from the recent mean count as an anomaly
+    #[serde(default = "default_anomaly_sigma")]
+    pub sigma: f64,
+    //windows of history needed before anything gets flagged
+    #[serde(default = "default_anomaly_min_samples")]
+    pub min_samples: usize,
+    //http endpoint that gets a json POST per anomaly
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub webhook: Option<String>,
+}
+
+//a peer that failed this many times in a row isn't tried for a cooldown, which doubles every
+//time the next try fails too, eg:
+//[peer_breaker]
+//failures = 5
+//cooldown = "1s"
+//max_cooldown = "1m"
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PeerBreaker {
+    #[serde(default = "default_breaker_failures")]
+    pub failures: u32,
+    #[serde(default = "default_breaker_cooldown", with = "units::millis")]
+    pub cooldown: Duration,
+    #[serde(default = "default_breaker_max_cooldown", with = "units::millis")]
+    pub max_cooldown: Duration,
+}
+
+impl Default for PeerBreaker {
+    fn default() -> Self {
+        PeerBreaker {
+            failures: default_breaker_failures(),
+            cooldown: default_breaker_cooldown(),
+            max_cooldown: default_breaker_max_cooldown(),
+        }
+    }
+}
+
+//how many threads the node runs on, eg for a small machine where gossip shouldn't hold up
+//clients:
+//[runtime]
+//worker_threads = 2
+//max_blocking_threads = 16
+//gossip_threads = 1
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct RuntimeConfig {
+    //threads serving client requests and everything else, one per core when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub worker_threads: Option<usize>,
+    //threads for blocking work such as snapshots and the wal replay, tokio's 512 when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_blocking_threads: Option<usize>,
+    //when set, peer connections, the gossip loop and a separate peer listener get a runtime of
+    //their own with this many threads, so heavy replication can't starve clients. unset, they
+    //share the workers
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gossip_threads: Option<usize>,
+}
+
+impl RuntimeConfig {
+    pub fn build(&self) -> std::io::Result<tokio::runtime::Runtime> {
+        let mut builder = tokio::runtime::Builder::new_multi_thread();
+        builder.enable_all().thread_name("mergedb-worker");
+        if let Some(threads) = self.worker_threads {
+            builder.worker_threads(threads);
+        }
+        if let Some(threads) = self.max_blocking_threads {
+            builder.max_blocking_threads(threads);
+        }
+        builder.build()
+    }
+
+    //None when gossip shares the client runtime
+    pub fn build_gossip(&self) -> Option<std::io::Result<tokio::runtime::Runtime>> {
+        let threads = self.gossip_threads?;
+        Some(
+            tokio::runtime::Builder::new_multi_thread()
+                .enable_all()
+                .thread_name("mergedb-gossip")
+                .worker_threads(threads)
+                .build(),
+        )
+    }
+}
+
+//client commands (PropagateData and PropagateBatch) run at most max_in_flight at a time, the
+//rest wait their turn, highest priority first, and are refused with RESOURCE_EXHAUSTED once
+//max_queued are waiting, eg:
+//[request_queue]
+//max_in_flight = 64
+//max_queued = 1024
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RequestQueue {
+    #[serde(default = "default_max_in_flight")]
+    pub max_in_flight: usize,
+    #[serde(default = "default_max_queued")]
+    pub max_queued: usize,
+}
+
+impl Default for RequestQueue {
+    fn default() -> Self {
+        RequestQueue {
+            max_in_flight: default_max_in_flight(),
+            max_queued: default_max_queued(),
+        }
+    }
+}
+
+//pushes every client write to the peers of a spanning tree, which pass it on, and only
+//announces it to the rest, see the plumtree module, eg:
+//[plumtree]
+//announce_interval = "200ms"
+//graft_timeout = "1s"
+//message_ttl = "30s"
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Plumtree {
+    //how often queued announcements go out to the peers off the tree
+    #[serde(default = "default_announce_interval", with = "units::millis")]
+    pub announce_interval: Duration,
+    //how long an announced message may take to arrive before it is asked for
+    #[serde(default = "default_graft_timeout", with = "units::millis")]
+    pub graft_timeout: Duration,
+    //how long messages are kept for peers that ask for them, and to spot duplicates
+    #[serde(default = "default_message_ttl", with = "units::secs")]
+    pub message_ttl: Duration,
+}
+
+impl Default for Plumtree {
+    fn default() -> Self {
+        Plumtree {
+            announce_interval: default_announce_interval(),
+            graft_timeout: default_graft_timeout(),
+            message_ttl: default_message_ttl(),
+        }
+    }
+}
+
+//every interval, compares a few random keys with a few random peers and reports each peer that
+//is missing updates this node has held for longer than grace, see the audit module, eg:
+//[audit]
+//interval = "1m"
+//keys = 20
+//replicas = 2
+//grace = "30s"
+//webhook = "http://alerts.internal/mergedb"
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Audit {
+    #[serde(default = "default_audit_interval", with = "units::secs")]
+    pub interval: Duration,
+    //keys sampled per round
+    #[serde(default = "default_audit_keys")]
+    pub keys: usize,
+    //peers they are compared with per round
+    #[serde(default = "default_audit_replicas")]
+    pub replicas: usize,
+    //updates younger than this may still be on their way, a peer missing them isn't reported
+    #[serde(default = "default_audit_grace", with = "units::secs")]
+    pub grace: Duration,
+    //http endpoint that gets a json POST per stale key
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub webhook: Option<String>,
+}
+
+impl Default for Audit {
+    fn default() -> Self {
+        Audit {
+            interval: default_audit_interval(),
+            keys: default_audit_keys(),
+            replicas: default_audit_replicas(),
+            grace: default_audit_grace(),
+            webhook: None,
+        }
+    }
+}
+
+//every interval, looks for keys that changed here and that no peer has taken for longer than
+//stalled_after, see the watchdog module, eg:
+//[watchdog]
+//interval = "30s"
+//stalled_after = "5m"
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Watchdog {
+    #[serde(default = "default_watchdog_interval", with = "units::secs")]
+    pub interval: Duration,
+    //well past a few gossip rounds, a key only that far behind is merely waiting for its turn
+    #[serde(default = "default_stalled_after", with = "units::secs")]
+    pub stalled_after: Duration,
+}
+
+impl Default for Watchdog {
+    fn default() -> Self {
+        Watchdog {
+            interval: default_watchdog_interval(),
+            stalled_after: default_stalled_after(),
+        }
+    }
+}
+
+fn default_watchdog_interval() -> Duration {
+    Duration::from_secs(30)
+}
+
+fn default_stalled_after() -> Duration {
+    Duration::from_secs(300)
+}
+
+fn default_audit_interval() -> Duration {
+    Duration::from_secs(60)
+}
+
+fn default_audit_keys() -> usize {
+    20
+}
+
+fn default_audit_replicas() -> usize {
+    2
+}
+
+fn default_audit_grace() -> Duration {
+    Duration::from_secs(30)
+}
+
+fn default_announce_interval() -> Duration {
+    Duration::from_millis(200)
+}
+
+fn default_graft_timeout() -> Duration {
+    Duration::from_secs(1)
+}
+
+fn default_message_ttl() -> Duration {
+    Duration::from_secs(30)
+}
+
+fn default_max_in_flight() -> usize {
+    256
+}
+
+fn default_max_queued() -> usize {
+    4096
+}
+
+fn default_breaker_failures() -> u32 {
+    5
+}
+
+fn default_breaker_cooldown() -> Duration {
+    Duration::from_secs(1)
+}
+
+fn default_breaker_max_cooldown() -> Duration {
+    Duration::from_secs(60)
+}
+
+fn default_anomaly_window() -> Duration {
+    Duration::from_secs(10)
+}
+
+fn default_anomaly_sigma() -> f64 {
+    4.0
+}
+
+fn default_anomaly_min_samples() -> usize {
+    6
+}
+
+fn default_peer_timeout() -> Duration {
+    Duration::from_secs(10)
+}
+
+fn default_split_brain_after() -> Duration {
+    Duration::from_secs(30)
+}
+
+fn default_snapshot_every() -> Duration {
+    Duration::from_secs(60)
+}
+
+fn default_discover_peers() -> bool {
+    true
+}
+
+fn default_gossip_interval() -> Duration {
+    Duration::from_secs(2)
+}
+
+//grpc's default message limit, anything bigger wouldn't make it through gossip anyway
+fn default_max_value_size() -> u64 {
+    4 << 20
+}
+
+fn default_script_max_operations() -> u64 {
+    100_000
+}
+
+//scripts hold every other client command back while they run
+fn default_script_timeout() -> Duration {
+    Duration::from_millis(250)
+}
+
+//a peer can either be given as a bare "host:port" string, or as a table when it needs
+//more than that, eg:
+//peers = ["node2:8000", { address = "node3:8443", scheme = "https", proxy = "http://proxy:3128" }]
+//peers are always written back out in the table form
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(from = "PeerEntry")]
+pub struct PeerConfig {
+    pub address: String,
+    pub scheme: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tls: Option<PeerTlsConfig>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub proxy: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub connect_timeout_ms: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct PeerTlsConfig {
+    //overrides the SNI / certificate name, for when the address is an ip or a proxy hostname
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub domain_name: Option<String>,
+    //pem encoded CA to trust for this peer, system roots are used otherwise
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ca_cert: Option<PathBuf>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PeerEntry {
+    Address(String),
+    Detailed {
+        address: String,
+        #[serde(default)]
+        scheme: Option<String>,
+        #[serde(default)]
+        tls: Option<PeerTlsConfig>,
+        #[serde(default)]
+        proxy: Option<String>,
+        #[serde(default)]
+        connect_timeout_ms: Option<u64>,
+    },
+}
+
+impl From<PeerEntry> for PeerConfig {
+    fn from(entry: PeerEntry) -> Self {
+        match entry {
+            PeerEntry::Address(address) => PeerConfig::new(address),
+            PeerEntry::Detailed {
+                address,
+                scheme,
+                tls,
+                proxy,
+                connect_timeout_ms,
+            } => {
+                let mut peer = PeerConfig::new(address);
+                if let Some(scheme) = scheme {
+                    peer.scheme = scheme;
+                }
+                //asking for tls options without a scheme implies https
+                if tls.is_some() && !peer.uses_tls() {
+                    peer.scheme = String::from("https");
+                }
+                peer.tls = tls;
+                peer.proxy = proxy;
+                peer.connect_timeout_ms = connect_timeout_ms;
+                peer
+            }
+        }
+    }
+}
+
+impl PeerConfig {
+    //accepts "host:port" as well as "http://host:port" / "https://host:port"
+    pub fn new(address: String) -> Self {
+        let (scheme, address) = match address.split_once("://") {
+            Some((scheme, rest)) => (scheme.to_string(), rest.to_string()),
+            None => (String::from("http"), address),
+        };
+        PeerConfig {
+            address,
+            scheme,
+            tls: None,
+            proxy: None,
+            connect_timeout_ms: None,
+        }
+    }
+
+    pub fn uses_tls(&self) -> bool {
+        self.scheme == "https"
+    }
+
+    pub fn uri(&self) -> String {
+        format!("{}://{}", self.scheme, self.address)
+    }
 }
 
 impl Config {
@@ -20,6 +587,7 @@ impl Config {
         file.read_to_string(&mut contents)?;
 
         let new_config: Self = toml::from_str(&contents)?;
+        new_config.validate()?;
 
         Ok(new_config)
     }
@@ -27,10 +595,585 @@ impl Config {
     pub fn store_config(node: &Self, config_path: PathBuf) -> Result<()> {
         let mut file = File::create(&config_path)?;
 
-        let contents = toml::to_string(node)?;
+        //going through a Value makes toml emit plain fields before the peer tables
+        let contents = toml::to_string(&toml::Value::try_from(node)?)?;
 
         file.write_all(contents.as_bytes())?;
 
         Ok(())
     }
+
+    //addresses are only checked for shape here, hostnames get resolved when connecting
+    pub fn validate(&self) -> Result<()> {
+        split_host_port(&self.listen_address).context("invalid listen_address")?;
+        if self.cluster_id.as_deref() == Some("") {
+            bail!("cluster_id can't be empty, leave it out instead");
+        }
+        if let Some(address) = &self.client_listen_address {
+            split_host_port(address).context("invalid client_listen_address")?;
+        }
+        if let Some(address) = &self.peer_listen_address {
+            split_host_port(address).context("invalid peer_listen_address")?;
+        }
+        for peer in &self.peers {
+            split_host_port(&peer.address)
+                .with_context(|| format!("invalid peer address {}", peer.address))?;
+        }
+        if let Some(rules) = &self.counter_anomaly {
+            if rules.window.is_zero() || rules.sigma <= 0.0 {
+                bail!("counter_anomaly needs a window and sigma above 0");
+            }
+        }
+        let breaker = &self.peer_breaker;
+        if breaker.failures == 0 || breaker.cooldown.is_zero() {
+            bail!("peer_breaker needs failures and a cooldown above 0");
+        }
+        if breaker.max_cooldown < breaker.cooldown {
+            bail!("peer_breaker max_cooldown can't be below its cooldown");
+        }
+        if let Some(rule) = self
+            .label_rules
+            .iter()
+            .find(|rule| rule.retention.is_some_and(|retention| retention.is_zero()))
+        {
+            bail!(
+                "label_rules retention for {:?} can't be 0",
+                rule.selector.to_string()
+            );
+        }
+        let runtime = &self.runtime;
+        let threads = [runtime.worker_threads, runtime.max_blocking_threads, runtime.gossip_threads];
+        if threads.contains(&Some(0)) {
+            bail!("runtime thread counts can't be 0");
+        }
+        if self.fanout == Some(0) || self.gossip_interval.is_some_and(|every| every.is_zero()) {
+            bail!("fanout and gossip_interval can't be 0");
+        }
+        if self.request_queue.max_in_flight == 0 {
+            bail!("request_queue.max_in_flight can't be 0");
+        }
+        if let Some(plumtree) = &self.plumtree {
+            if plumtree.announce_interval.is_zero() || plumtree.graft_timeout.is_zero() {
+                bail!("plumtree needs an announce_interval and graft_timeout above 0");
+            }
+            if plumtree.message_ttl < plumtree.graft_timeout {
+                bail!("plumtree message_ttl can't be below its graft_timeout");
+            }
+        }
+        if let Some(audit) = &self.audit {
+            if audit.interval.is_zero() || audit.keys == 0 || audit.replicas == 0 {
+                bail!("audit needs an interval, keys and replicas above 0");
+            }
+        }
+        if let Some(watchdog) = &self.watchdog {
+            if watchdog.interval.is_zero() || watchdog.stalled_after.is_zero() {
+                bail!("watchdog needs an interval and stalled_after above 0");
+            }
+        }
+        if let Some(entry) = self
+            .gossip_allow
+            .iter()
+            .chain(&self.gossip_deny)
+            .find(|entry| entry.trim().is_empty())
+        {
+            bail!("empty entry {:?} in gossip_allow or gossip_deny", entry);
+        }
+        if let Some(entry) = self
+            .gossip_allow
+            .iter()
+            .find(|entry| fence(entry).ip.is_none())
+        {
+            bail!(
+                "gossip_allow entry {:?} has no ip, any node can claim a node_id. \
+                 allow it by ip, or as \"{}@<ip>\"",
+                entry,
+                entry
+            );
+        }
+        Ok(())
+    }
+
+    //why gossip from `node_id` at `remote` is refused, None if it is accepted. either can be
+    //unknown, older nodes don't say who they are and unix sockets have no address
+    pub fn gossip_refusal(&self, node_id: &str, remote: Option<IpAddr>) -> Option<String> {
+        let sender = if node_id.is_empty() {
+            remote.map(|ip| ip.to_string()).unwrap_or_default()
+        } else {
+            node_id.to_string()
+        };
+        let remote = remote.map(|ip| ip.to_canonical());
+        let denies = |entry: &String| {
+            let fence = fence(entry);
+            (!node_id.is_empty() && fence.node_id == Some(node_id))
+                || (fence.ip.is_some() && fence.ip == remote)
+        };
+        //the ip has to match, and the node_id too when the entry names one
+        let allows = |entry: &String| {
+            let fence = fence(entry);
+            fence.ip.is_some()
+                && fence.ip == remote
+                && fence.node_id.is_none_or(|allowed| allowed == node_id)
+        };
+        if self.gossip_deny.iter().any(denies) {
+            return Some(format!("{} is on gossip_deny", sender));
+        }
+        if !self.gossip_allow.is_empty() && !self.gossip_allow.iter().any(allows) {
+            return Some(format!("{} is not on gossip_allow", sender));
+        }
+        None
+    }
+
+    //sent along with every peer message, empty without a cluster_id
+    pub fn cluster_id(&self) -> &str {
+        self.cluster_id.as_deref().unwrap_or_default()
+    }
+
+    //why traffic from `node_id` claiming `cluster_id` is refused. a node without a cluster_id, on
+    //either end, is let through so that a cluster can be given one a node at a time
+    pub fn cluster_refusal(&self, node_id: &str, cluster_id: &str) -> Option<String> {
+        let ours = self.cluster_id.as_deref()?;
+        if cluster_id.is_empty() || cluster_id == ours {
+            return None;
+        }
+        Some(format!(
+            "{} belongs to cluster {}, this is cluster {}",
+            if node_id.is_empty() {
+                "sender"
+            } else {
+                node_id
+            },
+            cluster_id,
+            ours
+        ))
+    }
+
+    pub fn client_address(&self) -> &str {
+        self.client_listen_address
+            .as_deref()
+            .unwrap_or(&self.listen_address)
+    }
+
+    //where other nodes reach this one
+    pub fn peer_address(&self) -> &str {
+        self.peer_listen_address
+            .as_deref()
+            .unwrap_or(&self.listen_address)
+    }
+
+    pub fn peer(&self, address: &str) -> Option<&PeerConfig> {
+        self.peers.iter().find(|peer| peer.address == address)
+    }
+
+    //drops peers that point back at this node or repeat an earlier entry, gossiping with
+    //either only merges state with itself. returns a warning for each peer dropped.
+    //hostnames can still hide this node or a duplicate, those get caught once they answer
+    //a heartbeat
+    pub fn dedup_peers(&mut self) -> Vec<String> {
+        let own = split_host_port(self.peer_address()).ok();
+        let mut seen = Vec::new();
+        let mut warnings = Vec::new();
+
+        self.peers.retain(|peer| {
+            let Ok((host, port)) = split_host_port(&peer.address) else {
+                return true;
+            };
+            let host = canonical_host(&host);
+            if let Some((own_host, own_port)) = &own {
+                let own_host = canonical_host(own_host);
+                //a node listening on every interface is also reachable on loopback
+                let listens_everywhere = own_host == "0.0.0.0" || own_host == "::";
+                if port == *own_port
+                    && (host == own_host || (listens_everywhere && is_loopback(&host)))
+                {
+                    warnings.push(format!(
+                        "peer {} is this node's own peer address, ignoring it",
+                        peer.address
+                    ));
+                    return false;
+                }
+            }
+            if seen.contains(&(host.clone(), port)) {
+                warnings.push(format!(
+                    "peer {} is listed more than once, ignoring the repeat",
+                    peer.address
+                ));
+                return false;
+            }
+            seen.push((host, port));
+            true
+        });
+
+        warnings
+    }
+}
+
+//localhost and the loopback ips are the same place, and hostnames don't care about case
+fn canonical_host(host: &str) -> String {
+    let host = host.to_ascii_lowercase();
+    if host == "localhost" {
+        String::from("127.0.0.1")
+    } else {
+        host
+    }
+}
+
+//what a gossip_allow/gossip_deny entry names
+struct Fence<'a> {
+    node_id: Option<&'a str>,
+    ip: Option<IpAddr>,
+}
+
+//an entry is a node_id, an ip, an "ip:port", or a "node_id@" in front of either of the latter
+fn fence(entry: &str) -> Fence<'_> {
+    let ip = |address: &str| {
+        let host = match split_host_port(address) {
+            Ok((host, _)) => host,
+            Err(_) => address.to_string(),
+        };
+        canonical_host(host.trim_start_matches('[').trim_end_matches(']'))
+            .parse::<IpAddr>()
+            .ok()
+            .map(|ip| ip.to_canonical())
+    };
+    match entry.split_once('@') {
+        Some((node_id, address)) => Fence {
+            node_id: Some(node_id),
+            ip: ip(address),
+        },
+        None => match ip(entry) {
+            Some(ip) => Fence {
+                node_id: None,
+                ip: Some(ip),
+            },
+            None => Fence {
+                node_id: Some(entry),
+                ip: None,
+            },
+        },
+    }
+}
+
+fn is_loopback(host: &str) -> bool {
+    host.parse::<std::net::IpAddr>()
+        .map(|ip| ip.is_loopback())
+        .unwrap_or(false)
+}
+
+//splits "host:port", "1.2.3.4:port" or "[::1]:port" into host and port
+pub fn split_host_port(address: &str) -> Result<(String, u16)> {
+    if let Ok(socket_addr) = address.parse::<SocketAddr>() {
+        return Ok((socket_addr.ip().to_string(), socket_addr.port()));
+    }
+    if address.parse::<Ipv6Addr>().is_ok() {
+        bail!(
+            "ipv6 addresses need brackets and a port, eg [{}]:8000",
+            address
+        );
+    }
+
+    let (host, port) = address
+        .rsplit_once(':')
+        .with_context(|| format!("{} is missing a port", address))?;
+    if host.is_empty() {
+        bail!("{} is missing a host", address);
+    }
+    if host.contains(':') {
+        bail!("ipv6 addresses need brackets, eg [{}]:{}", host, port);
+    }
+    let port: u16 = port
+        .parse()
+        .with_context(|| format!("{} is not a valid port", port))?;
+
+    Ok((host.to_string(), port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_peers_accept_strings_and_tables() {
+        let config: Config = toml::from_str(
+            r#"
+            node_id = "node_1"
+            listen_address = "127.0.0.1:8000"
+            peers = [
+                "127.0.0.1:8001",
+                "https://node3.internal:8443",
+                { address = "10.0.0.4:8000", tls = { domain_name = "node4.internal" }, proxy = "http://proxy:3128", connect_timeout_ms = 500 },
+            ]
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.peers[0],
+            PeerConfig::new("127.0.0.1:8001".to_string())
+        );
+        assert_eq!(config.peers[1].scheme, "https");
+        assert_eq!(config.peers[1].address, "node3.internal:8443");
+
+        let detailed = config.peer("10.0.0.4:8000").unwrap();
+        assert!(detailed.uses_tls(), "tls options should imply https");
+        assert_eq!(
+            detailed.tls.as_ref().unwrap().domain_name.as_deref(),
+            Some("node4.internal")
+        );
+        assert_eq!(detailed.proxy.as_deref(), Some("http://proxy:3128"));
+        assert_eq!(detailed.connect_timeout_ms, Some(500));
+    }
+
+    #[test]
+    fn test_split_host_port() {
+        assert_eq!(
+            split_host_port("127.0.0.1:8000").unwrap(),
+            ("127.0.0.1".to_string(), 8000)
+        );
+        assert_eq!(
+            split_host_port("[::1]:8000").unwrap(),
+            ("::1".to_string(), 8000)
+        );
+        assert_eq!(
+            split_host_port("node2.internal:8000").unwrap(),
+            ("node2.internal".to_string(), 8000)
+        );
+
+        assert!(split_host_port("::1").is_err());
+        assert!(split_host_port("fe80::1:8000").is_err());
+        assert!(split_host_port("node2.internal").is_err());
+        assert!(split_host_port("node2.internal:http").is_err());
+        assert!(split_host_port(":8000").is_err());
+    }
+
+    #[test]
+    fn test_peers_round_trip() {
+        let mut detailed = PeerConfig::new("10.0.0.4:8000".to_string());
+        detailed.proxy = Some("http://proxy:3128".to_string());
+        let config = Config {
+            node_id: "node_1".to_string(),
+            cluster_id: Some("prod".to_string()),
+            listen_address: "127.0.0.1:8000".to_string(),
+            client_listen_address: None,
+            peer_listen_address: Some("10.0.0.1:8001".to_string()),
+            peers: vec![PeerConfig::new("127.0.0.1:8001".to_string()), detailed],
+            uds_path: Some(PathBuf::from("/tmp/mergedb.sock")),
+            uds_mode: Some(0o660),
+            peer_timeout: default_peer_timeout(),
+            split_brain_after: Duration::from_secs(90),
+            split_brain_webhook: None,
+            data_dir: Some(PathBuf::from("/var/lib/mergedb")),
+            snapshot_every: default_snapshot_every(),
+            preload: vec!["flags:".to_string()],
+            gossip_priority: vec![GossipPriority {
+                prefix: "flags:".to_string(),
+                priority: 10,
+                interval: Duration::from_millis(200),
+            }],
+            gossip_interval: None,
+            fanout: Some(5),
+            max_value_size: default_max_value_size(),
+            gossip_allow: Vec::new(),
+            gossip_deny: vec!["node_9".to_string()],
+            counter_anomaly: None,
+            script_max_operations: default_script_max_operations(),
+            script_timeout: default_script_timeout(),
+            witness: false,
+            metrics_address: Some("127.0.0.1:9100".to_string()),
+            websocket_address: Some("127.0.0.1:9300".to_string()),
+            graphql_address: Some("127.0.0.1:9400".to_string()),
+            grpc_web: true,
+            gossip_mode: GossipMode::Digest,
+            discover_peers: false,
+            peer_breaker: PeerBreaker::default(),
+            wal_sync: WalSync::Interval(Duration::from_millis(10)),
+            label_rules: vec![LabelRule {
+                selector: "env=staging".parse().unwrap(),
+                retention: Some(Duration::from_secs(7 * 24 * 3600)),
+                webhook: None,
+            }],
+            runtime: RuntimeConfig {
+                worker_threads: Some(2),
+                max_blocking_threads: None,
+                gossip_threads: Some(1),
+            },
+            request_queue: RequestQueue {
+                max_in_flight: 8,
+                max_queued: 100,
+            },
+            plumtree: Some(Plumtree {
+                graft_timeout: Duration::from_millis(500),
+                ..Plumtree::default()
+            }),
+            audit: Some(Audit {
+                keys: 5,
+                ..Audit::default()
+            }),
+            watchdog: Some(Watchdog {
+                stalled_after: Duration::from_secs(90),
+                ..Watchdog::default()
+            }),
+        };
+
+        let contents = toml::to_string(&toml::Value::try_from(&config).unwrap()).unwrap();
+        let parsed: Config = toml::from_str(&contents).unwrap();
+        assert_eq!(parsed.peers, config.peers);
+        assert_eq!(parsed.uds_path, config.uds_path);
+        assert_eq!(parsed.gossip_priority, config.gossip_priority);
+        assert_eq!(parsed.split_brain_after, config.split_brain_after);
+        assert_eq!(parsed.max_value_size, config.max_value_size);
+        assert_eq!(parsed.preload, config.preload);
+        assert_eq!(parsed.gossip_deny, config.gossip_deny);
+        assert_eq!(parsed.label_rules, config.label_rules);
+        assert_eq!(parsed.runtime, config.runtime);
+        assert_eq!(parsed.request_queue, config.request_queue);
+        assert_eq!(parsed.plumtree, config.plumtree);
+        assert_eq!(parsed.audit, config.audit);
+        assert_eq!(parsed.watchdog, config.watchdog);
+        assert_eq!(parsed.gossip_interval, None);
+        assert_eq!(parsed.fanout, Some(5));
+        assert!(!parsed.discover_peers);
+        assert_eq!(parsed.gossip_mode, GossipMode::Digest);
+        assert_eq!(parsed.client_address(), "127.0.0.1:8000");
+        assert_eq!(parsed.peer_address(), "10.0.0.1:8001");
+    }
+
+    #[test]
+    fn test_dedup_peers() {
+        let mut config: Config = toml::from_str(
+            r#"
+            node_id = "node_1"
+            listen_address = "0.0.0.0:8000"
+            peers = [
+                "127.0.0.1:8001",
+                "localhost:8000",
+                "https://127.0.0.1:8001",
+                "Node2.internal:8000",
+                "node2.internal:8000",
+                "10.0.0.4:8000",
+            ]
+            "#,
+        )
+        .unwrap();
+
+        let warnings = config.dedup_peers();
+        assert_eq!(warnings.len(), 3);
+        let kept: Vec<&str> = config
+            .peers
+            .iter()
+            .map(|peer| peer.address.as_str())
+            .collect();
+        assert_eq!(
+            kept,
+            ["127.0.0.1:8001", "Node2.internal:8000", "10.0.0.4:8000"]
+        );
+    }
+
+    #[test]
+    fn test_gossip_fence() {
+        let mut config: Config = toml::from_str(
+            r#"
+            node_id = "node_1"
+            listen_address = "127.0.0.1:8000"
+            peers = []
+            gossip_allow = ["node_2@10.0.0.2", "10.0.0.3:8000", "localhost"]
+            gossip_deny = ["node_4"]
+            "#,
+        )
+        .unwrap();
+        assert!(config.validate().is_ok());
+        let ip = |ip: &str| Some(ip.parse::<IpAddr>().unwrap());
+
+        assert_eq!(config.gossip_refusal("node_2", ip("10.0.0.2")), None);
+        assert_eq!(config.gossip_refusal("node_2", ip("::ffff:10.0.0.2")), None);
+        //node_2 is only let in from its own address
+        assert!(config.gossip_refusal("node_2", ip("10.0.0.9")).is_some());
+        assert!(config.gossip_refusal("node_6", ip("10.0.0.2")).is_some());
+        //an older node that doesn't send its node_id is still known by its address
+        assert_eq!(config.gossip_refusal("", ip("10.0.0.3")), None);
+        assert_eq!(config.gossip_refusal("node_5", ip("127.0.0.1")), None);
+        assert_eq!(
+            config.gossip_refusal("node_5", ip("10.0.0.5")).unwrap(),
+            "node_5 is not on gossip_allow"
+        );
+        assert!(config.gossip_refusal("node_4", ip("10.0.0.3")).is_some());
+        assert!(config.gossip_refusal("", None).is_some());
+
+        config.gossip_allow.clear();
+        assert_eq!(config.gossip_refusal("node_5", None), None);
+        assert!(config.gossip_refusal("node_4", None).is_some());
+
+        //a bare node_id would let in whoever claims it
+        config.gossip_allow = vec!["node_2".to_string()];
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_cluster_id() {
+        let mut config: Config = toml::from_str(
+            r#"
+            node_id = "node_1"
+            cluster_id = "prod"
+            listen_address = "127.0.0.1:8000"
+            peers = []
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.cluster_id(), "prod");
+        assert_eq!(config.cluster_refusal("node_2", "prod"), None);
+        //a node that isn't given one yet is let in
+        assert_eq!(config.cluster_refusal("node_2", ""), None);
+        assert_eq!(
+            config.cluster_refusal("node_2", "staging").unwrap(),
+            "node_2 belongs to cluster staging, this is cluster prod"
+        );
+
+        config.cluster_id = None;
+        assert_eq!(config.cluster_id(), "");
+        assert_eq!(config.cluster_refusal("node_2", "staging"), None);
+        config.cluster_id = Some(String::new());
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_older_integer_settings_still_load() {
+        let config: Config = toml::from_str(
+            r#"
+            node_id = "node_1"
+            listen_address = "127.0.0.1:8000"
+            peers = []
+            peer_timeout_secs = 5
+            snapshot_every = "5m"
+            gossip_interval = "500ms"
+            max_value_size = "1MiB"
+            gossip_priority = [{ prefix = "flags:", priority = 10, interval_ms = 200 }]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.peer_timeout, Duration::from_secs(5));
+        assert_eq!(config.snapshot_every, Duration::from_secs(300));
+        assert_eq!(config.gossip_interval, Some(Duration::from_millis(500)));
+        assert_eq!(config.max_value_size, 1 << 20);
+        assert_eq!(
+            config.gossip_priority[0].interval,
+            Duration::from_millis(200)
+        );
+    }
+
+    #[test]
+    fn test_runtime_threads() {
+        let config = |runtime: &str| -> Result<Config> {
+            let config: Config = toml::from_str(&format!(
+                "node_id = \"node_1\"\nlisten_address = \"127.0.0.1:8000\"\npeers = []\n{}",
+                runtime
+            ))?;
+            config.validate()?;
+            Ok(config)
+        };
+        assert_eq!(config("").unwrap().runtime, RuntimeConfig::default());
+        let tuned = config("[runtime]\nworker_threads = 2\ngossip_threads = 1").unwrap();
+        assert_eq!(tuned.runtime.worker_threads, Some(2));
+        assert!(tuned.runtime.build_gossip().is_some());
+        assert!(config("").unwrap().runtime.build_gossip().is_none());
+        assert!(config("[runtime]\ngossip_threads = 0").is_err());
+    }
 }
@@ -0,0 +1,147 @@
+//per-key write contention, for CONTENTION: how many client writes of a key were running at once
+//and how long writes waited for the key's store entry before they could run. store entries are
+//locked per dashmap shard, not per key, so a wait can also come from another key of the same
+//shard. only keys that saw contention are kept, at most TRACKED of them, the least contended
+//one makes room for a new one. counts are since the node started
+use dashmap::DashMap;
+use std::{collections::HashMap, sync::Mutex, time::Duration};
+
+//keys kept by name, CONTENTION can't ask for more
+pub const TRACKED: usize = 100;
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct KeyContention {
+    pub key: String,
+    //writes that found the entry locked
+    pub waits: u64,
+    pub wait_total: Duration,
+    pub wait_max: Duration,
+    //the most writes of the key that were running at once
+    pub peak_writers: usize,
+}
+
+impl KeyContention {
+    //what the worst offenders are sorted by
+    fn weight(&self) -> (Duration, usize) {
+        (self.wait_total, self.peak_writers)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Contention {
+    //writes of each key running right now, a key is dropped once none are
+    writers: DashMap<String, usize>,
+    keys: Mutex<HashMap<String, KeyContention>>,
+}
+
+//held while a write runs, see Contention::enter
+pub struct Writing<'a> {
+    contention: &'a Contention,
+    key: String,
+}
+
+impl Drop for Writing<'_> {
+    fn drop(&mut self) {
+        self.contention
+            .writers
+            .remove_if_mut(&self.key, |_, writers| {
+                *writers -= 1;
+                *writers == 0
+            });
+    }
+}
+
+impl Contention {
+    //a client write of the key starts, it counts as running until the guard is dropped
+    pub fn enter(&self, key: &str) -> Writing<'_> {
+        let running = {
+            let mut writers = self.writers.entry(key.to_string()).or_insert(0);
+            *writers += 1;
+            *writers
+        };
+        if running > 1 {
+            self.record(key, |stats| {
+                stats.peak_writers = stats.peak_writers.max(running)
+            });
+        }
+        Writing {
+            contention: self,
+            key: key.to_string(),
+        }
+    }
+
+    //a write found the key's entry locked and waited this long for it
+    pub fn record_wait(&self, key: &str, waited: Duration) {
+        self.record(key, |stats| {
+            stats.waits += 1;
+            stats.wait_total += waited;
+            stats.wait_max = stats.wait_max.max(waited);
+        });
+    }
+
+    fn record(&self, key: &str, update: impl FnOnce(&mut KeyContention)) {
+        let mut keys = self.keys.lock().unwrap();
+        if keys.len() >= TRACKED && !keys.contains_key(key) {
+            let least = keys
+                .values()
+                .min_by_key(|stats| stats.weight())
+                .map(|stats| stats.key.clone());
+            if let Some(least) = least {
+                keys.remove(&least);
+            }
+        }
+        let stats = keys
+            .entry(key.to_string())
+            .or_insert_with(|| KeyContention {
+                key: key.to_string(),
+                ..Default::default()
+            });
+        update(stats);
+    }
+
+    //the n most contended keys, longest waited first
+    pub fn worst(&self, n: usize) -> Vec<KeyContention> {
+        let mut worst: Vec<KeyContention> = self.keys.lock().unwrap().values().cloned().collect();
+        worst.sort_by(|a, b| b.weight().cmp(&a.weight()).then_with(|| a.key.cmp(&b.key)));
+        worst.truncate(n);
+        worst
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_worst_keys_come_first() {
+        let contention = Contention::default();
+        let first = contention.enter("cart:42");
+        let second = contention.enter("cart:42");
+        {
+            let _third = contention.enter("cart:42");
+        }
+        drop((first, second));
+        //running one at a time isn't contention
+        drop(contention.enter("views"));
+
+        contention.record_wait("views", Duration::from_millis(3));
+        contention.record_wait("cart:42", Duration::from_millis(1));
+        contention.record_wait("cart:42", Duration::from_millis(4));
+
+        let worst = contention.worst(10);
+        assert_eq!(worst.len(), 2);
+        assert_eq!(worst[0].key, "cart:42");
+        assert_eq!(worst[0].waits, 2);
+        assert_eq!(worst[0].wait_total, Duration::from_millis(5));
+        assert_eq!(worst[0].wait_max, Duration::from_millis(4));
+        assert_eq!(worst[0].peak_writers, 3);
+        assert_eq!(worst[1].peak_writers, 0);
+        assert!(contention.writers.is_empty());
+
+        for i in 0..500 {
+            contention.record_wait(&format!("tail:{}", i), Duration::from_micros(1));
+        }
+        assert_eq!(contention.worst(1000).len(), TRACKED);
+        assert_eq!(contention.worst(1)[0].key, "cart:42");
+    }
+}
@@ -0,0 +1,267 @@
+//`cluster decommission`: the node refuses writes, hands its whole store to every peer it can
+//reach, waits until their fingerprints match its own, tells all of its peers it is leaving and
+//then stops, Node::wait() returns. each step is reported to the client as it happens. anything
+//going wrong before the peers were told aborts it and the node takes writes again
+use mergedb_proto::CrdtProto;
+use std::{
+    collections::HashMap,
+    sync::atomic::Ordering,
+    time::{Duration, Instant},
+};
+use tokio::sync::mpsc;
+use tonic::{Request, Status};
+
+use crate::{
+    communication::{DecommissionProgress, FingerprintRequest, GossipBatchRequest, LeaveRequest},
+    fingerprint,
+    network::{ReplicationServer, BATCH_SIZE},
+};
+
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
+
+pub type Progress = mpsc::Sender<Result<DecommissionProgress, Status>>;
+
+async fn report(progress: &Progress, stage: &str, message: String) {
+    println!("decommission, {}: {}", stage, message);
+    let _ = progress
+        .send(Ok(DecommissionProgress {
+            stage: stage.to_string(),
+            message,
+        }))
+        .await;
+}
+
+//Ok once every peer holds what this node holds and has been told it is leaving
+pub async fn run(
+    server: &ReplicationServer,
+    timeout: Duration,
+    progress: &Progress,
+) -> Result<(), Status> {
+    server.decommissioning.store(true, Ordering::SeqCst);
+    let result = hand_over(server, timeout, progress).await;
+    if result.is_err() {
+        server.decommissioning.store(false, Ordering::SeqCst);
+    }
+    result
+}
+
+async fn hand_over(
+    server: &ReplicationServer,
+    timeout: Duration,
+    progress: &Progress,
+) -> Result<(), Status> {
+    report(
+        progress,
+        "draining",
+        "writes are refused from now on".to_string(),
+    )
+    .await;
+    //every client command shares the script lock, once it's ours none is left half done
+    drop(server.script_lock.write().await);
+    //keys still waiting in the snapshot have to be handed over too
+    server.warm_up().await;
+
+    if server.config.witness {
+        report(
+            progress,
+            "pushing",
+            "a witness has nothing to hand over".to_string(),
+        )
+        .await;
+    } else {
+        verify_handover(server, timeout, progress).await?;
+    }
+
+    //every peer, reachable or not, so none of them keeps trying to gossip with this node
+    let all_peers: Vec<String> = server
+        .peers
+        .iter()
+        .map(|entry| entry.key().clone())
+        .collect();
+    let mut told = 0;
+    for peer_addr in &all_peers {
+        let leave = Request::new(LeaveRequest {
+            node_id: server.config.node_id.clone(),
+            cluster_id: server.config.cluster_id().to_string(),
+        });
+        let sent = match server.peer_client(peer_addr).await {
+            Ok(mut client) => client
+                .leave(leave)
+                .await
+                .map(|_| ())
+                .map_err(|e| e.to_string()),
+            Err(e) => Err(e.to_string()),
+        };
+        match sent {
+            Ok(()) => told += 1,
+            Err(e) => {
+                report(
+                    progress,
+                    "leaving",
+                    format!("{} wasn't told: {}", peer_addr, e),
+                )
+                .await
+            }
+        }
+    }
+    report(
+        progress,
+        "leaving",
+        format!("{} of {} peers told", told, all_peers.len()),
+    )
+    .await;
+    report(progress, "done", "stopping".to_string()).await;
+    Ok(())
+}
+
+//pushes everything to every reachable data node until each one's fingerprint matches
+async fn verify_handover(
+    server: &ReplicationServer,
+    timeout: Duration,
+    progress: &Progress,
+) -> Result<(), Status> {
+    let peers = handover_peers(server);
+    if peers.is_empty() {
+        return Err(Status::failed_precondition(
+            "no reachable peer to hand the data to",
+        ));
+    }
+
+    let deadline = Instant::now() + timeout;
+    let mut differing = peers.clone();
+    loop {
+        for peer_addr in &differing {
+            match push_everything(server, peer_addr).await {
+                Ok(keys) => {
+                    report(
+                        progress,
+                        "pushing",
+                        format!("{} keys to {}", keys, peer_addr),
+                    )
+                    .await
+                }
+                Err(e) => report(progress, "pushing", format!("{} failed: {}", peer_addr, e)).await,
+            }
+        }
+
+        //every peer is checked again, merges from them may have changed this node's store
+        let own = fingerprint::of_store(&server.store);
+        differing.clear();
+        for peer_addr in &peers {
+            let message = match peer_fingerprint(server, peer_addr).await {
+                Ok(digest) if digest == own.digest => {
+                    format!(
+                        "{} matches ({:016x} over {} keys)",
+                        peer_addr, digest, own.keys
+                    )
+                }
+                Ok(digest) => {
+                    differing.push(peer_addr.clone());
+                    format!(
+                        "{} differs ({:016x}, here {:016x})",
+                        peer_addr, digest, own.digest
+                    )
+                }
+                Err(e) => {
+                    differing.push(peer_addr.clone());
+                    format!("{} couldn't be asked: {}", peer_addr, e)
+                }
+            };
+            report(progress, "verifying", message).await;
+        }
+        if differing.is_empty() {
+            break;
+        }
+
+        let wait = server.settings().gossip_interval;
+        if Instant::now() + wait >= deadline {
+            return Err(Status::deadline_exceeded(format!(
+                "{} still differ after {:?}, the node keeps serving",
+                differing.join(", "),
+                timeout
+            )));
+        }
+        tokio::time::sleep(wait).await;
+    }
+    Ok(())
+}
+
+//addresses of the data nodes heard from recently, they are the ones that can take the data
+fn handover_peers(server: &ReplicationServer) -> Vec<String> {
+    let mut peers: Vec<String> = server
+        .membership
+        .members
+        .iter()
+        .filter(|entry| server.membership.is_reachable(entry.value()) && !entry.value().witness)
+        .filter_map(|entry| entry.value().address.clone())
+        .collect();
+    peers.sort();
+    peers
+}
+
+async fn push_everything(server: &ReplicationServer, peer_addr: &str) -> anyhow::Result<usize> {
+    let format = server.membership.write_format();
+    let everything: Vec<_> = server
+        .store
+        .iter()
+        .map(|entry| {
+            (
+                entry.key().clone(),
+                entry.value().data.clone().to_proto_as(format),
+            )
+        })
+        .collect();
+    let mut client = server.peer_client(peer_addr).await?;
+    for chunk in everything.chunks(BATCH_SIZE) {
+        let batch: HashMap<_, _> = chunk.iter().cloned().collect();
+        client
+            .gossip_batch(Request::new(GossipBatchRequest {
+                batch,
+                node_id: server.config.node_id.clone(),
+                cluster_id: server.config.cluster_id().to_string(),
+            }))
+            .await?;
+    }
+    Ok(everything.len())
+}
+
+async fn peer_fingerprint(server: &ReplicationServer, peer_addr: &str) -> anyhow::Result<u64> {
+    let mut client = server.peer_client(peer_addr).await?;
+    let response = client
+        .fingerprint(Request::new(FingerprintRequest {}))
+        .await?;
+    Ok(response.into_inner().fingerprint)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        communication::replication_service_server::ReplicationService,
+        testing::{request, server},
+    };
+
+    #[tokio::test]
+    async fn test_writes_are_refused_until_it_is_aborted() {
+        let server = server("n1");
+        server.decommissioning.store(true, Ordering::SeqCst);
+        let refused = server
+            .propagate_data(request("RSET", "k", "v"))
+            .await
+            .unwrap_err();
+        assert_eq!(refused.code(), tonic::Code::FailedPrecondition);
+
+        //nobody to hand the data to, so the node goes back to serving
+        server.decommissioning.store(false, Ordering::SeqCst);
+        let (progress, mut receiver) = mpsc::channel(16);
+        let aborted = run(&server, DEFAULT_TIMEOUT, &progress).await.unwrap_err();
+        assert_eq!(aborted.code(), tonic::Code::FailedPrecondition);
+        let first = receiver.recv().await.unwrap().unwrap();
+        assert_eq!(first.stage, "draining");
+        assert!(!server.decommissioning.load(Ordering::SeqCst));
+        assert!(server
+            .propagate_data(request("RSET", "k", "v"))
+            .await
+            .is_ok());
+    }
+}
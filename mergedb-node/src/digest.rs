@@ -0,0 +1,118 @@
+//with gossip_mode = "digest" a round sends a peer the hash of each dirty key first (see
+//fingerprint::key_hash), the peer answers with the keys it is missing or holds in another state,
+//and only those are sent in full. costs a round trip, saves the bandwidth of every key the peer
+//already has, eg when most writes reach it from other nodes first. "push" sends every dirty key.
+//"scuttlebutt" swaps version vectors instead, see scuttlebutt. it is a cluster setting too, so
+//every node can be switched over at once. peers too old for the mode are pushed to
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, fmt, str::FromStr};
+
+use crate::{fingerprint, network::StoredValue};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(try_from = "String", into = "String")]
+pub enum GossipMode {
+    #[default]
+    Push,
+    Digest,
+    Scuttlebutt,
+}
+
+impl FromStr for GossipMode {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim() {
+            "push" => Ok(GossipMode::Push),
+            "digest" => Ok(GossipMode::Digest),
+            "scuttlebutt" => Ok(GossipMode::Scuttlebutt),
+            _ => Err(format!(
+                "gossip_mode is \"push\", \"digest\" or \"scuttlebutt\", not {:?}",
+                value
+            )),
+        }
+    }
+}
+
+impl fmt::Display for GossipMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            GossipMode::Push => "push",
+            GossipMode::Digest => "digest",
+            GossipMode::Scuttlebutt => "scuttlebutt",
+        })
+    }
+}
+
+impl TryFrom<String> for GossipMode {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<GossipMode> for String {
+    fn from(mode: GossipMode) -> Self {
+        mode.to_string()
+    }
+}
+
+//the keys of a digest this node doesn't hold in the state it was hashed in, sorted
+pub fn wanted(store: &DashMap<String, StoredValue>, digests: &HashMap<String, u64>) -> Vec<String> {
+    let mut wanted: Vec<String> = digests
+        .iter()
+        .filter(|(key, hash)| match store.get(*key) {
+            Some(stored) => fingerprint::key_hash(key, &stored.data) != **hash,
+            None => true,
+        })
+        .map(|(key, _)| key.clone())
+        .collect();
+    wanted.sort();
+    wanted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mergedb_types::{pn_counter::PNCounter, CrdtValue};
+    use std::time::SystemTime;
+
+    fn counter(value: i64) -> CrdtValue {
+        let mut counter = PNCounter::new("n1".to_string(), 0, 0);
+        let _ = counter.checked_add("n1".to_string(), value);
+        CrdtValue::Counter(counter)
+    }
+
+    #[test]
+    fn test_only_keys_out_of_step_are_wanted() {
+        let store = DashMap::new();
+        for (key, value) in [("same", 1), ("behind", 2)] {
+            store.insert(
+                key.to_string(),
+                StoredValue {
+                    data: counter(value),
+                    last_updated: SystemTime::now(),
+                },
+            );
+        }
+        let digests: HashMap<String, u64> = [
+            ("same", fingerprint::key_hash("same", &counter(1))),
+            ("behind", fingerprint::key_hash("behind", &counter(3))),
+            ("missing", fingerprint::key_hash("missing", &counter(1))),
+        ]
+        .into_iter()
+        .map(|(key, hash)| (key.to_string(), hash))
+        .collect();
+        assert_eq!(wanted(&store, &digests), vec!["behind", "missing"]);
+    }
+
+    #[test]
+    fn test_mode_names() {
+        assert_eq!("digest".parse::<GossipMode>(), Ok(GossipMode::Digest));
+        assert_eq!(GossipMode::Scuttlebutt.to_string(), "scuttlebutt");
+        assert_eq!(GossipMode::default().to_string(), "push");
+        assert!("pull".parse::<GossipMode>().is_err());
+    }
+}
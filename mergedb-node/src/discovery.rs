@@ -0,0 +1,221 @@
+//nodes learn each other from heartbeats, so a node configured with a single seed peer finds
+//the rest of the cluster by itself. a heartbeat says where its sender takes gossip, and every
+//answer lists the nodes the responder knows an address for and whether it still hears from
+//them. an address learnt either way joins the gossip loop like a configured peer would, unless
+//gossip_allow/gossip_deny fence the node off, the node is already gossiped with at another
+//address, or nobody has heard from it lately. discovered peers are plain http connections and
+//are forgotten on restart, set discover_peers = false to only ever gossip with `peers`
+use std::net::IpAddr;
+
+use crate::{
+    communication::KnownPeer,
+    config::split_host_port,
+    membership::Membership,
+};
+
+//where a heartbeat's sender can be reached. a node listening on every interface advertises
+//0.0.0.0 or ::, the address its heartbeat came from stands in for it then
+pub fn advertised_address(advertised: &str, remote: Option<IpAddr>) -> Option<String> {
+    let (host, port) = split_host_port(advertised).ok()?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let unspecified = host
+        .parse::<IpAddr>()
+        .map(|ip| ip.is_unspecified())
+        .unwrap_or(false);
+    if !unspecified {
+        return Some(advertised.to_string());
+    }
+    match remote?.to_canonical() {
+        IpAddr::V4(ip) => Some(format!("{}:{}", ip, port)),
+        IpAddr::V6(ip) => Some(format!("[{}]:{}", ip, port)),
+    }
+}
+
+//what a heartbeat answer tells the sender about the cluster, every member with an address
+pub fn known_peers(membership: &Membership) -> Vec<KnownPeer> {
+    let mut peers: Vec<KnownPeer> = membership
+        .members
+        .iter()
+        .filter_map(|entry| {
+            let address = entry.value().address.clone()?;
+            Some(KnownPeer {
+                node_id: entry.key().clone(),
+                address,
+                reachable: membership.is_reachable(entry.value()),
+                last_seen_ms: entry.value().last_seen.elapsed().as_millis() as u64,
+            })
+        })
+        .collect();
+    peers.sort_by(|a, b| a.node_id.cmp(&b.node_id));
+    peers
+}
+
+//the ip an address is fenced by, None for a hostname
+pub fn address_ip(address: &str) -> Option<IpAddr> {
+    let (host, _) = split_host_port(address).ok()?;
+    host.trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse()
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        communication::{replication_service_server::ReplicationService, HeartbeatRequest},
+        listener::{Listener, Role},
+        network::ReplicationServer,
+        testing,
+    };
+    use std::time::Duration;
+    use tonic::Request;
+
+    fn server() -> ReplicationServer {
+        ReplicationServer::for_tests(testing::config(
+            "n1",
+            "cluster_id = \"prod\"\ngossip_deny = [\"n9\", \"10.0.0.8\"]",
+        ))
+    }
+
+    fn heartbeat(node_id: &str, address: &str) -> Request<HeartbeatRequest> {
+        Request::new(HeartbeatRequest {
+            node_id: node_id.to_string(),
+            reachable: Vec::new(),
+            format_version: 0,
+            witness: false,
+            address: address.to_string(),
+            instance: String::new(),
+            cluster_id: String::new(),
+        })
+    }
+
+    #[test]
+    fn test_advertised_address() {
+        let remote: Option<IpAddr> = Some("10.0.0.7".parse().unwrap());
+        assert_eq!(
+            advertised_address("node2.internal:8001", remote).as_deref(),
+            Some("node2.internal:8001")
+        );
+        assert_eq!(
+            advertised_address("0.0.0.0:8001", remote).as_deref(),
+            Some("10.0.0.7:8001")
+        );
+        assert_eq!(
+            advertised_address("[::]:8001", Some("::ffff:10.0.0.7".parse().unwrap())).as_deref(),
+            Some("10.0.0.7:8001")
+        );
+        assert_eq!(
+            advertised_address("[::]:8001", Some("fd00::7".parse().unwrap())).as_deref(),
+            Some("[fd00::7]:8001")
+        );
+        assert_eq!(advertised_address("0.0.0.0:8001", None), None);
+        assert_eq!(advertised_address("", remote), None);
+
+        assert_eq!(address_ip("10.0.0.7:8001"), remote);
+        assert_eq!(address_ip("[fd00::7]:8001"), Some("fd00::7".parse().unwrap()));
+        assert_eq!(address_ip("node2.internal:8001"), None);
+    }
+
+    #[test]
+    fn test_known_peers() {
+        let membership = Membership::new("n1".to_string(), Duration::from_secs(10));
+        membership.observe("n3".to_string(), Some("10.0.0.3:8001".to_string()), vec![], 0, false);
+        membership.observe("n2".to_string(), Some("10.0.0.2:8001".to_string()), vec![], 0, false);
+        //only heard from, its address is unknown
+        membership.observe("n4".to_string(), None, vec![], 0, false);
+
+        let peers = known_peers(&membership);
+        let listed: Vec<(&str, &str, bool)> = peers
+            .iter()
+            .map(|peer| (peer.node_id.as_str(), peer.address.as_str(), peer.reachable))
+            .collect();
+        assert_eq!(
+            listed,
+            vec![("n2", "10.0.0.2:8001", true), ("n3", "10.0.0.3:8001", true)]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_heartbeats_teach_peers() {
+        let server = server();
+        server
+            .membership
+            .observe("n5".to_string(), Some("10.0.0.5:8001".to_string()), vec![], 0, false);
+
+        let answer = server
+            .heartbeat(heartbeat("n2", "10.0.0.2:8001"))
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(server.peers.contains_key("10.0.0.2:8001"));
+        let listed: Vec<&str> = answer.peers.iter().map(|peer| peer.address.as_str()).collect();
+        assert_eq!(listed, vec!["10.0.0.5:8001"]);
+
+        //fenced off by node id or by ip, and no address to go by
+        server.heartbeat(heartbeat("n9", "10.0.0.9:8001")).await.unwrap();
+        server.heartbeat(heartbeat("n8", "10.0.0.8:8001")).await.unwrap();
+        server.heartbeat(heartbeat("n3", "0.0.0.0:8001")).await.unwrap();
+        //n5 is already gossiped with, at the address it was reached on
+        server.peers.insert("10.0.0.5:8001".to_string(), std::time::SystemTime::now());
+        server.heartbeat(heartbeat("n5", "node5.internal:8001")).await.unwrap();
+        let mut peers: Vec<String> = server.peers.iter().map(|entry| entry.key().clone()).collect();
+        peers.sort();
+        assert_eq!(peers, vec!["10.0.0.2:8001", "10.0.0.5:8001"]);
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_node_ids_are_refused() {
+        let server = server();
+        let claiming = |node_id: &str, address: &str, instance: &str| {
+            let mut request = heartbeat(node_id, address);
+            request.get_mut().instance = instance.to_string();
+            request
+        };
+        server.heartbeat(claiming("n2", "10.0.0.2:8001", "a")).await.unwrap();
+
+        let taken = server
+            .heartbeat(claiming("n2", "10.0.0.3:8001", "b"))
+            .await
+            .unwrap_err();
+        assert_eq!(taken.code(), tonic::Code::AlreadyExists);
+        assert!(taken.message().contains("10.0.0.2:8001"));
+        let own = server
+            .heartbeat(claiming(&server.config.node_id, "10.0.0.4:8001", "c"))
+            .await
+            .unwrap_err();
+        assert_eq!(own.code(), tonic::Code::AlreadyExists);
+        assert_eq!(server.metrics.counter("duplicate_node_ids_total"), 2);
+        assert!(!server.peers.contains_key("10.0.0.3:8001"));
+    }
+
+    #[tokio::test]
+    async fn test_other_clusters_are_refused() {
+        let listener = Listener::new(server(), Role::All);
+        let from = |cluster_id: &str, address: &str| {
+            let mut request = heartbeat("n2", address);
+            request.get_mut().cluster_id = cluster_id.to_string();
+            request
+        };
+        listener
+            .heartbeat(from("prod", "10.0.0.2:8001"))
+            .await
+            .unwrap();
+        //not given a cluster_id yet, let in
+        listener.heartbeat(from("", "10.0.0.2:8001")).await.unwrap();
+        let refused = listener
+            .heartbeat(from("staging", "10.0.0.3:8001"))
+            .await
+            .unwrap_err();
+        assert_eq!(refused.code(), tonic::Code::PermissionDenied);
+        assert!(refused.message().contains("cluster staging"));
+        assert_eq!(
+            listener
+                .server
+                .metrics
+                .counter("cross_cluster_refused_total"),
+            1
+        );
+        assert!(!listener.server.peers.contains_key("10.0.0.3:8001"));
+    }
+}
@@ -0,0 +1,319 @@
+//what an embedding application is told about, see node. callbacks are only ever called on the
+//mergedb-events thread, one event at a time in the order they happened, so a slow callback
+//holds up the ones after it but never a client write or the gossip loop:
+//- KeyChanged for every change to a key, a local write or merged gossip, with its value once
+//  the commit let go of it. on_change callbacks only get the keys under their prefix
+//- MergeConflict when a peer sent a key holding another type than here, the local value stays
+//- PeerJoined and PeerLeft when a node starts or stops answering heartbeats within peer_timeout
+//- Lagging and CaughtUp when a peer goes longer than the lag threshold without getting every
+//  change, and when it has them again
+//nothing is queued while no callback is registered
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::{Arc, Mutex, RwLock},
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use mergedb_types::CrdtValue;
+use tokio::sync::mpsc;
+
+use crate::network::ReplicationServer;
+
+//how often peers are looked at for joins, departures and lag
+pub const WATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    KeyChanged { key: String, value: CrdtValue },
+    MergeConflict { key: String, local: &'static str, remote: &'static str },
+    PeerJoined { node_id: String, address: Option<String> },
+    PeerLeft { node_id: String },
+    //how long the peer has gone without getting every change
+    Lagging { peer: String, lag: Duration },
+    CaughtUp { peer: String },
+}
+
+pub type ChangeCallback = Arc<dyn Fn(&str, &CrdtValue) + Send + Sync>;
+pub type EventCallback = Arc<dyn Fn(&Event) + Send + Sync>;
+
+//what is waiting for the mergedb-events thread. a changed key is only looked up there
+enum Queued {
+    Changed(String),
+    Event(Event),
+}
+
+#[derive(Default)]
+pub struct Events {
+    changes: RwLock<Vec<(String, ChangeCallback)>>,
+    listeners: RwLock<Vec<EventCallback>>,
+    sender: Mutex<Option<mpsc::UnboundedSender<Queued>>>,
+    lag_threshold: RwLock<Option<Duration>>,
+    //who answered within peer_timeout and who was lagging at the last look
+    reachable: Mutex<HashSet<String>>,
+    lagging: Mutex<HashSet<String>>,
+    //when each peer was first seen, the lag of one that was never sent everything counts
+    //from there
+    first_seen: Mutex<HashMap<String, SystemTime>>,
+}
+
+impl std::fmt::Debug for Events {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Events")
+            .field("changes", &self.changes.read().unwrap().len())
+            .field("listeners", &self.listeners.read().unwrap().len())
+            .field("lag_threshold", &self.lag_threshold.read().unwrap())
+            .finish()
+    }
+}
+
+impl Events {
+    pub fn on_change(&self, prefix: &str, callback: ChangeCallback) {
+        self.changes
+            .write()
+            .unwrap()
+            .push((prefix.to_string(), callback));
+    }
+
+    pub fn on_event(&self, callback: EventCallback) {
+        self.listeners.write().unwrap().push(callback);
+    }
+
+    pub fn set_lag_threshold(&self, threshold: Option<Duration>) {
+        *self.lag_threshold.write().unwrap() = threshold;
+    }
+
+    fn has_listeners(&self) -> bool {
+        !self.listeners.read().unwrap().is_empty()
+    }
+
+    //called with every commit, under the key's entry lock
+    pub fn changed(&self, key: &str) {
+        if self.changes.read().unwrap().is_empty() && !self.has_listeners() {
+            return;
+        }
+        self.queue(Queued::Changed(key.to_string()));
+    }
+
+    pub fn conflict(&self, key: &str, local: &CrdtValue, remote: &CrdtValue) {
+        if self.has_listeners() {
+            self.queue(Queued::Event(Event::MergeConflict {
+                key: key.to_string(),
+                local: local.type_name(),
+                remote: remote.type_name(),
+            }));
+        }
+    }
+
+    fn emit(&self, event: Event) {
+        if self.has_listeners() {
+            self.queue(Queued::Event(event));
+        }
+    }
+
+    fn queue(&self, queued: Queued) {
+        if let Some(sender) = self.sender.lock().unwrap().as_ref() {
+            let _ = sender.send(queued);
+        }
+    }
+
+    //starts the mergedb-events thread, events from before this are dropped
+    pub fn start(server: &Arc<ReplicationServer>) -> std::io::Result<()> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        *server.events.sender.lock().unwrap() = Some(sender);
+        let delivering = server.clone();
+        thread::Builder::new()
+            .name("mergedb-events".to_string())
+            .spawn(move || deliver(&delivering, receiver))?;
+        Ok(())
+    }
+
+    //compares what the peers look like now with the last look, and queues the differences
+    pub fn watch(&self, server: &ReplicationServer) {
+        let membership = &server.membership;
+        let now: BTreeMap<String, Option<String>> = membership
+            .members
+            .iter()
+            .filter(|entry| membership.is_reachable(entry.value()))
+            .map(|entry| (entry.key().clone(), entry.value().address.clone()))
+            .collect();
+        {
+            let mut reachable = self.reachable.lock().unwrap();
+            for (node_id, address) in &now {
+                if !reachable.contains(node_id) {
+                    self.emit(Event::PeerJoined {
+                        node_id: node_id.clone(),
+                        address: address.clone(),
+                    });
+                }
+            }
+            let mut left: Vec<&String> = reachable
+                .iter()
+                .filter(|node_id| !now.contains_key(*node_id))
+                .collect();
+            left.sort();
+            for node_id in left {
+                self.emit(Event::PeerLeft {
+                    node_id: node_id.clone(),
+                });
+            }
+            *reachable = now.into_keys().collect();
+        }
+
+        let Some(threshold) = *self.lag_threshold.read().unwrap() else {
+            return;
+        };
+        let started = SystemTime::now();
+        let mut peer_addrs: Vec<String> = server
+            .peers
+            .iter()
+            .map(|entry| entry.key().clone())
+            .filter(|peer_addr| !membership.is_witness_at(peer_addr))
+            .collect();
+        peer_addrs.sort();
+        let mut first_seen = self.first_seen.lock().unwrap();
+        first_seen.retain(|peer_addr, _| peer_addrs.contains(peer_addr));
+        let mut lagging = self.lagging.lock().unwrap();
+        lagging.retain(|peer_addr| peer_addrs.contains(peer_addr));
+        for peer_addr in peer_addrs {
+            //the default class takes every key outside of gossip_priority, it is only marked
+            //sent once the peer got all of them
+            let since = server
+                .outbound
+                .sent_at(&peer_addr, 0)
+                .unwrap_or(*first_seen.entry(peer_addr.clone()).or_insert(started));
+            let lag = started.duration_since(since).unwrap_or(Duration::ZERO);
+            if lag > threshold {
+                if lagging.insert(peer_addr.clone()) {
+                    self.emit(Event::Lagging {
+                        peer: peer_addr,
+                        lag,
+                    });
+                }
+            } else if lagging.remove(&peer_addr) {
+                self.emit(Event::CaughtUp { peer: peer_addr });
+            }
+        }
+    }
+}
+
+//looks peers over every WATCH_INTERVAL
+pub async fn run(server: &ReplicationServer) {
+    let mut ticks = tokio::time::interval(WATCH_INTERVAL);
+    loop {
+        ticks.tick().await;
+        server.events.watch(server);
+    }
+}
+
+//the mergedb-events thread, it runs as long as the process does
+fn deliver(server: &ReplicationServer, mut receiver: mpsc::UnboundedReceiver<Queued>) {
+    while let Some(queued) = receiver.blocking_recv() {
+        let event = match queued {
+            Queued::Event(event) => event,
+            Queued::Changed(key) => {
+                let changes: Vec<ChangeCallback> = server
+                    .events
+                    .changes
+                    .read()
+                    .unwrap()
+                    .iter()
+                    .filter(|(prefix, _)| key.starts_with(prefix.as_str()))
+                    .map(|(_, callback)| callback.clone())
+                    .collect();
+                if changes.is_empty() && !server.events.has_listeners() {
+                    continue;
+                }
+                let Some(value) = server.store.get(&key).map(|stored| stored.data.clone()) else {
+                    continue;
+                };
+                for callback in changes {
+                    callback(&key, &value);
+                }
+                Event::KeyChanged { key, value }
+            }
+        };
+        let listeners: Vec<EventCallback> = server.events.listeners.read().unwrap().clone();
+        for listener in listeners {
+            listener(&event);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{node::Node, testing};
+    use mergedb_types::pn_counter::PNCounter;
+
+    #[tokio::test]
+    async fn test_events_reach_callbacks_on_their_own_thread() {
+        let config = testing::config("n1", "peer_timeout = \"100ms\"");
+        let (sender, events) = std::sync::mpsc::channel();
+        let node = Node::builder()
+            .config(config)
+            .on_event(move |event| {
+                let thread = thread::current().name().map(str::to_string);
+                let _ = sender.send((thread, event.clone()));
+            })
+            .lag_threshold(Duration::from_millis(100))
+            .spawn()
+            .await
+            .unwrap();
+        let server = node.server();
+        let next = || {
+            let (thread, event) = events.recv_timeout(Duration::from_secs(5)).unwrap();
+            assert_eq!(thread.as_deref(), Some("mergedb-events"));
+            event
+        };
+
+        node.execute(testing::command("RSET", "k", "v"))
+            .await
+            .unwrap();
+        assert!(matches!(next(), Event::KeyChanged { key, .. } if key == "k"));
+
+        let counter = PNCounter::new("n2".to_string(), 0, 0);
+        server.merge_remote("k".to_string(), CrdtValue::Counter(counter));
+        assert_eq!(
+            next(),
+            Event::MergeConflict {
+                key: "k".to_string(),
+                local: "register",
+                remote: "counter"
+            }
+        );
+
+        let address = Some("127.0.0.1:1".to_string());
+        server
+            .membership
+            .observe("n2".to_string(), address.clone(), vec![], 0, false);
+        server.events.watch(server);
+        assert_eq!(
+            next(),
+            Event::PeerJoined {
+                node_id: "n2".to_string(),
+                address
+            }
+        );
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        server.events.watch(server);
+        assert_eq!(
+            next(),
+            Event::PeerLeft {
+                node_id: "n2".to_string()
+            }
+        );
+
+        //never sent anything, it lags from when it was first looked at
+        let peer = "127.0.0.1:2".to_string();
+        server.peers.insert(peer.clone(), SystemTime::UNIX_EPOCH);
+        server.events.watch(server);
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        server.events.watch(server);
+        assert!(matches!(next(), Event::Lagging { peer: lagging, lag }
+            if lagging == peer && lag > Duration::from_millis(100)));
+        server.outbound.mark_sent(&peer, 0, SystemTime::now());
+        server.events.watch(server);
+        assert_eq!(next(), Event::CaughtUp { peer });
+    }
+}
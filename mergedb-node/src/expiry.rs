@@ -0,0 +1,99 @@
+//a key a label rule's retention drops is dropped on the whole cluster, not just on the node that
+//noticed it was idle. there are no deletes, so the node writes a marker under MARKER_PREFIX + key
+//holding a digest of the state it dropped, for the key and for each of its labels. the marker is
+//an lww register and gossips like any other key: a node it reaches drops its own copy if it holds
+//that same state, and state merged in later that is exactly what was dropped is ignored rather
+//than bringing the key back. a copy holding anything else, a write made since or one the
+//dropping node never saw, is kept and gossips the key back
+use mergedb_types::CrdtValue;
+
+use crate::fingerprint;
+
+pub const MARKER_PREFIX: &str = "__expired:";
+
+pub fn marker_key(key: &str) -> String {
+    format!("{}{}", MARKER_PREFIX, key)
+}
+
+//markers are only ever written when a key expires
+pub fn is_marker(key: &str) -> bool {
+    key.starts_with(MARKER_PREFIX)
+}
+
+//the key a marker is for
+pub fn expired_key(marker: &str) -> Option<&str> {
+    marker.strip_prefix(MARKER_PREFIX)
+}
+
+//what a marker holds for the key's state
+pub fn digest(key: &str, value: &CrdtValue) -> String {
+    format!("{:016x}", fingerprint::key_hash(key, value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        network::ReplicationServer,
+        testing::{self, send},
+    };
+    use std::time::{Duration, SystemTime};
+
+    fn server(node_id: &str) -> ReplicationServer {
+        ReplicationServer::for_tests(testing::config(
+            node_id,
+            "[[label_rules]]\nselector = \"env=staging\"\nretention = \"1h\"",
+        ))
+    }
+
+    //gossip of every key under the prefix from one node to the other
+    fn gossip(from: &ReplicationServer, to: &ReplicationServer, prefix: &str) {
+        let states: Vec<(String, CrdtValue)> = from
+            .store
+            .iter()
+            .filter(|entry| entry.key().starts_with(prefix))
+            .map(|entry| (entry.key().clone(), entry.value().data.clone()))
+            .collect();
+        for (key, state) in states {
+            to.merge_remote(key, state);
+        }
+    }
+
+    #[test]
+    fn test_marker_keys() {
+        assert_eq!(marker_key("cart:42"), "__expired:cart:42");
+        assert!(is_marker(&marker_key("views")));
+        assert!(!is_marker("views"));
+        assert_eq!(expired_key(&marker_key("a:b")), Some("a:b"));
+    }
+
+    #[tokio::test]
+    async fn test_expiry_reaches_every_node() {
+        let (a, b) = (server("n1"), server("n2"));
+        send(&a, "RSET", "k", "v").await;
+        send(&a, "LABEL", "k", "env=staging").await;
+        gossip(&a, &b, "");
+        let dropped = a.store.get("k").unwrap().data.clone();
+
+        let later = SystemTime::now() + Duration::from_secs(7200);
+        assert_eq!(a.expire_idle_keys(later).await, 1);
+        assert!(!a.store.contains_key("k"));
+        assert!(a.labels("k").is_empty());
+
+        //the markers reach b, which held the same state
+        gossip(&a, &b, MARKER_PREFIX);
+        assert!(!b.store.contains_key("k"));
+        assert!(b.labels("k").is_empty());
+
+        //what was dropped doesn't come back
+        a.merge_remote("k".to_string(), dropped.clone());
+        assert!(!a.store.contains_key("k"));
+
+        //a write made since does
+        let c = server("n3");
+        c.merge_remote("k".to_string(), dropped);
+        send(&c, "RSET", "k", "w").await;
+        gossip(&c, &a, "k");
+        assert!(a.store.contains_key("k"));
+    }
+}
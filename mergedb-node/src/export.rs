@@ -0,0 +1,217 @@
+//Export streams a range of keys, so a backup of a big store can be split over several
+//connections: by key, eg one worker for a-m and one for m-z, or by hash slot when the keys
+//don't spread evenly over the alphabet. each chunk is read when it is due, a key that keeps
+//changing goes as it was then, restoring merges it like gossip would
+use mergedb_proto::{
+    communication::{ExportChunk, ExportRequest, ExportedKey},
+    CrdtProto,
+};
+use tokio::sync::mpsc;
+use xxhash_rust::xxh3::xxh3_64;
+
+use crate::{keygroup, network::ReplicationServer};
+
+pub const SLOTS: u32 = 16384;
+pub const DEFAULT_BATCH: usize = 500;
+pub const MAX_BATCH: usize = 10_000;
+
+//keys of one hash tag group share a slot, like they share everything else
+pub fn slot(key: &str) -> u32 {
+    (xxh3_64(keygroup::group_of(key).as_bytes()) % SLOTS as u64) as u32
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Range {
+    //[start, end), no end when None
+    Keys { start: String, end: Option<String> },
+    //the keys whose slot is in [start, end)
+    Slots { start: u32, end: u32 },
+}
+
+impl Range {
+    pub fn from_request(request: &ExportRequest) -> Result<Range, String> {
+        if !request.slots {
+            let end = (!request.end.is_empty()).then(|| request.end.clone());
+            if end.as_ref().is_some_and(|end| *end <= request.start) {
+                return Err(format!(
+                    "the range starts at {:?} and ends at {:?}, it holds no keys",
+                    request.start, request.end
+                ));
+            }
+            return Ok(Range::Keys {
+                start: request.start.clone(),
+                end,
+            });
+        }
+        if !request.start.is_empty() || !request.end.is_empty() {
+            return Err("a range is either keys or slots, not both".to_string());
+        }
+        if request.slot_start >= request.slot_end || request.slot_end > SLOTS {
+            return Err(format!(
+                "slots {}-{} is not a range within 0-{}",
+                request.slot_start, request.slot_end, SLOTS
+            ));
+        }
+        Ok(Range::Slots {
+            start: request.slot_start,
+            end: request.slot_end,
+        })
+    }
+
+    pub fn contains(&self, key: &str) -> bool {
+        match self {
+            Range::Keys { start, end } => {
+                key >= start.as_str() && end.as_ref().is_none_or(|end| key < end.as_str())
+            }
+            Range::Slots { start, end } => (*start..*end).contains(&slot(key)),
+        }
+    }
+}
+
+//the keys in range, those still waiting in the snapshot included, in order
+pub fn keys(server: &ReplicationServer, range: &Range) -> Vec<String> {
+    let mut keys: Vec<String> = server
+        .store
+        .iter()
+        .map(|entry| entry.key().clone())
+        .chain(server.persistence.pending_keys(&[]))
+        .filter(|key| range.contains(key))
+        .collect();
+    keys.sort();
+    keys.dedup();
+    keys
+}
+
+//sends the range in chunks of `batch` keys until it is done or nobody is listening anymore
+pub async fn run(
+    server: &ReplicationServer,
+    range: Range,
+    batch: usize,
+    chunks: &mpsc::Sender<Result<ExportChunk, tonic::Status>>,
+) {
+    let keys = keys(server, &range);
+    println!("Exporting {} keys in {:?}", keys.len(), range);
+    server.metrics.incr("exports_total", 1);
+    for chunk in keys.chunks(batch.max(1)) {
+        let exported: Vec<ExportedKey> = chunk
+            .iter()
+            .filter_map(|key| {
+                server.ensure_loaded(key);
+                let value = server.store.get(key)?.data.clone();
+                Some(ExportedKey {
+                    key: key.clone(),
+                    value: Some(value.to_proto()),
+                })
+            })
+            .collect();
+        server
+            .metrics
+            .incr("exported_keys_total", exported.len() as u64);
+        if chunks
+            .send(Ok(ExportChunk { keys: exported }))
+            .await
+            .is_err()
+        {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        communication::replication_service_server::ReplicationService, node::Node, testing,
+    };
+    use tokio_stream::StreamExt;
+    use tonic::Request;
+
+    fn request(start: &str, end: &str) -> ExportRequest {
+        ExportRequest {
+            start: start.to_string(),
+            end: end.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_key_ranges() {
+        let range = Range::from_request(&request("b", "d")).unwrap();
+        assert!(range.contains("b"));
+        assert!(range.contains("c:1"));
+        assert!(!range.contains("d"));
+        assert!(!range.contains("a"));
+
+        let rest = Range::from_request(&request("d", "")).unwrap();
+        assert!(rest.contains("d") && rest.contains("zzz"));
+        assert!(Range::from_request(&request("d", "b")).is_err());
+        assert!(Range::from_request(&request("b", "b")).is_err());
+    }
+
+    #[test]
+    fn test_slot_ranges_cover_every_key_once() {
+        let halves = [(0, SLOTS / 2), (SLOTS / 2, SLOTS)].map(|(slot_start, slot_end)| {
+            Range::from_request(&ExportRequest {
+                slots: true,
+                slot_start,
+                slot_end,
+                ..Default::default()
+            })
+            .unwrap()
+        });
+        let mut in_first = 0;
+        for i in 0..1000 {
+            let key = format!("key:{}", i);
+            let holding = halves.iter().filter(|half| half.contains(&key)).count();
+            assert_eq!(holding, 1, "{}", key);
+            in_first += halves[0].contains(&key) as usize;
+        }
+        //and spread them about evenly
+        assert!((400..600).contains(&in_first), "{}", in_first);
+        assert_eq!(slot("{user:123}:likes"), slot("{user:123}:tags"));
+        assert_eq!(slot("{user:123}:likes"), slot("user:123"));
+
+        let slots = |slot_start, slot_end| ExportRequest {
+            slots: true,
+            slot_start,
+            slot_end,
+            ..Default::default()
+        };
+        assert!(Range::from_request(&slots(5, 5)).is_err());
+        assert!(Range::from_request(&slots(0, SLOTS + 1)).is_err());
+        assert!(Range::from_request(&ExportRequest {
+            start: "a".to_string(),
+            ..slots(0, 1)
+        })
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_export_streams_the_range_in_chunks() {
+        let config = testing::config("n1", "");
+        let node = Node::builder().config(config).spawn().await.unwrap();
+        for key in ["a", "b1", "b2", "b3", "c"] {
+            node.execute(testing::command("RSET", key, "v"))
+                .await
+                .unwrap();
+        }
+
+        let range = ExportRequest {
+            batch: 2,
+            ..request("b", "c")
+        };
+        let chunks: Vec<Vec<String>> = node
+            .server()
+            .export(Request::new(range))
+            .await
+            .unwrap()
+            .into_inner()
+            .map(|chunk| chunk.unwrap().keys.into_iter().map(|k| k.key).collect())
+            .collect()
+            .await;
+        assert_eq!(chunks, vec![vec!["b1", "b2"], vec!["b3"]]);
+
+        let bad = node.server().export(Request::new(request("c", "a"))).await;
+        assert_eq!(bad.err().unwrap().code(), tonic::Code::InvalidArgument);
+    }
+}
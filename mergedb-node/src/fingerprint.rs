@@ -0,0 +1,73 @@
+use dashmap::DashMap;
+use mergedb_types::{canonical::CanonicalHash, CrdtValue};
+use std::hash::Hasher;
+use xxhash_rust::xxh3::Xxh3;
+
+use crate::network::StoredValue;
+
+//a digest of the whole store that two nodes agree on exactly when they hold the same keys in
+//the same converged state: the xor of one hash per key, so it doesn't matter in which order keys are
+//visited or were written. xxh3 because it is specified, nodes of any build hash alike
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fingerprint {
+    pub digest: u64,
+    pub keys: u64,
+}
+
+pub fn of_store(store: &DashMap<String, StoredValue>) -> Fingerprint {
+    let mut fingerprint = Fingerprint { digest: 0, keys: 0 };
+    for entry in store.iter() {
+        fingerprint.digest ^= key_hash(entry.key(), &entry.value().data);
+        fingerprint.keys += 1;
+    }
+    fingerprint
+}
+
+//the key and its crdt state, see mergedb_types::canonical for what counts as state
+pub fn key_hash(key: &str, value: &CrdtValue) -> u64 {
+    let mut hasher = Xxh3::new();
+    hasher.write(&(key.len() as u64).to_le_bytes());
+    hasher.write(key.as_bytes());
+    value.canonical_hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mergedb_types::{aw_set::AWSet, Merge};
+    use std::time::SystemTime;
+
+    fn stored(data: CrdtValue) -> StoredValue {
+        StoredValue {
+            data,
+            last_updated: SystemTime::now(),
+        }
+    }
+
+    #[test]
+    fn test_merge_order_does_not_matter() {
+        let mut from_1 = AWSet::new();
+        from_1.add("a".to_string(), "node_1".to_string());
+        let mut from_2 = AWSet::new();
+        from_2.add("b".to_string(), "node_2".to_string());
+        let mut ab = from_1.clone();
+        ab.merge(&from_2);
+        let mut ba = from_2.clone();
+        ba.merge(&from_1);
+
+        let first = DashMap::new();
+        first.insert("tags".to_string(), stored(CrdtValue::Set(ab)));
+        first.insert("other".to_string(), stored(CrdtValue::Set(AWSet::new())));
+        let second = DashMap::new();
+        second.insert("other".to_string(), stored(CrdtValue::Set(AWSet::new())));
+        second.insert("tags".to_string(), stored(CrdtValue::Set(ba)));
+
+        assert_eq!(of_store(&first), of_store(&second));
+        assert_eq!(of_store(&first).keys, 2);
+
+        second.remove("other");
+        assert_ne!(of_store(&first), of_store(&second));
+        assert_eq!(of_store(&DashMap::new()).digest, 0);
+    }
+}
@@ -0,0 +1,27 @@
+//FREEZE makes a key read-only for clients on every node, eg while its data is being corrected
+//or migrated. the marker is an lww register stored under MARKER_PREFIX + key, so it gossips
+//like any other key and concurrent FREEZE and THAW settle on whichever came last. merges keep
+//being accepted, state written before the freeze still has to converge
+pub const MARKER_PREFIX: &str = "__frozen:";
+pub const FROZEN: &str = "frozen";
+
+pub fn marker_key(key: &str) -> String {
+    format!("{}{}", MARKER_PREFIX, key)
+}
+
+//markers are only ever written through FREEZE/THAW
+pub fn is_marker(key: &str) -> bool {
+    key.starts_with(MARKER_PREFIX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_marker_keys() {
+        assert_eq!(marker_key("flags:dark"), "__frozen:flags:dark");
+        assert!(is_marker(&marker_key("views")));
+        assert!(!is_marker("views"));
+    }
+}
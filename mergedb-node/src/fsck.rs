@@ -0,0 +1,345 @@
+use anyhow::{Context, Result};
+use mergedb_proto::{communication::CrdtData, CrdtProto};
+use mergedb_types::CrdtValue;
+use prost::Message;
+use std::{
+    fs::{self, File},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    persistence::{self, Record, SNAPSHOT_FILE},
+    wal,
+};
+
+//what `mergedb-node fsck` found in a data_dir. a record is bad when it fails its checksum or
+//its value doesn't decode, and everything from the first bad record on is what a repair cuts
+//off, including whole records that happen to come after it
+#[derive(Debug, Default)]
+pub struct Report {
+    pub path: PathBuf,
+    pub exists: bool,
+    pub checksummed: bool,
+    pub intact: usize,
+    //keys whose value is gone, with what was wrong
+    pub unrecoverable: Vec<(String, String)>,
+    //whole records after the first bad one, lost by a repair
+    pub dropped: Vec<String>,
+    //a record cut off at the end of the file
+    pub truncated: Option<String>,
+    //where the last good record ends, and the length of the file
+    pub valid_len: usize,
+    pub file_len: usize,
+    pub repaired: bool,
+    //the wal files that exist, oldest first
+    pub wal: Vec<LogReport>,
+}
+
+//what fsck found in a wal file. the node drops a torn end itself when it replays the log, but
+//a bad record in the middle takes every record after it along
+#[derive(Debug, Default)]
+pub struct LogReport {
+    pub path: PathBuf,
+    pub records: usize,
+    pub valid_len: usize,
+    pub file_len: usize,
+    pub repaired: bool,
+}
+
+impl LogReport {
+    pub fn is_clean(&self) -> bool {
+        self.valid_len == self.file_len
+    }
+}
+
+impl Report {
+    pub fn is_clean(&self) -> bool {
+        self.snapshot_is_clean() && self.wal.iter().all(LogReport::is_clean)
+    }
+
+    fn snapshot_is_clean(&self) -> bool {
+        self.valid_len == self.file_len
+    }
+
+    //0 for a clean or repaired data_dir, 1 when damage was found and left alone. a file that
+    //can't be checked at all is an error, which main turns into 2
+    pub fn exit_code(&self) -> i32 {
+        let snapshot = self.snapshot_is_clean() || self.repaired;
+        if snapshot && self.wal.iter().all(|log| log.is_clean() || log.repaired) {
+            0
+        } else {
+            1
+        }
+    }
+
+    pub fn summary(&self) -> String {
+        let mut lines = Vec::new();
+        if self.exists {
+            self.snapshot_summary(&mut lines);
+        } else {
+            lines.push(format!(
+                "{}: no snapshot, nothing to check",
+                self.path.display()
+            ));
+        }
+        for log in &self.wal {
+            lines.push(format!(
+                "{}: {} intact records",
+                log.path.display(),
+                log.records
+            ));
+            if log.is_clean() {
+                lines.push("wal is clean".to_string());
+            } else if log.repaired {
+                lines.push(format!(
+                    "repaired: truncated from {} to {} bytes",
+                    log.file_len, log.valid_len
+                ));
+            } else {
+                lines.push(format!(
+                    "damaged: {} of {} bytes are good, run with --repair to truncate the rest",
+                    log.valid_len, log.file_len
+                ));
+            }
+        }
+        lines.join("\n")
+    }
+
+    fn snapshot_summary(&self, lines: &mut Vec<String>) {
+        lines.push(format!(
+            "{}: {} intact records{}",
+            self.path.display(),
+            self.intact,
+            if self.checksummed {
+                ""
+            } else {
+                " (MDBSNAP1, no checksums, values checked by decoding only)"
+            }
+        ));
+        for (key, reason) in &self.unrecoverable {
+            lines.push(format!("unrecoverable: {} ({})", key, reason));
+        }
+        if let Some(truncated) = &self.truncated {
+            lines.push(format!("truncated: {}", truncated));
+        }
+        if !self.dropped.is_empty() {
+            lines.push(format!(
+                "{} intact records follow the damage and are lost by a repair: {}",
+                self.dropped.len(),
+                self.dropped.join(", ")
+            ));
+        }
+        if self.snapshot_is_clean() {
+            lines.push("snapshot is clean".to_string());
+        } else if self.repaired {
+            lines.push(format!(
+                "repaired: truncated from {} to {} bytes",
+                self.file_len, self.valid_len
+            ));
+        } else {
+            lines.push(format!(
+                "damaged: {} of {} bytes are good, run with --repair to truncate the rest",
+                self.valid_len, self.file_len
+            ));
+        }
+    }
+}
+
+//checks the snapshot and the wal in `data_dir`, and with `repair` truncates each to its last
+//good record. refused while a node holds the data_dir, it has the snapshot mapped
+pub fn run(data_dir: &Path, repair: bool) -> Result<Report> {
+    let path = data_dir.join(SNAPSHOT_FILE);
+    let mut report = Report {
+        path: path.clone(),
+        ..Report::default()
+    };
+    if !data_dir.exists() {
+        return Ok(report);
+    }
+    let _lock = persistence::lock(data_dir)?;
+
+    for path in wal::logs(data_dir) {
+        if path.exists() {
+            report.wal.push(check_log(path, repair)?);
+        }
+    }
+    if !path.exists() {
+        return Ok(report);
+    }
+    report.exists = true;
+
+    let snapshot = fs::read(&path).with_context(|| format!("failed to read {}", path.display()))?;
+    let scan = persistence::scan(&snapshot)
+        .with_context(|| format!("failed to read {}", path.display()))?;
+    report.checksummed = scan.checksummed;
+    report.file_len = snapshot.len();
+    report.valid_len = scan.valid_len;
+    report.truncated = scan.damage;
+
+    let mut bad_from = None;
+    for record in scan.records {
+        match (bad_from, check(&snapshot, &record)) {
+            (None, Ok(())) => report.intact += 1,
+            (None, Err(reason)) => {
+                bad_from = Some(record.location.start);
+                report.unrecoverable.push((record.key, reason));
+            }
+            (Some(_), Ok(())) => report.dropped.push(record.key),
+            (Some(_), Err(reason)) => report.unrecoverable.push((record.key, reason)),
+        }
+    }
+    if let Some(start) = bad_from {
+        report.valid_len = start;
+    }
+
+    if repair && !report.snapshot_is_clean() {
+        replace(&path, &snapshot[..report.valid_len])?;
+        report.repaired = true;
+    }
+    Ok(report)
+}
+
+fn check_log(path: PathBuf, repair: bool) -> Result<LogReport> {
+    let log = fs::read(&path).with_context(|| format!("failed to read {}", path.display()))?;
+    let (records, valid_len) =
+        wal::scan(&log).with_context(|| format!("failed to read {}", path.display()))?;
+    let mut report = LogReport {
+        path,
+        records,
+        valid_len,
+        file_len: log.len(),
+        repaired: false,
+    };
+    if repair && !report.is_clean() {
+        replace(&report.path, &log[..valid_len])?;
+        report.repaired = true;
+    }
+    Ok(report)
+}
+
+//swaps `path` for a file holding `contents`, written next to it and renamed over it the way a
+//snapshot is, so the file is never seen half written
+fn replace(path: &Path, contents: &[u8]) -> Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".fsck");
+    let tmp = PathBuf::from(tmp);
+    let mut file =
+        File::create(&tmp).with_context(|| format!("failed to create {}", tmp.display()))?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)
+        .with_context(|| format!("failed to replace {} for repair", path.display()))?;
+    Ok(())
+}
+
+fn check(snapshot: &[u8], record: &Record) -> Result<(), String> {
+    let location = record.location;
+    if !location.intact(snapshot) {
+        return Err("checksum mismatch".to_string());
+    }
+    let raw = &snapshot[location.offset..location.offset + location.len];
+    let wire = CrdtData::decode(raw).map_err(|e| format!("value does not decode: {}", e))?;
+    CrdtValue::from_proto(wire).map_err(|e| format!("value does not decode: {}", e))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{network::StoredValue, persistence::Persistence};
+    use dashmap::DashMap;
+    use mergedb_types::pn_counter::PNCounter;
+    use std::time::SystemTime;
+
+    #[test]
+    fn test_repair_truncates_to_the_last_good_record() {
+        let dir = std::env::temp_dir().join(format!("mergedb-fsck-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        let store = DashMap::new();
+        for key in ["a", "b", "c"] {
+            store.insert(
+                key.to_string(),
+                StoredValue {
+                    data: CrdtValue::Counter(PNCounter::new("node_1".to_string(), 1, 0)),
+                    last_updated: SystemTime::now(),
+                },
+            );
+        }
+        Persistence::open(Some(dir.clone()))
+            .unwrap()
+            .write_snapshot(&store)
+            .unwrap();
+        assert_eq!(run(&dir, false).unwrap().exit_code(), 0);
+
+        //flip a byte in the second record's value and cut the last one short
+        let path = dir.join(SNAPSHOT_FILE);
+        let mut snapshot = fs::read(&path).unwrap();
+        let records = persistence::scan(&snapshot).unwrap().records;
+        let second = records[1].clone();
+        snapshot[second.location.offset] ^= 0xff;
+        snapshot.truncate(snapshot.len() - 2);
+        fs::write(&path, &snapshot).unwrap();
+
+        let report = run(&dir, false).unwrap();
+        assert_eq!(report.intact, 1);
+        assert_eq!(report.unrecoverable[0].0, second.key);
+        assert!(report.truncated.is_some());
+        assert_eq!(report.exit_code(), 1);
+        assert_eq!(fs::read(&path).unwrap().len(), snapshot.len());
+
+        let repaired = run(&dir, true).unwrap();
+        assert_eq!(repaired.exit_code(), 0);
+        assert_eq!(fs::read(&path).unwrap().len(), second.location.start);
+        assert!(run(&dir, false).unwrap().is_clean());
+        assert_eq!(
+            Persistence::open(Some(dir.clone())).unwrap().snapshot_keys,
+            1
+        );
+
+        //a running node holds the data_dir
+        let running = Persistence::open(Some(dir.clone())).unwrap();
+        assert!(run(&dir, true).is_err());
+        drop(running);
+        assert!(run(&dir, false).is_ok());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_repair_cuts_the_wal_at_the_first_bad_record() {
+        let dir = std::env::temp_dir().join(format!("mergedb-fsck-wal-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let mut log = b"MDBWAL01".to_vec();
+        let mut ends = Vec::new();
+        for key in ["a", "b", "c"] {
+            let value = CrdtValue::Counter(PNCounter::new("node_1".to_string(), 1, 0));
+            persistence::encode_record(key, &value, &mut log).unwrap();
+            ends.push(log.len());
+        }
+        let path = dir.join(wal::WAL_FILE);
+        fs::write(&path, &log).unwrap();
+        let report = run(&dir, false).unwrap();
+        assert!(!report.exists);
+        assert_eq!(report.wal[0].records, 3);
+        assert_eq!(report.exit_code(), 0);
+
+        //the second record's checksum no longer matches
+        log[ends[1] - 1] ^= 0xff;
+        fs::write(&path, &log).unwrap();
+        let report = run(&dir, false).unwrap();
+        assert_eq!(report.wal[0].records, 1);
+        assert_eq!(report.wal[0].valid_len, ends[0]);
+        assert_eq!(report.exit_code(), 1);
+        assert_eq!(fs::read(&path).unwrap().len(), log.len());
+
+        assert_eq!(run(&dir, true).unwrap().exit_code(), 0);
+        assert_eq!(fs::read(&path).unwrap(), &log[..ends[0]]);
+        assert!(run(&dir, false).unwrap().is_clean());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,406 @@
+//a graphql endpoint at http://<graphql_address>/graphql for frontends. queries read counters,
+//sets and registers, one key at a time or every one of the type under a prefix:
+//  {counter(key: "views") sets(prefix: "flags:") { key elements }}
+//a missing key is null, one holding another type is an error. a subscription is a WATCH, see
+//websocket: every key under the prefix once and then again whenever it changes, streamed back
+//as server-sent events to a request that accepts text/event-stream:
+//  subscription { changes(prefix: "user:") { key type value } }
+//nothing here writes, writes go through the client api
+use anyhow::{Context as _, Result};
+use async_graphql::{
+    futures_util::{stream, Stream, StreamExt},
+    Context, EmptyMutation, Json, Object, Schema, SimpleObject, Subscription,
+};
+use hyper::{
+    header,
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use mergedb_types::CrdtValue;
+use serde_json::Value;
+use std::{collections::HashMap, convert::Infallible, net::SocketAddr, sync::Arc};
+
+use crate::{
+    network::{is_internal, ReplicationServer},
+    websocket,
+};
+
+pub const PATH: &str = "/graphql";
+//a query bigger than this is refused
+const MAX_QUERY: usize = 1 << 20;
+
+pub type MergeDbSchema = Schema<Query, EmptyMutation, Subscription>;
+
+#[derive(SimpleObject, Debug, Clone, PartialEq)]
+pub struct Counter {
+    key: String,
+    value: i64,
+}
+
+#[derive(SimpleObject, Debug, Clone, PartialEq)]
+pub struct Set {
+    key: String,
+    elements: Vec<String>,
+}
+
+#[derive(SimpleObject, Debug, Clone, PartialEq)]
+pub struct Register {
+    key: String,
+    value: String,
+}
+
+//a watched key that is new or changed, its value the way materialize writes it out
+#[derive(SimpleObject, Debug, Clone)]
+pub struct Change {
+    key: String,
+    #[graphql(name = "type")]
+    kind: String,
+    value: Json<Value>,
+}
+
+pub struct Query;
+
+#[Object]
+impl Query {
+    async fn counter(&self, ctx: &Context<'_>, key: String) -> async_graphql::Result<Option<i64>> {
+        Ok(match value(server(ctx), &key)? {
+            Some(CrdtValue::Counter(counter)) => Some(counter.value()),
+            Some(other) => return Err(wrong_type(&key, &other, "counter")),
+            None => None,
+        })
+    }
+
+    async fn set(
+        &self,
+        ctx: &Context<'_>,
+        key: String,
+    ) -> async_graphql::Result<Option<Vec<String>>> {
+        Ok(match value(server(ctx), &key)? {
+            Some(CrdtValue::Set(set)) => Some(elements(&set)),
+            Some(other) => return Err(wrong_type(&key, &other, "set")),
+            None => None,
+        })
+    }
+
+    async fn register(
+        &self,
+        ctx: &Context<'_>,
+        key: String,
+    ) -> async_graphql::Result<Option<String>> {
+        Ok(match value(server(ctx), &key)? {
+            Some(CrdtValue::Register(register)) => Some(register.get()),
+            Some(other) => return Err(wrong_type(&key, &other, "register")),
+            None => None,
+        })
+    }
+
+    //every counter under the prefix, by key
+    async fn counters(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(default)] prefix: String,
+    ) -> Vec<Counter> {
+        under(server(ctx), &prefix)
+            .into_iter()
+            .filter_map(|(key, value)| match value {
+                CrdtValue::Counter(counter) => Some(Counter {
+                    key,
+                    value: counter.value(),
+                }),
+                _ => None,
+            })
+            .collect()
+    }
+
+    async fn sets(&self, ctx: &Context<'_>, #[graphql(default)] prefix: String) -> Vec<Set> {
+        under(server(ctx), &prefix)
+            .into_iter()
+            .filter_map(|(key, value)| match value {
+                CrdtValue::Set(set) => Some(Set {
+                    key,
+                    elements: elements(&set),
+                }),
+                _ => None,
+            })
+            .collect()
+    }
+
+    async fn registers(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(default)] prefix: String,
+    ) -> Vec<Register> {
+        under(server(ctx), &prefix)
+            .into_iter()
+            .filter_map(|(key, value)| match value {
+                CrdtValue::Register(register) => Some(Register {
+                    key,
+                    value: register.get(),
+                }),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+pub struct Subscription;
+
+#[Subscription]
+impl Subscription {
+    async fn changes(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(default)] prefix: String,
+    ) -> impl Stream<Item = Change> {
+        let server = server(ctx).clone();
+        let watching = (server, prefix, HashMap::new(), None);
+        stream::unfold(watching, |(server, prefix, mut sent, tick)| async move {
+            let mut tick = tick.unwrap_or_else(|| tokio::time::interval(websocket::WATCH_EVERY));
+            tick.tick().await;
+            let changes: Vec<Change> = websocket::changes(&server, &prefix, &mut sent)
+                .into_iter()
+                .map(|(key, kind, value)| Change {
+                    key,
+                    kind: kind.to_string(),
+                    value: Json(value),
+                })
+                .collect();
+            Some((stream::iter(changes), (server, prefix, sent, Some(tick))))
+        })
+        .flatten()
+    }
+}
+
+pub fn schema(server: Arc<ReplicationServer>) -> MergeDbSchema {
+    Schema::build(Query, EmptyMutation, Subscription)
+        .data(server)
+        .finish()
+}
+
+fn server<'a>(ctx: &Context<'a>) -> &'a Arc<ReplicationServer> {
+    ctx.data_unchecked::<Arc<ReplicationServer>>()
+}
+
+//the key's value, loaded from the snapshot if it is still waiting there
+fn value(server: &ReplicationServer, key: &str) -> async_graphql::Result<Option<CrdtValue>> {
+    if is_internal(key) {
+        return Err(format!("{} is internal to the node", key).into());
+    }
+    server.ensure_loaded(key);
+    if let Some(target) = server.renamed_to(key) {
+        return Err(format!("{} was renamed to {}", key, target).into());
+    }
+    Ok(server.store.get(key).map(|stored| stored.data.clone()))
+}
+
+//the keys under the prefix with their values, sorted, the way SCAN lists them
+fn under(server: &ReplicationServer, prefix: &str) -> Vec<(String, CrdtValue)> {
+    let mut keys: Vec<String> = server
+        .store
+        .iter()
+        .map(|entry| entry.key().clone())
+        .chain(server.persistence.pending_keys(&[prefix.to_string()]))
+        .filter(|key| key.starts_with(prefix) && !is_internal(key))
+        .collect();
+    keys.sort();
+    keys.dedup();
+    keys.into_iter()
+        .filter(|key| server.renamed_to(key).is_none())
+        .filter_map(|key| {
+            server.ensure_loaded(&key);
+            let value = server.store.get(&key)?.data.clone();
+            Some((key, value))
+        })
+        .collect()
+}
+
+fn elements(set: &mergedb_types::aw_set::AWSet) -> Vec<String> {
+    set.read_sorted().iter().map(ToString::to_string).collect()
+}
+
+fn wrong_type(key: &str, value: &CrdtValue, wanted: &str) -> async_graphql::Error {
+    format!("{} holds a {}, not a {}", key, value.type_name(), wanted).into()
+}
+
+pub async fn serve(address: &str, server: Arc<ReplicationServer>) -> Result<()> {
+    let address: SocketAddr = address
+        .parse()
+        .with_context(|| format!("invalid graphql_address {}", address))?;
+    let schema = schema(server);
+    let make_service = make_service_fn(move |_| {
+        let schema = schema.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let schema = schema.clone();
+                async move { Ok::<_, Infallible>(respond(schema, request).await) }
+            }))
+        }
+    });
+    println!("serving graphql on http://{}{}", address, PATH);
+    Server::try_bind(&address)
+        .with_context(|| format!("can't listen on graphql_address {}", address))?
+        .serve(make_service)
+        .await?;
+    Ok(())
+}
+
+async fn respond(schema: MergeDbSchema, request: Request<Body>) -> Response<Body> {
+    if request.method() != Method::POST || request.uri().path() != PATH {
+        return plain(
+            StatusCode::NOT_FOUND,
+            format!("graphql queries are POSTed to {}\n", PATH),
+        );
+    }
+    let streamed = request
+        .headers()
+        .get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| accept.contains("text/event-stream"));
+    let body = match hyper::body::to_bytes(request.into_body()).await {
+        Ok(body) if body.len() > MAX_QUERY => {
+            return plain(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                format!("a query can take up to {} bytes\n", MAX_QUERY),
+            )
+        }
+        Ok(body) => body,
+        Err(e) => return plain(StatusCode::BAD_REQUEST, format!("{}\n", e)),
+    };
+    let query: async_graphql::Request = match serde_json::from_slice(&body) {
+        Ok(query) => query,
+        Err(e) => {
+            return plain(
+                StatusCode::BAD_REQUEST,
+                format!("not a graphql request: {}\n", e),
+            )
+        }
+    };
+
+    if !streamed {
+        let answer = schema.execute(query).await;
+        return json(serde_json::to_vec(&answer).unwrap());
+    }
+    //every answer is an event, until the subscription ends or the client goes away
+    let (mut sender, body) = Body::channel();
+    tokio::spawn(async move {
+        let mut answers = schema.execute_stream(query);
+        while let Some(answer) = answers.next().await {
+            let event = format!(
+                "event: next\ndata: {}\n\n",
+                serde_json::to_string(&answer).unwrap()
+            );
+            if sender.send_data(event.into()).await.is_err() {
+                return;
+            }
+        }
+        let _ = sender.send_data("event: complete\ndata:\n\n".into()).await;
+    });
+    Response::builder()
+        .header(header::CONTENT_TYPE, "text/event-stream")
+        .header(header::CACHE_CONTROL, "no-cache")
+        .body(body)
+        .unwrap()
+}
+
+fn json(body: Vec<u8>) -> Response<Body> {
+    Response::builder()
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body))
+        .unwrap()
+}
+
+fn plain(status: StatusCode, message: String) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "text/plain")
+        .body(Body::from(message))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, send};
+    use mergedb_proto::wire;
+    use serde_json::json;
+
+    fn server() -> Arc<ReplicationServer> {
+        Arc::new(testing::server("n1"))
+    }
+
+    async fn query(schema: &MergeDbSchema, query: &str) -> Value {
+        let answer = schema.execute(query).await;
+        serde_json::to_value(&answer).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_queries_read_typed_values() {
+        let server = server();
+        send(&server, "CSET", "views:home", wire::encode_i64(7)).await;
+        send(&server, "CSET", "views:about", wire::encode_i64(-2)).await;
+        send(&server, "SADD", "flags:dark", "beta").await;
+        send(&server, "RSET", "name", "mergedb").await;
+        let schema = schema(server.clone());
+
+        let answer = query(
+            &schema,
+            r#"{ counter(key: "views:home") register(key: "name") set(key: "flags:dark")
+                 missing: counter(key: "nope") counters(prefix: "views:") { key value } }"#,
+        )
+        .await;
+        assert_eq!(
+            answer["data"],
+            json!({
+                "counter": 7,
+                "register": "mergedb",
+                "set": ["beta"],
+                "missing": null,
+                "counters": [
+                    {"key": "views:about", "value": -2},
+                    {"key": "views:home", "value": 7},
+                ],
+            })
+        );
+        //a prefix listing only has the type asked for
+        let answer = query(&schema, "{ registers { key value } sets { key } }").await;
+        assert_eq!(
+            answer["data"],
+            json!({
+                "registers": [{"key": "name", "value": "mergedb"}],
+                "sets": [{"key": "flags:dark"}],
+            })
+        );
+
+        let answer = query(&schema, r#"{ counter(key: "name") }"#).await;
+        assert_eq!(
+            answer["errors"][0]["message"],
+            "name holds a register, not a counter"
+        );
+        let answer = query(&schema, r#"{ register(key: "__paused") }"#).await;
+        assert!(answer["errors"][0]["message"]
+            .as_str()
+            .unwrap()
+            .contains("internal"));
+        //nothing to write with
+        assert!(query(&schema, "mutation { x }").await["errors"].is_array());
+    }
+
+    #[tokio::test]
+    async fn test_subscriptions_send_changes() {
+        let server = server();
+        send(&server, "CSET", "user:1", wire::encode_i64(1)).await;
+        let schema = schema(server.clone());
+        let mut changes = schema
+            .execute_stream(r#"subscription { changes(prefix: "user:") { key type value } }"#);
+
+        let first = serde_json::to_value(changes.next().await.unwrap()).unwrap();
+        assert_eq!(
+            first["data"]["changes"],
+            json!({"key": "user:1", "type": "counter", "value": 1})
+        );
+        send(&server, "CSET", "other", wire::encode_i64(5)).await;
+        send(&server, "CSET", "user:1", wire::encode_i64(3)).await;
+        let next = serde_json::to_value(changes.next().await.unwrap()).unwrap();
+        assert_eq!(next["data"]["changes"]["value"], 3);
+    }
+}
@@ -0,0 +1,297 @@
+//grpc-web on the client listener, for browsers that can't speak grpc's http/2 trailers and have
+//no proxy in front to translate. with grpc_web on, a request whose content-type is
+//application/grpc-web(-text) is answered here: its one message is decoded, handed to the
+//listener like any rpc, and the reply comes back as a data frame followed by a trailers frame
+//(base64 encoded for -text). only the unary client rpcs are offered, everything else is left
+//to tonic untouched. preflights are answered so that pages on other origins can call in
+use base64::{engine::general_purpose::STANDARD, Engine};
+use hyper::{body::HttpBody, header, Body, Method, Request, Response, StatusCode};
+use prost::Message;
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tonic::{body::BoxBody, Code, Status};
+use tower::{Layer, Service};
+
+use crate::{communication::replication_service_server::ReplicationService, listener::Listener};
+
+const SERVICE: &str = "/communication.ReplicationService/";
+//a frame's first byte, set for the trailers frame
+const TRAILERS_FLAG: u8 = 0x80;
+//a call bigger than this is refused before it is decoded
+const MAX_MESSAGE: usize = 4 << 20;
+
+#[derive(Clone)]
+pub struct GrpcWebLayer {
+    listener: Listener,
+}
+
+impl GrpcWebLayer {
+    pub fn new(listener: Listener) -> Self {
+        GrpcWebLayer { listener }
+    }
+}
+
+impl<S> Layer<S> for GrpcWebLayer {
+    type Service = GrpcWeb<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        GrpcWeb {
+            inner,
+            listener: self.listener.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct GrpcWeb<S> {
+    inner: S,
+    listener: Listener,
+}
+
+impl<S> Service<Request<Body>> for GrpcWeb<S>
+where
+    S: Service<Request<Body>, Response = Response<BoxBody>> + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = Response<BoxBody>;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<Body>) -> Self::Future {
+        if request.method() == Method::OPTIONS {
+            return Box::pin(async { Ok(preflight()) });
+        }
+        match WebFormat::of(&request) {
+            Some(format) => {
+                let listener = self.listener.clone();
+                Box::pin(async move { Ok(respond(listener, request, format).await) })
+            }
+            None => Box::pin(self.inner.call(request)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum WebFormat {
+    Binary,
+    //the whole body base64 encoded, for clients that can only send text
+    Text,
+}
+
+impl WebFormat {
+    fn of(request: &Request<Body>) -> Option<WebFormat> {
+        let content_type = request.headers().get(header::CONTENT_TYPE)?.to_str().ok()?;
+        if content_type.starts_with("application/grpc-web-text") {
+            Some(WebFormat::Text)
+        } else if content_type.starts_with("application/grpc-web") {
+            Some(WebFormat::Binary)
+        } else {
+            None
+        }
+    }
+
+    fn content_type(self) -> &'static str {
+        match self {
+            WebFormat::Binary => "application/grpc-web+proto",
+            WebFormat::Text => "application/grpc-web-text+proto",
+        }
+    }
+}
+
+fn preflight() -> Response<BoxBody> {
+    let mut response = Response::new(tonic::body::empty_body());
+    *response.status_mut() = StatusCode::NO_CONTENT;
+    let headers = response.headers_mut();
+    headers.insert("access-control-allow-origin", "*".parse().unwrap());
+    headers.insert(
+        "access-control-allow-methods",
+        "POST, OPTIONS".parse().unwrap(),
+    );
+    headers.insert(
+        "access-control-allow-headers",
+        "content-type, x-grpc-web, x-user-agent, grpc-timeout"
+            .parse()
+            .unwrap(),
+    );
+    headers.insert("access-control-max-age", "86400".parse().unwrap());
+    response
+}
+
+async fn respond(
+    listener: Listener,
+    request: Request<Body>,
+    format: WebFormat,
+) -> Response<BoxBody> {
+    let rpc = request
+        .uri()
+        .path()
+        .strip_prefix(SERVICE)
+        .unwrap_or_default()
+        .to_string();
+    let result = match hyper::body::to_bytes(request.into_body()).await {
+        Ok(body) => match decode_body(&body, format) {
+            Ok(message) => call(&listener, &rpc, &message).await,
+            Err(status) => Err(*status),
+        },
+        Err(e) => Err(Status::invalid_argument(format!(
+            "failed to read the body: {}",
+            e
+        ))),
+    };
+
+    let mut body = Vec::new();
+    let status = match result {
+        Ok(reply) => {
+            push_frame(&mut body, 0, &reply);
+            Status::new(Code::Ok, "")
+        }
+        Err(status) => status,
+    };
+    let trailers = format!(
+        "grpc-status:{}\r\ngrpc-message:{}\r\n",
+        status.code() as i32,
+        percent_encode(status.message())
+    );
+    push_frame(&mut body, TRAILERS_FLAG, trailers.as_bytes());
+    if format == WebFormat::Text {
+        body = STANDARD.encode(body).into_bytes();
+    }
+
+    let body = Body::from(body)
+        .map_err(|e| Status::internal(e.to_string()))
+        .boxed_unsync();
+    let mut response = Response::new(body);
+    let headers = response.headers_mut();
+    headers.insert(header::CONTENT_TYPE, format.content_type().parse().unwrap());
+    headers.insert("access-control-allow-origin", "*".parse().unwrap());
+    headers.insert(
+        "access-control-expose-headers",
+        "grpc-status, grpc-message".parse().unwrap(),
+    );
+    response
+}
+
+//the unary rpcs a client needs, answered by the listener so its role still applies
+async fn call(listener: &Listener, rpc: &str, message: &[u8]) -> Result<Vec<u8>, Status> {
+    let malformed = |e: prost::DecodeError| Status::invalid_argument(e.to_string());
+    let reply = match rpc {
+        "PropagateData" => listener
+            .propagate_data(tonic::Request::new(
+                Message::decode(message).map_err(malformed)?,
+            ))
+            .await?
+            .into_inner()
+            .encode_to_vec(),
+        "PropagateBatch" => listener
+            .propagate_batch(tonic::Request::new(
+                Message::decode(message).map_err(malformed)?,
+            ))
+            .await?
+            .into_inner()
+            .encode_to_vec(),
+        "ClusterStatus" => listener
+            .cluster_status(tonic::Request::new(
+                Message::decode(message).map_err(malformed)?,
+            ))
+            .await?
+            .into_inner()
+            .encode_to_vec(),
+        rpc => {
+            return Err(Status::unimplemented(format!(
+                "{} isn't served over grpc-web",
+                rpc
+            )))
+        }
+    };
+    Ok(reply)
+}
+
+//the one message in a request body
+fn decode_body(body: &[u8], format: WebFormat) -> Result<Vec<u8>, Box<Status>> {
+    let body = match format {
+        WebFormat::Binary => body.to_vec(),
+        WebFormat::Text => STANDARD
+            .decode(body)
+            .map_err(|e| Status::invalid_argument(format!("body is not base64: {}", e)))?,
+    };
+    let Some((header, rest)) = body.split_first_chunk::<5>() else {
+        return Err(Box::new(Status::invalid_argument("body has no grpc frame")));
+    };
+    let len = u32::from_be_bytes(header[1..].try_into().unwrap()) as usize;
+    if header[0] != 0 {
+        return Err(Box::new(Status::unimplemented(
+            "compressed messages aren't supported",
+        )));
+    }
+    if len > MAX_MESSAGE {
+        return Err(Box::new(Status::resource_exhausted(format!(
+            "message of {} bytes is over the {} byte limit",
+            len, MAX_MESSAGE
+        ))));
+    }
+    if rest.len() != len {
+        return Err(Box::new(Status::invalid_argument(
+            "body is not exactly one grpc frame",
+        )));
+    }
+    Ok(rest.to_vec())
+}
+
+fn push_frame(body: &mut Vec<u8>, flag: u8, payload: &[u8]) {
+    body.push(flag);
+    body.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    body.extend_from_slice(payload);
+}
+
+//grpc-message is percent-encoded, like it is in http/2 trailers
+fn percent_encode(message: &str) -> String {
+    message
+        .bytes()
+        .map(|byte| match byte {
+            b' '..=b'~' if byte != b'%' => (byte as char).to_string(),
+            byte => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{communication::PropagateDataRequest, testing};
+
+    #[test]
+    fn test_request_bodies() {
+        let request = testing::command("CGET", "views", "");
+        let mut body = Vec::new();
+        push_frame(&mut body, 0, &request.encode_to_vec());
+
+        let message = decode_body(&body, WebFormat::Binary).unwrap();
+        assert_eq!(PropagateDataRequest::decode(&message[..]).unwrap(), request);
+        let text = STANDARD.encode(&body);
+        assert_eq!(
+            decode_body(text.as_bytes(), WebFormat::Text).unwrap(),
+            message
+        );
+
+        assert!(decode_body(&body[..3], WebFormat::Binary).is_err());
+        assert!(decode_body(&body[..body.len() - 1], WebFormat::Binary).is_err());
+        body[0] = 1;
+        assert_eq!(
+            decode_body(&body, WebFormat::Binary).unwrap_err().code(),
+            Code::Unimplemented
+        );
+    }
+
+    #[test]
+    fn test_grpc_message_is_percent_encoded() {
+        assert_eq!(percent_encode("not found: 100%"), "not found: 100%25");
+        assert_eq!(percent_encode("naïve\r\n"), "na%C3%AFve%0D%0A");
+    }
+}
@@ -0,0 +1,193 @@
+//approximate per-key read and write frequency, for HOTKEYS. counts live in count-min sketches,
+//so memory stays fixed however many keys there are, and only a short list of the keys that
+//look hottest is kept by name. a sketch can only overestimate, by a small fraction of all
+//accesses. counts are halved every DECAY_EVERY, so the report leans to what is hot lately
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+use xxhash_rust::xxh3::xxh3_64_with_seed;
+
+const WIDTH: usize = 2048;
+const DEPTH: usize = 4;
+//keys kept by name, HOTKEYS can't ask for more
+pub const TRACKED: usize = 100;
+const DECAY_EVERY: Duration = Duration::from_secs(60);
+
+#[derive(Debug)]
+struct Sketch {
+    cells: Vec<AtomicU64>,
+}
+
+impl Sketch {
+    fn new() -> Self {
+        Sketch {
+            cells: (0..WIDTH * DEPTH).map(|_| AtomicU64::new(0)).collect(),
+        }
+    }
+
+    fn cell(row: usize, key: &str) -> usize {
+        row * WIDTH + (xxh3_64_with_seed(key.as_bytes(), row as u64) as usize % WIDTH)
+    }
+
+    //adds one, returns the new estimate
+    fn add(&self, key: &str) -> u64 {
+        (0..DEPTH)
+            .map(|row| self.cells[Self::cell(row, key)].fetch_add(1, Ordering::Relaxed) + 1)
+            .min()
+            .unwrap_or(0)
+    }
+
+    fn estimate(&self, key: &str) -> u64 {
+        (0..DEPTH)
+            .map(|row| self.cells[Self::cell(row, key)].load(Ordering::Relaxed))
+            .min()
+            .unwrap_or(0)
+    }
+
+    fn halve(&self) {
+        for cell in &self.cells {
+            let _ = cell.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| Some(n / 2));
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HotKey {
+    pub key: String,
+    pub reads: u64,
+    pub writes: u64,
+}
+
+#[derive(Debug)]
+struct Tracked {
+    //key -> estimated reads + writes when it was last touched
+    keys: HashMap<String, u64>,
+    last_decay: Instant,
+}
+
+#[derive(Debug)]
+pub struct HotKeys {
+    reads: Sketch,
+    writes: Sketch,
+    tracked: Mutex<Tracked>,
+}
+
+impl Default for HotKeys {
+    fn default() -> Self {
+        HotKeys {
+            reads: Sketch::new(),
+            writes: Sketch::new(),
+            tracked: Mutex::new(Tracked {
+                keys: HashMap::new(),
+                last_decay: Instant::now(),
+            }),
+        }
+    }
+}
+
+impl HotKeys {
+    pub fn record_read(&self, key: &str) {
+        self.decay();
+        let total = self.reads.add(key) + self.writes.estimate(key);
+        self.track(key, total);
+    }
+
+    pub fn record_write(&self, key: &str) {
+        self.decay();
+        let total = self.writes.add(key) + self.reads.estimate(key);
+        self.track(key, total);
+    }
+
+    fn decay(&self) {
+        let mut tracked = self.tracked.lock().unwrap();
+        if tracked.last_decay.elapsed() >= DECAY_EVERY {
+            self.reads.halve();
+            self.writes.halve();
+            tracked.keys.values_mut().for_each(|total| *total /= 2);
+            tracked.last_decay = Instant::now();
+        }
+    }
+
+    //keeps the key by name if it is hotter than the coolest one kept, which it replaces
+    fn track(&self, key: &str, total: u64) {
+        let mut tracked = self.tracked.lock().unwrap();
+        if tracked.keys.len() >= TRACKED && !tracked.keys.contains_key(key) {
+            let coolest = tracked
+                .keys
+                .iter()
+                .min_by_key(|(_, total)| **total)
+                .map(|(key, total)| (key.clone(), *total));
+            match coolest {
+                Some((coolest, coolest_total)) if coolest_total < total => {
+                    tracked.keys.remove(&coolest);
+                }
+                _ => return,
+            }
+        }
+        tracked.keys.insert(key.to_string(), total);
+    }
+
+    //the n hottest keys, by reads and writes together
+    pub fn top(&self, n: usize) -> Vec<HotKey> {
+        let keys: Vec<String> = self.tracked.lock().unwrap().keys.keys().cloned().collect();
+        let mut hot: Vec<HotKey> = keys
+            .into_iter()
+            .map(|key| HotKey {
+                reads: self.reads.estimate(&key),
+                writes: self.writes.estimate(&key),
+                key,
+            })
+            .collect();
+        hot.sort_by(|a, b| {
+            (b.reads + b.writes)
+                .cmp(&(a.reads + a.writes))
+                .then_with(|| a.key.cmp(&b.key))
+        });
+        hot.truncate(n);
+        hot
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hottest_keys_come_first() {
+        let hot = HotKeys::default();
+        for _ in 0..50 {
+            hot.record_read("views:home");
+        }
+        for _ in 0..20 {
+            hot.record_write("cart:42");
+        }
+        hot.record_read("cart:42");
+        //a long tail of keys touched once, more than can be kept by name
+        for i in 0..500 {
+            hot.record_write(&format!("tail:{}", i));
+        }
+
+        let top = hot.top(2);
+        assert_eq!(top[0].key, "views:home");
+        assert!(top[0].reads >= 50);
+        assert_eq!(top[1].key, "cart:42");
+        assert!(top[1].writes >= 20 && top[1].reads >= 1);
+        assert!(hot.top(1000).len() <= TRACKED);
+    }
+
+    #[test]
+    fn test_counts_decay() {
+        let hot = HotKeys::default();
+        for _ in 0..10 {
+            hot.record_write("k");
+        }
+        hot.tracked.lock().unwrap().last_decay = Instant::now() - DECAY_EVERY;
+        hot.record_write("k");
+        assert_eq!(hot.top(1)[0].writes, 6);
+    }
+}
@@ -0,0 +1,235 @@
+//who a node is, kept in the data dir so it outlives the config. the first start with a data_dir
+//writes identity.json: the node_id (from the config, or a generated uuid for node_id = "auto"),
+//the cluster_id it was created with, if any, and a random key for anything that later has
+//to prove the node is the one that wrote it. every later start reads it back and refuses to
+//run under another node_id or move to another cluster: a config copied between hosts would otherwise have two nodes
+//adding to the same counter entries, or one node's data attributed to another. two nodes that
+//do end up sharing an id are caught by the heartbeat handshake instead, see Membership::claim
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{fs, path::Path};
+
+use crate::config::Config;
+
+pub const AUTO_NODE_ID: &str = "auto";
+pub const IDENTITY_FILE: &str = "identity.json";
+//where node_id = "auto" kept the generated id before there was an identity file
+pub const NODE_ID_FILE: &str = "node_id";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Identity {
+    pub node_id: String,
+    //empty for a node created without one, it takes the first one it is given
+    #[serde(default)]
+    pub cluster_id: String,
+    //32 random bytes, hex
+    pub key: String,
+    pub created_at_ms: u64,
+}
+
+impl Identity {
+    fn generate(node_id: String, cluster_id: String) -> Self {
+        let key: [u8; 32] = rand::random();
+        Identity {
+            node_id,
+            cluster_id,
+            key: key.iter().map(|byte| format!("{:02x}", byte)).collect(),
+            created_at_ms: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|d| d.as_millis() as u64)
+                .unwrap_or(0),
+        }
+    }
+}
+
+//the node's identity, None without a data_dir. an "auto" node_id is replaced with the kept one
+pub fn resolve(config: &mut Config) -> Result<Option<Identity>> {
+    let Some(dir) = config.data_dir.clone() else {
+        if config.node_id == AUTO_NODE_ID {
+            bail!("node_id = \"auto\" needs a data_dir to keep the generated id in");
+        }
+        return Ok(None);
+    };
+    let mut identity = match load(&dir)? {
+        Some(identity) => identity,
+        None => {
+            let node_id = match legacy_node_id(&dir)? {
+                Some(node_id) => node_id,
+                None if config.node_id == AUTO_NODE_ID => uuid_v4(),
+                None => config.node_id.clone(),
+            };
+            let identity = Identity::generate(node_id, config.cluster_id().to_string());
+            store(&dir, &identity)?;
+            println!(
+                "Created identity {} in {}",
+                identity.node_id,
+                dir.join(IDENTITY_FILE).display()
+            );
+            identity
+        }
+    };
+    if config.node_id != AUTO_NODE_ID && config.node_id != identity.node_id {
+        bail!(
+            "node_id {} conflicts with {}, which belongs to node {}. point data_dir elsewhere, \
+             set node_id = \"{}\" (or \"auto\"), or remove the file to start over as {}",
+            config.node_id,
+            dir.join(IDENTITY_FILE).display(),
+            identity.node_id,
+            identity.node_id,
+            config.node_id
+        );
+    }
+    match config.cluster_id.as_deref() {
+        Some(cluster_id) if identity.cluster_id.is_empty() => {
+            identity.cluster_id = cluster_id.to_string();
+            store(&dir, &identity)?;
+        }
+        Some(cluster_id) if cluster_id != identity.cluster_id => bail!(
+            "cluster_id {} conflicts with {}, which was created in cluster {}. its data can't \
+             be moved to another cluster, point data_dir elsewhere to join {} as a new node",
+            cluster_id,
+            dir.join(IDENTITY_FILE).display(),
+            identity.cluster_id,
+            cluster_id
+        ),
+        _ => {}
+    }
+    config.node_id = identity.node_id.clone();
+    Ok(Some(identity))
+}
+
+pub fn load(dir: &Path) -> Result<Option<Identity>> {
+    let path = dir.join(IDENTITY_FILE);
+    let raw = match fs::read(&path) {
+        Ok(raw) => raw,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("failed to read {}", path.display())),
+    };
+    let identity = serde_json::from_slice(&raw)
+        .with_context(|| format!("{} is damaged, restore it from a backup", path.display()))?;
+    Ok(Some(identity))
+}
+
+//readable by the node's user only, it holds the key
+fn store(dir: &Path, identity: &Identity) -> Result<()> {
+    fs::create_dir_all(dir).with_context(|| format!("failed to create {}", dir.display()))?;
+    let path = dir.join(IDENTITY_FILE);
+    let tmp = dir.join(format!("{}.tmp", IDENTITY_FILE));
+    fs::write(&tmp, serde_json::to_vec_pretty(identity)?)
+        .with_context(|| format!("failed to write {}", tmp.display()))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&tmp, fs::Permissions::from_mode(0o600))?;
+    }
+    fs::rename(&tmp, &path).with_context(|| format!("failed to write {}", path.display()))?;
+    Ok(())
+}
+
+fn legacy_node_id(dir: &Path) -> Result<Option<String>> {
+    let path = dir.join(NODE_ID_FILE);
+    match fs::read_to_string(&path) {
+        Ok(node_id) if !node_id.trim().is_empty() => Ok(Some(node_id.trim().to_string())),
+        Ok(_) => Ok(None),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("failed to read {}", path.display())),
+    }
+}
+
+//a random (version 4) uuid
+pub fn uuid_v4() -> String {
+    let mut bytes: [u8; 16] = rand::random();
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(node_id: &str, data_dir: Option<&Path>) -> Config {
+        let mut config: Config = toml::from_str(&format!(
+            "node_id = \"{}\"\nlisten_address = \"127.0.0.1:8000\"\npeers = []",
+            node_id
+        ))
+        .unwrap();
+        config.data_dir = data_dir.map(Path::to_path_buf);
+        config
+    }
+
+    #[test]
+    fn test_auto_node_id_is_kept() {
+        let dir = std::env::temp_dir().join(format!("mergedb-identity-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let mut first = config("auto", Some(&dir));
+        let identity = resolve(&mut first).unwrap().unwrap();
+        assert_eq!(first.node_id, identity.node_id);
+        assert_eq!(first.node_id.len(), 36);
+        assert_eq!(&first.node_id[14..15], "4");
+        assert_eq!(identity.key.len(), 64);
+
+        let mut again = config("auto", Some(&dir));
+        assert_eq!(resolve(&mut again).unwrap(), Some(identity));
+        assert_eq!(again.node_id, first.node_id);
+
+        let mut other = config("auto", Some(&dir.join("other")));
+        resolve(&mut other).unwrap();
+        assert_ne!(other.node_id, first.node_id);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(resolve(&mut config("auto", None)).is_err());
+        assert_eq!(resolve(&mut config("node_1", None)).unwrap(), None);
+    }
+
+    #[test]
+    fn test_node_id_conflicts_are_refused() {
+        let dir = std::env::temp_dir().join(format!("mergedb-conflict-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        resolve(&mut config("node_1", Some(&dir))).unwrap();
+        assert!(resolve(&mut config("node_1", Some(&dir))).is_ok());
+        let err = resolve(&mut config("node_2", Some(&dir))).unwrap_err();
+        assert!(err.to_string().contains("belongs to node node_1"));
+        fs::remove_dir_all(&dir).unwrap();
+
+        //an id generated before identity files is carried over
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join(NODE_ID_FILE), "legacy-id\n").unwrap();
+        let mut legacy = config("auto", Some(&dir));
+        resolve(&mut legacy).unwrap();
+        assert_eq!(legacy.node_id, "legacy-id");
+        assert_eq!(load(&dir).unwrap().unwrap().node_id, "legacy-id");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_cluster_id_is_kept() {
+        let dir = std::env::temp_dir().join(format!("mergedb-cluster-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let in_cluster = |cluster_id: Option<&str>| {
+            let mut config = config("node_1", Some(&dir));
+            config.cluster_id = cluster_id.map(str::to_string);
+            resolve(&mut config)
+        };
+        //created without one, the first one given sticks
+        assert_eq!(in_cluster(None).unwrap().unwrap().cluster_id, "");
+        assert_eq!(
+            in_cluster(Some("prod")).unwrap().unwrap().cluster_id,
+            "prod"
+        );
+        assert_eq!(load(&dir).unwrap().unwrap().cluster_id, "prod");
+        assert!(in_cluster(Some("prod")).is_ok());
+        assert!(in_cluster(None).is_ok());
+        let err = in_cluster(Some("staging")).unwrap_err();
+        assert!(err.to_string().contains("created in cluster prod"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
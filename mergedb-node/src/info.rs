@@ -0,0 +1,173 @@
+use mergedb_types::{custom, CrdtValue};
+use serde_json::{json, Value};
+use std::{collections::BTreeMap, sync::atomic::Ordering};
+
+use crate::{metrics::Amplification, network::ReplicationServer};
+
+pub const SECTIONS: [&str; 6] = [
+    "server",
+    "memory",
+    "replication",
+    "gossip",
+    "keyspace",
+    "persistence",
+];
+
+pub type InfoSections = BTreeMap<String, BTreeMap<String, Value>>;
+
+//the INFO command, every section or just the one asked for. None if the section is unknown
+pub fn collect(server: &ReplicationServer, section: Option<&str>) -> Option<InfoSections> {
+    let wanted: Vec<&str> = match section {
+        Some(name) => {
+            let name = SECTIONS.iter().find(|s| s.eq_ignore_ascii_case(name))?;
+            vec![name]
+        }
+        None => SECTIONS.to_vec(),
+    };
+
+    let mut sections = InfoSections::new();
+    for name in wanted {
+        let fields = match name {
+            "server" => server_section(server),
+            "memory" => memory_section(server),
+            "replication" => replication_section(server),
+            "gossip" => gossip_section(server),
+            "keyspace" => keyspace_section(server),
+            _ => persistence_section(server),
+        };
+        sections.insert(name.to_string(), fields);
+    }
+    Some(sections)
+}
+
+fn to_fields(value: Value) -> BTreeMap<String, Value> {
+    match value {
+        Value::Object(map) => map.into_iter().collect(),
+        _ => BTreeMap::new(),
+    }
+}
+
+fn server_section(server: &ReplicationServer) -> BTreeMap<String, Value> {
+    to_fields(json!({
+        "node_id": server.config.node_id,
+        "listen_address": server.config.listen_address,
+        "client_listen_address": server.config.client_address(),
+        "peer_listen_address": server.config.peer_address(),
+        "witness": server.config.witness,
+        "version": env!("CARGO_PKG_VERSION"),
+        "uptime_secs": server.metrics.started_at.elapsed().as_secs(),
+        "commands_total": server.metrics.counter("commands_total"),
+        "scripts_total": server.metrics.counter("scripts_total"),
+        "script_errors_total": server.metrics.counter("script_errors_total"),
+        "commit_seq": server.commit_seq.load(Ordering::SeqCst),
+        "paused": server.pause_refusal().unwrap_or_default(),
+    }))
+}
+
+fn memory_section(server: &ReplicationServer) -> BTreeMap<String, Value> {
+    let store_bytes: usize = server
+        .store
+        .iter()
+        .map(|entry| entry.key().len() + entry.value().data.estimated_size())
+        .sum();
+    to_fields(json!({
+        "store_estimated_bytes": store_bytes,
+        "keys": server.store.len(),
+    }))
+}
+
+fn replication_section(server: &ReplicationServer) -> BTreeMap<String, Value> {
+    let metrics = &server.metrics;
+    let split_brain = server.split_brain.status();
+    to_fields(json!({
+        "peers_configured": server.config.peers.len(),
+        "members_known": server.membership.members.len(),
+        "members_reachable": server.membership.reachable_ids().len(),
+        "split_brain": split_brain.split_brain,
+        "fanout": server.fanout(),
+        "gossip_interval_ms": server.settings().gossip_interval.as_millis() as u64,
+        "gossip_pushes_total": metrics.counter("gossip_pushes_total"),
+        "gossip_push_failures_total": metrics.counter("gossip_push_failures_total"),
+        "gossip_merges_total": metrics.counter("gossip_merges_total"),
+        "gossip_redundant_total": metrics.counter("gossip_redundant_total"),
+        "gossip_expired_total": metrics.counter("gossip_expired_total"),
+        "gossip_fenced_total": metrics.counter("gossip_fenced_total"),
+        "watchdog_stalled_keys": metrics.gauge("watchdog_stalled_keys"),
+    }))
+}
+
+//write amplification: how many times key state went out to peers against how many times it
+//changed, overall and per key prefix (the part before the first ':')
+fn gossip_section(server: &ReplicationServer) -> BTreeMap<String, Value> {
+    let mut total = Amplification::default();
+    let mut fields = BTreeMap::new();
+    for (prefix, amplification) in server.metrics.amplification() {
+        total.mutations += amplification.mutations;
+        total.transmissions += amplification.transmissions;
+        let prefix = if prefix.is_empty() { "(none)" } else { &prefix };
+        fields.insert(
+            format!("{}.mutations", prefix),
+            json!(amplification.mutations),
+        );
+        fields.insert(
+            format!("{}.transmissions", prefix),
+            json!(amplification.transmissions),
+        );
+        fields.insert(
+            format!("{}.amplification", prefix),
+            json!(amplification.ratio().map(rounded)),
+        );
+    }
+    fields.insert("mutations_total".to_string(), json!(total.mutations));
+    fields.insert(
+        "transmissions_total".to_string(),
+        json!(total.transmissions),
+    );
+    fields.insert(
+        "amplification".to_string(),
+        json!(total.ratio().map(rounded)),
+    );
+    fields
+}
+
+fn rounded(ratio: f64) -> f64 {
+    (ratio * 100.0).round() / 100.0
+}
+
+fn keyspace_section(server: &ReplicationServer) -> BTreeMap<String, Value> {
+    let (mut counters, mut sets, mut registers, mut maps, mut customs) = (0, 0, 0, 0, 0);
+    for entry in server.store.iter() {
+        match entry.value().data {
+            CrdtValue::Counter(_) => counters += 1,
+            CrdtValue::Set(_) => sets += 1,
+            CrdtValue::Register(_) => registers += 1,
+            CrdtValue::Map(_) => maps += 1,
+            CrdtValue::Custom(_) => customs += 1,
+        }
+    }
+    to_fields(json!({
+        "keys": server.store.len(),
+        "counters": counters,
+        "sets": sets,
+        "registers": registers,
+        "maps": maps,
+        "custom": customs,
+        "custom_types": custom::registered_tags().join(","),
+        "counter_anomalies_total": server.metrics.counter("counter_anomalies_total"),
+        "hottest_key": server.metrics.hot_keys.top(1).first().map(|hot| hot.key.clone()),
+    }))
+}
+
+fn persistence_section(server: &ReplicationServer) -> BTreeMap<String, Value> {
+    let persistence = &server.persistence;
+    to_fields(json!({
+        "enabled": persistence.enabled(),
+        "data_dir": persistence.data_dir.as_ref().map(|dir| dir.display().to_string()),
+        "snapshot_keys": persistence.snapshot_keys,
+        "loaded_keys": persistence.loaded.load(Ordering::SeqCst),
+        "warm": persistence.is_warm(),
+        "damaged": persistence.damaged.load(Ordering::SeqCst),
+        "preload_prefixes": server.config.preload.len(),
+        "last_snapshot_keys": server.metrics.gauge("snapshot_keys"),
+    }))
+}
@@ -0,0 +1,37 @@
+//hash tags: when a key has a non-empty `{...}` in it, only what is inside the first pair of
+//braces picks where the key is placed, so `{user:123}:likes` and `{user:123}:tags` always land
+//together. every node holds every key for now, nothing places keys by group yet. this is the
+//rule partitioned placement is meant to hash, so MULTI batches and scripts over related keys
+//can keep running on one node once it does
+pub fn group_of(key: &str) -> &str {
+    let Some(open) = key.find('{') else {
+        return key;
+    };
+    match key[open + 1..].find('}') {
+        Some(len) if len > 0 => &key[open + 1..open + 1 + len],
+        _ => key,
+    }
+}
+
+pub fn same_group(a: &str, b: &str) -> bool {
+    group_of(a) == group_of(b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hash_tags_pick_the_group() {
+        assert_eq!(group_of("{user:123}:likes"), "user:123");
+        assert!(same_group("{user:123}:likes", "{user:123}:tags"));
+        assert!(!same_group("{user:123}:likes", "{user:124}:likes"));
+
+        //no tag, an empty one or an unclosed one mean the whole key
+        assert_eq!(group_of("views"), "views");
+        assert_eq!(group_of("{}:views"), "{}:views");
+        assert_eq!(group_of("{user:views"), "{user:views");
+        //only the first pair counts
+        assert_eq!(group_of("a{b}{c}"), "b");
+    }
+}
@@ -0,0 +1,272 @@
+//labels are k=v pairs attached to a key with LABEL, for picking keys out by what they are
+//(SCAN with a selector, label_rules in the config) rather than by how they are named. each
+//label is an lww register under PREFIX + key + "=" + name, so together they make a small lww
+//map per key that gossips like any other key, and concurrent changes to different labels
+//don't clobber each other. an empty register is a removed label
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, fmt, str::FromStr, time::Duration};
+
+use crate::units;
+
+pub const PREFIX: &str = "__label:";
+//longer names are refused, a label is meant to be a short tag
+const MAX_NAME_LEN: usize = 63;
+
+//eg, to drop staging keys nobody wrote for a week and to hear about writes to search's keys:
+//[[label_rules]]
+//selector = "env=staging"
+//retention = "7d"
+//
+//[[label_rules]]
+//selector = "team=search"
+//webhook = "http://hooks.internal/search"
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LabelRule {
+    pub selector: Selector,
+    //a matching key that hasn't changed for this long is dropped, along with its labels, on
+    //every node, see expiry. a write after that brings the key back
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "units::optional_secs"
+    )]
+    pub retention: Option<Duration>,
+    //http endpoint that gets a json POST per client write to a matching key, from the node
+    //the write was sent to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub webhook: Option<String>,
+}
+
+pub fn label_key(key: &str, name: &str) -> String {
+    format!("{}{}={}", PREFIX, key, name)
+}
+
+//labels are only ever written through LABEL
+pub fn is_label(key: &str) -> bool {
+    key.starts_with(PREFIX)
+}
+
+//the key and the label name a label register is stored under. names can't hold a '=', so the
+//last one is where the key ends
+pub fn parse_label_key(label_key: &str) -> Option<(&str, &str)> {
+    label_key.strip_prefix(PREFIX)?.rsplit_once('=')
+}
+
+pub fn validate_name(name: &str) -> Result<(), String> {
+    if name.is_empty() || name.len() > MAX_NAME_LEN {
+        return Err(format!(
+            "label name {:?} must be 1 to {} characters",
+            name, MAX_NAME_LEN
+        ));
+    }
+    match name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '/'))
+    {
+        true => Ok(()),
+        false => Err(format!(
+            "label name {:?} may only hold letters, digits, '-', '_', '.' and '/'",
+            name
+        )),
+    }
+}
+
+//LABEL's "env=prod,team=search", an empty value ("team=") removes the label
+pub fn parse_assignments(raw: &str) -> Result<Vec<(String, String)>, String> {
+    let assignments: Vec<(String, String)> = raw
+        .split(',')
+        .map(str::trim)
+        .filter(|part| !part.is_empty())
+        .map(|part| {
+            let (name, value) = part
+                .split_once('=')
+                .ok_or_else(|| format!("{:?} is not name=value", part))?;
+            let name = name.trim();
+            validate_name(name)?;
+            Ok((name.to_string(), value.trim().to_string()))
+        })
+        .collect::<Result<_, String>>()?;
+    if assignments.is_empty() {
+        return Err("no labels given, expected name=value[,name=value...]".to_string());
+    }
+    Ok(assignments)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Term {
+    Equals(String, String),
+    NotEquals(String, String),
+    Exists(String),
+    Missing(String),
+}
+
+impl Term {
+    fn name(&self) -> &str {
+        match self {
+            Term::Equals(name, _)
+            | Term::NotEquals(name, _)
+            | Term::Exists(name)
+            | Term::Missing(name) => name,
+        }
+    }
+}
+
+//comma separated terms that all have to hold: name=value, name!=value (which a key without
+//the label passes), name (has the label) and !name (doesn't). an empty selector matches
+//every key
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Selector {
+    terms: Vec<Term>,
+}
+
+impl Selector {
+    //the label names the terms look at, all a key's labels don't have to be read to match it
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.terms.iter().map(Term::name)
+    }
+
+    pub fn matches(&self, labels: &BTreeMap<String, String>) -> bool {
+        self.terms.iter().all(|term| match term {
+            Term::Equals(name, value) => labels.get(name) == Some(value),
+            Term::NotEquals(name, value) => labels.get(name) != Some(value),
+            Term::Exists(name) => labels.contains_key(name),
+            Term::Missing(name) => !labels.contains_key(name),
+        })
+    }
+}
+
+impl FromStr for Selector {
+    type Err = String;
+
+    fn from_str(raw: &str) -> Result<Self, Self::Err> {
+        let terms = raw
+            .split(',')
+            .map(str::trim)
+            .filter(|part| !part.is_empty())
+            .map(|part| {
+                let term = if let Some((name, value)) = part.split_once("!=") {
+                    Term::NotEquals(name.trim().to_string(), value.trim().to_string())
+                } else if let Some((name, value)) = part.split_once('=') {
+                    Term::Equals(name.trim().to_string(), value.trim().to_string())
+                } else if let Some(name) = part.strip_prefix('!') {
+                    Term::Missing(name.trim().to_string())
+                } else {
+                    Term::Exists(part.to_string())
+                };
+                validate_name(term.name())?;
+                Ok(term)
+            })
+            .collect::<Result<_, String>>()?;
+        Ok(Selector { terms })
+    }
+}
+
+impl TryFrom<String> for Selector {
+    type Error = String;
+
+    fn try_from(raw: String) -> Result<Self, Self::Error> {
+        raw.parse()
+    }
+}
+
+impl From<Selector> for String {
+    fn from(selector: Selector) -> Self {
+        selector.to_string()
+    }
+}
+
+impl fmt::Display for Selector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let terms: Vec<String> = self
+            .terms
+            .iter()
+            .map(|term| match term {
+                Term::Equals(name, value) => format!("{}={}", name, value),
+                Term::NotEquals(name, value) => format!("{}!={}", name, value),
+                Term::Exists(name) => name.clone(),
+                Term::Missing(name) => format!("!{}", name),
+            })
+            .collect();
+        f.write_str(&terms.join(","))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{send, server};
+    use mergedb_proto::wire;
+
+    fn labels(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_label_keys() {
+        let key = label_key("users:a=b", "team");
+        assert_eq!(key, "__label:users:a=b=team");
+        assert!(is_label(&key));
+        assert_eq!(parse_label_key(&key), Some(("users:a=b", "team")));
+        assert_eq!(parse_label_key("views"), None);
+    }
+
+    #[test]
+    fn test_assignments() {
+        assert_eq!(
+            parse_assignments("env=prod, team=").unwrap(),
+            [
+                ("env".to_string(), "prod".to_string()),
+                ("team".to_string(), String::new())
+            ]
+        );
+        assert!(parse_assignments("env").is_err());
+        assert!(parse_assignments("bad name=1").is_err());
+        assert!(parse_assignments(",").is_err());
+    }
+
+    #[test]
+    fn test_selectors() {
+        let selector: Selector = "env=prod, team!=search, owner, !legacy".parse().unwrap();
+        assert_eq!(selector.to_string(), "env=prod,team!=search,owner,!legacy");
+        assert_eq!(
+            selector.names().collect::<Vec<_>>(),
+            ["env", "team", "owner", "legacy"]
+        );
+        assert!(selector.matches(&labels(&[("env", "prod"), ("owner", "ada")])));
+        assert!(!selector.matches(&labels(&[
+            ("env", "prod"),
+            ("owner", "ada"),
+            ("legacy", "yes")
+        ])));
+        assert!(!selector.matches(&labels(&[
+            ("env", "prod"),
+            ("team", "search"),
+            ("owner", "ada")
+        ])));
+        assert!(!selector.matches(&labels(&[("env", "dev"), ("owner", "ada")])));
+        assert!(Selector::default().matches(&BTreeMap::new()));
+        assert!("a b=1".parse::<Selector>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_labels_arent_listed_as_keys() {
+        let server = server("n1");
+        send(&server, "RSET", "k", "v").await;
+        send(&server, "LABEL", "k", "env=prod,team=search").await;
+        assert_eq!(server.store.len(), 3);
+
+        let keys = server
+            .handle_keys(String::new())
+            .await
+            .unwrap()
+            .into_inner();
+        let keys: Vec<String> = serde_json::from_slice(&keys.response).unwrap();
+        assert_eq!(keys, ["k"]);
+        let size = server.handle_dbsize().await.unwrap().into_inner();
+        assert_eq!(wire::decode_u64(&size.response).unwrap(), 1);
+    }
+}
@@ -1,6 +1,54 @@
+pub mod anomaly;
+pub mod audit;
+pub mod breaker;
 pub mod config;
+pub mod contention;
+pub mod decommission;
+pub mod digest;
+pub mod discovery;
+pub mod events;
+pub mod expiry;
+pub mod export;
+pub mod fingerprint;
+pub mod freeze;
+pub mod fsck;
+pub mod graphql;
+pub mod grpc_web;
+pub mod identity;
+pub mod hotkeys;
+pub mod info;
+pub mod keygroup;
+pub mod labels;
+pub mod listener;
+pub mod materialize;
+pub mod membership;
+pub mod metrics;
 pub mod network;
+pub mod node;
+pub mod outbound;
+pub mod pause;
+pub mod peer;
+pub mod persistence;
+pub mod plumtree;
+pub mod priority;
+pub mod prometheus;
+pub mod recovery;
+pub mod rename;
+pub mod script;
+pub mod scuttlebutt;
+pub mod set_limit;
+pub mod settings;
+pub mod shards;
+pub mod setup;
+pub mod split_brain;
+#[cfg(test)]
+pub mod testing;
+pub mod tuning;
+pub mod units;
+pub mod wal;
+pub mod watchdog;
+pub mod webhook;
+pub mod websocket;
+pub mod work_queue;
 
-pub mod communication {
-    tonic::include_proto!("communication");
-}
\ No newline at end of file
+pub use mergedb_proto::communication;
\ No newline at end of file
@@ -0,0 +1,434 @@
+use mergedb_proto::metadata::SERVER_TIME_HEADER;
+use std::time::Instant;
+use tonic::{Request, Response, Status};
+
+use crate::{
+    communication::{
+        replication_service_server::ReplicationService, AnnounceRequest, AnnounceResponse,
+        BroadcastRequest, BroadcastResponse, ClusterStatusRequest, ClusterStatusResponse,
+        DecommissionRequest, ExportRequest, FingerprintRequest, FingerprintResponse, GossipBatchRequest,
+        GossipBatchResponse, GossipChangesRequest, GossipChangesResponse, GossipDigestRequest,
+        GossipDigestResponse, GraftRequest, GraftResponse,
+        HeartbeatRequest, HeartbeatResponse, LeaveRequest, LeaveResponse, NodeStatusRequest,
+        NodeStatusResponse, PauseRequest, PauseResponse, PingRequest, PingResponse,
+        PropagateBatchRequest,
+        PropagateBatchResponse, PropagateDataRequest, PropagateDataResponse, ReconcileRequest,
+        ReconcileResponse, ResumeRequest, SampleRequest, SampleResponse,
+    },
+    network::ReplicationServer,
+    recovery::Stage,
+    work_queue::{self, Refusal, Slot},
+};
+
+//which rpcs a listening socket answers. with client_listen_address and peer_listen_address
+//on different addresses, each only serves its own half, otherwise one socket serves both
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Client,
+    Peer,
+    All,
+}
+
+impl Role {
+    fn allows(self, needed: Role) -> bool {
+        self == Role::All || self == needed
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Listener {
+    pub server: ReplicationServer,
+    pub role: Role,
+}
+
+impl Listener {
+    pub fn new(server: ReplicationServer, role: Role) -> Self {
+        Listener { server, role }
+    }
+
+    //the error to answer with when this listener doesn't serve `rpc`
+    fn refuse(&self, needed: Role, rpc: &str) -> Option<Status> {
+        if self.role.allows(needed) {
+            return None;
+        }
+        let address = match needed {
+            Role::Peer => self.server.config.peer_address(),
+            _ => self.server.config.client_address(),
+        };
+        Some(Status::permission_denied(format!(
+            "{} is only served on {}",
+            rpc, address
+        )))
+    }
+
+    //the error to answer with while the wal is still being replayed, anything reading or
+    //writing the store would see it half way
+    fn recovering(&self) -> Option<Status> {
+        if self.server.recovery.stage() != Stage::Wal {
+            return None;
+        }
+        Some(Status::unavailable(format!(
+            "{} is still recovering, {}",
+            self.server.config.node_id,
+            self.server.recovery.progress().summary()
+        )))
+    }
+
+    //waits for the request's turn in the work queue, or the error to answer with when its
+    //deadline passed first or the queue is full
+    async fn admit<T>(&self, request: &Request<T>) -> Result<Slot, Status> {
+        let (priority, deadline) =
+            work_queue::scheduling(request).map_err(Status::invalid_argument)?;
+        let node_id = &self.server.config.node_id;
+        match self.server.work_queue.acquire(priority, deadline).await {
+            Ok(slot) => Ok(slot),
+            Err(Refusal::Expired) => {
+                self.server.metrics.incr("requests_expired_total", 1);
+                Err(Status::deadline_exceeded(format!(
+                    "the deadline passed before {} got to the request",
+                    node_id
+                )))
+            }
+            Err(Refusal::Full) => {
+                self.server.metrics.incr("requests_shed_total", 1);
+                Err(Status::resource_exhausted(format!(
+                    "{} has too many requests waiting",
+                    node_id
+                )))
+            }
+        }
+    }
+
+    //the error to answer with when the sending node is fenced off by gossip_allow/gossip_deny
+    fn fence<T>(
+        &self,
+        request: &Request<T>,
+        node_id: &str,
+        cluster_id: &str,
+        rpc: &str,
+    ) -> Option<Status> {
+        if let Some(reason) = self.server.config.cluster_refusal(node_id, cluster_id) {
+            eprintln!("refused {}: {}", rpc, reason);
+            self.server.metrics.incr("cross_cluster_refused_total", 1);
+            return Some(Status::permission_denied(reason));
+        }
+        let remote = request.remote_addr().map(|addr| addr.ip());
+        let reason = self.server.config.gossip_refusal(node_id, remote)?;
+        eprintln!("refused {}: {}", rpc, reason);
+        self.server.metrics.incr("gossip_fenced_total", 1);
+        Some(Status::permission_denied(reason))
+    }
+}
+
+//stamps how long the node took on a client's request into the answer, refusals included, so
+//that the client can tell the node's time from the network's
+fn stamp_time<T>(started: Instant, answer: &mut Result<Response<T>, Status>) {
+    let metadata = match answer {
+        Ok(response) => response.metadata_mut(),
+        Err(status) => status.metadata_mut(),
+    };
+    let us = started.elapsed().as_micros().to_string();
+    if let Ok(value) = us.parse() {
+        metadata.insert(SERVER_TIME_HEADER, value);
+    }
+}
+
+#[tonic::async_trait]
+impl ReplicationService for Listener {
+    async fn propagate_data(
+        &self,
+        request: Request<PropagateDataRequest>,
+    ) -> Result<Response<PropagateDataResponse>, Status> {
+        if let Some(refused) = self.refuse(Role::Client, "PropagateData") {
+            return Err(refused);
+        }
+        if let Some(recovering) = self.recovering() {
+            return Err(recovering);
+        }
+        let started = Instant::now();
+        let mut answer = match self.admit(&request).await {
+            Ok(_slot) => self.server.propagate_data(request).await,
+            Err(refused) => Err(refused),
+        };
+        stamp_time(started, &mut answer);
+        answer
+    }
+
+    async fn propagate_batch(
+        &self,
+        request: Request<PropagateBatchRequest>,
+    ) -> Result<Response<PropagateBatchResponse>, Status> {
+        if let Some(refused) = self.refuse(Role::Client, "PropagateBatch") {
+            return Err(refused);
+        }
+        if let Some(recovering) = self.recovering() {
+            return Err(recovering);
+        }
+        let started = Instant::now();
+        let mut answer = match self.admit(&request).await {
+            Ok(_slot) => self.server.propagate_batch(request).await,
+            Err(refused) => Err(refused),
+        };
+        stamp_time(started, &mut answer);
+        answer
+    }
+
+    async fn gossip_changes(
+        &self,
+        request: Request<GossipChangesRequest>,
+    ) -> Result<Response<GossipChangesResponse>, Status> {
+        if let Some(refused) = self.refuse(Role::Peer, "GossipChanges") {
+            return Err(refused);
+        }
+        if let Some(recovering) = self.recovering() {
+            return Err(recovering);
+        }
+        let sender = request.get_ref();
+        if let Some(fenced) = self.fence(
+            &request,
+            &sender.node_id,
+            &sender.cluster_id,
+            "GossipChanges",
+        ) {
+            return Err(fenced);
+        }
+        self.server.gossip_changes(request).await
+    }
+
+    async fn gossip_batch(
+        &self,
+        request: Request<GossipBatchRequest>,
+    ) -> Result<Response<GossipBatchResponse>, Status> {
+        if let Some(refused) = self.refuse(Role::Peer, "GossipBatch") {
+            return Err(refused);
+        }
+        if let Some(recovering) = self.recovering() {
+            return Err(recovering);
+        }
+        let sender = request.get_ref();
+        if let Some(fenced) =
+            self.fence(&request, &sender.node_id, &sender.cluster_id, "GossipBatch")
+        {
+            return Err(fenced);
+        }
+        self.server.gossip_batch(request).await
+    }
+
+    async fn gossip_digest(
+        &self,
+        request: Request<GossipDigestRequest>,
+    ) -> Result<Response<GossipDigestResponse>, Status> {
+        if let Some(refused) = self.refuse(Role::Peer, "GossipDigest") {
+            return Err(refused);
+        }
+        if let Some(recovering) = self.recovering() {
+            return Err(recovering);
+        }
+        let sender = request.get_ref();
+        if let Some(fenced) = self.fence(
+            &request,
+            &sender.node_id,
+            &sender.cluster_id,
+            "GossipDigest",
+        ) {
+            return Err(fenced);
+        }
+        self.server.gossip_digest(request).await
+    }
+
+    async fn reconcile(
+        &self,
+        request: Request<ReconcileRequest>,
+    ) -> Result<Response<ReconcileResponse>, Status> {
+        if let Some(refused) = self.refuse(Role::Peer, "Reconcile") {
+            return Err(refused);
+        }
+        if let Some(recovering) = self.recovering() {
+            return Err(recovering);
+        }
+        let sender = request.get_ref();
+        if let Some(fenced) = self.fence(&request, &sender.node_id, &sender.cluster_id, "Reconcile")
+        {
+            return Err(fenced);
+        }
+        self.server.reconcile(request).await
+    }
+
+    async fn broadcast(
+        &self,
+        request: Request<BroadcastRequest>,
+    ) -> Result<Response<BroadcastResponse>, Status> {
+        if let Some(refused) = self.refuse(Role::Peer, "Broadcast") {
+            return Err(refused);
+        }
+        if let Some(recovering) = self.recovering() {
+            return Err(recovering);
+        }
+        let sender = request.get_ref();
+        if let Some(fenced) = self.fence(&request, &sender.node_id, &sender.cluster_id, "Broadcast")
+        {
+            return Err(fenced);
+        }
+        self.server.broadcast(request).await
+    }
+
+    async fn announce(
+        &self,
+        request: Request<AnnounceRequest>,
+    ) -> Result<Response<AnnounceResponse>, Status> {
+        if let Some(refused) = self.refuse(Role::Peer, "Announce") {
+            return Err(refused);
+        }
+        let sender = request.get_ref();
+        if let Some(fenced) = self.fence(&request, &sender.node_id, &sender.cluster_id, "Announce")
+        {
+            return Err(fenced);
+        }
+        self.server.announce(request).await
+    }
+
+    async fn graft(&self, request: Request<GraftRequest>) -> Result<Response<GraftResponse>, Status> {
+        if let Some(refused) = self.refuse(Role::Peer, "Graft") {
+            return Err(refused);
+        }
+        let sender = request.get_ref();
+        if let Some(fenced) = self.fence(&request, &sender.node_id, &sender.cluster_id, "Graft") {
+            return Err(fenced);
+        }
+        self.server.graft(request).await
+    }
+
+    type ExportStream = <ReplicationServer as ReplicationService>::ExportStream;
+
+    async fn export(
+        &self,
+        request: Request<ExportRequest>,
+    ) -> Result<Response<Self::ExportStream>, Status> {
+        if let Some(refused) = self.refuse(Role::Client, "Export") {
+            return Err(refused);
+        }
+        if let Some(recovering) = self.recovering() {
+            return Err(recovering);
+        }
+        self.server.export(request).await
+    }
+
+    async fn sample(
+        &self,
+        request: Request<SampleRequest>,
+    ) -> Result<Response<SampleResponse>, Status> {
+        if let Some(refused) = self.refuse(Role::Peer, "Sample") {
+            return Err(refused);
+        }
+        if let Some(recovering) = self.recovering() {
+            return Err(recovering);
+        }
+        let sender = request.get_ref();
+        if let Some(fenced) = self.fence(&request, &sender.node_id, &sender.cluster_id, "Sample") {
+            return Err(fenced);
+        }
+        self.server.sample(request).await
+    }
+
+    async fn heartbeat(
+        &self,
+        request: Request<HeartbeatRequest>,
+    ) -> Result<Response<HeartbeatResponse>, Status> {
+        if let Some(refused) = self.refuse(Role::Peer, "Heartbeat") {
+            return Err(refused);
+        }
+        let sender = request.get_ref();
+        if let Some(fenced) = self.fence(&request, &sender.node_id, &sender.cluster_id, "Heartbeat")
+        {
+            return Err(fenced);
+        }
+        self.server.heartbeat(request).await
+    }
+
+    async fn cluster_status(
+        &self,
+        request: Request<ClusterStatusRequest>,
+    ) -> Result<Response<ClusterStatusResponse>, Status> {
+        if let Some(refused) = self.refuse(Role::Client, "ClusterStatus") {
+            return Err(refused);
+        }
+        self.server.cluster_status(request).await
+    }
+
+    //served to both, a decommissioned node compares its peers' fingerprints with its own
+    async fn fingerprint(
+        &self,
+        request: Request<FingerprintRequest>,
+    ) -> Result<Response<FingerprintResponse>, Status> {
+        self.server.fingerprint(request).await
+    }
+
+    type DecommissionStream = <ReplicationServer as ReplicationService>::DecommissionStream;
+
+    async fn decommission(
+        &self,
+        request: Request<DecommissionRequest>,
+    ) -> Result<Response<Self::DecommissionStream>, Status> {
+        if let Some(refused) = self.refuse(Role::Client, "Decommission") {
+            return Err(refused);
+        }
+        if let Some(recovering) = self.recovering() {
+            return Err(recovering);
+        }
+        self.server.decommission(request).await
+    }
+
+    async fn leave(
+        &self,
+        request: Request<LeaveRequest>,
+    ) -> Result<Response<LeaveResponse>, Status> {
+        if let Some(refused) = self.refuse(Role::Peer, "Leave") {
+            return Err(refused);
+        }
+        let sender = request.get_ref();
+        if let Some(fenced) = self.fence(&request, &sender.node_id, &sender.cluster_id, "Leave") {
+            return Err(fenced);
+        }
+        self.server.leave(request).await
+    }
+
+    async fn pause(
+        &self,
+        request: Request<PauseRequest>,
+    ) -> Result<Response<PauseResponse>, Status> {
+        if let Some(refused) = self.refuse(Role::Client, "Pause") {
+            return Err(refused);
+        }
+        if let Some(recovering) = self.recovering() {
+            return Err(recovering);
+        }
+        self.server.pause(request).await
+    }
+
+    async fn resume(
+        &self,
+        request: Request<ResumeRequest>,
+    ) -> Result<Response<PauseResponse>, Status> {
+        if let Some(refused) = self.refuse(Role::Client, "Resume") {
+            return Err(refused);
+        }
+        if let Some(recovering) = self.recovering() {
+            return Err(recovering);
+        }
+        self.server.resume(request).await
+    }
+
+    //served to both and while recovering, clients measure their latency to the node and the
+    //node its own to each peer
+    async fn ping(&self, request: Request<PingRequest>) -> Result<Response<PingResponse>, Status> {
+        self.server.ping(request).await
+    }
+
+    //served to both and while recovering, it is how recovery is followed
+    async fn node_status(
+        &self,
+        request: Request<NodeStatusRequest>,
+    ) -> Result<Response<NodeStatusResponse>, Status> {
+        self.server.node_status(request).await
+    }
+}
@@ -1,40 +1,70 @@
 use anyhow::Result;
-use dashmap::DashMap;
-use mergedb_node::{config::Config, network::ReplicationServer};
-use std::{path::PathBuf, sync::Arc, time::SystemTime};
+use mergedb_node::{config::Config, fsck, node::Node, setup::Setup};
+use std::{io, path::PathBuf};
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    let config = Config::load_config(PathBuf::from("config.toml"))?;
-
-    let store = Arc::new(DashMap::new());
-    let peers = Arc::new(DashMap::new());
-
-    for peer_addr in &config.peers {
-        peers.insert(peer_addr.clone(), SystemTime::UNIX_EPOCH);
+fn main() -> Result<()> {
+    if std::env::args().nth(1).as_deref() == Some("fsck") {
+        std::process::exit(run_fsck());
     }
 
-    println!(
-        "Node '{}' starting on {}",
-        config.node_id, config.listen_address
-    );
-
-    let server = Arc::new(ReplicationServer {
-        store: store,
-        config: Arc::new(config),
-        peers: peers,
-        pool: Arc::new(DashMap::new()),
-    });
-
-    let server_clone = server.clone();
+    //--interactive asks for the config instead of reading config.toml, and can save the answers
+    let mut config = if std::env::args().any(|arg| arg == "--interactive") {
+        let (config, save_to) = Setup::new(io::stdin().lock(), io::stdout()).run()?;
+        if let Some(path) = save_to {
+            Config::store_config(&config, path.clone())?;
+            println!("config saved to {}", path.display());
+        }
+        config
+    } else {
+        Config::load_config(PathBuf::from("config.toml"))?
+    };
+    for warning in config.dedup_peers() {
+        eprintln!("warning: {}", warning);
+    }
 
-    tokio::spawn(async move {
-        if let Err(e) = server_clone.start_listener().await {
-            eprintln!("server listener failed: {e}");
+    //sized from the config, so they can only be built once it has been read. both have to
+    //outlive the node, a runtime can't be dropped from inside another one
+    let runtime = config.runtime.build()?;
+    let gossip_runtime = config.runtime.build_gossip().transpose()?;
+    runtime.block_on(async {
+        let mut node = Node::builder().config(config);
+        if let Some(gossip) = &gossip_runtime {
+            node = node.gossip_runtime(gossip.handle().clone());
         }
-    });
+        //a decommissioned node has nothing left to do, the binary exits when wait() returns
+        node.spawn().await?.wait().await
+    })
+}
 
-    server.create_and_gossip_batch().await?;
+//mergedb-node fsck [--data-dir DIR] [--repair], checks the snapshot and the wal while the node
+//is stopped. exits 0 when they are clean or were repaired, 1 when damage was left alone, 2 when
+//they couldn't be checked at all, or the node is running
+fn run_fsck() -> i32 {
+    let args: Vec<String> = std::env::args().skip(2).collect();
+    let repair = args.iter().any(|arg| arg == "--repair");
+    let data_dir = match args.iter().position(|arg| arg == "--data-dir") {
+        Some(i) => args.get(i + 1).map(PathBuf::from),
+        None => match Config::load_config(PathBuf::from("config.toml")) {
+            Ok(config) => config.data_dir,
+            Err(e) => {
+                eprintln!("fsck: {:#}", e);
+                return 2;
+            }
+        },
+    };
+    let Some(data_dir) = data_dir else {
+        eprintln!("fsck: no data_dir configured, pass --data-dir");
+        return 2;
+    };
 
-    Ok(())
+    match fsck::run(&data_dir, repair) {
+        Ok(report) => {
+            println!("{}", report.summary());
+            report.exit_code()
+        }
+        Err(e) => {
+            eprintln!("fsck: {:#}", e);
+            2
+        }
+    }
 }
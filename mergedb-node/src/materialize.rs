@@ -0,0 +1,332 @@
+//MATERIALIZE <prefix> <path> keeps a file on the node's host in step with the keys under a
+//prefix, for systems that can only read files. the file is rewritten, through a temporary file
+//and a rename so a reader never sees half of it, whenever the projection changes.
+//a .csv path gets `key,type,value` rows, a set a row per element, anything else gets a json
+//object of key -> value. exports are kept next to the snapshot, so they outlive a restart
+use anyhow::{bail, Context, Result};
+use dashmap::DashMap;
+use mergedb_proto::wire;
+use mergedb_types::{element::Element, CrdtValue};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+use xxhash_rust::xxh3::xxh3_64;
+
+use crate::network::{self, StoredValue};
+
+pub const MATERIALIZE_FILE: &str = "materialize.json";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    Json,
+    Csv,
+}
+
+impl Format {
+    pub fn for_path(path: &Path) -> Format {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some(ext) if ext.eq_ignore_ascii_case("csv") => Format::Csv,
+            _ => Format::Json,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Export {
+    pub prefix: String,
+    pub path: PathBuf,
+    pub format: Format,
+}
+
+#[derive(Debug, Default)]
+pub struct Materializer {
+    //where the exports are saved, they only last until a restart without it
+    data_dir: Option<PathBuf>,
+    //path -> export, a path is written by one export at most
+    exports: Mutex<BTreeMap<PathBuf, Export>>,
+    //hash of what each path was last written with, an unchanged projection isn't rewritten
+    written: Mutex<HashMap<PathBuf, u64>>,
+}
+
+impl Materializer {
+    //picks up the exports saved in data_dir, nothing saved just means there are none
+    pub fn restore(data_dir: Option<PathBuf>) -> Result<Self> {
+        let materializer = Materializer {
+            data_dir,
+            ..Materializer::default()
+        };
+        let Some(path) = materializer.saved_path() else {
+            return Ok(materializer);
+        };
+        let raw = match fs::read(&path) {
+            Ok(raw) => raw,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(materializer),
+            Err(e) => return Err(e).with_context(|| format!("failed to read {}", path.display())),
+        };
+        let exports: Vec<Export> = serde_json::from_slice(&raw)
+            .with_context(|| format!("{} is not a list of exports", path.display()))?;
+        *materializer.exports.lock().unwrap() = exports
+            .into_iter()
+            .map(|export| (export.path.clone(), export))
+            .collect();
+        Ok(materializer)
+    }
+
+    fn saved_path(&self) -> Option<PathBuf> {
+        self.data_dir.as_ref().map(|dir| dir.join(MATERIALIZE_FILE))
+    }
+
+    //starts keeping path in step with the prefix, replacing whatever export wrote it before
+    pub fn start(&self, prefix: &str, path: &Path) -> Result<Export> {
+        if path.file_name().is_none() {
+            bail!("{} is not a file path", path.display());
+        }
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        if !dir.is_dir() {
+            bail!("{} is not a directory on this node", dir.display());
+        }
+        let export = Export {
+            prefix: prefix.to_string(),
+            path: path.to_path_buf(),
+            format: Format::for_path(path),
+        };
+        self.exports
+            .lock()
+            .unwrap()
+            .insert(export.path.clone(), export.clone());
+        self.written.lock().unwrap().remove(path);
+        self.save()?;
+        Ok(export)
+    }
+
+    //the file is left as it was last written. false when nothing was exported there
+    pub fn stop(&self, path: &Path) -> Result<bool> {
+        let stopped = self.exports.lock().unwrap().remove(path).is_some();
+        self.written.lock().unwrap().remove(path);
+        if stopped {
+            self.save()?;
+        }
+        Ok(stopped)
+    }
+
+    pub fn list(&self) -> Vec<Export> {
+        self.exports.lock().unwrap().values().cloned().collect()
+    }
+
+    fn save(&self) -> Result<()> {
+        let Some(path) = self.saved_path() else {
+            return Ok(());
+        };
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_vec(&self.list())?)?;
+        fs::rename(&tmp, &path)?;
+        Ok(())
+    }
+
+    //rewrites every export whose projection changed since it was last written
+    pub fn refresh(&self, store: &DashMap<String, StoredValue>) {
+        for export in self.list() {
+            let projection = render(store, &export.prefix, export.format);
+            let hash = xxh3_64(&projection);
+            if self.written.lock().unwrap().get(&export.path) == Some(&hash) {
+                continue;
+            }
+            match write_atomically(&export.path, &projection) {
+                Ok(()) => {
+                    self.written.lock().unwrap().insert(export.path, hash);
+                }
+                Err(e) => eprintln!(
+                    "failed to materialize {} to {}: {:#}",
+                    export.prefix,
+                    export.path.display(),
+                    e
+                ),
+            }
+        }
+    }
+}
+
+//the keys under prefix, sorted, node-internal keys left out
+pub fn render(store: &DashMap<String, StoredValue>, prefix: &str, format: Format) -> Vec<u8> {
+    let values: BTreeMap<String, CrdtValue> = store
+        .iter()
+        .filter(|entry| entry.key().starts_with(prefix))
+        .filter(|entry| !network::is_internal(entry.key()))
+        .map(|entry| (entry.key().clone(), entry.value().data.clone()))
+        .collect();
+    match format {
+        Format::Json => {
+            let projection: Map<String, Value> = values
+                .into_iter()
+                .map(|(key, value)| (key, to_json(&value)))
+                .collect();
+            serde_json::to_vec_pretty(&projection).unwrap()
+        }
+        Format::Csv => {
+            let mut out = String::from("key,type,value\n");
+            for (key, value) in values {
+                for field in fields(&value) {
+                    out.push_str(&format!(
+                        "{},{},{}\n",
+                        csv_field(&key),
+                        value.type_name(),
+                        csv_field(&field)
+                    ));
+                }
+            }
+            out.into_bytes()
+        }
+    }
+}
+
+//how a value reads outside mergeDB: a number, a string, a sorted list or an object, a custom
+//type reads as its plugin displays it
+pub fn to_json(value: &CrdtValue) -> Value {
+    match value {
+        CrdtValue::Counter(counter) => json!(counter.value()),
+        CrdtValue::Register(register) => json!(register.get()),
+        CrdtValue::Set(set) => set.read_sorted().iter().map(wire::element_to_json).collect(),
+        CrdtValue::Map(map) => map
+            .read()
+            .into_iter()
+            .map(|(field, value)| (field.to_string(), json!(value)))
+            .collect(),
+        CrdtValue::Custom(custom) => json!(custom.display()),
+    }
+}
+
+//a value's csv fields, one per set element or map field (as field=value), sorted
+fn fields(value: &CrdtValue) -> Vec<String> {
+    match value {
+        CrdtValue::Counter(counter) => vec![counter.value().to_string()],
+        CrdtValue::Register(register) => vec![register.get()],
+        CrdtValue::Set(set) => set.read_sorted().iter().map(Element::to_string).collect(),
+        CrdtValue::Map(map) => map
+            .read()
+            .into_iter()
+            .map(|(field, value)| format!("{}={}", field, value))
+            .collect(),
+        CrdtValue::Custom(custom) => vec![custom.display()],
+    }
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+fn write_atomically(path: &Path, contents: &[u8]) -> Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    fs::write(&tmp, contents)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mergedb_types::{
+        aw_set::AWSet, lww_map::LwwMap, lww_register::LwwRegister, pn_counter::PNCounter,
+    };
+    use std::time::SystemTime;
+
+    fn stored(data: CrdtValue) -> StoredValue {
+        StoredValue {
+            data,
+            last_updated: SystemTime::now(),
+        }
+    }
+
+    #[test]
+    fn test_exports_follow_the_prefix() {
+        let dir = std::env::temp_dir().join(format!("mergedb-materialize-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let store = DashMap::new();
+        let mut set = AWSet::new();
+        set.add("b".to_string(), "node_1".to_string());
+        set.add("a,1".to_string(), "node_1".to_string());
+        store.insert("user:tags".to_string(), stored(CrdtValue::Set(set)));
+        let mut register = LwwRegister::new("node_1".to_string());
+        register.set("Ada \"the\" first".to_string(), "node_1".to_string());
+        store.insert(
+            "user:name".to_string(),
+            stored(CrdtValue::Register(register)),
+        );
+        store.insert(
+            "views".to_string(),
+            stored(CrdtValue::Counter(PNCounter::new(
+                "node_1".to_string(),
+                3,
+                0,
+            ))),
+        );
+
+        let materializer = Materializer::restore(Some(dir.clone())).unwrap();
+        let csv = dir.join("users.csv");
+        let json = dir.join("users.json");
+        assert_eq!(
+            materializer.start("user:", &csv).unwrap().format,
+            Format::Csv
+        );
+        materializer.start("user:", &json).unwrap();
+        assert!(materializer
+            .start("user:", &dir.join("missing/users.json"))
+            .is_err());
+        materializer.refresh(&store);
+
+        assert_eq!(
+            fs::read_to_string(&csv).unwrap(),
+            "key,type,value\n\
+             user:name,register,\"Ada \"\"the\"\" first\"\n\
+             user:tags,set,\"a,1\"\n\
+             user:tags,set,b\n"
+        );
+        let projection: Value = serde_json::from_slice(&fs::read(&json).unwrap()).unwrap();
+        assert_eq!(
+            projection,
+            json!({"user:name": "Ada \"the\" first", "user:tags": ["a,1", "b"]})
+        );
+
+        //exports outlive a restart, a stopped one leaves its file as it was
+        let restored = Materializer::restore(Some(dir.clone())).unwrap();
+        assert_eq!(restored.list(), materializer.list());
+        assert!(restored.stop(&csv).unwrap());
+        assert!(!restored.stop(&csv).unwrap());
+        store.remove("user:name");
+        restored.refresh(&store);
+        assert!(fs::read_to_string(&csv).unwrap().contains("user:name"));
+        assert!(!fs::read_to_string(&json).unwrap().contains("user:name"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_maps_read_as_objects() {
+        let mut map = LwwMap::new();
+        map.set(
+            "theme".to_string(),
+            "dark".to_string(),
+            "node_1".to_string(),
+        );
+        map.set("lang".to_string(), "en".to_string(), "node_1".to_string());
+        map.remove("theme", "node_1".to_string());
+        map.set("beta".to_string(), "on".to_string(), "node_1".to_string());
+        let value = CrdtValue::Map(map);
+        assert_eq!(to_json(&value), json!({"beta": "on", "lang": "en"}));
+        assert_eq!(fields(&value), ["beta=on", "lang=en"]);
+    }
+}
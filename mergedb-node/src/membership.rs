@@ -0,0 +1,251 @@
+use dashmap::DashMap;
+use mergedb_proto::migrate;
+use std::{
+    collections::BTreeSet,
+    time::{Duration, Instant},
+};
+
+//what this node knows about another node, only nodes that were reached at least once are
+//members, a peer address that never answered says nothing about the cluster's shape
+#[derive(Debug, Clone)]
+pub struct Member {
+    pub address: Option<String>,
+    pub last_seen: Instant,
+    //node ids the member itself could reach when it last told us
+    pub reachable: Vec<String>,
+    //newest crdt format the member reads, 0 for nodes that predate format versions
+    pub format_version: u32,
+    //witnesses hold no data, nothing is gossiped to them but heartbeats
+    pub witness: bool,
+    //the process the member's heartbeats come from, empty for nodes that predate instances,
+    //and the address it claimed its node_id from
+    pub instance: String,
+    pub claimed_from: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct Membership {
+    pub node_id: String,
+    pub members: DashMap<String, Member>,
+    pub peer_timeout: Duration,
+    //random per process, it tells a heartbeat looping back to this node from one sent by
+    //another node that was given the same node_id
+    pub instance: String,
+}
+
+impl Membership {
+    pub fn new(node_id: String, peer_timeout: Duration) -> Self {
+        Membership {
+            node_id,
+            members: DashMap::new(),
+            peer_timeout,
+            instance: format!("{:016x}", rand::random::<u64>()),
+        }
+    }
+
+    //whether the process `instance` at `address` may go by node_id, the reason it may not
+    //otherwise. taken ids are this node's own, and one a member at another address still
+    //heartbeats under. a restarted node comes back under a new instance, at its old address
+    //that is fine right away, at a new one once the old has gone quiet for peer_timeout
+    pub fn claim(
+        &self,
+        node_id: &str,
+        instance: &str,
+        address: Option<&str>,
+    ) -> Result<(), String> {
+        if instance.is_empty() {
+            return Ok(());
+        }
+        if node_id == self.node_id {
+            if instance == self.instance {
+                return Ok(());
+            }
+            return Err(format!("node_id {} is this node's own", node_id));
+        }
+        let mut member = self
+            .members
+            .entry(node_id.to_string())
+            .or_insert_with(|| Member {
+                address: None,
+                last_seen: Instant::now(),
+                reachable: Vec::new(),
+                format_version: migrate::FORMAT_VERSION,
+                witness: false,
+                instance: String::new(),
+                claimed_from: None,
+            });
+        if !member.instance.is_empty() && member.instance != instance && self.is_reachable(&member)
+        {
+            if let (Some(known), Some(address)) = (member.claimed_from.as_deref(), address) {
+                if known != address {
+                    return Err(format!(
+                        "node_id {} is already taken by the node at {}",
+                        node_id, known
+                    ));
+                }
+            }
+        }
+        member.instance = instance.to_string();
+        if address.is_some() {
+            member.claimed_from = address.map(str::to_string);
+        }
+        Ok(())
+    }
+
+    //a heartbeat went through, either one we sent (address known) or one we received
+    pub fn observe(
+        &self,
+        node_id: String,
+        address: Option<String>,
+        reachable: Vec<String>,
+        format_version: u32,
+        witness: bool,
+    ) {
+        if node_id == self.node_id {
+            return;
+        }
+        let mut member = self.members.entry(node_id).or_insert_with(|| Member {
+            address: None,
+            last_seen: Instant::now(),
+            reachable: Vec::new(),
+            format_version,
+            witness,
+            instance: String::new(),
+            claimed_from: None,
+        });
+        if address.is_some() {
+            member.address = address;
+        }
+        member.last_seen = Instant::now();
+        member.reachable = reachable;
+        member.format_version = format_version;
+        member.witness = witness;
+    }
+
+    //whether the peer at this address said it is a witness, unknown peers are assumed not to be
+    pub fn is_witness_at(&self, address: &str) -> bool {
+        self.members
+            .iter()
+            .any(|entry| entry.value().witness && entry.value().address.as_deref() == Some(address))
+    }
+
+    //the crdt format to gossip in, the newest one every known member reads. members that are
+    //down count too, they'll be sent whatever piled up once they're back
+    pub fn write_format(&self) -> u32 {
+        self.members
+            .iter()
+            .map(|entry| entry.value().format_version.max(migrate::OLDEST_READABLE))
+            .fold(migrate::FORMAT_VERSION, u32::min)
+    }
+
+    pub fn is_reachable(&self, member: &Member) -> bool {
+        member.last_seen.elapsed() < self.peer_timeout
+    }
+
+    //node ids we currently hear from directly
+    pub fn reachable_ids(&self) -> Vec<String> {
+        let mut ids: Vec<String> = self
+            .members
+            .iter()
+            .filter(|entry| self.is_reachable(entry.value()))
+            .map(|entry| entry.key().clone())
+            .collect();
+        ids.sort();
+        ids
+    }
+
+    //(local group, remote group): everyone reachable from here directly or through a reachable
+    //member, and every known member outside of that
+    pub fn groups(&self) -> (BTreeSet<String>, BTreeSet<String>) {
+        let mut local = BTreeSet::from([self.node_id.clone()]);
+        for entry in self.members.iter() {
+            if self.is_reachable(entry.value()) {
+                local.insert(entry.key().clone());
+                local.extend(entry.value().reachable.iter().cloned());
+            }
+        }
+
+        let remote = self
+            .members
+            .iter()
+            .map(|entry| entry.key().clone())
+            .filter(|id| !local.contains(id))
+            .collect();
+
+        (local, remote)
+    }
+
+    //the members of `group` that still reached another member of it when they were last heard
+    //from. what they said then is all there is to go by, they aren't heard from anymore
+    pub fn linked_within(&self, group: &BTreeSet<String>) -> BTreeSet<String> {
+        group
+            .iter()
+            .filter(|id| {
+                self.members.get(*id).is_some_and(|member| {
+                    member
+                        .reachable
+                        .iter()
+                        .any(|other| other != *id && group.contains(other))
+                })
+            })
+            .cloned()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_format_waits_for_the_oldest_member() {
+        let membership = Membership::new("node_1".to_string(), Duration::from_secs(60));
+        assert_eq!(membership.write_format(), migrate::FORMAT_VERSION);
+
+        membership.observe(
+            "node_2".to_string(),
+            None,
+            Vec::new(),
+            migrate::FORMAT_VERSION,
+            false,
+        );
+        membership.observe("node_3".to_string(), None, Vec::new(), 0, false);
+        assert_eq!(membership.write_format(), migrate::OLDEST_READABLE);
+
+        //node_3 got upgraded
+        membership.observe(
+            "node_3".to_string(),
+            None,
+            Vec::new(),
+            migrate::FORMAT_VERSION,
+            false,
+        );
+        assert_eq!(membership.write_format(), migrate::FORMAT_VERSION);
+    }
+
+    #[test]
+    fn test_node_ids_are_claimed_once() {
+        let membership = Membership::new("node_1".to_string(), Duration::from_secs(60));
+        let own = membership.instance.clone();
+        assert!(membership.claim("node_1", &own, None).is_ok());
+        assert!(membership.claim("node_1", "other", None).is_err());
+
+        let node_2 = Some("10.0.0.2:8000");
+        assert!(membership.claim("node_2", "a", node_2).is_ok());
+        assert!(membership
+            .claim("node_2", "b", Some("10.0.0.3:8000"))
+            .is_err());
+        //restarted in place
+        assert!(membership.claim("node_2", "b", node_2).is_ok());
+        //older nodes send no instance, nothing to go by
+        assert!(membership
+            .claim("node_2", "", Some("10.0.0.3:8000"))
+            .is_ok());
+
+        //the old process went quiet
+        membership.members.get_mut("node_2").unwrap().last_seen -= Duration::from_secs(61);
+        assert!(membership
+            .claim("node_2", "c", Some("10.0.0.3:8000"))
+            .is_ok());
+    }
+}
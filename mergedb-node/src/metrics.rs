@@ -0,0 +1,289 @@
+use dashmap::DashMap;
+use std::{
+    collections::BTreeMap,
+    time::{Duration, Instant},
+};
+
+use crate::{contention::Contention, hotkeys::HotKeys};
+
+//hot keys exported as metrics, HOTKEYS can list more
+const HOT_KEY_METRICS: usize = 10;
+
+//how often the keys under one prefix changed on this node, and how often their state was
+//sent to a peer because of it
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Amplification {
+    pub mutations: u64,
+    pub transmissions: u64,
+}
+
+impl Amplification {
+    //state transmissions per mutation, the overhead delta replication would cut down. None
+    //until something under the prefix changed
+    pub fn ratio(&self) -> Option<f64> {
+        (self.mutations > 0).then(|| self.transmissions as f64 / self.mutations as f64)
+    }
+}
+
+//upper bounds of the latency buckets, in microseconds. anything slower lands in the last,
+//unbounded one
+const LATENCY_BUCKETS_US: [u64; 14] = [
+    100, 250, 500, 1_000, 2_500, 5_000, 10_000, 25_000, 50_000, 100_000, 250_000, 500_000,
+    1_000_000, 2_500_000,
+];
+
+//a fixed-bucket histogram of rpc latencies, quantiles come out as the upper bound of the bucket
+//they fall in, so they are only as precise as the buckets
+#[derive(Debug, Clone, PartialEq)]
+pub struct Histogram {
+    //one more than there are bounds, the last one counts everything slower
+    counts: [u64; LATENCY_BUCKETS_US.len() + 1],
+    sum_us: u64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Histogram {
+            counts: [0; LATENCY_BUCKETS_US.len() + 1],
+            sum_us: 0,
+        }
+    }
+}
+
+impl Histogram {
+    pub fn record(&mut self, latency: Duration) {
+        let us = latency.as_micros() as u64;
+        let bucket = LATENCY_BUCKETS_US
+            .iter()
+            .position(|bound| us <= *bound)
+            .unwrap_or(LATENCY_BUCKETS_US.len());
+        self.counts[bucket] += 1;
+        self.sum_us += us;
+    }
+
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    //None until something was recorded. a quantile past the last bound reports that bound
+    pub fn quantile(&self, q: f64) -> Option<Duration> {
+        let count = self.count();
+        if count == 0 {
+            return None;
+        }
+        let rank = ((q * count as f64).ceil() as u64).clamp(1, count);
+        let mut seen = 0;
+        for (bucket, n) in self.counts.iter().enumerate() {
+            seen += n;
+            if seen >= rank {
+                let bound = LATENCY_BUCKETS_US[bucket.min(LATENCY_BUCKETS_US.len() - 1)];
+                return Some(Duration::from_micros(bound));
+            }
+        }
+        None
+    }
+
+    //(upper bound, samples at or under it) for every bound, the way prometheus wants buckets
+    fn cumulative(&self) -> Vec<(u64, u64)> {
+        let mut seen = 0;
+        LATENCY_BUCKETS_US
+            .iter()
+            .zip(self.counts.iter())
+            .map(|(bound, n)| {
+                seen += n;
+                (*bound, seen)
+            })
+            .collect()
+    }
+}
+
+//the part of the key before its first ':', keys without one share the empty prefix
+pub fn prefix_of(key: &str) -> &str {
+    key.split_once(':').map(|(prefix, _)| prefix).unwrap_or("")
+}
+
+//a small name -> value registry, counters only ever go up while gauges get overwritten
+#[derive(Debug)]
+pub struct Metrics {
+    pub started_at: Instant,
+    counters: DashMap<String, u64>,
+    gauges: DashMap<String, i64>,
+    amplification: DashMap<String, Amplification>,
+    //gossip rpc round trips, by peer address
+    latency: DashMap<String, Histogram>,
+    pub hot_keys: HotKeys,
+    pub contention: Contention,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics {
+            started_at: Instant::now(),
+            counters: DashMap::new(),
+            gauges: DashMap::new(),
+            amplification: DashMap::new(),
+            latency: DashMap::new(),
+            hot_keys: HotKeys::default(),
+            contention: Contention::default(),
+        }
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Metrics::default()
+    }
+
+    pub fn incr(&self, name: &str, by: u64) {
+        *self.counters.entry(name.to_string()).or_insert(0) += by;
+    }
+
+    pub fn set_gauge(&self, name: &str, value: i64) {
+        self.gauges.insert(name.to_string(), value);
+    }
+
+    pub fn counter(&self, name: &str) -> u64 {
+        self.counters.get(name).map(|v| *v).unwrap_or(0)
+    }
+
+    pub fn gauge(&self, name: &str) -> i64 {
+        self.gauges.get(name).map(|v| *v).unwrap_or(0)
+    }
+
+    pub fn record_mutation(&self, key: &str) {
+        self.amplification
+            .entry(prefix_of(key).to_string())
+            .or_default()
+            .mutations += 1;
+    }
+
+    pub fn record_transmissions(&self, key: &str, peers: u64) {
+        self.amplification
+            .entry(prefix_of(key).to_string())
+            .or_default()
+            .transmissions += peers;
+    }
+
+    //per prefix, sorted by prefix
+    pub fn amplification(&self) -> BTreeMap<String, Amplification> {
+        self.amplification
+            .iter()
+            .map(|entry| (entry.key().clone(), *entry.value()))
+            .collect()
+    }
+
+    //how long a successful heartbeat, gossip or batch to the peer took
+    pub fn record_latency(&self, peer_addr: &str, latency: Duration) {
+        self.latency
+            .entry(peer_addr.to_string())
+            .or_default()
+            .record(latency);
+    }
+
+    pub fn latency(&self, peer_addr: &str) -> Option<Histogram> {
+        self.latency
+            .get(peer_addr)
+            .map(|histogram| histogram.clone())
+    }
+
+    //sorted by name so that the output is stable
+    pub fn snapshot(&self) -> BTreeMap<String, i64> {
+        let mut all = BTreeMap::new();
+        for entry in self.counters.iter() {
+            all.insert(entry.key().clone(), *entry.value() as i64);
+        }
+        for entry in self.gauges.iter() {
+            all.insert(entry.key().clone(), *entry.value());
+        }
+        for (prefix, amplification) in self.amplification() {
+            all.insert(
+                format!("key_mutations_total{{prefix=\"{}\"}}", prefix),
+                amplification.mutations as i64,
+            );
+            all.insert(
+                format!("key_transmissions_total{{prefix=\"{}\"}}", prefix),
+                amplification.transmissions as i64,
+            );
+        }
+        for entry in self.latency.iter() {
+            let (peer, histogram) = (entry.key(), entry.value());
+            for (bound, seen) in histogram.cumulative() {
+                all.insert(
+                    format!(
+                        "gossip_rpc_latency_us_bucket{{peer=\"{}\",le=\"{}\"}}",
+                        peer, bound
+                    ),
+                    seen as i64,
+                );
+            }
+            all.insert(
+                format!(
+                    "gossip_rpc_latency_us_bucket{{peer=\"{}\",le=\"+Inf\"}}",
+                    peer
+                ),
+                histogram.count() as i64,
+            );
+            all.insert(
+                format!("gossip_rpc_latency_us_sum{{peer=\"{}\"}}", peer),
+                histogram.sum_us as i64,
+            );
+            all.insert(
+                format!("gossip_rpc_latency_us_count{{peer=\"{}\"}}", peer),
+                histogram.count() as i64,
+            );
+        }
+        for hot in self.hot_keys.top(HOT_KEY_METRICS) {
+            all.insert(
+                format!("hot_key_reads{{key=\"{}\"}}", hot.key),
+                hot.reads as i64,
+            );
+            all.insert(
+                format!("hot_key_writes{{key=\"{}\"}}", hot.key),
+                hot.writes as i64,
+            );
+        }
+        all
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_amplification_per_prefix() {
+        let metrics = Metrics::new();
+        metrics.record_mutation("flags:dark");
+        metrics.record_mutation("flags:beta");
+        metrics.record_transmissions("flags:dark", 3);
+        metrics.record_transmissions("views", 2);
+
+        let amplification = metrics.amplification();
+        assert_eq!(amplification["flags"].ratio(), Some(1.5));
+        assert_eq!(amplification[""].ratio(), None);
+        assert_eq!(
+            metrics.snapshot()["key_transmissions_total{prefix=\"flags\"}"],
+            3
+        );
+    }
+
+    #[test]
+    fn test_latency_quantiles() {
+        let metrics = Metrics::new();
+        assert!(metrics.latency("10.0.0.2:8000").is_none());
+        for ms in 1..=100 {
+            metrics.record_latency("10.0.0.2:8000", Duration::from_millis(ms));
+        }
+        let histogram = metrics.latency("10.0.0.2:8000").unwrap();
+        assert_eq!(histogram.count(), 100);
+        assert_eq!(histogram.quantile(0.5), Some(Duration::from_millis(50)));
+        assert_eq!(histogram.quantile(0.95), Some(Duration::from_millis(100)));
+        assert_eq!(histogram.quantile(0.01), Some(Duration::from_millis(1)));
+
+        let snapshot = metrics.snapshot();
+        let bucket = "gossip_rpc_latency_us_bucket{peer=\"10.0.0.2:8000\",le=\"25000\"}";
+        assert_eq!(snapshot[bucket], 25);
+        let all = "gossip_rpc_latency_us_bucket{peer=\"10.0.0.2:8000\",le=\"+Inf\"}";
+        assert_eq!(snapshot[all], 100);
+    }
+}
@@ -1,43 +1,170 @@
 use anyhow::Result;
-use dashmap::DashMap;
+use dashmap::{mapref::entry::Entry, try_result::TryResult, DashMap};
+use mergedb_proto::{migrate, wire, CrdtProto};
 use mergedb_types::{
-    Merge, aw_set::{AWSet, Dot as AW_Dot}, lww_register::{Dot as LWW_Dot, LwwRegister}, pn_counter::PNCounter
+    aw_set::AWSet, custom::CustomValue, element::Element, hlc, lww_map::LwwMap,
+    lww_register::LwwRegister, pn_counter::PNCounter, CrdtValue, Merge,
 };
 use rand::{rngs::SmallRng, seq::IndexedRandom, SeedableRng};
 use std::str::FromStr;
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, HashMap, HashSet},
     net::SocketAddr,
-    sync::Arc,
-    time::{Duration, SystemTime},
+    os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime},
 };
+use tokio::net::UnixListener;
+use tokio_stream::wrappers::UnixListenerStream;
 use tonic::{transport::Channel, transport::Server, Request, Response};
+use tonic_health::pb::health_server::{Health, HealthServer};
 
 use crate::{
+    anomaly::AnomalyDetector,
+    audit,
+    breaker::Breakers,
     communication::{
-        crdt_data::Data,
         replication_service_client::ReplicationServiceClient,
         replication_service_server::{ReplicationService, ReplicationServiceServer},
-        AwSetMessage, CrdtData, GossipBatchRequest, GossipBatchResponse, GossipChangesRequest,
-        GossipChangesResponse, PnCounterMessage, PropagateDataRequest, PropagateDataResponse,
-        ProtoDot, ProtoDotSet, ProtoRegisterDot, LwwRegisterMessage,
+        AnnounceRequest, AnnounceResponse, BroadcastMessage, BroadcastRequest, BroadcastResponse,
+        ClusterStatusRequest, ClusterStatusResponse, CrdtData, DecommissionProgress,
+        DecommissionRequest, ExportChunk, ExportRequest, FingerprintRequest, FingerprintResponse,
+        GossipBatchRequest,
+        GossipBatchResponse, GossipChangesRequest, GossipChangesResponse, GossipDigestRequest,
+        GossipDigestResponse, GraftRequest, GraftResponse, HeartbeatRequest,
+        HeartbeatResponse, LeaveRequest, LeaveResponse, MemberStatus, NodeStatusRequest,
+        NodeStatusResponse, PauseRequest, PauseResponse, PeerPing, PingRequest, PingResponse,
+        PropagateBatchRequest, PropagateBatchResponse, PropagateBatchResult, PropagateDataRequest,
+        PropagateDataResponse,
+        ReconcileRequest, ReconcileResponse, Replicas, ResumeRequest, SampleRequest,
+        SampleResponse, VersionedUpdate,
     },
-    config::Config,
+    config::{split_host_port, Config, PeerConfig},
+    contention,
+    decommission,
+    digest::{self, GossipMode},
+    discovery,
+    events::Events,
+    expiry,
+    export,
+    fingerprint,
+    freeze,
+    grpc_web::GrpcWebLayer,
+    hotkeys,
+    info,
+    labels::{self, Selector},
+    listener::{Listener, Role},
+    materialize::Materializer,
+    membership::Membership,
+    metrics::Metrics,
+    peer,
+    outbound::Outbound,
+    pause::{self, Pause},
+    persistence::Persistence,
+    plumtree::{self, Tree},
+    priority::Schedule,
+    recovery::{Recovery, Stage},
+    rename,
+    script::{self, Written},
+    scuttlebutt::{Scuttlebutt, Vector},
+    set_limit,
+    settings::{self, Setting, Settings},
+    shards,
+    split_brain::SplitBrainDetector,
+    tuning::{self, Tuning},
+    units,
+    wal::Wal,
+    watchdog, webhook,
+    work_queue::WorkQueue,
 };
 
-const K: usize = 3;
-const BATCH_SIZE: usize = 1000;
+pub const BATCH_SIZE: usize = 1000;
+const WARM_UP_CHUNK: usize = 1000;
+const MATERIALIZE_EVERY: Duration = Duration::from_secs(1);
+//how often label rules look for keys past their retention
+const EXPIRE_EVERY: Duration = Duration::from_secs(30);
+//how long a peer without its own connect_timeout_ms gets to answer when the pool is filled
+//at startup
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+//commands a client can pipeline in one PropagateBatch
+const MAX_PIPELINE: usize = 10_000;
+
+//resolved rather than parsed, so hostnames like "node1.internal:8000" work too
+async fn resolve(address: &str) -> Result<SocketAddr> {
+    tokio::net::lookup_host(address)
+        .await?
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("listen address {} did not resolve", address))
+}
 
-#[derive(Debug, Clone)]
-pub enum CRDTValue {
-    Counter(PNCounter),
-    AWSet(AWSet),
-    LWWRegister(LwwRegister),
+//every listener also answers the standard grpc health check, so load balancers and
+//orchestrators can hold traffic back until the node is ready
+async fn serve<H: Health>(
+    listener: Listener,
+    health: HealthServer<H>,
+    addr: SocketAddr,
+) -> Result<()> {
+    if listener.server.config.grpc_web && listener.role != Role::Peer {
+        Server::builder()
+            .accept_http1(true)
+            .layer(GrpcWebLayer::new(listener.clone()))
+            .add_service(health)
+            .add_service(ReplicationServiceServer::new(listener))
+            .serve(addr)
+            .await?;
+        return Ok(());
+    }
+    Server::builder()
+        .add_service(health)
+        .add_service(ReplicationServiceServer::new(listener))
+        .serve(addr)
+        .await?;
+    Ok(())
+}
+
+//malformed values are the client's fault
+fn malformed(e: wire::WireError) -> tonic::Status {
+    tonic::Status::invalid_argument(e.to_string())
+}
+
+//the answer to a write of one type to a key holding another
+fn type_mismatch() -> Response<PropagateDataResponse> {
+    Response::new(PropagateDataResponse {
+        success: false,
+        response: Vec::new(),
+        seq: 0,
+        number: None,
+        replicas: None,
+    })
+}
+
+//what RLEN answers: the length of the value itself, not of the text a binary value is held as
+fn register_len(register: &LwwRegister) -> i64 {
+    let (value, _) = wire::decode_value(&register.get());
+    value.len() as i64
+}
+
+//keys the node keeps its own state under, freeze, rename and expiry markers, settings, labels,
+//set limits, counter shards and the cluster wide pause
+pub fn is_internal(key: &str) -> bool {
+    freeze::is_marker(key)
+        || rename::is_marker(key)
+        || expiry::is_marker(key)
+        || pause::is_pause(key)
+        || settings::is_setting(key)
+        || labels::is_label(key)
+        || set_limit::is_limit(key)
+        || shards::is_count(key)
+        || shards::is_shard(key)
 }
 
 #[derive(Debug)]
 pub struct StoredValue {
-    pub data: CRDTValue,
+    pub data: CrdtValue,
     pub last_updated: SystemTime,
 }
 
@@ -47,6 +174,43 @@ pub struct ReplicationServer {
     pub config: Arc<Config>,
     pub peers: Arc<DashMap<String, SystemTime>>,
     pub pool: Arc<DashMap<String, ReplicationServiceClient<Channel>>>,
+    pub membership: Arc<Membership>,
+    pub metrics: Arc<Metrics>,
+    pub split_brain: Arc<SplitBrainDetector>,
+    //last commit sequence number handed out, see commit()
+    pub commit_seq: Arc<AtomicU64>,
+    pub persistence: Arc<Persistence>,
+    //EVAL scripts hold this exclusively, every other client command shares it, so a script's
+    //reads and writes don't interleave with anyone else's. gossip doesn't take it, merging
+    //is safe at any point
+    pub script_lock: Arc<tokio::sync::RwLock<()>>,
+    //set while the node hands its data over before leaving the cluster, writes are refused
+    pub decommissioning: Arc<AtomicBool>,
+    //notified once the handover is done and the node has left, Node::wait() resolves then
+    pub decommissioned: Arc<tokio::sync::Notify>,
+    //this node's own pause of client writes, a cluster wide one is in the store, see pause
+    pub paused: Arc<Pause>,
+    //how far the node has got loading its data since it started
+    pub recovery: Arc<Recovery>,
+    //where peer connections are made and the peer listener runs when runtime.gossip_threads
+    //is set, None when gossip shares the client runtime
+    pub gossip_runtime: Option<tokio::runtime::Handle>,
+    //client commands wait here for their turn, see request_queue in the config
+    pub work_queue: Arc<WorkQueue>,
+    //which updates each key carries, for gossip_mode = "scuttlebutt"
+    pub scuttlebutt: Arc<Scuttlebutt>,
+    //who client writes are pushed to and who they are only announced to, with [plumtree]
+    pub plumtree: Arc<Tree>,
+    //what embedding applications are told about, see events
+    pub events: Arc<Events>,
+    //peers that keep failing are left alone for a while, by push() and the gossip loop
+    pub breakers: Arc<Breakers>,
+    //what has gone out to each peer, and what it is still owed from before a restart
+    pub outbound: Arc<Outbound>,
+    //every change since the last snapshot, see commit()
+    pub wal: Arc<Wal>,
+    //files kept in step with a prefix, see MATERIALIZE
+    pub materializer: Arc<Materializer>,
 }
 
 #[derive(Debug, PartialEq)]
@@ -62,9 +226,74 @@ pub enum Command {
     GetRegister,  //RGET
     AppendRegister,   //RAPP
     GetRegisterLen,   //RLEN
+    Info,       //INFO
+    DbSize,     //DBSIZE
+    Keys,       //KEYS
+    SetMeta,    //SMETA
+    Type,       //TYPE
+    Freeze,     //FREEZE
+    Thaw,       //THAW
+    Eval,       //EVAL
+    Setting,    //SETTING
+    Settings,   //SETTINGS
+    HotKeys,    //HOTKEYS
+    Materialize,   //MATERIALIZE
+    Unmaterialize, //UNMATERIALIZE
+    Label,      //LABEL
+    Labels,     //LABELS
+    Scan,       //SCAN
+    SetLimit,   //SLIMIT
+    Shard,      //CSHARD
+    Audit,      //AUDIT
+    Copy,       //COPY
+    Rename,     //RENAME
+    Contention, //CONTENTION
     Unknown,
 }
 
+impl Command {
+    //commands that change the key, refused while it is frozen
+    fn is_write(&self) -> bool {
+        matches!(
+            self,
+            Command::SetCounter
+                | Command::IncCounter
+                | Command::DecCounter
+                | Command::SetAdd
+                | Command::SetRemove
+                | Command::SetRegister
+                | Command::AppendRegister
+                | Command::SetLimit
+                | Command::Shard
+        )
+    }
+
+    //commands whose value is a set tag, the ones that take an element type and an encoding
+    fn takes_element(&self) -> bool {
+        matches!(
+            self,
+            Command::SetAdd | Command::SetRemove | Command::SetMeta
+        )
+    }
+
+    //commands whose value is a register value, they take an encoding too
+    fn takes_register_value(&self) -> bool {
+        matches!(self, Command::SetRegister | Command::AppendRegister)
+    }
+
+    //commands that read the key, counted towards how hot it is along with writes
+    fn is_read(&self) -> bool {
+        matches!(
+            self,
+            Command::GetCounter
+                | Command::GetSet
+                | Command::GetRegister
+                | Command::GetRegisterLen
+                | Command::SetMeta
+        )
+    }
+}
+
 impl FromStr for Command {
     type Err = ();
 
@@ -81,176 +310,267 @@ impl FromStr for Command {
             "RGET" => Ok(Command::GetRegister),
             "RAPP" => Ok(Command::AppendRegister),
             "RLEN" => Ok(Command::GetRegisterLen),
+            "INFO" => Ok(Command::Info),
+            "DBSIZE" => Ok(Command::DbSize),
+            "KEYS" => Ok(Command::Keys),
+            "SMETA" => Ok(Command::SetMeta),
+            "TYPE" => Ok(Command::Type),
+            "FREEZE" => Ok(Command::Freeze),
+            "THAW" => Ok(Command::Thaw),
+            "EVAL" => Ok(Command::Eval),
+            "SETTING" => Ok(Command::Setting),
+            "SETTINGS" => Ok(Command::Settings),
+            "HOTKEYS" => Ok(Command::HotKeys),
+            "MATERIALIZE" => Ok(Command::Materialize),
+            "UNMATERIALIZE" => Ok(Command::Unmaterialize),
+            "LABEL" => Ok(Command::Label),
+            "LABELS" => Ok(Command::Labels),
+            "SCAN" => Ok(Command::Scan),
+            "SLIMIT" => Ok(Command::SetLimit),
+            "CSHARD" => Ok(Command::Shard),
+            "AUDIT" => Ok(Command::Audit),
+            "COPY" => Ok(Command::Copy),
+            "RENAME" => Ok(Command::Rename),
+            "CONTENTION" => Ok(Command::Contention),
             _ => Ok(Command::Unknown),
         }
     }
 }
 
-// convert domain -> proto for sending
-impl From<PNCounter> for PnCounterMessage {
-    fn from(domain: PNCounter) -> Self {
-        Self {
-            p: domain.p,
-            n: domain.n,
-        }
-    }
-}
+#[tonic::async_trait]
+impl ReplicationService for ReplicationServer {
+    async fn propagate_data(
+        &self,
+        request: tonic::Request<PropagateDataRequest>,
+    ) -> Result<tonic::Response<PropagateDataResponse>, tonic::Status> {
+        let req_inner = request.into_inner();
 
-// convert proto -> domain for receiving
-impl From<PnCounterMessage> for PNCounter {
-    fn from(wire: PnCounterMessage) -> Self {
-        Self {
-            p: wire.p,
-            n: wire.n,
-        }
-    }
-}
+        let value_type = req_inner.valuetype;
+        let key = req_inner.key;
+        let raw_value_bytes = req_inner.value;
+        let dry_run = req_inner.dry_run;
+        let encoding: wire::Encoding = req_inner.encoding.parse().map_err(malformed)?;
+        let element_type = req_inner.element_type;
+        let amount = req_inner.amount;
 
-//same for AWSet
-impl From<AW_Dot> for ProtoDot {
-    fn from(domain: AW_Dot) -> Self {
-        Self {
-            node_id: domain.node_id,
-            counter: domain.counter,
+        let command = Command::from_str(&value_type).unwrap_or(Command::Unknown);
+        self.metrics.incr("commands_total", 1);
+        //anything else would be applied for real, EVAL included
+        if dry_run && command != Command::SetRemove {
+            return Err(tonic::Status::invalid_argument(format!(
+                "{} has no dry run, only SREM does",
+                value_type
+            )));
         }
-    }
-}
-
-impl From<ProtoDot> for AW_Dot {
-    fn from(wire: ProtoDot) -> Self {
-        Self {
-            node_id: wire.node_id,
-            counter: wire.counter,
+        if self.config.witness && !matches!(command, Command::Info | Command::DbSize) {
+            return Err(tonic::Status::failed_precondition(format!(
+                "{} is a witness, it holds no data, send {} to another node",
+                self.config.node_id, value_type
+            )));
         }
-    }
-}
+        self.ensure_loaded(&key);
 
-impl From<AWSet> for AwSetMessage {
-    fn from(domain: AWSet) -> Self {
-        let convert_map = |input_map: HashMap<String, HashSet<AW_Dot>>| {
-            input_map
-                .into_iter()
-                .map(|(tag, dots)| {
-                    let proto_dots = dots.into_iter().map(ProtoDot::from).collect();
-                    (tag, ProtoDotSet { dots: proto_dots })
-                })
-                .collect()
-        };
-        Self {
-            clock: domain.clock,
-            add_tags: convert_map(domain.add_tags),
-            remove_tags: convert_map(domain.remove_tags),
+        if command.is_write() {
+            if let Some(refusal) = self.refuse_write(&key, raw_value_bytes.len()) {
+                return Err(refusal);
+            }
+        }
+        if command.is_read() {
+            if let Some(target) = self.renamed_to(&key) {
+                return Err(tonic::Status::not_found(format!(
+                    "{} was renamed to {}",
+                    key, target
+                )));
+            }
         }
-    }
-}
 
-impl From<AwSetMessage> for AWSet {
-    fn from(wire: AwSetMessage) -> Self {
-        let convert_map = |input_map: HashMap<String, ProtoDotSet>| {
-            input_map
-                .into_iter()
-                .map(|(tag, dot_set)| {
-                    let domain_dots = dot_set.dots.into_iter().map(AW_Dot::from).collect();
-                    (tag, domain_dots)
-                })
-                .collect()
+        if !element_type.is_empty() && !command.takes_element() {
+            return Err(tonic::Status::invalid_argument(format!(
+                "{} takes no element type, only set tags do",
+                value_type
+            )));
+        }
+        //from here on a register value is the text it is held as
+        let raw_value_bytes = if command.takes_register_value() {
+            wire::encode_value(raw_value_bytes, encoding)
+                .map_err(malformed)?
+                .into_bytes()
+        } else if encoding != wire::Encoding::Utf8 && !command.takes_element() {
+            return Err(tonic::Status::invalid_argument(format!(
+                "{} takes no encoding, only tags and register values do",
+                value_type
+            )));
+        } else {
+            raw_value_bytes
         };
-        Self {
-            clock: wire.clock,
-            add_tags: convert_map(wire.add_tags),
-            remove_tags: convert_map(wire.remove_tags),
+        let element = |raw: Vec<u8>| wire::decode_element(raw, &element_type, encoding);
+        //counter amounts are signed, whether they came in the field or as bytes
+        let amount = |raw: &[u8]| wire::decode_signed(amount, raw);
+
+        let changes_store = command.is_write()
+            || matches!(
+                command,
+                Command::Eval
+                    | Command::Freeze
+                    | Command::Thaw
+                    | Command::Setting
+                    | Command::Label
+                    | Command::Copy
+                    | Command::Rename
+            );
+        //FREEZE, THAW and SETTING still go through, they may be what the pause is for. a
+        //script might write, so EVAL doesn't
+        let writes_data = command.is_write()
+            || matches!(
+                command,
+                Command::Eval | Command::Label | Command::Copy | Command::Rename
+            );
+        if changes_store {
+            if let Some(refusal) = self.refuse_change(writes_data && !dry_run) {
+                return Err(refusal);
+            }
         }
-    }
-}
 
-//same for LWWRegister
-impl From<LWW_Dot> for ProtoRegisterDot {
-    fn from(domain: LWW_Dot) -> Self {
-        Self {
-            node_id: domain.node_id,
-            counter: domain.counter,
-            register: domain.register,
+        if command.is_write() && !dry_run {
+            self.metrics.hot_keys.record_write(&key);
+        } else if command.is_read() {
+            self.metrics.hot_keys.record_read(&key);
         }
-    }
-}
 
-impl From<ProtoRegisterDot> for LWW_Dot {
-    fn from(wire: ProtoRegisterDot) -> Self {
-        Self {
-            node_id: wire.node_id,
-            counter: wire.counter,
-            register: wire.register,
+        if command == Command::Eval {
+            let response = self.handle_eval(raw_value_bytes).await;
+            return self.when_durable(response).await;
         }
-    }
-}
+        let shared = self.script_lock.read().await;
 
-impl From<LwwRegister> for LwwRegisterMessage {
-    fn from(domain: LwwRegister) -> Self {
-        Self {
-            clock: domain.clock,
-            register_state: Some(ProtoRegisterDot::from(domain.register_state)),
+        if dry_run {
+            let tag = element(raw_value_bytes).map_err(malformed)?;
+            return self.dry_run_rem_set(key, tag).await;
         }
-    }
-}
 
-impl From<LwwRegisterMessage> for LwwRegister {
-    fn from(wire: LwwRegisterMessage) -> Self {
-        let raw_dot = wire.register_state.unwrap_or_default();
-        Self {
-            clock: wire.clock,
-            register_state: LWW_Dot::from(raw_dot),
+        //label rule webhooks hear about the write once it has been applied
+        let watched = self
+            .config
+            .label_rules
+            .iter()
+            .any(|rule| rule.webhook.is_some());
+        let written = (command.is_write() && watched).then(|| key.clone());
+        let writing = command
+            .is_write()
+            .then(|| self.metrics.contention.enter(&key));
+        if writing.is_some() {
+            self.wait_for_entry(&key);
         }
-    }
-}
-
-
-#[tonic::async_trait]
-impl ReplicationService for ReplicationServer {
-    async fn propagate_data(
-        &self,
-        request: tonic::Request<PropagateDataRequest>,
-    ) -> Result<tonic::Response<PropagateDataResponse>, tonic::Status> {
-        let req_inner = request.into_inner();
-
-        let value_type = req_inner.valuetype;
-        let key = req_inner.key;
-        let raw_value_bytes = req_inner.value;
-
-        let command = Command::from_str(&value_type).unwrap_or(Command::Unknown);
-
-        match command {
-            Command::SetCounter => self.handle_set_counter(key, raw_value_bytes).await,
+        let response = match command {
+            Command::SetCounter => {
+                let amount = amount(&raw_value_bytes).map_err(malformed)?;
+                self.handle_set_counter(key, amount).await
+            }
             Command::GetCounter => self.handle_get_counter(key).await,
-            Command::IncCounter => self.handle_inc_counter(key, raw_value_bytes).await,
-            Command::DecCounter => self.handle_dec_counter(key, raw_value_bytes).await,
-            Command::SetAdd => self.handle_add_set(key, raw_value_bytes).await,
-            Command::SetRemove => self.handle_rem_set(key, raw_value_bytes).await,
-            Command::GetSet => self.handle_get_set(key).await,
+            Command::IncCounter => {
+                let amount = amount(&raw_value_bytes).map_err(malformed)?;
+                self.handle_inc_counter(key, amount).await
+            }
+            Command::DecCounter => {
+                let amount = amount(&raw_value_bytes).map_err(malformed)?;
+                self.handle_dec_counter(key, amount).await
+            }
+            Command::SetAdd => {
+                let tag = element(raw_value_bytes).map_err(malformed)?;
+                self.handle_add_set(key, tag).await
+            }
+            Command::SetRemove => {
+                let tag = element(raw_value_bytes).map_err(malformed)?;
+                self.handle_rem_set(key, tag).await
+            }
+            Command::GetSet => self.handle_get_set(key, raw_value_bytes).await,
             Command::SetRegister => self.handle_set_register(key, raw_value_bytes).await,
             Command::GetRegister => self.handle_get_register(key).await,
             Command::AppendRegister => self.handle_append_register(key, raw_value_bytes).await,
             Command::GetRegisterLen => self.handle_get_len_register(key).await,
+            Command::Info => self.handle_info(raw_value_bytes).await,
+            Command::DbSize => self.handle_dbsize().await,
+            Command::Keys => self.handle_keys(key).await,
+            Command::SetMeta => {
+                let tag = element(raw_value_bytes).map_err(malformed)?;
+                self.handle_meta_set(key, tag).await
+            }
+            Command::Type => self.handle_type(key).await,
+            Command::Freeze => self.handle_freeze(key, true).await,
+            Command::Thaw => self.handle_freeze(key, false).await,
+            Command::Eval => unreachable!("handled above"),
+            Command::Setting => self.handle_setting(key, raw_value_bytes).await,
+            Command::Settings => self.handle_settings().await,
+            Command::HotKeys => self.handle_hotkeys(raw_value_bytes).await,
+            Command::Materialize => self.handle_materialize(key, raw_value_bytes).await,
+            Command::Unmaterialize => self.handle_unmaterialize(key).await,
+            Command::Label => self.handle_label(key, raw_value_bytes).await,
+            Command::Labels => self.handle_labels(key).await,
+            Command::Scan => self.handle_scan(key, raw_value_bytes).await,
+            Command::SetLimit => self.handle_set_limit(key, raw_value_bytes).await,
+            Command::Shard => self.handle_shard(key, raw_value_bytes).await,
+            Command::Audit => self.handle_audit().await,
+            Command::Copy => self.handle_copy(key, raw_value_bytes).await,
+            Command::Rename => self.handle_rename(key, raw_value_bytes).await,
+            Command::Contention => self.handle_contention(raw_value_bytes).await,
             Command::Unknown => {
                 println!("Unknown command received");
                 Ok(tonic::Response::new(PropagateDataResponse {
                     success: false,
                     response: Vec::new(),
+                    seq: 0,
+                    number: None,
+                    replicas: None,
                 }))
             }
-            _ => {
-                println!("Command {:?} not implemented yet", command);
-                Ok(tonic::Response::new(PropagateDataResponse {
-                    success: false,
-                    response: Vec::new(),
-                }))
-            }
+        };
+        drop(writing);
+        drop(shared);
+        if let (Some(key), Ok(response)) = (&written, &response) {
+            self.notify_label_rules(key, &value_type, response.get_ref().seq);
+        }
+        self.when_durable(response).await
+    }
+
+    //each command runs on its own, a failed one doesn't stop the ones after it
+    async fn propagate_batch(
+        &self,
+        request: tonic::Request<PropagateBatchRequest>,
+    ) -> Result<tonic::Response<PropagateBatchResponse>, tonic::Status> {
+        let requests = request.into_inner().requests;
+        if requests.len() > MAX_PIPELINE {
+            return Err(tonic::Status::invalid_argument(format!(
+                "{} commands in one batch, at most {} are allowed",
+                requests.len(),
+                MAX_PIPELINE
+            )));
+        }
+        self.metrics.incr("pipelines_total", 1);
+
+        let mut results = Vec::with_capacity(requests.len());
+        for request in requests {
+            results.push(match self.propagate_data(Request::new(request)).await {
+                Ok(response) => PropagateBatchResult {
+                    response: Some(response.into_inner()),
+                    error: String::new(),
+                },
+                Err(status) => PropagateBatchResult {
+                    response: None,
+                    error: status.message().to_string(),
+                },
+            });
         }
+        Ok(Response::new(PropagateBatchResponse { results }))
     }
 
     async fn gossip_changes(
         &self,
         changes: tonic::Request<GossipChangesRequest>,
     ) -> Result<tonic::Response<GossipChangesResponse>, tonic::Status> {
+        if self.config.witness {
+            self.metrics.incr("witness_dropped_total", 1);
+            return Ok(Response::new(GossipChangesResponse { success: false }));
+        }
         let changes_inner = changes.into_inner();
         let key = changes_inner.key;
         let crdt_data = match changes_inner.counter {
@@ -258,84 +578,16 @@ impl ReplicationService for ReplicationServer {
             None => return Ok(Response::new(GossipChangesResponse { success: false })),
         };
         
-        let remote_crdt = match crdt_data.data {
-            Some(Data::PnCounter(wire)) => {
-                //convert Proto -> Domain
-                let domain_counter = PNCounter::from(wire);
-                CRDTValue::Counter(domain_counter)
-            }
-            Some(Data::AwSet(wire)) => {
-                //same thing, convert Proto -> Domain
-                let domain_set = AWSet::from(wire);
-                CRDTValue::AWSet(domain_set)
-            }
-            Some(Data::LwwRegister(wire)) => {
-                let domain_register = LwwRegister::from(wire);
-                CRDTValue::LWWRegister(domain_register)
-            }
-            None => {
-                println!("Received CRDTData but the oneof field was empty");
+        let remote_crdt = match CrdtValue::from_proto(crdt_data) {
+            Ok(remote_crdt) => remote_crdt,
+            Err(e) => {
+                println!("Rejected CRDTData for {}: {}", key, e);
                 return Ok(Response::new(GossipChangesResponse { success: false }));
             }
         };
 
         //call merge now with the value corresponding to the same key in this node
-        self.store
-            .entry(key.clone())
-            .and_modify(|stored_value| {
-                match (&mut stored_value.data, &remote_crdt) {
-                    //match wrt both the values
-                    (CRDTValue::Counter(local_counter), CRDTValue::Counter(remote_counter)) => {
-                        let old_state = local_counter.clone();
-
-                        local_counter.merge(&mut remote_counter.clone());
-
-                        if *local_counter != old_state {
-                            println!("Merged NEW update for {}", key);
-                            stored_value.last_updated = SystemTime::now();
-                        } else {
-                            println!("Ignored redundant update for {}", key);
-                        }
-                    }
-
-                    (CRDTValue::AWSet(local_set), CRDTValue::AWSet(remote_set)) => {
-                        let old_state = local_set.clone();
-
-                        local_set.merge(&mut remote_set.clone());
-
-                        if *local_set != old_state {
-                            println!("Merged NEW update for {}", key);
-                            stored_value.last_updated = SystemTime::now();
-                        } else {
-                            println!("Ignored redundant update for {}", key);
-                        }
-                    }
-                    
-                    (CRDTValue::LWWRegister(local_reg), CRDTValue::LWWRegister(remote_reg)) => {
-                        println!("inside the gossip condition 1");
-                        let old_state = local_reg.clone();
-
-                        local_reg.merge(&mut remote_reg.clone());
-
-                        if *local_reg != old_state {
-                            println!("Merged NEW update for {}", key);
-                            stored_value.last_updated = SystemTime::now();
-                        } else {
-                            println!("Ignored redundant update for {}", key);
-                        }
-                    }
-
-                    _ => println!(
-                        "type mismatch: key exisits, but value is not of type PNCounter or AWSet"
-                    ),
-                }
-
-                stored_value.last_updated = SystemTime::now()
-            })
-            .or_insert_with(|| StoredValue {
-                data: remote_crdt.clone(),
-                last_updated: SystemTime::now(),
-            });
+        self.merge_remote(key, remote_crdt);
 
         Ok(Response::new(GossipChangesResponse { success: true }))
     }
@@ -344,255 +596,750 @@ impl ReplicationService for ReplicationServer {
         &self,
         batch: tonic::Request<GossipBatchRequest>,
     ) -> Result<tonic::Response<GossipBatchResponse>, tonic::Status> {
+        if self.config.witness {
+            self.metrics.incr("witness_dropped_total", 1);
+            return Ok(Response::new(GossipBatchResponse { success: false }));
+        }
         let batch = batch.into_inner().batch;
         for (key, crdt_data) in batch {
-            let remote_crdt = match crdt_data.data {
-                Some(Data::PnCounter(wire)) => {
-                    let domain_counter = PNCounter::from(wire);
-                    CRDTValue::Counter(domain_counter)
-                }
-                Some(Data::AwSet(wire)) => {
-                    let domain_set = AWSet::from(wire);
-                    CRDTValue::AWSet(domain_set)
-                }
-                Some(Data::LwwRegister(wire)) => {
-                    let domain_register = LwwRegister::from(wire);
-                    CRDTValue::LWWRegister(domain_register)
-                }
-                None => {
-                    println!("Received CRDTData but the oneof field was empty");
+            let remote_crdt = match CrdtValue::from_proto(crdt_data) {
+                Ok(remote_crdt) => remote_crdt,
+                Err(e) => {
+                    println!("Rejected CRDTData for {}: {}", key, e);
                     return Ok(Response::new(GossipBatchResponse { success: false }));
                 }
             };
-
-            self.store
-                .entry(key.clone())
-                .and_modify(|stored_value| {
-                    match (&mut stored_value.data, &remote_crdt) {
-                        (CRDTValue::Counter(local_counter), CRDTValue::Counter(remote_counter)) => {
-                            let old_state = local_counter.clone();
-
-                            local_counter.merge(&mut remote_counter.clone());
-
-                            if *local_counter != old_state {
-                                println!("Merged NEW update for {}", key);
-                                stored_value.last_updated = SystemTime::now();
-                            } else {
-                                println!("Ignored redundant update for {}", key);
-                            }
-                        },
-
-                        (CRDTValue::AWSet(local_set), CRDTValue::AWSet(remote_set)) => {
-                            let old_state = local_set.clone();
-
-                            local_set.merge(&mut remote_set.clone());
-
-                            if *local_set != old_state {
-                                println!("Merged NEW update for {}", key);
-                                stored_value.last_updated = SystemTime::now();
-                            }else {
-                                println!("Ignored redundant update for {}", key);
-                            }
-                        },
-
-                        (CRDTValue::LWWRegister(local_reg), CRDTValue::LWWRegister(remote_reg)) => {
-                            println!("inside the gossip condition 2");
-                            let old_state = local_reg.clone();
-    
-                            local_reg.merge(&mut remote_reg.clone());
-    
-                            if *local_reg != old_state {
-                                println!("Merged NEW update for {}", key);
-                                stored_value.last_updated = SystemTime::now();
-                            } else {
-                                println!("Ignored redundant update for {}", key);
-                            }
-                            },
-    
-                        _ => println!(
-                            "type mismatch: key exisits, but value is not of type PNCounter or AWSet"
-                        ),
-                    }
-                    stored_value.last_updated = SystemTime::now()
-                })
-                .or_insert_with(|| StoredValue {
-                    data: remote_crdt.clone(),
-                    last_updated: SystemTime::now(),
-                });
+            self.merge_remote(key, remote_crdt);
         }
         Ok(Response::new(GossipBatchResponse { success: (true) }))
     }
-}
-
-impl ReplicationServer {
-    pub async fn start_listener(&self) -> Result<()> {
-        let addr: SocketAddr = self.config.listen_address.as_str().parse()?;
-        Server::builder()
-            .add_service(ReplicationServiceServer::new(self.clone()))
-            .serve(addr)
-            .await?;
 
-        Ok(())
+    async fn gossip_digest(
+        &self,
+        request: tonic::Request<GossipDigestRequest>,
+    ) -> Result<tonic::Response<GossipDigestResponse>, tonic::Status> {
+        //a witness wants nothing, it holds no data
+        if self.config.witness {
+            return Ok(Response::new(GossipDigestResponse { wanted: Vec::new() }));
+        }
+        let digests = request.into_inner().digests;
+        for key in digests.keys() {
+            self.ensure_loaded(key);
+        }
+        Ok(Response::new(GossipDigestResponse {
+            wanted: digest::wanted(&self.store, &digests),
+        }))
     }
 
-    //// COUNTER HELPER FUNCTIONS
-    pub async fn handle_set_counter(
+    async fn reconcile(
         &self,
-        key: String,
-        raw_value_bytes: Vec<u8>,
-    ) -> Result<tonic::Response<PropagateDataResponse>, tonic::Status> {
-        //value shld be a u64
-        let bytes: [u8; 8] = raw_value_bytes.try_into().map_err(|_| {
-            tonic::Status::invalid_argument("invalid byte length for u64, expected 8 bytes")
-        })?;
+        request: tonic::Request<ReconcileRequest>,
+    ) -> Result<tonic::Response<ReconcileResponse>, tonic::Status> {
+        //a witness holds nothing, and its empty answer claims nothing either
+        if self.config.witness {
+            return Ok(Response::new(ReconcileResponse::default()));
+        }
+        let request = request.into_inner();
+        match self.merge_updates(request.updates) {
+            //picked for this node as it was before a restart, merged but nothing is claimed
+            Ok(_) if request.expected_origin != self.scuttlebutt.origin => {}
+            Ok(_) => self.scuttlebutt.learn(&request.complete.into_iter().collect()),
+            Err(e) => println!("Rejected an update from {}: {}", request.node_id, e),
+        }
+        let (updates, complete) = self.versioned_updates(&request.vector.into_iter().collect());
+        self.metrics
+            .incr("reconcile_updates_sent_total", updates.len() as u64);
+        Ok(Response::new(ReconcileResponse {
+            origin: self.scuttlebutt.origin.clone(),
+            vector: self.scuttlebutt.vector().into_iter().collect(),
+            updates,
+            complete: complete.into_iter().collect(),
+        }))
+    }
 
-        let numeric_val: u64 = u64::from_be_bytes(bytes);
+    async fn broadcast(
+        &self,
+        request: tonic::Request<BroadcastRequest>,
+    ) -> Result<tonic::Response<BroadcastResponse>, tonic::Status> {
+        //a witness is never pushed to on purpose, the sender should stop
+        if self.config.witness {
+            return Ok(Response::new(BroadcastResponse { prune: true }));
+        }
+        let request = request.into_inner();
+        let Some(message) = request.message else {
+            return Err(tonic::Status::invalid_argument("a broadcast needs a message"));
+        };
+        let first = plumtree::received(self, &request.node_id, message);
+        Ok(Response::new(BroadcastResponse { prune: !first }))
+    }
 
-        println!("received valid CSET: {}", numeric_val);
+    async fn announce(
+        &self,
+        request: tonic::Request<AnnounceRequest>,
+    ) -> Result<tonic::Response<AnnounceResponse>, tonic::Status> {
+        let request = request.into_inner();
+        if !self.config.witness {
+            plumtree::announced(self, &request.node_id, request.ids);
+        }
+        Ok(Response::new(AnnounceResponse {}))
+    }
 
-        let counter = PNCounter {
-            p: HashMap::from([(self.config.node_id.clone(), numeric_val)]),
-            n: HashMap::from([(self.config.node_id.clone(), 0)]),
-        };
+    async fn graft(
+        &self,
+        request: tonic::Request<GraftRequest>,
+    ) -> Result<tonic::Response<GraftResponse>, tonic::Status> {
+        let request = request.into_inner();
+        Ok(Response::new(GraftResponse {
+            messages: plumtree::grafted(self, &request.node_id, &request.ids),
+        }))
+    }
 
-        let new_pn: CRDTValue = CRDTValue::Counter(counter.clone());
-        self.store.insert(
-            key.clone(),
-            StoredValue {
-                data: new_pn,
-                last_updated: SystemTime::now(),
-            },
-        );
-        println!("Counter set!");
+    type ExportStream = std::pin::Pin<
+        Box<dyn tokio_stream::Stream<Item = Result<ExportChunk, tonic::Status>> + Send>,
+    >;
 
-        match self.push(key, CRDTValue::Counter(counter)).await {
-            Ok(_) => {}
-            Err(_) => {}
+    //chunks are read as the stream is taken, a slow reader holds the export back rather than
+    //piling the range up in memory
+    async fn export(
+        &self,
+        request: tonic::Request<ExportRequest>,
+    ) -> Result<tonic::Response<Self::ExportStream>, tonic::Status> {
+        let request = request.into_inner();
+        if self.config.witness {
+            return Err(tonic::Status::failed_precondition(format!(
+                "{} is a witness, it holds no data, export from another node",
+                self.config.node_id
+            )));
+        }
+        let range =
+            export::Range::from_request(&request).map_err(tonic::Status::invalid_argument)?;
+        let batch = match request.batch {
+            0 => export::DEFAULT_BATCH,
+            batch => (batch as usize).min(export::MAX_BATCH),
         };
 
-        //need to send an ack that the op has been done
-        Ok(Response::new(PropagateDataResponse {
-            success: true,
-            response: Vec::new(),
-        })) //send empty bytes for response
+        let (chunks, receiver) = tokio::sync::mpsc::channel(4);
+        let server = self.clone();
+        tokio::spawn(async move { export::run(&server, range, batch, &chunks).await });
+        Ok(Response::new(Box::pin(
+            tokio_stream::wrappers::ReceiverStream::new(receiver),
+        )))
     }
 
-    pub async fn handle_get_counter(
+    async fn sample(
         &self,
-        key: String,
-    ) -> Result<tonic::Response<PropagateDataResponse>, tonic::Status> {
-        println!("received valid CGET, get value of key: {}", key);
+        request: tonic::Request<SampleRequest>,
+    ) -> Result<tonic::Response<SampleResponse>, tonic::Status> {
+        let request = request.into_inner();
+        Ok(Response::new(SampleResponse {
+            states: audit::answer(self, &request.keys),
+        }))
+    }
 
-        let val = match self.store.get_mut(&key) {
-            Some(val) => val,
-            None => {
-                return Err(tonic::Status::not_found("The requested key was not found!"));
-            }
-        };
-        match &val.data {
-            CRDTValue::Counter(local_counter) => {
-                let value = local_counter.value();
-                println!("value is {}", value);
-                return Ok(Response::new(PropagateDataResponse {
-                    success: true,
-                    response: value.to_be_bytes().to_vec(),
-                }));
-            }
-            _ => println!("type mismatch: key exisits, but value is not of type PNCounter"),
+    async fn heartbeat(
+        &self,
+        request: tonic::Request<HeartbeatRequest>,
+    ) -> Result<tonic::Response<HeartbeatResponse>, tonic::Status> {
+        let remote = request.remote_addr().map(|addr| addr.ip());
+        let req_inner = request.into_inner();
+        let advertised = discovery::advertised_address(&req_inner.address, remote);
+        if let Err(reason) = self.membership.claim(
+            &req_inner.node_id,
+            &req_inner.instance,
+            advertised.as_deref(),
+        ) {
+            self.metrics.incr("duplicate_node_ids_total", 1);
+            eprintln!(
+                "error: refusing heartbeat from {}: {}",
+                advertised.as_deref().unwrap_or("an unknown address"),
+                reason
+            );
+            return Err(tonic::Status::already_exists(format!(
+                "{}, give this node another node_id or set node_id = \"auto\"",
+                reason
+            )));
         }
-        Ok(Response::new(PropagateDataResponse {
-            success: false,
-            response: Vec::new(),
+        //so a node that only has this one as its seed gets gossiped to as well
+        if let Some(address) = advertised {
+            self.discover(&req_inner.node_id, &address, "its heartbeat");
+        }
+        self.membership.observe(
+            req_inner.node_id,
+            None,
+            req_inner.reachable,
+            req_inner.format_version,
+            req_inner.witness,
+        );
+
+        Ok(Response::new(HeartbeatResponse {
+            node_id: self.config.node_id.clone(),
+            cluster_id: self.config.cluster_id().to_string(),
+            reachable: self.membership.reachable_ids(),
+            format_version: migrate::FORMAT_VERSION,
+            witness: self.config.witness,
+            peers: discovery::known_peers(&self.membership),
+            instance: self.membership.instance.clone(),
         }))
     }
 
-    pub async fn handle_inc_counter(
+    async fn cluster_status(
         &self,
-        key: String,
-        raw_value_bytes: Vec<u8>,
-    ) -> Result<tonic::Response<PropagateDataResponse>, tonic::Status> {
-        let bytes: [u8; 8] = raw_value_bytes.try_into().map_err(|_| {
-            tonic::Status::invalid_argument("invalid byte length for u64, expected 8 bytes")
-        })?;
-
-        let numeric_val: u64 = u64::from_be_bytes(bytes);
+        _request: tonic::Request<ClusterStatusRequest>,
+    ) -> Result<tonic::Response<ClusterStatusResponse>, tonic::Status> {
+        let mut members: Vec<MemberStatus> = self
+            .membership
+            .members
+            .iter()
+            .map(|entry| {
+                let address = entry.value().address.clone().unwrap_or_default();
+                let latency = self.metrics.latency(&address).unwrap_or_default();
+                let quantile_us =
+                    |q| latency.quantile(q).map(|d| d.as_micros() as u64).unwrap_or(0);
+                MemberStatus {
+                    node_id: entry.key().clone(),
+                    reachable: self.membership.is_reachable(entry.value()),
+                    last_seen_ms: entry.value().last_seen.elapsed().as_millis() as u64,
+                    format_version: entry.value().format_version,
+                    witness: entry.value().witness,
+                    latency_samples: latency.count(),
+                    latency_p50_us: quantile_us(0.5),
+                    latency_p95_us: quantile_us(0.95),
+                    latency_p99_us: quantile_us(0.99),
+                    address,
+                }
+            })
+            .collect();
+        members.sort_by(|a, b| a.node_id.cmp(&b.node_id));
+
+        let split_brain = self.split_brain.status();
+        Ok(Response::new(ClusterStatusResponse {
+            node_id: self.config.node_id.clone(),
+            members,
+            split_brain: split_brain.split_brain,
+            local_group: split_brain.local_group,
+            remote_group: split_brain.remote_group,
+            partitioned_for_secs: split_brain.partitioned_for.as_secs(),
+            last_divergence_estimate: split_brain.last_divergence,
+            majority: split_brain.majority,
+        }))
+    }
 
-        println!("received valid CINC, to increase by: {}", numeric_val);
+    type DecommissionStream = std::pin::Pin<
+        Box<dyn tokio_stream::Stream<Item = Result<DecommissionProgress, tonic::Status>> + Send>,
+    >;
 
-        let mut val = match self.store.get_mut(&key) {
-            Some(val) => val,
-            None => {
-                return Err(tonic::Status::not_found("The requested key was not found!"));
-            }
+    //the handover keeps going in the background, the stream only reports on it
+    async fn decommission(
+        &self,
+        request: tonic::Request<DecommissionRequest>,
+    ) -> Result<tonic::Response<Self::DecommissionStream>, tonic::Status> {
+        let request = request.into_inner();
+        if request.node_id != self.config.node_id {
+            return Err(tonic::Status::invalid_argument(format!(
+                "this is {}, not {}, send it to the node being decommissioned",
+                self.config.node_id, request.node_id
+            )));
+        }
+        if self.decommissioning.load(Ordering::SeqCst) {
+            return Err(tonic::Status::already_exists(format!(
+                "{} is already being decommissioned",
+                self.config.node_id
+            )));
+        }
+        let timeout = match request.timeout_secs {
+            0 => decommission::DEFAULT_TIMEOUT,
+            secs => Duration::from_secs(secs),
         };
-        match &mut val.data {
-            CRDTValue::Counter(local_counter) => {
-                local_counter.increment(self.config.node_id.clone(), numeric_val);
-                println!("Counter incremented by: {}", numeric_val);
 
-                match self
-                    .push(key, CRDTValue::Counter(local_counter.clone()))
-                    .await
-                {
-                    Ok(_) => {}
-                    Err(_) => {}
-                };
+        let (progress, receiver) = tokio::sync::mpsc::channel(64);
+        let server = self.clone();
+        tokio::spawn(async move {
+            match decommission::run(&server, timeout, &progress).await {
+                Ok(()) => {
+                    drop(progress);
+                    //long enough for the last progress message to make it out
+                    tokio::time::sleep(Duration::from_millis(500)).await;
+                    println!("decommissioned");
+                    //a permit is kept if nobody is waiting yet
+                    server.decommissioned.notify_one();
+                }
+                Err(status) => {
+                    eprintln!("decommission aborted: {}", status.message());
+                    let _ = progress.send(Err(status)).await;
+                }
+            }
+        });
+        Ok(Response::new(Box::pin(
+            tokio_stream::wrappers::ReceiverStream::new(receiver),
+        )))
+    }
 
-                return Ok(Response::new(PropagateDataResponse {
-                    success: true,
-                    response: Vec::new(),
-                }));
+    //a decommissioned peer is gone for good, it is dropped from gossip and from membership
+    async fn leave(
+        &self,
+        request: tonic::Request<LeaveRequest>,
+    ) -> Result<tonic::Response<LeaveResponse>, tonic::Status> {
+        let node_id = request.into_inner().node_id;
+        if let Some((_, member)) = self.membership.members.remove(&node_id) {
+            if let Some(address) = member.address {
+                self.peers.remove(&address);
+                self.pool.remove(&address);
             }
-            _ => println!("type mismatch: key exisits, but value is not of type PNCounter"),
         }
-        Ok(Response::new(PropagateDataResponse {
-            success: false,
-            response: Vec::new(),
-        }))
+        println!("{} left the cluster", node_id);
+        Ok(Response::new(LeaveResponse {}))
     }
 
-    pub async fn handle_dec_counter(
+    async fn pause(
         &self,
-        key: String,
-        raw_value_bytes: Vec<u8>,
-    ) -> Result<tonic::Response<PropagateDataResponse>, tonic::Status> {
-        let bytes: [u8; 8] = raw_value_bytes.try_into().map_err(|_| {
-            tonic::Status::invalid_argument("invalid byte length for u64, expected 8 bytes")
-        })?;
-
-        let numeric_val: u64 = u64::from_be_bytes(bytes);
-
-        println!("received valid CDEC, to decrease by: {}", numeric_val);
+        request: tonic::Request<PauseRequest>,
+    ) -> Result<tonic::Response<PauseResponse>, tonic::Status> {
+        let request = request.into_inner();
+        println!(
+            "received valid PAUSE ({}): {}",
+            if request.cluster { "cluster" } else { "node" },
+            request.reason
+        );
+        let seq = if request.cluster {
+            let reason = match request.reason.trim() {
+                "" => pause::NO_REASON,
+                reason => reason,
+            };
+            self.set_pause_register(reason).await?
+        } else {
+            self.paused.set(&request.reason);
+            0
+        };
+        Ok(Response::new(self.pause_response(seq)))
+    }
 
-        let mut val = match self.store.get_mut(&key) {
-            Some(val) => val,
+    async fn resume(
+        &self,
+        request: tonic::Request<ResumeRequest>,
+    ) -> Result<tonic::Response<PauseResponse>, tonic::Status> {
+        let request = request.into_inner();
+        println!(
+            "received valid RESUME ({})",
+            if request.cluster { "cluster" } else { "node" }
+        );
+        let seq = if request.cluster {
+            self.set_pause_register("").await?
+        } else {
+            self.paused.clear();
+            0
+        };
+        Ok(Response::new(self.pause_response(seq)))
+    }
+
+    async fn node_status(
+        &self,
+        _request: tonic::Request<NodeStatusRequest>,
+    ) -> Result<tonic::Response<NodeStatusResponse>, tonic::Status> {
+        let progress = self.recovery.progress();
+        Ok(Response::new(NodeStatusResponse {
+            node_id: self.config.node_id.clone(),
+            stage: progress.stage.to_string(),
+            entries: progress.entries,
+            done: progress.done,
+            total: progress.total,
+            elapsed_ms: progress.elapsed.as_millis() as u64,
+            eta_secs: progress.eta().map(|eta| eta.as_secs()),
+            paused: self.pause_refusal().unwrap_or_default(),
+        }))
+    }
+
+    async fn ping(
+        &self,
+        request: tonic::Request<PingRequest>,
+    ) -> Result<tonic::Response<PingResponse>, tonic::Status> {
+        let peers = match request.into_inner().peers {
+            true => self.ping_peers().await,
+            false => Vec::new(),
+        };
+        Ok(Response::new(PingResponse {
+            node_id: self.config.node_id.clone(),
+            peers,
+        }))
+    }
+
+    async fn fingerprint(
+        &self,
+        _request: tonic::Request<FingerprintRequest>,
+    ) -> Result<tonic::Response<FingerprintResponse>, tonic::Status> {
+        let fingerprint = fingerprint::of_store(&self.store);
+        Ok(Response::new(FingerprintResponse {
+            node_id: self.config.node_id.clone(),
+            fingerprint: fingerprint.digest,
+            keys: fingerprint.keys,
+            commit_seq: self.commit_seq.load(Ordering::SeqCst),
+        }))
+    }
+}
+
+impl ReplicationServer {
+    //everything a node runs on, with what data_dir holds loaded back. gossip_runtime is left
+    //to the caller, start() sets it
+    pub fn new(config: Config) -> Result<Self> {
+        let peers = DashMap::new();
+        for peer in &config.peers {
+            peers.insert(peer.address.clone(), SystemTime::UNIX_EPOCH);
+        }
+        let outbound = match &config.data_dir {
+            Some(dir) => Outbound::restore(dir)?,
+            None => Outbound::default(),
+        };
+        let metrics = Arc::new(Metrics::new());
+        Ok(ReplicationServer {
+            store: Arc::new(DashMap::new()),
+            peers: Arc::new(peers),
+            pool: Arc::new(DashMap::new()),
+            membership: Arc::new(Membership::new(config.node_id.clone(), config.peer_timeout)),
+            split_brain: Arc::new(SplitBrainDetector::new(config.split_brain_after)),
+            commit_seq: Arc::new(AtomicU64::new(0)),
+            persistence: Arc::new(Persistence::open(config.data_dir.clone())?),
+            script_lock: Arc::new(tokio::sync::RwLock::new(())),
+            decommissioning: Arc::new(AtomicBool::new(false)),
+            decommissioned: Arc::new(tokio::sync::Notify::new()),
+            paused: Arc::new(Pause::default()),
+            recovery: Arc::new(Recovery::default()),
+            gossip_runtime: None,
+            work_queue: Arc::new(WorkQueue::new(config.request_queue.clone())),
+            scuttlebutt: Arc::new(Scuttlebutt::new(&config.node_id)),
+            plumtree: Arc::new(Tree::new(&config.node_id)),
+            events: Arc::new(Events::default()),
+            breakers: Arc::new(Breakers::new(config.peer_breaker.clone(), metrics.clone())),
+            outbound: Arc::new(outbound),
+            wal: Arc::new(Wal::open(config.data_dir.clone(), config.wal_sync)?),
+            materializer: Arc::new(Materializer::restore(config.data_dir.clone())?),
+            metrics,
+            config: Arc::new(config),
+        })
+    }
+
+    pub async fn start_listener<H: Health>(&self, health: HealthServer<H>) -> Result<()> {
+        let client_addr = resolve(self.config.client_address()).await?;
+        let peer_addr = resolve(self.config.peer_address()).await?;
+        let split = client_addr != peer_addr;
+
+        if let Some(uds_path) = self.config.uds_path.clone() {
+            let server = self.clone();
+            let role = if split { Role::Client } else { Role::All };
+            let health = health.clone();
+            tokio::spawn(async move {
+                if let Err(e) = server.start_uds_listener(uds_path, role, health).await {
+                    eprintln!("uds listener failed: {e}");
+                }
+            });
+        }
+
+        if !split {
+            return serve(Listener::new(self.clone(), Role::All), health, client_addr).await;
+        }
+        println!("Accepting clients on {} and peers on {}", client_addr, peer_addr);
+        let clients = serve(Listener::new(self.clone(), Role::Client), health.clone(), client_addr);
+        let peers = serve(Listener::new(self.clone(), Role::Peer), health, peer_addr);
+        match &self.gossip_runtime {
+            Some(gossip) => {
+                let peers = gossip.spawn(peers);
+                tokio::try_join!(clients, async { peers.await? })?;
+            }
             None => {
-                return Err(tonic::Status::not_found("The requested key was not found!"));
+                tokio::try_join!(clients, peers)?;
             }
+        }
+        Ok(())
+    }
+
+    //local clients (sidecars etc) can skip tcp and talk over a unix socket instead, access
+    //to which is then governed by the socket file's permissions
+    pub async fn start_uds_listener<H: Health>(
+        &self,
+        uds_path: PathBuf,
+        role: Role,
+        health: HealthServer<H>,
+    ) -> Result<()> {
+        let listener = bind_uds(&uds_path, self.config.uds_mode)?;
+        println!("Accepting local clients on {}", uds_path.display());
+
+        let service = ReplicationServiceServer::new(Listener::new(self.clone(), role));
+        Server::builder()
+            .add_service(health)
+            .add_service(service)
+            .serve_with_incoming(UnixListenerStream::new(listener))
+            .await?;
+
+        Ok(())
+    }
+
+    //// COUNTER HELPER FUNCTIONS
+    //negative counters are fine. an existing counter is brought to the value through this
+    //node's own count (see PNCounter::checked_set), what other nodes counted, in the key or its
+    //shards, is left as it is, so increments made elsewhere concurrently still add to the value
+    pub async fn handle_set_counter(
+        &self,
+        key: String,
+        numeric_val: i64,
+    ) -> Result<tonic::Response<PropagateDataResponse>, tonic::Status> {
+        println!("received valid CSET: {}", numeric_val);
+
+        //what the key itself has to hold for the shards and it to add up to the value
+        let own = self
+            .shard_total(&key)
+            .and_then(|total| numeric_val.checked_sub(total))
+            .ok_or_else(|| tonic::Status::out_of_range("counter would overflow"))?;
+        let counter = {
+            let mut val = self.store.entry(key.clone()).or_insert_with(|| StoredValue {
+                data: CrdtValue::Counter(PNCounter::new(self.config.node_id.clone(), 0, 0)),
+                last_updated: SystemTime::now(),
+            });
+            //a key holding something else is left as it is, like CINC leaves it
+            let CrdtValue::Counter(local_counter) = &mut val.data else {
+                println!("type mismatch: key exisits, but value is not of type PNCounter");
+                return Ok(type_mismatch());
+            };
+            local_counter
+                .checked_set(self.config.node_id.clone(), own)
+                .ok_or_else(|| tonic::Status::out_of_range("counter would overflow"))?;
+            let counter = local_counter.clone();
+            val.last_updated = SystemTime::now();
+            counter
         };
-        match &mut val.data {
-            CRDTValue::Counter(local_counter) => {
-                local_counter.decrement(self.config.node_id.clone(), numeric_val);
-                println!("Counter decremented by: {}", numeric_val);
+        println!("Counter set!");
 
-                match self
-                    .push(key, CRDTValue::Counter(local_counter.clone()))
-                    .await
-                {
-                    Ok(_) => {}
-                    Err(_) => {}
-                };
+        let seq = self.commit(&key);
+        let replicas = self.push(key, CrdtValue::Counter(counter)).await;
+
+        //need to send an ack that the op has been done, with the value it left
+        Ok(Response::new(PropagateDataResponse {
+            success: true,
+            response: Vec::new(),
+            seq,
+            number: Some(numeric_val),
+            replicas: Some(replicas),
+        })) //send empty bytes for response
+    }
+
+    pub async fn handle_get_counter(
+        &self,
+        key: String,
+    ) -> Result<tonic::Response<PropagateDataResponse>, tonic::Status> {
+        println!("received valid CGET, get value of key: {}", key);
 
+        let value = match self.store.get(&key).as_deref() {
+            Some(StoredValue {
+                data: CrdtValue::Counter(local_counter),
+                ..
+            }) => local_counter.value(),
+            Some(_) => {
+                println!("type mismatch: key exisits, but value is not of type PNCounter");
                 return Ok(Response::new(PropagateDataResponse {
-                    success: true,
+                    success: false,
                     response: Vec::new(),
+                    seq: 0,
+                    number: None,
+                    replicas: None,
                 }));
             }
-            _ => println!("type mismatch: key exisits, but value is not of type PNCounter"),
+            None => {
+                return Err(tonic::Status::not_found("The requested key was not found!"));
+            }
+        };
+        //a sharded counter is the sum of its shards
+        let value = self
+            .shard_total(&key)
+            .and_then(|total| value.checked_add(total))
+            .ok_or_else(|| tonic::Status::out_of_range("counter overflowed summing its shards"))?;
+        println!("value is {}", value);
+        Ok(Response::new(PropagateDataResponse {
+            success: true,
+            response: wire::encode_i64(value),
+            seq: 0,
+            number: Some(value),
+            replicas: None,
+        }))
+    }
+
+    pub async fn handle_inc_counter(
+        &self,
+        key: String,
+        numeric_val: i64,
+    ) -> Result<tonic::Response<PropagateDataResponse>, tonic::Status> {
+        println!("received valid CINC, to increase by: {}", numeric_val);
+
+        //a negative increment is a decrement
+        self.add_counter(key, numeric_val).await
+    }
+
+    pub async fn handle_dec_counter(
+        &self,
+        key: String,
+        numeric_val: i64,
+    ) -> Result<tonic::Response<PropagateDataResponse>, tonic::Status> {
+        println!("received valid CDEC, to decrease by: {}", numeric_val);
+
+        //and a negative decrement an increment
+        let delta = numeric_val
+            .checked_neg()
+            .ok_or_else(|| tonic::Status::out_of_range("cannot decrement by i64::MIN"))?;
+        self.add_counter(key, delta).await
+    }
+
+    //CINC and CDEC, the delta goes to the key or, once CSHARD has spread it out, to one of its
+    //shards. the entry is let go before the push so writers don't queue behind the network
+    pub async fn add_counter(
+        &self,
+        key: String,
+        delta: i64,
+    ) -> Result<tonic::Response<PropagateDataResponse>, tonic::Status> {
+        let mismatch = || {
+            println!("type mismatch: key exisits, but value is not of type PNCounter");
+            type_mismatch()
+        };
+
+        let target = match shards::pick(self.counter_shards(&key)) {
+            0 => key.clone(),
+            shard => {
+                //a shard only takes writes while the key it belongs to is a counter
+                match self.store.get(&key).as_deref() {
+                    Some(StoredValue {
+                        data: CrdtValue::Counter(_),
+                        ..
+                    }) => {}
+                    Some(_) => return Ok(mismatch()),
+                    None => {
+                        return Err(tonic::Status::not_found("The requested key was not found!"))
+                    }
+                }
+                let shard_key = shards::shard_key(&key, shard);
+                self.ensure_loaded(&shard_key);
+                shard_key
+            }
+        };
+
+        let counter = {
+            let mut val = if target == key {
+                match self.store.get_mut(&key) {
+                    Some(val) => val,
+                    None => {
+                        return Err(tonic::Status::not_found("The requested key was not found!"));
+                    }
+                }
+            } else {
+                self.store
+                    .entry(target.clone())
+                    .or_insert_with(|| StoredValue {
+                        data: CrdtValue::Counter(PNCounter::new(self.config.node_id.clone(), 0, 0)),
+                        last_updated: SystemTime::now(),
+                    })
+            };
+            let CrdtValue::Counter(local_counter) = &mut val.data else {
+                return Ok(mismatch());
+            };
+            local_counter
+                .checked_add(self.config.node_id.clone(), delta)
+                .ok_or_else(|| tonic::Status::out_of_range("counter would overflow"))?;
+            let counter = local_counter.clone();
+            val.last_updated = SystemTime::now();
+            counter
+        };
+        println!("Counter {} changed by: {}", target, delta);
+        //the total as CGET would read it here now, None if the shards overflow summed up
+        let key_value = if target == key {
+            Some(counter.value())
+        } else {
+            match self.store.get(&key).as_deref() {
+                Some(StoredValue {
+                    data: CrdtValue::Counter(key_counter),
+                    ..
+                }) => Some(key_counter.value()),
+                _ => None,
+            }
+        };
+        let total = key_value
+            .zip(self.shard_total(&key))
+            .and_then(|(value, shards)| value.checked_add(shards));
+
+        let seq = self.commit(&target);
+        let replicas = self.push(target, CrdtValue::Counter(counter)).await;
+        Ok(Response::new(PropagateDataResponse {
+            success: true,
+            response: Vec::new(),
+            seq,
+            number: total,
+            replicas: Some(replicas),
+        }))
+    }
+
+    //how many entries the counter's writes are spread over, 1 for an unsharded one
+    pub fn counter_shards(&self, key: &str) -> usize {
+        let count_key = shards::count_key(key);
+        self.ensure_loaded(&count_key);
+        match self.store.get(&count_key).as_deref() {
+            Some(StoredValue {
+                data: CrdtValue::Register(register),
+                ..
+            }) => shards::parse(&register.get()).unwrap_or(1),
+            _ => 1,
+        }
+    }
+
+    //what the counter's shards other than the key itself add up to, None on overflow
+    pub fn shard_total(&self, key: &str) -> Option<i64> {
+        let mut total: i64 = 0;
+        for shard in 1..self.counter_shards(key) {
+            let shard_key = shards::shard_key(key, shard);
+            self.ensure_loaded(&shard_key);
+            if let Some(StoredValue {
+                data: CrdtValue::Counter(shard_counter),
+                ..
+            }) = self.store.get(&shard_key).as_deref()
+            {
+                total = total.checked_add(shard_counter.value())?;
+            }
+        }
+        Some(total)
+    }
+
+    //CSHARD <key> <n> spreads the counter's writes over n entries from now on
+    pub async fn handle_shard(
+        &self,
+        key: String,
+        raw_value_bytes: Vec<u8>,
+    ) -> Result<tonic::Response<PropagateDataResponse>, tonic::Status> {
+        let value = wire::decode_string(raw_value_bytes).map_err(malformed)?;
+        let shard_count = shards::parse(&value).map_err(tonic::Status::invalid_argument)?;
+        match self.store.get(&key).as_deref() {
+            Some(StoredValue {
+                data: CrdtValue::Counter(_),
+                ..
+            }) => {}
+            Some(stored) => {
+                return Err(tonic::Status::failed_precondition(format!(
+                    "{} is a {}, only counters can be sharded",
+                    key,
+                    stored.data.type_name()
+                )));
+            }
+            None => return Err(tonic::Status::not_found("The requested key was not found!")),
+        }
+        let current = self.counter_shards(&key);
+        if shard_count < current {
+            return Err(tonic::Status::failed_precondition(format!(
+                "{} already has {} shards, they hold part of its value so the count can't go down",
+                key, current
+            )));
         }
+        println!("received valid CSHARD: {} {}", key, shard_count);
+
+        let seq = self
+            .set_system_register(shards::count_key(&key), &shard_count.to_string())
+            .await?;
         Ok(Response::new(PropagateDataResponse {
-            success: false,
+            success: true,
             response: Vec::new(),
+            seq,
+            number: None,
+            replicas: None,
         }))
     }
 
@@ -601,98 +1348,150 @@ impl ReplicationServer {
     pub async fn handle_add_set(
         &self,
         key: String,
-        raw_value_bytes: Vec<u8>,
+        tag: Element,
     ) -> Result<tonic::Response<PropagateDataResponse>, tonic::Status> {
         
-        let tag = String::from_utf8(raw_value_bytes).map_err(|_| tonic::Status::invalid_argument("Invalid UTF-8 sequence for tag"))?;
-
         println!("received valid SADD, to add tag: {}", tag);
 
-        let mut stored_val = self.store.entry(key.clone()).or_insert_with(|| {
-            let set = AWSet {
-                clock: 0,
-                add_tags: HashMap::new(),
-                remove_tags: HashMap::new(),
-            };
-
-            println!("Set set!");
-
-            StoredValue {
-                data: CRDTValue::AWSet(set),
-                last_updated: SystemTime::now(),
-            }
-        });
+        let limit = self.set_limit(&key);
+        //the entry is let go before the commit and the push
+        let set = {
+            let mut stored_val = self.store.entry(key.clone()).or_insert_with(|| {
+                let set = AWSet::new();
 
-        match &mut stored_val.data {
-            CRDTValue::AWSet(set) => {
-                set.add(tag, self.config.node_id.clone()); //finally add the tag
+                println!("Set set!");
 
-                match self.push(key, CRDTValue::AWSet(set.clone())).await {
-                    //propagate
-                    Ok(_) => {}
-                    Err(_) => {}
+                StoredValue {
+                    data: CrdtValue::Set(set),
+                    last_updated: SystemTime::now(),
                 }
+            });
+            let CrdtValue::Set(set) = &mut stored_val.data else {
+                println!("type mismatch: key exisits, but value is not of type AWSet");
+                return Ok(type_mismatch());
+            };
+            set.add(tag, self.config.node_id.clone()); //finally add the tag
+            self.evict(&key, set, limit);
+            let set = set.clone();
+            stored_val.last_updated = SystemTime::now();
+            set
+        };
 
-                return Ok(Response::new(PropagateDataResponse {
-                    success: true,
-                    response: Vec::new(),
-                }));
-            }
-            _ => println!("type mismatch: key exisits, but value is not of type AWSet"),
-        }
-
+        let members = set.read().len() as i64;
+        let seq = self.commit(&key);
+        let replicas = self.push(key, CrdtValue::Set(set)).await;
         Ok(Response::new(PropagateDataResponse {
-            success: false,
+            success: true,
             response: Vec::new(),
+            seq,
+            number: Some(members),
+            replicas: Some(replicas),
         }))
     }
 
     pub async fn handle_rem_set(
         &self,
         key: String,
-        raw_value_bytes: Vec<u8>,
+        tag: Element,
     ) -> Result<tonic::Response<PropagateDataResponse>, tonic::Status> {
 
-        let tag = String::from_utf8(raw_value_bytes).map_err(|_| tonic::Status::invalid_argument("Invalid UTF-8 sequence for tag"))?;
-
         println!("received valid SREM, to remove tag: {}", tag);
 
-        //doesnt make sense to remove tag from key which does not exist
-        let mut stored_val = match self.store.get_mut(&key) {
+        let set = {
+            //doesnt make sense to remove tag from key which does not exist
+            let mut stored_val = match self.store.get_mut(&key) {
+                Some(val) => val,
+                None => {
+                    return Err(tonic::Status::not_found("The requested key was not found!"));
+                }
+            };
+            let CrdtValue::Set(set) = &mut stored_val.data else {
+                println!("type mismatch: key exisits, but value is not of type AWSet");
+                return Ok(type_mismatch());
+            };
+            set.remove(tag); //remove the tag
+            let set = set.clone();
+            stored_val.last_updated = SystemTime::now();
+            set
+        };
+
+        let members = set.read().len() as i64;
+        let seq = self.commit(&key);
+        let replicas = self.push(key, CrdtValue::Set(set)).await;
+        Ok(Response::new(PropagateDataResponse {
+            success: true,
+            response: Vec::new(),
+            seq,
+            number: Some(members),
+            replicas: Some(replicas),
+        }))
+    }
+
+    //what SREM would do, without doing it: the set before and after, and how many peers the
+    //change would be pushed to
+    pub async fn dry_run_rem_set(
+        &self,
+        key: String,
+        tag: Element,
+    ) -> Result<tonic::Response<PropagateDataResponse>, tonic::Status> {
+        println!("received valid SREM dry run, would remove tag: {}", tag);
+
+        let stored_val = match self.store.get(&key) {
             Some(val) => val,
             None => {
                 return Err(tonic::Status::not_found("The requested key was not found!"));
             }
         };
+        let CrdtValue::Set(set) = &stored_val.data else {
+            return Err(tonic::Status::failed_precondition(format!(
+                "{} is not a set",
+                key
+            )));
+        };
 
-        match &mut stored_val.data {
-            CRDTValue::AWSet(set) => {
-                set.remove(tag); //remove the tag
-
-                match self.push(key, CRDTValue::AWSet(set.clone())).await {
-                    //propagate
-                    Ok(_) => {}
-                    Err(_) => {}
-                }
-
-                return Ok(Response::new(PropagateDataResponse {
-                    success: true,
-                    response: Vec::new(),
-                }));
-            }
-            _ => println!("type mismatch: key exisits, but value is not of type AWSet"),
-        }
+        let current = set.read_sorted();
+        let changes = current.contains(&tag);
+        let after: Vec<serde_json::Value> = current
+            .iter()
+            .filter(|member| **member != tag)
+            .map(wire::element_to_json)
+            .collect();
+        let current: Vec<serde_json::Value> = current.iter().map(wire::element_to_json).collect();
+        let report = serde_json::json!({
+            "command": "SREM",
+            "affected_keys": if changes { vec![key.clone()] } else { Vec::new() },
+            "current": current,
+            "after": after,
+            "fanout": if changes { self.peers.len().min(self.fanout()) } else { 0 },
+        });
 
         Ok(Response::new(PropagateDataResponse {
-            success: false,
-            response: Vec::new(),
+            success: true,
+            response: serde_json::to_vec(&report).unwrap(),
+            seq: 0,
+            number: None,
+            replicas: None,
         }))
     }
 
+    //the value picks the order: ASC or DESC sort tags by Element's order (numbers by value),
+    //nothing leaves them unordered
     pub async fn handle_get_set(
         &self,
         key: String,
+        raw_value_bytes: Vec<u8>,
     ) -> Result<tonic::Response<PropagateDataResponse>, tonic::Status> {
+        let order = wire::decode_string(raw_value_bytes).map_err(malformed)?;
+        let descending = match order.to_ascii_uppercase().as_str() {
+            "" | "ASC" => false,
+            "DESC" => true,
+            _ => {
+                return Err(tonic::Status::invalid_argument(format!(
+                    "unknown SGET order {:?}, expected ASC or DESC",
+                    order
+                )))
+            }
+        };
         let stored_val = match self.store.get_mut(&key) {
             Some(val) => val,
             None => {
@@ -700,12 +1499,24 @@ impl ReplicationServer {
             }
         };
         match &stored_val.data {
-            CRDTValue::AWSet(set) => {
-                let value: Vec<_> = set.read().into_iter().collect();
+            CrdtValue::Set(set) => {
+                let mut value: Vec<Element> = if order.is_empty() {
+                    set.read().into_iter().collect()
+                } else {
+                    set.read_sorted()
+                };
+                if descending {
+                    value.reverse();
+                }
+                let value: Vec<serde_json::Value> =
+                    value.iter().map(wire::element_to_json).collect();
                 let response_bytes = serde_json::to_vec(&value).unwrap();
                 return Ok(Response::new(PropagateDataResponse {
                     success: true,
                     response: response_bytes,
+                    seq: 0,
+                    number: None,
+                    replicas: None,
                 }));
             }
             _ => println!("type mismatch: key exisits, but value is not of type AWSet"),
@@ -713,6 +1524,9 @@ impl ReplicationServer {
         Ok(Response::new(PropagateDataResponse {
             success: false,
             response: Vec::new(),
+            seq: 0,
+            number: None,
+            replicas: None,
         }))
     }
     
@@ -724,42 +1538,40 @@ impl ReplicationServer {
         raw_value_bytes: Vec<u8>,
     ) -> Result<tonic::Response<PropagateDataResponse>, tonic::Status> {
         
-        let register_value = String::from_utf8(raw_value_bytes).map_err(|_| tonic::Status::invalid_argument("Invalid UTF-8 sequence for tag"))?;
+        let register_value = wire::decode_string(raw_value_bytes).map_err(malformed)?;
 
         println!("received valid RSET, to set register: {}", register_value);
 
-        let mut stored_val = self.store.entry(key.clone()).or_insert_with(|| {
-            let register = LwwRegister::new(self.config.node_id.clone());
-
-            println!("Register set!");
-
-            StoredValue {
-                data: CRDTValue::LWWRegister(register),
-                last_updated: SystemTime::now(),
-            }
-        });
+        let reg = {
+            let mut stored_val = self.store.entry(key.clone()).or_insert_with(|| {
+                let register = LwwRegister::new(self.config.node_id.clone());
 
-        match &mut stored_val.data {
-            CRDTValue::LWWRegister(reg) => {
-                reg.set(register_value, self.config.node_id.clone());
+                println!("Register set!");
 
-                match self.push(key, CRDTValue::LWWRegister(reg.clone())).await {
-                    //propagate
-                    Ok(_) => {}
-                    Err(_) => {}
+                StoredValue {
+                    data: CrdtValue::Register(register),
+                    last_updated: SystemTime::now(),
                 }
+            });
+            let CrdtValue::Register(reg) = &mut stored_val.data else {
+                println!("type mismatch: key exisits, but value is not of type LWWRegister");
+                return Ok(type_mismatch());
+            };
+            reg.set(register_value, self.config.node_id.clone());
+            let reg = reg.clone();
+            stored_val.last_updated = SystemTime::now();
+            reg
+        };
 
-                return Ok(Response::new(PropagateDataResponse {
-                    success: true,
-                    response: Vec::new(),
-                }));
-            }
-            _ => println!("type mismatch: key exisits, but value is not of type LWWRegister"),
-        }
-
+        let len = register_len(&reg);
+        let seq = self.commit(&key);
+        let replicas = self.push(key, CrdtValue::Register(reg)).await;
         Ok(Response::new(PropagateDataResponse {
-            success: false,
+            success: true,
             response: Vec::new(),
+            seq,
+            number: Some(len),
+            replicas: Some(replicas),
         }))
     }
     
@@ -774,11 +1586,14 @@ impl ReplicationServer {
             }
         };
         match &stored_val.data {
-            CRDTValue::LWWRegister(reg) => {
+            CrdtValue::Register(reg) => {
                 let response_bytes = reg.get().into_bytes();
                 return Ok(Response::new(PropagateDataResponse {
                     success: true,
                     response: response_bytes,
+                    seq: 0,
+                    number: None,
+                    replicas: None,
                 }));
             }
             _ => println!("type mismatch: key exisits, but value is not of type LWWRegister"),
@@ -786,6 +1601,9 @@ impl ReplicationServer {
         Ok(Response::new(PropagateDataResponse {
             success: false,
             response: Vec::new(),
+            seq: 0,
+            number: None,
+            replicas: None,
         }))
     }
     
@@ -796,39 +1614,41 @@ impl ReplicationServer {
         raw_value_bytes: Vec<u8>,
     ) -> Result<tonic::Response<PropagateDataResponse>, tonic::Status> {
         
-        let register_value = String::from_utf8(raw_value_bytes).map_err(|_| tonic::Status::invalid_argument("Invalid UTF-8 sequence for tag"))?;
+        let register_value = wire::decode_string(raw_value_bytes).map_err(malformed)?;
 
         println!("received valid RAPP, to append register: {}", register_value);
 
-        let mut stored_val = match self.store.get_mut(&key) {
-            Some(val) => val,
-            None => {
-                return Err(tonic::Status::not_found("The requested key was not found!"));
-            }
-        };
-
-        match &mut stored_val.data {
-            CRDTValue::LWWRegister(reg) => {
-                reg.append(register_value, self.config.node_id.clone());
-
-                match self.push(key, CRDTValue::LWWRegister(reg.clone())).await {
-                    //propagate
-                    Ok(_) => {}
-                    Err(_) => {}
+        let reg = {
+            let mut stored_val = match self.store.get_mut(&key) {
+                Some(val) => val,
+                None => {
+                    return Err(tonic::Status::not_found("The requested key was not found!"));
                 }
-                stored_val.last_updated = SystemTime::now();
-                
-                return Ok(Response::new(PropagateDataResponse {
-                    success: true,
-                    response: Vec::new(),
-                }));
+            };
+            let CrdtValue::Register(reg) = &mut stored_val.data else {
+                println!("type mismatch: key exisits, but value is not of type LWWRegister");
+                return Ok(type_mismatch());
+            };
+            if wire::is_binary(&register_value) || wire::is_binary(&reg.get()) {
+                return Err(tonic::Status::failed_precondition(
+                    "RAPP only appends utf8 text to utf8 text, RSET binary values whole",
+                ));
             }
-            _ => println!("type mismatch: key exisits, but value is not of type LWWRegister"),
-        }
+            reg.append(register_value, self.config.node_id.clone());
+            let reg = reg.clone();
+            stored_val.last_updated = SystemTime::now();
+            reg
+        };
 
+        let len = register_len(&reg);
+        let seq = self.commit(&key);
+        let replicas = self.push(key, CrdtValue::Register(reg)).await;
         Ok(Response::new(PropagateDataResponse {
-            success: false,
+            success: true,
             response: Vec::new(),
+            seq,
+            number: Some(len),
+            replicas: Some(replicas),
         }))
     }
     
@@ -843,11 +1663,14 @@ impl ReplicationServer {
             }
         };
         match &stored_val.data {
-            CRDTValue::LWWRegister(reg) => {
-                let response_bytes = reg.strlen().to_be_bytes().to_vec();
+            CrdtValue::Register(reg) => {
+                let response_bytes = wire::encode_u64(register_len(reg) as u64);
                 return Ok(Response::new(PropagateDataResponse {
                     success: true,
                     response: response_bytes,
+                    seq: 0,
+                    number: None,
+                    replicas: None,
                 }));
             }
             _ => println!("type mismatch: key exisits, but value is not of type LWWRegister"),
@@ -855,199 +1678,2231 @@ impl ReplicationServer {
         Ok(Response::new(PropagateDataResponse {
             success: false,
             response: Vec::new(),
+            seq: 0,
+            number: None,
+            replicas: None,
         }))
     }
 
+    //// MAP HELPER FUNCTIONS
+    //sets one field of an lww map, or removes it for None. the entry is let go before the push
+    pub async fn handle_set_map_field(
+        &self,
+        key: String,
+        field: String,
+        value: Option<String>,
+    ) -> Result<tonic::Response<PropagateDataResponse>, tonic::Status> {
+        let map = {
+            let mut stored_val = self
+                .store
+                .entry(key.clone())
+                .or_insert_with(|| StoredValue {
+                    data: CrdtValue::Map(LwwMap::new()),
+                    last_updated: SystemTime::now(),
+                });
+            let CrdtValue::Map(map) = &mut stored_val.data else {
+                println!("type mismatch: key exisits, but value is not of type LwwMap");
+                return Ok(Response::new(PropagateDataResponse {
+                    success: false,
+                    response: Vec::new(),
+                    seq: 0,
+                    number: None,
+                    replicas: None,
+                }));
+            };
+            match value {
+                Some(value) => map.set(field, value, self.config.node_id.clone()),
+                None => map.remove(&field, self.config.node_id.clone()),
+            }
+            let map = map.clone();
+            stored_val.last_updated = SystemTime::now();
+            map
+        };
 
-    pub async fn push(&self, key: String, value: CRDTValue) -> Result<()> {
-        //send updates to k randomly chosen peers
-        //first make sure to preconnect to 3 randomly chosen peer nodes
-        //lots of things to think of, like what if a node goes down, how will this node reconnect to
-        //some other node etc, will tackle these later
+        let seq = self.commit(&key);
+        let replicas = self.push(key, CrdtValue::Map(map)).await;
+        Ok(Response::new(PropagateDataResponse {
+            success: true,
+            response: Vec::new(),
+            seq,
+            number: None,
+            replicas: Some(replicas),
+        }))
+    }
+
+    //// CUSTOM CRDT HELPER FUNCTIONS
+    //merges a state of a registered custom type in, the embedded node's only way to write one
+    pub async fn handle_merge_custom(
+        &self,
+        key: String,
+        value: CustomValue,
+    ) -> Result<tonic::Response<PropagateDataResponse>, tonic::Status> {
+        let custom = {
+            let mut stored_val = self.store.entry(key.clone()).or_insert_with(|| StoredValue {
+                data: CrdtValue::Custom(CustomValue {
+                    type_tag: value.type_tag.clone(),
+                    states: Vec::new(),
+                }),
+                last_updated: SystemTime::now(),
+            });
+            let CrdtValue::Custom(custom) = &mut stored_val.data else {
+                println!("type mismatch: key exisits, but value is not a {}", value.type_tag);
+                return Ok(type_mismatch());
+            };
+            if custom.type_tag != value.type_tag {
+                println!("type mismatch: key exisits, but value is not a {}", value.type_tag);
+                return Ok(type_mismatch());
+            }
+            custom.merge(&value);
+            let custom = custom.clone();
+            stored_val.last_updated = SystemTime::now();
+            custom
+        };
+
+        let seq = self.commit(&key);
+        let replicas = self.push(key, CrdtValue::Custom(custom)).await;
+        Ok(Response::new(PropagateDataResponse {
+            success: true,
+            response: Vec::new(),
+            seq,
+            number: None,
+            replicas: Some(replicas),
+        }))
+    }
+
+    //peers learnt outside the config (discovered ones) fall back to a plain http connection
+    //a client for the peer out of the pool, connecting first when it isn't pooled yet
+    pub async fn peer_client(&self, peer_addr: &str) -> Result<ReplicationServiceClient<Channel>> {
+        if let Some(client) = self.pool.get(peer_addr) {
+            return Ok(client.clone());
+        }
+        let client = self.connect_peer(peer_addr).await?;
+        self.pool.insert(peer_addr.to_string(), client.clone());
+        Ok(client)
+    }
+
+    //the peer's connect_timeout_ms, or the default for peers without one and discovered ones
+    fn connect_timeout(&self, peer_addr: &str) -> Duration {
+        self.config
+            .peer(peer_addr)
+            .and_then(|peer| peer.connect_timeout_ms)
+            .map(Duration::from_millis)
+            .unwrap_or(DEFAULT_CONNECT_TIMEOUT)
+    }
+
+    //one round trip to every peer at once, each gets as long to answer as when connecting at
+    //startup. the time includes connecting to a peer that isn't pooled yet
+    pub async fn ping_peers(&self) -> Vec<PeerPing> {
+        let mut pinging = tokio::task::JoinSet::new();
+        for entry in self.peers.iter() {
+            let (server, peer_addr) = (self.clone(), entry.key().clone());
+            pinging.spawn(async move {
+                let started = Instant::now();
+                let ping = async {
+                    let mut client = server.peer_client(&peer_addr).await?;
+                    let pong = client
+                        .ping(Request::new(PingRequest { peers: false }))
+                        .await?;
+                    anyhow::Ok(pong.into_inner().node_id)
+                };
+                let timeout = server.connect_timeout(&peer_addr);
+                let answered = tokio::time::timeout(timeout, ping).await;
+                let (node_id, error) = match answered {
+                    Ok(Ok(node_id)) => (node_id, String::new()),
+                    Ok(Err(e)) => (String::new(), e.root_cause().to_string()),
+                    Err(_) => (String::new(), format!("no answer within {:?}", timeout)),
+                };
+                PeerPing {
+                    address: peer_addr,
+                    node_id,
+                    rtt_us: started.elapsed().as_micros() as u64,
+                    error,
+                }
+            });
+        }
+
+        let mut pings = Vec::new();
+        while let Some(joined) = pinging.join_next().await {
+            if let Ok(ping) = joined {
+                pings.push(ping);
+            }
+        }
+        pings.sort_by(|a, b| a.address.cmp(&b.address));
+        pings
+    }
+
+    //connects to every peer at once when the node starts, so the first write doesn't wait on a
+    //handshake and a peer that is down shows up in the log straight away. a peer that can't be
+    //reached is left to the gossip loop, which keeps trying
+    pub async fn connect_all_peers(&self) {
+        let started = Instant::now();
+        let mut connecting = tokio::task::JoinSet::new();
+        for entry in self.peers.iter() {
+            let (server, peer_addr) = (self.clone(), entry.key().clone());
+            connecting.spawn(async move {
+                let timeout = server.connect_timeout(&peer_addr);
+                let connect = server.connect_peer(&peer_addr);
+                let connected = match tokio::time::timeout(timeout, connect).await {
+                    Ok(Ok(client)) => {
+                        server.pool.insert(peer_addr.clone(), client);
+                        Ok(())
+                    }
+                    Ok(Err(e)) => Err(e.root_cause().to_string()),
+                    Err(_) => Err(format!("no answer within {:?}", timeout)),
+                };
+                if connected.is_err() {
+                    server.breakers.record_failure(&peer_addr);
+                }
+                (peer_addr, connected)
+            });
+        }
+
+        let (mut total, mut failed) = (0, Vec::new());
+        while let Some(joined) = connecting.join_next().await {
+            total += 1;
+            if let Ok((peer_addr, Err(e))) = joined {
+                failed.push(format!("{} ({})", peer_addr, e));
+            }
+        }
+        if total == 0 {
+            return;
+        }
+        failed.sort();
+        println!(
+            "connected to {} of {} peers in {:?}",
+            total - failed.len(),
+            total,
+            started.elapsed()
+        );
+        for failure in &failed {
+            println!("  unreachable: {}", failure);
+        }
+    }
+
+    async fn connect_peer(&self, peer_addr: &str) -> Result<ReplicationServiceClient<Channel>> {
+        let peer = match self.config.peer(peer_addr) {
+            Some(peer) => peer.clone(),
+            None => PeerConfig::new(peer_addr.to_string()),
+        };
+        match &self.gossip_runtime {
+            //a connection's tasks run on the runtime it was made on, so its i/o stays on the
+            //gossip threads whoever sends over it
+            Some(gossip) => gossip.spawn(async move { peer::connect(&peer).await }).await?,
+            None => peer::connect(&peer).await,
+        }
+    }
+
+    //gossips with a node learnt from a heartbeat from now on, unless it is this node, fenced
+    //off or already gossiped with at some address
+    fn discover(&self, node_id: &str, address: &str, through: &str) {
+        if !self.config.discover_peers
+            || node_id.is_empty()
+            || node_id == self.config.node_id
+            || address == self.config.peer_address()
+            || self.peers.contains_key(address)
+            || split_host_port(address).is_err()
+        {
+            return;
+        }
+        if let Some(member) = self.membership.members.get(node_id) {
+            if member.address.as_deref().is_some_and(|known| self.peers.contains_key(known)) {
+                return;
+            }
+        }
+        let ip = discovery::address_ip(address);
+        if self.config.gossip_refusal(node_id, ip).is_some() {
+            return;
+        }
+        println!("discovered {} at {} through {}", node_id, address, through);
+        self.metrics.incr("peers_discovered_total", 1);
+        self.peers.insert(address.to_string(), SystemTime::UNIX_EPOCH);
+    }
+
+    //a discovered peer that can't be reached and hasn't answered for peer_timeout is let go,
+    //it is only found again if some node still hears from it. configured peers are kept
+    fn forget_if_discovered(&self, peer_addr: &str) {
+        if self.config.peer(peer_addr).is_some() {
+            return;
+        }
+        let silent = self
+            .peers
+            .get(peer_addr)
+            .map(|seen| seen.elapsed().unwrap_or(Duration::ZERO) >= self.config.peer_timeout)
+            .unwrap_or(false);
+        if silent {
+            println!("forgetting discovered peer {}, it stopped answering", peer_addr);
+            self.peers.remove(peer_addr);
+            self.pool.remove(peer_addr);
+        }
+    }
+
+    //why gossip to peer_addr, answering as node_id, would loop back here or repeat a route
+    fn loopback_or_duplicate(&self, peer_addr: &str, node_id: &str) -> Option<String> {
+        if node_id == self.config.node_id {
+            return Some(String::from("is this node"));
+        }
+        let member = self.membership.members.get(node_id)?;
+        let known_addr = member.address.as_deref()?;
+        if known_addr != peer_addr
+            && self.peers.contains_key(known_addr)
+            && self.membership.is_reachable(&member)
+        {
+            return Some(format!("is {}, already reached through {}", node_id, known_addr));
+        }
+        None
+    }
+
+    //the next commit sequence number. every change to this node's store takes one, local
+    //writes and merged in gossip alike, so a consumer reading one node sees a single order
+    pub fn commit(&self, key: &str) -> u64 {
+        self.commit_as(key, None)
+    }
+
+    //a change merged in from a scuttlebutt peer keeps the versions it came with, anything
+    //else is a new update of this node's
+    fn commit_as(&self, key: &str, versions: Option<&Vector>) -> u64 {
+        match versions {
+            Some(versions) => self.scuttlebutt.merge(key, versions),
+            None => self.scuttlebutt.local(key),
+        }
+        self.metrics.record_mutation(key);
+        self.split_brain.record_change(key);
+        let seq = self.wal.record(key, &self.commit_seq);
+        self.events.changed(key);
+        seq
+    }
+
+    //holds a write's answer back until its wal record is as durable as wal_sync asks for
+    pub async fn when_durable(
+        &self,
+        response: Result<tonic::Response<PropagateDataResponse>, tonic::Status>,
+    ) -> Result<tonic::Response<PropagateDataResponse>, tonic::Status> {
+        if let Ok(response) = &response {
+            self.wal.wait_durable(response.get_ref().seq).await;
+        }
+        response
+    }
+
+    //merges state gossiped by a peer into the local value for the key
+    pub fn merge_remote(&self, key: String, remote_crdt: CrdtValue) {
+        self.merge_versioned(key, remote_crdt, None)
+    }
+
+    fn merge_versioned(&self, key: String, mut remote_crdt: CrdtValue, versions: Option<&Vector>) {
+        if self.was_expired(&key, &remote_crdt) {
+            println!("Ignored expired state for {}", key);
+            self.metrics.incr("gossip_expired_total", 1);
+            return;
+        }
+        //looked up first, the key's entry stays locked while it is merged
+        let limit = match remote_crdt {
+            CrdtValue::Set(_) => self.set_limit(&key),
+            _ => None,
+        };
+        let mut changed = true;
+        self.store
+            .entry(key.clone())
+            .and_modify(|stored_value| match stored_value.data.merge_with(&remote_crdt) {
+                Ok(true) => {
+                    if let CrdtValue::Set(set) = &mut stored_value.data {
+                        self.evict(&key, set, limit);
+                    }
+                    self.commit_as(&key, versions);
+                    println!("Merged NEW update for {}", key);
+                    self.metrics.incr("gossip_merges_total", 1);
+                    stored_value.last_updated = SystemTime::now();
+                }
+                Ok(false) => {
+                    //held already, but this node may not have known it was
+                    if let Some(versions) = versions {
+                        self.scuttlebutt.merge(&key, versions);
+                    }
+                    changed = false;
+                    println!("Ignored redundant update for {}", key);
+                    self.metrics.incr("gossip_redundant_total", 1);
+                }
+                Err(e) => {
+                    changed = false;
+                    println!("{} for {}", e, key);
+                    self.events.conflict(&key, &stored_value.data, &remote_crdt);
+                }
+            })
+            .or_insert_with(|| {
+                if let CrdtValue::Set(set) = &mut remote_crdt {
+                    self.evict(&key, set, limit);
+                }
+                self.commit_as(&key, versions);
+                StoredValue {
+                    data: remote_crdt.clone(),
+                    last_updated: SystemTime::now(),
+                }
+            });
+        //a limit that was just merged in applies to its set straight away
+        if let Some(limited) = set_limit::limited_key(&key) {
+            self.trim_set(limited);
+        }
+        if changed {
+            self.forward_renamed(&key);
+            self.drop_expired(&key);
+        }
+    }
+
+    //brings a key in from the snapshot if it is still waiting there. merged rather than
+    //inserted, gossip for the key may have arrived in the meantime
+    pub fn ensure_loaded(&self, key: &str) {
+        let value = match self.persistence.take(key) {
+            Ok(Some(value)) => value,
+            Ok(None) => return,
+            Err(e) => {
+                eprintln!("failed to load {} from the snapshot: {:#}", key, e);
+                return;
+            }
+        };
+        match self.store.entry(key.to_string()) {
+            Entry::Occupied(mut stored) => match stored.get_mut().data.merge_with(&value) {
+                Ok(true) => self.scuttlebutt.local(key),
+                Ok(false) => {}
+                Err(e) => eprintln!("{} for {} in the snapshot", e, key),
+            },
+            Entry::Vacant(vacant) => {
+                let peer_addrs: Vec<String> =
+                    self.peers.iter().map(|entry| entry.key().clone()).collect();
+                vacant.insert(StoredValue {
+                    data: value,
+                    last_updated: self.outbound.loaded_stamp(&peer_addrs),
+                });
+                self.scuttlebutt.local(key);
+            }
+        }
+    }
+
+    //loads the configured preload prefixes from the snapshot, the node reports itself as
+    //serving once this returns
+    pub fn preload(&self) {
+        if self.config.preload.is_empty() {
+            return;
+        }
+        let keys = self.persistence.pending_keys(&self.config.preload);
+        for key in &keys {
+            self.ensure_loaded(key);
+        }
+        println!("Preloaded {} keys", keys.len());
+    }
+
+    //loads whatever preload and client commands haven't, a chunk at a time so that serving
+    //clients isn't held up
+    pub async fn warm_up(&self) {
+        let keys = self.persistence.pending_keys(&[]);
+        if !keys.is_empty() {
+            self.recovery.start(Stage::WarmUp, keys.len() as u64);
+        }
+        for chunk in keys.chunks(WARM_UP_CHUNK) {
+            for key in chunk {
+                self.ensure_loaded(key);
+            }
+            self.recovery.advance(chunk.len() as u64, 0);
+            tokio::task::yield_now().await;
+        }
+        self.recovery.finish();
+        println!(
+            "Store is warm, {} keys loaded from the snapshot",
+            self.persistence.loaded.load(Ordering::SeqCst)
+        );
+    }
+
+    pub async fn snapshot_periodically(&self) {
+        let interval = self.config.snapshot_every.max(Duration::from_secs(1));
+        loop {
+            tokio::time::sleep(interval).await;
+            let store = self.store.clone();
+            let persistence = self.persistence.clone();
+            let wal = self.wal.clone();
+            //the log is rotated first, so everything in the rotated part is in the snapshot
+            let written = tokio::task::spawn_blocking(move || {
+                wal.rotate()?;
+                persistence.write_snapshot(&store)
+            })
+            .await;
+            match written {
+                Ok(Ok(keys)) => {
+                    self.metrics.set_gauge("snapshot_keys", keys as i64);
+                    self.save_outbound();
+                    if let Err(e) = self.wal.drop_rotated() {
+                        eprintln!("failed to drop the rotated wal: {:#}", e);
+                    }
+                }
+                Ok(Err(e)) => eprintln!("snapshot failed: {:#}", e),
+                Err(e) => eprintln!("snapshot task failed: {}", e),
+            }
+        }
+    }
+
+    //rewrites the materialized files whose keys changed, once the store is warm so that a
+    //file never drops keys that just haven't been loaded yet
+    pub async fn materialize_periodically(&self) {
+        loop {
+            tokio::time::sleep(MATERIALIZE_EVERY).await;
+            self.refresh_materialized().await;
+        }
+    }
+
+    async fn refresh_materialized(&self) {
+        if !self.persistence.is_warm() {
+            return;
+        }
+        let (materializer, store) = (self.materializer.clone(), self.store.clone());
+        if let Err(e) = tokio::task::spawn_blocking(move || materializer.refresh(&store)).await {
+            eprintln!("materialize task failed: {}", e);
+        }
+    }
+
+    //what every peer is owed, saved next to the snapshot that was just written
+    fn save_outbound(&self) {
+        let Some(dir) = self.persistence.data_dir.as_deref() else {
+            return;
+        };
+        let schedule = Schedule::new(self.settings().gossip_interval, &self.config.gossip_priority);
+        let peer_addrs: Vec<String> = self.peers.iter().map(|entry| entry.key().clone()).collect();
+        let owed = self.outbound.collect(&self.store, &peer_addrs, &schedule);
+        if let Err(e) = Outbound::save(dir, &owed) {
+            eprintln!("failed to save what peers are owed: {:#}", e);
+        }
+    }
+
+    //samples every watched counter once a window and reports contributions whose rate of
+    //change jumps away from their history
+    pub async fn watch_counters(&self, detector: AnomalyDetector) {
+        let window = detector.rules.window;
+        loop {
+            tokio::time::sleep(window).await;
+            let now = Instant::now();
+            let samples: Vec<(String, HashMap<String, i64>)> = self
+                .store
+                .iter()
+                .filter(|entry| detector.watches(entry.key()))
+                .filter_map(|entry| match &entry.value().data {
+                    CrdtValue::Counter(counter) => {
+                        Some((entry.key().clone(), counter.value_by_node()))
+                    }
+                    _ => None,
+                })
+                .collect();
+
+            for (key, contributions) in samples {
+                for (contributor, value) in contributions {
+                    let Some(anomaly) = detector.observe(&key, &contributor, value, now) else {
+                        continue;
+                    };
+                    self.metrics.incr("counter_anomalies_total", 1);
+                    eprintln!(
+                        "WARNING: {}'s share of {} is changing at {:.1}/s, usually {:.1}/s",
+                        contributor, key, anomaly.rate, anomaly.mean
+                    );
+                    webhook::notify(
+                        detector.rules.webhook.clone(),
+                        anomaly.to_event(&self.config.node_id, detector.rules.sigma),
+                    );
+                }
+            }
+        }
+    }
+
+    //why a client write of `size` bytes to the key can't run, for PropagateData and for an
+    //embedded node's typed writes
+    pub fn refuse_write(&self, key: &str, size: usize) -> Option<tonic::Status> {
+        let max_value_size = self.settings().max_value_size;
+        if size as u64 > max_value_size {
+            return Some(tonic::Status::invalid_argument(format!(
+                "value is {} bytes, max_value_size is {}",
+                size,
+                units::size::format(max_value_size)
+            )));
+        }
+        if freeze::is_marker(key) {
+            return Some(tonic::Status::invalid_argument(format!(
+                "{} is a freeze marker, use FREEZE/THAW",
+                key
+            )));
+        }
+        if settings::is_setting(key) {
+            return Some(tonic::Status::invalid_argument(format!(
+                "{} is a cluster setting, use SETTING",
+                key
+            )));
+        }
+        if rename::is_marker(key) {
+            return Some(tonic::Status::invalid_argument(format!(
+                "{} is a rename marker, use RENAME",
+                key
+            )));
+        }
+        if expiry::is_marker(key) {
+            return Some(tonic::Status::invalid_argument(format!(
+                "{} is an expiry marker, only label_rules write it",
+                key
+            )));
+        }
+        if is_internal(key) {
+            return Some(tonic::Status::invalid_argument(format!(
+                "{} is a label or a set limit, use LABEL/SLIMIT",
+                key
+            )));
+        }
+        if self.is_frozen(key) {
+            self.metrics.incr("frozen_writes_refused_total", 1);
+            return Some(tonic::Status::failed_precondition(format!(
+                "{} is frozen, THAW it first",
+                key
+            )));
+        }
+        if let Some(target) = self.renamed_to(key) {
+            return Some(tonic::Status::failed_precondition(format!(
+                "{} was renamed to {}",
+                key, target
+            )));
+        }
+        None
+    }
+
+    //why nothing may change the store right now, a pause only counts for data writes
+    pub fn refuse_change(&self, writes_data: bool) -> Option<tonic::Status> {
+        if self.decommissioning.load(Ordering::SeqCst) {
+            return Some(tonic::Status::failed_precondition(format!(
+                "{} is being decommissioned, write to another node",
+                self.config.node_id
+            )));
+        }
+        if writes_data {
+            if let Some(reason) = self.pause_refusal() {
+                self.metrics.incr("paused_writes_refused_total", 1);
+                return Some(tonic::Status::unavailable(reason));
+            }
+        }
+        None
+    }
+
+    //// FREEZE HELPER FUNCTIONS
+    pub fn is_frozen(&self, key: &str) -> bool {
+        let marker = freeze::marker_key(key);
+        self.ensure_loaded(&marker);
+        match self.store.get(&marker).as_deref() {
+            Some(StoredValue {
+                data: CrdtValue::Register(register),
+                ..
+            }) => register.get() == freeze::FROZEN,
+            _ => false,
+        }
+    }
+
+    //the key RENAME moved this one to, None unless it was renamed
+    pub fn renamed_to(&self, key: &str) -> Option<String> {
+        let marker = rename::marker_key(key);
+        self.ensure_loaded(&marker);
+        match self.store.get(&marker).as_deref() {
+            Some(StoredValue {
+                data: CrdtValue::Register(register),
+                ..
+            }) if !register.get().is_empty() => Some(register.get()),
+            _ => None,
+        }
+    }
+
+    //why client writes are refused right now, None while they are taken
+    pub fn pause_refusal(&self) -> Option<String> {
+        self.ensure_loaded(pause::KEY);
+        let cluster = match self.store.get(pause::KEY).as_deref() {
+            Some(StoredValue {
+                data: CrdtValue::Register(register),
+                ..
+            }) => Some(register.get()).filter(|reason| !reason.is_empty()),
+            _ => None,
+        };
+        pause::refusal(
+            &self.config.node_id,
+            self.paused.reason().as_deref(),
+            cluster.as_deref(),
+        )
+    }
+
+    //the cluster wide pause is written like FREEZE writes its marker, so the same nodes refuse it
+    async fn set_pause_register(&self, reason: &str) -> Result<u64, tonic::Status> {
+        if self.config.witness {
+            return Err(tonic::Status::failed_precondition(format!(
+                "{} is a witness, it holds no data, send it to another node",
+                self.config.node_id
+            )));
+        }
+        if self.decommissioning.load(Ordering::SeqCst) {
+            return Err(tonic::Status::failed_precondition(format!(
+                "{} is being decommissioned, send it to another node",
+                self.config.node_id
+            )));
+        }
+        self.set_system_register(pause::KEY.to_string(), reason).await
+    }
+
+    fn pause_response(&self, seq: u64) -> PauseResponse {
+        PauseResponse {
+            node_id: self.config.node_id.clone(),
+            seq,
+            paused: self.pause_refusal().unwrap_or_default(),
+        }
+    }
+
+    pub async fn handle_freeze(
+        &self,
+        key: String,
+        frozen: bool,
+    ) -> Result<tonic::Response<PropagateDataResponse>, tonic::Status> {
+        if key.is_empty() || freeze::is_marker(&key) {
+            return Err(tonic::Status::invalid_argument(format!(
+                "{:?} can't be frozen",
+                key
+            )));
+        }
+        println!(
+            "received valid {}: {}",
+            if frozen { "FREEZE" } else { "THAW" },
+            key
+        );
+
+        let state = if frozen { freeze::FROZEN } else { "" };
+        let seq = self
+            .set_system_register(freeze::marker_key(&key), state)
+            .await?;
+
+        Ok(Response::new(PropagateDataResponse {
+            success: true,
+            response: Vec::new(),
+            seq,
+            number: None,
+            replicas: None,
+        }))
+    }
+
+    //freeze markers and settings are lww registers only the node itself writes, returns the
+    //commit sequence number of the write
+    async fn set_system_register(&self, key: String, value: &str) -> Result<u64, tonic::Status> {
+        self.ensure_loaded(&key);
+        let register = {
+            let mut stored_val = self
+                .store
+                .entry(key.clone())
+                .or_insert_with(|| StoredValue {
+                    data: CrdtValue::Register(LwwRegister::new(self.config.node_id.clone())),
+                    last_updated: SystemTime::now(),
+                });
+            let CrdtValue::Register(register) = &mut stored_val.data else {
+                return Err(tonic::Status::internal(format!(
+                    "{} is not a register",
+                    key
+                )));
+            };
+            register.set(value.to_string(), self.config.node_id.clone());
+            let register = register.clone();
+            stored_val.last_updated = SystemTime::now();
+            register
+        };
+
+        let seq = self.commit(&key);
+        self.push(key, CrdtValue::Register(register)).await;
+        Ok(seq)
+    }
+
+    //// COPY AND RENAME HELPER FUNCTIONS
+    //COPY <src> <dst>, see rename. dst may already hold a value of the same type, src's state is
+    //merged into it
+    pub async fn handle_copy(
+        &self,
+        src: String,
+        raw_value_bytes: Vec<u8>,
+    ) -> Result<tonic::Response<PropagateDataResponse>, tonic::Status> {
+        let dst = wire::decode_string(raw_value_bytes).map_err(malformed)?;
+        println!("received valid COPY: {} {}", src, dst);
+
+        let (seq, replicas) = self.copy_key(&src, &dst).await?;
+        Ok(Response::new(PropagateDataResponse {
+            success: true,
+            response: Vec::new(),
+            seq,
+            number: None,
+            replicas: Some(replicas),
+        }))
+    }
+
+    //RENAME <src> <dst>, a COPY and then src's marker. the seq is the marker's
+    pub async fn handle_rename(
+        &self,
+        src: String,
+        raw_value_bytes: Vec<u8>,
+    ) -> Result<tonic::Response<PropagateDataResponse>, tonic::Status> {
+        let dst = wire::decode_string(raw_value_bytes).map_err(malformed)?;
+        println!("received valid RENAME: {} {}", src, dst);
+        if let Some(refusal) = self.refuse_write(&src, 0) {
+            return Err(refusal);
+        }
+
+        let (_, replicas) = self.copy_key(&src, &dst).await?;
+        let seq = self
+            .set_system_register(rename::marker_key(&src), &dst)
+            .await?;
+        Ok(Response::new(PropagateDataResponse {
+            success: true,
+            response: Vec::new(),
+            seq,
+            number: None,
+            replicas: Some(replicas),
+        }))
+    }
+
+    //merges src's state into dst and pushes dst, returns the commit sequence number of the write
+    async fn copy_key(&self, src: &str, dst: &str) -> Result<(u64, Replicas), tonic::Status> {
+        if src == dst {
+            return Err(tonic::Status::invalid_argument(format!(
+                "{} can't be copied onto itself",
+                src
+            )));
+        }
+        if is_internal(src) {
+            return Err(tonic::Status::invalid_argument(format!(
+                "{} is kept by the node itself, it can't be copied",
+                src
+            )));
+        }
+        if let Some(refusal) = self.refuse_write(dst, 0) {
+            return Err(refusal);
+        }
+        if let Some(target) = self.renamed_to(src) {
+            return Err(tonic::Status::not_found(format!(
+                "{} was renamed to {}",
+                src, target
+            )));
+        }
+        //the shards hold part of the value under other keys, merging them into one counter
+        //would lose what they add up to
+        if self.counter_shards(src) > 1 {
+            return Err(tonic::Status::failed_precondition(format!(
+                "{} is sharded, its shards can't be copied along",
+                src
+            )));
+        }
+        self.ensure_loaded(src);
+        self.ensure_loaded(dst);
+        let state = match self.store.get(src).as_deref() {
+            Some(stored) => stored.data.clone(),
+            None => return Err(tonic::Status::not_found("The requested key was not found!")),
+        };
+
+        let limit = match state {
+            CrdtValue::Set(_) => self.set_limit(dst),
+            _ => None,
+        };
+        let copied = {
+            let mut stored_val = self
+                .store
+                .entry(dst.to_string())
+                .or_insert_with(|| StoredValue {
+                    data: state.clone(),
+                    last_updated: SystemTime::now(),
+                });
+            stored_val.data.merge_with(&state).map_err(|e| {
+                tonic::Status::failed_precondition(format!("{}, {} can't go into {}", e, src, dst))
+            })?;
+            if let CrdtValue::Set(set) = &mut stored_val.data {
+                self.evict(dst, set, limit);
+            }
+            stored_val.last_updated = SystemTime::now();
+            stored_val.data.clone()
+        };
+
+        let seq = self.commit(dst);
+        let replicas = self.push(dst.to_string(), copied).await;
+        Ok((seq, replicas))
+    }
+
+    //a renamed key that took in something new passes it on, see rename
+    fn forward_renamed(&self, key: &str) {
+        let Some(target) = self.renamed_to(key) else {
+            return;
+        };
+        let Some(state) = self.store.get(key).map(|stored| stored.data.clone()) else {
+            return;
+        };
+        self.merge_remote(target, state);
+    }
+
+    //// LABEL HELPER FUNCTIONS
+    //what a key's label registers are stored under, removed labels included
+    fn label_keys(&self, key: &str) -> Vec<String> {
+        let prefix = labels::label_key(key, "");
+        for label_key in self.persistence.pending_keys(std::slice::from_ref(&prefix)) {
+            self.ensure_loaded(&label_key);
+        }
+        self.store
+            .iter()
+            .filter(|entry| entry.key().starts_with(&prefix))
+            .map(|entry| entry.key().clone())
+            //the prefix also covers the labels of keys that go on past a '=', eg a=b's for a
+            .filter(|label_key| {
+                labels::parse_label_key(label_key).is_some_and(|(labelled, _)| labelled == key)
+            })
+            .collect()
+    }
+
+    //all of a key's labels
+    pub fn labels(&self, key: &str) -> BTreeMap<String, String> {
+        self.label_keys(key)
+            .iter()
+            .filter_map(|label_key| {
+                let (_, name) = labels::parse_label_key(label_key)?;
+                Some((name.to_string(), self.label(key, name)?))
+            })
+            .collect()
+    }
+
+    fn label(&self, key: &str, name: &str) -> Option<String> {
+        let label_key = labels::label_key(key, name);
+        self.ensure_loaded(&label_key);
+        match self.store.get(&label_key).as_deref() {
+            Some(StoredValue {
+                data: CrdtValue::Register(register),
+                ..
+            }) if !register.get().is_empty() => Some(register.get()),
+            _ => None,
+        }
+    }
+
+    //only the labels a selector looks at, matching a key doesn't need the rest
+    pub fn selected_labels(&self, key: &str, selector: &Selector) -> BTreeMap<String, String> {
+        selector
+            .names()
+            .filter_map(|name| Some((name.to_string(), self.label(key, name)?)))
+            .collect()
+    }
+
+    //one POST per label rule with a webhook that selects the key, for a write a client sent here
+    pub fn notify_label_rules(&self, key: &str, command: &str, seq: u64) {
+        for rule in &self.config.label_rules {
+            if rule.webhook.is_none() {
+                continue;
+            }
+            let labels = self.selected_labels(key, &rule.selector);
+            if !rule.selector.matches(&labels) {
+                continue;
+            }
+            webhook::notify(
+                rule.webhook.clone(),
+                serde_json::json!({
+                    "event": "labelled_key_written",
+                    "node_id": self.config.node_id,
+                    "key": key,
+                    "command": command,
+                    "selector": rule.selector.to_string(),
+                    "labels": labels,
+                    "seq": seq,
+                }),
+            );
+        }
+    }
+
+    //drops the keys a label rule's retention says have been idle for too long, once the store
+    //is warm so that last_updated is what was stored rather than when the key was loaded
+    pub async fn expire_periodically(&self) {
+        loop {
+            tokio::time::sleep(EXPIRE_EVERY).await;
+            if self.persistence.is_warm() {
+                self.expire_idle_keys(SystemTime::now()).await;
+            }
+        }
+    }
+
+    pub async fn expire_idle_keys(&self, now: SystemTime) -> usize {
+        let rules: Vec<(&Selector, Duration)> = self
+            .config
+            .label_rules
+            .iter()
+            .filter_map(|rule| Some((&rule.selector, rule.retention?)))
+            .collect();
+        if rules.is_empty() {
+            return 0;
+        }
+        let candidates: Vec<(String, SystemTime)> = self
+            .store
+            .iter()
+            .filter(|entry| !is_internal(entry.key()))
+            .map(|entry| (entry.key().clone(), entry.value().last_updated))
+            .collect();
+
+        let mut expired = 0;
+        for (key, last_updated) in candidates {
+            let idle = now.duration_since(last_updated).unwrap_or_default();
+            let Some((selector, retention)) = rules.iter().find(|(selector, retention)| {
+                idle >= *retention && selector.matches(&self.selected_labels(&key, selector))
+            }) else {
+                continue;
+            };
+            //a write that came in since it was looked at keeps the key
+            let Some((_, dropped)) = self
+                .store
+                .remove_if(&key, |_, stored| stored.last_updated == last_updated)
+            else {
+                continue;
+            };
+            let mut markers = vec![(key.clone(), dropped.data)];
+            for label_key in self.label_keys(&key) {
+                if let Some((label_key, label)) = self.store.remove(&label_key) {
+                    markers.push((label_key, label.data));
+                }
+            }
+            //so the other nodes drop it too, rather than gossiping it back
+            for (dropped_key, state) in markers {
+                let digest = expiry::digest(&dropped_key, &state);
+                if let Err(e) = self
+                    .set_system_register(expiry::marker_key(&dropped_key), &digest)
+                    .await
+                {
+                    eprintln!("failed to mark {} expired: {}", dropped_key, e);
+                }
+            }
+            expired += 1;
+            self.metrics.incr("keys_expired_total", 1);
+            println!(
+                "{} expired, it matches {} and was idle for over {}",
+                key,
+                selector,
+                humantime::format_duration(*retention)
+            );
+        }
+        expired
+    }
+
+    //what an expiry marker says was dropped for the key, None unless it expired
+    fn expired_digest(&self, key: &str) -> Option<String> {
+        let marker = expiry::marker_key(key);
+        self.ensure_loaded(&marker);
+        match self.store.get(&marker).as_deref() {
+            Some(StoredValue {
+                data: CrdtValue::Register(register),
+                ..
+            }) if !register.get().is_empty() => Some(register.get()),
+            _ => None,
+        }
+    }
+
+    //state gossiped for a key that isn't held here and is exactly what expired, see expiry
+    fn was_expired(&self, key: &str, remote_crdt: &CrdtValue) -> bool {
+        if expiry::is_marker(key) || self.store.contains_key(key) {
+            return false;
+        }
+        self.expired_digest(key)
+            .is_some_and(|digest| digest == expiry::digest(key, remote_crdt))
+    }
+
+    //an expiry marker merged in drops this node's copy of the key, if it is what expired
+    fn drop_expired(&self, marker: &str) {
+        let Some(key) = expiry::expired_key(marker) else {
+            return;
+        };
+        let Some(digest) = self.expired_digest(key) else {
+            return;
+        };
+        if self
+            .store
+            .remove_if(key, |key, stored| {
+                expiry::digest(key, &stored.data) == digest
+            })
+            .is_some()
+            && !is_internal(key)
+        {
+            println!("{} expired on another node", key);
+            self.metrics.incr("keys_expired_total", 1);
+        }
+    }
+
+    //// SET LIMIT HELPER FUNCTIONS
+    pub fn set_limit(&self, key: &str) -> Option<usize> {
+        let limit_key = set_limit::limit_key(key);
+        self.ensure_loaded(&limit_key);
+        match self.store.get(&limit_key).as_deref() {
+            Some(StoredValue {
+                data: CrdtValue::Register(register),
+                ..
+            }) => set_limit::parse(&register.get()).ok().flatten(),
+            _ => None,
+        }
+    }
+
+    //trims a set down to its limit, returns what was evicted
+    fn evict(&self, key: &str, set: &mut AWSet, limit: Option<usize>) -> Vec<Element> {
+        let Some(limit) = limit else {
+            return Vec::new();
+        };
+        let evicted = set.trim(limit);
+        if !evicted.is_empty() {
+            self.metrics.incr("set_evictions_total", evicted.len() as u64);
+            println!(
+                "evicted {} elements from {}, it is limited to {}",
+                evicted.len(),
+                key,
+                limit
+            );
+        }
+        evicted
+    }
+
+    //applies the key's limit to the set already stored under it. an eviction is committed like
+    //any other change and gossiped with the next round, None when nothing was evicted
+    fn trim_set(&self, key: &str) -> Option<(Vec<Element>, u64)> {
+        let limit = self.set_limit(key);
+        self.ensure_loaded(key);
+        let mut stored = self.store.get_mut(key)?;
+        let CrdtValue::Set(set) = &mut stored.data else {
+            return None;
+        };
+        let evicted = self.evict(key, set, limit);
+        if evicted.is_empty() {
+            return None;
+        }
+        stored.last_updated = SystemTime::now();
+        drop(stored);
+        Some((evicted, self.commit(key)))
+    }
+
+    //SLIMIT <key> <n> caps the set at n elements, evicting the oldest right away, without n the
+    //limit is lifted. answers with the limit and what was evicted
+    pub async fn handle_set_limit(
+        &self,
+        key: String,
+        raw_value_bytes: Vec<u8>,
+    ) -> Result<tonic::Response<PropagateDataResponse>, tonic::Status> {
+        let value = wire::decode_string(raw_value_bytes).map_err(malformed)?;
+        let limit = set_limit::parse(&value).map_err(tonic::Status::invalid_argument)?;
+        if let Some(stored) = self.store.get(&key) {
+            if !matches!(stored.data, CrdtValue::Set(_)) {
+                return Err(tonic::Status::failed_precondition(format!(
+                    "{} is a {}, only sets can be limited",
+                    key,
+                    stored.data.type_name()
+                )));
+            }
+        }
+        println!("received valid SLIMIT: {} {:?}", key, limit);
+
+        let mut seq = self
+            .set_system_register(set_limit::limit_key(&key), value.trim())
+            .await?;
+        let mut evicted = Vec::new();
+        if let Some((trimmed, trimmed_at)) = self.trim_set(&key) {
+            (evicted, seq) = (trimmed, trimmed_at);
+            let value = self.store.get(&key).map(|stored| stored.data.clone());
+            if let Some(value) = value {
+                self.push(key, value).await;
+            }
+        }
+        let evicted: Vec<serde_json::Value> = evicted.iter().map(wire::element_to_json).collect();
+        Ok(Response::new(PropagateDataResponse {
+            success: true,
+            response: serde_json::to_vec(&serde_json::json!({
+                "limit": limit,
+                "evicted": evicted,
+            }))
+            .unwrap(),
+            seq,
+            number: None,
+            replicas: None,
+        }))
+    }
+
+    //// SETTINGS HELPER FUNCTIONS
+    fn stored_setting(&self, setting: Setting) -> Option<String> {
+        let key = setting.key();
+        self.ensure_loaded(&key);
+        match self.store.get(&key).as_deref() {
+            Some(StoredValue {
+                data: CrdtValue::Register(register),
+                ..
+            }) if !register.get().is_empty() => Some(register.get()),
+            _ => None,
+        }
+    }
+
+    //looked up on every use rather than cached, so a setting merged from a peer applies at once
+    pub fn settings(&self) -> Settings {
+        Settings::resolve(&self.config, &self.tuning(), |setting| {
+            self.stored_setting(setting)
+        })
+    }
+
+    //what the config leaves out, for the cluster as big as it is now
+    pub fn tuning(&self) -> Tuning {
+        Tuning::for_cluster(tuning::cluster_size(self))
+    }
+
+    //how many peers a client write is pushed to straight away
+    pub fn fanout(&self) -> usize {
+        self.config.fanout.unwrap_or_else(|| self.tuning().fanout)
+    }
+
+    //the key names the setting, an empty value hands it back to each node's config
+    pub async fn handle_setting(
+        &self,
+        name: String,
+        raw_value_bytes: Vec<u8>,
+    ) -> Result<tonic::Response<PropagateDataResponse>, tonic::Status> {
+        let setting = name
+            .parse::<Setting>()
+            .map_err(tonic::Status::invalid_argument)?;
+        let value = wire::decode_string(raw_value_bytes).map_err(malformed)?;
+        let value = value.trim();
+        if !value.is_empty() {
+            setting
+                .validate(value)
+                .map_err(tonic::Status::invalid_argument)?;
+        }
+        println!("received valid SETTING: {} = {:?}", name, value);
+
+        let seq = self.set_system_register(setting.key(), value).await?;
+        Ok(Response::new(PropagateDataResponse {
+            success: true,
+            response: Vec::new(),
+            seq,
+            number: None,
+            replicas: None,
+        }))
+    }
+
+    pub async fn handle_settings(
+        &self,
+    ) -> Result<tonic::Response<PropagateDataResponse>, tonic::Status> {
+        let report = settings::report(&self.config, &self.tuning(), |setting| {
+            self.stored_setting(setting)
+        });
+        Ok(Response::new(PropagateDataResponse {
+            success: true,
+            response: serde_json::to_vec(&report).unwrap(),
+            seq: 0,
+            number: None,
+            replicas: None,
+        }))
+    }
+
+    //// SERVER HELPER FUNCTIONS
+    //runs a script with nothing else in between, then merges what it wrote into the store and
+    //pushes it like any other write. a script that fails leaves the store as it was
+    pub async fn handle_eval(
+        &self,
+        raw_value_bytes: Vec<u8>,
+    ) -> Result<tonic::Response<PropagateDataResponse>, tonic::Status> {
+        let source = wire::decode_string(raw_value_bytes).map_err(malformed)?;
+        let settings = self.settings();
+        let limits = script::Limits {
+            max_operations: settings.script_max_operations,
+            timeout: settings.script_timeout,
+            max_value_size: settings.max_value_size,
+        };
+
+        let _exclusive = self.script_lock.write().await;
+        let server = self.clone();
+        let outcome = tokio::task::spawn_blocking(move || script::run(&server, &source, limits))
+            .await
+            .map_err(|e| tonic::Status::internal(format!("script panicked: {}", e)))?
+            .map_err(|e| {
+                self.metrics.incr("script_errors_total", 1);
+                tonic::Status::invalid_argument(e)
+            })?;
+        self.metrics.incr("scripts_total", 1);
+
+        let seq = self.apply_script_writes(outcome.written).await?;
+        Ok(Response::new(PropagateDataResponse {
+            success: true,
+            response: serde_json::to_vec(&outcome.result).unwrap(),
+            seq,
+            number: None,
+            replicas: None,
+        }))
+    }
+
+    //merges what a script wrote into the store and pushes it, returns the seq of the last
+    //commit. gossip may have changed a key's type while the script ran, every key is checked
+    //before any is merged so a script that can't be applied whole isn't applied at all
+    pub async fn apply_script_writes(&self, written: Vec<Written>) -> Result<u64, tonic::Status> {
+        for written in &written {
+            self.ensure_loaded(&written.key);
+            if let Some(stored) = self.store.get(&written.key) {
+                if let Err(e) = stored.data.check_merge(&written.value) {
+                    return Err(tonic::Status::failed_precondition(format!(
+                        "{} for {}, the script wasn't applied",
+                        e, written.key
+                    )));
+                }
+            }
+        }
+
+        let mut merged = Vec::new();
+        for written in written {
+            let key = written.key;
+            let limit = self.set_limit(&key);
+            let value = match self.store.entry(key.clone()) {
+                Entry::Occupied(mut stored) => {
+                    let stored = stored.get_mut();
+                    if let Err(e) = stored.data.merge_with(&written.value) {
+                        return Err(tonic::Status::internal(format!("{} for {}", e, key)));
+                    }
+                    if let CrdtValue::Set(set) = &mut stored.data {
+                        self.evict(&key, set, limit);
+                    }
+                    stored.last_updated = SystemTime::now();
+                    stored.data.clone()
+                }
+                entry => {
+                    let mut value = written.value;
+                    if let CrdtValue::Set(set) = &mut value {
+                        self.evict(&key, set, limit);
+                    }
+                    entry.insert(StoredValue {
+                        data: value.clone(),
+                        last_updated: SystemTime::now(),
+                    });
+                    value
+                }
+            };
+            merged.push((key, value));
+        }
+
+        let mut seq = 0;
+        for (key, value) in merged {
+            seq = self.commit(&key);
+            self.push(key, value).await;
+        }
+        Ok(seq)
+    }
+
+    pub async fn handle_info(
+        &self,
+        raw_value_bytes: Vec<u8>,
+    ) -> Result<tonic::Response<PropagateDataResponse>, tonic::Status> {
+        //the value optionally names a single section
+        let section = wire::decode_string(raw_value_bytes).map_err(malformed)?;
+        let section = if section.is_empty() {
+            None
+        } else {
+            Some(section.as_str())
+        };
+
+        let sections = info::collect(self, section).ok_or_else(|| {
+            tonic::Status::invalid_argument(format!(
+                "unknown INFO section, expected one of {:?}",
+                info::SECTIONS
+            ))
+        })?;
+
+        let response_bytes = serde_json::to_vec(&sections).unwrap();
+        Ok(Response::new(PropagateDataResponse {
+            success: true,
+            response: response_bytes,
+            seq: 0,
+            number: None,
+            replicas: None,
+        }))
+    }
+
+    //the value optionally says how many keys, 10 unless given
+    pub async fn handle_hotkeys(
+        &self,
+        raw_value_bytes: Vec<u8>,
+    ) -> Result<tonic::Response<PropagateDataResponse>, tonic::Status> {
+        let count = wire::decode_string(raw_value_bytes).map_err(malformed)?;
+        let count = match count.trim() {
+            "" => 10,
+            count => count.parse::<usize>().map_err(|_| {
+                tonic::Status::invalid_argument(format!("{:?} is not a number of keys", count))
+            })?,
+        };
+        if count > hotkeys::TRACKED {
+            return Err(tonic::Status::invalid_argument(format!(
+                "at most {} hot keys are tracked",
+                hotkeys::TRACKED
+            )));
+        }
+
+        let hot: Vec<_> = self
+            .metrics
+            .hot_keys
+            .top(count)
+            .into_iter()
+            .map(
+                |hot| serde_json::json!({"key": hot.key, "reads": hot.reads, "writes": hot.writes}),
+            )
+            .collect();
+        Ok(Response::new(PropagateDataResponse {
+            success: true,
+            response: serde_json::to_vec(&hot).unwrap(),
+            seq: 0,
+            number: None,
+            replicas: None,
+        }))
+    }
+
+    //a write waits here for the key's store entry when another one holds it, so CONTENTION can
+    //tell for how long. the handler takes the entry again right after
+    fn wait_for_entry(&self, key: &str) {
+        if !matches!(self.store.try_get_mut(key), TryResult::Locked) {
+            return;
+        }
+        let started = Instant::now();
+        drop(self.store.get_mut(key));
+        self.metrics.contention.record_wait(key, started.elapsed());
+    }
+
+    //CONTENTION [n], the keys whose writes waited on each other the most, see contention
+    pub async fn handle_contention(
+        &self,
+        raw_value_bytes: Vec<u8>,
+    ) -> Result<tonic::Response<PropagateDataResponse>, tonic::Status> {
+        let count = wire::decode_string(raw_value_bytes).map_err(malformed)?;
+        let count = match count.trim() {
+            "" => 10,
+            count => count.parse::<usize>().map_err(|_| {
+                tonic::Status::invalid_argument(format!("{:?} is not a number of keys", count))
+            })?,
+        };
+        if count > contention::TRACKED {
+            return Err(tonic::Status::invalid_argument(format!(
+                "at most {} contended keys are tracked",
+                contention::TRACKED
+            )));
+        }
+
+        let worst: Vec<_> = self
+            .metrics
+            .contention
+            .worst(count)
+            .into_iter()
+            .map(|stats| {
+                serde_json::json!({
+                    "key": stats.key,
+                    "waits": stats.waits,
+                    "wait_total_us": stats.wait_total.as_micros() as u64,
+                    "wait_max_us": stats.wait_max.as_micros() as u64,
+                    "peak_writers": stats.peak_writers,
+                })
+            })
+            .collect();
+        Ok(Response::new(PropagateDataResponse {
+            success: true,
+            response: serde_json::to_vec(&worst).unwrap(),
+            seq: 0,
+            number: None,
+            replicas: None,
+        }))
+    }
+
+    //the keys no peer has taken for longer than the watchdog's stalled_after, the longest
+    //stalled first, see watchdog
+    pub async fn handle_audit(
+        &self,
+    ) -> Result<tonic::Response<PropagateDataResponse>, tonic::Status> {
+        let stalled_after = self
+            .config
+            .watchdog
+            .clone()
+            .unwrap_or_default()
+            .stalled_after;
+        let stalled = watchdog::check(self, stalled_after);
+        let listed: Vec<_> = stalled
+            .iter()
+            .take(watchdog::MAX_LISTED)
+            .map(|stall| stall.to_json())
+            .collect();
+        Ok(Response::new(PropagateDataResponse {
+            success: true,
+            response: serde_json::to_vec(&serde_json::json!({
+                "stalled_after_secs": stalled_after.as_secs(),
+                "stalled": stalled.len(),
+                "keys": listed,
+            }))
+            .unwrap(),
+            seq: 0,
+            number: None,
+            replicas: None,
+        }))
+    }
+
+    //MATERIALIZE <prefix> <path> starts keeping a file on this node in step with the prefix,
+    //without a path it only lists what is exported. answers with the exports running
+    pub async fn handle_materialize(
+        &self,
+        prefix: String,
+        raw_value_bytes: Vec<u8>,
+    ) -> Result<tonic::Response<PropagateDataResponse>, tonic::Status> {
+        let path = wire::decode_string(raw_value_bytes).map_err(malformed)?;
+        if !path.is_empty() {
+            let export = self
+                .materializer
+                .start(&prefix, Path::new(&path))
+                .map_err(|e| tonic::Status::invalid_argument(format!("{:#}", e)))?;
+            println!(
+                "materializing {:?} to {}",
+                export.prefix,
+                export.path.display()
+            );
+            //written right away rather than on the next round
+            self.refresh_materialized().await;
+        }
+        self.materialized_response().await
+    }
+
+    //UNMATERIALIZE <path>, the file stays as it was last written
+    pub async fn handle_unmaterialize(
+        &self,
+        path: String,
+    ) -> Result<tonic::Response<PropagateDataResponse>, tonic::Status> {
+        let stopped = self
+            .materializer
+            .stop(Path::new(&path))
+            .map_err(|e| tonic::Status::internal(format!("{:#}", e)))?;
+        if !stopped {
+            return Err(tonic::Status::not_found(format!(
+                "nothing is materialized to {}",
+                path
+            )));
+        }
+        self.materialized_response().await
+    }
+
+    //LABEL <key> name=value[,name=value...], an empty value removes that label. answers with
+    //all the key's labels
+    pub async fn handle_label(
+        &self,
+        key: String,
+        raw_value_bytes: Vec<u8>,
+    ) -> Result<tonic::Response<PropagateDataResponse>, tonic::Status> {
+        if key.is_empty() || is_internal(&key) {
+            return Err(tonic::Status::invalid_argument(format!(
+                "{:?} can't be labelled",
+                key
+            )));
+        }
+        let raw = wire::decode_string(raw_value_bytes).map_err(malformed)?;
+        let assignments =
+            labels::parse_assignments(&raw).map_err(tonic::Status::invalid_argument)?;
+        println!("received valid LABEL: {} {}", key, raw);
+
+        let mut seq = 0;
+        for (name, value) in assignments {
+            seq = self
+                .set_system_register(labels::label_key(&key, &name), &value)
+                .await?;
+        }
+        Ok(Response::new(PropagateDataResponse {
+            success: true,
+            response: serde_json::to_vec(&self.labels(&key)).unwrap(),
+            seq,
+            number: None,
+            replicas: None,
+        }))
+    }
+
+    pub async fn handle_labels(
+        &self,
+        key: String,
+    ) -> Result<tonic::Response<PropagateDataResponse>, tonic::Status> {
+        Ok(Response::new(PropagateDataResponse {
+            success: true,
+            response: serde_json::to_vec(&self.labels(&key)).unwrap(),
+            seq: 0,
+            number: None,
+            replicas: None,
+        }))
+    }
+
+    //SCAN <prefix> <selector>, the keys under the prefix whose labels match, sorted. keys still
+    //waiting in the snapshot count too, node-internal keys don't
+    pub async fn handle_scan(
+        &self,
+        prefix: String,
+        raw_value_bytes: Vec<u8>,
+    ) -> Result<tonic::Response<PropagateDataResponse>, tonic::Status> {
+        let raw = wire::decode_string(raw_value_bytes).map_err(malformed)?;
+        let selector: Selector = raw.parse().map_err(tonic::Status::invalid_argument)?;
+        let mut keys: Vec<String> = self
+            .store
+            .iter()
+            .map(|entry| entry.key().clone())
+            .chain(self.persistence.pending_keys(std::slice::from_ref(&prefix)))
+            .filter(|key| key.starts_with(&prefix) && !is_internal(key))
+            .collect();
+        keys.sort();
+        keys.dedup();
+        keys.retain(|key| {
+            self.renamed_to(key).is_none()
+                && selector.matches(&self.selected_labels(key, &selector))
+        });
+
+        Ok(Response::new(PropagateDataResponse {
+            success: true,
+            response: serde_json::to_vec(&keys).unwrap(),
+            seq: 0,
+            number: None,
+            replicas: None,
+        }))
+    }
+
+    async fn materialized_response(
+        &self,
+    ) -> Result<tonic::Response<PropagateDataResponse>, tonic::Status> {
+        Ok(Response::new(PropagateDataResponse {
+            success: true,
+            response: serde_json::to_vec(&self.materializer.list()).unwrap(),
+            seq: 0,
+            number: None,
+            replicas: None,
+        }))
+    }
+
+    pub async fn handle_dbsize(
+        &self,
+    ) -> Result<tonic::Response<PropagateDataResponse>, tonic::Status> {
+        //labels, markers and the like aren't keys anyone wrote
+        let size = self
+            .store
+            .iter()
+            .filter(|entry| !is_internal(entry.key()))
+            .count() as u64;
+        Ok(Response::new(PropagateDataResponse {
+            success: true,
+            response: wire::encode_u64(size),
+            seq: 0,
+            number: None,
+            replicas: None,
+        }))
+    }
+
+    //who added a tag and when, one entry per add that still keeps the tag visible
+    pub async fn handle_meta_set(
+        &self,
+        key: String,
+        tag: Element,
+    ) -> Result<tonic::Response<PropagateDataResponse>, tonic::Status> {
+        let stored_val = match self.store.get(&key) {
+            Some(val) => val,
+            None => {
+                return Err(tonic::Status::not_found("The requested key was not found!"));
+            }
+        };
+        match &stored_val.data {
+            CrdtValue::Set(set) => {
+                let meta: Vec<_> = set
+                    .metadata(&tag)
+                    .into_iter()
+                    .map(|meta| {
+                        serde_json::json!({
+                            "added_by": meta.added_by,
+                            "counter": meta.counter,
+                            "added_at": meta.added_at,
+                            "added_at_ms": hlc::physical_ms(meta.added_at),
+                        })
+                    })
+                    .collect();
+                let response_bytes = serde_json::to_vec(&meta).unwrap();
+                Ok(Response::new(PropagateDataResponse {
+                    success: true,
+                    response: response_bytes,
+                    seq: 0,
+                    number: None,
+                    replicas: None,
+                }))
+            }
+            _ => Err(tonic::Status::failed_precondition(
+                "type mismatch: key exisits, but value is not of type AWSet",
+            )),
+        }
+    }
+
+    //the key field carries a prefix here, an empty one lists everything
+    pub async fn handle_keys(
+        &self,
+        prefix: String,
+    ) -> Result<tonic::Response<PropagateDataResponse>, tonic::Status> {
+        let mut keys: Vec<String> = self
+            .store
+            .iter()
+            .filter(|entry| entry.key().starts_with(&prefix) && !is_internal(entry.key()))
+            .map(|entry| entry.key().clone())
+            .collect();
+        keys.sort();
+
+        let response_bytes = serde_json::to_vec(&keys).unwrap();
+        Ok(Response::new(PropagateDataResponse {
+            success: true,
+            response: response_bytes,
+            seq: 0,
+            number: None,
+            replicas: None,
+        }))
+    }
+
+    pub async fn handle_type(
+        &self,
+        key: String,
+    ) -> Result<tonic::Response<PropagateDataResponse>, tonic::Status> {
+        let type_name = self
+            .store
+            .get(&key)
+            .map(|entry| entry.value().data.type_name())
+            .unwrap_or("none");
+        Ok(Response::new(PropagateDataResponse {
+            success: true,
+            response: type_name.as_bytes().to_vec(),
+            seq: 0,
+            number: None,
+            replicas: None,
+        }))
+    }
+
+    //answers with the peers the update reached and those it didn't, see Replicas
+    pub async fn push(&self, key: String, value: CrdtValue) -> Replicas {
+        //send updates to fanout() randomly chosen peers, see tuning. a peer that keeps failing
+        //is skipped while its breaker is open, and a dropped connection is made again next time
 
         println!("Receieved {}-{:#?} to {}", key, value, self.config.node_id);
+        //in a format every known node reads, older nodes may still be around mid-upgrade
+        let crdt_data = value.to_proto_as(self.membership.write_format());
+
+        //down the broadcast tree instead, see plumtree
+        if self.config.plumtree.is_some() {
+            let message = BroadcastMessage {
+                id: self.plumtree.next_id(),
+                key,
+                value: Some(crdt_data),
+            };
+            self.plumtree.first_seen(&message);
+            return plumtree::broadcast(self, message, None).await;
+        }
 
         let mut rng = SmallRng::from_os_rng();
 
         let chosen_peers: Vec<String> = {
-            let peers: Vec<String> = self.peers.iter().map(|entry| entry.key().clone()).collect();
-            peers.choose_multiple(&mut rng, K).cloned().collect()
+            let peers: Vec<String> = self
+                .peers
+                .iter()
+                .map(|entry| entry.key().clone())
+                .filter(|peer_addr| !self.membership.is_witness_at(peer_addr))
+                .collect();
+            peers.choose_multiple(&mut rng, self.fanout()).cloned().collect()
         };
 
+        let mut replicas = Replicas::default();
         for peer_addr in chosen_peers.iter() {
+            if !self.breakers.allow(peer_addr) {
+                self.metrics.incr("gossip_push_skipped_total", 1);
+                replicas.failed.push(peer_addr.clone());
+                continue;
+            }
             if !self.pool.contains_key(peer_addr) {
-                let endpoint = if peer_addr.starts_with("http") {
-                    peer_addr.clone()
-                } else {
-                    format!("http://{}", peer_addr)
-                };
-
-                match ReplicationServiceClient::connect(endpoint).await {
+                match self.connect_peer(peer_addr).await {
                     Ok(client) => {
                         self.pool.insert(peer_addr.clone(), client);
                     }
                     Err(e) => {
                         println!("failed to connect to {}: {}", peer_addr, e);
+                        self.breakers.record_failure(peer_addr);
+                        replicas.failed.push(peer_addr.clone());
                         continue;
                     }
                 }
             }
 
-            if let Some(mut peer_client) = self.pool.get_mut(peer_addr) {
-                match &value {
-                    CRDTValue::Counter(inner) => {
-                        let wire_counter = PnCounterMessage::from(inner.clone());
-                        let oneof_type = Data::PnCounter(wire_counter);
-
-                        let crdt_data = CrdtData {
-                            data: Some(oneof_type),
-                        };
-
-                        let state = Request::new(GossipChangesRequest {
-                            key: key.clone(),
-                            counter: Some(crdt_data),
-                        });
+            //clone the client out of the pool so that no shard lock is held across the rpc,
+            //and so that a failed connection can be evicted (and re-resolved) below
+            let peer_client = self.pool.get(peer_addr).map(|client| client.clone());
+            if let Some(mut peer_client) = peer_client {
+                let state = Request::new(GossipChangesRequest {
+                    key: key.clone(),
+                    counter: Some(crdt_data.clone()),
+                    node_id: self.config.node_id.clone(),
+                    cluster_id: self.config.cluster_id().to_string(),
+                });
 
-                        println!("connected to the peer with id: {}", peer_addr);
-                        match peer_client.gossip_changes(state).await {
-                            Ok(response) => {
-                                println!("Response from peer: {:?}", response.into_inner())
-                            }
-                            Err(e) => println!("failed to send update to {}: {}", peer_addr, e),
-                        }
+                println!("connected to the peer with id: {}", peer_addr);
+                let sent = Instant::now();
+                match peer_client.gossip_changes(state).await {
+                    Ok(response) => {
+                        self.metrics.record_latency(peer_addr, sent.elapsed());
+                        self.breakers.record_success(peer_addr);
+                        self.metrics.incr("gossip_pushes_total", 1);
+                        self.metrics.record_transmissions(&key, 1);
+                        replicas.reached += 1;
+                        println!("Response from peer: {:?}", response.into_inner())
                     }
+                    Err(e) => {
+                        println!("failed to send update to {}: {}", peer_addr, e);
+                        self.metrics.incr("gossip_push_failures_total", 1);
+                        self.breakers.record_failure(peer_addr);
+                        self.pool.remove(peer_addr);
+                        replicas.failed.push(peer_addr.clone());
+                    }
+                }
+            }
+        }
+        replicas
+    }
 
-                    CRDTValue::AWSet(inner) => {
-                        let wire_counter = AwSetMessage::from(inner.clone());
-                        let oneof_type = Data::AwSet(wire_counter);
-
-                        let crdt_data = CrdtData {
-                            data: Some(oneof_type),
-                        };
+    //digest mode: asks the peer which of the batch's keys it doesn't already hold as they are
+    //here, and keeps only those. a peer too old to answer digests is sent the whole batch
+    async fn wanted_by(
+        &self,
+        peer_client: &mut ReplicationServiceClient<Channel>,
+        mut batch: HashMap<String, CrdtData>,
+    ) -> Result<HashMap<String, CrdtData>, tonic::Status> {
+        let digests: HashMap<String, u64> = batch
+            .keys()
+            .filter_map(|key| {
+                let stored = self.store.get(key)?;
+                Some((key.clone(), fingerprint::key_hash(key, &stored.data)))
+            })
+            .collect();
+        let request = Request::new(GossipDigestRequest {
+            digests,
+            node_id: self.config.node_id.clone(),
+            cluster_id: self.config.cluster_id().to_string(),
+        });
+        let wanted: HashSet<String> = match peer_client.gossip_digest(request).await {
+            Ok(response) => response.into_inner().wanted.into_iter().collect(),
+            Err(status) if status.code() == tonic::Code::Unimplemented => return Ok(batch),
+            Err(status) => return Err(status),
+        };
+        let digested = batch.len();
+        batch.retain(|key, _| wanted.contains(key));
+        self.metrics
+            .incr("gossip_digest_skipped_total", (digested - batch.len()) as u64);
+        Ok(batch)
+    }
 
-                        let state = Request::new(GossipChangesRequest {
-                            key: key.clone(),
-                            counter: Some(crdt_data),
-                        });
+    //what a node holding `theirs` is missing, as it is here now, and up to where that covers
+    //every update (see Scuttlebutt::missing)
+    fn versioned_updates(&self, theirs: &Vector) -> (Vec<VersionedUpdate>, Vector) {
+        let (missing, complete) = self.scuttlebutt.missing(theirs, BATCH_SIZE);
+        let format = self.membership.write_format();
+        //versions before state, the state sent is never older than the versions claim
+        let updates = missing
+            .into_iter()
+            .filter_map(|(key, versions)| {
+                let value = self.store.get(&key)?.data.clone().to_proto_as(format);
+                Some(VersionedUpdate {
+                    key,
+                    value: Some(value),
+                    versions: versions.into_iter().collect(),
+                })
+            })
+            .collect();
+        (updates, complete)
+    }
 
-                        println!("connected to the peer with id: {}", peer_addr);
-                        match peer_client.gossip_changes(state).await {
-                            Ok(response) => {
-                                println!("Response from peer: {:?}", response.into_inner())
-                            }
-                            Err(e) => println!("failed to send update to {}: {}", peer_addr, e),
-                        }
-                    }
-                    
-                    CRDTValue::LWWRegister(inner) => {
-                        let wire_counter = LwwRegisterMessage::from(inner.clone());
-                        let oneof_type = Data::LwwRegister(wire_counter);
-
-                        let crdt_data = CrdtData {
-                            data: Some(oneof_type),
-                        };
-
-                        let state = Request::new(GossipChangesRequest {
-                            key: key.clone(),
-                            counter: Some(crdt_data),
-                        });
-
-                        println!("connected to the peer with id: {}", peer_addr);
-                        match peer_client.gossip_changes(state).await {
-                            Ok(response) => {
-                                println!("Response from peer: {:?}", response.into_inner())
-                            }
-                            Err(e) => println!("failed to send update to {}: {}", peer_addr, e),
-                        }
-                    }
-                    
-                    _ => print!("other types soon!"),
+    //merges a peer's updates along with their versions, Err when one of them was unreadable
+    fn merge_updates(&self, updates: Vec<VersionedUpdate>) -> Result<usize, String> {
+        let mut rejected = None;
+        let merged = updates.len();
+        for update in updates {
+            let remote_crdt = match update.value.map(CrdtValue::from_proto) {
+                Some(Ok(remote_crdt)) => remote_crdt,
+                Some(Err(e)) => {
+                    rejected = Some(format!("{} for {}", e, update.key));
+                    continue;
+                }
+                None => {
+                    rejected = Some(format!("no value for {}", update.key));
+                    continue;
                 }
+            };
+            let versions: Vector = update.versions.into_iter().collect();
+            self.merge_versioned(update.key, remote_crdt, Some(&versions));
+        }
+        match rejected {
+            Some(e) => Err(e),
+            None => Ok(merged),
+        }
+    }
+
+    //scuttlebutt mode: sends the peer what it was missing when it last answered, and merges in
+    //what this node misses going by its vector. None when the peer is too old to reconcile,
+    //otherwise how many updates went each way
+    async fn reconcile_with(
+        &self,
+        peer_client: &mut ReplicationServiceClient<Channel>,
+        peer_addr: &str,
+    ) -> Result<Option<(usize, usize)>, tonic::Status> {
+        let (updates, complete, expected_origin) = match self.scuttlebutt.peer_view(peer_addr) {
+            Some((origin, vector)) => {
+                let (updates, complete) = self.versioned_updates(&vector);
+                (updates, complete, origin)
             }
+            //nothing known about the peer yet, its answer will tell
+            None => (Vec::new(), Vector::new(), String::new()),
+        };
+        let keys: Vec<String> = updates.iter().map(|update| update.key.clone()).collect();
+        let request = Request::new(ReconcileRequest {
+            node_id: self.config.node_id.clone(),
+            cluster_id: self.config.cluster_id().to_string(),
+            vector: self.scuttlebutt.vector().into_iter().collect(),
+            updates,
+            complete: complete.into_iter().collect(),
+            expected_origin,
+        });
+        let response = match peer_client.reconcile(request).await {
+            Ok(response) => response.into_inner(),
+            Err(status) if status.code() == tonic::Code::Unimplemented => return Ok(None),
+            Err(status) => return Err(status),
+        };
+        for key in &keys {
+            self.metrics.record_transmissions(key, 1);
         }
-        Ok(())
+        self.metrics
+            .incr("reconcile_updates_sent_total", keys.len() as u64);
+
+        let received = response.updates.len();
+        self.metrics
+            .incr("reconcile_updates_received_total", received as u64);
+        match self.merge_updates(response.updates) {
+            Ok(_) => self.scuttlebutt.learn(&response.complete.into_iter().collect()),
+            Err(e) => println!("Rejected an update from {}: {}", peer_addr, e),
+        }
+        self.scuttlebutt.set_peer_view(
+            peer_addr,
+            response.origin,
+            response.vector.into_iter().collect(),
+        );
+        Ok(Some((keys.len(), received)))
     }
 
-    pub async fn create_and_gossip_batch(&self) -> Result<()> {
-        //a connection pool of rpc connections so as to not cause redundant ::connect's again if
-        //a node has already been connected to in an earlier iteration
+    //every key that changed since its class last went out to a peer, as batches in the order
+    //they should be sent: classes by priority, then chunks of at most BATCH_SIZE keys
+    fn dirty_batches(
+        &self,
+        schedule: &Schedule,
+        due: &[usize],
+        since: impl Fn(usize) -> SystemTime,
+    ) -> Vec<HashMap<String, CrdtData>> {
+        let format = self.membership.write_format();
+        let mut by_class: HashMap<usize, Vec<(String, CrdtData)>> = HashMap::new();
+        for entry in self.store.iter() {
+            let class = schedule.class_of(entry.key());
+            if due.contains(&class) && entry.value().last_updated >= since(class) {
+                let crdt_data = entry.value().data.clone().to_proto_as(format);
+                by_class
+                    .entry(class)
+                    .or_default()
+                    .push((entry.key().clone(), crdt_data));
+            }
+        }
+
+        let mut batches = Vec::new();
+        for class in due {
+            let mut keys = by_class.remove(class).unwrap_or_default();
+            while !keys.is_empty() {
+                let rest = keys.split_off(keys.len().min(BATCH_SIZE));
+                batches.push(keys.into_iter().collect());
+                keys = rest;
+            }
+        }
+        batches
+    }
 
-        // let mut connection_pool: HashMap<String, ReplicationServiceClient<Channel>> =
-        //     HashMap::new();
+    //batches of the owed keys that aren't in `batches` already, loaded from the snapshot if
+    //they still wait there. keys gone from the store since are skipped
+    fn owed_batches(
+        &self,
+        owed: &[String],
+        batches: &[HashMap<String, CrdtData>],
+    ) -> Vec<HashMap<String, CrdtData>> {
+        let format = self.membership.write_format();
+        let mut keys: Vec<(String, CrdtData)> = Vec::new();
+        for key in owed {
+            if batches.iter().any(|batch| batch.contains_key(key)) {
+                continue;
+            }
+            self.ensure_loaded(key);
+            if let Some(stored) = self.store.get(key) {
+                keys.push((key.clone(), stored.data.clone().to_proto_as(format)));
+            }
+        }
+        keys.chunks(BATCH_SIZE)
+            .map(|chunk| chunk.iter().cloned().collect())
+            .collect()
+    }
 
+    pub async fn create_and_gossip_batch(&self) -> Result<()> {
+        //when each class last went out to each peer is kept in self.outbound, a key is dirty
+        //for a peer when it changed after that
+        let mut tuned_for = 0;
         loop {
-            let mut chosen_peers: Vec<String> = Vec::new();
-            for peers in self.peers.iter() {
-                if peers.value().elapsed().unwrap_or(Duration::ZERO) > Duration::from_secs(2) {
-                    chosen_peers.push(peers.key().clone());
-                }
+            //rebuilt every round, gossip_interval is a cluster setting
+            let gossip_interval = self.settings().gossip_interval;
+            let cluster_size = tuning::cluster_size(self);
+            if cluster_size != tuned_for {
+                tuned_for = cluster_size;
+                println!(
+                    "Cluster of {} nodes, writes go to {} peers and gossip every {:?}",
+                    cluster_size,
+                    self.fanout().min(cluster_size - 1),
+                    gossip_interval
+                );
             }
+            let schedule = Schedule::new(gossip_interval, &self.config.gossip_priority);
+            let peer_addrs: Vec<String> =
+                self.peers.iter().map(|entry| entry.key().clone()).collect();
+
+            for peer_addr in &peer_addrs {
+                //heartbeats keep the default pace, however often priority classes go out
+                let heartbeat_due = self
+                    .peers
+                    .get(peer_addr)
+                    .map(|seen| seen.elapsed().unwrap_or(Duration::ZERO) >= gossip_interval)
+                    .unwrap_or(false);
+                let started = SystemTime::now();
+                let due = schedule.due(|class| {
+                    self.outbound
+                        .sent_at(peer_addr, class)
+                        .map(|sent| started.duration_since(sent).unwrap_or(Duration::ZERO))
+                });
+                if !heartbeat_due && due.is_empty() {
+                    continue;
+                }
+                if !self.breakers.allow(peer_addr) {
+                    continue;
+                }
 
-            for peer_addr in &chosen_peers {
                 if !self.pool.contains_key(peer_addr) {
-                    let endpoint = if peer_addr.starts_with("http") {
-                        peer_addr.clone()
-                    } else {
-                        format!("http://{}", peer_addr)
-                    };
-
-                    match ReplicationServiceClient::connect(endpoint).await {
+                    match self.connect_peer(peer_addr).await {
                         Ok(client) => {
                             self.pool.insert(peer_addr.clone(), client);
                         }
                         Err(e) => {
                             println!("failed to connect to {}: {}", peer_addr, e);
+                            self.breakers.record_failure(peer_addr);
+                            self.forget_if_discovered(peer_addr);
                             continue;
                         }
                     }
                 }
 
-                //for each key in the current node, transfer each of the node states for merge
-                if let Some(mut peer_client) = self.pool.get_mut(peer_addr) {
-                    let mut batch = HashMap::new();
-                    let mut updates_sent = 0;
-
-                    for mut key_val in self.store.iter_mut() {
-                        // let key = key_val.key().clone();
-                        let value = key_val.value_mut();
-
-                        if value.last_updated.elapsed().unwrap_or(Duration::ZERO)
-                            < Duration::from_secs(2)
-                        {
-                            if batch.len() >= BATCH_SIZE {
-                                let req = Request::new(GossipBatchRequest {
-                                    batch: batch.clone(),
-                                });
-                                if let Err(e) = peer_client.gossip_batch(req).await {
-                                    eprintln!("Failed to send batch to {}: {}", peer_addr, e);
-                                } else {
-                                    updates_sent += batch.len();
-                                }
-                                batch.clear();
+                let peer_client = self.pool.get(peer_addr).map(|client| client.clone());
+                let Some(mut peer_client) = peer_client else {
+                    continue;
+                };
+
+                if heartbeat_due {
+                    //heartbeat first, it keeps the membership view (and so split-brain
+                    //detection) up to date even when there is nothing to gossip
+                    let heartbeat = Request::new(HeartbeatRequest {
+                        node_id: self.config.node_id.clone(),
+                        cluster_id: self.config.cluster_id().to_string(),
+                        reachable: self.membership.reachable_ids(),
+                        format_version: migrate::FORMAT_VERSION,
+                        witness: self.config.witness,
+                        address: self.config.peer_address().to_string(),
+                        instance: self.membership.instance.clone(),
+                    });
+                    let sent = Instant::now();
+                    match peer_client.heartbeat(heartbeat).await {
+                        Ok(response) => {
+                            self.metrics.record_latency(peer_addr, sent.elapsed());
+                            self.breakers.record_success(peer_addr);
+                            let response = response.into_inner();
+                            if let Err(reason) = self.membership.claim(
+                                &response.node_id,
+                                &response.instance,
+                                Some(peer_addr),
+                            ) {
+                                self.metrics.incr("duplicate_node_ids_total", 1);
+                                eprintln!("error: peer {}: {}", peer_addr, reason);
+                                continue;
+                            }
+                            //a seed from another cluster, none of its data belongs here
+                            if let Some(reason) = self
+                                .config
+                                .cluster_refusal(&response.node_id, &response.cluster_id)
+                            {
+                                self.metrics.incr("cross_cluster_refused_total", 1);
+                                eprintln!("warning: peer {}: {}, dropping it", peer_addr, reason);
+                                self.peers.remove(peer_addr);
+                                self.pool.remove(peer_addr);
+                                continue;
+                            }
+                            if let Some(reason) =
+                                self.loopback_or_duplicate(peer_addr, &response.node_id)
+                            {
+                                eprintln!("warning: peer {} {}, dropping it", peer_addr, reason);
+                                self.peers.remove(peer_addr);
+                                self.pool.remove(peer_addr);
+                                continue;
+                            }
+                            self.membership.observe(
+                                response.node_id,
+                                Some(peer_addr.clone()),
+                                response.reachable,
+                                response.format_version,
+                                response.witness,
+                            );
+                            self.peers.insert(peer_addr.clone(), SystemTime::now());
+                            //only nodes the peer still hears from, a dead one would just be
+                            //dialled until it is forgotten again
+                            for known in response.peers.iter().filter(|known| known.reachable) {
+                                self.discover(&known.node_id, &known.address, peer_addr);
                             }
                         }
+                        Err(e) if e.code() == tonic::Code::AlreadyExists => {
+                            //the peer already knows another node by this one's node_id
+                            self.metrics.incr("duplicate_node_ids_total", 1);
+                            eprintln!("error: {} refused this node: {}", peer_addr, e.message());
+                            continue;
+                        }
+                        Err(e) => {
+                            println!("heartbeat to {} failed: {}", peer_addr, e);
+                            self.breakers.record_failure(peer_addr);
+                            self.pool.remove(peer_addr);
+                            self.forget_if_discovered(peer_addr);
+                            continue;
+                        }
                     }
+                }
+
+                //a witness only ever gets heartbeats
+                if self.membership.is_witness_at(peer_addr) {
+                    continue;
+                }
 
-                    if !batch.is_empty() {
-                        let req = Request::new(GossipBatchRequest {
-                            batch: batch.clone(),
-                        });
-                        if let Err(e) = peer_client.gossip_batch(req).await {
-                            eprintln!("Failed to send final batch to {}: {}", peer_addr, e);
-                        } else {
-                            updates_sent += batch.len();
+                //scuttlebutt replaces the dirty batches, a peer too old for it is pushed to
+                let mode = self.settings().gossip_mode;
+                if mode == GossipMode::Scuttlebutt {
+                    let sent = Instant::now();
+                    match self.reconcile_with(&mut peer_client, peer_addr).await {
+                        Ok(Some((updates_sent, updates_received))) => {
+                            self.metrics.record_latency(peer_addr, sent.elapsed());
+                            self.breakers.record_success(peer_addr);
+                            for class in &due {
+                                self.outbound.mark_sent(peer_addr, *class, started);
+                            }
+                            if updates_sent + updates_received > 0 {
+                                println!(
+                                    "Reconciled with {}, {} items sent and {} received",
+                                    peer_addr, updates_sent, updates_received
+                                );
+                            }
+                            continue;
+                        }
+                        Ok(None) => {}
+                        Err(e) => {
+                            eprintln!("Failed to reconcile with {}: {}", peer_addr, e);
+                            self.breakers.record_failure(peer_addr);
+                            self.pool.remove(peer_addr);
+                            continue;
                         }
                     }
+                }
+
+                let mut batches = self.dirty_batches(&schedule, &due, |class| {
+                    self.outbound.dirty_since(peer_addr, class)
+                });
+                //whatever the peer was still owed when this node last stopped
+                let owed = self.outbound.owed(peer_addr);
+                if !owed.is_empty() {
+                    batches.extend(self.owed_batches(&owed, &batches));
+                }
 
-                    self.peers.insert(peer_addr.clone(), SystemTime::now());
+                let mut updates_sent = 0;
+                let mut failed = false;
+                for batch in batches {
+                    let batch = match mode {
+                        GossipMode::Push | GossipMode::Scuttlebutt => batch,
+                        GossipMode::Digest => match self.wanted_by(&mut peer_client, batch).await {
+                            Ok(batch) => batch,
+                            Err(e) => {
+                                eprintln!("Failed to send digest to {}: {}", peer_addr, e);
+                                self.breakers.record_failure(peer_addr);
+                                self.pool.remove(peer_addr);
+                                failed = true;
+                                break;
+                            }
+                        },
+                    };
+                    if batch.is_empty() {
+                        continue;
+                    }
+                    let keys: Vec<String> = batch.keys().cloned().collect();
+                    let req = Request::new(GossipBatchRequest {
+                        batch,
+                        node_id: self.config.node_id.clone(),
+                        cluster_id: self.config.cluster_id().to_string(),
+                    });
+                    let sent = Instant::now();
+                    if let Err(e) = peer_client.gossip_batch(req).await {
+                        eprintln!("Failed to send batch to {}: {}", peer_addr, e);
+                        self.breakers.record_failure(peer_addr);
+                        self.pool.remove(peer_addr);
+                        failed = true;
+                        break;
+                    }
+                    self.metrics.record_latency(peer_addr, sent.elapsed());
+                    self.breakers.record_success(peer_addr);
+                    for key in &keys {
+                        self.metrics.record_transmissions(key, 1);
+                    }
+                    updates_sent += keys.len();
+                }
 
-                    if updates_sent > 0 {
-                        println!("Synced {} items with {}", updates_sent, peer_addr);
+                //a failed round leaves the classes dirty, so the next one sends them again
+                if !failed {
+                    for class in due {
+                        self.outbound.mark_sent(peer_addr, class, started);
                     }
+                    if !owed.is_empty() {
+                        self.outbound.delivered(peer_addr);
+                    }
+                }
+                if updates_sent > 0 {
+                    println!("Synced {} items with {}", updates_sent, peer_addr);
                 }
             }
-            //wait for 2s before the next gossip round
-            tokio::time::sleep(tokio::time::Duration::from_secs(2)).await;
+            if let Some(event) = self.split_brain.evaluate(&self.membership, &self.metrics) {
+                webhook::notify(self.config.split_brain_webhook.clone(), event);
+            }
+
+            tokio::time::sleep(schedule.tick()).await;
+        }
+    }
+}
+
+//binds the socket in a directory only this user can enter and moves it to `path` once it has
+//its mode, so it is never reachable with looser permissions. a socket left behind by an earlier
+//run is replaced, anything else at the path is refused
+fn bind_uds(path: &Path, mode: Option<u32>) -> Result<UnixListener> {
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if !metadata.file_type().is_socket() => {
+            anyhow::bail!("{} exists and is not a socket, not replacing it", path.display())
         }
+        _ => {}
     }
+    let Some(name) = path.file_name() else {
+        anyhow::bail!("uds_path {} names no file", path.display());
+    };
+    let mut private = std::ffi::OsString::from(".");
+    private.push(name);
+    private.push(format!(".{}", std::process::id()));
+    let private = path.with_file_name(private);
+    let _ = std::fs::remove_dir_all(&private);
+    std::fs::DirBuilder::new().mode(0o700).create(&private)?;
+
+    let bound = private.join("socket");
+    let placed = UnixListener::bind(&bound)
+        .map_err(anyhow::Error::from)
+        .and_then(|listener| {
+            if let Some(mode) = mode {
+                std::fs::set_permissions(&bound, std::fs::Permissions::from_mode(mode))?;
+            }
+            std::fs::rename(&bound, path)?;
+            Ok(listener)
+        });
+    let _ = std::fs::remove_dir_all(&private);
+    placed
 }
@@ -0,0 +1,1015 @@
+//a node as a library, for applications that embed a replica in-process instead of running
+//mergedb-node next to them:
+//  let node = Node::builder().config(config).on_change("flags:", |key, value| ..).spawn().await?;
+//  let flag = node.get("flags:dark_mode");
+//the node runs on the tokio runtime spawn() is awaited on, gossip on its own one when given a
+//gossip_runtime. it serves peers and clients on its configured addresses like any other node,
+//reads and writes through get(), execute() or the typed handles skip grpc altogether:
+//  node.counter("likes").add(1).await?;
+//  let tagged = node.set("tags").contains("x")?;
+//callbacks hear about changed keys, merge conflicts, peers coming and going and peers falling
+//behind from when spawn() returns, see events. crdts of the application's own are registered
+//on the builder and read and written through custom():
+//  let node = Node::builder().config(config).crdt::<Hll>().spawn().await?;
+//  node.custom::<Hll>("visitors").merge(&hll).await?;
+use anyhow::Result;
+use dashmap::Entry;
+use mergedb_types::{
+    custom::{self, CustomValue, Plugin},
+    element::Element,
+    CrdtValue,
+};
+use std::{
+    future::Future,
+    marker::PhantomData,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+use tokio::{runtime::Handle, task::JoinHandle};
+use tonic::{Request, Response, Status};
+use tonic_health::ServingStatus;
+
+use crate::{
+    anomaly::AnomalyDetector,
+    audit,
+    communication::{
+        replication_service_server::ReplicationService, PropagateDataRequest,
+        PropagateDataResponse,
+    },
+    config::Config,
+    events::{self, ChangeCallback, Event, EventCallback, Events},
+    graphql, identity,
+    network::{ReplicationServer, StoredValue},
+    plumtree, prometheus,
+    recovery::{self, Stage},
+    wal::Wal,
+    watchdog, websocket,
+};
+
+#[derive(Default)]
+pub struct NodeBuilder {
+    config: Option<Config>,
+    gossip_runtime: Option<Handle>,
+    changes: Vec<(String, ChangeCallback)>,
+    listeners: Vec<EventCallback>,
+    lag_threshold: Option<Duration>,
+}
+
+impl NodeBuilder {
+    pub fn config(mut self, config: Config) -> Self {
+        self.config = Some(config);
+        self
+    }
+
+    //peer connections, the peer listener and the gossip loop run here instead
+    pub fn gossip_runtime(mut self, handle: Handle) -> Self {
+        self.gossip_runtime = Some(handle);
+        self
+    }
+
+    pub fn on_change(
+        mut self,
+        prefix: &str,
+        callback: impl Fn(&str, &CrdtValue) + Send + Sync + 'static,
+    ) -> Self {
+        self.changes.push((prefix.to_string(), Arc::new(callback)));
+        self
+    }
+
+    pub fn on_event(mut self, callback: impl Fn(&Event) + Send + Sync + 'static) -> Self {
+        self.listeners.push(Arc::new(callback));
+        self
+    }
+
+    //registers the type for the whole process, see mergedb_types::custom. every node that is
+    //to merge its values needs it, the others store and forward them as they are
+    pub fn crdt<T: Plugin>(self) -> Self {
+        custom::register::<T>();
+        self
+    }
+
+    //a peer that goes longer than this without getting every change is reported Lagging
+    pub fn lag_threshold(mut self, threshold: Duration) -> Self {
+        self.lag_threshold = Some(threshold);
+        self
+    }
+
+    //starts the node and returns once it serves: the wal is replayed and the preload list is
+    //in, the rest of the snapshot keeps loading in the background
+    pub async fn spawn(self) -> Result<Node> {
+        let Some(config) = self.config else {
+            anyhow::bail!("a node needs a config");
+        };
+        config.validate()?;
+        let server = start(config, self.gossip_runtime).await?;
+        for (prefix, callback) in self.changes {
+            server.events.on_change(&prefix, callback);
+        }
+        for callback in self.listeners {
+            server.events.on_event(callback);
+        }
+        server.events.set_lag_threshold(self.lag_threshold);
+
+        let gossiping = server.clone();
+        let gossip = async move { gossiping.create_and_gossip_batch().await };
+        let gossip = match server.gossip_runtime.clone() {
+            Some(handle) => handle.spawn(gossip),
+            None => tokio::spawn(gossip),
+        };
+        Ok(Node { server, gossip })
+    }
+}
+
+pub struct Node {
+    server: Arc<ReplicationServer>,
+    gossip: JoinHandle<Result<()>>,
+}
+
+impl Node {
+    pub fn builder() -> NodeBuilder {
+        NodeBuilder::default()
+    }
+
+    //everything the node holds, for what get() and execute() don't cover
+    pub fn server(&self) -> &Arc<ReplicationServer> {
+        &self.server
+    }
+
+    //a key's value as this node holds it, loaded from the snapshot first if need be
+    pub fn get(&self, key: &str) -> Option<CrdtValue> {
+        self.server.ensure_loaded(key);
+        self.server.store.get(key).map(|stored| stored.data.clone())
+    }
+
+    //a client command, run the way PropagateData runs it but without a round trip
+    pub async fn execute(
+        &self,
+        request: PropagateDataRequest,
+    ) -> Result<PropagateDataResponse, Status> {
+        self.server
+            .propagate_data(Request::new(request))
+            .await
+            .map(|response| response.into_inner())
+    }
+
+    pub fn on_change(
+        &self,
+        prefix: &str,
+        callback: impl Fn(&str, &CrdtValue) + Send + Sync + 'static,
+    ) {
+        self.server.events.on_change(prefix, Arc::new(callback));
+    }
+
+    pub fn on_event(&self, callback: impl Fn(&Event) + Send + Sync + 'static) {
+        self.server.events.on_event(Arc::new(callback));
+    }
+
+    pub fn counter(&self, key: &str) -> Counter<'_> {
+        Counter {
+            node: self,
+            key: key.to_string(),
+        }
+    }
+
+    pub fn set(&self, key: &str) -> Set<'_> {
+        Set {
+            node: self,
+            key: key.to_string(),
+        }
+    }
+
+    pub fn register(&self, key: &str) -> Register<'_> {
+        Register {
+            node: self,
+            key: key.to_string(),
+        }
+    }
+
+    pub fn map(&self, key: &str) -> Map<'_> {
+        Map {
+            node: self,
+            key: key.to_string(),
+        }
+    }
+
+    pub fn custom<T: Plugin>(&self, key: &str) -> Custom<'_, T> {
+        Custom {
+            node: self,
+            key: key.to_string(),
+            kind: PhantomData,
+        }
+    }
+
+    //the key's value for a typed read, once the read is counted the way PropagateData counts it
+    fn read(&self, key: &str) -> Option<CrdtValue> {
+        self.server.metrics.incr("commands_total", 1);
+        self.server.metrics.hot_keys.record_read(key);
+        self.get(key)
+    }
+
+    //a typed write, refused for whatever PropagateData would refuse it for and answered with
+    //its seq once that is as durable as wal_sync asks for
+    async fn write(
+        &self,
+        key: &str,
+        command: &str,
+        size: usize,
+        apply: impl Future<Output = Result<Response<PropagateDataResponse>, Status>>,
+    ) -> Result<u64, Status> {
+        let server = &self.server;
+        server.metrics.incr("commands_total", 1);
+        if server.config.witness {
+            return Err(Status::failed_precondition(format!(
+                "{} is a witness, it holds no data, send {} to another node",
+                server.config.node_id, command
+            )));
+        }
+        server.ensure_loaded(key);
+        if let Some(refusal) = server
+            .refuse_write(key, size)
+            .or_else(|| server.refuse_change(true))
+        {
+            return Err(refusal);
+        }
+        server.metrics.hot_keys.record_write(key);
+
+        let shared = server.script_lock.read().await;
+        let response = apply.await;
+        drop(shared);
+        if let Ok(response) = &response {
+            server.notify_label_rules(key, command, response.get_ref().seq);
+        }
+        let response = server.when_durable(response).await?.into_inner();
+        if !response.success {
+            return Err(mismatch(key, self.get(key), command));
+        }
+        Ok(response.seq)
+    }
+
+    //resolves when the gossip loop stops, which it only does on an error, or once the node has
+    //been decommissioned and has nothing left to do
+    pub async fn wait(self) -> Result<()> {
+        tokio::select! {
+            stopped = self.gossip => stopped?,
+            _ = self.server.decommissioned.notified() => Ok(()),
+        }
+    }
+}
+
+//typed handles on one key of an embedded node. reads come straight from the store, writes are
+//checked, committed, logged and pushed to peers like the command they stand for, just without
+//encoding anything for grpc. a key that holds another type is a failed precondition, reads
+//box their Status to keep their results small
+pub struct Counter<'a> {
+    node: &'a Node,
+    key: String,
+}
+
+impl Counter<'_> {
+    //what CGET answers, shards summed up
+    pub fn value(&self) -> Result<i64, Box<Status>> {
+        let value = match self.node.read(&self.key) {
+            Some(CrdtValue::Counter(counter)) => counter.value(),
+            other => return Err(Box::new(mismatch(&self.key, other, "CGET"))),
+        };
+        self.node
+            .server
+            .shard_total(&self.key)
+            .and_then(|total| value.checked_add(total))
+            .ok_or_else(|| Box::new(Status::out_of_range("counter overflowed summing its shards")))
+    }
+
+    //CINC, a negative delta is a CDEC
+    pub async fn add(&self, delta: i64) -> Result<u64, Status> {
+        let server = &self.node.server;
+        let apply = server.add_counter(self.key.clone(), delta);
+        self.node.write(&self.key, "CINC", 8, apply).await
+    }
+
+    pub async fn set(&self, value: i64) -> Result<u64, Status> {
+        let server = &self.node.server;
+        let apply = server.handle_set_counter(self.key.clone(), value);
+        self.node.write(&self.key, "CSET", 8, apply).await
+    }
+}
+
+pub struct Set<'a> {
+    node: &'a Node,
+    key: String,
+}
+
+impl Set<'_> {
+    pub fn contains(&self, tag: impl Into<Element>) -> Result<bool, Box<Status>> {
+        match self.node.read(&self.key) {
+            Some(CrdtValue::Set(set)) => Ok(set.contains(&tag.into())),
+            other => Err(Box::new(mismatch(&self.key, other, "SGET"))),
+        }
+    }
+
+    //in Element's order, like SGET ASC
+    pub fn members(&self) -> Result<Vec<Element>, Box<Status>> {
+        match self.node.read(&self.key) {
+            Some(CrdtValue::Set(set)) => Ok(set.read_sorted()),
+            other => Err(Box::new(mismatch(&self.key, other, "SGET"))),
+        }
+    }
+
+    pub async fn add(&self, tag: impl Into<Element>) -> Result<u64, Status> {
+        let tag = tag.into();
+        let size = tag.to_string().len();
+        let apply = self.node.server.handle_add_set(self.key.clone(), tag);
+        self.node.write(&self.key, "SADD", size, apply).await
+    }
+
+    pub async fn remove(&self, tag: impl Into<Element>) -> Result<u64, Status> {
+        let tag = tag.into();
+        let size = tag.to_string().len();
+        let apply = self.node.server.handle_rem_set(self.key.clone(), tag);
+        self.node.write(&self.key, "SREM", size, apply).await
+    }
+}
+
+pub struct Register<'a> {
+    node: &'a Node,
+    key: String,
+}
+
+impl Register<'_> {
+    pub fn get(&self) -> Result<String, Box<Status>> {
+        match self.node.read(&self.key) {
+            Some(CrdtValue::Register(register)) => Ok(register.get()),
+            other => Err(Box::new(mismatch(&self.key, other, "RGET"))),
+        }
+    }
+
+    pub async fn set(&self, value: &str) -> Result<u64, Status> {
+        let apply = self
+            .node
+            .server
+            .handle_set_register(self.key.clone(), value.as_bytes().to_vec());
+        self.node.write(&self.key, "RSET", value.len(), apply).await
+    }
+}
+
+pub struct Map<'a> {
+    node: &'a Node,
+    key: String,
+}
+
+impl Map<'_> {
+    pub fn get(&self, field: &str) -> Result<Option<String>, Box<Status>> {
+        match self.node.read(&self.key) {
+            Some(CrdtValue::Map(map)) => Ok(map.get(field).map(str::to_string)),
+            other => Err(Box::new(mismatch(&self.key, other, "map get"))),
+        }
+    }
+
+    //the fields that are set, sorted
+    pub fn fields(&self) -> Result<Vec<(String, String)>, Box<Status>> {
+        match self.node.read(&self.key) {
+            Some(CrdtValue::Map(map)) => Ok(map
+                .read()
+                .into_iter()
+                .map(|(field, value)| (field.to_string(), value.to_string()))
+                .collect()),
+            other => Err(Box::new(mismatch(&self.key, other, "map get"))),
+        }
+    }
+
+    pub async fn set(&self, field: &str, value: &str) -> Result<u64, Status> {
+        let apply = self.node.server.handle_set_map_field(
+            self.key.clone(),
+            field.to_string(),
+            Some(value.to_string()),
+        );
+        let size = field.len() + value.len();
+        self.node.write(&self.key, "map set", size, apply).await
+    }
+
+    pub async fn remove(&self, field: &str) -> Result<u64, Status> {
+        let apply =
+            self.node
+                .server
+                .handle_set_map_field(self.key.clone(), field.to_string(), None);
+        self.node
+            .write(&self.key, "map remove", field.len(), apply)
+            .await
+    }
+}
+
+pub struct Custom<'a, T> {
+    node: &'a Node,
+    key: String,
+    kind: PhantomData<fn() -> T>,
+}
+
+impl<T: Plugin> Custom<'_, T> {
+    pub fn get(&self) -> Result<T, Box<Status>> {
+        match self.node.read(&self.key) {
+            Some(CrdtValue::Custom(custom)) => custom.decode::<T>().map_err(|e| {
+                Box::new(Status::failed_precondition(format!("{}: {}", self.key, e)))
+            }),
+            other => Err(Box::new(mismatch(&self.key, other, T::TAG))),
+        }
+    }
+
+    //an update is a state of its own, merged into what the key holds
+    pub async fn merge(&self, update: &T) -> Result<u64, Status> {
+        let value = CustomValue::of(update);
+        let size = value.estimated_size();
+        let apply = self.node.server.handle_merge_custom(self.key.clone(), value);
+        self.node.write(&self.key, T::TAG, size, apply).await
+    }
+}
+
+//why a typed command found nothing it can work on
+fn mismatch(key: &str, value: Option<CrdtValue>, command: &str) -> Status {
+    match value {
+        Some(value) => Status::failed_precondition(format!(
+            "{} is a {}, {} doesn't work on it",
+            key,
+            value.type_name(),
+            command
+        )),
+        None => Status::not_found("The requested key was not found!"),
+    }
+}
+
+//everything mergedb-node does before gossiping
+async fn start(
+    mut config: Config,
+    gossip_runtime: Option<Handle>,
+) -> Result<Arc<ReplicationServer>> {
+    if let Some(identity) = identity::resolve(&mut config)? {
+        match identity.cluster_id.as_str() {
+            "" => println!("Identity: node {}", identity.node_id),
+            cluster_id => println!(
+                "Identity: node {} of cluster {}",
+                identity.node_id, cluster_id
+            ),
+        }
+    }
+    println!(
+        "Node '{}' starting on {}",
+        config.node_id,
+        config.client_address()
+    );
+    if config.witness {
+        println!("Running as a witness, no data is held here");
+    }
+
+    let server = Arc::new(ReplicationServer {
+        gossip_runtime,
+        ..ReplicationServer::new(config)?
+    });
+    if server.persistence.snapshot_keys > 0 {
+        println!(
+            "Found {} keys in the snapshot",
+            server.persistence.snapshot_keys
+        );
+    }
+    let (resumed, owed) = server.outbound.restored();
+    if resumed > 0 {
+        println!(
+            "{} peers are still owed {} keys from before the restart",
+            resumed, owed
+        );
+    }
+
+    let logging = server.clone();
+    tokio::spawn(async move { logging.wal.run(logging.store.clone()).await });
+
+    //NOT_SERVING until the wal is replayed and the preload list is in, the rest of the snapshot
+    //loads in the background. the recovery service only turns SERVING once that is done too
+    let (mut health, health_service) = tonic_health::server::health_reporter();
+    health
+        .set_service_status("", ServingStatus::NotServing)
+        .await;
+    health
+        .set_service_status(recovery::RECOVERY_SERVICE, ServingStatus::NotServing)
+        .await;
+
+    //up before the wal is replayed so `node status` can follow it, everything touching the
+    //store is refused until then
+    if server.persistence.enabled() {
+        server.recovery.start(Stage::Wal, 0);
+    }
+    let server_clone = server.clone();
+
+    tokio::spawn(async move {
+        if let Err(e) = server_clone.start_listener(health_service).await {
+            eprintln!("server listener failed: {e}");
+        }
+    });
+
+    //changes since the snapshot, they are new to every peer
+    if let Some(dir) = server.config.data_dir.clone() {
+        let replaying = server.clone();
+        tokio::task::spawn_blocking(move || {
+            Wal::replay_into(&dir, &replaying.recovery, |key, value| {
+                match replaying.store.entry(key.clone()) {
+                    Entry::Occupied(mut stored) => {
+                        if let Err(e) = stored.get_mut().data.merge_with(&value) {
+                            eprintln!("{} in the wal", e);
+                        }
+                    }
+                    Entry::Vacant(vacant) => {
+                        vacant.insert(StoredValue {
+                            data: value,
+                            last_updated: SystemTime::now(),
+                        });
+                    }
+                }
+                replaying.scuttlebutt.local(&key);
+            })
+        })
+        .await??;
+    }
+
+    server.preload();
+    health.set_service_status("", ServingStatus::Serving).await;
+
+    if server.persistence.enabled() {
+        let warming = server.clone();
+        let mut health = health.clone();
+        tokio::spawn(async move {
+            warming.warm_up().await;
+            health
+                .set_service_status(recovery::RECOVERY_SERVICE, ServingStatus::Serving)
+                .await;
+        });
+        let snapshotting = server.clone();
+        tokio::spawn(async move { snapshotting.snapshot_periodically().await });
+    } else {
+        health
+            .set_service_status(recovery::RECOVERY_SERVICE, ServingStatus::Serving)
+            .await;
+    }
+
+    Events::start(&server)?;
+    let watching = server.clone();
+    tokio::spawn(async move { events::run(&watching).await });
+
+    let materializing = server.clone();
+    tokio::spawn(async move { materializing.materialize_periodically().await });
+
+    if let Some(address) = server.config.metrics_address.clone() {
+        let metrics = server.metrics.clone();
+        tokio::spawn(async move {
+            if let Err(e) = prometheus::serve(&address, metrics).await {
+                eprintln!("metrics endpoint failed: {e}");
+            }
+        });
+    }
+
+    if let Some(address) = server.config.websocket_address.clone() {
+        let serving = server.clone();
+        tokio::spawn(async move {
+            if let Err(e) = websocket::serve(&address, serving).await {
+                eprintln!("websocket endpoint failed: {e}");
+            }
+        });
+    }
+
+    if let Some(address) = server.config.graphql_address.clone() {
+        let serving = server.clone();
+        tokio::spawn(async move {
+            if let Err(e) = graphql::serve(&address, serving).await {
+                eprintln!("graphql endpoint failed: {e}");
+            }
+        });
+    }
+
+    if server
+        .config
+        .label_rules
+        .iter()
+        .any(|rule| rule.retention.is_some())
+    {
+        let expiring = server.clone();
+        tokio::spawn(async move { expiring.expire_periodically().await });
+    }
+
+    if server.config.plumtree.is_some() {
+        let announcing = server.clone();
+        tokio::spawn(async move { plumtree::run(&announcing).await });
+    }
+
+    if server.config.audit.is_some() {
+        let auditing = server.clone();
+        tokio::spawn(async move { audit::run(&auditing).await });
+    }
+
+    if server.config.watchdog.is_some() {
+        let watching = server.clone();
+        tokio::spawn(async move { watchdog::run(&watching).await });
+    }
+
+    if let Some(rules) = server.config.counter_anomaly.clone() {
+        let watching = server.clone();
+        tokio::spawn(async move { watching.watch_counters(AnomalyDetector::new(rules)).await });
+    }
+
+    server.connect_all_peers().await;
+    Ok(server)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        communication::{PingRequest, Replicas},
+        testing::{self, command},
+    };
+    use mergedb_proto::wire;
+    use tokio::sync::mpsc;
+
+    #[tokio::test]
+    async fn test_embedded_node() {
+        let config = testing::config("n1", "");
+        let (changed, mut changes) = mpsc::unbounded_channel();
+        let node = Node::builder()
+            .config(config)
+            .on_change("flags:", move |key, value| {
+                let _ = changed.send((key.to_string(), value.type_name()));
+            })
+            .spawn()
+            .await
+            .unwrap();
+
+        for key in ["flags:dark_mode", "other"] {
+            node.execute(command("RSET", key, "on")).await.unwrap();
+        }
+        assert!(matches!(node.get("flags:dark_mode"), Some(CrdtValue::Register(_))));
+        assert!(node.get("missing").is_none());
+
+        let change = tokio::time::timeout(Duration::from_secs(5), changes.recv())
+            .await
+            .unwrap();
+        assert_eq!(change, Some(("flags:dark_mode".to_string(), "register")));
+        //nothing for keys outside the prefix
+        assert!(changes.try_recv().is_err());
+
+        assert!(Node::builder().spawn().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_typed_handles() {
+        let config = testing::config("n1", "");
+        let node = Node::builder().config(config).spawn().await.unwrap();
+
+        let likes = node.counter("likes");
+        assert_eq!(likes.value().unwrap_err().code(), tonic::Code::NotFound);
+        likes.set(10).await.unwrap();
+        let seq = likes.add(-3).await.unwrap();
+        assert_eq!(likes.value().unwrap(), 7);
+        assert_eq!(node.server().commit_seq.load(std::sync::atomic::Ordering::SeqCst), seq);
+
+        let tags = node.set("tags");
+        tags.add("x").await.unwrap();
+        tags.add(5).await.unwrap();
+        tags.remove("x").await.unwrap();
+        assert!(!tags.contains("x").unwrap());
+        assert!(tags.contains(5).unwrap());
+        assert_eq!(tags.members().unwrap(), vec![Element::Int(5)]);
+
+        node.register("name").set("mergedb").await.unwrap();
+        assert_eq!(node.register("name").get().unwrap(), "mergedb");
+
+        let config = node.map("config");
+        config.set("theme", "dark").await.unwrap();
+        config.set("lang", "en").await.unwrap();
+        config.remove("theme").await.unwrap();
+        assert_eq!(config.get("lang").unwrap().as_deref(), Some("en"));
+        assert_eq!(config.get("theme").unwrap(), None);
+        assert_eq!(
+            config.fields().unwrap(),
+            vec![("lang".to_string(), "en".to_string())]
+        );
+        assert!(node.map("name").set("x", "y").await.is_err());
+
+        //the same checks as over grpc
+        let wrong = node.set("likes").add("x").await.unwrap_err();
+        assert_eq!(wrong.code(), tonic::Code::FailedPrecondition);
+        assert_eq!(
+            node.counter("tags").value().unwrap_err().code(),
+            tonic::Code::FailedPrecondition
+        );
+        node.server().decommissioning.store(true, std::sync::atomic::Ordering::SeqCst);
+        assert!(likes.add(1).await.is_err());
+        assert_eq!(likes.value().unwrap(), 7);
+    }
+
+    #[tokio::test]
+    async fn test_wait_returns_once_decommissioned() {
+        use crate::communication::DecommissionRequest;
+        use tokio_stream::StreamExt;
+
+        //a witness with no peers has nothing to hand over and nobody to tell
+        let config = testing::config("n1", "witness = true");
+        let node = Node::builder().config(config).spawn().await.unwrap();
+        let request = Request::new(DecommissionRequest {
+            node_id: "n1".to_string(),
+            timeout_secs: 0,
+        });
+        let mut progress = node
+            .server()
+            .decommission(request)
+            .await
+            .unwrap()
+            .into_inner();
+        while let Some(step) = progress.next().await {
+            step.unwrap();
+        }
+        tokio::time::timeout(std::time::Duration::from_secs(5), node.wait())
+            .await
+            .expect("wait() should return once the node is decommissioned")
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_counter_amounts() {
+        let config = testing::config("n1", "");
+        let node = Node::builder().config(config).spawn().await.unwrap();
+        let command = |cmd: &str, value: Vec<u8>, amount: Option<i64>| PropagateDataRequest {
+            amount,
+            ..testing::command(cmd, "balance", value)
+        };
+
+        node.execute(command("CSET", Vec::new(), Some(i64::MAX - 1)))
+            .await
+            .unwrap();
+        node.execute(command("CINC", Vec::new(), Some(1)))
+            .await
+            .unwrap();
+        let read = node
+            .execute(command("CGET", Vec::new(), None))
+            .await
+            .unwrap();
+        assert_eq!(read.number, Some(i64::MAX));
+        assert_eq!(wire::decode_i64(&read.response), Ok(i64::MAX));
+
+        //the field wins over the bytes, older clients only send the bytes
+        node.execute(command("CSET", wire::encode_i64(5), Some(-40)))
+            .await
+            .unwrap();
+        node.execute(command("CDEC", wire::encode_i64(2), None))
+            .await
+            .unwrap();
+        let read = node
+            .execute(command("CGET", Vec::new(), None))
+            .await
+            .unwrap();
+        assert_eq!(read.number, Some(-42));
+        let malformed = node.execute(command("CINC", vec![1, 2], None)).await;
+        assert_eq!(malformed.unwrap_err().code(), tonic::Code::InvalidArgument);
+    }
+
+    #[tokio::test]
+    async fn test_only_srem_has_a_dry_run() {
+        let config = testing::config("n1", "");
+        let node = Node::builder().config(config).spawn().await.unwrap();
+        let dry_run = |cmd: &str, key: &str, value: &str| PropagateDataRequest {
+            dry_run: true,
+            ..command(cmd, key, value)
+        };
+
+        let tags = node.set("tags");
+        tags.add("a").await.unwrap();
+        let preview = node.execute(dry_run("SREM", "tags", "a")).await.unwrap();
+        assert!(preview.success);
+        assert!(tags.contains("a").unwrap());
+
+        //anything else is refused rather than applied
+        for (cmd, key, value) in [("RSET", "name", "x"), ("EVAL", "", "set(\"views\", 3); 0")] {
+            let refused = node.execute(dry_run(cmd, key, value)).await.unwrap_err();
+            assert_eq!(refused.code(), tonic::Code::InvalidArgument);
+        }
+        assert!(node.get("name").is_none());
+        assert!(node.get("views").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_writes_report_what_they_left() {
+        let config = testing::config("n1", "");
+        let node = Node::builder().config(config).spawn().await.unwrap();
+        let left = |cmd: &str, key: &str, value: &[u8], amount: Option<i64>| {
+            node.execute(PropagateDataRequest {
+                amount,
+                ..command(cmd, key, value)
+            })
+        };
+
+        assert_eq!(
+            left("CSET", "views", b"", Some(10)).await.unwrap().number,
+            Some(10)
+        );
+        assert_eq!(
+            left("CINC", "views", b"", Some(5)).await.unwrap().number,
+            Some(15)
+        );
+        assert_eq!(
+            left("CDEC", "views", b"", Some(20)).await.unwrap().number,
+            Some(-5)
+        );
+        //spread over shards, still the whole total
+        left("CSHARD", "views", b"4", None).await.unwrap();
+        for total in -4..=4 {
+            let inc = left("CINC", "views", b"", Some(1)).await.unwrap();
+            assert_eq!(inc.number, Some(total));
+        }
+
+        assert_eq!(
+            left("SADD", "tags", b"a", None).await.unwrap().number,
+            Some(1)
+        );
+        assert_eq!(
+            left("SADD", "tags", b"b", None).await.unwrap().number,
+            Some(2)
+        );
+        assert_eq!(
+            left("SADD", "tags", b"a", None).await.unwrap().number,
+            Some(2)
+        );
+        assert_eq!(
+            left("SREM", "tags", b"a", None).await.unwrap().number,
+            Some(1)
+        );
+
+        assert_eq!(
+            left("RSET", "name", b"merge", None).await.unwrap().number,
+            Some(5)
+        );
+        assert_eq!(
+            left("RAPP", "name", b"db", None).await.unwrap().number,
+            Some(7)
+        );
+        assert_eq!(
+            left("FREEZE", "name", b"", None).await.unwrap().number,
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_writes_name_the_peers_they_missed() {
+        //nothing listens on port 1, pushes to it fail straight away
+        let config: Config = toml::from_str(
+            "node_id = \"n1\"\nlisten_address = \"127.0.0.1:0\"\npeers = [\"127.0.0.1:1\"]\n\
+             fanout = 1",
+        )
+        .unwrap();
+        let node = Node::builder().config(config).spawn().await.unwrap();
+        let sent = |cmd: &str| {
+            node.execute(PropagateDataRequest {
+                amount: Some(1),
+                ..command(cmd, "views", "")
+            })
+        };
+
+        let set = sent("CSET").await.unwrap();
+        assert_eq!(
+            set.replicas,
+            Some(Replicas {
+                reached: 0,
+                failed: vec!["127.0.0.1:1".to_string()],
+            })
+        );
+        //reads don't push anything
+        assert_eq!(sent("CGET").await.unwrap().replicas, None);
+    }
+
+    #[tokio::test]
+    async fn test_ping_reports_each_peer() {
+        let config: Config = toml::from_str(
+            "node_id = \"n1\"\nlisten_address = \"127.0.0.1:0\"\npeers = [\"127.0.0.1:1\"]",
+        )
+        .unwrap();
+        let node = Node::builder().config(config).spawn().await.unwrap();
+        let ping = |peers| {
+            node.server()
+                .ping(tonic::Request::new(PingRequest { peers }))
+        };
+
+        let pong = ping(false).await.unwrap().into_inner();
+        assert_eq!(pong.node_id, "n1");
+        assert!(pong.peers.is_empty());
+
+        let pong = ping(true).await.unwrap().into_inner();
+        assert_eq!(pong.peers.len(), 1);
+        assert_eq!(pong.peers[0].address, "127.0.0.1:1");
+        assert_eq!(pong.peers[0].node_id, "");
+        assert!(!pong.peers[0].error.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_counter_set_keeps_other_nodes_counts() {
+        let config = testing::config("n1", "");
+        let node = Node::builder().config(config).spawn().await.unwrap();
+        let likes = node.counter("likes");
+        likes.set(3).await.unwrap();
+        //as if n2's increment had been merged in
+        let from_n2 = mergedb_types::pn_counter::PNCounter::new("n2".to_string(), 4, 0);
+        node.server()
+            .store
+            .get_mut("likes")
+            .unwrap()
+            .data
+            .merge_with(&CrdtValue::Counter(from_n2))
+            .unwrap();
+        assert_eq!(likes.value().unwrap(), 7);
+
+        likes.set(-2).await.unwrap();
+        assert_eq!(likes.value().unwrap(), -2);
+        let Some(CrdtValue::Counter(counter)) = node.get("likes") else {
+            panic!("likes is a counter");
+        };
+        assert_eq!(counter.value_by_node()["n2"], 4);
+        assert_eq!(counter.value_by_node()["n1"], -6);
+
+        //a key holding something else is refused and keeps what it holds
+        node.register("name").set("x").await.unwrap();
+        let refused = node.counter("name").set(5).await.unwrap_err();
+        assert_eq!(refused.code(), tonic::Code::FailedPrecondition);
+        assert_eq!(node.register("name").get().unwrap(), "x");
+    }
+    //a max register standing in for an application's own crdt
+    #[derive(Debug, Clone, PartialEq)]
+    struct Max(u64);
+
+    impl mergedb_types::Merge for Max {
+        fn merge(&mut self, other: &Self) {
+            self.0 = self.0.max(other.0);
+        }
+    }
+
+    impl Plugin for Max {
+        const TAG: &'static str = "test.node_max";
+
+        fn encode(&self) -> Vec<u8> {
+            self.0.to_le_bytes().to_vec()
+        }
+
+        fn decode(bytes: &[u8]) -> Result<Self, String> {
+            let bytes: [u8; 8] = bytes.try_into().map_err(|_| "not 8 bytes".to_string())?;
+            Ok(Max(u64::from_le_bytes(bytes)))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_copy_and_rename() {
+        let config = testing::config("n1", "");
+        let node = Node::builder().config(config).spawn().await.unwrap();
+        let cart = node.set("cart");
+        cart.add("apple").await.unwrap();
+        cart.add("pear").await.unwrap();
+        node.counter("views").set(1).await.unwrap();
+
+        node.execute(command("COPY", "cart", "backup")).await.unwrap();
+        node.execute(command("RENAME", "cart", "basket")).await.unwrap();
+        for key in ["backup", "basket"] {
+            assert_eq!(node.get(key), node.get("cart"));
+        }
+        let read = node.execute(command("SGET", "cart", "")).await;
+        assert_eq!(read.unwrap_err().code(), tonic::Code::NotFound);
+        let write = node.execute(command("SADD", "cart", "plum")).await;
+        assert_eq!(write.unwrap_err().code(), tonic::Code::FailedPrecondition);
+
+        //a write made elsewhere before the rename reached that node still ends up in basket
+        let Some(CrdtValue::Set(mut late)) = node.get("cart") else {
+            panic!("cart is a set");
+        };
+        late.add("fig", "n2".to_string());
+        node.server().merge_remote("cart".to_string(), CrdtValue::Set(late));
+        assert!(node.set("basket").contains("fig").unwrap());
+        assert!(!node.set("backup").contains("fig").unwrap());
+
+        let onto_counter = node.execute(command("COPY", "basket", "views")).await;
+        assert_eq!(onto_counter.unwrap_err().code(), tonic::Code::FailedPrecondition);
+        let onto_itself = node.execute(command("RENAME", "views", "views")).await;
+        assert_eq!(onto_itself.unwrap_err().code(), tonic::Code::InvalidArgument);
+    }
+
+    #[tokio::test]
+    async fn test_custom_crdts() {
+        let config = testing::config("n1", "");
+        let node = Node::builder().config(config).crdt::<Max>().spawn().await.unwrap();
+
+        let peak = node.custom::<Max>("peak");
+        peak.merge(&Max(3)).await.unwrap();
+        peak.merge(&Max(9)).await.unwrap();
+        peak.merge(&Max(4)).await.unwrap();
+        assert_eq!(peak.get().unwrap(), Max(9));
+
+        //a peer's state of a type this node can't merge is kept whole
+        let unknown = CrdtValue::Custom(CustomValue::new("test.unknown", vec![1]));
+        node.server().merge_remote("opaque".to_string(), unknown.clone());
+        assert_eq!(node.get("opaque"), Some(unknown));
+
+        assert_eq!(
+            node.custom::<Max>("opaque").get().unwrap_err().code(),
+            tonic::Code::FailedPrecondition
+        );
+        node.register("name").set("mergedb").await.unwrap();
+        assert!(node.custom::<Max>("name").merge(&Max(1)).await.is_err());
+    }
+}
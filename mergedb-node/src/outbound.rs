@@ -0,0 +1,225 @@
+//what each peer is still owed, kept next to the snapshot so that a restart doesn't forget it.
+//a key is owed to a peer when it changed after its priority class last went out to the peer.
+//the list is written with every snapshot, so both describe the same moment. on startup a peer
+//with a saved list is resumed: it gets that list plus whatever changes from then on, instead
+//of the whole store again. a peer without one (never reached, or owed more than MAX_OWED keys)
+//gets everything, as before
+use anyhow::{Context, Result};
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashSet},
+    fs,
+    path::Path,
+    time::SystemTime,
+};
+
+use crate::{network::StoredValue, priority::Schedule};
+
+pub const OUTBOUND_FILE: &str = "outbound.json";
+//more than this and the peer is simply sent everything after a restart
+pub const MAX_OWED: usize = 100_000;
+
+//peer address -> the keys it is owed, None when it needs everything
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
+pub struct Owed {
+    pub peers: BTreeMap<String, Option<Vec<String>>>,
+}
+
+#[derive(Debug)]
+pub struct Outbound {
+    //(peer, priority class) -> when the class last went out to the peer in full
+    sent: DashMap<(String, usize), SystemTime>,
+    //peers that had a list saved, they only need changes from started_at on
+    resumed: HashSet<String>,
+    //what is left of the saved lists, a peer's is dropped once it was delivered
+    owed: DashMap<String, Vec<String>>,
+    started_at: SystemTime,
+}
+
+impl Default for Outbound {
+    fn default() -> Self {
+        Outbound {
+            sent: DashMap::new(),
+            resumed: HashSet::new(),
+            owed: DashMap::new(),
+            started_at: SystemTime::now(),
+        }
+    }
+}
+
+impl Outbound {
+    //picks up the lists saved in `dir`, nothing saved yet just means no peer is resumed
+    pub fn restore(dir: &Path) -> Result<Self> {
+        let path = dir.join(OUTBOUND_FILE);
+        let mut outbound = Outbound::default();
+        let raw = match fs::read(&path) {
+            Ok(raw) => raw,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(outbound),
+            Err(e) => return Err(e).with_context(|| format!("failed to read {}", path.display())),
+        };
+        let saved: Owed = serde_json::from_slice(&raw)
+            .with_context(|| format!("{} is not an outbound list", path.display()))?;
+        for (peer_addr, keys) in saved.peers {
+            if let Some(keys) = keys {
+                outbound.resumed.insert(peer_addr.clone());
+                outbound.owed.insert(peer_addr, keys);
+            }
+        }
+        Ok(outbound)
+    }
+
+    //(resumed peers, keys they are owed between them)
+    pub fn restored(&self) -> (usize, usize) {
+        let keys = self.owed.iter().map(|entry| entry.value().len()).sum();
+        (self.resumed.len(), keys)
+    }
+
+    pub fn sent_at(&self, peer_addr: &str, class: usize) -> Option<SystemTime> {
+        self.sent
+            .get(&(peer_addr.to_string(), class))
+            .map(|sent| *sent)
+    }
+
+    pub fn mark_sent(&self, peer_addr: &str, class: usize, at: SystemTime) {
+        self.sent.insert((peer_addr.to_string(), class), at);
+    }
+
+    //keys of the class that changed at or after this are owed to the peer
+    pub fn dirty_since(&self, peer_addr: &str, class: usize) -> SystemTime {
+        self.sent_at(peer_addr, class)
+            .unwrap_or_else(|| match self.resumed.contains(peer_addr) {
+                true => self.started_at,
+                false => SystemTime::UNIX_EPOCH,
+            })
+    }
+
+    //the saved list still waiting to go out to the peer
+    pub fn owed(&self, peer_addr: &str) -> Vec<String> {
+        self.owed
+            .get(peer_addr)
+            .map(|keys| keys.clone())
+            .unwrap_or_default()
+    }
+
+    pub fn delivered(&self, peer_addr: &str) {
+        self.owed.remove(peer_addr);
+    }
+
+    //what last_updated a value loaded from the snapshot gets. when every peer was resumed none
+    //of them needs it, unless it changes, so it is stamped as long unchanged. otherwise it's
+    //new to some peer and has to go out like any change
+    pub fn loaded_stamp(&self, peer_addrs: &[String]) -> SystemTime {
+        match peer_addrs
+            .iter()
+            .all(|peer_addr| self.resumed.contains(peer_addr))
+        {
+            true => SystemTime::UNIX_EPOCH,
+            false => SystemTime::now(),
+        }
+    }
+
+    //what every peer is owed right now
+    pub fn collect(
+        &self,
+        store: &DashMap<String, StoredValue>,
+        peer_addrs: &[String],
+        schedule: &Schedule,
+    ) -> Owed {
+        let mut owed = Owed::default();
+        for peer_addr in peer_addrs {
+            let needs_everything = (0..schedule.classes.len())
+                .any(|class| self.dirty_since(peer_addr, class) == SystemTime::UNIX_EPOCH);
+            let keys = match needs_everything {
+                true => None,
+                false => {
+                    let mut keys: Vec<String> = store
+                        .iter()
+                        .filter(|entry| {
+                            let class = schedule.class_of(entry.key());
+                            entry.value().last_updated >= self.dirty_since(peer_addr, class)
+                        })
+                        .map(|entry| entry.key().clone())
+                        .collect();
+                    keys.extend(self.owed(peer_addr));
+                    keys.sort();
+                    keys.dedup();
+                    (keys.len() <= MAX_OWED).then_some(keys)
+                }
+            };
+            owed.peers.insert(peer_addr.clone(), keys);
+        }
+        owed
+    }
+
+    //through a temporary file, like the snapshot
+    pub fn save(dir: &Path, owed: &Owed) -> Result<()> {
+        let path = dir.join(OUTBOUND_FILE);
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_vec(owed)?)?;
+        fs::rename(&tmp, &path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mergedb_types::{pn_counter::PNCounter, CrdtValue};
+    use std::time::Duration;
+
+    fn stored(last_updated: SystemTime) -> StoredValue {
+        StoredValue {
+            data: CrdtValue::Counter(PNCounter::new("node_1".to_string(), 1, 0)),
+            last_updated,
+        }
+    }
+
+    #[test]
+    fn test_owed_keys_survive_a_restart() {
+        let dir = std::env::temp_dir().join(format!("mergedb-outbound-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let schedule = Schedule::new(Duration::from_secs(2), &[]);
+        let outbound = Outbound::default();
+        let round = SystemTime::now();
+        outbound.mark_sent("10.0.0.2:8000", 0, round);
+        let store = DashMap::new();
+        store.insert("sent".to_string(), stored(round - Duration::from_secs(1)));
+        store.insert("unsent".to_string(), stored(round + Duration::from_secs(1)));
+
+        let peers = ["10.0.0.2:8000".to_string(), "10.0.0.3:8000".to_string()];
+        let owed = outbound.collect(&store, &peers, &schedule);
+        assert_eq!(
+            owed.peers["10.0.0.2:8000"],
+            Some(vec!["unsent".to_string()])
+        );
+        //never reached, so it gets everything
+        assert_eq!(owed.peers["10.0.0.3:8000"], None);
+        Outbound::save(&dir, &owed).unwrap();
+
+        let restored = Outbound::restore(&dir).unwrap();
+        assert_eq!(restored.restored(), (1, 1));
+        assert_eq!(restored.owed("10.0.0.2:8000"), ["unsent"]);
+        assert_eq!(
+            restored.dirty_since("10.0.0.2:8000", 0),
+            restored.started_at
+        );
+        assert_eq!(
+            restored.dirty_since("10.0.0.3:8000", 0),
+            SystemTime::UNIX_EPOCH
+        );
+        //the peer that wasn't resumed needs whatever comes out of the snapshot
+        assert_ne!(restored.loaded_stamp(&peers), SystemTime::UNIX_EPOCH);
+        assert_eq!(restored.loaded_stamp(&peers[..1]), SystemTime::UNIX_EPOCH);
+
+        restored.delivered("10.0.0.2:8000");
+        assert!(restored.owed("10.0.0.2:8000").is_empty());
+        assert!(Outbound::restore(&dir.join("missing"))
+            .unwrap()
+            .resumed
+            .is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,122 @@
+//client writes can be paused, eg during a migration or while an incident is looked into. a
+//paused node answers every client write with UNAVAILABLE and the reason it was paused for,
+//reads and gossip carry on. a cluster wide pause is an lww register under KEY holding the
+//reason, so it gossips like any other key and reaches nodes that were down when it was set. a
+//node's own pause only lives in its memory and is gone after a restart. an empty register is
+//no pause
+use std::sync::Mutex;
+
+pub const KEY: &str = "__paused";
+//what an empty reason is stored as, the register has to be non-empty to mean paused
+pub const NO_REASON: &str = "no reason given";
+
+//the register is only ever written through the Pause and Resume rpcs
+pub fn is_pause(key: &str) -> bool {
+    key == KEY
+}
+
+//what a client write is refused with, the node's own pause comes first
+pub fn refusal(node_id: &str, local: Option<&str>, cluster: Option<&str>) -> Option<String> {
+    match (local, cluster) {
+        (Some(reason), _) => Some(format!("{} is paused: {}", node_id, reason)),
+        (None, Some(reason)) => Some(format!("the cluster is paused: {}", reason)),
+        (None, None) => None,
+    }
+}
+
+//this node's own pause
+#[derive(Debug, Default)]
+pub struct Pause {
+    reason: Mutex<Option<String>>,
+}
+
+impl Pause {
+    pub fn set(&self, reason: &str) {
+        let reason = match reason.trim() {
+            "" => NO_REASON,
+            reason => reason,
+        };
+        *self.reason.lock().unwrap() = Some(reason.to_string());
+    }
+
+    pub fn clear(&self) {
+        *self.reason.lock().unwrap() = None;
+    }
+
+    pub fn reason(&self) -> Option<String> {
+        self.reason.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        communication::{
+            replication_service_server::ReplicationService, PauseRequest, ResumeRequest,
+        },
+        testing::{request, server},
+    };
+    use tonic::Request;
+
+    #[test]
+    fn test_pause() {
+        let pause = Pause::default();
+        assert_eq!(pause.reason(), None);
+        pause.set("  ");
+        assert_eq!(pause.reason().as_deref(), Some(NO_REASON));
+        pause.set("moving to new disks");
+        assert_eq!(pause.reason().as_deref(), Some("moving to new disks"));
+        pause.clear();
+        assert_eq!(pause.reason(), None);
+
+        assert_eq!(refusal("node_1", None, None), None);
+        assert_eq!(
+            refusal("node_1", None, Some("migration")).as_deref(),
+            Some("the cluster is paused: migration")
+        );
+        assert_eq!(
+            refusal("node_1", Some("incident"), Some("migration")).as_deref(),
+            Some("node_1 is paused: incident")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_paused_writes_are_refused() {
+        let server = server("n1");
+        server.propagate_data(request("RSET", "k", "v")).await.unwrap();
+
+        let pause = |reason: &str, cluster| {
+            Request::new(PauseRequest {
+                reason: reason.to_string(),
+                cluster,
+            })
+        };
+        server.pause(pause("incident", false)).await.unwrap();
+        let refused = server.propagate_data(request("RSET", "k", "w")).await.unwrap_err();
+        assert_eq!(refused.code(), tonic::Code::Unavailable);
+        assert_eq!(refused.message(), "n1 is paused: incident");
+        assert!(server.propagate_data(request("RGET", "k", "")).await.is_ok());
+
+        //the node's own pause is lifted, the cluster wide one still holds
+        server.pause(pause("", true)).await.unwrap();
+        let resumed = server
+            .resume(Request::new(ResumeRequest { cluster: false }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(resumed.paused, format!("the cluster is paused: {}", NO_REASON));
+        let refused = server.propagate_data(request("RSET", "k", "w")).await.unwrap_err();
+        assert_eq!(refused.code(), tonic::Code::Unavailable);
+        assert!(server.propagate_data(request("FREEZE", "k", "")).await.is_ok());
+        assert!(server.propagate_data(request("THAW", "k", "")).await.is_ok());
+
+        let resumed = server
+            .resume(Request::new(ResumeRequest { cluster: true }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(resumed.paused.is_empty());
+        assert!(server.propagate_data(request("RSET", "k", "w")).await.is_ok());
+    }
+}
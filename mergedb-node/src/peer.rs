@@ -0,0 +1,114 @@
+use anyhow::{bail, Context, Result};
+use std::time::Duration;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+use tonic::transport::{Certificate, Channel, ClientTlsConfig, Endpoint, Uri};
+
+use crate::{
+    communication::replication_service_client::ReplicationServiceClient, config::PeerConfig,
+};
+
+//builds a channel to a peer honouring its scheme, tls and proxy options
+pub async fn connect(peer: &PeerConfig) -> Result<ReplicationServiceClient<Channel>> {
+    let mut endpoint = Endpoint::from_shared(peer.uri())
+        .with_context(|| format!("invalid peer address {}", peer.address))?;
+
+    if let Some(timeout_ms) = peer.connect_timeout_ms {
+        endpoint = endpoint.connect_timeout(Duration::from_millis(timeout_ms));
+    }
+
+    if peer.uses_tls() {
+        let mut tls = ClientTlsConfig::new();
+        if let Some(options) = &peer.tls {
+            if let Some(domain_name) = &options.domain_name {
+                tls = tls.domain_name(domain_name.clone());
+            }
+            if let Some(ca_path) = &options.ca_cert {
+                let pem = tokio::fs::read(ca_path)
+                    .await
+                    .with_context(|| format!("failed to read ca_cert {}", ca_path.display()))?;
+                tls = tls.ca_certificate(Certificate::from_pem(pem));
+            }
+        }
+        endpoint = endpoint.tls_config(tls)?;
+    }
+
+    let channel = match &peer.proxy {
+        Some(proxy) => {
+            let proxy_addr = proxy_authority(proxy)?;
+            endpoint
+                .connect_with_connector(tower::service_fn(move |target: Uri| {
+                    let proxy_addr = proxy_addr.clone();
+                    async move { tunnel(&proxy_addr, &target).await }
+                }))
+                .await?
+        }
+        None => endpoint.connect().await?,
+    };
+
+    Ok(ReplicationServiceClient::new(channel))
+}
+
+//"http://proxy:3128" -> "proxy:3128"
+fn proxy_authority(proxy: &str) -> Result<String> {
+    let uri: Uri = proxy
+        .parse()
+        .with_context(|| format!("invalid proxy url {}", proxy))?;
+    match (uri.scheme_str(), uri.authority()) {
+        (Some("http") | None, Some(authority)) => {
+            let port = authority.port_u16().unwrap_or(80);
+            Ok(format!("{}:{}", authority.host(), port))
+        }
+        (Some(scheme), _) if scheme != "http" => {
+            bail!(
+                "unsupported proxy scheme {}, only http CONNECT proxies are supported",
+                scheme
+            )
+        }
+        _ => bail!("proxy url {} has no host", proxy),
+    }
+}
+
+//opens a CONNECT tunnel through the proxy, tls (if any) is layered on top of it by tonic
+async fn tunnel(proxy_addr: &str, target: &Uri) -> std::io::Result<TcpStream> {
+    let host = target.host().unwrap_or_default();
+    let port = target.port_u16().unwrap_or(match target.scheme_str() {
+        Some("https") => 443,
+        _ => 80,
+    });
+    let authority = format!("{}:{}", host, port);
+
+    let mut stream = TcpStream::connect(proxy_addr).await?;
+    let connect_req = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n\r\n", authority);
+    stream.write_all(connect_req.as_bytes()).await?;
+
+    //read the proxy's response headers, byte by byte so nothing after them gets consumed
+    let mut response = Vec::new();
+    let mut byte = [0u8; 1];
+    while !response.ends_with(b"\r\n\r\n") {
+        if stream.read(&mut byte).await? == 0 || response.len() > 8192 {
+            return Err(std::io::Error::other(
+                "proxy closed the connection during CONNECT",
+            ));
+        }
+        response.push(byte[0]);
+    }
+
+    let status_line = String::from_utf8_lossy(&response);
+    let status_ok = status_line
+        .split_whitespace()
+        .nth(1)
+        .map(|code| code == "200")
+        .unwrap_or(false);
+    if !status_ok {
+        return Err(std::io::Error::other(format!(
+            "proxy refused CONNECT to {}: {}",
+            authority,
+            status_line.lines().next().unwrap_or_default()
+        )));
+    }
+
+    Ok(stream)
+}
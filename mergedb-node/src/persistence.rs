@@ -0,0 +1,607 @@
+use anyhow::{bail, Context, Result};
+use dashmap::DashMap;
+use memmap2::{Advice, Mmap};
+use mergedb_proto::{communication::CrdtData, CrdtProto};
+use mergedb_types::CrdtValue;
+use prost::{
+    encoding::{decode_varint, encode_varint},
+    Message,
+};
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions, TryLockError},
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Mutex,
+    },
+};
+
+use crate::network::StoredValue;
+
+//a snapshot is the magic followed by one record per key: a varint length and the key, then
+//a varint length and the key's CRDTData, then a little-endian crc32 of everything before it in
+//the record.
+//values carry their own format version, so older snapshots load through the same upgrade path
+//as gossip from older nodes. MDBSNAP1 snapshots are the same without the checksums
+const MAGIC: &[u8; 8] = b"MDBSNAP2";
+const MAGIC_V1: &[u8; 8] = b"MDBSNAP1";
+pub const SNAPSHOT_FILE: &str = "store.snapshot";
+//locked by the node holding the data_dir
+pub const LOCK_FILE: &str = "LOCK";
+//values copied out of the store at a time while snapshotting
+const SNAPSHOT_CHUNK: usize = 1000;
+
+//where a value sits in the snapshot file. `start` is where its record begins, and `checksum`
+//what the record carries, if the snapshot has them
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Location {
+    pub start: usize,
+    pub offset: usize,
+    pub len: usize,
+    checksum: Option<u32>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Record {
+    pub key: String,
+    pub location: Location,
+}
+
+//the records of a snapshot up to the first one that isn't whole. `valid_len` is where the last
+//whole record ends, `damage` says what was wrong with whatever follows it
+#[derive(Debug, Default)]
+pub struct Scan {
+    pub checksummed: bool,
+    pub records: Vec<Record>,
+    pub valid_len: usize,
+    pub damage: Option<String>,
+}
+
+//the snapshot on disk and how much of it has made it into the store. startup only reads the
+//keys, values get loaded when a preload prefix asks for them, when a command touches the key,
+//or by the background warm-up, whichever comes first.
+//the snapshot is memory mapped rather than read in, so a value only costs memory once it is
+//decoded into the store and the file's pages stay reclaimable page cache
+#[derive(Debug, Default)]
+pub struct Persistence {
+    pub data_dir: Option<PathBuf>,
+    snapshot: Option<Mmap>,
+    pending: Mutex<HashMap<String, Location>>,
+    pub snapshot_keys: usize,
+    pub loaded: AtomicUsize,
+    pub warm: AtomicBool,
+    //a value failed to load, so the snapshot is the only copy left of it
+    pub damaged: AtomicBool,
+    //held for as long as the node runs, so fsck keeps its hands off the files
+    _lock: Option<File>,
+}
+
+impl Persistence {
+    //nothing on disk yet (or no data_dir) just means there is nothing to load
+    pub fn open(data_dir: Option<PathBuf>) -> Result<Self> {
+        let Some(dir) = data_dir else {
+            return Ok(Persistence {
+                warm: AtomicBool::new(true),
+                ..Persistence::default()
+            });
+        };
+        fs::create_dir_all(&dir)
+            .with_context(|| format!("failed to create data_dir {}", dir.display()))?;
+        let lock = lock(&dir)?;
+
+        let path = dir.join(SNAPSHOT_FILE);
+        let snapshot = if path.exists() {
+            Some(map(&path).with_context(|| format!("failed to map {}", path.display()))?)
+        } else {
+            None
+        };
+        let pending = match &snapshot {
+            Some(snapshot) => index(snapshot).with_context(|| {
+                format!(
+                    "failed to read {}, run `mergedb-node fsck` to check it",
+                    path.display()
+                )
+            })?,
+            None => HashMap::new(),
+        };
+        Ok(Persistence {
+            data_dir: Some(dir),
+            snapshot,
+            snapshot_keys: pending.len(),
+            warm: AtomicBool::new(pending.is_empty()),
+            pending: Mutex::new(pending),
+            loaded: AtomicUsize::new(0),
+            damaged: AtomicBool::new(false),
+            _lock: Some(lock),
+        })
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.data_dir.is_some()
+    }
+
+    pub fn snapshot_path(&self) -> Option<PathBuf> {
+        self.data_dir.as_ref().map(|dir| dir.join(SNAPSHOT_FILE))
+    }
+
+    pub fn is_warm(&self) -> bool {
+        self.warm.load(Ordering::SeqCst)
+    }
+
+    //the key's value from the snapshot if it hasn't been loaded yet, each key is handed out
+    //only once
+    pub fn take(&self, key: &str) -> Result<Option<CrdtValue>> {
+        let location = {
+            let mut pending = self.pending.lock().unwrap();
+            let location = pending.remove(key);
+            if location.is_some() && pending.is_empty() {
+                self.warm.store(true, Ordering::SeqCst);
+            }
+            location
+        };
+        let Some(location) = location else {
+            return Ok(None);
+        };
+        let value = self.read(location).inspect_err(|_| {
+            self.damaged.store(true, Ordering::SeqCst);
+        })?;
+        self.loaded.fetch_add(1, Ordering::SeqCst);
+        Ok(Some(value))
+    }
+
+    //keys still waiting to be loaded, only those under `prefixes` if any are given
+    pub fn pending_keys(&self, prefixes: &[String]) -> Vec<String> {
+        self.pending
+            .lock()
+            .unwrap()
+            .keys()
+            .filter(|key| prefixes.is_empty() || prefixes.iter().any(|p| key.starts_with(p)))
+            .cloned()
+            .collect()
+    }
+
+    fn read(&self, location: Location) -> Result<CrdtValue> {
+        let snapshot = self
+            .snapshot
+            .as_ref()
+            .context("there is no snapshot to read")?;
+        let raw = &snapshot[location.offset..location.offset + location.len];
+        if !location.intact(snapshot) {
+            bail!("snapshot value fails its checksum, run `mergedb-node fsck` to check it");
+        }
+        Ok(CrdtValue::from_proto(CrdtData::decode(raw)?)?)
+    }
+
+    //writes the whole store, through a temporary file so a crash mid-write leaves the last
+    //snapshot intact. must not run before the store is warm, unloaded keys would be lost.
+    //writes carry on meanwhile: values are copied out a chunk at a time, each entry only locked
+    //while it is cloned, and encoded and written with no lock held. so the snapshot isn't one
+    //moment of the store, every value is just at least as new as when the snapshot started.
+    //the wal is rotated right before, so snapshot + wal since still merges to the live state
+    pub fn write_snapshot(&self, store: &DashMap<String, StoredValue>) -> Result<usize> {
+        let path = self.snapshot_path().context("persistence is not enabled")?;
+        if !self.is_warm() {
+            bail!("store is still loading, not overwriting the snapshot");
+        }
+        if self.damaged.load(Ordering::SeqCst) {
+            bail!("values failed to load, not overwriting the snapshot until fsck has run");
+        }
+        let tmp = path.with_extension("tmp");
+        let mut out = BufWriter::new(File::create(&tmp)?);
+        out.write_all(MAGIC)?;
+
+        let keys: Vec<String> = store.iter().map(|entry| entry.key().clone()).collect();
+        let mut written = 0;
+        let mut buf = Vec::new();
+        for chunk in keys.chunks(SNAPSHOT_CHUNK) {
+            let values: Vec<(&String, CrdtValue)> = chunk
+                .iter()
+                .filter_map(|key| store.get(key).map(|stored| (key, stored.data.clone())))
+                .collect();
+            for (key, data) in values {
+                buf.clear();
+                encode_record(key, &data, &mut buf)?;
+                out.write_all(&buf)?;
+                written += 1;
+            }
+        }
+
+        let file = out.into_inner()?;
+        file.sync_all()?;
+        fs::rename(&tmp, &path)?;
+        Ok(written)
+    }
+}
+
+//takes the data_dir's lock, which the os lets go of however its holder exits
+pub fn lock(dir: &Path) -> Result<File> {
+    let path = dir.join(LOCK_FILE);
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&path)
+        .with_context(|| format!("failed to open {}", path.display()))?;
+    match file.try_lock() {
+        Ok(()) => Ok(file),
+        Err(TryLockError::WouldBlock) => bail!("{} is held by a running node", dir.display()),
+        Err(TryLockError::Error(e)) => {
+            Err(e).with_context(|| format!("failed to lock {}", path.display()))
+        }
+    }
+}
+
+//appends one checksummed record, the way the snapshot (and the wal) stores a key
+pub fn encode_record(key: &str, data: &CrdtValue, buf: &mut Vec<u8>) -> Result<()> {
+    let start = buf.len();
+    let wire = data.clone().to_proto();
+    encode_varint(key.len() as u64, buf);
+    buf.extend_from_slice(key.as_bytes());
+    encode_varint(wire.encoded_len() as u64, buf);
+    wire.encode(buf)?;
+    let checksum = crc32fast::hash(&buf[start..]);
+    buf.extend_from_slice(&checksum.to_le_bytes());
+    Ok(())
+}
+
+//the checksummed records in `log` after its first `skip` bytes, up to the first one that isn't
+//whole, fails its checksum or doesn't decode. also gives where the last good one ends
+pub fn read_log(log: &[u8], skip: usize) -> (Vec<(String, CrdtValue)>, usize) {
+    let mut records = Vec::new();
+    let valid_len = scan_log(log, skip, |key, value, _| records.push((key, value)));
+    (records, valid_len)
+}
+
+//read_log a record at a time, `each` also gets how many bytes the record took up
+pub fn scan_log(
+    log: &[u8],
+    skip: usize,
+    mut each: impl FnMut(String, CrdtValue, usize),
+) -> usize {
+    let mut rest = &log[skip.min(log.len())..];
+    let mut valid_len = log.len() - rest.len();
+    while !rest.is_empty() {
+        let Ok(record) = next_record(log, &mut rest, true) else {
+            break;
+        };
+        let location = record.location;
+        let raw = &log[location.offset..location.offset + location.len];
+        let value = match location.intact(log) {
+            true => CrdtData::decode(raw).map_err(anyhow::Error::from),
+            false => Err(anyhow::anyhow!("checksum mismatch")),
+        }
+        .and_then(|wire| Ok(CrdtValue::from_proto(wire)?));
+        let Ok(value) = value else {
+            break;
+        };
+        let end = log.len() - rest.len();
+        each(record.key, value, end - valid_len);
+        valid_len = end;
+    }
+    valid_len
+}
+
+//the snapshot is only ever replaced by renaming a new file over it, so the mapped file itself
+//is never written to while the map is alive
+fn map(path: &Path) -> Result<Mmap> {
+    let file = File::open(path)?;
+    //SAFETY: see above, nothing truncates or writes the file in place
+    let snapshot = unsafe { Mmap::map(&file)? };
+    //values are loaded in whatever order keys get asked for
+    snapshot.advise(Advice::Random)?;
+    Ok(snapshot)
+}
+
+impl Location {
+    //whether the record still matches the checksum it was written with. MDBSNAP1 records have
+    //none and always pass
+    pub fn intact(&self, snapshot: &[u8]) -> bool {
+        match self.checksum {
+            Some(expected) => {
+                crc32fast::hash(&snapshot[self.start..self.offset + self.len]) == expected
+            }
+            None => true,
+        }
+    }
+}
+
+//walks the records without decoding the values or checking their checksums. only a file that
+//isn't a snapshot at all is an error, anything after the last whole record ends up in `damage`
+pub fn scan(snapshot: &[u8]) -> Result<Scan> {
+    let (checksummed, mut rest) = if let Some(rest) = snapshot.strip_prefix(MAGIC.as_slice()) {
+        (true, rest)
+    } else if let Some(rest) = snapshot.strip_prefix(MAGIC_V1.as_slice()) {
+        (false, rest)
+    } else {
+        bail!("not a mergeDB snapshot");
+    };
+
+    let mut scan = Scan {
+        checksummed,
+        valid_len: MAGIC.len(),
+        ..Scan::default()
+    };
+    while !rest.is_empty() {
+        match next_record(snapshot, &mut rest, checksummed) {
+            Ok(record) => {
+                scan.valid_len = snapshot.len() - rest.len();
+                scan.records.push(record);
+            }
+            Err(damage) => {
+                scan.damage = Some(format!("{} at byte {}", damage, scan.valid_len));
+                break;
+            }
+        }
+    }
+    Ok(scan)
+}
+
+fn next_record(snapshot: &[u8], rest: &mut &[u8], checksummed: bool) -> Result<Record> {
+    let start = snapshot.len() - rest.len();
+    let key_len = decode_varint(rest).context("snapshot is truncated")? as usize;
+    if key_len > rest.len() {
+        bail!("snapshot is truncated");
+    }
+    let (key, tail) = rest.split_at(key_len);
+    let key = String::from_utf8(key.to_vec()).context("snapshot key is not utf-8")?;
+    *rest = tail;
+
+    let value_len = decode_varint(rest)
+        .with_context(|| format!("snapshot ends in the middle of {}", key))?
+        as usize;
+    if value_len > rest.len() {
+        bail!("snapshot is truncated in the value of {}", key);
+    }
+    let offset = snapshot.len() - rest.len();
+    *rest = &rest[value_len..];
+
+    let checksum = if checksummed {
+        let Some((checksum, tail)) = rest.split_first_chunk::<4>() else {
+            bail!("snapshot is truncated in the checksum of {}", key);
+        };
+        *rest = tail;
+        Some(u32::from_le_bytes(*checksum))
+    } else {
+        None
+    };
+    Ok(Record {
+        key,
+        location: Location {
+            start,
+            offset,
+            len: value_len,
+            checksum,
+        },
+    })
+}
+
+//every key and where its value is. the node won't start on a damaged snapshot, that is for
+//fsck to look at and repair
+fn index(snapshot: &[u8]) -> Result<HashMap<String, Location>> {
+    let scan = scan(snapshot)?;
+    if let Some(damage) = scan.damage {
+        bail!(damage);
+    }
+    Ok(scan
+        .records
+        .into_iter()
+        .map(|record| (record.key, record.location))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wal::{Wal, WalSync};
+    use mergedb_types::{aw_set::AWSet, pn_counter::PNCounter};
+    use std::{
+        sync::{atomic::AtomicU64, Arc},
+        time::SystemTime,
+    };
+
+    fn stored(data: CrdtValue) -> StoredValue {
+        StoredValue {
+            data,
+            last_updated: SystemTime::now(),
+        }
+    }
+
+    #[test]
+    fn test_snapshot_round_trip_loads_lazily() {
+        let dir = std::env::temp_dir().join(format!("mergedb-persistence-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        let store = DashMap::new();
+        let mut set = AWSet::new();
+        set.add("x".to_string(), "node_1".to_string());
+        store.insert(
+            "flags:dark".to_string(),
+            stored(CrdtValue::Set(set.clone())),
+        );
+        store.insert(
+            "views".to_string(),
+            stored(CrdtValue::Counter(PNCounter::new(
+                "node_1".to_string(),
+                7,
+                0,
+            ))),
+        );
+
+        let persistence = Persistence::open(Some(dir.clone())).unwrap();
+        assert!(persistence.is_warm());
+        assert_eq!(persistence.write_snapshot(&store).unwrap(), 2);
+        assert!(Persistence::open(Some(dir.clone())).is_err());
+        drop(persistence);
+
+        let reopened = Persistence::open(Some(dir.clone())).unwrap();
+        assert_eq!(reopened.snapshot_keys, 2);
+        assert!(!reopened.is_warm());
+        assert!(reopened.write_snapshot(&DashMap::new()).is_err());
+        assert_eq!(
+            reopened.pending_keys(&["flags:".to_string()]),
+            ["flags:dark"]
+        );
+
+        assert_eq!(
+            reopened.take("flags:dark").unwrap(),
+            Some(CrdtValue::Set(set))
+        );
+        assert_eq!(reopened.take("flags:dark").unwrap(), None);
+        assert_eq!(reopened.pending_keys(&[]), ["views"]);
+        assert_eq!(reopened.loaded.load(Ordering::SeqCst), 1);
+        assert!(reopened.take("views").unwrap().is_some());
+        assert!(reopened.is_warm());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_garbage_is_not_a_snapshot() {
+        let dir = std::env::temp_dir().join(format!("mergedb-garbage-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join(SNAPSHOT_FILE), b"definitely not").unwrap();
+        assert!(Persistence::open(Some(dir.clone())).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_truncated_values_are_caught_by_the_index() {
+        let mut snapshot = MAGIC_V1.to_vec();
+        encode_varint(1, &mut snapshot);
+        snapshot.push(b'k');
+        encode_varint(10, &mut snapshot);
+        snapshot.extend_from_slice(&[0; 4]);
+        assert!(index(&snapshot).is_err());
+
+        snapshot.extend_from_slice(&[0; 6]);
+        let entries = index(&snapshot).unwrap();
+        assert_eq!(
+            entries["k"],
+            Location {
+                start: 8,
+                offset: 11,
+                len: 10,
+                checksum: None
+            }
+        );
+    }
+
+    #[test]
+    fn test_checksums_catch_flipped_bytes() {
+        let mut snapshot = MAGIC.to_vec();
+        let mut record = Vec::new();
+        encode_varint(1, &mut record);
+        record.push(b'k');
+        encode_varint(2, &mut record);
+        record.extend_from_slice(b"ab");
+        let checksum = crc32fast::hash(&record);
+        snapshot.extend_from_slice(&record);
+        snapshot.extend_from_slice(&checksum.to_le_bytes());
+
+        let clean = scan(&snapshot).unwrap();
+        assert!(clean.damage.is_none());
+        assert_eq!(clean.valid_len, snapshot.len());
+        assert!(clean.records[0].location.intact(&snapshot));
+
+        snapshot[12] = b'x';
+        assert!(!clean.records[0].location.intact(&snapshot));
+
+        //a record cut off in its checksum is damage, the whole ones before it still count
+        snapshot.extend_from_slice(&record);
+        snapshot.extend_from_slice(&[0; 2]);
+        let cut = scan(&snapshot).unwrap();
+        assert_eq!(cut.records.len(), 1);
+        assert_eq!(cut.valid_len, 17);
+        assert!(cut.damage.unwrap().contains("checksum of k"));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_snapshot_taken_during_writes_plus_wal_is_the_live_state() {
+        let dir = std::env::temp_dir().join(format!("mergedb-live-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let store = Arc::new(DashMap::new());
+        for i in 0..SNAPSHOT_CHUNK * 3 {
+            store.insert(
+                format!("c{}", i),
+                stored(CrdtValue::Counter(PNCounter::new(
+                    "node_1".to_string(),
+                    1,
+                    0,
+                ))),
+            );
+        }
+        let wal = Arc::new(Wal::open(Some(dir.clone()), WalSync::Always).unwrap());
+        let logging = {
+            let (wal, store) = (wal.clone(), store.clone());
+            tokio::spawn(async move { wal.run(store).await })
+        };
+
+        //increments, and keys that don't exist yet, keep landing while the snapshot is taken
+        let writing = {
+            let (wal, store) = (wal.clone(), store.clone());
+            std::thread::spawn(move || {
+                let seqs = AtomicU64::new(0);
+                for seq in 1..=20_000u64 {
+                    let key = format!("c{}", seq as usize % (SNAPSHOT_CHUNK * 4));
+                    let mut entry = store.entry(key.clone()).or_insert_with(|| {
+                        stored(CrdtValue::Counter(PNCounter::new(
+                            "node_1".to_string(),
+                            0,
+                            0,
+                        )))
+                    });
+                    if let CrdtValue::Counter(counter) = &mut entry.data {
+                        counter.checked_add("node_1".to_string(), 1).unwrap();
+                    }
+                    drop(entry);
+                    assert_eq!(wal.record(&key, &seqs), seq);
+                }
+            })
+        };
+        let persistence = Persistence::open(Some(dir.clone())).unwrap();
+        let snapshotting = {
+            let (wal, store) = (wal.clone(), store.clone());
+            tokio::task::spawn_blocking(move || {
+                wal.rotate().unwrap();
+                persistence.write_snapshot(&store).unwrap()
+            })
+        };
+        assert!(snapshotting.await.unwrap() >= SNAPSHOT_CHUNK * 3);
+        wal.drop_rotated().unwrap();
+        writing.join().unwrap();
+        wal.wait_durable(20_000).await;
+        logging.abort();
+
+        let reopened = Persistence::open(Some(dir.clone())).unwrap();
+        let mut recovered: HashMap<String, CrdtValue> = reopened
+            .pending_keys(&[])
+            .into_iter()
+            .map(|key| {
+                let value = reopened.take(&key).unwrap().unwrap();
+                (key, value)
+            })
+            .collect();
+        for (key, value) in Wal::replay(&dir).unwrap() {
+            match recovered.get_mut(&key) {
+                Some(current) => {
+                    current.merge_with(&value).unwrap();
+                }
+                None => {
+                    recovered.insert(key, value);
+                }
+            }
+        }
+        assert_eq!(recovered.len(), store.len());
+        for entry in store.iter() {
+            assert_eq!(
+                recovered[entry.key()],
+                entry.value().data,
+                "{}",
+                entry.key()
+            );
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
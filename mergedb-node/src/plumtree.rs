@@ -0,0 +1,385 @@
+//with [plumtree] set, a client write is no longer pushed to K random peers. every node pushes
+//it on to its eager peers, which together form a spanning tree of the cluster, and only
+//announces its id to the lazy ones. a node that gets a message twice tells the second sender
+//to prune it, that edge turns lazy and the tree thins out to one path per node. a node that
+//is announced a message it never gets grafts the announcer: asks it for the message and makes
+//it eager again, which is how the tree heals around nodes that went away. every peer starts
+//out eager, a failed push makes the peer lazy until something grafts it back. the gossip loop
+//still sends everything on its own schedule, the tree only gets writes there sooner
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use mergedb_proto::CrdtProto;
+use mergedb_types::CrdtValue;
+use tonic::Request;
+
+use crate::{
+    communication::{AnnounceRequest, BroadcastMessage, BroadcastRequest, GraftRequest, Replicas},
+    network::ReplicationServer,
+};
+
+#[derive(Debug)]
+struct Missing {
+    //peers that announced the message, grafted in turn
+    announcers: Vec<String>,
+    first_announced: Instant,
+    //when the message was last asked for, or first announced
+    since: Instant,
+    grafts: usize,
+}
+
+#[derive(Debug)]
+pub struct Tree {
+    //ids are this plus a counter, unique across restarts too
+    prefix: String,
+    next: AtomicU64,
+    lazy: Mutex<HashSet<String>>,
+    seen: Mutex<HashMap<String, (Instant, BroadcastMessage)>>,
+    missing: Mutex<HashMap<String, Missing>>,
+    //ids waiting to be announced, per lazy peer
+    announcements: Mutex<HashMap<String, Vec<String>>>,
+}
+
+impl Tree {
+    pub fn new(node_id: &str) -> Self {
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        Tree {
+            prefix: format!("{}@{}", node_id, started),
+            next: AtomicU64::new(0),
+            lazy: Mutex::new(HashSet::new()),
+            seen: Mutex::new(HashMap::new()),
+            missing: Mutex::new(HashMap::new()),
+            announcements: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn next_id(&self) -> String {
+        format!("{}:{}", self.prefix, self.next.fetch_add(1, Ordering::SeqCst) + 1)
+    }
+
+    pub fn is_lazy(&self, peer_addr: &str) -> bool {
+        self.lazy.lock().unwrap().contains(peer_addr)
+    }
+
+    //true when the peer was eager until now
+    pub fn prune(&self, peer_addr: &str) -> bool {
+        self.lazy.lock().unwrap().insert(peer_addr.to_string())
+    }
+
+    pub fn graft(&self, peer_addr: &str) {
+        self.lazy.lock().unwrap().remove(peer_addr);
+    }
+
+    //false for a message that was seen already
+    pub fn first_seen(&self, message: &BroadcastMessage) -> bool {
+        let mut seen = self.seen.lock().unwrap();
+        if seen.contains_key(&message.id) {
+            return false;
+        }
+        seen.insert(message.id.clone(), (Instant::now(), message.clone()));
+        self.missing.lock().unwrap().remove(&message.id);
+        true
+    }
+
+    pub fn announce(&self, peer_addr: &str, id: &str) {
+        self.announcements
+            .lock()
+            .unwrap()
+            .entry(peer_addr.to_string())
+            .or_default()
+            .push(id.to_string());
+    }
+
+    pub fn take_announcements(&self) -> HashMap<String, Vec<String>> {
+        std::mem::take(&mut *self.announcements.lock().unwrap())
+    }
+
+    //a peer has these, the ones not seen here are waited for
+    pub fn announced(&self, peer_addr: &str, ids: Vec<String>) {
+        let seen = self.seen.lock().unwrap();
+        let mut missing = self.missing.lock().unwrap();
+        let now = Instant::now();
+        for id in ids.into_iter().filter(|id| !seen.contains_key(id)) {
+            let waiting = missing.entry(id).or_insert_with(|| Missing {
+                announcers: Vec::new(),
+                first_announced: now,
+                since: now,
+                grafts: 0,
+            });
+            if !waiting.announcers.iter().any(|announcer| announcer == peer_addr) {
+                waiting.announcers.push(peer_addr.to_string());
+            }
+        }
+    }
+
+    //the ids to ask each peer for, every message that didn't arrive within `timeout` of being
+    //announced or last asked for. each time the next announcer is asked
+    pub fn due_grafts(&self, timeout: Duration) -> HashMap<String, Vec<String>> {
+        let mut due: HashMap<String, Vec<String>> = HashMap::new();
+        let now = Instant::now();
+        for (id, waiting) in self.missing.lock().unwrap().iter_mut() {
+            if now.duration_since(waiting.since) < timeout {
+                continue;
+            }
+            let announcer = &waiting.announcers[waiting.grafts % waiting.announcers.len()];
+            due.entry(announcer.clone()).or_default().push(id.clone());
+            waiting.grafts += 1;
+            waiting.since = now;
+        }
+        due
+    }
+
+    pub fn messages(&self, ids: &[String]) -> Vec<BroadcastMessage> {
+        let seen = self.seen.lock().unwrap();
+        ids.iter()
+            .filter_map(|id| seen.get(id).map(|(_, message)| message.clone()))
+            .collect()
+    }
+
+    //forgets messages older than `ttl`, and gives up on missing ones announced that long ago
+    pub fn expire(&self, ttl: Duration) {
+        self.seen
+            .lock()
+            .unwrap()
+            .retain(|_, (seen_at, _)| seen_at.elapsed() < ttl);
+        self.missing
+            .lock()
+            .unwrap()
+            .retain(|_, waiting| waiting.first_announced.elapsed() < ttl);
+    }
+}
+
+//the address a peer's node id is gossiped with at, None when this node doesn't gossip with it
+fn address_of(server: &ReplicationServer, node_id: &str) -> Option<String> {
+    let address = server.membership.members.get(node_id)?.address.clone()?;
+    server.peers.contains_key(&address).then_some(address)
+}
+
+//pushes a message on to every eager peer but the one it came from, and queues its id for
+//every lazy one. answers with the eager peers it reached and those it didn't, for a client write
+pub async fn broadcast(
+    server: &ReplicationServer,
+    message: BroadcastMessage,
+    from: Option<&str>,
+) -> Replicas {
+    let peer_addrs: Vec<String> = server
+        .peers
+        .iter()
+        .map(|entry| entry.key().clone())
+        .filter(|peer_addr| Some(peer_addr.as_str()) != from)
+        .filter(|peer_addr| !server.membership.is_witness_at(peer_addr))
+        .collect();
+    let mut replicas = Replicas::default();
+    for peer_addr in &peer_addrs {
+        if server.plumtree.is_lazy(peer_addr) {
+            server.plumtree.announce(peer_addr, &message.id);
+            continue;
+        }
+        if !server.breakers.allow(peer_addr) {
+            server.plumtree.announce(peer_addr, &message.id);
+            replicas.failed.push(peer_addr.clone());
+            continue;
+        }
+        let request = Request::new(BroadcastRequest {
+            node_id: server.config.node_id.clone(),
+            cluster_id: server.config.cluster_id().to_string(),
+            message: Some(message.clone()),
+        });
+        let pushed = match server.peer_client(peer_addr).await {
+            Ok(mut client) => client
+                .broadcast(request)
+                .await
+                .map(|response| response.into_inner().prune)
+                .map_err(|e| e.to_string()),
+            Err(e) => Err(e.to_string()),
+        };
+        match pushed {
+            Ok(prune) => {
+                replicas.reached += 1;
+                server.breakers.record_success(peer_addr);
+                server.metrics.incr("plumtree_pushes_total", 1);
+                server.metrics.record_transmissions(&message.key, 1);
+                if prune && server.plumtree.prune(peer_addr) {
+                    server.metrics.incr("plumtree_prunes_total", 1);
+                }
+            }
+            Err(e) => {
+                println!("failed to broadcast to {}: {}", peer_addr, e);
+                server.breakers.record_failure(peer_addr);
+                server.pool.remove(peer_addr);
+                //off the tree until it grafts itself back, announced to like any lazy peer
+                server.plumtree.prune(peer_addr);
+                server.plumtree.announce(peer_addr, &message.id);
+                replicas.failed.push(peer_addr.clone());
+            }
+        }
+    }
+    replicas
+}
+
+//a message pushed or grafted from `from`, merged and passed on when it is new. false for a
+//duplicate, the edge it came over isn't needed then
+pub fn deliver(server: &ReplicationServer, message: BroadcastMessage, from: Option<String>) -> bool {
+    if !server.plumtree.first_seen(&message) {
+        server.metrics.incr("plumtree_duplicates_total", 1);
+        if let Some(from) = &from {
+            server.plumtree.prune(from);
+        }
+        return false;
+    }
+    if let Some(from) = &from {
+        server.plumtree.graft(from);
+    }
+    match message.value.clone().map(CrdtValue::from_proto) {
+        Some(Ok(value)) => server.merge_remote(message.key.clone(), value),
+        Some(Err(e)) => println!("Rejected CRDTData for {}: {}", message.key, e),
+        None => println!("Rejected a broadcast without a value for {}", message.key),
+    }
+    let relaying = server.clone();
+    tokio::spawn(async move {
+        broadcast(&relaying, message, from.as_deref()).await;
+    });
+    true
+}
+
+//the Broadcast rpc
+pub fn received(server: &ReplicationServer, node_id: &str, message: BroadcastMessage) -> bool {
+    deliver(server, message, address_of(server, node_id))
+}
+
+//the Announce rpc
+pub fn announced(server: &ReplicationServer, node_id: &str, ids: Vec<String>) {
+    if let Some(peer_addr) = address_of(server, node_id) {
+        server.plumtree.announced(&peer_addr, ids);
+    }
+}
+
+//the Graft rpc, the peer asking is pushed to again
+pub fn grafted(server: &ReplicationServer, node_id: &str, ids: &[String]) -> Vec<BroadcastMessage> {
+    if let Some(peer_addr) = address_of(server, node_id) {
+        server.plumtree.graft(&peer_addr);
+    }
+    server.plumtree.messages(ids)
+}
+
+//sends the queued announcements, grafts whatever is overdue and forgets old messages, every
+//announce_interval for as long as the node runs
+pub async fn run(server: &ReplicationServer) {
+    let Some(config) = server.config.plumtree.clone() else {
+        return;
+    };
+    let mut interval = tokio::time::interval(config.announce_interval);
+    loop {
+        interval.tick().await;
+        for (peer_addr, ids) in server.plumtree.take_announcements() {
+            let request = Request::new(AnnounceRequest {
+                node_id: server.config.node_id.clone(),
+                cluster_id: server.config.cluster_id().to_string(),
+                ids,
+            });
+            let announced = match server.peer_client(&peer_addr).await {
+                Ok(mut client) => client.announce(request).await.map(|_| ()).map_err(|e| e.to_string()),
+                Err(e) => Err(e.to_string()),
+            };
+            if let Err(e) = announced {
+                println!("failed to announce to {}: {}", peer_addr, e);
+                server.breakers.record_failure(&peer_addr);
+            }
+        }
+
+        for (peer_addr, ids) in server.plumtree.due_grafts(config.graft_timeout) {
+            server.metrics.incr("plumtree_grafts_total", ids.len() as u64);
+            server.plumtree.graft(&peer_addr);
+            let request = Request::new(GraftRequest {
+                node_id: server.config.node_id.clone(),
+                cluster_id: server.config.cluster_id().to_string(),
+                ids,
+            });
+            let grafted = match server.peer_client(&peer_addr).await {
+                Ok(mut client) => client
+                    .graft(request)
+                    .await
+                    .map(|response| response.into_inner().messages)
+                    .map_err(|e| e.to_string()),
+                Err(e) => Err(e.to_string()),
+            };
+            match grafted {
+                Ok(messages) => {
+                    for message in messages {
+                        deliver(server, message, Some(peer_addr.clone()));
+                    }
+                }
+                Err(e) => {
+                    println!("failed to graft {}: {}", peer_addr, e);
+                    server.breakers.record_failure(&peer_addr);
+                }
+            }
+        }
+        server.plumtree.expire(config.message_ttl);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(id: &str) -> BroadcastMessage {
+        BroadcastMessage {
+            id: id.to_string(),
+            key: "k".to_string(),
+            value: None,
+        }
+    }
+
+    #[test]
+    fn test_duplicates_and_prunes() {
+        let tree = Tree::new("n1");
+        assert_ne!(tree.next_id(), tree.next_id());
+        assert!(tree.first_seen(&message("a:1")));
+        assert!(!tree.first_seen(&message("a:1")));
+
+        assert!(!tree.is_lazy("p1"));
+        assert!(tree.prune("p1"));
+        assert!(!tree.prune("p1"));
+        assert!(tree.is_lazy("p1"));
+        tree.graft("p1");
+        assert!(!tree.is_lazy("p1"));
+
+        tree.announce("p1", "a:1");
+        tree.announce("p1", "a:2");
+        assert_eq!(tree.take_announcements()["p1"], vec!["a:1", "a:2"]);
+        assert!(tree.take_announcements().is_empty());
+    }
+
+    #[test]
+    fn test_missing_messages_are_grafted_from_each_announcer_in_turn() {
+        let tree = Tree::new("n1");
+        tree.first_seen(&message("a:1"));
+        tree.announced("p1", vec!["a:1".to_string(), "a:2".to_string()]);
+        tree.announced("p2", vec!["a:2".to_string()]);
+        //only what never arrived is waited for
+        assert!(tree.due_grafts(Duration::from_secs(60)).is_empty());
+
+        let first = tree.due_grafts(Duration::ZERO);
+        assert_eq!(first, HashMap::from([("p1".to_string(), vec!["a:2".to_string()])]));
+        let second = tree.due_grafts(Duration::ZERO);
+        assert_eq!(second, HashMap::from([("p2".to_string(), vec!["a:2".to_string()])]));
+
+        //it arrived, nothing is asked for anymore, and it can be handed out
+        tree.first_seen(&message("a:2"));
+        assert!(tree.due_grafts(Duration::ZERO).is_empty());
+        assert_eq!(tree.messages(&["a:2".to_string(), "a:3".to_string()]).len(), 1);
+
+        tree.expire(Duration::ZERO);
+        assert!(tree.messages(&["a:2".to_string()]).is_empty());
+    }
+}
@@ -0,0 +1,110 @@
+use std::time::Duration;
+
+use crate::config::GossipPriority;
+
+//the gossip loop never spins faster than this, however tight a class asks to be
+const MIN_INTERVAL: Duration = Duration::from_millis(50);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Class {
+    pub prefix: String,
+    pub priority: u8,
+    pub interval: Duration,
+}
+
+//the priority classes from the config plus the catch-all default class (index 0, empty
+//prefix, priority 0, gossip_interval). a key belongs to the class with the longest prefix it
+//starts with
+#[derive(Debug, Clone)]
+pub struct Schedule {
+    pub classes: Vec<Class>,
+}
+
+impl Schedule {
+    pub fn new(default_interval: Duration, configured: &[GossipPriority]) -> Self {
+        let mut classes = vec![Class {
+            prefix: String::new(),
+            priority: 0,
+            interval: default_interval.max(MIN_INTERVAL),
+        }];
+        classes.extend(configured.iter().map(|class| Class {
+            prefix: class.prefix.clone(),
+            priority: class.priority,
+            interval: class.interval.max(MIN_INTERVAL),
+        }));
+        Schedule { classes }
+    }
+
+    pub fn class_of(&self, key: &str) -> usize {
+        self.classes
+            .iter()
+            .enumerate()
+            .filter(|(_, class)| key.starts_with(&class.prefix))
+            .max_by_key(|(_, class)| class.prefix.len())
+            .map(|(index, _)| index)
+            .unwrap_or(0)
+    }
+
+    //how long the gossip loop sleeps between rounds, the tightest interval of any class
+    pub fn tick(&self) -> Duration {
+        self.classes
+            .iter()
+            .map(|class| class.interval)
+            .min()
+            .unwrap_or(MIN_INTERVAL)
+    }
+
+    //classes due a round, given how long ago each one last went out, highest priority first
+    pub fn due(&self, since_last: impl Fn(usize) -> Option<Duration>) -> Vec<usize> {
+        let mut due: Vec<usize> = (0..self.classes.len())
+            .filter(|&index| match since_last(index) {
+                Some(elapsed) => elapsed >= self.classes[index].interval,
+                None => true,
+            })
+            .collect();
+        due.sort_by_key(|&index| std::cmp::Reverse(self.classes[index].priority));
+        due
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn schedule() -> Schedule {
+        Schedule::new(
+            Duration::from_secs(2),
+            &[
+                GossipPriority {
+                    prefix: "flags:".to_string(),
+                    priority: 10,
+                    interval: Duration::from_millis(200),
+                },
+                GossipPriority {
+                    prefix: "flags:beta:".to_string(),
+                    priority: 5,
+                    interval: Duration::from_secs(1),
+                },
+            ],
+        )
+    }
+
+    #[test]
+    fn test_longest_prefix_wins() {
+        let schedule = schedule();
+        assert_eq!(schedule.class_of("analytics:views"), 0);
+        assert_eq!(schedule.class_of("flags:dark_mode"), 1);
+        assert_eq!(schedule.class_of("flags:beta:search"), 2);
+        assert_eq!(schedule.tick(), Duration::from_millis(200));
+    }
+
+    #[test]
+    fn test_due_classes_go_highest_priority_first() {
+        let schedule = schedule();
+        assert_eq!(schedule.due(|_| None), vec![1, 2, 0]);
+
+        //half a second after the last round only the flags are due again
+        let due = schedule.due(|_| Some(Duration::from_millis(500)));
+        assert_eq!(due, vec![1]);
+    }
+}
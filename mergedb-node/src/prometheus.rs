@@ -0,0 +1,68 @@
+//serves every metric in prometheus' text format on GET /metrics, at metrics_address
+use anyhow::{Context, Result};
+use hyper::{
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use std::{convert::Infallible, net::SocketAddr, sync::Arc};
+
+use crate::metrics::Metrics;
+
+//one `name{labels} value` line per metric, names already carry their labels
+pub fn render(metrics: &Metrics) -> String {
+    let mut text = String::new();
+    for (name, value) in metrics.snapshot() {
+        text.push_str(&format!("mergedb_{} {}\n", name, value));
+    }
+    text
+}
+
+fn respond(metrics: &Metrics, request: &Request<Body>) -> Response<Body> {
+    if request.method() != Method::GET || request.uri().path() != "/metrics" {
+        let mut response = Response::new(Body::from("only GET /metrics is served\n"));
+        *response.status_mut() = StatusCode::NOT_FOUND;
+        return response;
+    }
+    let mut response = Response::new(Body::from(render(metrics)));
+    response
+        .headers_mut()
+        .insert("content-type", "text/plain; version=0.0.4".parse().unwrap());
+    response
+}
+
+pub async fn serve(address: &str, metrics: Arc<Metrics>) -> Result<()> {
+    let address: SocketAddr = address
+        .parse()
+        .with_context(|| format!("invalid metrics_address {}", address))?;
+    let make_service = make_service_fn(move |_| {
+        let metrics = metrics.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let response = respond(&metrics, &request);
+                async move { Ok::<_, Infallible>(response) }
+            }))
+        }
+    });
+    println!("serving metrics on http://{}/metrics", address);
+    Server::try_bind(&address)
+        .with_context(|| format!("can't listen on metrics_address {}", address))?
+        .serve(make_service)
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_renders_one_line_per_metric() {
+        let metrics = Metrics::new();
+        metrics.incr("gossip_pushes_total", 3);
+        metrics.set_gauge("cluster_members_reachable", 2);
+        assert_eq!(
+            render(&metrics),
+            "mergedb_cluster_members_reachable 2\nmergedb_gossip_pushes_total 3\n"
+        );
+    }
+}
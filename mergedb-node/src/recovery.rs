@@ -0,0 +1,197 @@
+//how far a starting node has got bringing its data back, so operators can tell a slow start
+//from a stuck one. there are two stages before the node is ready:
+//  wal:     the log left from before the stop is replayed, counted in bytes read. the node
+//           answers client and gossip rpcs with UNAVAILABLE until it is done
+//  warm-up: snapshot values not loaded yet are loaded in the background, counted in keys.
+//           the node serves meanwhile, loading whatever a command touches first
+//progress is logged every LOG_EVERY, answered to `node status` and shown to health checks as
+//the RECOVERY_SERVICE service, NOT_SERVING until the node is ready
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicU64, AtomicU8, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::units;
+
+pub const RECOVERY_SERVICE: &str = "mergedb.recovery";
+const LOG_EVERY: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    Wal,
+    WarmUp,
+    Ready,
+}
+
+impl Stage {
+    fn from_u8(stage: u8) -> Stage {
+        match stage {
+            0 => Stage::Wal,
+            1 => Stage::WarmUp,
+            _ => Stage::Ready,
+        }
+    }
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Stage::Wal => "wal",
+            Stage::WarmUp => "warm-up",
+            Stage::Ready => "ready",
+        })
+    }
+}
+
+//where the current stage is at. `done` and `total` are bytes for the wal, keys for the warm-up
+#[derive(Debug, Clone, PartialEq)]
+pub struct Progress {
+    pub stage: Stage,
+    pub entries: u64,
+    pub done: u64,
+    pub total: u64,
+    pub elapsed: Duration,
+}
+
+impl Progress {
+    //what is left at the rate so far, None until there is a rate to go by or nothing is left
+    pub fn eta(&self) -> Option<Duration> {
+        let left = self.total.saturating_sub(self.done);
+        if self.stage == Stage::Ready || self.done == 0 || left == 0 {
+            return None;
+        }
+        Some(self.elapsed.mul_f64(left as f64 / self.done as f64))
+    }
+
+    pub fn summary(&self) -> String {
+        let eta = match self.eta() {
+            Some(eta) => format!(", eta {}s", eta.as_secs()),
+            None => String::new(),
+        };
+        match self.stage {
+            Stage::Wal => format!(
+                "wal replay: {} entries applied, {} of {}{}",
+                self.entries,
+                units::size::format(self.done),
+                units::size::format(self.total),
+                eta
+            ),
+            Stage::WarmUp => format!(
+                "warm-up: {} of {} snapshot keys loaded{}",
+                self.done, self.total, eta
+            ),
+            Stage::Ready => "ready".to_string(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Recovery {
+    stage: AtomicU8,
+    entries: AtomicU64,
+    done: AtomicU64,
+    total: AtomicU64,
+    started: Mutex<Instant>,
+    logged: Mutex<Instant>,
+}
+
+//a node with nothing to recover is ready from the start
+impl Default for Recovery {
+    fn default() -> Self {
+        Recovery {
+            stage: AtomicU8::new(Stage::Ready as u8),
+            entries: AtomicU64::new(0),
+            done: AtomicU64::new(0),
+            total: AtomicU64::new(0),
+            started: Mutex::new(Instant::now()),
+            logged: Mutex::new(Instant::now()),
+        }
+    }
+}
+
+impl Recovery {
+    pub fn start(&self, stage: Stage, total: u64) {
+        *self.started.lock().unwrap() = Instant::now();
+        *self.logged.lock().unwrap() = Instant::now();
+        self.entries.store(0, Ordering::SeqCst);
+        self.done.store(0, Ordering::SeqCst);
+        self.total.store(total, Ordering::SeqCst);
+        self.stage.store(stage as u8, Ordering::SeqCst);
+    }
+
+    pub fn stage(&self) -> Stage {
+        Stage::from_u8(self.stage.load(Ordering::SeqCst))
+    }
+
+    //`done` more bytes or keys, and `entries` more wal records applied. logs now and then
+    pub fn advance(&self, done: u64, entries: u64) {
+        self.done.fetch_add(done, Ordering::SeqCst);
+        self.entries.fetch_add(entries, Ordering::SeqCst);
+        let mut logged = self.logged.lock().unwrap();
+        if logged.elapsed() >= LOG_EVERY {
+            *logged = Instant::now();
+            println!("{}", self.progress().summary());
+        }
+    }
+
+    //ends the current stage, logging how it went. the next one is start()ed, or the node is
+    //ready
+    pub fn finish(&self) {
+        let progress = self.progress();
+        if progress.stage != Stage::Ready {
+            println!(
+                "{}, done in {:.1}s",
+                progress.summary(),
+                progress.elapsed.as_secs_f64()
+            );
+        }
+        self.stage.store(Stage::Ready as u8, Ordering::SeqCst);
+    }
+
+    pub fn progress(&self) -> Progress {
+        Progress {
+            stage: self.stage(),
+            entries: self.entries.load(Ordering::SeqCst),
+            done: self.done.load(Ordering::SeqCst),
+            total: self.total.load(Ordering::SeqCst),
+            elapsed: self.started.lock().unwrap().elapsed(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_progress() {
+        let recovery = Recovery::default();
+        assert_eq!(recovery.stage(), Stage::Ready);
+        assert_eq!(recovery.progress().eta(), None);
+
+        recovery.start(Stage::Wal, 4 << 20);
+        assert_eq!(recovery.progress().eta(), None);
+        recovery.advance(1 << 20, 10);
+        let progress = recovery.progress();
+        assert_eq!((progress.done, progress.entries), (1 << 20, 10));
+        assert!(progress.summary().starts_with("wal replay: 10 entries applied, 1MiB of 4MiB"));
+
+        //three times what the first quarter took
+        let progress = Progress {
+            elapsed: Duration::from_secs(2),
+            ..progress
+        };
+        assert_eq!(progress.eta(), Some(Duration::from_secs(6)));
+
+        recovery.start(Stage::WarmUp, 100);
+        assert_eq!(recovery.progress().done, 0);
+        recovery.advance(40, 0);
+        assert!(recovery.progress().summary().starts_with("warm-up: 40 of 100 snapshot keys"));
+        recovery.finish();
+        assert_eq!(recovery.progress().summary(), "ready");
+    }
+}
@@ -0,0 +1,28 @@
+//COPY <src> <dst> merges src's whole state into dst, dots and clocks included, so dst goes on
+//converging like src would have. RENAME is a COPY plus a marker under MARKER_PREFIX + src naming
+//dst, there are no deletes: src keeps its state but reads and writes of it are refused, and
+//whatever is still merged into it, writes made before their node heard of the rename, is passed
+//on to dst. the marker is an lww register, so concurrent renames of one key all copy it but
+//every node settles on the same dst for it, the one whose marker was written last
+pub const MARKER_PREFIX: &str = "__renamed:";
+
+pub fn marker_key(key: &str) -> String {
+    format!("{}{}", MARKER_PREFIX, key)
+}
+
+//markers are only ever written through RENAME
+pub fn is_marker(key: &str) -> bool {
+    key.starts_with(MARKER_PREFIX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_marker_keys() {
+        assert_eq!(marker_key("cart:42"), "__renamed:cart:42");
+        assert!(is_marker(&marker_key("views")));
+        assert!(!is_marker("views"));
+    }
+}
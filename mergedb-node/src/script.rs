@@ -0,0 +1,414 @@
+use mergedb_types::{
+    aw_set::AWSet, element::Element, lww_register::LwwRegister, pn_counter::PNCounter, CrdtValue,
+};
+use rhai::{Array, Dynamic, Engine, EvalAltResult, Map};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::network::ReplicationServer;
+
+//keys one script may touch, reads included
+const MAX_KEYS: usize = 1000;
+const MAX_CALL_LEVELS: usize = 32;
+const MAX_COLLECTION_SIZE: usize = 10_000;
+
+type ScriptResult<T> = Result<T, Box<EvalAltResult>>;
+
+//what one EVAL may spend, from script_max_operations and script_timeout
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    pub max_operations: u64,
+    pub timeout: Duration,
+    pub max_value_size: u64,
+}
+
+//what a script left behind: its return value, and every key it wrote with the value to merge
+//in
+#[derive(Debug)]
+pub struct Outcome {
+    pub result: serde_json::Value,
+    pub written: Vec<Written>,
+}
+
+#[derive(Debug)]
+pub struct Written {
+    pub key: String,
+    pub value: CrdtValue,
+}
+
+//the script's private copy of the keys it touched. nothing reaches the store until the script
+//has finished without an error, and then only by merging, so gossip that lands while the
+//script runs isn't lost
+struct Workspace {
+    server: ReplicationServer,
+    values: HashMap<String, Option<CrdtValue>>,
+    written: Vec<String>,
+    max_value_size: u64,
+}
+
+impl Workspace {
+    fn value(&mut self, key: &str) -> ScriptResult<&mut Option<CrdtValue>> {
+        if !self.values.contains_key(key) {
+            if self.values.len() >= MAX_KEYS {
+                return Err(format!("a script may touch at most {} keys", MAX_KEYS).into());
+            }
+            self.server.ensure_loaded(key);
+            let current = self.server.store.get(key).map(|entry| entry.data.clone());
+            self.values.insert(key.to_string(), current);
+        }
+        Ok(self.values.get_mut(key).unwrap())
+    }
+
+    //the key's value for a write, `create` when it doesn't exist yet
+    //refused for the same reasons as a client's write, the size is up to check_size
+    fn write(&mut self, key: &str, create: Option<CrdtValue>) -> ScriptResult<&mut CrdtValue> {
+        if let Some(refusal) = self.server.refuse_write(key, 0) {
+            return Err(refusal.message().into());
+        }
+        if !self.written.iter().any(|written| written == key) {
+            self.written.push(key.to_string());
+        }
+        let value = self.value(key)?;
+        match (value.is_some(), create) {
+            (false, Some(create)) => *value = Some(create),
+            (false, None) => return Err(format!("{} was not found", key).into()),
+            _ => {}
+        }
+        Ok(value.as_mut().unwrap())
+    }
+
+    fn check_size(&self, value: &str) -> ScriptResult<()> {
+        if value.len() as u64 > self.max_value_size {
+            return Err(format!("value is {} bytes, over max_value_size", value.len()).into());
+        }
+        Ok(())
+    }
+
+    fn counter(&mut self, key: &str) -> ScriptResult<&mut PNCounter> {
+        match self.write(key, None)? {
+            CrdtValue::Counter(counter) => Ok(counter),
+            other => Err(mismatch(key, other, "counter")),
+        }
+    }
+}
+
+fn mismatch(key: &str, value: &CrdtValue, wanted: &str) -> Box<EvalAltResult> {
+    format!("{} is a {}, not a {}", key, value.type_name(), wanted).into()
+}
+
+//set elements as rhai's own strings, ints, floats, bools and blobs
+fn element_to_dynamic(element: Element) -> Dynamic {
+    match element {
+        Element::Str(tag) => Dynamic::from(tag),
+        Element::Int(value) => Dynamic::from(value),
+        Element::Float(value) => Dynamic::from(value),
+        Element::Bool(value) => Dynamic::from(value),
+        Element::Bytes(bytes) => Dynamic::from_blob(bytes),
+    }
+}
+
+//runs `source` against a copy of the keys it touches. blocking, the caller runs it off the
+//async runtime and holds the node's script lock so no other command interleaves with it
+pub fn run(server: &ReplicationServer, source: &str, limits: Limits) -> Result<Outcome, String> {
+    let workspace = Arc::new(Mutex::new(Workspace {
+        server: server.clone(),
+        values: HashMap::new(),
+        written: Vec::new(),
+        max_value_size: limits.max_value_size,
+    }));
+    let engine = engine(&workspace, limits);
+
+    let result = engine.eval::<Dynamic>(source).map_err(|e| e.to_string())?;
+
+    let mut workspace = workspace.lock().unwrap();
+    let written = std::mem::take(&mut workspace.written)
+        .into_iter()
+        .filter_map(|key| {
+            let value = workspace.values.get(&key)?.clone()?;
+            Some(Written { key, value })
+        })
+        .collect();
+    Ok(Outcome {
+        result: to_json(result),
+        written,
+    })
+}
+
+fn engine(workspace: &Arc<Mutex<Workspace>>, limits: Limits) -> Engine {
+    let mut engine = Engine::new();
+    engine
+        .set_max_operations(limits.max_operations)
+        .set_max_call_levels(MAX_CALL_LEVELS)
+        .set_max_string_size(limits.max_value_size as usize)
+        .set_max_array_size(MAX_COLLECTION_SIZE)
+        .set_max_map_size(MAX_COLLECTION_SIZE)
+        .on_print(|_| {})
+        .on_debug(|_, _, _| {});
+    engine.disable_symbol("eval");
+
+    let started = Instant::now();
+    engine.on_progress(move |_| {
+        (started.elapsed() > limits.timeout).then(|| Dynamic::from("script timed out"))
+    });
+
+    let ws = workspace.clone();
+    engine.register_fn("get", move |key: &str| -> ScriptResult<Dynamic> {
+        let mut ws = ws.lock().unwrap();
+        Ok(match ws.value(key)? {
+            //a sharded counter's other shards are summed in, as CGET does
+            Some(CrdtValue::Counter(counter)) => {
+                let value = counter.value();
+                match ws.server.shard_total(key).and_then(|total| value.checked_add(total)) {
+                    Some(value) => Dynamic::from(value),
+                    None => return Err(format!("{} overflowed summing its shards", key).into()),
+                }
+            }
+            Some(CrdtValue::Register(register)) => Dynamic::from(register.get()),
+            Some(CrdtValue::Set(set)) => Dynamic::from_array(
+                set.read_sorted().into_iter().map(element_to_dynamic).collect(),
+            ),
+            Some(CrdtValue::Map(map)) => Dynamic::from_map(
+                map.read()
+                    .into_iter()
+                    .map(|(field, value)| (field.into(), Dynamic::from(value.to_string())))
+                    .collect::<Map>(),
+            ),
+            Some(CrdtValue::Custom(custom)) => Dynamic::from(custom.display()),
+            None => Dynamic::UNIT,
+        })
+    });
+
+    let ws = workspace.clone();
+    engine.register_fn("inc", move |key: &str, by: i64| -> ScriptResult<i64> {
+        let mut ws = ws.lock().unwrap();
+        let node_id = ws.server.config.node_id.clone();
+        let counter = ws.counter(key)?;
+        counter
+            .checked_add(node_id, by)
+            .ok_or("counter would overflow")?;
+        Ok(counter.value())
+    });
+
+    let ws = workspace.clone();
+    engine.register_fn("dec", move |key: &str, by: i64| -> ScriptResult<i64> {
+        let mut ws = ws.lock().unwrap();
+        let node_id = ws.server.config.node_id.clone();
+        let delta = by.checked_neg().ok_or("cannot decrement by i64::MIN")?;
+        let counter = ws.counter(key)?;
+        counter
+            .checked_add(node_id, delta)
+            .ok_or("counter would overflow")?;
+        Ok(counter.value())
+    });
+
+    let ws = workspace.clone();
+    engine.register_fn("add", move |key: &str, tag: &str| -> ScriptResult<()> {
+        let mut ws = ws.lock().unwrap();
+        ws.check_size(tag)?;
+        let node_id = ws.server.config.node_id.clone();
+        match ws.write(key, Some(CrdtValue::Set(AWSet::new())))? {
+            CrdtValue::Set(set) => {
+                set.add(tag.to_string(), node_id);
+                Ok(())
+            }
+            other => Err(mismatch(key, other, "set")),
+        }
+    });
+
+    let ws = workspace.clone();
+    engine.register_fn("rem", move |key: &str, tag: &str| -> ScriptResult<()> {
+        let mut ws = ws.lock().unwrap();
+        match ws.write(key, None)? {
+            CrdtValue::Set(set) => {
+                set.remove(tag.to_string());
+                Ok(())
+            }
+            other => Err(mismatch(key, other, "set")),
+        }
+    });
+
+    //set(key, "text") sets a register, set(key, 5) a counter, like RSET and CSET
+    let ws = workspace.clone();
+    engine.register_fn("set", move |key: &str, text: &str| -> ScriptResult<()> {
+        let mut ws = ws.lock().unwrap();
+        ws.check_size(text)?;
+        let node_id = ws.server.config.node_id.clone();
+        let create = CrdtValue::Register(LwwRegister::new(node_id.clone()));
+        match ws.write(key, Some(create))? {
+            CrdtValue::Register(register) => {
+                register.set(text.to_string(), node_id);
+                Ok(())
+            }
+            other => Err(mismatch(key, other, "register")),
+        }
+    });
+
+    //through this node's own count as CSET does, so what other nodes counted stays
+    let ws = workspace.clone();
+    engine.register_fn("set", move |key: &str, value: i64| -> ScriptResult<()> {
+        let mut ws = ws.lock().unwrap();
+        let node_id = ws.server.config.node_id.clone();
+        let own = ws
+            .server
+            .shard_total(key)
+            .and_then(|total| value.checked_sub(total))
+            .ok_or("counter would overflow")?;
+        let create = CrdtValue::Counter(PNCounter::new(node_id.clone(), 0, 0));
+        match ws.write(key, Some(create))? {
+            CrdtValue::Counter(counter) => {
+                counter
+                    .checked_set(node_id, own)
+                    .ok_or("counter would overflow")?;
+                Ok(())
+            }
+            other => Err(mismatch(key, other, "counter")),
+        }
+    });
+
+    engine
+}
+
+//the script's return value as json for the client, anything without a json form is its
+//rhai type name
+fn to_json(value: Dynamic) -> serde_json::Value {
+    if value.is_unit() {
+        return serde_json::Value::Null;
+    }
+    if let Some(b) = value.clone().try_cast::<bool>() {
+        return b.into();
+    }
+    if let Some(i) = value.clone().try_cast::<i64>() {
+        return i.into();
+    }
+    if let Some(f) = value.clone().try_cast::<f64>() {
+        return f.into();
+    }
+    if value.is_string() {
+        return value.into_string().unwrap_or_default().into();
+    }
+    if let Some(array) = value.clone().try_cast::<Array>() {
+        return array.into_iter().map(to_json).collect();
+    }
+    if let Some(map) = value.clone().try_cast::<Map>() {
+        return map
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), to_json(v)))
+            .collect::<serde_json::Map<_, _>>()
+            .into();
+    }
+    value.type_name().into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::server;
+    use mergedb_types::Merge;
+
+    fn limits() -> Limits {
+        Limits {
+            max_operations: 10_000,
+            timeout: Duration::from_secs(1),
+            max_value_size: 1024,
+        }
+    }
+
+    #[test]
+    fn test_writes_are_staged_until_the_script_succeeds() {
+        let server = server("n1");
+        let outcome = run(
+            &server,
+            r#"set("views", 3); inc("views", 4); add("tags", "a"); [get("views"), get("tags")]"#,
+            limits(),
+        )
+        .unwrap();
+        assert_eq!(outcome.result, serde_json::json!([7, ["a"]]));
+        let keys: Vec<&str> = outcome.written.iter().map(|w| w.key.as_str()).collect();
+        assert_eq!(keys, ["views", "tags"]);
+        //nothing touched the store, that is up to the caller
+        assert!(server.store.is_empty());
+
+        let failed = run(&server, r#"add("tags", "a"); inc("tags", 1)"#, limits());
+        assert!(failed.unwrap_err().contains("tags is a set, not a counter"));
+    }
+
+    #[test]
+    fn test_counter_set_keeps_other_nodes_counts() {
+        let server = server("n1");
+        let mut views = PNCounter::new("n1".to_string(), 3, 0);
+        views.merge(&PNCounter::new("n2".to_string(), 4, 0));
+        server.store.insert(
+            "views".to_string(),
+            crate::network::StoredValue {
+                data: CrdtValue::Counter(views.clone()),
+                last_updated: std::time::SystemTime::now(),
+            },
+        );
+        let outcome = run(&server, r#"set("views", 10); get("views")"#, limits()).unwrap();
+        assert_eq!(outcome.result, serde_json::json!(10));
+        let CrdtValue::Counter(written) = &outcome.written[0].value else {
+            panic!("views is a counter");
+        };
+        assert_eq!(written.p["n2"], 4);
+        //merging it into what is stored, as EVAL does, keeps the value the script set
+        views.merge(written);
+        assert_eq!(views.value(), 10);
+    }
+
+    //gossip turned tags into a register after the script read it, so nothing is applied,
+    //views included
+    #[tokio::test]
+    async fn test_a_script_that_cant_be_applied_whole_changes_nothing() {
+        let server = server("n1");
+        let outcome = run(&server, r#"set("views", 3); add("tags", "a"); 0"#, limits()).unwrap();
+        server.merge_remote(
+            "tags".to_string(),
+            CrdtValue::Register(LwwRegister::new("n2".to_string())),
+        );
+
+        let refused = server
+            .apply_script_writes(outcome.written)
+            .await
+            .unwrap_err();
+        assert_eq!(refused.code(), tonic::Code::FailedPrecondition);
+        assert!(!server.store.contains_key("views"));
+        assert!(matches!(
+            server.store.get("tags").unwrap().data,
+            CrdtValue::Register(_)
+        ));
+    }
+
+    #[test]
+    fn test_scripts_cant_write_system_keys() {
+        let server = server("n1");
+        for key in [
+            "__frozen:views",
+            "__setting:max_value_size",
+            "__renamed:views",
+            "__label:views",
+            "__limit:views",
+            "__shards:views",
+            "__shard:views#1",
+        ] {
+            let script = format!(r#"set("{}", "x")"#, key);
+            assert!(
+                run(&server, &script, limits()).is_err(),
+                "{} was written",
+                key
+            );
+        }
+        assert!(server.store.is_empty());
+    }
+
+    #[test]
+    fn test_runaway_scripts_are_stopped() {
+        let server = server("n1");
+        assert!(run(&server, "loop {}", limits()).is_err());
+        assert!(run(&server, r#"inc("missing", 1)"#, limits())
+            .unwrap_err()
+            .contains("missing was not found"));
+    }
+}
@@ -0,0 +1,258 @@
+//gossip_mode = "scuttlebutt": instead of pushing whatever changed lately, two nodes swap version
+//vectors and send each other exactly what the other is missing, in one round trip.
+//
+//every change a node makes to a key (a client write, or gossip merged in push mode) takes the
+//next number of the node's own sequence, and the key remembers the highest number it carries
+//from each origin. a vector says, per origin, up to which number a node holds every update. a
+//node sends a peer each key carrying a newer number than the peer's vector has for that origin,
+//and then tells it up to where that covers everything, so the peer can raise its vector. a
+//reply holds at most BATCH_SIZE keys, the oldest updates first, and only claims what it covered
+//
+//an origin is a node id plus when the node started, numbers start over with every start and
+//what was loaded from disk counts as new updates of the new origin, so nothing relies on state
+//from before a restart. the first round with a node after either restarted is a full exchange
+use dashmap::DashMap;
+use std::{
+    collections::BTreeMap,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+pub type Vector = BTreeMap<String, u64>;
+
+#[derive(Debug)]
+pub struct Scuttlebutt {
+    pub origin: String,
+    own: AtomicU64,
+    //per key, the highest number from each origin its state includes
+    versions: DashMap<String, Vector>,
+    //per origin other than this one, up to where every update is held here
+    known: DashMap<String, u64>,
+    //what each peer said it holds when it last answered, and its origin then
+    peers: DashMap<String, (String, Vector)>,
+}
+
+impl Scuttlebutt {
+    pub fn new(node_id: &str) -> Self {
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        Scuttlebutt {
+            origin: format!("{}@{}", node_id, started),
+            own: AtomicU64::new(0),
+            versions: DashMap::new(),
+            known: DashMap::new(),
+            peers: DashMap::new(),
+        }
+    }
+
+    //a change made here. numbered while the key's versions are locked, so a vector that
+    //includes the number never comes before the key carrying it
+    pub fn local(&self, key: &str) {
+        let mut versions = self.versions.entry(key.to_string()).or_default();
+        let seq = self.own.fetch_add(1, Ordering::SeqCst) + 1;
+        versions.insert(self.origin.clone(), seq);
+    }
+
+    //a peer's state for the key was merged in, along with what it carried
+    pub fn merge(&self, key: &str, remote: &Vector) {
+        let mut versions = self.versions.entry(key.to_string()).or_default();
+        for (origin, seq) in remote {
+            let held = versions.entry(origin.clone()).or_insert(0);
+            *held = (*held).max(*seq);
+        }
+    }
+
+    pub fn versions(&self, key: &str) -> Vector {
+        self.versions
+            .get(key)
+            .map(|versions| versions.clone())
+            .unwrap_or_default()
+    }
+
+    //up to where every update of each origin is held here
+    pub fn vector(&self) -> Vector {
+        let mut vector: Vector = self
+            .known
+            .iter()
+            .map(|entry| (entry.key().clone(), *entry.value()))
+            .collect();
+        vector.insert(self.origin.clone(), self.own.load(Ordering::SeqCst));
+        vector
+    }
+
+    //a peer covered everything up to `complete`, and it has all been merged
+    pub fn learn(&self, complete: &Vector) {
+        for (origin, seq) in complete {
+            if *origin == self.origin {
+                continue;
+            }
+            let mut known = self.known.entry(origin.clone()).or_insert(0);
+            *known = (*known).max(*seq);
+        }
+    }
+
+    //the keys a node holding `theirs` is missing, with their versions, oldest update first
+    //and at most `limit` of them. and up to where that covers every update, read before the
+    //keys are so that nothing merged in while they are gathered is claimed
+    pub fn missing(&self, theirs: &Vector, limit: usize) -> (Vec<(String, Vector)>, Vector) {
+        let mut complete = self.vector();
+        let newer = |origin: &String, seq: &u64| *seq > theirs.get(origin).copied().unwrap_or(0);
+        let mut candidates: Vec<(u64, String, Vector)> = self
+            .versions
+            .iter()
+            .filter_map(|entry| {
+                let oldest = entry
+                    .value()
+                    .iter()
+                    .filter(|(origin, seq)| newer(origin, seq))
+                    .map(|(_, seq)| *seq)
+                    .min()?;
+                Some((oldest, entry.key().clone(), entry.value().clone()))
+            })
+            .collect();
+        candidates.sort();
+
+        let left_out = candidates.split_off(candidates.len().min(limit));
+        for (_, _, versions) in &left_out {
+            for (origin, seq) in versions.iter().filter(|(origin, seq)| newer(origin, seq)) {
+                if let Some(covered) = complete.get_mut(origin) {
+                    *covered = (*covered).min(seq - 1);
+                }
+            }
+        }
+        //nothing is claimed for an origin this node has no vector entry for
+        complete.retain(|origin, covered| *covered > theirs.get(origin).copied().unwrap_or(0));
+        let missing = candidates
+            .into_iter()
+            .map(|(_, key, versions)| (key, versions))
+            .collect();
+        (missing, complete)
+    }
+
+    pub fn peer_view(&self, peer_addr: &str) -> Option<(String, Vector)> {
+        self.peers.get(peer_addr).map(|view| view.clone())
+    }
+
+    pub fn set_peer_view(&self, peer_addr: &str, origin: String, vector: Vector) {
+        self.peers.insert(peer_addr.to_string(), (origin, vector));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        communication::{replication_service_server::ReplicationService, ReconcileRequest},
+        network::ReplicationServer,
+        testing::{send, server},
+    };
+    use tonic::Request;
+
+    fn reconcile(from: &ReplicationServer) -> ReconcileRequest {
+        ReconcileRequest {
+            node_id: from.config.node_id.clone(),
+            vector: from.scuttlebutt.vector().into_iter().collect(),
+            ..Default::default()
+        }
+    }
+
+    fn vector(entries: &[(&str, u64)]) -> Vector {
+        entries
+            .iter()
+            .map(|(origin, seq)| (origin.to_string(), *seq))
+            .collect()
+    }
+
+    #[test]
+    fn test_only_missing_updates_are_sent() {
+        let a = Scuttlebutt::new("a");
+        a.local("x");
+        a.local("y");
+        a.merge("z", &vector(&[("c@1", 4)]));
+        a.learn(&vector(&[("c@1", 4)]));
+        let own = a.origin.clone();
+
+        //nothing known yet, everything goes
+        let (missing, complete) = a.missing(&Vector::new(), 100);
+        let keys: Vec<&str> = missing.iter().map(|(key, _)| key.as_str()).collect();
+        assert_eq!(keys, vec!["x", "y", "z"]);
+        assert_eq!(complete, vector(&[(&own, 2), ("c@1", 4)]));
+
+        //a peer that has x and everything from c only misses y
+        let (missing, complete) = a.missing(&vector(&[(&own, 1), ("c@1", 4)]), 100);
+        assert_eq!(missing, vec![("y".to_string(), vector(&[(&own, 2)]))]);
+        assert_eq!(complete, vector(&[(&own, 2)]));
+
+        let (missing, _) = a.missing(&a.vector(), 100);
+        assert!(missing.is_empty());
+    }
+
+    #[test]
+    fn test_truncated_replies_only_claim_what_they_cover() {
+        let a = Scuttlebutt::new("a");
+        for key in ["k1", "k2", "k3"] {
+            a.local(key);
+        }
+        let own = a.origin.clone();
+        let (missing, complete) = a.missing(&Vector::new(), 2);
+        assert_eq!(missing.len(), 2);
+        //k3 (update 3) was left out
+        assert_eq!(complete, vector(&[(&own, 2)]));
+
+        //a peer that learnt that gets the rest next time
+        let b = Scuttlebutt::new("b");
+        for (key, versions) in &missing {
+            b.merge(key, versions);
+        }
+        b.learn(&complete);
+        let (missing, complete) = a.missing(&b.vector(), 2);
+        assert_eq!(missing, vec![("k3".to_string(), vector(&[(&own, 3)]))]);
+        b.learn(&complete);
+        assert_eq!(b.vector()[&own], 3);
+        //b's own number is never taken from a peer
+        b.learn(&vector(&[(&b.origin, 99)]));
+        assert_eq!(b.vector()[&b.origin], 0);
+    }
+
+    #[tokio::test]
+    async fn test_servers_exchange_what_the_other_misses() {
+        let (a, b) = (server("n1"), server("n2"));
+        send(&a, "RSET", "k", "v").await;
+
+        //b asks, a answers with the write
+        let answer = a
+            .reconcile(Request::new(reconcile(&b)))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(answer.origin, a.scuttlebutt.origin);
+        assert_eq!(answer.updates.len(), 1);
+        assert_eq!(answer.updates[0].key, "k");
+
+        //the same updates pushed to b, picked for the b that is running
+        let push = ReconcileRequest {
+            updates: answer.updates.clone(),
+            complete: answer.complete.clone(),
+            expected_origin: b.scuttlebutt.origin.clone(),
+            ..reconcile(&a)
+        };
+        let back = b.reconcile(Request::new(push.clone())).await.unwrap().into_inner();
+        assert!(b.store.contains_key("k"));
+        assert_eq!(b.scuttlebutt.vector()[&a.scuttlebutt.origin], 1);
+        //a has everything b has, nothing comes back
+        assert!(back.updates.is_empty());
+
+        //picked for a node that has restarted since, merged but not claimed
+        let c = server("n2");
+        c.reconcile(Request::new(ReconcileRequest {
+            expected_origin: "n2@0".to_string(),
+            ..push
+        }))
+        .await
+        .unwrap();
+        assert!(c.store.contains_key("k"));
+        assert!(!c.scuttlebutt.vector().contains_key(&a.scuttlebutt.origin));
+    }
+}
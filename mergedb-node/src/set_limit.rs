@@ -0,0 +1,49 @@
+//SLIMIT <key> <n> caps how many elements a set shows, for sets used as recent-items lists. the
+//limit is an lww register under PREFIX + key, so it gossips like any other key. a set past its
+//limit loses the elements whose newest add is oldest (see AWSet::trim), on local writes and
+//again after every merge, so replicas that hold the same state agree on what was evicted. an
+//empty register (SLIMIT <key> without n) lifts the limit
+pub const PREFIX: &str = "__limit:";
+
+pub fn limit_key(key: &str) -> String {
+    format!("{}{}", PREFIX, key)
+}
+
+//limits are only ever written through SLIMIT
+pub fn is_limit(key: &str) -> bool {
+    key.starts_with(PREFIX)
+}
+
+//the set a limit register caps
+pub fn limited_key(limit_key: &str) -> Option<&str> {
+    limit_key.strip_prefix(PREFIX)
+}
+
+//what a limit register holds, None for no limit
+pub fn parse(value: &str) -> Result<Option<usize>, String> {
+    let value = value.trim();
+    if value.is_empty() {
+        return Ok(None);
+    }
+    match value.parse::<usize>() {
+        Ok(0) => Err("a set limit can't be 0, SLIMIT without a number lifts it".to_string()),
+        Ok(limit) => Ok(Some(limit)),
+        Err(_) => Err(format!("{:?} is not a number of elements", value)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_limits() {
+        assert_eq!(limit_key("recent:ada"), "__limit:recent:ada");
+        assert!(is_limit(&limit_key("recent:ada")));
+        assert_eq!(limited_key(&limit_key("recent:ada")), Some("recent:ada"));
+        assert_eq!(parse(" 50 "), Ok(Some(50)));
+        assert_eq!(parse(""), Ok(None));
+        assert!(parse("0").is_err());
+        assert!(parse("-1").is_err());
+    }
+}
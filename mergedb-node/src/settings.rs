@@ -0,0 +1,233 @@
+//settings that can be changed for the whole cluster with SETTING on any node, instead of editing
+//every node's toml. each one is an lww register under PREFIX + name, so it gossips like any
+//other key and concurrent changes settle on whichever came last. nodes look settings up when
+//they use them, so a change applies on every node as soon as it has been merged there. an
+//empty register (SETTING <name> without a value) hands the setting back to each node's config
+use serde_json::json;
+use std::{collections::BTreeMap, str::FromStr, time::Duration};
+
+use crate::{config::Config, digest::GossipMode, tuning::Tuning, units};
+
+pub const PREFIX: &str = "__setting:";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Setting {
+    GossipInterval,
+    MaxValueSize,
+    ScriptMaxOperations,
+    ScriptTimeout,
+    GossipMode,
+}
+
+impl Setting {
+    pub const ALL: [Setting; 5] = [
+        Setting::GossipInterval,
+        Setting::MaxValueSize,
+        Setting::ScriptMaxOperations,
+        Setting::ScriptTimeout,
+        Setting::GossipMode,
+    ];
+
+    //the same name as in the toml
+    pub fn name(&self) -> &'static str {
+        match self {
+            Setting::GossipInterval => "gossip_interval",
+            Setting::MaxValueSize => "max_value_size",
+            Setting::ScriptMaxOperations => "script_max_operations",
+            Setting::ScriptTimeout => "script_timeout",
+            Setting::GossipMode => "gossip_mode",
+        }
+    }
+
+    pub fn key(&self) -> String {
+        format!("{}{}", PREFIX, self.name())
+    }
+
+    //a value is checked before it is stored, so every node can read it back
+    pub fn validate(&self, value: &str) -> Result<(), String> {
+        let positive = match self {
+            Setting::GossipMode => return value.parse::<GossipMode>().map(|_| ()),
+            Setting::GossipInterval | Setting::ScriptTimeout => {
+                !units::parse_duration(value, Duration::from_millis)?.is_zero()
+            }
+            Setting::MaxValueSize => units::size::parse(value)? > 0,
+            Setting::ScriptMaxOperations => {
+                value
+                    .trim()
+                    .parse::<u64>()
+                    .map_err(|_| format!("{:?} is not a number of operations", value))?
+                    > 0
+            }
+        };
+        match positive {
+            true => Ok(()),
+            false => Err(format!("{} can't be 0", self.name())),
+        }
+    }
+}
+
+impl FromStr for Setting {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        Setting::ALL
+            .into_iter()
+            .find(|setting| setting.name() == name)
+            .ok_or_else(|| {
+                let names: Vec<&str> = Setting::ALL.iter().map(Setting::name).collect();
+                format!("unknown setting {:?}, expected one of {:?}", name, names)
+            })
+    }
+}
+
+//settings are only ever written through SETTING
+pub fn is_setting(key: &str) -> bool {
+    key.starts_with(PREFIX)
+}
+
+//what a node runs with: its config, overridden by the settings stored in the cluster
+#[derive(Debug, Clone, PartialEq)]
+pub struct Settings {
+    pub gossip_interval: Duration,
+    pub max_value_size: u64,
+    pub script_max_operations: u64,
+    pub script_timeout: Duration,
+    pub gossip_mode: GossipMode,
+}
+
+impl Settings {
+    //`stored` gives a setting's register value, when it has a non-empty one. a stored value
+    //this node can't read, eg from a newer node, is ignored in favour of the config. what the
+    //config leaves out is `tuned` for the cluster's size
+    pub fn resolve(
+        config: &Config,
+        tuned: &Tuning,
+        stored: impl Fn(Setting) -> Option<String>,
+    ) -> Settings {
+        let stored = |setting: Setting| stored(setting).filter(|v| setting.validate(v).is_ok());
+        Settings {
+            gossip_interval: stored(Setting::GossipInterval)
+                .and_then(|v| units::parse_duration(&v, Duration::from_millis).ok())
+                .or(config.gossip_interval)
+                .unwrap_or(tuned.gossip_interval),
+            max_value_size: stored(Setting::MaxValueSize)
+                .and_then(|v| units::size::parse(&v).ok())
+                .unwrap_or(config.max_value_size),
+            script_max_operations: stored(Setting::ScriptMaxOperations)
+                .and_then(|v| v.trim().parse().ok())
+                .unwrap_or(config.script_max_operations),
+            script_timeout: stored(Setting::ScriptTimeout)
+                .and_then(|v| units::parse_duration(&v, Duration::from_millis).ok())
+                .unwrap_or(config.script_timeout),
+            gossip_mode: stored(Setting::GossipMode)
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(config.gossip_mode),
+        }
+    }
+
+    pub fn format(&self, setting: Setting) -> String {
+        match setting {
+            Setting::GossipInterval => humantime::format_duration(self.gossip_interval).to_string(),
+            Setting::MaxValueSize => units::size::format(self.max_value_size),
+            Setting::ScriptMaxOperations => self.script_max_operations.to_string(),
+            Setting::ScriptTimeout => humantime::format_duration(self.script_timeout).to_string(),
+            Setting::GossipMode => self.gossip_mode.to_string(),
+        }
+    }
+}
+
+//{name: {value, source}} for SETTINGS, source is "cluster" when a stored setting is in effect,
+//"config" when the node's own toml is and "auto" when it was worked out for the cluster's size
+pub fn report(
+    config: &Config,
+    tuned: &Tuning,
+    stored: impl Fn(Setting) -> Option<String>,
+) -> BTreeMap<&'static str, serde_json::Value> {
+    let settings = Settings::resolve(config, tuned, &stored);
+    Setting::ALL
+        .into_iter()
+        .map(|setting| {
+            let source = match stored(setting) {
+                Some(value) if setting.validate(&value).is_ok() => "cluster",
+                _ if setting == Setting::GossipInterval && config.gossip_interval.is_none() => {
+                    "auto"
+                }
+                _ => "config",
+            };
+            (
+                setting.name(),
+                json!({ "value": settings.format(setting), "source": source }),
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    fn config() -> Config {
+        testing::config("n1", "gossip_interval = \"2s\"")
+    }
+
+    #[test]
+    fn test_stored_settings_override_the_config() {
+        let config = config();
+        let tuned = Tuning::for_cluster(3);
+        let nothing = Settings::resolve(&config, &tuned, |_| None);
+        assert_eq!(nothing.gossip_interval, Duration::from_secs(2));
+        assert_eq!(nothing.max_value_size, config.max_value_size);
+
+        let stored = |setting| match setting {
+            Setting::GossipInterval => Some("250ms".to_string()),
+            Setting::MaxValueSize => Some("64KiB".to_string()),
+            //unreadable values leave the config in charge
+            Setting::ScriptTimeout => Some("soon".to_string()),
+            Setting::ScriptMaxOperations => Some("0".to_string()),
+            Setting::GossipMode => Some("digest".to_string()),
+        };
+        let settings = Settings::resolve(&config, &tuned, stored);
+        assert_eq!(settings.gossip_interval, Duration::from_millis(250));
+        assert_eq!(settings.max_value_size, 64 << 10);
+        assert_eq!(settings.script_timeout, config.script_timeout);
+        assert_eq!(settings.script_max_operations, config.script_max_operations);
+        assert_eq!(settings.gossip_mode, GossipMode::Digest);
+
+        let reported = report(&config, &tuned, stored);
+        assert_eq!(reported["gossip_interval"]["source"], "cluster");
+        assert_eq!(reported["gossip_interval"]["value"], "250ms");
+        assert_eq!(reported["script_timeout"]["source"], "config");
+
+        //left out of the config, it follows the cluster's size
+        let auto = testing::config("n1", "");
+        let big = Tuning::for_cluster(100);
+        assert_eq!(
+            Settings::resolve(&auto, &big, |_| None).gossip_interval,
+            big.gossip_interval
+        );
+        assert_eq!(
+            Settings::resolve(&config, &big, |_| None).gossip_interval,
+            Duration::from_secs(2)
+        );
+        assert_eq!(report(&auto, &big, |_| None)["gossip_interval"]["source"], "auto");
+    }
+
+    #[test]
+    fn test_setting_names_and_values() {
+        assert_eq!(
+            "max_value_size".parse::<Setting>(),
+            Ok(Setting::MaxValueSize)
+        );
+        assert!("peers".parse::<Setting>().is_err());
+        assert!(is_setting(&Setting::ScriptTimeout.key()));
+        assert!(!is_setting("script_timeout"));
+
+        assert!(Setting::GossipInterval.validate("1s").is_ok());
+        assert!(Setting::GossipInterval.validate("0s").is_err());
+        assert!(Setting::MaxValueSize.validate("lots").is_err());
+        assert!(Setting::ScriptMaxOperations.validate("5000").is_ok());
+        assert!(Setting::GossipMode.validate("digest").is_ok());
+        assert!(Setting::GossipMode.validate("0").is_err());
+    }
+}
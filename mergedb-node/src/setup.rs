@@ -0,0 +1,309 @@
+use anyhow::{bail, Context, Result};
+use std::{
+    io::{BufRead, Write},
+    path::PathBuf,
+    time::Duration,
+};
+
+use crate::{
+    config::{
+        split_host_port, Config, PeerBreaker, PeerConfig, PeerTlsConfig, RequestQueue,
+        RuntimeConfig,
+    },
+    digest::GossipMode,
+    units,
+    wal::WalSync,
+};
+
+//walks the operator through every config field, a typo gets the question asked again
+//instead of ending the setup. answers are validated the same way load_config does
+pub struct Setup<R, W> {
+    input: R,
+    output: W,
+}
+
+impl<R: BufRead, W: Write> Setup<R, W> {
+    pub fn new(input: R, output: W) -> Self {
+        Setup { input, output }
+    }
+
+    //the config that was entered, and where the operator asked for it to be saved
+    pub fn run(&mut self) -> Result<(Config, Option<PathBuf>)> {
+        let node_id = self.ask("node id", None, |answer| {
+            if answer.is_empty() {
+                bail!("the node id can't be empty");
+            }
+            Ok(answer.to_string())
+        })?;
+        let listen_address = self.ask("listen address", Some("127.0.0.1:8000"), |answer| {
+            split_host_port(answer)?;
+            Ok(answer.to_string())
+        })?;
+        let peer_listen_address = self.ask(
+            "separate address for peer gossip (blank to use the listen address)",
+            Some(""),
+            |answer| {
+                if answer.is_empty() {
+                    return Ok(None);
+                }
+                split_host_port(answer)?;
+                Ok(Some(answer.to_string()))
+            },
+        )?;
+        let peers = self.ask(
+            "peers, comma separated host:port (blank for none)",
+            Some(""),
+            parse_peers,
+        )?;
+
+        let peers = if peers.is_empty() {
+            peers
+        } else {
+            let https = self.ask(
+                "connect to peers over https? [y/n]",
+                Some("n"),
+                parse_yes_no,
+            )?;
+            let ca_cert = if https {
+                self.ask(
+                    "CA certificate (pem) to trust for peers (blank for system roots)",
+                    Some(""),
+                    parse_existing_path,
+                )?
+            } else {
+                None
+            };
+            peers
+                .into_iter()
+                .map(|mut peer| {
+                    if https {
+                        peer.scheme = String::from("https");
+                        peer.tls = ca_cert.clone().map(|ca_cert| PeerTlsConfig {
+                            domain_name: None,
+                            ca_cert: Some(ca_cert),
+                        });
+                    }
+                    peer
+                })
+                .collect()
+        };
+
+        let uds_path = self.ask(
+            "unix socket path for local clients (blank for none)",
+            Some(""),
+            |answer| Ok((!answer.is_empty()).then(|| PathBuf::from(answer))),
+        )?;
+        let uds_mode = match uds_path {
+            Some(_) => self.ask("unix socket permissions", Some("660"), |answer| {
+                u32::from_str_radix(answer.trim_start_matches("0o"), 8)
+                    .map(Some)
+                    .with_context(|| format!("{} is not an octal mode", answer))
+            })?,
+            None => None,
+        };
+        let peer_timeout = self.ask("peer timeout", Some("10s"), parse_duration)?;
+        let split_brain_after = self.ask(
+            "how long unreachable before a split-brain is reported",
+            Some("30s"),
+            parse_duration,
+        )?;
+        let split_brain_webhook = self.ask(
+            "split-brain webhook url (blank for none)",
+            Some(""),
+            |answer| {
+                if answer.is_empty() {
+                    return Ok(None);
+                }
+                if !answer.starts_with("http://") {
+                    bail!("webhook url must be http://");
+                }
+                Ok(Some(answer.to_string()))
+            },
+        )?;
+
+        let data_dir = self.ask(
+            "data directory for snapshots (blank to keep everything in memory)",
+            Some(""),
+            |answer| Ok((!answer.is_empty()).then(|| PathBuf::from(answer))),
+        )?;
+
+        let mut config = Config {
+            node_id,
+            cluster_id: None,
+            listen_address,
+            client_listen_address: None,
+            peer_listen_address,
+            peers,
+            uds_path,
+            uds_mode,
+            peer_timeout,
+            split_brain_after,
+            split_brain_webhook,
+            data_dir,
+            snapshot_every: Duration::from_secs(60),
+            preload: Vec::new(),
+            gossip_interval: None,
+            fanout: None,
+            gossip_priority: Vec::new(),
+            max_value_size: 4 << 20,
+            gossip_allow: Vec::new(),
+            gossip_deny: Vec::new(),
+            counter_anomaly: None,
+            script_max_operations: 100_000,
+            script_timeout: Duration::from_millis(250),
+            witness: false,
+            metrics_address: None,
+            websocket_address: None,
+            graphql_address: None,
+            grpc_web: false,
+            gossip_mode: GossipMode::default(),
+            discover_peers: true,
+            peer_breaker: PeerBreaker::default(),
+            wal_sync: WalSync::default(),
+            label_rules: Vec::new(),
+            runtime: RuntimeConfig::default(),
+            request_queue: RequestQueue::default(),
+            plumtree: None,
+            audit: None,
+            watchdog: None,
+        };
+        for warning in config.dedup_peers() {
+            writeln!(self.output, "warning: {}", warning)?;
+        }
+        config.validate()?;
+
+        let save_to = self.ask(
+            "save this config to (blank to not save)",
+            Some("config.toml"),
+            |answer| Ok((!answer.is_empty()).then(|| PathBuf::from(answer))),
+        )?;
+
+        Ok((config, save_to))
+    }
+
+    //asks until `parse` accepts the answer, an empty answer takes the default if there is one
+    fn ask<T>(
+        &mut self,
+        question: &str,
+        default: Option<&str>,
+        parse: impl Fn(&str) -> Result<T>,
+    ) -> Result<T> {
+        loop {
+            match default {
+                Some(default) if !default.is_empty() => {
+                    write!(self.output, "{} [{}]: ", question, default)?
+                }
+                _ => write!(self.output, "{}: ", question)?,
+            }
+            self.output.flush()?;
+
+            let mut line = String::new();
+            if self.input.read_line(&mut line)? == 0 {
+                bail!("setup aborted, input ended at \"{}\"", question);
+            }
+            let answer = match line.trim() {
+                "" => default.unwrap_or(""),
+                answer => answer,
+            };
+
+            match parse(answer) {
+                Ok(value) => return Ok(value),
+                Err(e) => writeln!(self.output, "  {:#}, try again", e)?,
+            }
+        }
+    }
+}
+
+fn parse_peers(answer: &str) -> Result<Vec<PeerConfig>> {
+    answer
+        .split(',')
+        .map(str::trim)
+        .filter(|address| !address.is_empty())
+        .map(|address| {
+            let peer = PeerConfig::new(address.to_string());
+            split_host_port(&peer.address)
+                .with_context(|| format!("invalid peer address {}", address))?;
+            Ok(peer)
+        })
+        .collect()
+}
+
+fn parse_yes_no(answer: &str) -> Result<bool> {
+    match answer.to_lowercase().as_str() {
+        "y" | "yes" => Ok(true),
+        "n" | "no" => Ok(false),
+        _ => bail!("expected y or n"),
+    }
+}
+
+//"30s", "2m", or a bare number of seconds
+fn parse_duration(answer: &str) -> Result<Duration> {
+    match units::parse_duration(answer, Duration::from_secs) {
+        Ok(duration) if duration < Duration::from_secs(1) => bail!("must be at least 1 second"),
+        Ok(duration) => Ok(duration),
+        Err(e) => bail!(e),
+    }
+}
+
+fn parse_existing_path(answer: &str) -> Result<Option<PathBuf>> {
+    if answer.is_empty() {
+        return Ok(None);
+    }
+    let path = PathBuf::from(answer);
+    if !path.is_file() {
+        bail!("{} does not exist", answer);
+    }
+    Ok(Some(path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_typos_are_asked_again() {
+        let answers = "\n\
+                       node_1\n\
+                       localhost\n\
+                       \n\
+                       10.0.0.1\n\
+                       10.0.0.1:8001\n\
+                       127.0.0.1:8001, node3:80x0\n\
+                       127.0.0.1:8001, node3:8000, 127.0.0.1:8001\n\
+                       maybe\n\
+                       n\n\
+                       \n\
+                       0\n\
+                       5\n\
+                       \n\
+                       https://hooks.internal\n\
+                       \n\
+                       /var/lib/mergedb\n\
+                       \n";
+        let mut output = Vec::new();
+        let (config, save_to) = Setup::new(answers.as_bytes(), &mut output).run().unwrap();
+
+        assert_eq!(config.node_id, "node_1");
+        assert_eq!(config.listen_address, "127.0.0.1:8000");
+        assert_eq!(config.peer_address(), "10.0.0.1:8001");
+        let peers: Vec<&str> = config.peers.iter().map(|p| p.address.as_str()).collect();
+        assert_eq!(peers, ["127.0.0.1:8001", "node3:8000"]);
+        assert_eq!(config.peer_timeout, Duration::from_secs(5));
+        assert_eq!(config.split_brain_after, Duration::from_secs(30));
+        assert_eq!(config.split_brain_webhook, None);
+        assert_eq!(config.data_dir, Some(PathBuf::from("/var/lib/mergedb")));
+        assert_eq!(save_to, Some(PathBuf::from("config.toml")));
+
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(output.matches("try again").count(), 7);
+        assert!(output.contains("listed more than once"));
+    }
+
+    #[test]
+    fn test_input_ending_aborts() {
+        let mut output = Vec::new();
+        assert!(Setup::new("node_1\n".as_bytes(), &mut output)
+            .run()
+            .is_err());
+    }
+}
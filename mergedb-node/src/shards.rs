@@ -0,0 +1,78 @@
+//CSHARD <key> <n> spreads a hot counter's writes over n entries, so thousands of clients
+//incrementing one key don't all wait on the same store entry. the key itself is shard 0, the
+//others are ordinary counters under SHARD_PREFIX + key + "#" + i that gossip like any other
+//key, and CGET sums them. the count is an lww register under PREFIX + key. it can only go up,
+//a shard that was written to holds part of the value for good
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+pub const PREFIX: &str = "__shards:";
+pub const SHARD_PREFIX: &str = "__shard:";
+//past this the sum on read costs more than the writes save
+pub const MAX_SHARDS: usize = 64;
+
+pub fn count_key(key: &str) -> String {
+    format!("{}{}", PREFIX, key)
+}
+
+//shard counts are only ever written through CSHARD
+pub fn is_count(key: &str) -> bool {
+    key.starts_with(PREFIX)
+}
+
+//the key itself for shard 0
+pub fn shard_key(key: &str, shard: usize) -> String {
+    match shard {
+        0 => key.to_string(),
+        _ => format!("{}{}#{}", SHARD_PREFIX, key, shard),
+    }
+}
+
+//shards are only ever written through CINC and CDEC on the key they belong to
+pub fn is_shard(key: &str) -> bool {
+    key.starts_with(SHARD_PREFIX)
+}
+
+//what a shard count register holds, an empty one is an unsharded key
+pub fn parse(value: &str) -> Result<usize, String> {
+    let value = value.trim();
+    if value.is_empty() {
+        return Ok(1);
+    }
+    match value.parse::<usize>() {
+        Ok(shards @ 1..=MAX_SHARDS) => Ok(shards),
+        Ok(_) => Err(format!("a counter takes 1 to {} shards", MAX_SHARDS)),
+        Err(_) => Err(format!("{:?} is not a number of shards", value)),
+    }
+}
+
+static NEXT: AtomicUsize = AtomicUsize::new(0);
+
+//the shard the next write goes to, round robin so concurrent writers land on different ones
+pub fn pick(shards: usize) -> usize {
+    match shards {
+        0 | 1 => 0,
+        _ => NEXT.fetch_add(1, Ordering::Relaxed) % shards,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shards() {
+        assert_eq!(count_key("views"), "__shards:views");
+        assert!(is_count(&count_key("views")));
+        assert!(!is_shard(&count_key("views")));
+        assert_eq!(shard_key("views", 0), "views");
+        assert_eq!(shard_key("views", 3), "__shard:views#3");
+        assert!(is_shard(&shard_key("views", 3)));
+        assert_eq!(parse(" 8 "), Ok(8));
+        assert_eq!(parse(""), Ok(1));
+        assert!(parse("0").is_err());
+        assert!(parse("65").is_err());
+
+        let picked: Vec<usize> = (0..8).map(|_| pick(4)).collect();
+        assert!((0..4).all(|shard| picked.contains(&shard)));
+    }
+}
@@ -0,0 +1,306 @@
+use serde_json::json;
+use std::{
+    collections::{BTreeSet, HashSet},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{membership::Membership, metrics::Metrics};
+
+//purely observational: when the known cluster falls apart into two groups that stay disjoint
+//for longer than split_after, a split-brain is reported. the other group is made of members
+//that can't be reached from here but still reached each other when they were last heard from,
+//a member that went silent on its own is taken to be down, not partitioned off.
+//
+//while a split lasts every key changed on this side is remembered, its state differs from the
+//other side's by the time the partition heals. how many that were is the divergence estimate,
+//each side reports its own half of it
+//
+//each side also learns whether it holds the majority of the known cluster, witnesses counted,
+//which is how a witness at a third site arbitrates between two equally sized ones
+#[derive(Debug)]
+pub struct SplitBrainDetector {
+    split_after: Duration,
+    //whether changes are being remembered, checked on every commit without taking the lock
+    partitioned: AtomicBool,
+    state: Mutex<State>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    partitioned_since: Option<Instant>,
+    reported: bool,
+    local_group: BTreeSet<String>,
+    remote_group: BTreeSet<String>,
+    majority: bool,
+    changed_keys: HashSet<String>,
+    last_divergence: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SplitBrainStatus {
+    pub split_brain: bool,
+    pub local_group: Vec<String>,
+    pub remote_group: Vec<String>,
+    //more than half of every known node, this one included, is on this side
+    pub majority: bool,
+    pub partitioned_for: Duration,
+    pub last_divergence: Option<u64>,
+}
+
+impl SplitBrainDetector {
+    pub fn new(split_after: Duration) -> Self {
+        SplitBrainDetector {
+            split_after,
+            partitioned: AtomicBool::new(false),
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    //run once per gossip round, returns a webhook payload whenever something worth telling the
+    //operator about happened
+    pub fn evaluate(
+        &self,
+        membership: &Membership,
+        metrics: &Metrics,
+    ) -> Option<serde_json::Value> {
+        let (local, remote) = membership.groups();
+        metrics.set_gauge("cluster_members_reachable", local.len() as i64 - 1);
+        metrics.set_gauge("cluster_members_unreachable", remote.len() as i64);
+        let other_side = membership.linked_within(&remote);
+
+        let mut state = self.state.lock().unwrap();
+        state.majority = local.len() * 2 > local.len() + remote.len();
+        state.local_group = local;
+
+        if !other_side.is_empty() {
+            let since = match state.partitioned_since {
+                Some(since) => since,
+                None => {
+                    state.changed_keys.clear();
+                    self.partitioned.store(true, Ordering::SeqCst);
+                    *state.partitioned_since.insert(Instant::now())
+                }
+            };
+            state.remote_group = other_side;
+
+            if !state.reported && since.elapsed() >= self.split_after {
+                state.reported = true;
+                metrics.set_gauge("split_brain", 1);
+                metrics.incr("split_brain_events_total", 1);
+                println!(
+                    "WARNING: split-brain suspected, {:?} cannot reach {:?} for {}s",
+                    state.local_group,
+                    state.remote_group,
+                    since.elapsed().as_secs()
+                );
+                return Some(json!({
+                    "event": "split_brain_detected",
+                    "node_id": membership.node_id,
+                    "local_group": state.local_group,
+                    "remote_group": state.remote_group,
+                    "majority": state.majority,
+                    "partitioned_for_secs": since.elapsed().as_secs(),
+                }));
+            }
+            return None;
+        }
+
+        let since = state.partitioned_since.take()?;
+        self.partitioned.store(false, Ordering::SeqCst);
+        let healed_group = std::mem::take(&mut state.remote_group);
+        let changed = std::mem::take(&mut state.changed_keys);
+        if !state.reported {
+            return None;
+        }
+        //the groups can see each other again
+        state.reported = false;
+        let diverged = changed.len() as u64;
+        state.last_divergence = Some(diverged);
+        metrics.set_gauge("split_brain", 0);
+        metrics.set_gauge("split_brain_divergent_keys", diverged as i64);
+        println!(
+            "partition with {:?} healed, {} keys changed on this side meanwhile",
+            healed_group, diverged
+        );
+        Some(json!({
+            "event": "split_brain_healed",
+            "node_id": membership.node_id,
+            "healed_group": healed_group,
+            "partitioned_for_secs": since.elapsed().as_secs(),
+            "divergent_keys_estimate": diverged,
+        }))
+    }
+
+    //a key changed here, by a client or by a merge. while a split lasts it now differs from
+    //what the other side holds
+    pub fn record_change(&self, key: &str) {
+        if !self.partitioned.load(Ordering::Relaxed) {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        if state.partitioned_since.is_some() {
+            state.changed_keys.insert(key.to_string());
+        }
+    }
+
+    pub fn status(&self) -> SplitBrainStatus {
+        let state = self.state.lock().unwrap();
+        SplitBrainStatus {
+            split_brain: state.reported,
+            local_group: state.local_group.iter().cloned().collect(),
+            remote_group: state.remote_group.iter().cloned().collect(),
+            majority: state.majority,
+            partitioned_for: state
+                .partitioned_since
+                .map(|since| since.elapsed())
+                .unwrap_or_default(),
+            last_divergence: state.last_divergence,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mergedb_proto::migrate::FORMAT_VERSION;
+
+    //the members reach node_1 and each other, then go quiet
+    fn cut_off(membership: &Membership, ids: &[&str]) {
+        for id in ids {
+            let mut reachable = vec!["node_1".to_string()];
+            reachable.extend(ids.iter().map(|id| id.to_string()));
+            membership.observe(id.to_string(), None, reachable, FORMAT_VERSION, false);
+        }
+        for id in ids {
+            membership.members.get_mut(*id).unwrap().last_seen =
+                Instant::now() - Duration::from_secs(120);
+        }
+    }
+
+    #[test]
+    fn test_partition_reported_then_healed() {
+        let membership = Membership::new("node_1".to_string(), Duration::from_secs(60));
+        let metrics = Metrics::new();
+        let detector = SplitBrainDetector::new(Duration::ZERO);
+
+        cut_off(&membership, &["node_2", "node_3"]);
+        detector.record_change("before");
+        let detected = detector.evaluate(&membership, &metrics).unwrap();
+        assert_eq!(detected["event"], "split_brain_detected");
+        assert_eq!(detected["remote_group"], json!(["node_2", "node_3"]));
+        assert!(detector.status().split_brain);
+        assert_eq!(metrics.gauge("split_brain"), 1);
+
+        //only reported once, and writes on this side meanwhile diverge
+        assert!(detector.evaluate(&membership, &metrics).is_none());
+        detector.record_change("likes");
+        detector.record_change("likes");
+        detector.record_change("tags");
+
+        //they come back
+        for id in ["node_2", "node_3"] {
+            membership.observe(
+                id.to_string(),
+                None,
+                vec!["node_1".to_string()],
+                FORMAT_VERSION,
+                false,
+            );
+        }
+        let healed = detector.evaluate(&membership, &metrics).unwrap();
+        assert_eq!(healed["event"], "split_brain_healed");
+        assert_eq!(healed["divergent_keys_estimate"], 2);
+        assert!(!detector.status().split_brain);
+        assert_eq!(detector.status().last_divergence, Some(2));
+
+        //writes after the heal are just writes
+        detector.record_change("views");
+        assert!(detector.evaluate(&membership, &metrics).is_none());
+        assert_eq!(detector.status().last_divergence, Some(2));
+    }
+
+    #[test]
+    fn test_a_silent_node_is_not_a_split() {
+        let membership = Membership::new("node_1".to_string(), Duration::from_secs(60));
+        let metrics = Metrics::new();
+        let detector = SplitBrainDetector::new(Duration::ZERO);
+
+        membership.observe(
+            "node_3".to_string(),
+            None,
+            vec!["node_1".to_string()],
+            FORMAT_VERSION,
+            false,
+        );
+        cut_off(&membership, &["node_2"]);
+        assert!(detector.evaluate(&membership, &metrics).is_none());
+        assert!(!detector.status().split_brain);
+        assert_eq!(metrics.gauge("cluster_members_unreachable"), 1);
+    }
+
+    #[test]
+    fn test_transitively_reachable_node_is_not_partitioned() {
+        let membership = Membership::new("node_1".to_string(), Duration::from_secs(60));
+        let metrics = Metrics::new();
+        let detector = SplitBrainDetector::new(Duration::ZERO);
+
+        //node_3 can't be reached directly, but node_2 reaches it
+        membership.observe(
+            "node_3".to_string(),
+            None,
+            Vec::new(),
+            FORMAT_VERSION,
+            false,
+        );
+        membership.members.get_mut("node_3").unwrap().last_seen =
+            Instant::now() - Duration::from_secs(120);
+        membership.observe(
+            "node_2".to_string(),
+            None,
+            vec!["node_3".to_string()],
+            FORMAT_VERSION,
+            false,
+        );
+
+        assert!(detector.evaluate(&membership, &metrics).is_none());
+        assert!(!detector.status().split_brain);
+    }
+
+    #[test]
+    fn test_witness_decides_the_majority() {
+        let membership = Membership::new("node_1".to_string(), Duration::from_secs(60));
+        let metrics = Metrics::new();
+        let detector = SplitBrainDetector::new(Duration::ZERO);
+
+        //four data nodes and a witness, node_2 and node_3 are cut off but the witness is still
+        //here with node_4
+        cut_off(&membership, &["node_2", "node_3"]);
+        membership.observe(
+            "node_4".to_string(),
+            None,
+            vec!["node_1".to_string()],
+            FORMAT_VERSION,
+            false,
+        );
+        membership.observe(
+            "witness".to_string(),
+            Some("10.0.3.1:8000".to_string()),
+            vec!["node_1".to_string()],
+            FORMAT_VERSION,
+            true,
+        );
+
+        let detected = detector.evaluate(&membership, &metrics).unwrap();
+        assert_eq!(detected["majority"], true);
+        assert!(membership.is_witness_at("10.0.3.1:8000"));
+
+        //without the witness it's two against two, neither side has a majority
+        membership.members.remove("witness");
+        detector.evaluate(&membership, &metrics);
+        assert!(!detector.status().majority);
+    }
+}
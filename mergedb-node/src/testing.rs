@@ -0,0 +1,67 @@
+//what the tests of the modules built on ReplicationServer share: a config that keeps nothing on
+//disk and has no peers, servers made from it, and the client commands sent to them
+use tonic::Request;
+
+use crate::{
+    communication::{
+        replication_service_server::ReplicationService, PropagateDataRequest, PropagateDataResponse,
+    },
+    config::Config,
+    network::ReplicationServer,
+};
+
+impl ReplicationServer {
+    //a node that keeps nothing on disk, for the tests of the modules built on it
+    pub fn for_tests(config: Config) -> Self {
+        assert!(
+            config.data_dir.is_none(),
+            "test servers keep nothing on disk"
+        );
+        ReplicationServer::new(config).unwrap()
+    }
+}
+
+//node_id on 127.0.0.1:0 with no peers, extra is added for whatever else a test needs
+pub fn config(node_id: &str, extra: &str) -> Config {
+    toml::from_str(&format!(
+        "node_id = \"{}\"\nlisten_address = \"127.0.0.1:0\"\npeers = []\n{}",
+        node_id, extra
+    ))
+    .unwrap()
+}
+
+pub fn server(node_id: &str) -> ReplicationServer {
+    ReplicationServer::for_tests(config(node_id, ""))
+}
+
+//a client command with everything but the value left at its default
+pub fn command(valuetype: &str, key: &str, value: impl Into<Vec<u8>>) -> PropagateDataRequest {
+    PropagateDataRequest {
+        valuetype: valuetype.to_string(),
+        key: key.to_string(),
+        value: value.into(),
+        ..Default::default()
+    }
+}
+
+pub fn request(
+    valuetype: &str,
+    key: &str,
+    value: impl Into<Vec<u8>>,
+) -> Request<PropagateDataRequest> {
+    Request::new(command(valuetype, key, value))
+}
+
+//sends the command the way a client would, it has to go through
+pub async fn send(
+    server: &ReplicationServer,
+    valuetype: &str,
+    key: &str,
+    value: impl Into<Vec<u8>>,
+) -> PropagateDataResponse {
+    server
+        .propagate_data(request(valuetype, key, value))
+        .await
+        .unwrap()
+        .into_inner()
+}
@@ -0,0 +1,83 @@
+//how many peers a write is pushed to and how often the gossip loop goes round, when the config
+//doesn't say. both follow the size of the cluster, which is this node plus every node it gossips
+//with or has heard from, and are worked out again whenever they are used:
+//- fanout: ln(N) + 1 peers, so a write still reaches everyone in a few hops with high
+//  probability, but never fewer than the 3 every cluster used to get
+//- gossip_interval: 2s up to 4 nodes, and 1s more every time the cluster doubles after that. a
+//  round talks to every peer, so bigger clusters space them out a little
+//a fanout or gossip_interval in the config wins, and a gossip_interval SETTING over both
+use std::time::Duration;
+
+use crate::{membership::Membership, network::ReplicationServer};
+
+pub const MIN_FANOUT: usize = 3;
+pub const BASE_INTERVAL: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Tuning {
+    pub cluster_size: usize,
+    pub fanout: usize,
+    pub gossip_interval: Duration,
+}
+
+impl Tuning {
+    pub fn for_cluster(cluster_size: usize) -> Tuning {
+        let size = cluster_size.max(1);
+        let fanout = ((size as f64).ln().ceil() as usize + 1).max(MIN_FANOUT);
+        let doublings = size.next_power_of_two().trailing_zeros().saturating_sub(2);
+        Tuning {
+            cluster_size: size,
+            fanout,
+            gossip_interval: BASE_INTERVAL + Duration::from_secs(doublings as u64),
+        }
+    }
+}
+
+//this node and every other one it knows of
+pub fn cluster_size(server: &ReplicationServer) -> usize {
+    known_nodes(&server.membership, server.peers.len()) + 1
+}
+
+fn known_nodes(membership: &Membership, peers: usize) -> usize {
+    membership.members.len().max(peers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fanout_and_interval_grow_with_the_cluster() {
+        let tuned: Vec<(usize, usize, u64)> = [1, 3, 4, 5, 8, 20, 100, 1000]
+            .into_iter()
+            .map(|size| {
+                let tuning = Tuning::for_cluster(size);
+                (size, tuning.fanout, tuning.gossip_interval.as_secs())
+            })
+            .collect();
+        assert_eq!(
+            tuned,
+            vec![
+                (1, 3, 2),
+                (3, 3, 2),
+                (4, 3, 2),
+                (5, 3, 3),
+                (8, 4, 3),
+                (20, 4, 5),
+                (100, 6, 7),
+                (1000, 8, 10),
+            ]
+        );
+        assert_eq!(Tuning::for_cluster(0), Tuning::for_cluster(1));
+    }
+
+    #[test]
+    fn test_cluster_size_counts_every_known_node() {
+        let membership = Membership::new("n1".to_string(), Duration::from_secs(10));
+        assert_eq!(known_nodes(&membership, 2), 2);
+        for node_id in ["n2", "n3", "n4"] {
+            membership.observe(node_id.to_string(), None, vec![], 0, false);
+        }
+        assert_eq!(known_nodes(&membership, 2), 3);
+    }
+}
@@ -0,0 +1,220 @@
+//durations ("500ms", "5m", "1h 30m") and sizes ("512KiB", "1MiB") in the config are written
+//the way people say them. a bare integer is still read in the unit the setting used to be
+//given in, so configs from before keep meaning the same thing
+use serde::{de, Deserializer, Serializer};
+use std::{fmt, time::Duration};
+
+//durations are written back out the same way, eg "1m 30s"
+fn serialize_duration<S: Serializer>(
+    duration: &Duration,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&humantime::format_duration(*duration).to_string())
+}
+
+//reads "5m" style strings, and integers as `bare` units
+fn deserialize_duration<'de, D: Deserializer<'de>>(
+    deserializer: D,
+    bare: fn(u64) -> Duration,
+) -> Result<Duration, D::Error> {
+    struct Visitor(fn(u64) -> Duration);
+
+    impl de::Visitor<'_> for Visitor {
+        type Value = Duration;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "a duration like \"500ms\" or \"5m\"")
+        }
+
+        fn visit_u64<E: de::Error>(self, value: u64) -> Result<Duration, E> {
+            Ok((self.0)(value))
+        }
+
+        fn visit_i64<E: de::Error>(self, value: i64) -> Result<Duration, E> {
+            u64::try_from(value)
+                .map(self.0)
+                .map_err(|_| E::custom("a duration can't be negative"))
+        }
+
+        fn visit_str<E: de::Error>(self, value: &str) -> Result<Duration, E> {
+            parse_duration(value, self.0).map_err(E::custom)
+        }
+    }
+
+    deserializer.deserialize_any(Visitor(bare))
+}
+
+pub fn parse_duration(value: &str, bare: fn(u64) -> Duration) -> Result<Duration, String> {
+    if let Ok(number) = value.trim().parse::<u64>() {
+        return Ok(bare(number));
+    }
+    humantime::parse_duration(value).map_err(|e| format!("{:?} is not a duration: {}", value, e))
+}
+
+//bare integers are seconds
+pub mod secs {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        serialize_duration(duration, serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        deserialize_duration(deserializer, Duration::from_secs)
+    }
+}
+
+//secs for settings that are off when left out, use with #[serde(default)]
+pub mod optional_secs {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(
+        duration: &Option<Duration>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match duration {
+            Some(duration) => serialize_duration(duration, serializer),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Duration>, D::Error> {
+        deserialize_duration(deserializer, Duration::from_secs).map(Some)
+    }
+}
+
+//millis for settings that are worked out when left out, use with #[serde(default)]
+pub mod optional_millis {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(
+        duration: &Option<Duration>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match duration {
+            Some(duration) => serialize_duration(duration, serializer),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Duration>, D::Error> {
+        deserialize_duration(deserializer, Duration::from_millis).map(Some)
+    }
+}
+
+//bare integers are milliseconds
+pub mod millis {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        serialize_duration(duration, serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        deserialize_duration(deserializer, Duration::from_millis)
+    }
+}
+
+//a number of bytes, bare integers are bytes
+pub mod size {
+    use super::*;
+
+    const UNITS: [(u64, &str); 3] = [(1 << 30, "GiB"), (1 << 20, "MiB"), (1 << 10, "KiB")];
+
+    //the largest binary unit that divides the size evenly, so the value reads back exactly
+    pub fn format(bytes: u64) -> String {
+        UNITS
+            .iter()
+            .find(|(unit, _)| bytes > 0 && bytes.is_multiple_of(*unit))
+            .map(|(unit, name)| format!("{}{}", bytes / unit, name))
+            .unwrap_or_else(|| format!("{}B", bytes))
+    }
+
+    pub fn parse(value: &str) -> Result<u64, String> {
+        value
+            .trim()
+            .parse::<bytesize::ByteSize>()
+            .map(|size| size.as_u64())
+            .map_err(|e| format!("{:?} is not a size: {}", value, e))
+    }
+
+    pub fn serialize<S: Serializer>(bytes: &u64, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&format(*bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+        struct Visitor;
+
+        impl de::Visitor<'_> for Visitor {
+            type Value = u64;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "a size like \"512KiB\" or \"1MiB\"")
+            }
+
+            fn visit_u64<E: de::Error>(self, value: u64) -> Result<u64, E> {
+                Ok(value)
+            }
+
+            fn visit_i64<E: de::Error>(self, value: i64) -> Result<u64, E> {
+                u64::try_from(value).map_err(|_| E::custom("a size can't be negative"))
+            }
+
+            fn visit_str<E: de::Error>(self, value: &str) -> Result<u64, E> {
+                parse(value).map_err(E::custom)
+            }
+        }
+
+        deserializer.deserialize_any(Visitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Settings {
+        #[serde(with = "secs")]
+        timeout: Duration,
+        #[serde(with = "millis")]
+        interval: Duration,
+        #[serde(with = "size")]
+        limit: u64,
+    }
+
+    #[test]
+    fn test_human_and_bare_values() {
+        let settings: Settings =
+            toml::from_str("timeout = \"1m 30s\"\ninterval = 500\nlimit = \"1MiB\"").unwrap();
+        assert_eq!(settings.timeout, Duration::from_secs(90));
+        assert_eq!(settings.interval, Duration::from_millis(500));
+        assert_eq!(settings.limit, 1 << 20);
+
+        let bare: Settings =
+            toml::from_str("timeout = 10\ninterval = \"2s\"\nlimit = 100").unwrap();
+        assert_eq!(bare.timeout, Duration::from_secs(10));
+        assert_eq!(bare.interval, Duration::from_secs(2));
+
+        let written = toml::to_string(&settings).unwrap();
+        assert!(written.contains("timeout = \"1m 30s\""));
+        assert!(written.contains("limit = \"1MiB\""));
+        assert_eq!(toml::from_str::<Settings>(&written).unwrap(), settings);
+    }
+
+    #[test]
+    fn test_errors_name_the_field() {
+        let error = toml::from_str::<Settings>("timeout = \"soon\"\ninterval = 1\nlimit = 1")
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("timeout"), "{}", error);
+        assert!(error.contains("\"soon\" is not a duration"), "{}", error);
+        assert_eq!(size::format(1536), "1536B");
+        assert_eq!(size::format(3 << 20), "3MiB");
+    }
+}
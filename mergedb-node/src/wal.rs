@@ -0,0 +1,545 @@
+//write-ahead log of every change since the last snapshot, so a crash between snapshots doesn't
+//lose them. commit() queues the key, a single writer appends the key's current state in the
+//snapshot's record format and syncs it according to wal_sync:
+//  always: a write is answered once it is on disk. writes that arrive while a sync is running
+//          go out together with the next one, so concurrent writers share one fsync
+//  <duration>, eg "10ms": a write is answered right away and the log is synced that often
+//  os: the log is never synced, the os writes it back when it likes
+//each snapshot rotates the log first and drops the rotated part once the snapshot is down,
+//startup merges whatever is left on top of the snapshot
+use anyhow::{Context, Result};
+use dashmap::DashMap;
+use mergedb_types::CrdtValue;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashSet,
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tokio::sync::{watch, Notify};
+
+use crate::{
+    network::StoredValue,
+    persistence::{self, encode_record},
+    recovery::{Recovery, Stage},
+};
+
+pub const WAL_FILE: &str = "store.wal";
+//what the log was rotated to while a snapshot is being written
+const ROTATED_FILE: &str = "store.wal.old";
+const MAGIC: &[u8; 8] = b"MDBWAL01";
+//how soon a round whose write or sync failed is tried again
+const FAILED_RETRY: Duration = Duration::from_millis(100);
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(try_from = "String", into = "String")]
+pub enum WalSync {
+    #[default]
+    Always,
+    Interval(Duration),
+    Os,
+}
+
+impl FromStr for WalSync {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim() {
+            "always" => Ok(WalSync::Always),
+            "os" => Ok(WalSync::Os),
+            interval => match humantime::parse_duration(interval) {
+                Ok(every) if !every.is_zero() => Ok(WalSync::Interval(every)),
+                _ => Err(format!(
+                    "wal_sync is \"always\", \"os\" or how often to sync eg \"10ms\", not {:?}",
+                    value
+                )),
+            },
+        }
+    }
+}
+
+impl fmt::Display for WalSync {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WalSync::Always => write!(f, "always"),
+            WalSync::Interval(every) => write!(f, "{}", humantime::format_duration(*every)),
+            WalSync::Os => write!(f, "os"),
+        }
+    }
+}
+
+impl TryFrom<String> for WalSync {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<WalSync> for String {
+    fn from(sync: WalSync) -> Self {
+        sync.to_string()
+    }
+}
+
+//keys changed since the writer last ran, and the highest commit seq among them
+#[derive(Debug, Default)]
+struct Pending {
+    keys: HashSet<String>,
+    seq: u64,
+}
+
+//the open log and how long it is up to its last complete record. a failed append can leave
+//part of a record behind, the next append cuts it off first so no record follows a torn one
+#[derive(Debug)]
+struct Log {
+    file: File,
+    len: u64,
+}
+
+impl Log {
+    fn open(path: &Path) -> Result<Self> {
+        let file = open_log(path)?;
+        let len = file.metadata()?.len();
+        Ok(Log { file, len })
+    }
+
+    fn append(&mut self, buf: &[u8], sync: bool) -> std::io::Result<()> {
+        if self.file.metadata()?.len() != self.len {
+            self.file.set_len(self.len)?;
+        }
+        self.file.write_all(buf)?;
+        if sync {
+            self.file.sync_data()?;
+        }
+        self.len += buf.len() as u64;
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct Wal {
+    dir: Option<PathBuf>,
+    sync: WalSync,
+    file: Arc<Mutex<Option<Log>>>,
+    pending: Mutex<Pending>,
+    wake: Notify,
+    //every commit up to this seq is in the log, and synced when wal_sync is always
+    durable: watch::Sender<u64>,
+}
+
+impl Wal {
+    //without a data_dir nothing is logged and writes never wait
+    pub fn open(dir: Option<PathBuf>, sync: WalSync) -> Result<Self> {
+        let file = match &dir {
+            Some(dir) => Some(Log::open(&dir.join(WAL_FILE))?),
+            None => None,
+        };
+        Ok(Wal {
+            dir,
+            sync,
+            file: Arc::new(Mutex::new(file)),
+            pending: Mutex::new(Pending::default()),
+            wake: Notify::new(),
+            durable: watch::channel(0).0,
+        })
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.dir.is_some()
+    }
+
+    //every record left from before the node stopped, oldest first. a torn record at the end
+    //of the log, from a crash mid-append, is cut off
+    pub fn replay(dir: &Path) -> Result<Vec<(String, CrdtValue)>> {
+        let mut records = Vec::new();
+        Wal::replay_into(dir, &Recovery::default(), |key, value| {
+            records.push((key, value))
+        })?;
+        Ok(records)
+    }
+
+    //replay() handing each record to `apply` as it is read, with the progress in `recovery`
+    pub fn replay_into(
+        dir: &Path,
+        recovery: &Recovery,
+        mut apply: impl FnMut(String, CrdtValue),
+    ) -> Result<()> {
+        let paths = logs(dir);
+        let total: u64 = paths
+            .iter()
+            .filter_map(|path| fs::metadata(path).ok())
+            .map(|metadata| metadata.len())
+            .sum();
+        recovery.start(Stage::Wal, total);
+        for path in paths {
+            let log = match fs::read(&path) {
+                Ok(log) => log,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => {
+                    return Err(e).with_context(|| format!("failed to read {}", path.display()))
+                }
+            };
+            if !log.starts_with(MAGIC) {
+                anyhow::bail!("{} is not a mergeDB wal", path.display());
+            }
+            recovery.advance(MAGIC.len() as u64, 0);
+            let valid_len = persistence::scan_log(&log, MAGIC.len(), |key, value, len| {
+                apply(key, value);
+                recovery.advance(len as u64, 1);
+            });
+            if valid_len < log.len() {
+                eprintln!(
+                    "warning: {} has {} torn bytes at its end, dropping them",
+                    path.display(),
+                    log.len() - valid_len
+                );
+                OpenOptions::new()
+                    .write(true)
+                    .open(&path)?
+                    .set_len(valid_len as u64)?;
+                recovery.advance((log.len() - valid_len) as u64, 0);
+            }
+        }
+        recovery.finish();
+        Ok(())
+    }
+
+    //called with every commit, takes the commit's seq out of `seqs` and has the writer pick
+    //the key up. both happen under the pending lock, so a batch the writer takes holds every
+    //commit up to its seq and durable never passes a commit that isn't in the log yet
+    pub fn record(&self, key: &str, seqs: &AtomicU64) -> u64 {
+        if !self.enabled() {
+            return seqs.fetch_add(1, Ordering::SeqCst) + 1;
+        }
+        let mut pending = self.pending.lock().unwrap();
+        let seq = seqs.fetch_add(1, Ordering::SeqCst) + 1;
+        pending.keys.insert(key.to_string());
+        pending.seq = seq;
+        drop(pending);
+        self.wake.notify_one();
+        seq
+    }
+
+    //resolves once the commit is as durable as wal_sync promises
+    pub async fn wait_durable(&self, seq: u64) {
+        if !self.enabled() || self.sync != WalSync::Always || seq == 0 {
+            return;
+        }
+        let mut durable = self.durable.subscribe();
+        let _ = durable.wait_for(|durable| *durable >= seq).await;
+    }
+
+    //the single writer, runs for as long as the node does
+    pub async fn run(&self, store: Arc<DashMap<String, StoredValue>>) {
+        if !self.enabled() {
+            return;
+        }
+        let mut last_sync = Instant::now();
+        let mut unsynced = false;
+        let mut retry = None;
+        loop {
+            match (retry.take(), self.sync) {
+                (Some(after), _) => tokio::time::sleep(after).await,
+                (None, WalSync::Interval(every)) => {
+                    let _ = tokio::time::timeout(every, self.wake.notified()).await;
+                }
+                (None, _) => self.wake.notified().await,
+            }
+            let Pending { keys, seq } = std::mem::take(&mut *self.pending.lock().unwrap());
+
+            let mut buf = Vec::new();
+            let mut encoded = Vec::new();
+            for key in keys {
+                //read after the commit, so this is at least as new as what was committed.
+                //writers let go of the entry before they commit, it is never held for long
+                let Some(stored) = store.get(&key).map(|stored| stored.data.clone()) else {
+                    continue;
+                };
+                match encode_record(&key, &stored, &mut buf) {
+                    Ok(()) => encoded.push(key),
+                    Err(e) => eprintln!("failed to encode {} for the wal: {:#}", key, e),
+                }
+            }
+            unsynced |= !buf.is_empty();
+            let sync = match self.sync {
+                WalSync::Always => unsynced,
+                WalSync::Interval(every) => unsynced && last_sync.elapsed() >= every,
+                WalSync::Os => false,
+            };
+
+            let file = self.file.clone();
+            let written = tokio::task::spawn_blocking(move || -> Result<()> {
+                let mut log = file.lock().unwrap();
+                match log.as_mut() {
+                    Some(log) => Ok(log.append(&buf, sync)?),
+                    None => Ok(()),
+                }
+            })
+            .await;
+            let failed = match written {
+                Ok(Ok(())) => None,
+                Ok(Err(e)) => Some(format!("wal write failed: {:#}", e)),
+                Err(e) => Some(format!("wal task failed: {}", e)),
+            };
+            if let Some(failed) = failed {
+                //nothing this round wrote counts, a torn record is cut off before the next
+                //append and the keys go out again with it. under always their writes stay
+                //unanswered until then, rather than acknowledged but lost
+                eprintln!("{}", failed);
+                self.requeue(encoded, seq);
+                retry = Some(FAILED_RETRY);
+                continue;
+            }
+            if sync {
+                last_sync = Instant::now();
+                unsynced = false;
+            }
+            if seq > 0 {
+                self.durable.send_if_modified(|durable| {
+                    let newer = seq > *durable;
+                    *durable = (*durable).max(seq);
+                    newer
+                });
+            }
+        }
+    }
+
+    //keys the writer has to try again, with the seq they were committed under
+    fn requeue(&self, keys: Vec<String>, seq: u64) {
+        let mut pending = self.pending.lock().unwrap();
+        pending.keys.extend(keys);
+        pending.seq = pending.seq.max(seq);
+    }
+
+    //starts a fresh log before a snapshot, so the rotated one only holds what the snapshot is
+    //about to cover. false when an earlier rotated log is still waiting for its snapshot
+    pub fn rotate(&self) -> Result<bool> {
+        let Some(dir) = &self.dir else {
+            return Ok(false);
+        };
+        let rotated = dir.join(ROTATED_FILE);
+        if rotated.exists() {
+            return Ok(false);
+        }
+        let mut log = self.file.lock().unwrap();
+        if let Some(log) = log.as_mut() {
+            //a torn record left by a failed append goes, the records before it stay
+            log.file.set_len(log.len)?;
+            log.file.sync_data()?;
+        }
+        fs::rename(dir.join(WAL_FILE), &rotated)?;
+        *log = Some(Log::open(&dir.join(WAL_FILE))?);
+        Ok(true)
+    }
+
+    //the snapshot is down, the rotated log isn't needed anymore
+    pub fn drop_rotated(&self) -> Result<()> {
+        if let Some(dir) = &self.dir {
+            match fs::remove_file(dir.join(ROTATED_FILE)) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+//the wal files in `dir`, oldest first
+pub fn logs(dir: &Path) -> [PathBuf; 2] {
+    [ROTATED_FILE, WAL_FILE].map(|name| dir.join(name))
+}
+
+//how many whole records a wal holds and where the last of them ends
+pub fn scan(log: &[u8]) -> Result<(usize, usize)> {
+    if !log.starts_with(MAGIC) {
+        anyhow::bail!("not a mergeDB wal");
+    }
+    let mut records = 0;
+    let valid_len = persistence::scan_log(log, MAGIC.len(), |_, _, _| records += 1);
+    Ok((records, valid_len))
+}
+
+fn open_log(path: &Path) -> Result<File> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("failed to open {}", path.display()))?;
+    if file.metadata()?.len() == 0 {
+        file.write_all(MAGIC)?;
+        file.sync_data()?;
+    }
+    Ok(file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mergedb_types::pn_counter::PNCounter;
+    use std::time::SystemTime;
+
+    fn counter(n: u64) -> StoredValue {
+        StoredValue {
+            data: CrdtValue::Counter(PNCounter::new("node_1".to_string(), n, 0)),
+            last_updated: SystemTime::now(),
+        }
+    }
+
+    #[test]
+    fn test_wal_sync_values() {
+        assert_eq!("always".parse(), Ok(WalSync::Always));
+        assert_eq!("os".parse(), Ok(WalSync::Os));
+        assert_eq!(
+            "10ms".parse(),
+            Ok(WalSync::Interval(Duration::from_millis(10)))
+        );
+        assert!("0ms".parse::<WalSync>().is_err());
+        assert!("sometimes".parse::<WalSync>().is_err());
+        assert_eq!(
+            WalSync::Interval(Duration::from_millis(10)).to_string(),
+            "10ms"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_writes_survive_until_the_snapshot_covers_them() {
+        let dir = std::env::temp_dir().join(format!("mergedb-wal-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let store = Arc::new(DashMap::new());
+        let wal = Arc::new(Wal::open(Some(dir.clone()), WalSync::Always).unwrap());
+        let writer = {
+            let (wal, store) = (wal.clone(), store.clone());
+            tokio::spawn(async move { wal.run(store).await })
+        };
+        let seqs = AtomicU64::new(0);
+
+        store.insert("views".to_string(), counter(1));
+        wal.record("views", &seqs);
+        store.insert("likes".to_string(), counter(2));
+        wal.record("likes", &seqs);
+        wal.wait_durable(2).await;
+        assert!(wal.rotate().unwrap());
+        store.insert("views".to_string(), counter(3));
+        wal.record("views", &seqs);
+        wal.wait_durable(3).await;
+        writer.abort();
+
+        //a crash mid-append leaves a torn record behind
+        let mut log = OpenOptions::new()
+            .append(true)
+            .open(dir.join(WAL_FILE))
+            .unwrap();
+        log.write_all(&[9, b'h', b'a']).unwrap();
+
+        let replayed = Wal::replay(&dir).unwrap();
+        let keys: Vec<&str> = replayed.iter().map(|(key, _)| key.as_str()).collect();
+        assert_eq!(keys.len(), 3);
+        assert_eq!(replayed.last().unwrap().1, counter(3).data);
+        assert_eq!(Wal::replay(&dir).unwrap().len(), 3);
+
+        wal.drop_rotated().unwrap();
+        assert_eq!(Wal::replay(&dir).unwrap().len(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    //commits racing each other: when one is acknowledged, its key is in the log already,
+    //whatever later commits got written first
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_commits_are_acknowledged_once_logged() {
+        let dir = std::env::temp_dir().join(format!("mergedb-wal-race-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let store = Arc::new(DashMap::new());
+        let wal = Arc::new(Wal::open(Some(dir.clone()), WalSync::Always).unwrap());
+        let writer = {
+            let (wal, store) = (wal.clone(), store.clone());
+            tokio::spawn(async move { wal.run(store).await })
+        };
+        let seqs = Arc::new(AtomicU64::new(0));
+
+        let mut committing = tokio::task::JoinSet::new();
+        for task in 0..8 {
+            let (wal, store, seqs, dir) = (wal.clone(), store.clone(), seqs.clone(), dir.clone());
+            committing.spawn(async move {
+                for i in 0..50 {
+                    let key = format!("k{}-{}", task, i);
+                    store.insert(key.clone(), counter(i));
+                    let seq = wal.record(&key, &seqs);
+                    wal.wait_durable(seq).await;
+                    let log = fs::read(dir.join(WAL_FILE)).unwrap();
+                    let mut logged = false;
+                    persistence::scan_log(&log, MAGIC.len(), |logged_key, _, _| {
+                        logged |= logged_key == key
+                    });
+                    assert!(logged, "{} was acknowledged before it was logged", key);
+                }
+            });
+        }
+        while let Some(committed) = committing.join_next().await {
+            committed.unwrap();
+        }
+        writer.abort();
+        assert_eq!(Wal::replay(&dir).unwrap().len(), 400);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_failed_writes_are_retried_and_never_acknowledged() {
+        let dir = std::env::temp_dir().join(format!("mergedb-wal-fail-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let path = dir.join(WAL_FILE);
+        let store = Arc::new(DashMap::new());
+        let wal = Arc::new(Wal::open(Some(dir.clone()), WalSync::Always).unwrap());
+        let writer = {
+            let (wal, store) = (wal.clone(), store.clone());
+            tokio::spawn(async move { wal.run(store).await })
+        };
+        let seqs = AtomicU64::new(0);
+        store.insert("views".to_string(), counter(1));
+        wal.record("views", &seqs);
+        wal.wait_durable(1).await;
+
+        //the disk starts refusing writes, after one of them got part of a record out
+        let writable = {
+            let mut log = wal.file.lock().unwrap();
+            let log = log.as_mut().unwrap();
+            std::mem::replace(&mut log.file, File::open(&path).unwrap())
+        };
+        OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(&[9, b'v', b'i'])
+            .unwrap();
+        store.insert("views".to_string(), counter(2));
+        wal.record("views", &seqs);
+        let waited = tokio::time::timeout(Duration::from_millis(300), wal.wait_durable(2)).await;
+        assert!(
+            waited.is_err(),
+            "a write that never reached the log was acknowledged"
+        );
+
+        wal.file.lock().unwrap().as_mut().unwrap().file = writable;
+        tokio::time::timeout(Duration::from_secs(5), wal.wait_durable(2))
+            .await
+            .unwrap();
+        writer.abort();
+
+        //the torn bytes were cut off before the retry, so its record isn't lost behind them
+        let replayed = Wal::replay(&dir).unwrap();
+        assert_eq!(replayed.len(), 2);
+        assert_eq!(replayed.last().unwrap().1, counter(2).data);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,169 @@
+//[watchdog]: a check for replication that got stuck on this node. a key is taken by a peer once
+//the key's priority class went out to the peer in full after the key last changed, the same
+//bookkeeping the gossip loop goes by to pick what is dirty (see outbound). a key no peer has
+//taken for longer than stalled_after is stalled, the gossip loop keeps leaving it out or keeps
+//failing to send it, however healthy the peers look otherwise. stalled keys are counted in the
+//watchdog_stalled_keys gauge, warned about once per stall and listed by AUDIT
+use dashmap::DashMap;
+use serde_json::json;
+use std::{
+    collections::HashSet,
+    time::{Duration, SystemTime},
+};
+
+use crate::{
+    network::{ReplicationServer, StoredValue},
+    outbound::Outbound,
+    priority::Schedule,
+};
+
+//the most stalled keys AUDIT lists, the gauge counts every one
+pub const MAX_LISTED: usize = 100;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stalled {
+    pub key: String,
+    //since it last changed, no peer has taken it for that long
+    pub behind: Duration,
+}
+
+impl Stalled {
+    pub fn to_json(&self) -> serde_json::Value {
+        json!({"key": self.key, "behind_secs": self.behind.as_secs()})
+    }
+}
+
+//keys every peer is still owed that changed at least stalled_after before now, the longest
+//stalled first. nothing is stalled without peers to take it
+pub fn stalled_keys(
+    store: &DashMap<String, StoredValue>,
+    outbound: &Outbound,
+    peer_addrs: &[String],
+    schedule: &Schedule,
+    now: SystemTime,
+    stalled_after: Duration,
+) -> Vec<Stalled> {
+    if peer_addrs.is_empty() {
+        return Vec::new();
+    }
+    let mut stalled: Vec<Stalled> = store
+        .iter()
+        .filter_map(|entry| {
+            let changed = entry.value().last_updated;
+            let behind = now.duration_since(changed).ok()?;
+            let class = schedule.class_of(entry.key());
+            let untaken = peer_addrs
+                .iter()
+                .all(|peer_addr| changed >= outbound.dirty_since(peer_addr, class));
+            (untaken && behind >= stalled_after).then(|| Stalled {
+                key: entry.key().clone(),
+                behind,
+            })
+        })
+        .collect();
+    stalled.sort_by(|a, b| b.behind.cmp(&a.behind).then_with(|| a.key.cmp(&b.key)));
+    stalled
+}
+
+//the stalled keys right now, the gauge is brought up to date on the way. witnesses never get
+//data, they don't count as peers that could take it
+pub fn check(server: &ReplicationServer, stalled_after: Duration) -> Vec<Stalled> {
+    let peer_addrs: Vec<String> = server
+        .peers
+        .iter()
+        .map(|entry| entry.key().clone())
+        .filter(|peer_addr| !server.membership.is_witness_at(peer_addr))
+        .collect();
+    let schedule = Schedule::new(
+        server.settings().gossip_interval,
+        &server.config.gossip_priority,
+    );
+    let stalled = stalled_keys(
+        &server.store,
+        &server.outbound,
+        &peer_addrs,
+        &schedule,
+        SystemTime::now(),
+        stalled_after,
+    );
+    server
+        .metrics
+        .set_gauge("watchdog_stalled_keys", stalled.len() as i64);
+    stalled
+}
+
+//a check every interval for as long as the node runs
+pub async fn run(server: &ReplicationServer) {
+    let Some(config) = server.config.watchdog.clone() else {
+        return;
+    };
+    let mut interval = tokio::time::interval(config.interval);
+    //keys already warned about, a key is warned about again once it was taken and stalls anew
+    let mut warned: HashSet<String> = HashSet::new();
+    loop {
+        interval.tick().await;
+        let stalled = check(server, config.stalled_after);
+        for stall in stalled.iter().filter(|stall| !warned.contains(&stall.key)) {
+            eprintln!(
+                "warning: no peer has taken {} for {}s, its replication is stuck",
+                stall.key,
+                stall.behind.as_secs()
+            );
+            server.metrics.incr("watchdog_stalls_total", 1);
+        }
+        warned = stalled.into_iter().map(|stall| stall.key).collect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mergedb_types::{pn_counter::PNCounter, CrdtValue};
+
+    fn stored(last_updated: SystemTime) -> StoredValue {
+        StoredValue {
+            data: CrdtValue::Counter(PNCounter::new("node_1".to_string(), 1, 0)),
+            last_updated,
+        }
+    }
+
+    #[test]
+    fn test_keys_no_peer_took_are_stalled() {
+        let now = SystemTime::now();
+        let minutes = |n: u64| now - Duration::from_secs(60 * n);
+        let schedule = Schedule::new(Duration::from_secs(2), &[]);
+        let peers = ["10.0.0.2:8000".to_string(), "10.0.0.3:8000".to_string()];
+        let outbound = Outbound::default();
+        outbound.mark_sent(&peers[0], 0, minutes(5));
+        outbound.mark_sent(&peers[1], 0, minutes(3));
+
+        let store = DashMap::new();
+        //taken by both, then by one of them
+        store.insert("synced".to_string(), stored(minutes(10)));
+        store.insert("half".to_string(), stored(minutes(4)));
+        //changed after the last round to either, a while ago and just now
+        store.insert("stuck".to_string(), stored(minutes(2)));
+        store.insert(
+            "older".to_string(),
+            stored(minutes(2) - Duration::from_secs(30)),
+        );
+        store.insert("fresh".to_string(), stored(now));
+
+        let stalled = stalled_keys(
+            &store,
+            &outbound,
+            &peers,
+            &schedule,
+            now,
+            Duration::from_secs(60),
+        );
+        let keys: Vec<&str> = stalled.iter().map(|stall| stall.key.as_str()).collect();
+        assert_eq!(keys, ["older", "stuck"]);
+        assert_eq!(stalled[1].behind, Duration::from_secs(120));
+        assert_eq!(stalled[1].to_json()["behind_secs"], 120);
+
+        //a standalone node has nobody to fall behind
+        let alone = stalled_keys(&store, &outbound, &[], &schedule, now, Duration::ZERO);
+        assert!(alone.is_empty());
+    }
+}
@@ -0,0 +1,41 @@
+use anyhow::{bail, Context, Result};
+use hyper::{Body, Client, Method, Request, Uri};
+use std::time::Duration;
+
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(5);
+
+//fire a json payload at an operator supplied url, only plain http is supported for now
+pub async fn post_json(url: &str, payload: &serde_json::Value) -> Result<()> {
+    let uri: Uri = url
+        .parse()
+        .with_context(|| format!("invalid webhook url {}", url))?;
+    if uri.scheme_str() != Some("http") {
+        bail!("webhook url {} must be http://", url);
+    }
+
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri(uri)
+        .header("content-type", "application/json")
+        .body(Body::from(serde_json::to_vec(payload)?))?;
+
+    let response = tokio::time::timeout(WEBHOOK_TIMEOUT, Client::new().request(request))
+        .await
+        .with_context(|| format!("webhook {} timed out", url))??;
+
+    if !response.status().is_success() {
+        bail!("webhook {} answered with {}", url, response.status());
+    }
+    Ok(())
+}
+
+//webhooks are best effort, failing to deliver one should never hold up the caller
+pub fn notify(url: Option<String>, payload: serde_json::Value) {
+    if let Some(url) = url {
+        tokio::spawn(async move {
+            if let Err(e) = post_json(&url, &payload).await {
+                eprintln!("failed to deliver webhook: {e}");
+            }
+        });
+    }
+}
@@ -0,0 +1,596 @@
+//a websocket endpoint at ws://<websocket_address>/ws for browsers, which can't speak plain
+//grpc. every text frame is one json call that goes through the same dispatcher as PropagateData:
+//  -> {"id": 1, "command": "CINC", "key": "views", "value": 5}
+//  <- {"id": 1, "ok": true, "seq": 12, "value": 17}
+//  <- {"id": 1, "ok": false, "code": "NotFound", "error": "..."}
+//counter amounts are json integers, set tags are strings, numbers, bools or {"bytes": base64},
+//everything else is a string. replies carry the value decoded the way the client does it, a
+//number, a string or json. a write's value is what it left: a counter's total, a set's size or
+//a register's length, null for the other writes. writes of a value also say which peers they
+//reached, see Replicas in the proto:
+//  <- {"id": 1, "ok": true, "seq": 12, "value": 17, "replicas": {"reached": 2, "failed": []}}
+//WATCH subscribes to a prefix, every key under it is sent once and then again whenever it
+//changes, until UNWATCH names the id the subscription was made with:
+//  -> {"id": 2, "command": "WATCH", "key": "user:"}
+//  <- {"watch": 2, "key": "user:42", "type": "counter", "value": 7}
+//  -> {"id": 3, "command": "UNWATCH", "value": 2}
+use anyhow::{bail, Context, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use hyper::{
+    header,
+    service::{make_service_fn, service_fn},
+    upgrade::Upgraded,
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use mergedb_proto::wire;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::{collections::HashMap, convert::Infallible, net::SocketAddr, sync::Arc, time::Duration};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    sync::mpsc,
+};
+
+use crate::{
+    communication::{replication_service_server::ReplicationService, PropagateDataRequest},
+    materialize,
+    network::{is_internal, ReplicationServer},
+};
+
+pub const PATH: &str = "/ws";
+//appended to the client's key before hashing, fixed by rfc 6455
+const HANDSHAKE_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+//a call bigger than this closes the connection
+const MAX_MESSAGE: usize = 4 << 20;
+//how often WATCH subscriptions look for changes
+pub const WATCH_EVERY: Duration = Duration::from_millis(250);
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xA;
+
+#[derive(Debug, PartialEq)]
+enum Message {
+    Text(String),
+    Ping(Vec<u8>),
+    Close,
+}
+
+#[derive(Deserialize, Debug)]
+struct Call {
+    #[serde(default)]
+    id: Value,
+    command: String,
+    #[serde(default)]
+    key: String,
+    #[serde(default)]
+    value: Value,
+    #[serde(default)]
+    dry_run: bool,
+}
+
+//a prefix and what each key under it was last sent as
+#[derive(Debug)]
+struct Watch {
+    id: Value,
+    prefix: String,
+    sent: HashMap<String, Value>,
+}
+
+pub async fn serve(address: &str, server: Arc<ReplicationServer>) -> Result<()> {
+    let address: SocketAddr = address
+        .parse()
+        .with_context(|| format!("invalid websocket_address {}", address))?;
+    let make_service = make_service_fn(move |_| {
+        let server = server.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let response = respond(server.clone(), request);
+                async move { Ok::<_, Infallible>(response) }
+            }))
+        }
+    });
+    println!("serving websocket clients on ws://{}{}", address, PATH);
+    Server::try_bind(&address)
+        .with_context(|| format!("can't listen on websocket_address {}", address))?
+        .serve(make_service)
+        .await?;
+    Ok(())
+}
+
+fn respond(server: Arc<ReplicationServer>, mut request: Request<Body>) -> Response<Body> {
+    if request.uri().path() != PATH {
+        return plain(
+            StatusCode::NOT_FOUND,
+            format!("websocket clients connect to {}\n", PATH),
+        );
+    }
+    let Some(key) = handshake_key(&request) else {
+        return plain(
+            StatusCode::BAD_REQUEST,
+            "expected a websocket upgrade\n".to_string(),
+        );
+    };
+
+    let upgrade = hyper::upgrade::on(&mut request);
+    tokio::spawn(async move {
+        match upgrade.await {
+            Ok(io) => session(server, io).await,
+            Err(e) => eprintln!("websocket upgrade failed: {}", e),
+        }
+    });
+    Response::builder()
+        .status(StatusCode::SWITCHING_PROTOCOLS)
+        .header(header::UPGRADE, "websocket")
+        .header(header::CONNECTION, "Upgrade")
+        .header(header::SEC_WEBSOCKET_ACCEPT, accept_key(&key))
+        .body(Body::empty())
+        .unwrap()
+}
+
+fn plain(status: StatusCode, text: String) -> Response<Body> {
+    let mut response = Response::new(Body::from(text));
+    *response.status_mut() = status;
+    response
+}
+
+//the client's Sec-WebSocket-Key, when the request is a websocket upgrade we can take
+fn handshake_key(request: &Request<Body>) -> Option<String> {
+    let headers = request.headers();
+    let has = |name: header::HeaderName, value: &str| {
+        headers
+            .get(name)
+            .and_then(|header| header.to_str().ok())
+            .is_some_and(|header| {
+                header
+                    .split(',')
+                    .any(|token| token.trim().eq_ignore_ascii_case(value))
+            })
+    };
+    if request.method() != Method::GET
+        || !has(header::UPGRADE, "websocket")
+        || !has(header::SEC_WEBSOCKET_VERSION, "13")
+    {
+        return None;
+    }
+    headers
+        .get(header::SEC_WEBSOCKET_KEY)
+        .and_then(|key| key.to_str().ok())
+        .map(|key| key.trim().to_string())
+}
+
+fn accept_key(key: &str) -> String {
+    STANDARD.encode(sha1(format!("{}{}", key, HANDSHAKE_GUID).as_bytes()))
+}
+
+//only the handshake needs it, so it is kept here rather than pulled in as a dependency
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+    let mut padded = data.to_vec();
+    padded.push(0x80);
+    while padded.len() % 64 != 56 {
+        padded.push(0);
+    }
+    padded.extend_from_slice(&(data.len() as u64 * 8).to_be_bytes());
+
+    for block in padded.chunks_exact(64) {
+        let mut w = [0u32; 80];
+        for (word, bytes) in w.iter_mut().zip(block.chunks_exact(4)) {
+            *word = u32::from_be_bytes(bytes.try_into().unwrap());
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e] = h;
+        for (i, word) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5A827999),
+                20..=39 => (b ^ c ^ d, 0x6ED9EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+                _ => (b ^ c ^ d, 0xCA62C1D6),
+            };
+            let t = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(*word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = t;
+        }
+        for (h, v) in h.iter_mut().zip([a, b, c, d, e]) {
+            *h = h.wrapping_add(v);
+        }
+    }
+
+    let mut digest = [0u8; 20];
+    for (bytes, h) in digest.chunks_exact_mut(4).zip(h) {
+        bytes.copy_from_slice(&h.to_be_bytes());
+    }
+    digest
+}
+
+async fn session(server: Arc<ReplicationServer>, io: Upgraded) {
+    let (mut reader, mut writer) = tokio::io::split(io);
+    //frames are read on their own task, a read cut off halfway by the watch tick would lose
+    //the bytes it already had
+    let (incoming_tx, mut incoming) = mpsc::channel(16);
+    let reading = tokio::spawn(async move {
+        let mut partial = None;
+        loop {
+            let message = read_message(&mut reader, &mut partial).await;
+            let last = !matches!(message, Ok(Message::Text(_)) | Ok(Message::Ping(_)));
+            if incoming_tx.send(message).await.is_err() || last {
+                break;
+            }
+        }
+    });
+
+    let mut watches: Vec<Watch> = Vec::new();
+    let mut tick = tokio::time::interval(WATCH_EVERY);
+    loop {
+        let written = tokio::select! {
+            message = incoming.recv() => match message {
+                Some(Ok(Message::Text(text))) => {
+                    let reply = call(&server, &text, &mut watches).await;
+                    write_frame(&mut writer, OP_TEXT, reply.to_string().as_bytes()).await
+                }
+                Some(Ok(Message::Ping(payload))) => {
+                    write_frame(&mut writer, OP_PONG, &payload).await
+                }
+                Some(Ok(Message::Close)) | None => {
+                    let _ = write_frame(&mut writer, OP_CLOSE, &[]).await;
+                    break;
+                }
+                Some(Err(e)) => {
+                    println!("closing websocket client: {:#}", e);
+                    let _ = write_frame(&mut writer, OP_CLOSE, &1002u16.to_be_bytes()).await;
+                    break;
+                }
+            },
+            _ = tick.tick() => {
+                let mut written = Ok(());
+                for change in watched_changes(&server, &mut watches) {
+                    written = write_frame(&mut writer, OP_TEXT, change.to_string().as_bytes()).await;
+                    if written.is_err() {
+                        break;
+                    }
+                }
+                written
+            }
+        };
+        if written.is_err() {
+            break;
+        }
+    }
+    reading.abort();
+}
+
+//one call, answered with the reply frame
+async fn call(server: &ReplicationServer, text: &str, watches: &mut Vec<Watch>) -> Value {
+    let call: Call = match serde_json::from_str(text) {
+        Ok(call) => call,
+        Err(e) => return refused(&Value::Null, "InvalidArgument", &e.to_string()),
+    };
+    let command = call.command.to_ascii_uppercase();
+    match command.as_str() {
+        "WATCH" => {
+            if call.id.is_null() || watches.iter().any(|watch| watch.id == call.id) {
+                return refused(&call.id, "InvalidArgument", "WATCH needs an id of its own");
+            }
+            watches.push(Watch {
+                id: call.id.clone(),
+                prefix: call.key,
+                sent: HashMap::new(),
+            });
+            json!({"id": call.id, "ok": true})
+        }
+        "UNWATCH" => {
+            let before = watches.len();
+            watches.retain(|watch| watch.id != call.value);
+            match watches.len() < before {
+                true => json!({"id": call.id, "ok": true}),
+                false => refused(&call.id, "NotFound", "no WATCH was made with that id"),
+            }
+        }
+        _ => {
+            let (value, element_type) = match encode_value(&command, &call.value) {
+                Ok(value) => value,
+                Err(e) => return refused(&call.id, "InvalidArgument", &e),
+            };
+            let amount = match command.as_str() {
+                "CSET" | "CINC" | "CDEC" => call.value.as_i64(),
+                _ => None,
+            };
+            let request = tonic::Request::new(PropagateDataRequest {
+                valuetype: command.clone(),
+                key: call.key,
+                value,
+                dry_run: call.dry_run,
+                encoding: String::new(),
+                element_type: element_type.to_string(),
+                amount,
+            });
+            match server.propagate_data(request).await {
+                Ok(response) => {
+                    let response = response.into_inner();
+                    if !response.success {
+                        return refused(
+                            &call.id,
+                            "FailedPrecondition",
+                            &format!("{} was refused, wrong type for this key?", command),
+                        );
+                    }
+                    let mut reply = json!({
+                        "id": call.id,
+                        "ok": true,
+                        "seq": response.seq,
+                        "value": decode_response(&command, response.response, response.number),
+                    });
+                    if let Some(replicas) = response.replicas {
+                        reply["replicas"] = json!({
+                            "reached": replicas.reached,
+                            "failed": replicas.failed,
+                        });
+                    }
+                    reply
+                }
+                Err(status) => refused(&call.id, &format!("{:?}", status.code()), status.message()),
+            }
+        }
+    }
+}
+
+fn refused(id: &Value, code: &str, error: &str) -> Value {
+    json!({"id": id, "ok": false, "code": code, "error": error})
+}
+
+//a call's value as the command expects it on the wire, and its element type for set tags. see
+//mergedb_proto::wire
+fn encode_value(command: &str, value: &Value) -> Result<(Vec<u8>, &'static str), String> {
+    match command {
+        "CSET" | "CINC" | "CDEC" => value
+            .as_i64()
+            .map(|value| (wire::encode_i64(value), ""))
+            .ok_or_else(|| format!("{} takes an integer value", command)),
+        "SADD" | "SREM" | "SMETA" => wire::element_from_json(value)
+            .map(|element| wire::encode_element(&element))
+            .ok_or_else(|| format!("{} takes a string, number, bool or bytes tag", command)),
+        _ => Ok(match value {
+            Value::Null => (Vec::new(), ""),
+            Value::String(text) => (text.clone().into_bytes(), ""),
+            other => (other.to_string().into_bytes(), ""),
+        }),
+    }
+}
+
+fn decode_response(command: &str, raw: Vec<u8>, number: Option<i64>) -> Value {
+    if let Some(number) = number {
+        return Value::from(number);
+    }
+    if raw.is_empty() {
+        return Value::Null;
+    }
+    match command {
+        "CGET" => wire::decode_i64(&raw)
+            .map(Value::from)
+            .unwrap_or(Value::Null),
+        "DBSIZE" | "RLEN" => wire::decode_u64(&raw)
+            .map(Value::from)
+            .unwrap_or(Value::Null),
+        "RGET" | "TYPE" => Value::String(String::from_utf8_lossy(&raw).into_owned()),
+        _ => wire::decode_json(&raw)
+            .unwrap_or_else(|_| Value::String(String::from_utf8_lossy(&raw).into_owned())),
+    }
+}
+
+//a frame for every watched key that is new or changed since it was last sent
+fn watched_changes(server: &ReplicationServer, watches: &mut [Watch]) -> Vec<Value> {
+    let mut frames = Vec::new();
+    for watch in watches {
+        for (key, kind, value) in changes(server, &watch.prefix, &mut watch.sent) {
+            frames.push(json!({
+                "watch": watch.id,
+                "key": key,
+                "type": kind,
+                "value": value,
+            }));
+        }
+    }
+    frames
+}
+
+//the keys under `prefix` that are new or changed since they were last put in `sent`, with
+//their type and value. graphql subscriptions watch the same way
+pub fn changes(
+    server: &ReplicationServer,
+    prefix: &str,
+    sent: &mut HashMap<String, Value>,
+) -> Vec<(String, &'static str, Value)> {
+    let mut changes = Vec::new();
+    for entry in server.store.iter() {
+        let key = entry.key();
+        if !key.starts_with(prefix) || is_internal(key) {
+            continue;
+        }
+        let value = materialize::to_json(&entry.value().data);
+        if sent.get(key) == Some(&value) {
+            continue;
+        }
+        changes.push((key.clone(), entry.value().data.type_name(), value.clone()));
+        sent.insert(key.clone(), value);
+    }
+    changes
+}
+
+//the next text, ping or close, fragments put back together and pongs skipped. a ping can come
+//between the fragments of a text, so the part already read is kept in `partial`
+async fn read_message<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    partial: &mut Option<Vec<u8>>,
+) -> Result<Message> {
+    loop {
+        let mut head = [0u8; 2];
+        reader.read_exact(&mut head).await?;
+        let fin = head[0] & 0x80 != 0;
+        let opcode = head[0] & 0x0F;
+        let masked = head[1] & 0x80 != 0;
+        let len = match head[1] & 0x7F {
+            126 => reader.read_u16().await? as u64,
+            127 => reader.read_u64().await?,
+            len => len as u64,
+        };
+        if len as usize > MAX_MESSAGE {
+            bail!(
+                "frame of {} bytes is over the {} byte limit",
+                len,
+                MAX_MESSAGE
+            );
+        }
+        let mut mask = [0u8; 4];
+        if masked {
+            reader.read_exact(&mut mask).await?;
+        }
+        let mut payload = vec![0u8; len as usize];
+        reader.read_exact(&mut payload).await?;
+        if masked {
+            for (i, byte) in payload.iter_mut().enumerate() {
+                *byte ^= mask[i % 4];
+            }
+        }
+
+        match opcode {
+            OP_TEXT | OP_BINARY if partial.is_none() => *partial = Some(payload),
+            OP_CONTINUATION if partial.is_some() => {
+                let message = partial.as_mut().unwrap();
+                message.extend_from_slice(&payload);
+                if message.len() > MAX_MESSAGE {
+                    bail!("message is over the {} byte limit", MAX_MESSAGE);
+                }
+            }
+            OP_CLOSE => return Ok(Message::Close),
+            OP_PING => return Ok(Message::Ping(payload)),
+            OP_PONG => continue,
+            opcode => bail!("unexpected frame, opcode {:#x}", opcode),
+        }
+        if fin {
+            let text = partial.take().unwrap_or_default();
+            return Ok(Message::Text(
+                String::from_utf8(text).context("calls are utf-8 json")?,
+            ));
+        }
+    }
+}
+
+//server frames are never masked
+async fn write_frame<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    opcode: u8,
+    payload: &[u8],
+) -> Result<()> {
+    let mut frame = vec![0x80 | opcode];
+    match payload.len() {
+        len @ 0..=125 => frame.push(len as u8),
+        len @ 126..=0xFFFF => {
+            frame.push(126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    writer.write_all(&frame).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_handshake_accept_key() {
+        //the example from rfc 6455
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[tokio::test]
+    async fn test_frames_round_trip() {
+        //a masked text message split over two frames, with a ping in between
+        let mut raw = Vec::new();
+        let mask = [1, 2, 3, 4];
+        for (head, part) in [
+            (OP_TEXT, &b"{\"command\":"[..]),
+            (0x80, &b"\"DBSIZE\"}"[..]),
+        ] {
+            raw.push(head);
+            raw.push(0x80 | part.len() as u8);
+            raw.extend_from_slice(&mask);
+            raw.extend(part.iter().enumerate().map(|(i, byte)| byte ^ mask[i % 4]));
+            if head == OP_TEXT {
+                raw.extend_from_slice(&[0x80 | OP_PING, 0x80, 1, 2, 3, 4]);
+            }
+        }
+        let mut reader = &raw[..];
+        let mut partial = None;
+        assert_eq!(
+            read_message(&mut reader, &mut partial).await.unwrap(),
+            Message::Ping(Vec::new())
+        );
+        assert_eq!(
+            read_message(&mut reader, &mut partial).await.unwrap(),
+            Message::Text("{\"command\":\"DBSIZE\"}".to_string())
+        );
+
+        let mut written = Vec::new();
+        let long = vec![b'x'; 300];
+        write_frame(&mut written, OP_TEXT, &long).await.unwrap();
+        assert_eq!(&written[..4], &[0x81, 126, 1, 44]);
+        assert_eq!(&written[4..], &long[..]);
+    }
+
+    #[test]
+    fn test_values_take_the_wire_encoding() {
+        assert_eq!(
+            encode_value("CINC", &json!(5)).unwrap(),
+            (wire::encode_i64(5), "")
+        );
+        assert!(encode_value("CINC", &json!("5")).is_err());
+        assert_eq!(encode_value("HOTKEYS", &json!(3)).unwrap(), (b"3".to_vec(), ""));
+        assert_eq!(encode_value("SADD", &json!("x")).unwrap(), (b"x".to_vec(), ""));
+        assert_eq!(
+            encode_value("SADD", &json!(2.5)).unwrap(),
+            (2.5f64.to_be_bytes().to_vec(), "float")
+        );
+        assert!(encode_value("SREM", &json!(null)).is_err());
+        assert_eq!(
+            decode_response("CGET", wire::encode_i64(-2), None),
+            json!(-2)
+        );
+        assert_eq!(
+            decode_response("CGET", wire::encode_i64(-2), Some(-2)),
+            json!(-2)
+        );
+        assert_eq!(decode_response("SADD", Vec::new(), Some(3)), json!(3));
+        assert_eq!(decode_response("RSET", Vec::new(), None), json!(null));
+        assert_eq!(
+            decode_response("SGET", b"[\"x\"]".to_vec(), None),
+            json!(["x"])
+        );
+        assert_eq!(
+            decode_response("RGET", b"[not json".to_vec(), None),
+            json!("[not json")
+        );
+    }
+}
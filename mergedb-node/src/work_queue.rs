@@ -0,0 +1,312 @@
+//client commands wait here for one of max_in_flight slots, so a burst can't take every worker
+//thread the node has. a freed slot goes to the waiting command with the highest priority (the
+//mergedb-priority header), first come first served within a priority. a command whose
+//deadline (the grpc-timeout header, counted from when it arrived) passes before it gets a slot
+//is failed without being run, the client has given up on it by then
+use std::{
+    cmp::Ordering,
+    collections::BinaryHeap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use mergedb_proto::metadata::{Priority, PRIORITY_HEADER};
+use tokio::sync::oneshot;
+use tonic::Request;
+
+use crate::config::RequestQueue;
+
+#[derive(Debug)]
+pub enum Refusal {
+    Expired,
+    Full,
+}
+
+#[derive(Debug)]
+struct Waiter {
+    priority: Priority,
+    seq: u64,
+    grant: oneshot::Sender<()>,
+}
+
+//a max-heap, so the highest priority and then the lowest seq comes out first
+impl Ord for Waiter {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.priority
+            .cmp(&other.priority)
+            .then_with(|| other.seq.cmp(&self.seq))
+    }
+}
+
+impl PartialOrd for Waiter {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Waiter {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Waiter {}
+
+#[derive(Debug, Default)]
+struct State {
+    running: usize,
+    next_seq: u64,
+    waiting: BinaryHeap<Waiter>,
+}
+
+#[derive(Debug)]
+pub struct WorkQueue {
+    limits: RequestQueue,
+    state: Mutex<State>,
+}
+
+//a running command's slot, handed on to the next waiter when dropped
+#[derive(Debug)]
+pub struct Slot {
+    queue: Arc<WorkQueue>,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.queue.release();
+    }
+}
+
+impl WorkQueue {
+    pub fn new(limits: RequestQueue) -> Self {
+        WorkQueue {
+            limits,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    pub async fn acquire(
+        self: &Arc<Self>,
+        priority: Priority,
+        deadline: Option<Instant>,
+    ) -> Result<Slot, Refusal> {
+        if deadline.is_some_and(|deadline| deadline <= Instant::now()) {
+            return Err(Refusal::Expired);
+        }
+        let mut granted = {
+            let mut state = self.state.lock().unwrap();
+            if state.running < self.limits.max_in_flight {
+                state.running += 1;
+                return Ok(Slot {
+                    queue: self.clone(),
+                });
+            }
+            //waiters that gave up are only dropped from the heap when they come up, unless
+            //they would be counted against a full queue
+            if state.waiting.len() >= self.limits.max_queued {
+                state.waiting.retain(|waiter| !waiter.grant.is_closed());
+                if state.waiting.len() >= self.limits.max_queued {
+                    return Err(Refusal::Full);
+                }
+            }
+            let (grant, granted) = oneshot::channel();
+            let seq = state.next_seq;
+            state.next_seq += 1;
+            state.waiting.push(Waiter {
+                priority,
+                seq,
+                grant,
+            });
+            granted
+        };
+
+        let waited = match deadline {
+            Some(deadline) => {
+                tokio::time::timeout_at(deadline.into(), &mut granted)
+                    .await
+                    .ok()
+            }
+            None => Some((&mut granted).await),
+        };
+        match waited {
+            Some(Ok(())) => Ok(Slot {
+                queue: self.clone(),
+            }),
+            //the sender is only dropped with the queue, which outlives every caller
+            Some(Err(_)) => Err(Refusal::Expired),
+            None => {
+                //a slot handed over just as the deadline passed is passed on in turn
+                granted.close();
+                if granted.try_recv().is_ok() {
+                    drop(Slot {
+                        queue: self.clone(),
+                    });
+                }
+                Err(Refusal::Expired)
+            }
+        }
+    }
+
+    fn release(&self) {
+        let mut state = self.state.lock().unwrap();
+        while let Some(waiter) = state.waiting.pop() {
+            if waiter.grant.send(()).is_ok() {
+                return;
+            }
+        }
+        state.running -= 1;
+    }
+
+    //commands running and waiting
+    pub fn depth(&self) -> (usize, usize) {
+        let state = self.state.lock().unwrap();
+        let waiting = state.waiting.iter().filter(|waiter| !waiter.grant.is_closed());
+        (state.running, waiting.count())
+    }
+}
+
+//the grpc-timeout header, at most 8 digits and a unit, eg "250m". None when it is malformed
+pub fn parse_timeout(value: &str) -> Option<Duration> {
+    let unit = value.chars().last()?;
+    let digits = &value[..value.len() - unit.len_utf8()];
+    if digits.is_empty() || digits.len() > 8 || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let amount: u64 = digits.parse().ok()?;
+    Some(match unit {
+        'H' => Duration::from_secs(amount * 3600),
+        'M' => Duration::from_secs(amount * 60),
+        'S' => Duration::from_secs(amount),
+        'm' => Duration::from_millis(amount),
+        'u' => Duration::from_micros(amount),
+        'n' => Duration::from_nanos(amount),
+        _ => return None,
+    })
+}
+
+//the priority and deadline a request was sent with, or why the priority header is no good. a
+//malformed grpc-timeout is as good as none, tonic itself ignores it too
+pub fn scheduling<T>(request: &Request<T>) -> Result<(Priority, Option<Instant>), String> {
+    let priority = match request.metadata().get(PRIORITY_HEADER) {
+        Some(value) => value
+            .to_str()
+            .map_err(|_| "malformed priority header".to_string())?
+            .parse()?,
+        None => Priority::default(),
+    };
+    let deadline = request
+        .metadata()
+        .get("grpc-timeout")
+        .and_then(|value| value.to_str().ok())
+        .and_then(parse_timeout)
+        .map(|timeout| Instant::now() + timeout);
+    Ok((priority, deadline))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queue(max_in_flight: usize, max_queued: usize) -> Arc<WorkQueue> {
+        Arc::new(WorkQueue::new(RequestQueue {
+            max_in_flight,
+            max_queued,
+        }))
+    }
+
+    #[test]
+    fn test_parse_timeout() {
+        assert_eq!(parse_timeout("250m"), Some(Duration::from_millis(250)));
+        assert_eq!(parse_timeout("2S"), Some(Duration::from_secs(2)));
+        assert_eq!(parse_timeout("1H"), Some(Duration::from_secs(3600)));
+        assert_eq!(parse_timeout("0n"), Some(Duration::ZERO));
+        assert_eq!(parse_timeout("m"), None);
+        assert_eq!(parse_timeout("123456789m"), None);
+        assert_eq!(parse_timeout("-5m"), None);
+        assert_eq!(parse_timeout("5x"), None);
+        assert_eq!(parse_timeout(""), None);
+    }
+
+    #[test]
+    fn test_scheduling_headers() {
+        let mut request = Request::new(());
+        assert_eq!(scheduling(&request).unwrap(), (Priority::Normal, None));
+
+        request.metadata_mut().insert(PRIORITY_HEADER, "high".parse().unwrap());
+        request.set_timeout(Duration::from_secs(5));
+        let (priority, deadline) = scheduling(&request).unwrap();
+        assert_eq!(priority, Priority::High);
+        assert!(deadline.unwrap() > Instant::now() + Duration::from_secs(4));
+
+        request.metadata_mut().insert(PRIORITY_HEADER, "urgent".parse().unwrap());
+        assert!(scheduling(&request).unwrap_err().contains("unknown priority"));
+    }
+
+    #[tokio::test]
+    async fn test_highest_priority_goes_first() {
+        let queue = queue(1, 10);
+        let running = queue.acquire(Priority::Normal, None).await.unwrap();
+
+        let (order, mut done) = tokio::sync::mpsc::unbounded_channel();
+        let mut tasks = Vec::new();
+        for (name, priority) in [
+            ("low", Priority::Low),
+            ("normal 1", Priority::Normal),
+            ("high", Priority::High),
+            ("normal 2", Priority::Normal),
+        ] {
+            let waiting = queue.clone();
+            let order = order.clone();
+            tasks.push(tokio::spawn(async move {
+                let _slot = waiting.acquire(priority, None).await.unwrap();
+                order.send(name).unwrap();
+            }));
+            //so they queue up in the order above
+            while queue.depth().1 < tasks.len() {
+                tokio::task::yield_now().await;
+            }
+        }
+        drop(running);
+        for task in tasks {
+            task.await.unwrap();
+        }
+        let mut served = Vec::new();
+        while let Ok(name) = done.try_recv() {
+            served.push(name);
+        }
+        assert_eq!(served, vec!["high", "normal 1", "normal 2", "low"]);
+        assert_eq!(queue.depth(), (0, 0));
+    }
+
+    #[tokio::test]
+    async fn test_expired_and_full() {
+        let queue = queue(1, 1);
+        assert!(matches!(
+            queue.acquire(Priority::High, Some(Instant::now())).await,
+            Err(Refusal::Expired)
+        ));
+
+        let running = queue.acquire(Priority::Normal, None).await.unwrap();
+        let soon = Instant::now() + Duration::from_millis(20);
+        assert!(matches!(
+            queue.acquire(Priority::High, Some(soon)).await,
+            Err(Refusal::Expired)
+        ));
+
+        //the expired waiter doesn't hold a place in the queue
+        let waiting = {
+            let queue = queue.clone();
+            tokio::spawn(async move { queue.acquire(Priority::Low, None).await.map(drop) })
+        };
+        while queue.depth().1 < 1 {
+            tokio::task::yield_now().await;
+        }
+        assert!(matches!(
+            queue.acquire(Priority::High, None).await,
+            Err(Refusal::Full)
+        ));
+        drop(running);
+        assert!(waiting.await.unwrap().is_ok());
+        assert_eq!(queue.depth(), (0, 0));
+    }
+}
@@ -0,0 +1,206 @@
+//rolling-upgrade compatibility: a cluster that mixes nodes of this build with nodes of the
+//previous release has to converge. the previous release is whatever binary
+//MERGEDB_PREVIOUS_NODE points at, scripts/compat.sh builds it from the newest tag. without it
+//every node runs this build, which still checks the harness and plain convergence
+use mergedb_node::communication::{
+    replication_service_client::ReplicationServiceClient, PropagateDataRequest,
+};
+use mergedb_proto::wire;
+use std::{
+    fs,
+    net::TcpListener,
+    path::{Path, PathBuf},
+    process::{Child, Command, Stdio},
+    time::{Duration, Instant},
+};
+use tonic::{transport::Channel, Request};
+
+const CONVERGE_TIMEOUT: Duration = Duration::from_secs(15);
+
+struct Node {
+    child: Child,
+    dir: PathBuf,
+    addr: String,
+}
+
+impl Drop for Node {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+//the node reads config.toml from its working directory, so each one gets its own
+fn spawn(binary: &Path, node_id: &str, port: u16, peers: &[u16]) -> Node {
+    let dir =
+        std::env::temp_dir().join(format!("mergedb-compat-{}-{}", std::process::id(), node_id));
+    fs::create_dir_all(&dir).unwrap();
+    let peers: Vec<String> = peers
+        .iter()
+        .map(|port| format!("\"127.0.0.1:{}\"", port))
+        .collect();
+    fs::write(
+        dir.join("config.toml"),
+        format!(
+            "node_id = \"{}\"\nlisten_address = \"127.0.0.1:{}\"\npeers = [{}]\n",
+            node_id,
+            port,
+            peers.join(", ")
+        ),
+    )
+    .unwrap();
+
+    let child = Command::new(binary)
+        .current_dir(&dir)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap_or_else(|e| panic!("failed to start {}: {}", binary.display(), e));
+    Node {
+        child,
+        dir,
+        addr: format!("http://127.0.0.1:{}", port),
+    }
+}
+
+async fn connect(node: &Node) -> ReplicationServiceClient<Channel> {
+    let started = Instant::now();
+    loop {
+        match ReplicationServiceClient::connect(node.addr.clone()).await {
+            Ok(client) => return client,
+            Err(e) if started.elapsed() > CONVERGE_TIMEOUT => {
+                panic!("{} never came up: {}", node.addr, e)
+            }
+            Err(_) => tokio::time::sleep(Duration::from_millis(100)).await,
+        }
+    }
+}
+
+async fn send(
+    client: &mut ReplicationServiceClient<Channel>,
+    cmd: &str,
+    key: &str,
+    value: Vec<u8>,
+) {
+    let response = client
+        .propagate_data(Request::new(PropagateDataRequest {
+            valuetype: cmd.to_string(),
+            key: key.to_string(),
+            value,
+            dry_run: false,
+            encoding: String::new(),
+            element_type: String::new(),
+            amount: None,
+        }))
+        .await
+        .unwrap()
+        .into_inner();
+    assert!(response.success, "{} {} was refused", cmd, key);
+}
+
+//None until the key has reached the node
+async fn read(
+    client: &mut ReplicationServiceClient<Channel>,
+    cmd: &str,
+    key: &str,
+) -> Option<Vec<u8>> {
+    let response = client
+        .propagate_data(Request::new(PropagateDataRequest {
+            valuetype: cmd.to_string(),
+            key: key.to_string(),
+            value: Vec::new(),
+            dry_run: false,
+            encoding: String::new(),
+            element_type: String::new(),
+            amount: None,
+        }))
+        .await
+        .ok()?
+        .into_inner();
+    response.success.then_some(response.response)
+}
+
+//what every node has to agree on in the end
+async fn snapshot(
+    client: &mut ReplicationServiceClient<Channel>,
+) -> Option<(i64, Vec<String>, String)> {
+    let votes = wire::decode_i64(&read(client, "CGET", "votes").await?).unwrap();
+    let mut tags: Vec<String> = wire::decode_json(&read(client, "SGET", "tags").await?).unwrap();
+    tags.sort();
+    let motd = wire::decode_string(read(client, "RGET", "motd").await?).unwrap();
+    Some((votes, tags, motd))
+}
+
+#[tokio::test]
+async fn test_mixed_version_cluster_converges() {
+    let current = PathBuf::from(env!("CARGO_BIN_EXE_mergedb-node"));
+    let previous = std::env::var_os("MERGEDB_PREVIOUS_NODE")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| current.clone());
+
+    let ports = [free_port(), free_port(), free_port()];
+    let peers_of = |i: usize| -> Vec<u16> {
+        ports
+            .iter()
+            .enumerate()
+            .filter(|(j, _)| *j != i)
+            .map(|(_, port)| *port)
+            .collect()
+    };
+    let nodes = [
+        spawn(&current, "current_1", ports[0], &peers_of(0)),
+        spawn(&previous, "previous_1", ports[1], &peers_of(1)),
+        spawn(&current, "current_2", ports[2], &peers_of(2)),
+    ];
+    let mut clients = Vec::new();
+    for node in &nodes {
+        clients.push(connect(node).await);
+    }
+
+    //every type gets written on both versions
+    send(&mut clients[0], "CSET", "votes", wire::encode_i64(3)).await;
+    //CINC needs the counter to exist, so it has to have been gossiped over first
+    let started = Instant::now();
+    while read(&mut clients[1], "CGET", "votes").await.is_none() {
+        assert!(
+            started.elapsed() < CONVERGE_TIMEOUT,
+            "votes never reached previous_1"
+        );
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    send(&mut clients[1], "CINC", "votes", wire::encode_i64(4)).await;
+    send(&mut clients[1], "SADD", "tags", b"old".to_vec()).await;
+    send(&mut clients[2], "SADD", "tags", b"new".to_vec()).await;
+    send(&mut clients[0], "RSET", "motd", b"hello".to_vec()).await;
+
+    let expected = Some((
+        7,
+        vec!["new".to_string(), "old".to_string()],
+        "hello".to_string(),
+    ));
+    let started = Instant::now();
+    loop {
+        let mut states = Vec::new();
+        for client in &mut clients {
+            states.push(snapshot(client).await);
+        }
+        if states.iter().all(|state| *state == expected) {
+            break;
+        }
+        assert!(
+            started.elapsed() < CONVERGE_TIMEOUT,
+            "cluster did not converge, nodes hold {:?}",
+            states
+        );
+        tokio::time::sleep(Duration::from_millis(250)).await;
+    }
+}
@@ -0,0 +1,456 @@
+//proto <-> domain conversions for every crdt, shared by the node and the client so that both
+//read raw crdt payloads the same way
+use mergedb_types::{
+    aw_set::{AWSet, Dot as AW_Dot},
+    custom::CustomValue,
+    element::Element,
+    lww_map::{Entry as MapEntry, LwwMap},
+    lww_register::{Dot as LWW_Dot, LwwRegister},
+    pn_counter::PNCounter,
+    CrdtValue,
+};
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    communication::{
+        crdt_data::Data, proto_element::Value, AwSetMessage, CrdtData, CustomMessage,
+        LwwMapMessage, LwwRegisterMessage, PnCounterMessage, ProtoDot, ProtoDotSet, ProtoElement,
+        ProtoMapEntry, ProtoRegisterDot, ProtoTypedTag,
+    },
+    migrate::{self, FormatError},
+};
+
+// convert domain -> proto for sending
+impl From<PNCounter> for PnCounterMessage {
+    fn from(domain: PNCounter) -> Self {
+        Self {
+            p: domain.p,
+            n: domain.n,
+        }
+    }
+}
+
+// convert proto -> domain for receiving
+impl From<PnCounterMessage> for PNCounter {
+    fn from(wire: PnCounterMessage) -> Self {
+        Self {
+            p: wire.p,
+            n: wire.n,
+        }
+    }
+}
+
+//same for AWSet
+impl From<AW_Dot> for ProtoDot {
+    fn from(domain: AW_Dot) -> Self {
+        Self {
+            node_id: domain.node_id,
+            counter: domain.counter,
+            added_at: 0,
+        }
+    }
+}
+
+impl From<ProtoDot> for AW_Dot {
+    fn from(wire: ProtoDot) -> Self {
+        Self {
+            node_id: wire.node_id,
+            counter: wire.counter,
+        }
+    }
+}
+
+//None for strings, they are map keys instead
+fn element_to_proto(element: Element) -> Option<ProtoElement> {
+    let value = match element {
+        Element::Str(_) => return None,
+        Element::Int(value) => Value::Int(value),
+        Element::Float(value) => Value::Float(value),
+        Element::Bool(value) => Value::Boolean(value),
+        Element::Bytes(bytes) => Value::Bytes(bytes),
+    };
+    Some(ProtoElement { value: Some(value) })
+}
+
+fn element_from_proto(wire: ProtoElement) -> Option<Element> {
+    Some(match wire.value? {
+        Value::Int(value) => Element::Int(value),
+        Value::Float(value) => Element::Float(value),
+        Value::Boolean(value) => Element::Bool(value),
+        Value::Bytes(bytes) => Element::Bytes(bytes),
+    })
+}
+
+impl From<AWSet> for AwSetMessage {
+    fn from(domain: AWSet) -> Self {
+        //the add timestamps travel inside the dots themselves
+        let added_at = &domain.added_at;
+        let convert_dots = |dots: HashSet<AW_Dot>| ProtoDotSet {
+            dots: dots
+                .into_iter()
+                .map(|dot| ProtoDot {
+                    added_at: added_at.get(&dot).copied().unwrap_or(0),
+                    ..ProtoDot::from(dot)
+                })
+                .collect(),
+        };
+        //string tags go in the map, the rest in the typed list
+        let convert_map = |input_map: HashMap<Element, HashSet<AW_Dot>>| {
+            let mut tags = HashMap::new();
+            let mut typed = Vec::new();
+            for (tag, dots) in input_map {
+                match tag {
+                    Element::Str(tag) => {
+                        tags.insert(tag, convert_dots(dots));
+                    }
+                    tag => typed.push(ProtoTypedTag {
+                        element: element_to_proto(tag),
+                        dots: Some(convert_dots(dots)),
+                    }),
+                }
+            }
+            (tags, typed)
+        };
+        let (add_tags, typed_add_tags) = convert_map(domain.add_tags);
+        let (remove_tags, typed_remove_tags) = convert_map(domain.remove_tags);
+        Self {
+            clock: domain.clock,
+            add_tags,
+            remove_tags,
+            hlc: domain.hlc,
+            typed_add_tags,
+            typed_remove_tags,
+        }
+    }
+}
+
+impl From<AwSetMessage> for AWSet {
+    fn from(wire: AwSetMessage) -> Self {
+        let mut added_at = HashMap::new();
+        let mut convert_dots = |dot_set: ProtoDotSet| -> HashSet<AW_Dot> {
+            dot_set
+                .dots
+                .into_iter()
+                .map(|dot| {
+                    if dot.added_at > 0 {
+                        added_at.insert(AW_Dot::from(dot.clone()), dot.added_at);
+                    }
+                    AW_Dot::from(dot)
+                })
+                .collect()
+        };
+        //a typed tag without an element came from a newer build, it is left out
+        let mut convert_map = |input_map: HashMap<String, ProtoDotSet>, typed: Vec<ProtoTypedTag>| {
+            let mut tags: HashMap<Element, HashSet<AW_Dot>> = input_map
+                .into_iter()
+                .map(|(tag, dot_set)| (Element::Str(tag), convert_dots(dot_set)))
+                .collect();
+            for tag in typed {
+                if let Some(element) = tag.element.and_then(element_from_proto) {
+                    tags.insert(element, convert_dots(tag.dots.unwrap_or_default()));
+                }
+            }
+            tags
+        };
+        let add_tags = convert_map(wire.add_tags, wire.typed_add_tags);
+        let remove_tags = convert_map(wire.remove_tags, wire.typed_remove_tags);
+        Self {
+            clock: wire.clock,
+            add_tags,
+            remove_tags,
+            added_at,
+            hlc: wire.hlc,
+        }
+    }
+}
+
+//same for LWWRegister
+impl From<LWW_Dot> for ProtoRegisterDot {
+    fn from(domain: LWW_Dot) -> Self {
+        Self {
+            node_id: domain.node_id,
+            counter: domain.counter,
+            register: domain.register,
+        }
+    }
+}
+
+impl From<ProtoRegisterDot> for LWW_Dot {
+    fn from(wire: ProtoRegisterDot) -> Self {
+        Self {
+            node_id: wire.node_id,
+            counter: wire.counter,
+            register: wire.register,
+        }
+    }
+}
+
+impl From<LwwRegister> for LwwRegisterMessage {
+    fn from(domain: LwwRegister) -> Self {
+        Self {
+            clock: domain.clock,
+            register_state: Some(ProtoRegisterDot::from(domain.register_state)),
+        }
+    }
+}
+
+impl From<LwwRegisterMessage> for LwwRegister {
+    fn from(wire: LwwRegisterMessage) -> Self {
+        let raw_dot = wire.register_state.unwrap_or_default();
+        Self {
+            clock: wire.clock,
+            register_state: LWW_Dot::from(raw_dot),
+        }
+    }
+}
+
+//same for LwwMap, removed fields travel as entries without a value
+impl From<MapEntry> for ProtoMapEntry {
+    fn from(domain: MapEntry) -> Self {
+        Self {
+            node_id: domain.node_id,
+            counter: domain.counter,
+            value: domain.value,
+        }
+    }
+}
+
+impl From<ProtoMapEntry> for MapEntry {
+    fn from(wire: ProtoMapEntry) -> Self {
+        Self {
+            node_id: wire.node_id,
+            counter: wire.counter,
+            value: wire.value,
+        }
+    }
+}
+
+impl From<LwwMap> for LwwMapMessage {
+    fn from(domain: LwwMap) -> Self {
+        Self {
+            clock: domain.clock,
+            entries: domain
+                .entries
+                .into_iter()
+                .map(|(field, entry)| (field, ProtoMapEntry::from(entry)))
+                .collect(),
+        }
+    }
+}
+
+impl From<LwwMapMessage> for LwwMap {
+    fn from(wire: LwwMapMessage) -> Self {
+        Self {
+            clock: wire.clock,
+            entries: wire
+                .entries
+                .into_iter()
+                .map(|(field, entry)| (field, MapEntry::from(entry)))
+                .collect(),
+        }
+    }
+}
+
+//a custom value's states are opaque bytes both ways
+impl From<CustomValue> for CustomMessage {
+    fn from(domain: CustomValue) -> Self {
+        Self {
+            type_tag: domain.type_tag,
+            states: domain.states,
+        }
+    }
+}
+
+impl From<CustomMessage> for CustomValue {
+    fn from(wire: CustomMessage) -> Self {
+        let mut states = wire.states;
+        states.sort();
+        states.dedup();
+        Self {
+            type_tag: wire.type_tag,
+            states,
+        }
+    }
+}
+
+//the wire side of CrdtValue, the types crate itself knows nothing about proto
+pub trait CrdtProto: Sized {
+    //in the newest format, see the migrate module
+    fn to_proto(self) -> CrdtData;
+    //in an older format for peers that don't read the newest one yet, clamped to what this
+    //build can still write
+    fn to_proto_as(self, version: u32) -> CrdtData;
+    //payloads in older formats are upgraded first
+    fn from_proto(wire: CrdtData) -> Result<Self, FormatError>;
+}
+
+impl CrdtProto for CrdtValue {
+    fn to_proto(self) -> CrdtData {
+        let data = match self {
+            CrdtValue::Counter(counter) => Data::PnCounter(PnCounterMessage::from(counter)),
+            CrdtValue::Set(set) => Data::AwSet(AwSetMessage::from(set)),
+            CrdtValue::Register(reg) => Data::LwwRegister(LwwRegisterMessage::from(reg)),
+            CrdtValue::Map(map) => Data::LwwMap(LwwMapMessage::from(map)),
+            CrdtValue::Custom(custom) => Data::Custom(CustomMessage::from(custom)),
+        };
+        CrdtData {
+            data: Some(data),
+            format_version: migrate::FORMAT_VERSION,
+        }
+    }
+
+    fn to_proto_as(self, version: u32) -> CrdtData {
+        let version = version.clamp(migrate::OLDEST_READABLE, migrate::FORMAT_VERSION);
+        let wire = self.to_proto();
+        //the payload is never empty and the version is in range, so this can't fail
+        migrate::downgrade(wire.clone(), version).unwrap_or(wire)
+    }
+
+    fn from_proto(wire: CrdtData) -> Result<Self, FormatError> {
+        let wire = migrate::upgrade(wire)?;
+        Ok(match wire.data.ok_or(FormatError::Empty)? {
+            Data::PnCounter(wire) => CrdtValue::Counter(PNCounter::from(wire)),
+            Data::AwSet(wire) => CrdtValue::Set(AWSet::from(wire)),
+            Data::LwwRegister(wire) => CrdtValue::Register(LwwRegister::from(wire)),
+            Data::LwwMap(wire) => CrdtValue::Map(LwwMap::from(wire)),
+            Data::Custom(wire) => CrdtValue::Custom(CustomValue::from(wire)),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prost::Message;
+    use proptest::prelude::*;
+
+    //through the actual wire encoding, not just the generated structs
+    fn round_trip(value: CrdtValue) -> Option<CrdtValue> {
+        let bytes = value.to_proto().encode_to_vec();
+        CrdtValue::from_proto(CrdtData::decode(bytes.as_slice()).ok()?).ok()
+    }
+
+    fn node_id() -> impl Strategy<Value = String> {
+        "node_[0-9]"
+    }
+
+    fn counter() -> impl Strategy<Value = PNCounter> {
+        (
+            prop::collection::hash_map(node_id(), any::<u64>(), 0..5),
+            prop::collection::hash_map(node_id(), any::<u64>(), 0..5),
+        )
+            .prop_map(|(p, n)| PNCounter { p, n })
+    }
+
+    //built through the public api so that dots, clocks and timestamps stay consistent
+    fn element() -> impl Strategy<Value = Element> {
+        prop_oneof![
+            "[a-d]".prop_map(Element::Str),
+            (0..4i64).prop_map(Element::Int),
+            (0..4).prop_map(|value| Element::Float(value as f64 / 2.0)),
+            any::<bool>().prop_map(Element::Bool),
+            prop::collection::vec(any::<u8>(), 0..2).prop_map(Element::Bytes),
+        ]
+    }
+
+    fn set() -> impl Strategy<Value = AWSet> {
+        prop::collection::vec((any::<bool>(), element(), node_id()), 0..20).prop_map(|ops| {
+            let mut set = AWSet::new();
+            for (add, tag, node) in ops {
+                if add {
+                    set.add(tag, node);
+                } else {
+                    set.remove(tag);
+                }
+            }
+            set
+        })
+    }
+
+    fn register() -> impl Strategy<Value = LwwRegister> {
+        (any::<u64>(), node_id(), any::<u64>(), ".*").prop_map(|(clock, node_id, counter, register)| {
+            LwwRegister {
+                clock,
+                register_state: LWW_Dot {
+                    node_id,
+                    counter,
+                    register,
+                },
+            }
+        })
+    }
+
+    fn map() -> impl Strategy<Value = LwwMap> {
+        prop::collection::vec((any::<bool>(), "[a-d]", ".*", node_id()), 0..20).prop_map(|ops| {
+            let mut map = LwwMap::new();
+            for (set, field, value, node) in ops {
+                if set {
+                    map.set(field, value, node);
+                } else {
+                    map.remove(&field, node);
+                }
+            }
+            map
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn test_counter_round_trip(counter in counter()) {
+            let value = CrdtValue::Counter(counter);
+            prop_assert_eq!(round_trip(value.clone()), Some(value));
+        }
+
+        #[test]
+        fn test_set_round_trip(set in set()) {
+            let value = CrdtValue::Set(set);
+            prop_assert_eq!(round_trip(value.clone()), Some(value));
+        }
+
+        #[test]
+        fn test_register_round_trip(register in register()) {
+            let value = CrdtValue::Register(register);
+            prop_assert_eq!(round_trip(value.clone()), Some(value));
+        }
+
+        #[test]
+        fn test_map_round_trip(map in map()) {
+            let value = CrdtValue::Map(map);
+            prop_assert_eq!(round_trip(value.clone()), Some(value));
+        }
+    }
+
+    #[test]
+    fn test_custom_round_trip() {
+        //the type needn't be registered to travel
+        let mut custom = CustomValue::new("acme.hll", vec![3, 1]);
+        custom.states.push(vec![]);
+        custom.states.sort();
+        let value = CrdtValue::Custom(custom);
+        assert_eq!(round_trip(value.clone()), Some(value));
+    }
+
+    #[test]
+    fn test_empty_oneof_is_rejected() {
+        let wire = CrdtData {
+            data: None,
+            format_version: migrate::FORMAT_VERSION,
+        };
+        assert_eq!(CrdtValue::from_proto(wire), Err(FormatError::Empty));
+    }
+
+    //what a node one format behind gets still reads back as the same set, minus add times
+    #[test]
+    fn test_old_format_still_converges() {
+        let mut set = AWSet::new();
+        set.add("a".to_string(), "node_1".to_string());
+        let old = CrdtValue::Set(set.clone()).to_proto_as(1);
+        assert_eq!(old.format_version, 1);
+
+        let Ok(CrdtValue::Set(read)) = CrdtValue::from_proto(old) else {
+            panic!("expected a set");
+        };
+        assert_eq!(read.add_tags, set.add_tags);
+        assert!(read.added_at.is_empty());
+    }
+}
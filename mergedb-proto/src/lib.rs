@@ -0,0 +1,10 @@
+pub mod convert;
+pub mod metadata;
+pub mod migrate;
+pub mod wire;
+
+pub use convert::CrdtProto;
+
+pub mod communication {
+    tonic::include_proto!("communication");
+}
@@ -0,0 +1,52 @@
+//request metadata the client attaches next to the message and the node reads. the deadline is
+//the standard grpc-timeout header (tonic's Request::set_timeout), the priority is our own.
+//the node's time on a request comes back in the response's metadata
+use std::{fmt, str::FromStr};
+
+pub const PRIORITY_HEADER: &str = "mergedb-priority";
+
+//response metadata the other way round: how long the node spent on a request in microseconds,
+//from taking it to answering, waiting its turn in the queue included
+pub const SERVER_TIME_HEADER: &str = "mergedb-server-time-us";
+
+//which waiting commands the node runs first when it is busy. a request without the header is
+//normal
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, Hash)]
+pub enum Priority {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+impl Priority {
+    pub fn name(self) -> &'static str {
+        match self {
+            Priority::Low => "low",
+            Priority::Normal => "normal",
+            Priority::High => "high",
+        }
+    }
+}
+
+impl FromStr for Priority {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name.to_ascii_lowercase().as_str() {
+            "low" => Ok(Priority::Low),
+            "" | "normal" => Ok(Priority::Normal),
+            "high" => Ok(Priority::High),
+            _ => Err(format!(
+                "unknown priority {:?}, expected low, normal or high",
+                name
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Priority {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
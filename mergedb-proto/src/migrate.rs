@@ -0,0 +1,204 @@
+//crdt payloads carry the format they were written in (CRDTData.format_version), so a release
+//that changes a representation still reads what older nodes send, and keeps writing the older
+//format until every node it knows of reads the newer one. format history:
+//  1 - the original format. payloads from before formats were versioned say 0, meaning 1
+//  2 - aw set dots carry their hlc add timestamp (ProtoDot.added_at), and the set the latest
+//      hlc it has seen (AWSetMessage.hlc)
+//  3 - aw set elements can be ints, floats, bools and bytes (AWSetMessage.typed_add_tags and
+//      typed_remove_tags), string elements stay in the maps
+//a release that changes a representation bumps FORMAT_VERSION, appends a step to STEPS and
+//blesses new golden files, see tests/golden.rs
+use std::fmt;
+
+use crate::communication::{crdt_data::Data, CrdtData};
+
+pub const FORMAT_VERSION: u32 = 3;
+pub const OLDEST_READABLE: u32 = 1;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FormatError {
+    //the oneof was left empty
+    Empty,
+    Unsupported { version: u32 },
+}
+
+impl fmt::Display for FormatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FormatError::Empty => write!(f, "crdt payload carries no data"),
+            FormatError::Unsupported { version } => write!(
+                f,
+                "crdt format {} is not supported, this node reads {} to {}",
+                version, OLDEST_READABLE, FORMAT_VERSION
+            ),
+        }
+    }
+}
+
+impl std::error::Error for FormatError {}
+
+//moves a payload between format `from` and `from + 1`, both ways
+struct Step {
+    from: u32,
+    upgrade: fn(&mut Data),
+    downgrade: fn(&mut Data),
+}
+
+const STEPS: &[Step] = &[
+    Step {
+        from: 1,
+        upgrade: upgrade_v1,
+        downgrade: downgrade_v2,
+    },
+    Step {
+        from: 2,
+        upgrade: upgrade_v2,
+        downgrade: downgrade_v3,
+    },
+];
+
+//format 1 sets have no add timestamps, they stay 0 (unknown), so the set's hlc only has to be
+//at least the newest one that is there
+fn upgrade_v1(data: &mut Data) {
+    if let Data::AwSet(set) = data {
+        let newest = set
+            .add_tags
+            .values()
+            .flat_map(|dots| dots.dots.iter().map(|dot| dot.added_at))
+            .max()
+            .unwrap_or(0);
+        set.hlc = set.hlc.max(newest);
+    }
+}
+
+fn downgrade_v2(data: &mut Data) {
+    if let Data::AwSet(set) = data {
+        set.hlc = 0;
+        for dots in set
+            .add_tags
+            .values_mut()
+            .chain(set.remove_tags.values_mut())
+        {
+            for dot in &mut dots.dots {
+                dot.added_at = 0;
+            }
+        }
+    }
+}
+
+//format 2 sets only hold strings, nothing to add
+fn upgrade_v2(_: &mut Data) {}
+
+//an older node has no way to hold a typed element, so it doesn't get them. they only come
+//from writes to newer nodes, and reach it by gossip once it is upgraded
+fn downgrade_v3(data: &mut Data) {
+    if let Data::AwSet(set) = data {
+        set.typed_add_tags.clear();
+        set.typed_remove_tags.clear();
+    }
+}
+
+//the format a payload is in, with the pre-versioning 0 read as 1
+pub fn version_of(wire: &CrdtData) -> u32 {
+    wire.format_version.max(1)
+}
+
+fn check(version: u32) -> Result<(), FormatError> {
+    if (OLDEST_READABLE..=FORMAT_VERSION).contains(&version) {
+        Ok(())
+    } else {
+        Err(FormatError::Unsupported { version })
+    }
+}
+
+//brings a payload written in any readable format up to FORMAT_VERSION
+pub fn upgrade(mut wire: CrdtData) -> Result<CrdtData, FormatError> {
+    let version = version_of(&wire);
+    check(version)?;
+    let data = wire.data.as_mut().ok_or(FormatError::Empty)?;
+    for step in STEPS.iter().filter(|step| step.from >= version) {
+        (step.upgrade)(data);
+    }
+    wire.format_version = FORMAT_VERSION;
+    Ok(wire)
+}
+
+//rewrites a FORMAT_VERSION payload in an older format, for peers that don't read ours yet
+pub fn downgrade(mut wire: CrdtData, to: u32) -> Result<CrdtData, FormatError> {
+    check(to)?;
+    let data = wire.data.as_mut().ok_or(FormatError::Empty)?;
+    for step in STEPS.iter().rev().filter(|step| step.from >= to) {
+        (step.downgrade)(data);
+    }
+    wire.format_version = to;
+    Ok(wire)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::communication::{AwSetMessage, ProtoDot, ProtoDotSet, ProtoTypedTag};
+    use std::collections::HashMap;
+
+    fn set_payload(format_version: u32, added_at: u64, hlc: u64) -> CrdtData {
+        let dot = ProtoDot {
+            node_id: "node_1".to_string(),
+            counter: 1,
+            added_at,
+        };
+        CrdtData {
+            data: Some(Data::AwSet(AwSetMessage {
+                clock: 1,
+                add_tags: HashMap::from([("a".to_string(), ProtoDotSet { dots: vec![dot] })]),
+                remove_tags: HashMap::new(),
+                hlc,
+                typed_add_tags: Vec::new(),
+                typed_remove_tags: Vec::new(),
+            })),
+            format_version,
+        }
+    }
+
+    #[test]
+    fn test_unversioned_payloads_upgrade() {
+        let upgraded = upgrade(set_payload(0, 0, 0)).unwrap();
+        assert_eq!(upgraded.format_version, FORMAT_VERSION);
+        assert_eq!(upgraded.data, set_payload(0, 0, 0).data);
+    }
+
+    #[test]
+    fn test_downgrade_then_upgrade() {
+        let current = set_payload(FORMAT_VERSION, 7, 7);
+        let old = downgrade(current.clone(), 1).unwrap();
+        assert_eq!(old, set_payload(1, 0, 0));
+        assert_eq!(upgrade(old).unwrap(), set_payload(FORMAT_VERSION, 0, 0));
+        assert_eq!(downgrade(current.clone(), FORMAT_VERSION), Ok(current));
+    }
+
+    #[test]
+    fn test_typed_elements_are_left_out_for_format_2() {
+        let mut current = set_payload(FORMAT_VERSION, 7, 7);
+        if let Some(Data::AwSet(set)) = &mut current.data {
+            set.typed_add_tags.push(ProtoTypedTag::default());
+        }
+        assert_eq!(downgrade(current, 2).unwrap(), set_payload(2, 7, 7));
+    }
+
+    #[test]
+    fn test_unknown_formats_are_refused() {
+        let newer = set_payload(FORMAT_VERSION + 1, 0, 0);
+        assert_eq!(
+            upgrade(newer),
+            Err(FormatError::Unsupported {
+                version: FORMAT_VERSION + 1
+            })
+        );
+        assert_eq!(
+            upgrade(CrdtData {
+                data: None,
+                format_version: FORMAT_VERSION
+            }),
+            Err(FormatError::Empty)
+        );
+    }
+}
@@ -0,0 +1,359 @@
+//encodings of the raw `value`/`response` bytes of PropagateData, shared by the node and the
+//client so that neither side has to guess:
+//  - integers (counter amounts and values, lengths, sizes) are exactly 8 big-endian bytes,
+//    i64 for counters and u64 for everything that can't be negative. counter amounts and CGET's
+//    value also travel in their own sint64 fields (amount, number), see decode_signed
+//  - tags, register values and other text are utf-8
+//  - a tag or register value sent with a msgpack or cbor `encoding` is whatever bytes it is,
+//    it is held (and read back by RGET/SGET) as a binary value, see encode_value
+//  - a set tag with an `element_type` is 8 big-endian bytes for an int or a float, a 0 or 1
+//    byte for a bool, and the bytes themselves for bytes
+//  - lists and maps (SGET, INFO, KEYS, SMETA) are json, SGET has tags as strings, ints, floats,
+//    bools or {"bytes": "<base64>"} (and {"float": "NaN"} for floats json can't hold)
+//anything else is an error, never a silent zero
+use base64::{engine::general_purpose::STANDARD, Engine};
+use mergedb_types::element::Element;
+use serde::de::DeserializeOwned;
+use serde_json::{json, Value};
+use std::{fmt, str::FromStr};
+
+//a binary value is text like any other to the crdts: BINARY_MARKER, its encoding, ':' and its
+//bytes in base64, eg "\0cbor:oWFhAQ==". so they replicate, persist and merge exactly as strings
+//do, on nodes that know nothing of encodings too. text can't start with the marker
+pub const BINARY_MARKER: char = '\0';
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WireError {
+    Length { expected: usize, got: usize },
+    Utf8,
+    Json(String),
+    Encoding(String),
+    //utf-8 text starting with BINARY_MARKER, it would read back as a binary value
+    Marker,
+    ElementType(String),
+    Element(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Encoding {
+    #[default]
+    Utf8,
+    Msgpack,
+    Cbor,
+}
+
+impl Encoding {
+    pub fn name(self) -> &'static str {
+        match self {
+            Encoding::Utf8 => "utf8",
+            Encoding::Msgpack => "msgpack",
+            Encoding::Cbor => "cbor",
+        }
+    }
+}
+
+//an empty encoding is utf8, so requests from clients that predate encodings read as before
+impl FromStr for Encoding {
+    type Err = WireError;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name.to_ascii_lowercase().as_str() {
+            "" | "utf8" | "utf-8" => Ok(Encoding::Utf8),
+            "msgpack" => Ok(Encoding::Msgpack),
+            "cbor" => Ok(Encoding::Cbor),
+            _ => Err(WireError::Encoding(name.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for Encoding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl fmt::Display for WireError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WireError::Length { expected, got } => write!(
+                f,
+                "malformed payload: expected {} big-endian bytes, got {}",
+                expected, got
+            ),
+            WireError::Utf8 => write!(f, "malformed payload: not valid utf-8"),
+            WireError::Json(e) => write!(f, "malformed payload: {}", e),
+            WireError::Encoding(name) => write!(
+                f,
+                "unknown encoding {:?}, expected utf8, msgpack or cbor",
+                name
+            ),
+            WireError::Marker => write!(f, "malformed payload: text can't start with \\0"),
+            WireError::ElementType(name) => write!(
+                f,
+                "unknown element type {:?}, expected string, int, float, bool or bytes",
+                name
+            ),
+            WireError::Element(e) => write!(f, "malformed payload: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for WireError {}
+
+fn eight_bytes(raw: &[u8]) -> Result<[u8; 8], WireError> {
+    raw.try_into().map_err(|_| WireError::Length {
+        expected: 8,
+        got: raw.len(),
+    })
+}
+
+pub fn encode_i64(value: i64) -> Vec<u8> {
+    value.to_be_bytes().to_vec()
+}
+
+pub fn decode_i64(raw: &[u8]) -> Result<i64, WireError> {
+    eight_bytes(raw).map(i64::from_be_bytes)
+}
+
+//a counter amount or value: the sint64 field when the sender set it, the 8 bytes in value or
+//response otherwise, as nodes and clients predating the field send them
+pub fn decode_signed(field: Option<i64>, raw: &[u8]) -> Result<i64, WireError> {
+    match field {
+        Some(value) => Ok(value),
+        None => decode_i64(raw),
+    }
+}
+
+pub fn encode_u64(value: u64) -> Vec<u8> {
+    value.to_be_bytes().to_vec()
+}
+
+pub fn decode_u64(raw: &[u8]) -> Result<u64, WireError> {
+    eight_bytes(raw).map(u64::from_be_bytes)
+}
+
+pub fn decode_string(raw: Vec<u8>) -> Result<String, WireError> {
+    String::from_utf8(raw).map_err(|_| WireError::Utf8)
+}
+
+pub fn decode_json<T: DeserializeOwned>(raw: &[u8]) -> Result<T, WireError> {
+    serde_json::from_slice(raw).map_err(|e| WireError::Json(e.to_string()))
+}
+
+//a tag or register value as it is held: the text itself, or the binary value for the others
+pub fn encode_value(raw: Vec<u8>, encoding: Encoding) -> Result<String, WireError> {
+    match encoding {
+        Encoding::Utf8 => {
+            let text = decode_string(raw)?;
+            if text.starts_with(BINARY_MARKER) {
+                return Err(WireError::Marker);
+            }
+            Ok(text)
+        }
+        encoding => Ok(format!(
+            "{}{}:{}",
+            BINARY_MARKER,
+            encoding,
+            STANDARD.encode(raw)
+        )),
+    }
+}
+
+//the bytes and encoding of a held value. anything that isn't a well formed binary value, like
+//values written before encodings existed, is utf8 text
+pub fn decode_value(held: &str) -> (Vec<u8>, Encoding) {
+    let binary = held.strip_prefix(BINARY_MARKER).and_then(|binary| {
+        let (encoding, base64) = binary.split_once(':')?;
+        let encoding = encoding.parse().ok().filter(|e| *e != Encoding::Utf8)?;
+        Some((STANDARD.decode(base64).ok()?, encoding))
+    });
+    binary.unwrap_or_else(|| (held.as_bytes().to_vec(), Encoding::Utf8))
+}
+
+pub fn is_binary(held: &str) -> bool {
+    decode_value(held).1 != Encoding::Utf8
+}
+
+//a set tag's value bytes and element_type, "" for strings as older clients send them
+pub fn encode_element(element: &Element) -> (Vec<u8>, &'static str) {
+    match element {
+        Element::Str(tag) => (tag.as_bytes().to_vec(), ""),
+        Element::Int(value) => (encode_i64(*value), "int"),
+        Element::Float(value) => (value.to_be_bytes().to_vec(), "float"),
+        Element::Bool(value) => (vec![*value as u8], "bool"),
+        Element::Bytes(bytes) => (bytes.clone(), "bytes"),
+    }
+}
+
+//a string tag takes an encoding like a register value does, the other kinds are what they are
+pub fn decode_element(
+    raw: Vec<u8>,
+    element_type: &str,
+    encoding: Encoding,
+) -> Result<Element, WireError> {
+    if !matches!(element_type, "" | "string") && encoding != Encoding::Utf8 {
+        return Err(WireError::Element(format!(
+            "only string tags take an encoding, not {} ones",
+            element_type
+        )));
+    }
+    match element_type {
+        "" | "string" => encode_value(raw, encoding).map(Element::Str),
+        "int" => decode_i64(&raw).map(Element::Int),
+        "float" => eight_bytes(&raw).map(|bytes| Element::Float(f64::from_be_bytes(bytes))),
+        "bool" => match raw[..] {
+            [0] => Ok(Element::Bool(false)),
+            [1] => Ok(Element::Bool(true)),
+            [byte] => Err(WireError::Element(format!(
+                "a bool is 0 or 1, not {}",
+                byte
+            ))),
+            _ => Err(WireError::Length {
+                expected: 1,
+                got: raw.len(),
+            }),
+        },
+        "bytes" => Ok(Element::Bytes(raw)),
+        other => Err(WireError::ElementType(other.to_string())),
+    }
+}
+
+pub fn element_to_json(element: &Element) -> Value {
+    match element {
+        Element::Str(tag) => json!(tag),
+        Element::Int(value) => json!(value),
+        Element::Float(value) if value.is_finite() => json!(value),
+        Element::Float(value) => json!({ "float": value.to_string() }),
+        Element::Bool(value) => json!(value),
+        Element::Bytes(bytes) => json!({ "bytes": STANDARD.encode(bytes) }),
+    }
+}
+
+pub fn element_from_json(json: &Value) -> Option<Element> {
+    match json {
+        Value::String(tag) => Some(Element::Str(tag.clone())),
+        Value::Number(number) => match number.as_i64() {
+            Some(value) => Some(Element::Int(value)),
+            None => number.as_f64().map(Element::Float),
+        },
+        Value::Bool(value) => Some(Element::Bool(*value)),
+        Value::Object(object) => match (object.get("bytes"), object.get("float")) {
+            (Some(Value::String(bytes)), _) => STANDARD.decode(bytes).ok().map(Element::Bytes),
+            (_, Some(Value::String(value))) => value.parse().ok().map(Element::Float),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    #[test]
+    fn test_wrong_lengths_are_rejected() {
+        assert_eq!(
+            decode_i64(&[]),
+            Err(WireError::Length {
+                expected: 8,
+                got: 0
+            })
+        );
+        assert_eq!(
+            decode_u64(&[0; 4]),
+            Err(WireError::Length {
+                expected: 8,
+                got: 4
+            })
+        );
+        assert_eq!(decode_i64(&[0xff; 8]), Ok(-1));
+        assert_eq!(decode_string(vec![0xff, 0xfe]), Err(WireError::Utf8));
+        assert!(decode_json::<Vec<String>>(b"[\"a\",").is_err());
+    }
+
+    #[test]
+    fn test_binary_values() {
+        let cbor = vec![0xa1, 0x61, 0x61, 0x01];
+        let held = encode_value(cbor.clone(), Encoding::Cbor).unwrap();
+        assert_eq!(held, "\0cbor:oWFhAQ==");
+        assert_eq!(decode_value(&held), (cbor, Encoding::Cbor));
+        assert!(is_binary(&held));
+
+        assert_eq!(
+            encode_value(b"red".to_vec(), Encoding::Utf8),
+            Ok("red".to_string())
+        );
+        assert_eq!(decode_value("red"), (b"red".to_vec(), Encoding::Utf8));
+        assert_eq!(
+            encode_value(vec![0xff], Encoding::Utf8),
+            Err(WireError::Utf8)
+        );
+        assert_eq!(
+            encode_value(held.clone().into_bytes(), Encoding::Utf8),
+            Err(WireError::Marker)
+        );
+        //a marker without a well formed value after it is only text
+        assert!(!is_binary("\0utf8:cmVk"));
+        assert!(!is_binary("\0cbor:not base64"));
+        assert_eq!("".parse(), Ok(Encoding::Utf8));
+        assert!("bson".parse::<Encoding>().is_err());
+    }
+
+    #[test]
+    fn test_elements_round_trip() {
+        let elements = [
+            Element::from("red"),
+            Element::from(-7),
+            Element::from(2.0),
+            Element::from(f64::INFINITY),
+            Element::from(true),
+            Element::from(vec![0, 0xff]),
+        ];
+        for element in elements {
+            let (raw, element_type) = encode_element(&element);
+            assert_eq!(
+                decode_element(raw, element_type, Encoding::Utf8),
+                Ok(element.clone())
+            );
+            let json = serde_json::to_string(&element_to_json(&element)).unwrap();
+            let json: Value = serde_json::from_str(&json).unwrap();
+            assert_eq!(element_from_json(&json), Some(element));
+        }
+        assert_eq!(
+            decode_element(vec![2], "bool", Encoding::Utf8),
+            Err(WireError::Element("a bool is 0 or 1, not 2".to_string()))
+        );
+        assert!(decode_element(vec![1; 8], "int", Encoding::Cbor).is_err());
+        assert!(decode_element(vec![1; 8], "decimal", Encoding::Utf8).is_err());
+    }
+
+    proptest! {
+        #[test]
+        fn test_integers_round_trip(value in any::<i64>(), size in any::<u64>()) {
+            prop_assert_eq!(decode_i64(&encode_i64(value)), Ok(value));
+            prop_assert_eq!(decode_u64(&encode_u64(size)), Ok(size));
+            //the field wins over the bytes, which older senders fill in alone
+            prop_assert_eq!(decode_signed(Some(value), &[]), Ok(value));
+            prop_assert_eq!(decode_signed(Some(value), &encode_i64(0)), Ok(value));
+            prop_assert_eq!(decode_signed(None, &encode_i64(value)), Ok(value));
+        }
+
+        #[test]
+        fn test_strings_round_trip(value in ".*") {
+            prop_assert_eq!(decode_string(value.clone().into_bytes()), Ok(value.clone()));
+            let list = vec![value];
+            let json = serde_json::to_vec(&list).unwrap();
+            prop_assert_eq!(decode_json::<Vec<String>>(&json), Ok(list));
+        }
+
+        //whatever arrives, decoding answers with a value or an error, it never panics
+        #[test]
+        fn test_arbitrary_bytes_never_panic(raw in prop::collection::vec(any::<u8>(), 0..32)) {
+            prop_assert_eq!(decode_i64(&raw).is_ok(), raw.len() == 8);
+            prop_assert_eq!(decode_u64(&raw).is_ok(), raw.len() == 8);
+            let _ = decode_string(raw.clone());
+            let _ = decode_json::<Vec<String>>(&raw);
+        }
+    }
+}
@@ -0,0 +1,303 @@
+//golden files pinning the wire bytes of every message nodes send each other, for one
+//representative state of each crdt. a change to communication.proto that renumbers, retypes or
+//drops a field fails here instead of in a cluster that mixes builds. each file has to decode to
+//the same message (what older nodes send still reads the same) and the message has to encode to
+//the same bytes (what this build sends still reads on older nodes). a deliberate change that the
+//migrate module covers is blessed by rerunning with MERGEDB_BLESS=1 and committing the new files.
+//maps and dot sets hold at most one entry, prost writes them in hash order
+use mergedb_proto::{communication::*, migrate, CrdtProto};
+use mergedb_types::{
+    aw_set::{AWSet, Dot as AW_Dot},
+    custom::CustomValue,
+    element::Element,
+    lww_map::{Entry as MapEntry, LwwMap},
+    lww_register::{Dot as LWW_Dot, LwwRegister},
+    pn_counter::PNCounter,
+    CrdtValue,
+};
+use prost::Message;
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Debug,
+    fs,
+    path::PathBuf,
+};
+
+fn golden_path(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/golden")
+        .join(format!("{}.hex", name))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(hex: &str) -> Vec<u8> {
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).expect("golden files hold hex"))
+        .collect()
+}
+
+fn check<M: Message + Default + PartialEq + Debug>(name: &str, message: &M) {
+    let path = golden_path(name);
+    let encoded = to_hex(&message.encode_to_vec());
+    if std::env::var_os("MERGEDB_BLESS").is_some() {
+        fs::write(&path, format!("{}\n", encoded)).unwrap();
+        return;
+    }
+    let golden = fs::read_to_string(&path).unwrap_or_else(|e| {
+        panic!(
+            "{}: {}, run with MERGEDB_BLESS=1 to write it",
+            path.display(),
+            e
+        )
+    });
+    let golden = golden.trim();
+    let decoded = M::decode(from_hex(golden).as_slice())
+        .unwrap_or_else(|e| panic!("{} no longer decodes: {}", path.display(), e));
+    assert_eq!(
+        &decoded,
+        message,
+        "{} decodes to another message, older nodes' payloads would be misread",
+        path.display()
+    );
+    assert_eq!(
+        encoded,
+        golden,
+        "{} is encoded differently, older nodes may misread it. if that is deliberate, rerun \
+         with MERGEDB_BLESS=1",
+        path.display()
+    );
+}
+
+fn counter() -> CrdtValue {
+    CrdtValue::Counter(PNCounter {
+        p: HashMap::from([("node_1".to_string(), 5)]),
+        n: HashMap::from([("node_2".to_string(), 2)]),
+    })
+}
+
+//a string element added and removed, and an int element
+fn set() -> CrdtValue {
+    let apple = AW_Dot {
+        node_id: "node_1".to_string(),
+        counter: 1,
+    };
+    let seven = AW_Dot {
+        node_id: "node_2".to_string(),
+        counter: 2,
+    };
+    CrdtValue::Set(AWSet {
+        clock: 2,
+        add_tags: HashMap::from([
+            (Element::from("apple"), HashSet::from([apple.clone()])),
+            (Element::Int(-7), HashSet::from([seven.clone()])),
+        ]),
+        remove_tags: HashMap::from([(Element::from("apple"), HashSet::from([apple.clone()]))]),
+        added_at: HashMap::from([(apple, 100), (seven, 200)]),
+        hlc: 200,
+    })
+}
+
+fn register() -> CrdtValue {
+    CrdtValue::Register(LwwRegister {
+        clock: 4,
+        register_state: LWW_Dot {
+            node_id: "node_1".to_string(),
+            counter: 4,
+            register: "hello".to_string(),
+        },
+    })
+}
+
+fn map() -> CrdtValue {
+    CrdtValue::Map(LwwMap {
+        clock: 3,
+        entries: HashMap::from([(
+            "theme".to_string(),
+            MapEntry {
+                node_id: "node_1".to_string(),
+                counter: 3,
+                value: Some("dark".to_string()),
+            },
+        )]),
+    })
+}
+
+fn custom() -> CrdtValue {
+    CrdtValue::Custom(CustomValue {
+        type_tag: "acme.hll".to_string(),
+        states: vec![vec![1, 2], vec![3]],
+    })
+}
+
+fn broadcast() -> BroadcastMessage {
+    BroadcastMessage {
+        id: "node_1:1".to_string(),
+        key: "name".to_string(),
+        value: Some(register().to_proto()),
+    }
+}
+
+#[test]
+fn test_crdt_payloads() {
+    for (name, value) in [
+        ("crdt_counter", counter()),
+        ("crdt_set", set()),
+        ("crdt_register", register()),
+        ("crdt_lww_map", map()),
+        ("crdt_custom", custom()),
+    ] {
+        check(name, &value.clone().to_proto());
+        let golden = fs::read_to_string(golden_path(name)).unwrap();
+        let wire = CrdtData::decode(from_hex(golden.trim()).as_slice()).unwrap();
+        assert_eq!(CrdtValue::from_proto(wire), Ok(value), "{}", name);
+    }
+    assert_eq!(
+        migrate::FORMAT_VERSION,
+        3,
+        "add golden files for the new format"
+    );
+}
+
+//what peers still on an older format are sent
+#[test]
+fn test_older_formats() {
+    check("crdt_set_format_1", &set().to_proto_as(1));
+    check("crdt_set_format_2", &set().to_proto_as(2));
+}
+
+#[test]
+fn test_gossip_messages() {
+    check(
+        "gossip_changes",
+        &GossipChangesRequest {
+            key: "likes".to_string(),
+            counter: Some(counter().to_proto()),
+            node_id: "node_1".to_string(),
+            cluster_id: "prod".to_string(),
+        },
+    );
+    check(
+        "gossip_batch",
+        &GossipBatchRequest {
+            batch: HashMap::from([("likes".to_string(), counter().to_proto())]),
+            node_id: "node_1".to_string(),
+            cluster_id: "prod".to_string(),
+        },
+    );
+    check(
+        "gossip_digest",
+        &GossipDigestRequest {
+            digests: HashMap::from([("likes".to_string(), 42)]),
+            node_id: "node_1".to_string(),
+            cluster_id: "prod".to_string(),
+        },
+    );
+    let versions = |seq| HashMap::from([("node_1@1700".to_string(), seq)]);
+    check(
+        "reconcile",
+        &ReconcileRequest {
+            node_id: "node_1".to_string(),
+            vector: versions(9),
+            updates: vec![VersionedUpdate {
+                key: "tags".to_string(),
+                value: Some(set().to_proto()),
+                versions: versions(9),
+            }],
+            complete: versions(8),
+            expected_origin: "node_2@1701".to_string(),
+            cluster_id: "prod".to_string(),
+        },
+    );
+    check(
+        "sample_request",
+        &SampleRequest {
+            node_id: "node_1".to_string(),
+            keys: vec!["likes".to_string(), "tags".to_string()],
+            cluster_id: "prod".to_string(),
+        },
+    );
+    check(
+        "sample_response",
+        &SampleResponse {
+            states: HashMap::from([("likes".to_string(), counter().to_proto())]),
+        },
+    );
+}
+
+#[test]
+fn test_plumtree_messages() {
+    check(
+        "broadcast",
+        &BroadcastRequest {
+            node_id: "node_1".to_string(),
+            message: Some(broadcast()),
+            cluster_id: "prod".to_string(),
+        },
+    );
+    check(
+        "announce",
+        &AnnounceRequest {
+            node_id: "node_1".to_string(),
+            ids: vec!["node_1:1".to_string(), "node_1:2".to_string()],
+            cluster_id: "prod".to_string(),
+        },
+    );
+    check(
+        "graft",
+        &GraftRequest {
+            node_id: "node_1".to_string(),
+            ids: vec!["node_1:1".to_string()],
+            cluster_id: "prod".to_string(),
+        },
+    );
+    check(
+        "graft_response",
+        &GraftResponse {
+            messages: vec![broadcast()],
+        },
+    );
+}
+
+#[test]
+fn test_membership_messages() {
+    check(
+        "heartbeat_request",
+        &HeartbeatRequest {
+            node_id: "node_1".to_string(),
+            reachable: vec!["node_2".to_string(), "node_3".to_string()],
+            format_version: 3,
+            witness: true,
+            address: "10.0.0.1:8001".to_string(),
+            instance: "9f2c".to_string(),
+            cluster_id: "prod".to_string(),
+        },
+    );
+    check(
+        "heartbeat_response",
+        &HeartbeatResponse {
+            node_id: "node_2".to_string(),
+            reachable: vec!["node_1".to_string()],
+            format_version: 3,
+            witness: false,
+            peers: vec![KnownPeer {
+                node_id: "node_3".to_string(),
+                address: "10.0.0.3:8001".to_string(),
+                reachable: true,
+                last_seen_ms: 1_700_000_000_000,
+            }],
+            instance: "77ab".to_string(),
+            cluster_id: "prod".to_string(),
+        },
+    );
+    check(
+        "leave",
+        &LeaveRequest {
+            node_id: "node_1".to_string(),
+            cluster_id: "prod".to_string(),
+        },
+    );
+}
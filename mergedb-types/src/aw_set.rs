@@ -1,12 +1,13 @@
-use super::Merge;
+use super::{DeltaMerge, Merge};
+use serde::{Deserialize, Serialize};
 use std::{
     collections::{HashMap, HashSet},
     hash::Hash,
 };
-use crate::NodeId;
+use crate::{element::Element, hlc, NodeId};
 
 //Dot here is used to identify from which node the change has occurred and when(when is handled by counter)
-#[derive(Debug, Clone, Hash, PartialEq, Eq)]
+#[derive(Debug, Clone, Hash, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Dot {
     pub node_id: NodeId,
     pub counter: u64,
@@ -15,22 +16,34 @@ pub struct Dot {
 
 //add_tags structure: {"apple": {("node_1", 1), ("node_1", 5), ("node_2", 3)}}
 //similar for remove_tags
-#[derive(Debug, Clone, PartialEq)]
+//added_at keeps the hlc timestamp of every add dot, it's only metadata and never decides
+//visibility. hlc is the latest timestamp this set has seen, so new ones sort after it
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
 pub struct AWSet
 {
     pub clock: u64,      
-    pub add_tags: HashMap<String, HashSet<Dot>>,
-    pub remove_tags: HashMap<String, HashSet<Dot>>,
+    #[serde(with = "crate::pairs")]
+    pub add_tags: HashMap<Element, HashSet<Dot>>,
+    #[serde(with = "crate::pairs")]
+    pub remove_tags: HashMap<Element, HashSet<Dot>>,
+    #[serde(with = "crate::pairs")]
+    pub added_at: HashMap<Dot, hlc::Timestamp>,
+    pub hlc: hlc::Timestamp,
+}
+
+//who added a visible element and when, one per live add of that element
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ElementMeta {
+    pub added_by: NodeId,
+    pub counter: u64,
+    //0 when the add came from a node that didn't record timestamps yet
+    pub added_at: hlc::Timestamp,
 }
 
 impl AWSet
 {
     pub fn new() -> Self {
-        AWSet {
-            clock: 0,
-            add_tags: HashMap::new(),
-            remove_tags: HashMap::new(),
-        }
+        AWSet::default()
     }
     
     pub fn next_dot(&mut self, id: NodeId) -> Dot {
@@ -41,14 +54,17 @@ impl AWSet
         }
     }
 
-    pub fn add(&mut self, tag: String, id: NodeId) {
+    pub fn add(&mut self, tag: impl Into<Element>, id: NodeId) {
         let dot = self.next_dot(id);
-        self.add_tags.entry(tag).or_default().insert(dot);
+        self.hlc = hlc::tick(self.hlc);
+        self.added_at.insert(dot.clone(), self.hlc);
+        self.add_tags.entry(tag.into()).or_default().insert(dot);
     }
     
-    pub fn remove(&mut self, tag: String) {
+    pub fn remove(&mut self, tag: impl Into<Element>) {
         //all versions of the tag must be tombstoned, even if those came from additions
         //from different nodes
+        let tag = tag.into();
         if let Some(dots) = self.add_tags.get(&tag) {
             for dot in dots {
                 self.remove_tags.entry(tag.clone()).or_default().insert(dot.clone());
@@ -56,7 +72,36 @@ impl AWSet
         }
     }
     
-    pub fn read(&self) -> HashSet<String> {
+    //add returning the delta: the new dot with its timestamp, and the clocks it moved
+    pub fn add_delta(&mut self, tag: impl Into<Element>, id: NodeId) -> AWSet {
+        let tag = tag.into();
+        self.add(tag.clone(), id.clone());
+        let dot = Dot {
+            node_id: id,
+            counter: self.clock,
+        };
+        AWSet {
+            clock: self.clock,
+            added_at: HashMap::from([(dot.clone(), self.hlc)]),
+            add_tags: HashMap::from([(tag, HashSet::from([dot]))]),
+            remove_tags: HashMap::new(),
+            hlc: self.hlc,
+        }
+    }
+
+    //remove returning the delta: tombstones for the dots this replica saw, an add it hasn't
+    //seen stays visible wherever the delta goes, as with a whole state
+    pub fn remove_delta(&mut self, tag: impl Into<Element>) -> AWSet {
+        let tag = tag.into();
+        self.remove(tag.clone());
+        let mut delta = AWSet::new();
+        if let Some(dots) = self.add_tags.get(&tag) {
+            delta.remove_tags.insert(tag, dots.clone());
+        }
+        delta
+    }
+
+    pub fn read(&self) -> HashSet<Element> {
         let mut visible_elements = HashSet::new();
         
         for (tag, add_dots) in &self.add_tags {
@@ -70,12 +115,81 @@ impl AWSet
         }
         visible_elements
     }
+
+    //whether the tag is visible, without reading the rest of the set
+    pub fn contains(&self, tag: &Element) -> bool {
+        let Some(add_dots) = self.add_tags.get(tag) else {
+            return false;
+        };
+        match self.remove_tags.get(tag) {
+            Some(remove_dots) => add_dots.difference(remove_dots).next().is_some(),
+            None => !add_dots.is_empty(),
+        }
+    }
+
+    //the visible elements in Element's order, numbers by value
+    pub fn read_sorted(&self) -> Vec<Element> {
+        let mut elements: Vec<Element> = self.read().into_iter().collect();
+        elements.sort();
+        elements
+    }
+
+    //the adds that currently keep a tag visible, oldest first. empty if the tag isn't in the set
+    pub fn metadata(&self, tag: &Element) -> Vec<ElementMeta> {
+        let Some(add_dots) = self.add_tags.get(tag) else {
+            return Vec::new();
+        };
+        let removed = self.remove_tags.get(tag);
+
+        let mut meta: Vec<ElementMeta> = add_dots
+            .iter()
+            .filter(|dot| !removed.is_some_and(|removed| removed.contains(*dot)))
+            .map(|dot| ElementMeta {
+                added_by: dot.node_id.clone(),
+                counter: dot.counter,
+                added_at: self.added_at.get(dot).copied().unwrap_or(0),
+            })
+            .collect();
+        meta.sort_by_key(|meta| (meta.added_at, meta.counter));
+        meta
+    }
+
+    //removes the elements whose newest live add is oldest until at most `max` are visible, ties
+    //go by element order. it only looks at the set's own state, so replicas holding the same
+    //state evict the same elements. returns what was evicted
+    pub fn trim(&mut self, max: usize) -> Vec<Element> {
+        let visible = self.read();
+        if visible.len() <= max {
+            return Vec::new();
+        }
+        let mut by_age: Vec<(hlc::Timestamp, Element)> = visible
+            .into_iter()
+            .map(|tag| {
+                let newest = self
+                    .metadata(&tag)
+                    .last()
+                    .map_or(0, |meta| meta.added_at);
+                (newest, tag)
+            })
+            .collect();
+        by_age.sort();
+        let excess = by_age.len() - max;
+        let evicted: Vec<Element> = by_age
+            .into_iter()
+            .take(excess)
+            .map(|(_, tag)| tag)
+            .collect();
+        for tag in &evicted {
+            self.remove(tag.clone());
+        }
+        evicted
+    }
 }
 
 impl Merge for AWSet
 {
     //merging would just be union-ising the add_tags and remove_tags
-    fn merge(&mut self, other: &mut Self) {
+    fn merge(&mut self, other: &Self) {
         //merge add_tags
         for (tag, other_add_dots) in &other.add_tags {
             let self_dots = self.add_tags.entry(tag.clone()).or_default();
@@ -92,16 +206,28 @@ impl Merge for AWSet
             }
         }
         
+        //a dot's timestamp never changes once taken, so any copy of it is as good as another
+        for (dot, added_at) in &other.added_at {
+            self.added_at.entry(dot.clone()).or_insert(*added_at);
+        }
+
         //sync the self clock, lamport clock logic
         self.clock = std::cmp::max(self.clock, other.clock);
+        self.hlc = std::cmp::max(self.hlc, other.hlc);
     }
 }
 
+//add and remove tags are only ever unioned, a delta is merged like any other state
+impl DeltaMerge for AWSet {}
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    fn el(tag: &str) -> Element {
+        Element::from(tag)
+    }
+
     #[test]
     fn test_local_add_remove() {
         let node_id: NodeId = String::from("node_1");
@@ -111,15 +237,18 @@ mod tests {
         set.add("banana".to_string(), node_id);
         
         let view = set.read();
-        assert!(view.contains("apple"));
-        assert!(view.contains("banana"));
+        assert!(view.contains(&el("apple")));
+        assert!(view.contains(&el("banana")));
         assert_eq!(view.len(), 2);
 
         set.remove("apple".to_string());
         let view_after = set.read();
-        assert!(!view_after.contains("apple"));
-        assert!(view_after.contains("banana"));
+        assert!(!view_after.contains(&el("apple")));
+        assert!(view_after.contains(&el("banana")));
         assert_eq!(view_after.len(), 1);
+        assert!(!set.contains(&el("apple")));
+        assert!(set.contains(&el("banana")));
+        assert!(!set.contains(&el("cherry")));
     }
 
     #[test]
@@ -133,11 +262,11 @@ mod tests {
         replica_2.add("swimming".to_string(), node_2);
 
         //merge node_2 into node_1
-        replica_1.merge(&mut replica_2);
+        replica_1.merge(&replica_2);
 
         let view = replica_1.read();
-        assert!(view.contains("hiking"));
-        assert!(view.contains("swimming"));
+        assert!(view.contains(&el("hiking")));
+        assert!(view.contains(&el("swimming")));
         assert_eq!(view.len(), 2);
     }
 
@@ -158,21 +287,21 @@ mod tests {
 
         //Node A removes apple (Tombstones Dot (A,1))
         replica_1.remove("apple".to_string());
-        assert!(!replica_1.read().contains("apple"));
+        assert!(!replica_1.read().contains(&el("apple")));
 
         //Node B adds apple concurrently (Creates Dot (B, 2))
         //Clock is 2 as B inherited As clock of 1.
         replica_2.add("apple".to_string(), node_2);
-        assert!(replica_2.read().contains("apple"));
+        assert!(replica_2.read().contains(&el("apple")));
 
         //merge B into A
-        replica_1.merge(&mut replica_2);
+        replica_1.merge(&replica_2);
 
         // The set contains:
         // Add-Set: {(A,1), (B,2)}
         // Remove-Set: {(A,1)}
         // (B,2) is in Add but not Remove, so visible.
-        assert!(replica_1.read().contains("apple"), "Add should win over Remove in concurrency");
+        assert!(replica_1.read().contains(&el("apple")), "Add should win over Remove in concurrency");
     }
 
     #[test]
@@ -183,14 +312,14 @@ mod tests {
         
         let mut replica_2 = AWSet::new();
         
-        replica_2.merge(&mut replica_1);
-        assert!(replica_2.read().contains("apple"));
+        replica_2.merge(&replica_1);
+        assert!(replica_2.read().contains(&el("apple")));
 
         replica_1.remove("apple".to_string());
 
-        replica_2.merge(&mut replica_1);
+        replica_2.merge(&replica_1);
         
-        assert!(!replica_2.read().contains("apple"));
+        assert!(!replica_2.read().contains(&el("apple")));
     }
 
     #[test]
@@ -207,21 +336,98 @@ mod tests {
         replica_2.add("cherry".to_string(), node_2);
 
         let mut a_then_b = replica_1.clone();
-        a_then_b.merge(&mut replica_2);
+        a_then_b.merge(&replica_2);
 
         let mut b_then_a = replica_2.clone();
-        b_then_a.merge(&mut replica_1);
+        b_then_a.merge(&replica_1);
 
         //check lengths
         assert_eq!(a_then_b.read().len(), b_then_a.read().len());
         
         //check contents
         let view_a = a_then_b.read();
-        assert!(view_a.contains("banana"));
-        assert!(view_a.contains("cherry"));
-        assert!(view_a.contains("apple"));
+        assert!(view_a.contains(&el("banana")));
+        assert!(view_a.contains(&el("cherry")));
+        assert!(view_a.contains(&el("apple")));
 
         let view_b = b_then_a.read();
         assert_eq!(view_a, view_b);
     }
+
+    #[test]
+    fn test_deltas_converge_like_whole_states() {
+        let node_1: NodeId = String::from("node_1");
+        let mut replica_1 = AWSet::new();
+        let mut replica_2 = AWSet::new();
+
+        let deltas = [
+            replica_1.add_delta("apple", node_1.clone()),
+            replica_1.add_delta("banana", node_1.clone()),
+            replica_1.remove_delta("apple"),
+            replica_1.add_delta("cherry", node_1.clone()),
+        ];
+        assert_eq!(deltas[3].add_tags.len(), 1);
+
+        //the remove arriving before the add it hides still hides it
+        for delta in deltas.iter().rev() {
+            replica_2.merge_delta(delta);
+        }
+        assert_eq!(replica_2, replica_1);
+        assert_eq!(replica_2.read_sorted(), [el("banana"), el("cherry")]);
+
+        //several deltas merged into one are sent as one
+        let mut batch = replica_1.add_delta("date", node_1.clone());
+        batch.merge_delta(&replica_1.remove_delta("banana"));
+        replica_2.merge_delta(&batch);
+        assert_eq!(replica_2, replica_1);
+    }
+
+    #[test]
+    fn test_metadata_reports_live_adds() {
+        let node_1: NodeId = String::from("node_1");
+        let mut replica_1 = AWSet::new();
+        replica_1.add("apple".to_string(), node_1.clone());
+        replica_1.remove("apple".to_string());
+
+        let node_2: NodeId = String::from("node_2");
+        let mut replica_2 = AWSet::new();
+        replica_2.add("apple".to_string(), node_2.clone());
+
+        replica_1.merge(&replica_2);
+
+        //node_1's add was removed, only node_2's keeps apple alive
+        let meta = replica_1.metadata(&el("apple"));
+        assert_eq!(meta.len(), 1);
+        assert_eq!(meta[0].added_by, node_2);
+        assert_eq!(meta[0].added_at, replica_2.hlc);
+        assert!(replica_1.metadata(&el("cherry")).is_empty());
+
+        //adds made after the merge sort after everything seen so far
+        replica_1.add("apple".to_string(), node_1.clone());
+        let meta = replica_1.metadata(&el("apple"));
+        assert_eq!(meta.len(), 2);
+        assert_eq!(meta[1].added_by, node_1);
+        assert!(meta[1].added_at > meta[0].added_at);
+    }
+
+    #[test]
+    fn test_trim_evicts_the_oldest_adds() {
+        let node_1: NodeId = String::from("node_1");
+        let mut replica_1 = AWSet::new();
+        for tag in ["a", "b", "c"] {
+            replica_1.add(tag.to_string(), node_1.clone());
+        }
+        //re-adding a makes it the newest
+        replica_1.add("a".to_string(), node_1.clone());
+        let mut replica_2 = replica_1.clone();
+
+        assert_eq!(replica_1.trim(2), [el("b")]);
+        assert_eq!(replica_1.read_sorted(), [el("a"), el("c")]);
+        assert!(replica_1.trim(2).is_empty());
+
+        //a replica trimming the same state on its own agrees, and the merge keeps the view
+        assert_eq!(replica_2.trim(2), [el("b")]);
+        replica_2.merge(&replica_1);
+        assert_eq!(replica_2.read(), replica_1.read());
+    }
 }
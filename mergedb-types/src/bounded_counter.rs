@@ -0,0 +1,193 @@
+//a counter that never goes below zero cluster wide, eg stock left in an inventory. a PNCounter
+//can't promise that, two nodes that each see 1 left can both take it. here every node holds
+//rights to part of the value and only decrements what it holds: an increment gives the node
+//that made it as many rights, a decrement spends them and a transfer hands some to another
+//node (escrow), eg to one that ran out. a node's rights only ever go down through its own
+//operations, so no merge can make them negative, and since the rights add up to the value
+//neither can the value
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::{pn_counter::PNCounter, Merge, NodeId};
+
+//a decrement or transfer asked for more than the node holds, nothing was changed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NotEnoughRights {
+    pub wanted: u64,
+    pub available: u64,
+}
+
+impl std::fmt::Display for NotEnoughRights {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "not enough rights: wanted {}, this node holds {}",
+            self.wanted, self.available
+        )
+    }
+}
+
+impl std::error::Error for NotEnoughRights {}
+
+//transfers structure: {("node_1", "node_2"): 5}, every transfer from node_1 to node_2 summed up
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BoundedCounter {
+    pub counter: PNCounter,
+    #[serde(with = "crate::pairs")]
+    pub transfers: HashMap<(NodeId, NodeId), u64>,
+}
+
+impl Default for BoundedCounter {
+    fn default() -> Self {
+        BoundedCounter {
+            counter: PNCounter {
+                p: HashMap::new(),
+                n: HashMap::new(),
+            },
+            transfers: HashMap::new(),
+        }
+    }
+}
+
+impl BoundedCounter {
+    pub fn new() -> Self {
+        BoundedCounter::default()
+    }
+
+    pub fn value(&self) -> u64 {
+        //never negative, see above
+        self.counter.value() as u64
+    }
+
+    //what the node may still decrement or transfer: what it added and was given, less what
+    //it took and gave away
+    pub fn rights(&self, id: &str) -> u64 {
+        let (mut gained, mut lost) = (0, 0);
+        gained += self.counter.p.get(id).copied().unwrap_or(0);
+        lost += self.counter.n.get(id).copied().unwrap_or(0);
+        for ((from, to), amount) in &self.transfers {
+            if to == id {
+                gained += amount;
+            }
+            if from == id {
+                lost += amount;
+            }
+        }
+        gained - lost
+    }
+
+    fn spend(&self, id: &str, amount: u64) -> Result<(), NotEnoughRights> {
+        let available = self.rights(id);
+        match amount <= available {
+            true => Ok(()),
+            false => Err(NotEnoughRights {
+                wanted: amount,
+                available,
+            }),
+        }
+    }
+
+    pub fn increment(&mut self, id: NodeId, amount: u64) {
+        self.counter.increment(id, amount);
+    }
+
+    //refused unless the node holds the rights, whatever the value is
+    pub fn decrement(&mut self, id: NodeId, amount: u64) -> Result<(), NotEnoughRights> {
+        self.spend(&id, amount)?;
+        self.counter.decrement(id, amount);
+        Ok(())
+    }
+
+    //hands amount of the node's rights to another node, the value stays as it is
+    pub fn transfer(&mut self, id: NodeId, to: NodeId, amount: u64) -> Result<(), NotEnoughRights> {
+        self.spend(&id, amount)?;
+        if id != to {
+            *self.transfers.entry((id, to)).or_insert(0) += amount;
+        }
+        Ok(())
+    }
+
+    //every node holding rights, with how many
+    pub fn rights_by_node(&self) -> HashMap<NodeId, u64> {
+        let nodes = self
+            .counter
+            .p
+            .keys()
+            .chain(self.transfers.keys().map(|(_, to)| to));
+        nodes
+            .map(|id| (id.clone(), self.rights(id)))
+            .filter(|(_, rights)| *rights > 0)
+            .collect()
+    }
+}
+
+impl Merge for BoundedCounter {
+    //the counts and the transfers between each pair of nodes only grow, merging takes the max
+    //of both
+    fn merge(&mut self, other: &Self) {
+        self.counter.merge(&other.counter);
+        for (pair, amount) in &other.transfers {
+            let local = self.transfers.entry(pair.clone()).or_insert(0);
+            *local = std::cmp::max(*local, *amount);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::check_laws;
+
+    #[test]
+    fn test_decrements_need_rights() {
+        let node_1: NodeId = String::from("node_1");
+        let node_2: NodeId = String::from("node_2");
+        let mut stock = BoundedCounter::new();
+        stock.increment(node_1.clone(), 10);
+        stock.decrement(node_1.clone(), 4).unwrap();
+        assert_eq!(stock.value(), 6);
+
+        //node_2 sees the stock but holds none of it
+        assert_eq!(
+            stock.decrement(node_2.clone(), 1),
+            Err(NotEnoughRights {
+                wanted: 1,
+                available: 0
+            })
+        );
+        stock.transfer(node_1.clone(), node_2.clone(), 5).unwrap();
+        stock.decrement(node_2.clone(), 5).unwrap();
+        assert_eq!(stock.rights(&node_1), 1);
+        assert!(stock.transfer(node_1.clone(), node_2, 2).is_err());
+        assert_eq!(stock.value(), 1);
+        assert_eq!(stock.rights_by_node(), HashMap::from([(node_1, 1)]));
+    }
+
+    #[test]
+    fn test_concurrent_decrements_never_go_below_zero() {
+        let node_1: NodeId = String::from("node_1");
+        let node_2: NodeId = String::from("node_2");
+        let mut replica_1 = BoundedCounter::new();
+        replica_1.increment(node_1.clone(), 2);
+        replica_1
+            .transfer(node_1.clone(), node_2.clone(), 1)
+            .unwrap();
+        let mut replica_2 = replica_1.clone();
+
+        //each takes what it holds, and can't take more however much it sees
+        replica_1.decrement(node_1.clone(), 1).unwrap();
+        replica_2.decrement(node_2.clone(), 1).unwrap();
+        assert!(replica_1.decrement(node_1, 1).is_err());
+        assert!(replica_2.decrement(node_2, 1).is_err());
+
+        replica_1.merge(&replica_2);
+        replica_2.merge(&replica_1);
+        assert_eq!(replica_1, replica_2);
+        assert_eq!(replica_1.value(), 0);
+    }
+
+    #[test]
+    fn test_bounded_counter_obeys_the_laws() {
+        check_laws::<BoundedCounter>(1, 50);
+    }
+}
@@ -0,0 +1,228 @@
+//order-independent hashing of crdt state: two replicas that converged hash equally, however
+//their maps were filled. entries are sorted before they are hashed, and everything goes in
+//as little-endian bytes with length prefixes, so the result only depends on the hasher.
+//bookkeeping that never decides what is read or how a merge resolves (clocks used to mint the
+//next dot, hlc timestamps, zero counts) is left out
+use std::{
+    collections::{HashMap, HashSet},
+    hash::Hasher,
+};
+
+use crate::{
+    aw_set, custom,
+    element::{self, Element},
+    lww_map, lww_register, pn_counter, CrdtValue,
+};
+
+pub trait CanonicalHash {
+    fn canonical_hash<H: Hasher>(&self, state: &mut H);
+}
+
+fn write_u64<H: Hasher>(state: &mut H, value: u64) {
+    state.write(&value.to_le_bytes());
+}
+
+fn write_str<H: Hasher>(state: &mut H, value: &str) {
+    write_u64(state, value.len() as u64);
+    state.write(value.as_bytes());
+}
+
+//a count per node, without the nodes that are at 0, a counter created with zeroes is the same
+//as one that never heard of the node
+fn write_counts<H: Hasher>(state: &mut H, counts: &HashMap<String, u64>) {
+    let mut entries: Vec<(&String, &u64)> = counts.iter().filter(|(_, n)| **n > 0).collect();
+    entries.sort();
+    write_u64(state, entries.len() as u64);
+    for (node, count) in entries {
+        write_str(state, node);
+        write_u64(state, *count);
+    }
+}
+
+//a string element goes in as any string does, so sets of strings hash as they did before typed
+//elements. the others start with a length no string can have, then their kind and value
+fn write_element<H: Hasher>(state: &mut H, element: &Element) {
+    let (kind, value) = match element {
+        Element::Str(tag) => return write_str(state, tag),
+        Element::Int(value) => (0, value.to_le_bytes().to_vec()),
+        Element::Float(value) => (1, element::normalized(*value).to_le_bytes().to_vec()),
+        Element::Bool(value) => (2, vec![*value as u8]),
+        Element::Bytes(bytes) => (3, bytes.clone()),
+    };
+    write_u64(state, u64::MAX);
+    write_u64(state, kind);
+    write_u64(state, value.len() as u64);
+    state.write(&value);
+}
+
+//tag -> dots, without tags whose dot set is empty
+fn write_tags<H: Hasher>(state: &mut H, tags: &HashMap<Element, HashSet<aw_set::Dot>>) {
+    let mut entries: Vec<(&Element, Vec<(&str, u64)>)> = tags
+        .iter()
+        .filter(|(_, dots)| !dots.is_empty())
+        .map(|(tag, dots)| {
+            let mut dots: Vec<(&str, u64)> = dots
+                .iter()
+                .map(|dot| (dot.node_id.as_str(), dot.counter))
+                .collect();
+            dots.sort();
+            (tag, dots)
+        })
+        .collect();
+    entries.sort();
+    write_u64(state, entries.len() as u64);
+    for (tag, dots) in entries {
+        write_element(state, tag);
+        write_u64(state, dots.len() as u64);
+        for (node, counter) in dots {
+            write_str(state, node);
+            write_u64(state, counter);
+        }
+    }
+}
+
+impl CanonicalHash for pn_counter::PNCounter {
+    fn canonical_hash<H: Hasher>(&self, state: &mut H) {
+        write_counts(state, &self.p);
+        write_counts(state, &self.n);
+    }
+}
+
+impl CanonicalHash for aw_set::AWSet {
+    fn canonical_hash<H: Hasher>(&self, state: &mut H) {
+        write_tags(state, &self.add_tags);
+        write_tags(state, &self.remove_tags);
+    }
+}
+
+//the winning write decides both the value and every later merge
+impl CanonicalHash for lww_register::LwwRegister {
+    fn canonical_hash<H: Hasher>(&self, state: &mut H) {
+        write_str(state, &self.register_state.node_id);
+        write_u64(state, self.register_state.counter);
+        write_str(state, &self.register_state.register);
+    }
+}
+
+//removed fields too, their stamps still decide merges
+impl CanonicalHash for lww_map::LwwMap {
+    fn canonical_hash<H: Hasher>(&self, state: &mut H) {
+        let mut entries: Vec<(&String, &lww_map::Entry)> = self.entries.iter().collect();
+        entries.sort_by_key(|(field, _)| *field);
+        write_u64(state, entries.len() as u64);
+        for (field, entry) in entries {
+            write_str(state, field);
+            write_str(state, &entry.node_id);
+            write_u64(state, entry.counter);
+            match &entry.value {
+                Some(value) => {
+                    write_u64(state, 1);
+                    write_str(state, value);
+                }
+                None => write_u64(state, 0),
+            }
+        }
+    }
+}
+
+//the states are kept sorted already
+impl CanonicalHash for custom::CustomValue {
+    fn canonical_hash<H: Hasher>(&self, state: &mut H) {
+        write_str(state, &self.type_tag);
+        write_u64(state, self.states.len() as u64);
+        for custom_state in &self.states {
+            write_u64(state, custom_state.len() as u64);
+            state.write(custom_state);
+        }
+    }
+}
+
+//the type goes in first, so an empty set and an empty counter don't collide
+impl CanonicalHash for CrdtValue {
+    fn canonical_hash<H: Hasher>(&self, state: &mut H) {
+        write_str(state, self.type_name());
+        match self {
+            CrdtValue::Counter(counter) => counter.canonical_hash(state),
+            CrdtValue::Register(register) => register.canonical_hash(state),
+            CrdtValue::Set(set) => set.canonical_hash(state),
+            CrdtValue::Map(map) => map.canonical_hash(state),
+            CrdtValue::Custom(custom) => custom.canonical_hash(state),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Merge;
+    use std::collections::hash_map::DefaultHasher;
+
+    fn hash_of(value: &impl CanonicalHash) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        value.canonical_hash(&mut hasher);
+        hasher.finish()
+    }
+
+    #[test]
+    fn test_merge_order_does_not_change_the_hash() {
+        let nodes = ["node_1", "node_2", "node_3", "node_4", "node_5"];
+        let replicas: Vec<pn_counter::PNCounter> = nodes
+            .iter()
+            .enumerate()
+            .map(|(i, node)| pn_counter::PNCounter::new(node.to_string(), i as u64 + 1, 1))
+            .collect();
+
+        let mut forwards = replicas[0].clone();
+        for replica in &replicas[1..] {
+            forwards.merge(replica);
+        }
+        let mut backwards = replicas[4].clone();
+        for replica in replicas[..4].iter().rev() {
+            backwards.merge(replica);
+        }
+        assert_eq!(hash_of(&forwards), hash_of(&backwards));
+
+        //a zero count for a node is no count at all
+        let mut with_zero = forwards.clone();
+        with_zero.p.insert("node_9".to_string(), 0);
+        assert_eq!(hash_of(&forwards), hash_of(&with_zero));
+        forwards.increment("node_1".to_string(), 1);
+        assert_ne!(hash_of(&forwards), hash_of(&with_zero));
+    }
+
+    #[test]
+    fn test_sets_hash_by_dots_not_insertion_order() {
+        let mut a = aw_set::AWSet::new();
+        let mut b = aw_set::AWSet::new();
+        for tag in ["x", "y", "z"] {
+            a.add(tag.to_string(), "node_1".to_string());
+        }
+        b.merge(&a);
+        b.hlc += 100;
+        b.clock += 100;
+        assert_eq!(hash_of(&a), hash_of(&b));
+
+        b.remove("y".to_string());
+        assert_ne!(hash_of(&a), hash_of(&b));
+        a.merge(&b);
+        assert_eq!(hash_of(&a), hash_of(&b));
+    }
+
+    #[test]
+    fn test_types_do_not_collide() {
+        let counter = CrdtValue::Counter(pn_counter::PNCounter::new("node_1".to_string(), 0, 0));
+        let set = CrdtValue::Set(aw_set::AWSet::new());
+        assert_ne!(hash_of(&counter), hash_of(&set));
+        let map = CrdtValue::Map(lww_map::LwwMap::new());
+        assert_ne!(hash_of(&map), hash_of(&set));
+
+        let mut first = lww_register::LwwRegister::new("node_1".to_string());
+        first.set("v".to_string(), "node_1".to_string());
+        let mut second = lww_register::LwwRegister::new("node_2".to_string());
+        second.set("v".to_string(), "node_2".to_string());
+        assert_ne!(hash_of(&first), hash_of(&second));
+        second.merge(&first);
+        first.merge(&second);
+        assert_eq!(hash_of(&first), hash_of(&second));
+    }
+}
@@ -0,0 +1,235 @@
+//causal context, what a replica has seen. a VersionVector holds the highest counter seen from
+//each node and stands for every dot of that node up to it, so it only works while each node's
+//dots arrive in order, as they do when whole states are merged (the Orswot, the MVRegister).
+//a DotContext adds the dots that arrived past a gap, eg from deltas or a partial sync, and folds
+//them into its vector once the gap is filled. both merge by union, and compare tells whether one
+//side has seen everything the other has, which is what digest-based anti-entropy needs to skip
+//peers that have nothing new
+use serde::{Deserialize, Serialize};
+use std::{
+    cmp::Ordering,
+    collections::{HashMap, HashSet},
+};
+
+use crate::{aw_set::Dot, Merge, NodeId};
+
+//counters structure: {"node_1": 3, "node_2": 1}, a node that isn't there is at 0
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct VersionVector {
+    pub counters: HashMap<NodeId, u64>,
+}
+
+impl VersionVector {
+    pub fn new() -> Self {
+        VersionVector::default()
+    }
+
+    pub fn get(&self, node_id: &str) -> u64 {
+        self.counters.get(node_id).copied().unwrap_or(0)
+    }
+
+    //the node's next dot, which the vector has seen from then on
+    pub fn increment(&mut self, id: NodeId) -> Dot {
+        let counter = self.counters.entry(id.clone()).or_insert(0);
+        *counter += 1;
+        Dot {
+            node_id: id,
+            counter: *counter,
+        }
+    }
+
+    pub fn contains(&self, dot: &Dot) -> bool {
+        self.get(&dot.node_id) >= dot.counter
+    }
+
+    //seen everything the other has seen, equal vectors dominate each other
+    pub fn dominates(&self, other: &VersionVector) -> bool {
+        other
+            .counters
+            .iter()
+            .all(|(node_id, counter)| self.get(node_id) >= *counter)
+    }
+
+    //Less when other has seen more, None when each has seen something the other hasn't
+    pub fn compare(&self, other: &VersionVector) -> Option<Ordering> {
+        match (self.dominates(other), other.dominates(self)) {
+            (true, true) => Some(Ordering::Equal),
+            (true, false) => Some(Ordering::Greater),
+            (false, true) => Some(Ordering::Less),
+            (false, false) => None,
+        }
+    }
+
+    pub fn is_concurrent(&self, other: &VersionVector) -> bool {
+        self.compare(other).is_none()
+    }
+}
+
+impl Merge for VersionVector {
+    fn merge(&mut self, other: &Self) {
+        for (node_id, counter) in &other.counters {
+            let local = self.counters.entry(node_id.clone()).or_insert(0);
+            *local = std::cmp::max(*local, *counter);
+        }
+    }
+}
+
+//clock covers every node's dots up to its counter, cloud the dots seen past a gap. compact keeps
+//the cloud to dots the clock doesn't cover and can't take yet, so two contexts that have seen
+//the same dots are equal
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct DotContext {
+    pub clock: VersionVector,
+    pub cloud: HashSet<Dot>,
+}
+
+impl DotContext {
+    pub fn new() -> Self {
+        DotContext::default()
+    }
+
+    pub fn contains(&self, dot: &Dot) -> bool {
+        self.clock.contains(dot) || self.cloud.contains(dot)
+    }
+
+    //a new local dot, after every dot of this node the context has seen
+    pub fn next_dot(&mut self, id: NodeId) -> Dot {
+        let counter = self
+            .cloud
+            .iter()
+            .filter(|dot| dot.node_id == id)
+            .map(|dot| dot.counter)
+            .fold(self.clock.get(&id), std::cmp::max);
+        let dot = Dot {
+            node_id: id,
+            counter: counter + 1,
+        };
+        self.insert(dot.clone());
+        dot
+    }
+
+    //a dot seen from elsewhere, in any order
+    pub fn insert(&mut self, dot: Dot) {
+        if !self.clock.contains(&dot) {
+            self.cloud.insert(dot);
+            self.compact();
+        }
+    }
+
+    //moves the cloud's dots that follow on from the clock into it, drops the ones it covers
+    pub fn compact(&mut self) {
+        loop {
+            let mut moved = false;
+            self.cloud.retain(|dot| {
+                let counter = self.clock.get(&dot.node_id);
+                if dot.counter == counter + 1 {
+                    self.clock.counters.insert(dot.node_id.clone(), dot.counter);
+                    moved = true;
+                }
+                dot.counter > counter + 1
+            });
+            if !moved {
+                return;
+            }
+        }
+    }
+
+    //seen every dot the other has seen
+    pub fn dominates(&self, other: &DotContext) -> bool {
+        let clock = other.clock.counters.iter().all(|(node_id, counter)| {
+            //a compacted cloud never holds the dot right after the clock, so a context behind
+            //on a node fails on its first missing dot
+            (self.clock.get(node_id) + 1..=*counter).all(|counter| {
+                self.contains(&Dot {
+                    node_id: node_id.clone(),
+                    counter,
+                })
+            })
+        });
+        clock && other.cloud.iter().all(|dot| self.contains(dot))
+    }
+
+    //as on the VersionVector
+    pub fn compare(&self, other: &DotContext) -> Option<Ordering> {
+        match (self.dominates(other), other.dominates(self)) {
+            (true, true) => Some(Ordering::Equal),
+            (true, false) => Some(Ordering::Greater),
+            (false, true) => Some(Ordering::Less),
+            (false, false) => None,
+        }
+    }
+}
+
+impl Merge for DotContext {
+    fn merge(&mut self, other: &Self) {
+        self.clock.merge(&other.clock);
+        self.cloud.extend(other.cloud.iter().cloned());
+        let clock = &self.clock;
+        self.cloud.retain(|dot| !clock.contains(dot));
+        self.compact();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::check_laws;
+
+    fn dot(node_id: &str, counter: u64) -> Dot {
+        Dot {
+            node_id: node_id.to_string(),
+            counter,
+        }
+    }
+
+    #[test]
+    fn test_version_vectors_compare() {
+        let mut a = VersionVector::new();
+        a.increment("node_1".to_string());
+        let mut b = a.clone();
+        assert_eq!(a.compare(&b), Some(Ordering::Equal));
+
+        b.increment("node_2".to_string());
+        assert_eq!(a.compare(&b), Some(Ordering::Less));
+        assert_eq!(b.compare(&a), Some(Ordering::Greater));
+        assert!(b.contains(&dot("node_2", 1)));
+        assert!(!a.contains(&dot("node_2", 1)));
+
+        a.increment("node_1".to_string());
+        assert!(a.is_concurrent(&b));
+        a.merge(&b);
+        assert!(a.dominates(&b));
+        assert_eq!(a.get("node_1"), 2);
+        assert_eq!(a.get("node_3"), 0);
+    }
+
+    #[test]
+    fn test_dot_context_fills_gaps() {
+        let mut context = DotContext::new();
+        context.insert(dot("node_1", 1));
+        context.insert(dot("node_1", 3));
+        assert!(context.contains(&dot("node_1", 3)));
+        assert!(!context.contains(&dot("node_1", 2)));
+        assert_eq!(context.cloud, HashSet::from([dot("node_1", 3)]));
+
+        let mut behind = DotContext::new();
+        behind.insert(dot("node_1", 1));
+        assert_eq!(context.compare(&behind), Some(Ordering::Greater));
+        behind.insert(dot("node_1", 2));
+        assert_eq!(context.compare(&behind), None);
+
+        context.merge(&behind);
+        assert_eq!(context.clock.get("node_1"), 3);
+        assert!(context.cloud.is_empty());
+        //the next local dot comes after the ones seen past a gap
+        context.insert(dot("node_2", 2));
+        assert_eq!(context.next_dot("node_2".to_string()), dot("node_2", 3));
+    }
+
+    #[test]
+    fn test_causal_contexts_obey_the_laws() {
+        check_laws::<VersionVector>(1, 50);
+        check_laws::<DotContext>(1, 50);
+    }
+}
@@ -0,0 +1,269 @@
+//crdts defined outside this crate. a custom value travels as its type tag plus the type's own
+//encoding of its state, the node never looks inside. what a tag decodes to and how two states
+//merge comes from the CustomCrdt registered for it, usually a Plugin wrapped by register():
+//  impl Plugin for Hll { const TAG: &'static str = "acme.hll"; fn encode(..); fn decode(..) }
+//  custom::register::<Hll>();
+//a node that hasn't registered a tag still stores and gossips its values, it just can't merge
+//them. it keeps every distinct state it was sent instead, which is a crdt of its own (a set
+//union), and folds them into one as soon as the tag is registered
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fmt,
+    marker::PhantomData,
+    sync::{Arc, OnceLock, RwLock},
+};
+
+use crate::Merge;
+
+//the type erased side, what the registry holds
+pub trait CustomCrdt: Send + Sync {
+    fn type_tag(&self) -> &str;
+    //both states in the type's encoding, so is the result
+    fn merge(&self, local: &[u8], remote: &[u8]) -> Result<Vec<u8>, String>;
+    //for INFO, materialized views and scripts
+    fn display(&self, state: &[u8]) -> String {
+        format!("<{}, {} bytes>", self.type_tag(), state.len())
+    }
+}
+
+//the typed side, what a downstream crdt implements
+pub trait Plugin: Merge + Clone + Send + Sync + 'static {
+    //unique across the cluster, a prefix of your own (acme.) keeps it from clashing
+    const TAG: &'static str;
+    fn encode(&self) -> Vec<u8>;
+    fn decode(bytes: &[u8]) -> Result<Self, String>;
+    fn display(&self) -> String {
+        format!("<{}>", Self::TAG)
+    }
+}
+
+struct Typed<T>(PhantomData<fn() -> T>);
+
+impl<T: Plugin> CustomCrdt for Typed<T> {
+    fn type_tag(&self) -> &str {
+        T::TAG
+    }
+
+    fn merge(&self, local: &[u8], remote: &[u8]) -> Result<Vec<u8>, String> {
+        let mut local = T::decode(local)?;
+        local.merge(&T::decode(remote)?);
+        Ok(local.encode())
+    }
+
+    fn display(&self, state: &[u8]) -> String {
+        match T::decode(state) {
+            Ok(value) => value.display(),
+            Err(e) => format!("<{}, undecodable: {}>", T::TAG, e),
+        }
+    }
+}
+
+fn registry() -> &'static RwLock<HashMap<String, Arc<dyn CustomCrdt>>> {
+    static REGISTRY: OnceLock<RwLock<HashMap<String, Arc<dyn CustomCrdt>>>> = OnceLock::new();
+    REGISTRY.get_or_init(Default::default)
+}
+
+//for the whole process, a second registration of a tag replaces the first
+pub fn register_crdt(crdt: Arc<dyn CustomCrdt>) {
+    registry()
+        .write()
+        .unwrap()
+        .insert(crdt.type_tag().to_string(), crdt);
+}
+
+pub fn register<T: Plugin>() {
+    register_crdt(Arc::new(Typed::<T>(PhantomData)));
+}
+
+pub fn registered(type_tag: &str) -> Option<Arc<dyn CustomCrdt>> {
+    registry().read().unwrap().get(type_tag).cloned()
+}
+
+//sorted, so converged replicas hash alike
+pub fn registered_tags() -> Vec<String> {
+    let mut tags: Vec<String> = registry().read().unwrap().keys().cloned().collect();
+    tags.sort();
+    tags
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CustomError {
+    //the value holds another type
+    WrongType { expected: String, found: String },
+    Decode(String),
+}
+
+impl fmt::Display for CustomError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CustomError::WrongType { expected, found } => {
+                write!(f, "expected a {}, got a {}", expected, found)
+            }
+            CustomError::Decode(e) => write!(f, "custom value does not decode: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for CustomError {}
+
+//states holds exactly one state once the tag is registered, every distinct one seen so far
+//otherwise, sorted and without duplicates
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CustomValue {
+    pub type_tag: String,
+    pub states: Vec<Vec<u8>>,
+}
+
+impl CustomValue {
+    pub fn new(type_tag: impl Into<String>, state: Vec<u8>) -> Self {
+        CustomValue {
+            type_tag: type_tag.into(),
+            states: vec![state],
+        }
+    }
+
+    pub fn of<T: Plugin>(value: &T) -> Self {
+        CustomValue::new(T::TAG, value.encode())
+    }
+
+    //the states merged into one value
+    pub fn decode<T: Plugin>(&self) -> Result<T, CustomError> {
+        if self.type_tag != T::TAG {
+            return Err(CustomError::WrongType {
+                expected: T::TAG.to_string(),
+                found: self.type_tag.clone(),
+            });
+        }
+        let mut states = self.states.iter().map(|state| T::decode(state));
+        let mut value = match states.next() {
+            Some(first) => first.map_err(CustomError::Decode)?,
+            None => return Err(CustomError::Decode("no state".to_string())),
+        };
+        for state in states {
+            value.merge(&state.map_err(CustomError::Decode)?);
+        }
+        Ok(value)
+    }
+
+    //whether this process can merge it
+    pub fn is_known(&self) -> bool {
+        registered(&self.type_tag).is_some()
+    }
+
+    pub fn display(&self) -> String {
+        match (registered(&self.type_tag), self.states.as_slice()) {
+            (Some(crdt), [state]) => crdt.display(state),
+            _ => format!("<{}, {} unmerged states>", self.type_tag, self.states.len()),
+        }
+    }
+
+    pub fn estimated_size(&self) -> usize {
+        16 + self.type_tag.len() + self.states.iter().map(Vec::len).sum::<usize>()
+    }
+
+    //folds the states into one when the tag is registered. a state that fails to merge leaves
+    //them all as they are, nothing is dropped
+    fn collapse(&mut self) {
+        if self.states.len() < 2 {
+            return;
+        }
+        let Some(crdt) = registered(&self.type_tag) else {
+            return;
+        };
+        let mut states = self.states.iter();
+        let Some(first) = states.next() else {
+            return;
+        };
+        let mut merged = first.clone();
+        for state in states {
+            match crdt.merge(&merged, state) {
+                Ok(next) => merged = next,
+                Err(_) => return,
+            }
+        }
+        self.states = vec![merged];
+    }
+}
+
+//the tags have to match, CrdtValue::merge_with refuses values of two custom types
+impl Merge for CustomValue {
+    fn merge(&mut self, other: &Self) {
+        self.states.extend(other.states.iter().cloned());
+        self.states.sort();
+        self.states.dedup();
+        self.collapse();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{check_laws, Generate, Rng};
+    use crate::NodeId;
+
+    //a max register, the smallest crdt there is
+    #[derive(Debug, Clone, PartialEq)]
+    struct Max(u64);
+
+    impl Merge for Max {
+        fn merge(&mut self, other: &Self) {
+            self.0 = self.0.max(other.0);
+        }
+    }
+
+    impl Plugin for Max {
+        const TAG: &'static str = "test.max";
+
+        fn encode(&self) -> Vec<u8> {
+            self.0.to_le_bytes().to_vec()
+        }
+
+        fn decode(bytes: &[u8]) -> Result<Self, String> {
+            let bytes: [u8; 8] = bytes.try_into().map_err(|_| "not 8 bytes".to_string())?;
+            Ok(Max(u64::from_le_bytes(bytes)))
+        }
+
+        fn display(&self) -> String {
+            self.0.to_string()
+        }
+    }
+
+    impl Generate for CustomValue {
+        fn empty(_node: &NodeId) -> Self {
+            CustomValue::of(&Max(0))
+        }
+
+        fn random_op(&mut self, rng: &mut Rng, _node: &NodeId) {
+            self.merge(&CustomValue::of(&Max(rng.below(100))));
+        }
+    }
+
+    #[test]
+    fn test_registered_types_merge() {
+        register::<Max>();
+        let mut local = CustomValue::of(&Max(3));
+        local.merge(&CustomValue::of(&Max(7)));
+        assert_eq!(local.states.len(), 1);
+        assert_eq!(local.decode::<Max>(), Ok(Max(7)));
+        assert_eq!(local.display(), "7");
+        assert!(registered_tags().contains(&"test.max".to_string()));
+        check_laws::<CustomValue>(1, 20);
+    }
+
+    #[test]
+    fn test_unknown_types_keep_every_state() {
+        let mut local = CustomValue::new("test.unknown", vec![2]);
+        local.merge(&CustomValue::new("test.unknown", vec![1]));
+        local.merge(&CustomValue::new("test.unknown", vec![2]));
+        assert!(!local.is_known());
+        assert_eq!(local.states, vec![vec![1], vec![2]]);
+        assert_eq!(local.display(), "<test.unknown, 2 unmerged states>");
+
+        let other = CustomValue::new("test.other", vec![1]);
+        assert!(matches!(
+            other.decode::<Max>(),
+            Err(CustomError::WrongType { .. })
+        ));
+    }
+}
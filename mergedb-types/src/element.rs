@@ -0,0 +1,212 @@
+//a set element: a string, like every element used to be, or an integer, float, bool or bytes so
+//that numeric membership sets compare as numbers instead of as text. each kind is its own
+//element, 1 and 1.0 and "1" are three different members
+use serde::{Deserialize, Serialize};
+use std::{
+    cmp::Ordering,
+    fmt,
+    hash::{Hash, Hasher},
+};
+
+//written as {"str": "apple"}, {"int": 1}, {"float": 1.0} and so on, so the kind survives. json
+//has no nan or infinity, those floats only come back from formats that do
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Element {
+    Str(String),
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+    Bytes(Vec<u8>),
+}
+
+impl Element {
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Element::Str(_) => "string",
+            Element::Int(_) => "int",
+            Element::Float(_) => "float",
+            Element::Bool(_) => "bool",
+            Element::Bytes(_) => "bytes",
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Element::Str(tag) => Some(tag),
+            _ => None,
+        }
+    }
+
+    //rough in-memory footprint
+    pub fn size(&self) -> usize {
+        match self {
+            Element::Str(tag) => tag.len(),
+            Element::Bytes(bytes) => bytes.len(),
+            Element::Int(_) | Element::Float(_) => 8,
+            Element::Bool(_) => 1,
+        }
+    }
+
+    //the order sorted reads use: numbers by value (an int before an equal float), then false
+    //before true, then strings, then bytes
+    fn rank(&self) -> u8 {
+        match self {
+            Element::Int(_) | Element::Float(_) => 0,
+            Element::Bool(_) => 1,
+            Element::Str(_) => 2,
+            Element::Bytes(_) => 3,
+        }
+    }
+}
+
+//-0.0 is 0.0 and every nan is the same nan, so equal floats are one member
+pub fn normalized(value: f64) -> f64 {
+    if value == 0.0 {
+        0.0
+    } else if value.is_nan() {
+        f64::NAN
+    } else {
+        value
+    }
+}
+
+impl PartialEq for Element {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Element {}
+
+impl Hash for Element {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match self {
+            Element::Str(tag) => (0u8, tag).hash(state),
+            Element::Int(value) => (1u8, value).hash(state),
+            Element::Float(value) => (2u8, normalized(*value).to_bits()).hash(state),
+            Element::Bool(value) => (3u8, value).hash(state),
+            Element::Bytes(bytes) => (4u8, bytes).hash(state),
+        }
+    }
+}
+
+impl PartialOrd for Element {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Element {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (Element::Str(a), Element::Str(b)) => a.cmp(b),
+            (Element::Int(a), Element::Int(b)) => a.cmp(b),
+            (Element::Float(a), Element::Float(b)) => normalized(*a).total_cmp(&normalized(*b)),
+            (Element::Int(a), Element::Float(b)) => {
+                (*a as f64).total_cmp(&normalized(*b)).then(Ordering::Less)
+            }
+            (Element::Float(a), Element::Int(b)) => normalized(*a)
+                .total_cmp(&(*b as f64))
+                .then(Ordering::Greater),
+            (Element::Bool(a), Element::Bool(b)) => a.cmp(b),
+            (Element::Bytes(a), Element::Bytes(b)) => a.cmp(b),
+            _ => self.rank().cmp(&other.rank()),
+        }
+    }
+}
+
+//floats keep their point (1.0, not 1) and bytes show as hex, so kinds don't read alike
+impl fmt::Display for Element {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Element::Str(tag) => f.write_str(tag),
+            Element::Int(value) => write!(f, "{}", value),
+            Element::Float(value) => write!(f, "{:?}", value),
+            Element::Bool(value) => write!(f, "{}", value),
+            Element::Bytes(bytes) => {
+                f.write_str("0x")?;
+                bytes.iter().try_for_each(|byte| write!(f, "{:02x}", byte))
+            }
+        }
+    }
+}
+
+impl From<String> for Element {
+    fn from(tag: String) -> Self {
+        Element::Str(tag)
+    }
+}
+
+impl From<&str> for Element {
+    fn from(tag: &str) -> Self {
+        Element::Str(tag.to_string())
+    }
+}
+
+impl From<i64> for Element {
+    fn from(value: i64) -> Self {
+        Element::Int(value)
+    }
+}
+
+impl From<f64> for Element {
+    fn from(value: f64) -> Self {
+        Element::Float(value)
+    }
+}
+
+impl From<bool> for Element {
+    fn from(value: bool) -> Self {
+        Element::Bool(value)
+    }
+}
+
+impl From<Vec<u8>> for Element {
+    fn from(bytes: Vec<u8>) -> Self {
+        Element::Bytes(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_kinds_are_distinct_members() {
+        let members: HashSet<Element> = [
+            Element::from(1),
+            Element::from(1.0),
+            Element::from("1"),
+            Element::from(0.0),
+            Element::from(-0.0),
+            Element::from(f64::NAN),
+            Element::from(-f64::NAN),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(members.len(), 5);
+    }
+
+    #[test]
+    fn test_numbers_sort_by_value() {
+        let mut members = vec![
+            Element::from("10"),
+            Element::from(10),
+            Element::from(true),
+            Element::from(2.5),
+            Element::from(vec![0x01]),
+            Element::from(-3),
+            Element::from(2),
+            Element::from(2.0),
+            Element::from("9"),
+        ];
+        members.sort();
+        let shown: Vec<String> = members.iter().map(Element::to_string).collect();
+        assert_eq!(
+            shown,
+            ["-3", "2", "2.0", "2.5", "10", "true", "10", "9", "0x01"]
+        );
+    }
+}
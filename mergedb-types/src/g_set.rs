@@ -0,0 +1,80 @@
+//a grow-only set, elements are added and never removed. merging is a plain union, so the set
+//carries nothing but its elements: no dots, no tombstones. for sets that only ever grow, eg
+//the ids of events already seen, it is far lighter than the AWSet
+use serde::{Deserialize, Serialize};
+use std::{collections::HashSet, hash::Hash};
+
+use crate::Merge;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GSet<T: Eq + Hash> {
+    pub elements: HashSet<T>,
+}
+
+impl<T: Eq + Hash + Clone> Default for GSet<T> {
+    fn default() -> Self {
+        GSet {
+            elements: HashSet::new(),
+        }
+    }
+}
+
+impl<T: Eq + Hash + Clone> GSet<T> {
+    pub fn new() -> Self {
+        GSet::default()
+    }
+
+    //true if the element wasn't in the set yet
+    pub fn add(&mut self, element: T) -> bool {
+        self.elements.insert(element)
+    }
+
+    pub fn contains(&self, element: &T) -> bool {
+        self.elements.contains(element)
+    }
+
+    pub fn read(&self) -> &HashSet<T> {
+        &self.elements
+    }
+
+    pub fn len(&self) -> usize {
+        self.elements.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.elements.is_empty()
+    }
+}
+
+impl<T: Eq + Hash + Clone> Merge for GSet<T> {
+    fn merge(&mut self, other: &Self) {
+        self.elements.extend(other.elements.iter().cloned());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{element::Element, testing::check_laws};
+
+    #[test]
+    fn test_replicas_end_up_with_every_add() {
+        let mut replica_1 = GSet::new();
+        let mut replica_2 = GSet::new();
+        assert!(replica_1.add(Element::from("apple")));
+        assert!(!replica_1.add(Element::from("apple")));
+        replica_2.add(Element::from("banana"));
+        replica_2.add(Element::Int(7));
+
+        replica_1.merge(&replica_2);
+        replica_2.merge(&replica_1);
+        assert_eq!(replica_1, replica_2);
+        assert_eq!(replica_1.len(), 3);
+        assert!(replica_2.contains(&Element::from("apple")));
+    }
+
+    #[test]
+    fn test_g_set_obeys_the_laws() {
+        check_laws::<GSet<Element>>(1, 50);
+    }
+}
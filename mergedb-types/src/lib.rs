@@ -1,16 +1,243 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+
 pub mod aw_set;
+pub mod bounded_counter;
+pub mod canonical;
+pub mod causal;
+pub mod custom;
+pub mod element;
+pub mod g_set;
+pub mod hlc;
+pub mod lww_map;
 pub mod lww_register;
+pub mod mv_register;
+pub mod or_map;
+pub mod orswot;
+pub mod pairs;
 pub mod pn_counter;
+pub mod rga;
+pub mod testing;
+pub mod two_p_set;
 
 pub type NodeId = String;
 
 pub trait Merge {
-    fn merge(&mut self, other: &mut Self);
+    fn merge(&mut self, other: &Self);
+}
+
+//functional style merge, leaves both inputs untouched
+pub fn merged<T: Merge + Clone>(a: &T, b: &T) -> T {
+    let mut result = a.clone();
+    result.merge(b);
+    result
+}
+
+//delta-state replication: a type's *_delta mutators make the change and return only the part
+//of the state it touched, as a state of the same type. merging a delta in is then as good as
+//merging the whole state it was cut from, in any order and any number of times, so a node can
+//send deltas, or several merged into one, instead of the whole object
+pub trait DeltaMerge: Merge + Sized {
+    fn merge_delta(&mut self, delta: &Self) {
+        self.merge(delta);
+    }
 }
 
 //this enum is the value, so mergeDB really would be storing key : CrdtValue
+//a new crdt gets a variant here and an arm in each method below, the node only goes through these
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum CrdtValue {
     Counter(pn_counter::PNCounter),
     Register(lww_register::LwwRegister),
-    Set(aw_set::AWSet), //for now its String
+    Set(aw_set::AWSet), //of element::Element
+    Map(lww_map::LwwMap),
+    //types registered by whoever embeds the node, see custom
+    Custom(custom::CustomValue),
+}
+
+//a key holds one kind of crdt, merging in another kind is refused instead of guessed at
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TypeMismatch {
+    pub expected: &'static str,
+    pub found: &'static str,
+}
+
+impl std::fmt::Display for TypeMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "type mismatch: expected a {}, got a {}",
+            self.expected, self.found
+        )
+    }
+}
+
+impl std::error::Error for TypeMismatch {}
+
+impl CrdtValue {
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            CrdtValue::Counter(_) => "counter",
+            CrdtValue::Register(_) => "register",
+            CrdtValue::Set(_) => "set",
+            CrdtValue::Map(_) => "map",
+            CrdtValue::Custom(_) => "custom",
+        }
+    }
+
+    //the error merge_with would give for other, without merging anything
+    pub fn check_merge(&self, other: &CrdtValue) -> Result<(), TypeMismatch> {
+        let same = match (self, other) {
+            (CrdtValue::Custom(local), CrdtValue::Custom(remote)) => {
+                local.type_tag == remote.type_tag
+            }
+            _ => std::mem::discriminant(self) == std::mem::discriminant(other),
+        };
+        match same {
+            true => Ok(()),
+            false => Err(TypeMismatch {
+                expected: self.type_name(),
+                found: other.type_name(),
+            }),
+        }
+    }
+
+    //merges other into self, Ok(true) when that changed anything
+    pub fn merge_with(&mut self, other: &CrdtValue) -> Result<bool, TypeMismatch> {
+        let changed = match (&mut *self, other) {
+            (CrdtValue::Counter(local), CrdtValue::Counter(remote)) => merge_changed(local, remote),
+            (CrdtValue::Register(local), CrdtValue::Register(remote)) => {
+                merge_changed(local, remote)
+            }
+            (CrdtValue::Set(local), CrdtValue::Set(remote)) => merge_changed(local, remote),
+            (CrdtValue::Map(local), CrdtValue::Map(remote)) => merge_changed(local, remote),
+            (CrdtValue::Custom(local), CrdtValue::Custom(remote))
+                if local.type_tag == remote.type_tag =>
+            {
+                merge_changed(local, remote)
+            }
+            _ => {
+                return Err(TypeMismatch {
+                    expected: self.type_name(),
+                    found: other.type_name(),
+                })
+            }
+        };
+        Ok(changed)
+    }
+
+    //rough in-memory footprint, good enough to spot which keys are heavy
+    pub fn estimated_size(&self) -> usize {
+        match self {
+            CrdtValue::Counter(counter) => counter
+                .p
+                .keys()
+                .chain(counter.n.keys())
+                .map(|node| node.len() + 8)
+                .sum(),
+            CrdtValue::Register(reg) => {
+                16 + reg.register_state.node_id.len() + reg.register_state.register.len()
+            }
+            CrdtValue::Set(set) => {
+                16 + tags_size(&set.add_tags) + tags_size(&set.remove_tags) + set.added_at.len() * 8
+            }
+            CrdtValue::Map(map) => 16 + fields_size(&map.entries),
+            CrdtValue::Custom(custom) => custom.estimated_size(),
+        }
+    }
+}
+
+fn tags_size(tags: &HashMap<element::Element, HashSet<aw_set::Dot>>) -> usize {
+    tags.iter()
+        .map(|(tag, dots)| tag.size() + dots.iter().map(|dot| dot.node_id.len() + 8).sum::<usize>())
+        .sum()
+}
+
+fn fields_size(entries: &HashMap<String, lww_map::Entry>) -> usize {
+    entries
+        .iter()
+        .map(|(field, entry)| {
+            let value = entry.value.as_ref().map_or(0, String::len);
+            field.len() + entry.node_id.len() + 8 + value
+        })
+        .sum()
+}
+
+fn merge_changed<T: Merge + Clone + PartialEq>(local: &mut T, remote: &T) -> bool {
+    let old_state = local.clone();
+    local.merge(remote);
+    *local != old_state
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{node_ids, random_replicas, Generate, Rng};
+    use serde::de::DeserializeOwned;
+
+    fn round_trips<T: Generate + Serialize + DeserializeOwned>() {
+        let replicas: Vec<T> = random_replicas(&mut Rng::new(1), &node_ids(3), 30);
+        for replica in replicas {
+            let json = serde_json::to_string(&replica).unwrap();
+            assert_eq!(
+                serde_json::from_str::<T>(&json).unwrap(),
+                replica,
+                "{}",
+                json
+            );
+        }
+    }
+
+    #[test]
+    fn test_every_crdt_round_trips_through_json() {
+        round_trips::<pn_counter::PNCounter>();
+        round_trips::<bounded_counter::BoundedCounter>();
+        round_trips::<lww_register::LwwRegister>();
+        round_trips::<lww_map::LwwMap>();
+        round_trips::<mv_register::MVRegister>();
+        round_trips::<aw_set::AWSet>();
+        round_trips::<g_set::GSet<element::Element>>();
+        round_trips::<two_p_set::TwoPSet<element::Element>>();
+        round_trips::<orswot::Orswot>();
+        round_trips::<rga::Rga>();
+        round_trips::<or_map::ORMap<String, pn_counter::PNCounter>>();
+    }
+
+    #[test]
+    fn test_json_field_names() {
+        let counter = CrdtValue::Counter(pn_counter::PNCounter::new("n1".to_string(), 2, 0));
+        assert_eq!(
+            serde_json::to_value(&counter).unwrap(),
+            serde_json::json!({"counter": {"p": {"n1": 2}, "n": {"n1": 0}}})
+        );
+
+        let mut set = aw_set::AWSet::new();
+        set.add(7, "n1".to_string());
+        let json = serde_json::to_value(CrdtValue::Set(set.clone())).unwrap();
+        let dot = serde_json::json!({"node_id": "n1", "counter": 1});
+        assert_eq!(
+            json["set"]["add_tags"],
+            serde_json::json!([[{"int": 7}, [dot]]])
+        );
+        assert_eq!(
+            serde_json::from_value::<CrdtValue>(json).unwrap(),
+            CrdtValue::Set(set)
+        );
+    }
+
+    #[test]
+    fn test_merge_with_reports_changes_and_mismatches() {
+        let mut local = CrdtValue::Counter(pn_counter::PNCounter::new("node_1".to_string(), 1, 0));
+        let remote = CrdtValue::Counter(pn_counter::PNCounter::new("node_2".to_string(), 2, 0));
+
+        assert_eq!(local.check_merge(&remote), Ok(()));
+        assert_eq!(local.merge_with(&remote), Ok(true));
+        assert_eq!(local.merge_with(&remote), Ok(false));
+
+        let set = CrdtValue::Set(aw_set::AWSet::new());
+        let err = local.merge_with(&set).unwrap_err();
+        assert_eq!((err.expected, err.found), ("counter", "set"));
+        assert_eq!(local.check_merge(&set), Err(err));
+    }
 }
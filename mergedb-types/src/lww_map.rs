@@ -0,0 +1,159 @@
+//a map of string fields where every field is a last-writer-wins register of its own, eg a
+//config hash. lighter than the ORMap: no dots per key, one stamp per field, and a concurrent
+//write and remove of a field are decided like two writes, the later stamp wins. a removed field
+//keeps its stamp (as a tombstone) so that an older write merged in later doesn't bring it back
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::{Merge, NodeId};
+
+//the stamp orders by counter, then by node_id, the same tiebreak as the LwwRegister
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Entry {
+    pub node_id: NodeId,
+    pub counter: u64,
+    //None once removed
+    pub value: Option<String>,
+}
+
+impl Entry {
+    fn wins_over(&self, other: &Entry) -> bool {
+        (self.counter, &self.node_id) > (other.counter, &other.node_id)
+    }
+}
+
+//entries structure: {"theme": ("node_1", 3, Some("dark")), "beta": ("node_2", 5, None)}
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct LwwMap {
+    //the highest counter seen, lamport clock logic as in the LwwRegister
+    pub clock: u64,
+    pub entries: HashMap<String, Entry>,
+}
+
+impl LwwMap {
+    pub fn new() -> Self {
+        LwwMap::default()
+    }
+
+    fn write(&mut self, field: String, value: Option<String>, id: NodeId) {
+        self.clock += 1;
+        let entry = Entry {
+            node_id: id,
+            counter: self.clock,
+            value,
+        };
+        self.entries.insert(field, entry);
+    }
+
+    pub fn set(&mut self, field: String, value: String, id: NodeId) {
+        self.write(field, Some(value), id);
+    }
+
+    //stamped like a write, a no-op for a field that was never set
+    pub fn remove(&mut self, field: &str, id: NodeId) {
+        if self.contains_key(field) {
+            self.write(field.to_string(), None, id);
+        }
+    }
+
+    pub fn get(&self, field: &str) -> Option<&str> {
+        self.entries.get(field)?.value.as_deref()
+    }
+
+    pub fn contains_key(&self, field: &str) -> bool {
+        self.get(field).is_some()
+    }
+
+    //the fields that are set and their values, sorted by field
+    pub fn read(&self) -> Vec<(&str, &str)> {
+        let mut fields: Vec<(&str, &str)> = self
+            .entries
+            .iter()
+            .filter_map(|(field, entry)| Some((field.as_str(), entry.value.as_deref()?)))
+            .collect();
+        fields.sort();
+        fields
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries
+            .values()
+            .filter(|entry| entry.value.is_some())
+            .count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Merge for LwwMap {
+    //field by field, whichever stamp is higher
+    fn merge(&mut self, other: &Self) {
+        for (field, other_entry) in &other.entries {
+            match self.entries.get_mut(field) {
+                Some(local) if !other_entry.wins_over(local) => {}
+                Some(local) => *local = other_entry.clone(),
+                None => {
+                    self.entries.insert(field.clone(), other_entry.clone());
+                }
+            }
+        }
+        self.clock = std::cmp::max(self.clock, other.clock);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::check_laws;
+
+    #[test]
+    fn test_local_set_remove() {
+        let node_1: NodeId = String::from("node_1");
+        let mut map = LwwMap::new();
+        map.set("theme".to_string(), "dark".to_string(), node_1.clone());
+        map.set("lang".to_string(), "en".to_string(), node_1.clone());
+        map.set("theme".to_string(), "light".to_string(), node_1.clone());
+        assert_eq!(map.get("theme"), Some("light"));
+        assert_eq!(map.read(), [("lang", "en"), ("theme", "light")]);
+
+        map.remove("lang", node_1.clone());
+        map.remove("missing", node_1);
+        assert_eq!(map.get("lang"), None);
+        assert_eq!(map.len(), 1);
+        assert!(!map.entries.contains_key("missing"));
+    }
+
+    #[test]
+    fn test_fields_merge_on_their_own() {
+        let node_1: NodeId = String::from("node_1");
+        let node_2: NodeId = String::from("node_2");
+        let mut replica_1 = LwwMap::new();
+        replica_1.set("theme".to_string(), "dark".to_string(), node_1.clone());
+        let stale = replica_1.clone();
+        let mut replica_2 = replica_1.clone();
+
+        replica_1.set("lang".to_string(), "en".to_string(), node_1.clone());
+        replica_2.set("theme".to_string(), "light".to_string(), node_2.clone());
+        replica_1.merge(&replica_2);
+        replica_2.merge(&replica_1);
+        assert_eq!(replica_1, replica_2);
+        assert_eq!(replica_1.read(), [("lang", "en"), ("theme", "light")]);
+
+        //same counter on both, the higher node_id wins
+        replica_1.remove("lang", node_1);
+        replica_2.set("lang".to_string(), "de".to_string(), node_2);
+        replica_1.merge(&replica_2);
+        assert_eq!(replica_1.get("lang"), Some("de"));
+
+        //an older write doesn't bring a value back
+        replica_1.merge(&stale);
+        assert_eq!(replica_1.get("theme"), Some("light"));
+    }
+
+    #[test]
+    fn test_lww_map_obeys_the_laws() {
+        check_laws::<LwwMap>(1, 50);
+    }
+}
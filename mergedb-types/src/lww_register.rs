@@ -2,10 +2,11 @@
 
 //methods supported: get, set, append, strlen
 
-use super::Merge;
+use super::{DeltaMerge, Merge};
 use crate::NodeId;
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone, Hash, PartialEq, Eq)]
+#[derive(Debug, Clone, Hash, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Dot {
     pub node_id: NodeId,
     pub counter: u64,
@@ -13,7 +14,7 @@ pub struct Dot {
 }
 
 //register_state structure: ("node_1", 1, "name1")
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct LwwRegister {
     pub clock: u64,
     pub register_state: Dot,
@@ -51,22 +52,34 @@ impl LwwRegister {
         self.set(chosen_value, id);
     }
     
+    //set returning the delta. the register holds nothing but the winning write, so that is the
+    //whole register
+    pub fn set_delta(&mut self, register: String, id: NodeId) -> LwwRegister {
+        self.set(register, id);
+        self.clone()
+    }
+
+    pub fn append_delta(&mut self, to_append: String, id: NodeId) -> LwwRegister {
+        self.append(to_append, id);
+        self.clone()
+    }
+
     pub fn strlen(&self) -> usize {
         self.get().len()
     }
 }
 
 impl Merge for LwwRegister {
-    fn merge(&mut self, other: &mut Self) {
+    fn merge(&mut self, other: &Self) {
         //union-ise the register_states
         if self.register_state.counter < other.register_state.counter {
             self.register_state = other.register_state.clone();
         }
         //if equal clocks, then determine based on node ids
-        if self.register_state.counter == other.register_state.counter {
-            if other.register_state.node_id > self.register_state.node_id {
-                self.register_state = other.register_state.clone();
-            }
+        if self.register_state.counter == other.register_state.counter
+            && other.register_state.node_id > self.register_state.node_id
+        {
+            self.register_state = other.register_state.clone();
         }
         
         //sync the clocks
@@ -74,6 +87,7 @@ impl Merge for LwwRegister {
     }
 }
 
+impl DeltaMerge for LwwRegister {}
 
 #[cfg(test)]
 mod tests {
@@ -118,7 +132,7 @@ mod tests {
         r2.clock = 10; 
         r2.set("Value B".to_string(), node_2);
 
-        r1.merge(&mut r2);
+        r1.merge(&r2);
 
         assert_eq!(r1.get(), "Value B");
     }
@@ -140,14 +154,14 @@ mod tests {
 
         assert_eq!(r1.register_state.counter, r2.register_state.counter);
 
-        r1.merge(&mut r2);
+        r1.merge(&r2);
         assert_eq!(r1.get(), "Won Value", "node_2 should win because 'node_2' > 'node_1'");
 
         //verify commutativity
         let mut r1_reset = LwwRegister::new(String::from("node_1"));
         r1_reset.set("Lost Value".to_string(), String::from("node_1"));
         
-        r2.merge(&mut r1_reset);
+        r2.merge(&r1_reset);
         assert_eq!(r2.get(), "Won Value", "node_2 should stay because it beats node_1");
     }
 
@@ -162,10 +176,10 @@ mod tests {
         r2.set("Banana".to_string(), node_2); 
 
         let mut a_then_b = r1.clone();
-        a_then_b.merge(&mut r2.clone());
+        a_then_b.merge(&r2);
 
         let mut b_then_a = r2.clone();
-        b_then_a.merge(&mut r1.clone());
+        b_then_a.merge(&r1);
 
         assert_eq!(
             a_then_b.get(), 
@@ -176,6 +190,22 @@ mod tests {
         assert_eq!(a_then_b.clock, b_then_a.clock);
     }
     
+    #[test]
+    fn test_deltas_converge_like_whole_states() {
+        let node_1 = String::from("node_1");
+        let mut r1 = LwwRegister::new(node_1.clone());
+        let mut r2 = LwwRegister::new(String::from("node_2"));
+
+        let set = r1.set_delta("Hello".to_string(), node_1.clone());
+        let append = r1.append_delta(", World".to_string(), node_1);
+
+        //the older delta arriving late doesn't undo the newer one
+        r2.merge_delta(&append);
+        r2.merge_delta(&set);
+        assert_eq!(r2.get(), "Hello, World");
+        assert_eq!(r2, r1);
+    }
+
     #[test]
     fn test_outdated_update_ignored() {
         let node_1 = String::from("node_1");
@@ -189,7 +219,7 @@ mod tests {
         
         r2.set("Old Value".to_string(), node_2);
 
-        r1.merge(&mut r2);
+        r1.merge(&r2);
 
         assert_eq!(r1.get(), "Future Value");
     }
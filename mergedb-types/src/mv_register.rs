@@ -0,0 +1,130 @@
+//a register that keeps every value written concurrently instead of picking one the way the
+//LwwRegister does. each write is tagged with a dot and replaces the values its replica has
+//seen, the context (a causal::VersionVector) records which dots a replica has seen. a merge
+//keeps a value unless the other side has seen its dot and already replaced it, so writes that
+//didn't see each other all survive as siblings until a later write, eg resolve, replaces them
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::{aw_set::Dot, causal::VersionVector, Merge, NodeId};
+
+//values structure: {("node_1", 3): "ada", ("node_2", 1): "grace"}, context: {"node_1": 3, ...}
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct MVRegister {
+    #[serde(with = "crate::pairs")]
+    pub values: HashMap<Dot, String>,
+    pub context: VersionVector,
+}
+
+impl MVRegister {
+    pub fn new() -> Self {
+        MVRegister::default()
+    }
+
+    fn seen(&self, dot: &Dot) -> bool {
+        self.context.contains(dot)
+    }
+
+    //replaces every value this replica has seen, concurrent writes elsewhere stay siblings
+    pub fn set(&mut self, value: String, id: NodeId) {
+        let dot = self.context.increment(id);
+        self.values.clear();
+        self.values.insert(dot, value);
+    }
+
+    //the siblings with their dots, oldest first, ties by node_id so every replica lists them
+    //in the same order
+    pub fn siblings(&self) -> Vec<(&Dot, &String)> {
+        let mut siblings: Vec<_> = self.values.iter().collect();
+        siblings.sort_by(|(a, _), (b, _)| (a.counter, &a.node_id).cmp(&(b.counter, &b.node_id)));
+        siblings
+    }
+
+    //the values alone, in siblings' order. empty before the first write
+    pub fn get(&self) -> Vec<&String> {
+        self.siblings()
+            .into_iter()
+            .map(|(_, value)| value)
+            .collect()
+    }
+
+    pub fn is_conflicted(&self) -> bool {
+        self.values.len() > 1
+    }
+
+    //collapses the siblings into the one value pick makes of them, written as a regular set
+    pub fn resolve(&mut self, id: NodeId, pick: impl FnOnce(&[&String]) -> String) {
+        let value = pick(&self.get());
+        self.set(value, id);
+    }
+}
+
+impl Merge for MVRegister {
+    fn merge(&mut self, other: &Self) {
+        //a value only one side holds was replaced on the other if the other has seen its dot
+        self.values
+            .retain(|dot, _| other.values.contains_key(dot) || !other.seen(dot));
+        for (dot, value) in &other.values {
+            if !self.seen(dot) {
+                self.values.insert(dot.clone(), value.clone());
+            }
+        }
+        self.context.merge(&other.context);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::check_laws;
+
+    #[test]
+    fn test_local_set_get() {
+        let node_1: NodeId = String::from("node_1");
+        let mut reg = MVRegister::new();
+        assert!(reg.get().is_empty());
+
+        reg.set("Hello".to_string(), node_1.clone());
+        reg.set("World".to_string(), node_1);
+        assert_eq!(reg.get(), ["World"]);
+        assert!(!reg.is_conflicted());
+    }
+
+    #[test]
+    fn test_concurrent_writes_are_siblings() {
+        let node_1: NodeId = String::from("node_1");
+        let node_2: NodeId = String::from("node_2");
+        let mut replica_1 = MVRegister::new();
+        replica_1.set("ada".to_string(), node_1.clone());
+        let stale = replica_1.clone();
+        let mut replica_2 = replica_1.clone();
+
+        replica_1.set("grace".to_string(), node_1);
+        replica_2.set("alan".to_string(), node_2.clone());
+        replica_1.merge(&replica_2);
+        replica_2.merge(&replica_1);
+        assert_eq!(replica_1, replica_2);
+        assert!(replica_1.is_conflicted());
+        assert_eq!(replica_1.get(), ["alan", "grace"]);
+        assert_eq!(replica_1.siblings()[0].0.node_id, "node_2");
+
+        //a write that has seen both replaces both
+        replica_2.resolve(node_2, |siblings| {
+            siblings
+                .iter()
+                .map(|s| s.as_str())
+                .collect::<Vec<_>>()
+                .join("+")
+        });
+        replica_1.merge(&replica_2);
+        assert_eq!(replica_1.get(), ["alan+grace"]);
+        //an older state merged in doesn't bring replaced values back
+        replica_1.merge(&stale);
+        assert_eq!(replica_1.get(), ["alan+grace"]);
+    }
+
+    #[test]
+    fn test_mv_register_obeys_the_laws() {
+        check_laws::<MVRegister>(1, 50);
+    }
+}
@@ -0,0 +1,240 @@
+//a map whose values are crdts themselves, eg a hash of counters per field. keys come and go the
+//way elements do in the AWSet: every add or update of a key tags it with a dot, a remove
+//tombstones the dots it has seen, and a key stays visible while one of its dots isn't
+//tombstoned, so an update concurrent with a remove wins. the values are merged key by key.
+//a removed value isn't thrown away (another replica may still be updating it), a key that is
+//updated again after a remove comes back with everything it held before
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    hash::Hash,
+};
+
+use crate::{aw_set::Dot, Merge, NodeId};
+
+//add_tags structure: {"views": {("node_1", 1), ("node_2", 4)}}, similar for remove_tags
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(bound(
+    serialize = "K: Serialize, V: Serialize",
+    deserialize = "K: Deserialize<'de>, V: Deserialize<'de>"
+))]
+pub struct ORMap<K: Eq + Hash, V> {
+    pub clock: u64,
+    #[serde(with = "crate::pairs")]
+    pub entries: HashMap<K, V>,
+    #[serde(with = "crate::pairs")]
+    pub add_tags: HashMap<K, HashSet<Dot>>,
+    #[serde(with = "crate::pairs")]
+    pub remove_tags: HashMap<K, HashSet<Dot>>,
+}
+
+impl<K: Eq + Hash + Clone, V: Merge + Clone> Default for ORMap<K, V> {
+    fn default() -> Self {
+        ORMap {
+            clock: 0,
+            entries: HashMap::new(),
+            add_tags: HashMap::new(),
+            remove_tags: HashMap::new(),
+        }
+    }
+}
+
+impl<K: Eq + Hash + Clone, V: Merge + Clone> ORMap<K, V> {
+    pub fn new() -> Self {
+        ORMap::default()
+    }
+
+    fn tag(&mut self, key: K, id: NodeId) {
+        self.clock += 1;
+        let dot = Dot {
+            node_id: id,
+            counter: self.clock,
+        };
+        self.add_tags.entry(key).or_default().insert(dot);
+    }
+
+    //adds the key if it isn't there and merges value into what it holds
+    pub fn add(&mut self, key: K, value: &V, id: NodeId) {
+        match self.entries.get_mut(&key) {
+            Some(local) => local.merge(value),
+            None => {
+                self.entries.insert(key.clone(), value.clone());
+            }
+        }
+        self.tag(key, id);
+    }
+
+    //a local op on the key's value, made on `empty()` when the map doesn't hold the key yet
+    pub fn update(
+        &mut self,
+        key: K,
+        id: NodeId,
+        empty: impl FnOnce() -> V,
+        op: impl FnOnce(&mut V),
+    ) {
+        op(self.entries.entry(key.clone()).or_insert_with(empty));
+        self.tag(key, id);
+    }
+
+    pub fn remove(&mut self, key: &K) {
+        if let Some(dots) = self.add_tags.get(key) {
+            self.remove_tags
+                .entry(key.clone())
+                .or_default()
+                .extend(dots.iter().cloned());
+        }
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        let Some(add_dots) = self.add_tags.get(key) else {
+            return false;
+        };
+        match self.remove_tags.get(key) {
+            Some(remove_dots) => add_dots.difference(remove_dots).next().is_some(),
+            None => !add_dots.is_empty(),
+        }
+    }
+
+    //None for a key that was never added or has been removed
+    pub fn get(&self, key: &K) -> Option<&V> {
+        if !self.contains_key(key) {
+            return None;
+        }
+        self.entries.get(key)
+    }
+
+    //the visible keys and their values, in no particular order
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.entries
+            .iter()
+            .filter(|(key, _)| self.contains_key(key))
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &K> {
+        self.iter().map(|(key, _)| key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.iter().count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.iter().next().is_none()
+    }
+}
+
+impl<K: Eq + Hash + Clone, V: Merge + Clone> Merge for ORMap<K, V> {
+    fn merge(&mut self, other: &Self) {
+        for (key, other_value) in &other.entries {
+            match self.entries.get_mut(key) {
+                Some(local) => local.merge(other_value),
+                None => {
+                    self.entries.insert(key.clone(), other_value.clone());
+                }
+            }
+        }
+        for (key, dots) in &other.add_tags {
+            self.add_tags
+                .entry(key.clone())
+                .or_default()
+                .extend(dots.iter().cloned());
+        }
+        for (key, dots) in &other.remove_tags {
+            self.remove_tags
+                .entry(key.clone())
+                .or_default()
+                .extend(dots.iter().cloned());
+        }
+        self.clock = std::cmp::max(self.clock, other.clock);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::check_laws;
+    use crate::{
+        aw_set::AWSet, element::Element, lww_register::LwwRegister, pn_counter::PNCounter,
+    };
+
+    fn counter(node: &str) -> PNCounter {
+        PNCounter::new(node.to_string(), 0, 0)
+    }
+
+    #[test]
+    fn test_update_and_remove() {
+        let node_1: NodeId = String::from("node_1");
+        let mut map: ORMap<String, PNCounter> = ORMap::new();
+        map.update(
+            "views".to_string(),
+            node_1.clone(),
+            || counter("node_1"),
+            |c| c.increment(node_1.clone(), 3),
+        );
+        map.update(
+            "likes".to_string(),
+            node_1.clone(),
+            || counter("node_1"),
+            |c| c.decrement(node_1.clone(), 1),
+        );
+        assert_eq!(map.get(&"views".to_string()).map(PNCounter::value), Some(3));
+        assert_eq!(map.len(), 2);
+
+        map.remove(&"likes".to_string());
+        assert!(!map.contains_key(&"likes".to_string()));
+        assert_eq!(map.get(&"likes".to_string()), None);
+        assert_eq!(map.keys().collect::<Vec<_>>(), vec!["views"]);
+        map.remove(&"unknown".to_string());
+        assert_eq!(map.len(), 1);
+    }
+
+    #[test]
+    fn test_values_merge_per_key() {
+        let mut replica_1: ORMap<String, AWSet> = ORMap::new();
+        let mut replica_2 = replica_1.clone();
+        let mut tags = AWSet::new();
+        tags.add("red", "node_1".to_string());
+        replica_1.add("colors".to_string(), &tags, "node_1".to_string());
+        let mut tags = AWSet::new();
+        tags.add("blue", "node_2".to_string());
+        replica_2.add("colors".to_string(), &tags, "node_2".to_string());
+
+        replica_1.merge(&replica_2);
+        let colors = replica_1.get(&"colors".to_string()).unwrap();
+        assert_eq!(
+            colors.read_sorted(),
+            [Element::from("blue"), Element::from("red")]
+        );
+    }
+
+    #[test]
+    fn test_update_wins_over_concurrent_remove() {
+        let mut replica_1: ORMap<String, LwwRegister> = ORMap::new();
+        replica_1.update(
+            "name".to_string(),
+            "node_1".to_string(),
+            || LwwRegister::new("node_1".to_string()),
+            |r| r.set("ada".to_string(), "node_1".to_string()),
+        );
+        let mut replica_2 = replica_1.clone();
+
+        replica_1.remove(&"name".to_string());
+        replica_2.update(
+            "name".to_string(),
+            "node_2".to_string(),
+            || LwwRegister::new("node_2".to_string()),
+            |r| r.append("!".to_string(), "node_2".to_string()),
+        );
+
+        replica_1.merge(&replica_2);
+        replica_2.merge(&replica_1);
+        assert_eq!(replica_1, replica_2);
+        assert_eq!(replica_1.get(&"name".to_string()).unwrap().get(), "ada!");
+    }
+
+    #[test]
+    fn test_or_map_obeys_the_laws() {
+        check_laws::<ORMap<String, PNCounter>>(1, 50);
+        check_laws::<ORMap<String, AWSet>>(1, 50);
+    }
+}
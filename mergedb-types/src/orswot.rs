@@ -0,0 +1,120 @@
+//an observed-remove set without tombstones, the AWSet's add-wins behaviour at a fraction of its
+//memory. the AWSet remembers every removed dot forever, here a remove just drops the element
+//and its dots, and the context (a causal::VersionVector, as in the MVRegister) records which
+//dots a replica has seen. a merge keeps a dot only one side holds unless the other side has seen it,
+//in which case the other side removed it, so removed elements are gone from memory for good.
+//add, remove, contains and read work the way they do on the AWSet
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+
+use crate::{aw_set::Dot, causal::VersionVector, element::Element, Merge, NodeId};
+
+//entries structure: {"apple": {("node_1", 3), ("node_2", 1)}}, context: {"node_1": 3, ...}
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct Orswot {
+    #[serde(with = "crate::pairs")]
+    pub entries: HashMap<Element, HashSet<Dot>>,
+    pub context: VersionVector,
+}
+
+impl Orswot {
+    pub fn new() -> Self {
+        Orswot::default()
+    }
+
+    fn seen(&self, dot: &Dot) -> bool {
+        self.context.contains(dot)
+    }
+
+    //the new dot replaces the element's others, the context already covers them
+    pub fn add(&mut self, tag: impl Into<Element>, id: NodeId) {
+        let dot = self.context.increment(id);
+        self.entries.insert(tag.into(), HashSet::from([dot]));
+    }
+
+    //an add this replica hasn't seen yet survives it
+    pub fn remove(&mut self, tag: impl Into<Element>) {
+        self.entries.remove(&tag.into());
+    }
+
+    pub fn contains(&self, tag: &Element) -> bool {
+        self.entries.contains_key(tag)
+    }
+
+    pub fn read(&self) -> HashSet<Element> {
+        self.entries.keys().cloned().collect()
+    }
+
+    //the elements in Element's order, numbers by value
+    pub fn read_sorted(&self) -> Vec<Element> {
+        let mut elements: Vec<Element> = self.entries.keys().cloned().collect();
+        elements.sort();
+        elements
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+impl Merge for Orswot {
+    fn merge(&mut self, other: &Self) {
+        let empty = HashSet::new();
+        //a dot only one side holds was removed on the other if the other has seen it
+        for (tag, dots) in self.entries.iter_mut() {
+            let theirs = other.entries.get(tag).unwrap_or(&empty);
+            dots.retain(|dot| theirs.contains(dot) || !other.seen(dot));
+        }
+        for (tag, dots) in &other.entries {
+            let fresh: Vec<&Dot> = dots.iter().filter(|dot| !self.seen(dot)).collect();
+            if !fresh.is_empty() {
+                let local = self.entries.entry(tag.clone()).or_default();
+                local.extend(fresh.into_iter().cloned());
+            }
+        }
+        self.entries.retain(|_, dots| !dots.is_empty());
+        self.context.merge(&other.context);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::check_laws;
+
+    #[test]
+    fn test_removed_elements_leave_nothing_behind() {
+        let node_1: NodeId = String::from("node_1");
+        let node_2: NodeId = String::from("node_2");
+        let mut replica_1 = Orswot::new();
+        replica_1.add("apple", node_1.clone());
+        replica_1.add("banana", node_1.clone());
+        let mut replica_2 = replica_1.clone();
+
+        replica_2.remove("apple");
+        replica_1.merge(&replica_2);
+        assert_eq!(replica_1.read_sorted(), [Element::from("banana")]);
+        assert_eq!(replica_1.entries.len(), 1);
+
+        //an add concurrent with a remove wins, as on the AWSet
+        replica_1.add("banana", node_1);
+        replica_2.remove("banana");
+        replica_2.add("cherry", node_2);
+        replica_2.merge(&replica_1);
+        replica_1.merge(&replica_2);
+        assert_eq!(replica_1, replica_2);
+        assert_eq!(
+            replica_1.read_sorted(),
+            [Element::from("banana"), Element::from("cherry")]
+        );
+    }
+
+    #[test]
+    fn test_orswot_obeys_the_laws() {
+        check_laws::<Orswot>(1, 50);
+    }
+}
@@ -0,0 +1,23 @@
+//json objects only take string keys, so maps keyed by an element, a dot or a pair of node ids
+//are written as a list of [key, value] pairs instead, with #[serde(with = "crate::pairs")]
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::{collections::HashMap, hash::Hash};
+
+pub fn serialize<K, V, S>(map: &HashMap<K, V>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    K: Serialize,
+    V: Serialize,
+    S: Serializer,
+{
+    serializer.collect_seq(map)
+}
+
+pub fn deserialize<'de, K, V, D>(deserializer: D) -> Result<HashMap<K, V>, D::Error>
+where
+    K: Deserialize<'de> + Eq + Hash,
+    V: Deserialize<'de>,
+    D: Deserializer<'de>,
+{
+    let pairs: Vec<(K, V)> = Vec::deserialize(deserializer)?;
+    Ok(pairs.into_iter().collect())
+}
@@ -1,5 +1,6 @@
-use super::Merge;
-use std::collections::HashMap;
+use super::{DeltaMerge, Merge};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
 use std::cmp;
 use crate::NodeId;
 
@@ -11,7 +12,7 @@ use crate::NodeId;
 //{p: {"node_a": 2, "node_b": 1}, n: 0}. This is obtained by taking the max across the nodes for the value 
 //of p or n, and the union-ising it. Then the final value reflected will be 2 + 1 = 3. 
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct PNCounter {
     pub p: HashMap<NodeId, u64>,
     pub n: HashMap<NodeId, u64>,
@@ -19,21 +20,25 @@ pub struct PNCounter {
 
 impl Merge for PNCounter {
     //when merged, both the replicas get to a common state
-    fn merge(&mut self, other: &mut Self) {
+    fn merge(&mut self, other: &Self) {
         //merge positive counts
         for (node, cnt) in other.p.iter() {
             let entry = self.p.entry(node.clone()).or_insert(0);
-            *entry = cmp::max(*entry, cnt.clone());
+            *entry = cmp::max(*entry, *cnt);
         }
         
         //merge negative counts
         for (node, cnt) in other.n.iter() {
             let entry = self.n.entry(node.clone()).or_insert(0);
-            *entry = cmp::max(*entry, cnt.clone());
+            *entry = cmp::max(*entry, *cnt);
         }
     }
 }
 
+//a delta carries counts as they are after the change, not the change itself, so the max in merge
+//doesn't count it twice
+impl DeltaMerge for PNCounter {}
+
 impl PNCounter {
     pub fn new(node_id: String, p: u64, n: u64) -> Self {
         PNCounter { p: HashMap::from([(node_id.clone(), p)]), n: HashMap::from([(node_id.clone(), n)]) }
@@ -47,12 +52,91 @@ impl PNCounter {
         *self.n.entry(node_id).or_insert(0) += amt;
     }
 
-    //for the user of the node to see the value of the counter
+    //increment returning the delta, only this node's new positive count
+    pub fn increment_delta(&mut self, node_id: String, amt: u64) -> PNCounter {
+        self.increment(node_id.clone(), amt);
+        let p = HashMap::from([(node_id.clone(), self.p[&node_id])]);
+        PNCounter { p, n: HashMap::new() }
+    }
+
+    //decrement returning the delta, only this node's new negative count
+    pub fn decrement_delta(&mut self, node_id: String, amt: u64) -> PNCounter {
+        self.decrement(node_id.clone(), amt);
+        let n = HashMap::from([(node_id.clone(), self.n[&node_id])]);
+        PNCounter { p: HashMap::new(), n }
+    }
+
+    //signed change, positive amounts go to p and negative ones to n. None when this node's
+    //count would overflow or the change takes the value out of i64 (or further out, after a
+    //merge), the counter is left untouched then
+    pub fn checked_add(&mut self, node_id: String, delta: i64) -> Option<()> {
+        self.checked_change(node_id, delta as i128)
+    }
+
+    fn checked_change(&mut self, node_id: String, delta: i128) -> Option<()> {
+        let total = self.total() + delta;
+        if (delta > 0 && total > i64::MAX as i128) || (delta < 0 && total < i64::MIN as i128) {
+            return None;
+        }
+        let side = if delta >= 0 { &mut self.p } else { &mut self.n };
+        let amount = u64::try_from(delta.unsigned_abs()).ok()?;
+        let entry = side.entry(node_id).or_insert(0);
+        *entry = entry.checked_add(amount)?;
+        Some(())
+    }
+
+    //brings the value to `value` by adding the difference to this node's count, so nothing the
+    //other nodes counted is lost and merging stays the usual max. a change made elsewhere that
+    //this replica hasn't seen yet still adds to the value once merged, and two nodes setting the
+    //same value concurrently both add their difference. None on overflow, untouched then
+    pub fn checked_set(&mut self, node_id: String, value: i64) -> Option<()> {
+        let delta = value as i128 - self.total();
+        self.checked_change(node_id, delta)
+    }
+
+    //the sums in i128, so that no number of u64 counts can overflow them
+    fn total(&self) -> i128 {
+        let p_sum: i128 = self.p.values().map(|cnt| *cnt as i128).sum();
+        let n_sum: i128 = self.n.values().map(|cnt| *cnt as i128).sum();
+        p_sum - n_sum
+    }
+
+    //for the user of the node to see the value of the counter. checked_add keeps it in i64,
+    //but merging other nodes' counts in can still push it past, it saturates then
     pub fn value(&self) -> i64 {
-        let p_sum: u64 = self.p.values().sum();
-        let n_sum: u64 = self.n.values().sum();
-        (p_sum as i64) - (n_sum as i64)
+        saturate(self.total())
     }
+
+    //net contribution of every node that ever touched the counter, these add up to value()
+    //unless it saturated
+    pub fn value_by_node(&self) -> HashMap<NodeId, i64> {
+        let mut by_node: HashMap<NodeId, i128> = HashMap::new();
+        for (node, cnt) in self.p.iter() {
+            *by_node.entry(node.clone()).or_insert(0) += *cnt as i128;
+        }
+        for (node, cnt) in self.n.iter() {
+            *by_node.entry(node.clone()).or_insert(0) -= *cnt as i128;
+        }
+        by_node
+            .into_iter()
+            .map(|(node, value)| (node, saturate(value)))
+            .collect()
+    }
+
+    //nodes that actually incremented or decremented, a node that only created the counter
+    //with zeroes isn't one. sorted so that it can be shown as is
+    pub fn contributors(&self) -> BTreeSet<NodeId> {
+        self.p
+            .iter()
+            .chain(self.n.iter())
+            .filter(|(_, cnt)| **cnt > 0)
+            .map(|(node, _)| node.clone())
+            .collect()
+    }
+}
+
+fn saturate(value: i128) -> i64 {
+    value.clamp(i64::MIN as i128, i64::MAX as i128) as i64
 }
 
 #[cfg(test)]
@@ -81,7 +165,7 @@ mod tests {
         replica_b.increment(node_id_b.clone(), 1); //becomes 2 now
 
         //merge b's state to a
-        replica_a.merge(&mut replica_b);
+        replica_a.merge(&replica_b);
 
         assert_eq!(replica_a.value(), 3); //as it should get b's value now
 
@@ -97,7 +181,7 @@ mod tests {
         replica_d.increment(node_id_d.clone(), 1);
         replica_d.increment(node_id_d.clone(), 1);
 
-        replica_c.merge(&mut replica_d);
+        replica_c.merge(&replica_d);
         assert_eq!(replica_c.value(), 4);
     }
 
@@ -112,12 +196,151 @@ mod tests {
         replica_b.decrement(node_id_b.clone(), 1);
 
         let mut a_then_b = replica_a.clone();
-        a_then_b.merge(&mut replica_b);
+        a_then_b.merge(&replica_b);
 
         let mut b_then_a = replica_b.clone();
-        b_then_a.merge(&mut replica_a);
+        b_then_a.merge(&replica_a);
 
         //the final state must be identical regardless of merge order
         assert_eq!(a_then_b.value(), b_then_a.value());
     }
+
+    #[test]
+    fn test_signed_changes() {
+        let node_id = String::from("node_1");
+        let mut counter = PNCounter::new(node_id.clone(), 0, 0);
+
+        counter.checked_add(node_id.clone(), 5).unwrap();
+        counter.checked_add(node_id.clone(), -7).unwrap();
+        assert_eq!(counter.value(), -2);
+        assert_eq!(counter.p[&node_id], 5);
+        assert_eq!(counter.n[&node_id], 7);
+
+        //i64::MIN has no positive counterpart, but still fits in n
+        let mut lowest = PNCounter::new(node_id.clone(), 0, 0);
+        lowest.checked_add(node_id.clone(), i64::MIN).unwrap();
+        assert_eq!(lowest.n[&node_id], 1 << 63);
+        assert_eq!(lowest.value(), i64::MIN);
+
+        //overflowing leaves the counter as it was
+        let before = counter.clone();
+        assert_eq!(counter.checked_add(node_id, i64::MIN), None);
+        assert_eq!(counter, before);
+    }
+
+    #[test]
+    fn test_set_keeps_other_nodes_counts() {
+        let node_id_a = String::from("node_1");
+        let node_id_b = String::from("node_2");
+        let mut replica_a = PNCounter::new(node_id_a.clone(), 3, 0);
+        let mut replica_b = PNCounter::new(node_id_b.clone(), 4, 0);
+        replica_a.merge(&replica_b);
+
+        replica_a.checked_set(node_id_a.clone(), -1).unwrap();
+        assert_eq!(replica_a.value(), -1);
+        assert_eq!(replica_a.p[&node_id_b], 4);
+
+        //an increment the set didn't see still counts
+        replica_b.increment(node_id_b.clone(), 2);
+        replica_a.merge(&replica_b);
+        replica_b.merge(&replica_a);
+        assert_eq!(replica_a.value(), 1);
+        assert_eq!(replica_a, replica_b);
+
+        //only this node's own count can overflow now, the difference is taken in i128
+        replica_a.checked_set(node_id_a.clone(), i64::MIN).unwrap();
+        assert_eq!(replica_a.value(), i64::MIN);
+        let mut full = PNCounter {
+            p: HashMap::from([(node_id_b, u64::MAX)]),
+            n: HashMap::from([(node_id_a.clone(), u64::MAX)]),
+        };
+        let before = full.clone();
+        assert_eq!(full.checked_set(node_id_a, -1), None);
+        assert_eq!(full, before);
+    }
+
+    #[test]
+    fn test_value_stays_in_range() {
+        let node_id_a = String::from("node_1");
+        let mut counter = PNCounter::new(node_id_a.clone(), 0, 0);
+        counter.checked_add(node_id_a.clone(), i64::MAX).unwrap();
+        //this node's count would still fit, the value wouldn't
+        let before = counter.clone();
+        assert_eq!(counter.checked_add(node_id_a.clone(), i64::MAX), None);
+        assert_eq!(counter.checked_add(node_id_a.clone(), 1), None);
+        assert_eq!(counter, before);
+        assert_eq!(counter.value(), i64::MAX);
+
+        //nodes that stayed in range each can add up past it once merged
+        let mut other = PNCounter::new(String::from("node_2"), 0, 0);
+        other.checked_add(String::from("node_2"), i64::MAX).unwrap();
+        counter.merge(&other);
+        assert_eq!(counter.value(), i64::MAX);
+        assert_eq!(counter.checked_add(node_id_a.clone(), 1), None);
+        //but it can be brought back
+        counter.checked_add(node_id_a, i64::MIN).unwrap();
+        assert_eq!(counter.value(), i64::MAX - 1);
+    }
+
+    #[test]
+    fn test_merged_leaves_inputs_untouched() {
+        let replica_a = PNCounter::new(String::from("node_1"), 2, 0);
+        let replica_b = PNCounter::new(String::from("node_2"), 0, 1);
+
+        let both = crate::merged(&replica_a, &replica_b);
+        assert_eq!(both.value(), 1);
+        assert_eq!(both, crate::merged(&replica_b, &replica_a));
+        assert_eq!(replica_a.value(), 2);
+        assert_eq!(replica_b.value(), -1);
+    }
+
+    #[test]
+    fn test_deltas_converge_like_whole_states() {
+        let node_id_a = String::from("node_1");
+        let mut replica_a = PNCounter::new(node_id_a.clone(), 0, 0);
+        let mut replica_b = replica_a.clone();
+
+        let deltas = [
+            replica_a.increment_delta(node_id_a.clone(), 5),
+            replica_a.decrement_delta(node_id_a.clone(), 2),
+            replica_a.increment_delta(node_id_a.clone(), 1),
+        ];
+        assert_eq!(deltas[2].p.len() + deltas[2].n.len(), 1);
+
+        //out of order and twice over
+        for delta in deltas.iter().rev().chain(&deltas) {
+            replica_b.merge_delta(delta);
+        }
+        assert_eq!(replica_b, replica_a);
+        assert_eq!(replica_b.value(), 4);
+    }
+
+    #[test]
+    fn test_value_by_node_and_contributors() {
+        let node_id_a = String::from("node_1");
+        let mut replica_a = PNCounter::new(node_id_a.clone(), 5, 0);
+        replica_a.decrement(node_id_a.clone(), 2);
+
+        let node_id_b = String::from("node_2");
+        let mut replica_b = PNCounter::new(node_id_b.clone(), 0, 0);
+        replica_b.decrement(node_id_b.clone(), 4);
+
+        let node_id_c = String::from("node_3");
+        let replica_c = PNCounter::new(node_id_c.clone(), 0, 0);
+
+        replica_a.merge(&replica_b);
+        replica_a.merge(&replica_c);
+
+        let by_node = replica_a.value_by_node();
+        assert_eq!(by_node[&node_id_a], 3);
+        assert_eq!(by_node[&node_id_b], -4);
+        assert_eq!(by_node[&node_id_c], 0);
+        assert_eq!(by_node.values().sum::<i64>(), replica_a.value());
+
+        //node_3 never changed the count
+        assert_eq!(
+            replica_a.contributors().into_iter().collect::<Vec<_>>(),
+            vec![node_id_a, node_id_b]
+        );
+    }
 }
@@ -0,0 +1,217 @@
+//a replicated list (rga, replicated growable array). every inserted element gets an id that is
+//unique and larger than any id its replica has seen, and remembers the element it was inserted
+//after. the elements form a tree by that, and the list is the tree walked depth first with the
+//larger id first among siblings, so an insert lands right behind its origin on every replica and
+//two concurrent inserts at the same spot keep the same order everywhere. a remove only
+//tombstones the element, a later insert may still name it as its origin
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+
+use crate::{element::Element, Merge, NodeId};
+
+//ordered by counter, then by node_id, derived Ord goes by field order
+#[derive(Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct Id {
+    pub counter: u64,
+    pub node_id: NodeId,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Item {
+    //None at the head of the list
+    pub after: Option<Id>,
+    pub value: Element,
+}
+
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct Rga {
+    //the highest counter seen, lamport clock logic as in the AWSet
+    pub clock: u64,
+    #[serde(with = "crate::pairs")]
+    pub items: HashMap<Id, Item>,
+    pub removed: HashSet<Id>,
+}
+
+impl Rga {
+    pub fn new() -> Self {
+        Rga::default()
+    }
+
+    //every id in list order, removed ones included
+    fn order(&self) -> Vec<&Id> {
+        let mut children: HashMap<Option<&Id>, Vec<&Id>> = HashMap::new();
+        for (id, item) in &self.items {
+            children.entry(item.after.as_ref()).or_default().push(id);
+        }
+        //ascending, so that popping them off the stack gives the larger ones first
+        for siblings in children.values_mut() {
+            siblings.sort();
+        }
+        let mut order = Vec::with_capacity(self.items.len());
+        let mut stack: Vec<&Id> = children.get(&None).cloned().unwrap_or_default();
+        while let Some(id) = stack.pop() {
+            order.push(id);
+            if let Some(siblings) = children.get(&Some(id)) {
+                stack.extend(siblings);
+            }
+        }
+        order
+    }
+
+    fn visible(&self) -> Vec<&Id> {
+        self.order()
+            .into_iter()
+            .filter(|id| !self.removed.contains(*id))
+            .collect()
+    }
+
+    //puts value at index, shifting what is there to the right. None when index is past the end
+    pub fn insert(&mut self, index: usize, value: impl Into<Element>, id: NodeId) -> Option<()> {
+        let after = match index {
+            0 => None,
+            _ => Some((*self.visible().get(index - 1)?).clone()),
+        };
+        self.clock += 1;
+        let new_id = Id {
+            counter: self.clock,
+            node_id: id,
+        };
+        self.items.insert(
+            new_id,
+            Item {
+                after,
+                value: value.into(),
+            },
+        );
+        Some(())
+    }
+
+    pub fn push(&mut self, value: impl Into<Element>, id: NodeId) {
+        let len = self.len();
+        //can't be past the end
+        let _ = self.insert(len, value, id);
+    }
+
+    //the removed value, None when index is past the end
+    pub fn remove(&mut self, index: usize) -> Option<Element> {
+        let id = (*self.visible().get(index)?).clone();
+        let value = self.items[&id].value.clone();
+        self.removed.insert(id);
+        Some(value)
+    }
+
+    pub fn get(&self, index: usize) -> Option<&Element> {
+        let id = self.visible().into_iter().nth(index)?;
+        Some(&self.items[id].value)
+    }
+
+    //the values in list order
+    pub fn read(&self) -> Vec<Element> {
+        self.visible()
+            .into_iter()
+            .map(|id| self.items[id].value.clone())
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len() - self.removed.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Merge for Rga {
+    //an id's item never changes once inserted, so merging is a union of the items and of the
+    //tombstones
+    fn merge(&mut self, other: &Self) {
+        for (id, item) in &other.items {
+            self.items.entry(id.clone()).or_insert_with(|| item.clone());
+        }
+        self.removed.extend(other.removed.iter().cloned());
+        self.clock = std::cmp::max(self.clock, other.clock);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::check_laws;
+
+    fn list(values: &[&str]) -> Vec<Element> {
+        values.iter().map(|value| Element::from(*value)).collect()
+    }
+
+    #[test]
+    fn test_local_insert_remove() {
+        let node_1: NodeId = String::from("node_1");
+        let mut rga = Rga::new();
+        rga.push("a", node_1.clone());
+        rga.push("c", node_1.clone());
+        rga.insert(1, "b", node_1.clone()).unwrap();
+        rga.insert(0, "start", node_1.clone()).unwrap();
+        assert_eq!(rga.read(), list(&["start", "a", "b", "c"]));
+        assert_eq!(rga.insert(5, "x", node_1.clone()), None);
+
+        assert_eq!(rga.remove(0), Some(Element::from("start")));
+        assert_eq!(rga.remove(1), Some(Element::from("b")));
+        assert_eq!(rga.remove(2), None);
+        assert_eq!(rga.read(), list(&["a", "c"]));
+        assert_eq!(rga.get(1), Some(&Element::from("c")));
+        assert_eq!(rga.len(), 2);
+
+        //after a removed element's spot
+        rga.insert(1, "b2", node_1).unwrap();
+        assert_eq!(rga.read(), list(&["a", "b2", "c"]));
+    }
+
+    #[test]
+    fn test_concurrent_inserts_keep_one_order() {
+        let node_1: NodeId = String::from("node_1");
+        let node_2: NodeId = String::from("node_2");
+        let mut replica_1 = Rga::new();
+        replica_1.push("a", node_1.clone());
+        replica_1.push("d", node_1.clone());
+        let mut replica_2 = replica_1.clone();
+
+        //both insert after a
+        replica_1.insert(1, "b", node_1.clone()).unwrap();
+        replica_1.insert(2, "c", node_1).unwrap();
+        replica_2.insert(1, "x", node_2).unwrap();
+
+        let mut merged_1 = replica_1.clone();
+        merged_1.merge(&replica_2);
+        let mut merged_2 = replica_2.clone();
+        merged_2.merge(&replica_1);
+        assert_eq!(merged_1.read(), merged_2.read());
+        //a run typed by one node isn't interleaved with another's
+        let read = merged_1.read();
+        let b = read.iter().position(|v| *v == Element::from("b")).unwrap();
+        assert_eq!(read[b + 1], Element::from("c"));
+        assert_eq!(read.len(), 5);
+    }
+
+    #[test]
+    fn test_remove_and_insert_after_it() {
+        let node_1: NodeId = String::from("node_1");
+        let node_2: NodeId = String::from("node_2");
+        let mut replica_1 = Rga::new();
+        replica_1.push("a", node_1.clone());
+        replica_1.push("b", node_1.clone());
+        let mut replica_2 = replica_1.clone();
+
+        replica_1.remove(0);
+        replica_2.insert(1, "a2", node_2).unwrap();
+
+        replica_1.merge(&replica_2);
+        replica_2.merge(&replica_1);
+        assert_eq!(replica_1, replica_2);
+        assert_eq!(replica_1.read(), list(&["a2", "b"]));
+    }
+
+    #[test]
+    fn test_rga_obeys_the_laws() {
+        check_laws::<Rga>(1, 50);
+    }
+}
@@ -0,0 +1,445 @@
+//helpers for checking that a crdt converges: random states, random op sequences and asserts
+//for the merge laws (commutative, associative, idempotent, replicas converge in any merge
+//order). the crdts in this crate are checked with them, and a crdt written outside of it only
+//has to implement Generate to be checked the same way. everything is driven by a seeded Rng,
+//a failing check names its seed so that it can be replayed
+use std::fmt::Debug;
+
+use crate::{
+    aw_set::{AWSet, Dot},
+    bounded_counter::BoundedCounter,
+    causal::{DotContext, VersionVector},
+    element::Element,
+    g_set::GSet,
+    lww_map::LwwMap,
+    lww_register::LwwRegister,
+    mv_register::MVRegister,
+    or_map::ORMap,
+    orswot::Orswot,
+    pn_counter::PNCounter,
+    rga::Rga,
+    two_p_set::TwoPSet,
+};
+use crate::{Merge, NodeId};
+
+//splitmix64, small and good enough to shuffle ops around. not for anything else
+#[derive(Debug, Clone)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Rng { state: seed }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    //in 0..bound, bound has to be above 0
+    pub fn below(&mut self, bound: u64) -> u64 {
+        self.next_u64() % bound
+    }
+
+    pub fn chance(&mut self, percent: u64) -> bool {
+        self.below(100) < percent
+    }
+
+    pub fn pick<'a, T>(&mut self, items: &'a [T]) -> &'a T {
+        &items[self.below(items.len() as u64) as usize]
+    }
+
+    pub fn shuffle<T>(&mut self, items: &mut [T]) {
+        for i in (1..items.len()).rev() {
+            let j = self.below(i as u64 + 1) as usize;
+            items.swap(i, j);
+        }
+    }
+}
+
+//what a crdt needs to be generated and checked
+pub trait Generate: Merge + Clone + PartialEq + Debug {
+    //a fresh replica at node
+    fn empty(node: &NodeId) -> Self;
+    //one random local update made at node
+    fn random_op(&mut self, rng: &mut Rng, node: &NodeId);
+}
+
+//small pools, so that replicas keep touching the same elements and values
+const TAGS: [&str; 4] = ["apple", "banana", "cherry", "date"];
+const WORDS: [&str; 4] = ["a", "bb", "ccc", "dddd"];
+
+impl Generate for PNCounter {
+    fn empty(node: &NodeId) -> Self {
+        PNCounter::new(node.clone(), 0, 0)
+    }
+
+    fn random_op(&mut self, rng: &mut Rng, node: &NodeId) {
+        let amount = rng.below(10) + 1;
+        if rng.chance(50) {
+            self.increment(node.clone(), amount);
+        } else {
+            self.decrement(node.clone(), amount);
+        }
+    }
+}
+
+//decrements and transfers are refused past the node's rights, the refused ones change nothing
+impl Generate for BoundedCounter {
+    fn empty(_node: &NodeId) -> Self {
+        BoundedCounter::new()
+    }
+
+    fn random_op(&mut self, rng: &mut Rng, node: &NodeId) {
+        let amount = rng.below(10) + 1;
+        match rng.below(3) {
+            0 => self.increment(node.clone(), amount),
+            1 => {
+                let _ = self.decrement(node.clone(), amount);
+            }
+            _ => {
+                let to = node_ids(3).remove(rng.below(3) as usize);
+                let _ = self.transfer(node.clone(), to, amount);
+            }
+        }
+    }
+}
+
+impl Generate for LwwRegister {
+    fn empty(node: &NodeId) -> Self {
+        LwwRegister::new(node.clone())
+    }
+
+    fn random_op(&mut self, rng: &mut Rng, node: &NodeId) {
+        let word = rng.pick(&WORDS).to_string();
+        if rng.chance(75) {
+            self.set(word, node.clone());
+        } else {
+            self.append(word, node.clone());
+        }
+    }
+}
+
+impl Generate for LwwMap {
+    fn empty(_node: &NodeId) -> Self {
+        LwwMap::new()
+    }
+
+    fn random_op(&mut self, rng: &mut Rng, node: &NodeId) {
+        let field = rng.pick(&TAGS).to_string();
+        if rng.chance(70) {
+            self.set(field, rng.pick(&WORDS).to_string(), node.clone());
+        } else {
+            self.remove(&field, node.clone());
+        }
+    }
+}
+
+impl Generate for MVRegister {
+    fn empty(_node: &NodeId) -> Self {
+        MVRegister::new()
+    }
+
+    fn random_op(&mut self, rng: &mut Rng, node: &NodeId) {
+        let word = rng.pick(&WORDS).to_string();
+        if self.is_conflicted() && rng.chance(30) {
+            self.resolve(node.clone(), |siblings| {
+                siblings.iter().map(|s| s.as_str()).collect()
+            });
+        } else {
+            self.set(word, node.clone());
+        }
+    }
+}
+
+impl Generate for AWSet {
+    fn empty(_node: &NodeId) -> Self {
+        AWSet::new()
+    }
+
+    fn random_op(&mut self, rng: &mut Rng, node: &NodeId) {
+        let tag = if rng.chance(75) {
+            Element::from(*rng.pick(&TAGS))
+        } else {
+            Element::Int(rng.below(4) as i64)
+        };
+        if rng.chance(60) {
+            self.add(tag, node.clone());
+        } else {
+            self.remove(tag);
+        }
+    }
+}
+
+impl Generate for GSet<Element> {
+    fn empty(_node: &NodeId) -> Self {
+        GSet::new()
+    }
+
+    fn random_op(&mut self, rng: &mut Rng, _node: &NodeId) {
+        self.add(Element::from(*rng.pick(&TAGS)));
+    }
+}
+
+impl Generate for TwoPSet<Element> {
+    fn empty(_node: &NodeId) -> Self {
+        TwoPSet::new()
+    }
+
+    //removes are rarer, the pool is small and a removed tag is gone for good
+    fn random_op(&mut self, rng: &mut Rng, _node: &NodeId) {
+        let tag = Element::from(*rng.pick(&TAGS));
+        if rng.chance(80) {
+            self.add(tag);
+        } else {
+            self.remove(tag);
+        }
+    }
+}
+
+//the same ops as on the AWSet
+impl Generate for Orswot {
+    fn empty(_node: &NodeId) -> Self {
+        Orswot::new()
+    }
+
+    fn random_op(&mut self, rng: &mut Rng, node: &NodeId) {
+        let tag = if rng.chance(75) {
+            Element::from(*rng.pick(&TAGS))
+        } else {
+            Element::Int(rng.below(4) as i64)
+        };
+        if rng.chance(60) {
+            self.add(tag, node.clone());
+        } else {
+            self.remove(tag);
+        }
+    }
+}
+
+impl Generate for Rga {
+    fn empty(_node: &NodeId) -> Self {
+        Rga::new()
+    }
+
+    fn random_op(&mut self, rng: &mut Rng, node: &NodeId) {
+        let len = self.len() as u64;
+        if len > 0 && rng.chance(30) {
+            self.remove(rng.below(len) as usize);
+        } else {
+            let index = rng.below(len + 1) as usize;
+            let _ = self.insert(index, *rng.pick(&WORDS), node.clone());
+        }
+    }
+}
+
+impl Generate for VersionVector {
+    fn empty(_node: &NodeId) -> Self {
+        VersionVector::new()
+    }
+
+    fn random_op(&mut self, _rng: &mut Rng, node: &NodeId) {
+        self.increment(node.clone());
+    }
+}
+
+//local dots, and dots of other nodes that arrive out of order and leave gaps
+impl Generate for DotContext {
+    fn empty(_node: &NodeId) -> Self {
+        DotContext::new()
+    }
+
+    fn random_op(&mut self, rng: &mut Rng, node: &NodeId) {
+        if rng.chance(60) {
+            self.next_dot(node.clone());
+        } else {
+            let node_id = format!("remote_{}", rng.below(2));
+            let counter = self.clock.get(&node_id) + rng.below(3) + 1;
+            self.insert(Dot { node_id, counter });
+        }
+    }
+}
+
+//updates and removes over the same few keys, the values get ops of their own
+impl<V: Generate> Generate for ORMap<String, V> {
+    fn empty(_node: &NodeId) -> Self {
+        ORMap::new()
+    }
+
+    fn random_op(&mut self, rng: &mut Rng, node: &NodeId) {
+        let key = rng.pick(&TAGS).to_string();
+        if rng.chance(70) {
+            self.update(
+                key,
+                node.clone(),
+                || V::empty(node),
+                |value| value.random_op(rng, node),
+            );
+        } else {
+            self.remove(&key);
+        }
+    }
+}
+
+pub fn node_ids(count: usize) -> Vec<NodeId> {
+    (0..count).map(|i| format!("node_{}", i)).collect()
+}
+
+pub fn apply_random_ops<T: Generate>(state: &mut T, rng: &mut Rng, node: &NodeId, ops: usize) {
+    for _ in 0..ops {
+        state.random_op(rng, node);
+    }
+}
+
+pub fn random_state<T: Generate>(rng: &mut Rng, node: &NodeId, ops: usize) -> T {
+    let mut state = T::empty(node);
+    apply_random_ops(&mut state, rng, node, ops);
+    state
+}
+
+//one replica per node, each updated on its own and now and then merging in another one, so that
+//they share some history and diverge on the rest
+pub fn random_replicas<T: Generate>(rng: &mut Rng, nodes: &[NodeId], ops: usize) -> Vec<T> {
+    let mut replicas: Vec<T> = nodes.iter().map(T::empty).collect();
+    for _ in 0..ops {
+        let i = rng.below(nodes.len() as u64) as usize;
+        if rng.chance(20) {
+            let from = replicas[rng.below(nodes.len() as u64) as usize].clone();
+            replicas[i].merge(&from);
+        } else {
+            replicas[i].random_op(rng, &nodes[i]);
+        }
+    }
+    replicas
+}
+
+pub fn assert_commutative<T: Merge + Clone + PartialEq + Debug>(a: &T, b: &T) {
+    assert_eq!(
+        crate::merged(a, b),
+        crate::merged(b, a),
+        "merge is not commutative for\n{:?}\nand\n{:?}",
+        a,
+        b
+    );
+}
+
+pub fn assert_associative<T: Merge + Clone + PartialEq + Debug>(a: &T, b: &T, c: &T) {
+    assert_eq!(
+        crate::merged(&crate::merged(a, b), c),
+        crate::merged(a, &crate::merged(b, c)),
+        "merge is not associative for\n{:?}\n{:?}\n{:?}",
+        a,
+        b,
+        c
+    );
+}
+
+pub fn assert_idempotent<T: Merge + Clone + PartialEq + Debug>(a: &T) {
+    assert_eq!(
+        &crate::merged(a, a),
+        a,
+        "merge is not idempotent for\n{:?}",
+        a
+    );
+}
+
+//every replica ends up in the same state, whichever order the others are merged into it
+pub fn assert_converges<T: Merge + Clone + PartialEq + Debug>(replicas: &[T], rng: &mut Rng) {
+    let Some(first) = replicas.first() else {
+        return;
+    };
+    let mut expected = first.clone();
+    for replica in replicas {
+        expected.merge(replica);
+    }
+    for (i, replica) in replicas.iter().enumerate() {
+        let mut order: Vec<&T> = replicas.iter().collect();
+        rng.shuffle(&mut order);
+        let mut state = replica.clone();
+        for other in order {
+            state.merge(other);
+        }
+        assert_eq!(state, expected, "replica {} did not converge", i);
+    }
+}
+
+//all of the above on `rounds` sets of random replicas, panics with the failing seed
+pub fn check_laws<T: Generate>(seed: u64, rounds: usize) {
+    let nodes = node_ids(3);
+    for round in 0..rounds {
+        let round_seed = seed.wrapping_add(round as u64);
+        let mut rng = Rng::new(round_seed);
+        let replicas: Vec<T> = random_replicas(&mut rng, &nodes, 30);
+        let outcome = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let (a, b, c) = (&replicas[0], &replicas[1], &replicas[2]);
+            assert_commutative(a, b);
+            assert_associative(a, b, c);
+            for replica in &replicas {
+                assert_idempotent(replica);
+            }
+            assert_converges(&replicas, &mut rng);
+        }));
+        if let Err(panic) = outcome {
+            let reason = panic
+                .downcast_ref::<String>()
+                .cloned()
+                .or_else(|| panic.downcast_ref::<&str>().map(|s| s.to_string()))
+                .unwrap_or_default();
+            panic!("crdt laws broken with seed {}: {}", round_seed, reason);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crate_crdts_obey_the_laws() {
+        check_laws::<PNCounter>(1, 50);
+        check_laws::<LwwRegister>(1, 50);
+        check_laws::<AWSet>(1, 50);
+    }
+
+    #[test]
+    fn test_same_seed_same_states() {
+        let nodes = node_ids(3);
+        let a: Vec<AWSet> = random_replicas(&mut Rng::new(7), &nodes, 20);
+        let b: Vec<AWSet> = random_replicas(&mut Rng::new(7), &nodes, 20);
+        assert_eq!(
+            a.iter().map(AWSet::read).collect::<Vec<_>>(),
+            b.iter().map(AWSet::read).collect::<Vec<_>>()
+        );
+    }
+
+    //keeps whatever it merged last, so merge order shows
+    #[derive(Debug, Clone, PartialEq)]
+    struct LastWriter(u64);
+
+    impl Merge for LastWriter {
+        fn merge(&mut self, other: &Self) {
+            self.0 = other.0;
+        }
+    }
+
+    impl Generate for LastWriter {
+        fn empty(_node: &NodeId) -> Self {
+            LastWriter(0)
+        }
+
+        fn random_op(&mut self, rng: &mut Rng, _node: &NodeId) {
+            self.0 = rng.below(1000);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "crdt laws broken with seed")]
+    fn test_broken_merge_is_caught() {
+        check_laws::<LastWriter>(1, 10);
+    }
+}
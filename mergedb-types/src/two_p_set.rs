@@ -0,0 +1,94 @@
+//a two-phase set: a grow-only set of added elements and one of removed elements (tombstones).
+//an element is in the set while it was added and never removed, so an element can be removed
+//once and never comes back, a remove wins over every add. that takes no more metadata than one
+//tombstone per removed element, against the AWSet's dot per add, and suits elements that are
+//added once and retired for good, eg issued coupons or session ids
+use serde::{Deserialize, Serialize};
+use std::{collections::HashSet, hash::Hash};
+
+use crate::{g_set::GSet, Merge};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TwoPSet<T: Eq + Hash> {
+    pub added: GSet<T>,
+    pub removed: GSet<T>,
+}
+
+impl<T: Eq + Hash + Clone> Default for TwoPSet<T> {
+    fn default() -> Self {
+        TwoPSet {
+            added: GSet::new(),
+            removed: GSet::new(),
+        }
+    }
+}
+
+impl<T: Eq + Hash + Clone> TwoPSet<T> {
+    pub fn new() -> Self {
+        TwoPSet::default()
+    }
+
+    //false if the element was removed before, it stays out
+    pub fn add(&mut self, element: T) -> bool {
+        if self.removed.contains(&element) {
+            return false;
+        }
+        self.added.add(element);
+        true
+    }
+
+    //only what this replica has seen added can be removed, false otherwise
+    pub fn remove(&mut self, element: T) -> bool {
+        if !self.contains(&element) {
+            return false;
+        }
+        self.removed.add(element)
+    }
+
+    pub fn contains(&self, element: &T) -> bool {
+        self.added.contains(element) && !self.removed.contains(element)
+    }
+
+    pub fn read(&self) -> HashSet<T> {
+        self.added
+            .read()
+            .difference(self.removed.read())
+            .cloned()
+            .collect()
+    }
+}
+
+impl<T: Eq + Hash + Clone> Merge for TwoPSet<T> {
+    fn merge(&mut self, other: &Self) {
+        self.added.merge(&other.added);
+        self.removed.merge(&other.removed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{element::Element, testing::check_laws};
+
+    #[test]
+    fn test_a_removed_element_stays_removed() {
+        let coupon = Element::from("SPRING24");
+        let mut replica_1 = TwoPSet::new();
+        assert!(!replica_1.remove(coupon.clone()));
+        replica_1.add(coupon.clone());
+        let mut replica_2 = replica_1.clone();
+
+        //redeemed on one replica while the other adds it again
+        assert!(replica_1.remove(coupon.clone()));
+        replica_2.add(coupon.clone());
+        replica_2.merge(&replica_1);
+        assert!(!replica_2.contains(&coupon));
+        assert!(!replica_2.add(coupon.clone()));
+        assert!(replica_2.read().is_empty());
+    }
+
+    #[test]
+    fn test_two_p_set_obeys_the_laws() {
+        check_laws::<TwoPSet<Element>>(1, 50);
+    }
+}
@@ -0,0 +1,28 @@
+#!/bin/sh
+# runs the rolling-upgrade compatibility test against the previous release of the node.
+# the release is built from the newest git tag in a worktree under target/, pass any other
+# tag or commit to check against that one instead: scripts/compat.sh v0.1.0
+set -eu
+
+root="$(git rev-parse --show-toplevel)"
+
+if [ $# -gt 0 ]; then
+    PREVIOUS="$1"
+elif ! PREVIOUS="$(git -C "$root" describe --tags --abbrev=0 2>/dev/null)"; then
+    echo "compat: no release tag to test against, pass a tag or commit: scripts/compat.sh <rev>" >&2
+    exit 2
+fi
+if ! commit="$(git -C "$root" rev-parse --verify --quiet "$PREVIOUS^{commit}")"; then
+    echo "compat: $PREVIOUS is not a tag or commit in this repository" >&2
+    exit 2
+fi
+
+worktree="$root/target/compat/$commit"
+
+if [ ! -d "$worktree" ]; then
+    git -C "$root" worktree add --detach "$worktree" "$commit"
+fi
+cargo build --manifest-path "$worktree/Cargo.toml" -p mergedb-node
+
+MERGEDB_PREVIOUS_NODE="$worktree/target/debug/mergedb-node" \
+    cargo test --manifest-path "$root/Cargo.toml" -p mergedb-node --test compat